@@ -0,0 +1,101 @@
+use std::process::Command;
+
+/// Files changed relative to the merge base with a base ref, used by `all`
+/// to skip stages whose inputs are untouched.
+pub struct ChangeSet {
+    /// `None` when the diff could not be computed; every stage then runs.
+    files: Option<Vec<String>>,
+    base: String,
+}
+
+impl ChangeSet {
+    /// Diff the working tree of `source_path` against its merge base with
+    /// `base`, including uncommitted changes and untracked files.
+    ///
+    /// On `base` itself (the merge base is `HEAD`) or with nothing changed,
+    /// there is nothing to compare with, so every stage runs.
+    pub fn detect(source_path: &str, base: &str) -> Self {
+        let Some(merge_base) = git(source_path, &["merge-base", "HEAD", base]) else {
+            return Self::everything();
+        };
+        let merge_base = merge_base.trim();
+        if git(source_path, &["rev-parse", "HEAD"]).is_some_and(|head| head.trim() == merge_base) {
+            return Self::everything();
+        }
+        let diff = git(source_path, &["diff", "--name-only", merge_base]);
+        let untracked = git(source_path, &["ls-files", "--others", "--exclude-standard"]);
+        let (Some(diff), Some(untracked)) = (diff, untracked) else {
+            return Self::everything();
+        };
+        let files: Vec<String> = diff.lines().chain(untracked.lines()).map(str::to_string).collect();
+        if files.is_empty() {
+            return Self::everything();
+        }
+        Self {
+            files: Some(files),
+            base: base.to_string(),
+        }
+    }
+
+    /// A change set that runs every stage.
+    pub fn everything() -> Self {
+        Self {
+            files: None,
+            base: String::new(),
+        }
+    }
+
+    /// Why `stage` can be skipped, or `None` if it has to run.
+    pub fn skip_reason(&self, stage: &str) -> Option<String> {
+        let files = self.files.as_ref()?;
+        if files.iter().any(|f| affects(stage, f)) {
+            return None;
+        }
+        Some(format!("no {} changes since merge base with {}", inputs(stage), self.base))
+    }
+}
+
+/// Whether a change to `path` can change the outcome of `stage`. Files
+/// that aren't inputs of any specific stage (CI config, scripts) rerun
+/// everything.
+fn affects(stage: &str, path: &str) -> bool {
+    let rust = path.ends_with(".rs")
+        || path.ends_with("Cargo.toml")
+        || path == "Cargo.lock"
+        || path == "rust-toolchain.toml";
+    let module = path.starts_with("modules/");
+    let frontend = path.starts_with("erp_web/static/");
+    let docs = path.ends_with(".md");
+    let known = rust || module || frontend || docs;
+
+    match stage {
+        "check" | "fmt" | "lint" | "test" => rust || !known,
+        "integration-test" => rust || module || !known,
+        "module-lint" => module || (path.starts_with("erp_core/src/") && rust) || !known,
+        "tailwind-build" => frontend || !known,
+        _ => true,
+    }
+}
+
+fn inputs(stage: &str) -> &'static str {
+    match stage {
+        "check" | "fmt" | "lint" | "test" => "Rust",
+        "integration-test" => "Rust or module",
+        "module-lint" => "module",
+        "tailwind-build" => "erp_web/static",
+        _ => "input",
+    }
+}
+
+fn git(source_path: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(source_path)
+        .args(args)
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// Pipeline options from `ci.toml` at the root of the source tree. Every
+/// key is optional; a missing file means all defaults.
+///
+/// ```toml
+/// [rust]
+/// version = "1.85"              # or image = "rust:1.85-bookworm"
+/// apt_packages = ["protobuf-compiler"]
+///
+/// [source]
+/// exclude = ["target/", ".git/", "ci/", "erp_web/static/node_modules/"]
+///
+/// [lint]
+/// clippy_args = ["-D", "clippy::correctness", "-W", "clippy::all"]
+///
+/// [test]
+/// threads = 4
+///
+/// [deploy.targets.staging]
+/// hosts = ["192.168.3.148"]
+/// service = "erp.service"
+/// known_hosts = "192.168.3.148 ssh-ed25519 AAAA..."
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineConfig {
+    pub rust: RustConfig,
+    pub source: SourceConfig,
+    pub lint: LintConfig,
+    pub test: TestConfig,
+    pub deploy: DeployConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RustConfig {
+    /// Toolchain version of the official `rust:<version>-bookworm` image.
+    pub version: String,
+    /// Full image reference; overrides `version`.
+    pub image: Option<String>,
+    /// Installed on top of the packages Diesel/PG builds need.
+    pub apt_packages: Vec<String>,
+}
+
+impl Default for RustConfig {
+    fn default() -> Self {
+        Self {
+            version: "1.85".to_string(),
+            image: None,
+            apt_packages: Vec::new(),
+        }
+    }
+}
+
+impl RustConfig {
+    pub fn image(&self) -> String {
+        self.image
+            .clone()
+            .unwrap_or_else(|| format!("rust:{}-bookworm", self.version))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourceConfig {
+    /// Host paths left out of the source directory.
+    pub exclude: Vec<String>,
+}
+
+impl Default for SourceConfig {
+    fn default() -> Self {
+        Self {
+            exclude: ["target/", ".git/", "ci/", "erp_web/static/node_modules/"]
+                .map(str::to_string)
+                .to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LintConfig {
+    /// Arguments passed to clippy after `--`.
+    pub clippy_args: Vec<String>,
+}
+
+impl Default for LintConfig {
+    fn default() -> Self {
+        Self {
+            clippy_args: ["-D", "clippy::correctness", "-W", "clippy::all"]
+                .map(str::to_string)
+                .to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TestConfig {
+    /// `--test-threads` for the unit tests; the harness default if unset.
+    pub threads: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeployConfig {
+    /// Named deploy targets (`staging`, `production`, ...). Defaults to a
+    /// single `dev` target.
+    pub targets: BTreeMap<String, DeployTarget>,
+}
+
+impl Default for DeployConfig {
+    fn default() -> Self {
+        let dev = DeployTarget {
+            hosts: vec!["192.168.3.148".to_string()],
+            ..DeployTarget::default()
+        };
+        Self {
+            targets: BTreeMap::from([("dev".to_string(), dev)]),
+        }
+    }
+}
+
+impl DeployConfig {
+    pub fn target(&self, name: &str) -> eyre::Result<&DeployTarget> {
+        self.targets.get(name).ok_or_else(|| {
+            let known: Vec<&str> = self.targets.keys().map(String::as_str).collect();
+            eyre::eyre!("unknown deploy target {name:?} (configured: {})", known.join(", "))
+        })
+    }
+}
+
+/// Hosts of one deploy target and how the server is installed on them.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeployTarget {
+    pub hosts: Vec<String>,
+    /// SSH user; needs passwordless `sudo` for the install steps.
+    pub user: String,
+    /// systemd unit restarted on deploy and rollback.
+    pub service: String,
+    /// Directory holding `erp-server` (and `erp-server.bak`) and the static
+    /// files.
+    pub install_dir: String,
+    /// Checked after (re)starting the service; `{host}` is replaced.
+    pub health_url: String,
+    /// Host keys of `hosts`, in `known_hosts` format. Deploys refuse hosts
+    /// whose key is not pinned here or in `SSH_KNOWN_HOSTS`.
+    pub known_hosts: Option<String>,
+}
+
+impl Default for DeployTarget {
+    fn default() -> Self {
+        Self {
+            hosts: Vec::new(),
+            user: "ubuntu-server".to_string(),
+            service: "erp.service".to_string(),
+            install_dir: "/opt/rust-erp".to_string(),
+            health_url: "http://{host}:9089/health".to_string(),
+            known_hosts: None,
+        }
+    }
+}
+
+impl PipelineConfig {
+    /// Load `ci.toml` from the root of `source`.
+    pub fn load(source: &str) -> eyre::Result<Self> {
+        let path = Path::new(source).join("ci.toml");
+        match std::fs::read_to_string(&path) {
+            Ok(raw) => toml::from_str(&raw)
+                .map_err(|e| eyre::eyre!("invalid {}: {e}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(eyre::eyre!("cannot read {}: {e}", path.display())),
+        }
+    }
+}
@@ -1,15 +1,21 @@
 use dagger_sdk::{Container, Directory, Query, Service};
 
-/// Rust build container with Diesel/PG deps and cargo caches.
-pub fn rust_base(client: &Query, source: Directory) -> Container {
+use crate::config::PipelineConfig;
+
+/// Rust build container with Diesel/PG deps and cargo caches. Image and
+/// extra apt packages come from `[rust]` in `ci.toml`.
+pub fn rust_base(client: &Query, config: &PipelineConfig, source: Directory) -> Container {
+    let mut install = vec![
+        "apt-get", "install", "-y",
+        "libpq-dev", "pkg-config", "build-essential", "postgresql-client",
+    ];
+    install.extend(config.rust.apt_packages.iter().map(String::as_str));
+
     client
         .container()
-        .from("rust:1.85-bookworm")
+        .from(config.rust.image())
         .with_exec(vec!["apt-get", "update"])
-        .with_exec(vec![
-            "apt-get", "install", "-y",
-            "libpq-dev", "pkg-config", "build-essential", "postgresql-client",
-        ])
+        .with_exec(install)
         .with_mounted_cache(
             "/usr/local/cargo/registry",
             client.cache_volume("cargo-registry"),
@@ -1,12 +1,24 @@
+mod changes;
+mod config;
 mod containers;
+mod report;
 mod stages;
 
 use clap::{Parser, Subcommand};
 use dagger_sdk::{Directory, HostDirectoryOpts, Query};
 
+use changes::ChangeSet;
+use config::PipelineConfig;
+use report::{OutputFormat, Reporter};
+
 #[derive(Parser)]
 #[command(name = "centrix-ci", about = "Centrix CI/CD Pipeline")]
 struct Cli {
+    /// `json` emits one event per line per stage and writes stage output to --log-dir
+    #[arg(long, value_enum, global = true, default_value = "text")]
+    output: OutputFormat,
+    #[arg(long, global = true, default_value = "ci-logs")]
+    log_dir: String,
     #[command(subcommand)]
     command: Command,
 }
@@ -33,6 +45,13 @@ enum Command {
         #[arg(long)]
         source: String,
     },
+    /// Workspace test coverage (lcov + HTML exported to --report-dir)
+    Coverage {
+        #[arg(long)]
+        source: String,
+        #[arg(long, default_value = "coverage")]
+        report_dir: String,
+    },
     /// Module lifecycle integration test
     #[command(name = "integration-test")]
     IntegrationTest {
@@ -51,12 +70,45 @@ enum Command {
         #[arg(long)]
         source: String,
     },
-    /// Deploy to dev server
+    /// Deploy to every host of a [deploy.targets] entry in ci.toml
     Deploy {
         #[arg(long)]
         source: String,
-        #[arg(long, default_value = "192.168.3.148")]
-        host: String,
+        #[arg(long, default_value = "dev")]
+        target: String,
+    },
+    /// Restore the previous binary on every host of a deploy target
+    Rollback {
+        #[arg(long)]
+        source: String,
+        #[arg(long, default_value = "dev")]
+        target: String,
+    },
+    /// Build the server image and push it tagged with SHA and branch
+    #[command(name = "docker-publish")]
+    DockerPublish {
+        #[arg(long)]
+        source: String,
+        /// Defaults to $CI_COMMIT
+        #[arg(long)]
+        sha: Option<String>,
+        /// Defaults to $CI_BRANCH
+        #[arg(long)]
+        branch: Option<String>,
+    },
+    /// Build release binaries and upload them to the GitHub release of a tag
+    Release {
+        #[arg(long)]
+        source: String,
+        /// Defaults to the tag on HEAD
+        #[arg(long)]
+        tag: Option<String>,
+        /// Rust target triple; repeat for several (default: x86_64-unknown-linux-gnu)
+        #[arg(long = "target")]
+        targets: Vec<String>,
+        /// owner/name; defaults to $GITHUB_REPOSITORY
+        #[arg(long)]
+        repo: Option<String>,
     },
     /// Security audit
     #[command(name = "security-audit")]
@@ -64,23 +116,45 @@ enum Command {
         #[arg(long)]
         source: String,
     },
-    /// Full pipeline (check + fmt + lint + test + module-lint + integration)
+    /// Full pipeline (check + fmt + lint + test + module-lint + tailwind + integration),
+    /// skipping stages whose inputs didn't change since the merge base with --base
     All {
         #[arg(long)]
         source: String,
+        #[arg(long, default_value = "origin/main")]
+        base: String,
+        /// Run every stage regardless of what changed
+        #[arg(long)]
+        force: bool,
     },
 }
 
-fn host_directory(client: &Query, source: &str) -> Directory {
+impl Command {
+    fn source(&self) -> &str {
+        match self {
+            Command::Check { source }
+            | Command::Fmt { source }
+            | Command::Lint { source }
+            | Command::Test { source }
+            | Command::Coverage { source, .. }
+            | Command::IntegrationTest { source }
+            | Command::ModuleLint { source }
+            | Command::TailwindBuild { source }
+            | Command::Deploy { source, .. }
+            | Command::Rollback { source, .. }
+            | Command::DockerPublish { source, .. }
+            | Command::Release { source, .. }
+            | Command::SecurityAudit { source }
+            | Command::All { source, .. } => source,
+        }
+    }
+}
+
+fn host_directory(client: &Query, config: &PipelineConfig, source: &str) -> Directory {
     client.host().directory_opts(
         source,
         HostDirectoryOpts {
-            exclude: Some(vec![
-                "target/",
-                ".git/",
-                "ci/",
-                "erp_web/static/node_modules/",
-            ]),
+            exclude: Some(config.source.exclude.iter().map(String::as_str).collect()),
             include: None,
             gitignore: None,
             no_cache: None,
@@ -91,78 +165,109 @@ fn host_directory(client: &Query, source: &str) -> Directory {
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     color_eyre::install()?;
-    let Cli { command } = Cli::parse();
+    let Cli { output, log_dir, command } = Cli::parse();
+    let reporter = Reporter::new(output, log_dir)?;
+    let config = PipelineConfig::load(command.source())?;
 
     dagger_sdk::connect(|client| async move {
         match command {
             Command::Check { source } => {
-                let src = host_directory(&client, &source);
-                let out = stages::check::run(&client, src).await?;
-                println!("{out}");
+                let src = host_directory(&client, &config, &source);
+                reporter.stage("check", stages::check::run(&client, &config, src)).await?;
             }
             Command::Fmt { source } => {
-                let src = host_directory(&client, &source);
-                let out = stages::fmt::run(&client, src).await?;
-                println!("{out}");
+                let src = host_directory(&client, &config, &source);
+                reporter.stage("fmt", stages::fmt::run(&client, &config, src)).await?;
             }
             Command::Lint { source } => {
-                let src = host_directory(&client, &source);
-                let out = stages::lint::run(&client, src).await?;
-                println!("{out}");
+                let src = host_directory(&client, &config, &source);
+                reporter.stage("lint", stages::lint::run(&client, &config, src)).await?;
             }
             Command::Test { source } => {
-                let src = host_directory(&client, &source);
-                let out = stages::test::run(&client, src).await?;
-                println!("{out}");
+                let src = host_directory(&client, &config, &source);
+                reporter.stage("test", stages::test::run(&client, &config, src)).await?;
+            }
+            Command::Coverage { source, report_dir } => {
+                let src = host_directory(&client, &config, &source);
+                reporter.stage("coverage", stages::coverage::run(&client, &config, src, &report_dir)).await?;
             }
             Command::IntegrationTest { source } => {
-                let src = host_directory(&client, &source);
-                let out = stages::integration::run(&client, src).await?;
-                println!("{out}");
+                let src = host_directory(&client, &config, &source);
+                reporter.stage("integration-test", stages::integration::run(&client, &config, src)).await?;
             }
             Command::ModuleLint { source } => {
-                let src = host_directory(&client, &source);
-                let out = stages::module_lint::run(&client, src).await?;
-                println!("{out}");
+                let src = host_directory(&client, &config, &source);
+                reporter.stage("module-lint", stages::module_lint::run(&client, &config, src)).await?;
             }
             Command::TailwindBuild { source } => {
-                let src = host_directory(&client, &source);
-                let out = stages::tailwind::run(&client, src).await?;
-                println!("{out}");
+                let src = host_directory(&client, &config, &source);
+                reporter.stage("tailwind-build", stages::tailwind::run(&client, src)).await?;
+            }
+            Command::Deploy { source, target } => {
+                let src = host_directory(&client, &config, &source);
+                let deploy_target = config.deploy.target(&target)?;
+                for host in &deploy_target.hosts {
+                    let run = stages::deploy::run(&client, src.clone(), deploy_target, host);
+                    reporter.stage(&format!("deploy-{target}-{host}"), run).await?;
+                }
+            }
+            Command::Rollback { target, .. } => {
+                let deploy_target = config.deploy.target(&target)?;
+                for host in &deploy_target.hosts {
+                    let run = stages::deploy::rollback(&client, deploy_target, host);
+                    reporter.stage(&format!("rollback-{target}-{host}"), run).await?;
+                }
+            }
+            Command::DockerPublish { source, sha, branch } => {
+                let src = host_directory(&client, &config, &source);
+                let run = stages::docker_publish::run(&client, &config, src, sha, branch);
+                reporter.stage("docker-publish", run).await?;
             }
-            Command::Deploy { source, host } => {
-                let src = host_directory(&client, &source);
-                let out = stages::deploy::run(&client, src, &host).await?;
-                println!("{out}");
+            Command::Release { source, tag, targets, repo } => {
+                let src = host_directory(&client, &config, &source);
+                let run = stages::release::run(&client, &config, src, &source, tag, targets, repo);
+                reporter.stage("release", run).await?;
             }
             Command::SecurityAudit { source } => {
-                let src = host_directory(&client, &source);
-                let out = stages::security::run(&client, src).await?;
-                println!("{out}");
+                let src = host_directory(&client, &config, &source);
+                reporter.stage("security-audit", stages::security::run(&client, &config, src)).await?;
             }
-            Command::All { source } => {
-                let src = host_directory(&client, &source);
+            Command::All { source, base, force } => {
+                let src = host_directory(&client, &config, &source);
+                let changes = if force {
+                    ChangeSet::everything()
+                } else {
+                    ChangeSet::detect(&source, &base)
+                };
 
-                println!("=== Phase 1: Fast Gates ===");
-                let (check_out, fmt_out) = tokio::try_join!(
-                    stages::check::run(&client, src.clone()),
-                    stages::fmt::run(&client, src.clone()),
+                reporter.heading("=== Phase 1: Fast Gates ===");
+                tokio::try_join!(
+                    reporter.stage_if_changed(&changes, "check", stages::check::run(&client, &config, src.clone())),
+                    reporter.stage_if_changed(&changes, "fmt", stages::fmt::run(&client, &config, src.clone())),
                 )?;
-                println!("{check_out}\n{fmt_out}");
 
-                println!("=== Phase 2: Quality Gates ===");
-                let (lint_out, test_out, mlint_out) = tokio::try_join!(
-                    stages::lint::run(&client, src.clone()),
-                    stages::test::run(&client, src.clone()),
-                    stages::module_lint::run(&client, src.clone()),
+                reporter.heading("=== Phase 2: Quality Gates ===");
+                tokio::try_join!(
+                    reporter.stage_if_changed(&changes, "lint", stages::lint::run(&client, &config, src.clone())),
+                    reporter.stage_if_changed(&changes, "test", stages::test::run(&client, &config, src.clone())),
+                    reporter.stage_if_changed(
+                        &changes,
+                        "module-lint",
+                        stages::module_lint::run(&client, &config, src.clone()),
+                    ),
+                    reporter.stage_if_changed(
+                        &changes,
+                        "tailwind-build",
+                        stages::tailwind::run(&client, src.clone()),
+                    ),
                 )?;
-                println!("{lint_out}\n{test_out}\n{mlint_out}");
 
-                println!("=== Phase 3: Integration ===");
-                let int_out = stages::integration::run(&client, src).await?;
-                println!("{int_out}");
+                reporter.heading("=== Phase 3: Integration ===");
+                reporter
+                    .stage_if_changed(&changes, "integration-test", stages::integration::run(&client, &config, src))
+                    .await?;
 
-                println!("\n=== Full CI Pipeline Complete ===");
+                reporter.heading("\n=== Full CI Pipeline Complete ===");
             }
         }
         Ok(())
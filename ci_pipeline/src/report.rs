@@ -0,0 +1,140 @@
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+
+use crate::changes::ChangeSet;
+
+/// How stage results are written to stdout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Stage output as free-form text.
+    Text,
+    /// One JSON event per line; stage output goes to per-stage log files.
+    Json,
+}
+
+/// Runs stages and reports their progress in the chosen format.
+///
+/// In JSON mode every stage emits a `started` event and then a `finished`
+/// event with its duration, `status` (`success` or `failure`), the exit code
+/// of the failing command when known, and the path of its log file. Stages
+/// left out by change detection emit a single `skipped` event.
+pub struct Reporter {
+    format: OutputFormat,
+    log_dir: PathBuf,
+}
+
+impl Reporter {
+    pub fn new(format: OutputFormat, log_dir: impl Into<PathBuf>) -> eyre::Result<Self> {
+        let log_dir = log_dir.into();
+        if format == OutputFormat::Json {
+            std::fs::create_dir_all(&log_dir)?;
+        }
+        Ok(Self { format, log_dir })
+    }
+
+    /// Print a heading (text mode only).
+    pub fn heading(&self, text: &str) {
+        if self.format == OutputFormat::Text {
+            println!("{text}");
+        }
+    }
+
+    /// Report a stage that was not run.
+    pub fn skip(&self, name: &str, reason: &str) {
+        match self.format {
+            OutputFormat::Text => println!("[{name}] Skipped: {reason}"),
+            OutputFormat::Json => emit(serde_json::json!({
+                "event": "skipped",
+                "stage": name,
+                "timestamp": unix_millis(),
+                "reason": reason,
+            })),
+        }
+    }
+
+    /// Run `stage` unless `changes` shows its inputs are untouched.
+    pub async fn stage_if_changed(
+        &self,
+        changes: &ChangeSet,
+        name: &str,
+        run: impl Future<Output = eyre::Result<String>>,
+    ) -> eyre::Result<()> {
+        match changes.skip_reason(name) {
+            Some(reason) => {
+                self.skip(name, &reason);
+                Ok(())
+            }
+            None => self.stage(name, run).await,
+        }
+    }
+
+    /// Run one stage and report it.
+    pub async fn stage(
+        &self,
+        name: &str,
+        run: impl Future<Output = eyre::Result<String>>,
+    ) -> eyre::Result<()> {
+        if self.format == OutputFormat::Text {
+            let out = run.await?;
+            println!("{out}");
+            return Ok(());
+        }
+
+        emit(serde_json::json!({
+            "event": "started",
+            "stage": name,
+            "timestamp": unix_millis(),
+        }));
+        let started = Instant::now();
+        let result = run.await;
+        let duration_ms = started.elapsed().as_millis() as u64;
+
+        let log_path = self.log_dir.join(format!("{name}.log"));
+        let log = match &result {
+            Ok(out) => out.clone(),
+            Err(e) => format!("{e:?}"),
+        };
+        let log_path = match std::fs::write(&log_path, log) {
+            Ok(()) => Some(log_path.display().to_string()),
+            Err(_) => None,
+        };
+
+        emit(serde_json::json!({
+            "event": "finished",
+            "stage": name,
+            "timestamp": unix_millis(),
+            "duration_ms": duration_ms,
+            "status": if result.is_ok() { "success" } else { "failure" },
+            "exit_code": match &result {
+                Ok(_) => Some(0),
+                Err(e) => exit_code(&e.to_string()),
+            },
+            "log_path": log_path,
+            "error": result.as_ref().err().map(|e| e.to_string()),
+        }));
+        result.map(|_| ())
+    }
+}
+
+fn emit(event: serde_json::Value) {
+    println!("{event}");
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Exit code from a Dagger exec failure (`... exit code: 101`).
+fn exit_code(error: &str) -> Option<i32> {
+    let (_, rest) = error.split_once("exit code: ")?;
+    rest.split(|c: char| !c.is_ascii_digit() && c != '-')
+        .next()?
+        .parse()
+        .ok()
+}
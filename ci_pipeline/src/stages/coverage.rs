@@ -0,0 +1,60 @@
+use dagger_sdk::{Directory, Query};
+
+use crate::config::PipelineConfig;
+use crate::containers;
+
+/// Run `cargo llvm-cov --workspace` against a fresh PostgreSQL database and
+/// export `lcov.info` and the HTML report to `output` on the host.
+pub async fn run(client: &Query, config: &PipelineConfig, source: Directory, output: &str) -> eyre::Result<String> {
+    let pg = containers::postgres(client);
+    let db_url = "postgres://erp:erp_password@db:5432/erp_test";
+
+    let coverage = containers::rust_base(client, config, source)
+        .with_service_binding("db", pg)
+        .with_env_variable("DATABASE_URL", db_url)
+        .with_exec(vec![
+            "sh", "-c",
+            "for i in $(seq 1 30); do pg_isready -h db -p 5432 -U erp && break; sleep 1; done",
+        ])
+        .with_exec(vec!["rustup", "component", "add", "llvm-tools-preview"])
+        .with_exec(vec!["cargo", "install", "cargo-llvm-cov", "--locked"])
+        .with_exec(vec![
+            "cargo", "llvm-cov", "--workspace",
+            "--lcov", "--output-path", "/app/coverage/lcov.info",
+        ])
+        .with_exec(vec![
+            "cargo", "llvm-cov", "report",
+            "--html", "--output-dir", "/app/coverage",
+        ]);
+
+    let summary = coverage
+        .with_exec(vec!["cargo", "llvm-cov", "report", "--summary-only"])
+        .stdout()
+        .await?;
+
+    coverage
+        .file("/app/coverage/lcov.info")
+        .export(format!("{output}/lcov.info"))
+        .await?;
+    coverage
+        .directory("/app/coverage/html")
+        .export(format!("{output}/html"))
+        .await?;
+
+    let total = line_coverage(&summary).unwrap_or_else(|| "unknown".to_string());
+    Ok(format!(
+        "[coverage] Total line coverage: {total} (lcov + HTML in {output}/)\n{summary}"
+    ))
+}
+
+/// Line coverage from the `TOTAL` row of the llvm-cov summary table, whose
+/// percentage columns are regions, functions, then lines.
+fn line_coverage(summary: &str) -> Option<String> {
+    summary
+        .lines()
+        .find(|line| line.starts_with("TOTAL"))?
+        .split_whitespace()
+        .filter(|col| col.ends_with('%'))
+        .nth(2)
+        .map(str::to_string)
+}
@@ -1,60 +1,162 @@
-use dagger_sdk::{Directory, Query};
-
-/// Deploy to dev server via SSH (rsync + build + restart).
-/// Requires SSHPASS environment variable.
-pub async fn run(client: &Query, source: Directory, host: &str) -> eyre::Result<String> {
-    let password = std::env::var("SSHPASS").unwrap_or_default();
-    if password.is_empty() {
-        return Err(eyre::eyre!("SSHPASS environment variable not set"));
+use dagger_sdk::{Container, Directory, Query};
+
+use crate::config::DeployTarget;
+
+/// Shell prologue that installs the deploy key and the pinned host keys and
+/// defines `$SSH` and `health_check`. Hosts whose key is not pinned are
+/// refused.
+const SSH_SETUP: &str = r#"
+set -euo pipefail
+
+mkdir -p ~/.ssh && chmod 700 ~/.ssh
+printf '%s\n' "$SSH_PRIVATE_KEY" > ~/.ssh/id_deploy && chmod 600 ~/.ssh/id_deploy
+printf '%s\n' "$SSH_KNOWN_HOSTS" > ~/.ssh/known_hosts
+SSH="ssh -i $HOME/.ssh/id_deploy -o IdentitiesOnly=yes -o BatchMode=yes -o StrictHostKeyChecking=yes"
+
+health_check() {
+    for i in $(seq 1 10); do
+        curl -sf "$1" > /dev/null && return 0
+        sleep 3
+    done
+    return 1
+}
+"#;
+
+/// Debian container with the SSH tooling, the deploy key from
+/// `SSH_PRIVATE_KEY` as a Dagger secret, and the target's host keys: its
+/// `known_hosts` in `ci.toml`, else `SSH_KNOWN_HOSTS`.
+fn ssh_base(client: &Query, target: &DeployTarget) -> eyre::Result<Container> {
+    let key = std::env::var("SSH_PRIVATE_KEY").unwrap_or_default();
+    if key.is_empty() {
+        return Err(eyre::eyre!("SSH_PRIVATE_KEY environment variable not set"));
     }
+    let known_hosts = target
+        .known_hosts
+        .clone()
+        .or_else(|| std::env::var("SSH_KNOWN_HOSTS").ok())
+        .filter(|hosts| !hosts.trim().is_empty())
+        .ok_or_else(|| {
+            eyre::eyre!(
+                "no pinned host keys: set SSH_KNOWN_HOSTS or the target's known_hosts in ci.toml"
+            )
+        })?;
+
+    let container = client
+        .container()
+        .from("debian:bookworm-slim")
+        .with_exec(vec!["apt-get", "update"])
+        .with_exec(vec![
+            "apt-get", "install", "-y",
+            "rsync", "openssh-client", "curl",
+        ])
+        .with_secret_variable("SSH_PRIVATE_KEY", client.set_secret("ssh-private-key", key))
+        .with_env_variable("SSH_KNOWN_HOSTS", known_hosts);
+    Ok(container)
+}
 
-    let ssh_password = client.set_secret("ssh-password", password);
+/// Deploy to one host of a target via SSH (rsync + build + install +
+/// restart). The running binary is kept as `erp-server.bak`; if the
+/// install or the health check fails afterwards it is restored, when there
+/// is one, the service is started again, and the deploy fails.
+pub async fn run(
+    client: &Query,
+    source: Directory,
+    target: &DeployTarget,
+    host: &str,
+) -> eyre::Result<String> {
+    let DeployTarget { user, service, install_dir, .. } = target;
+    let health_url = target.health_url.replace("{host}", host);
 
     let script = format!(
-        r#"
-set -e
-
-SSH_OPTS="-o StrictHostKeyChecking=no -o PreferredAuthentications=password -o PubkeyAuthentication=no"
+        r#"{SSH_SETUP}
+REMOTE="{user}@{host}"
+SRC='$HOME/rust-erp-dev/rust-erp'
 
 echo "[1/4] Syncing source..."
 rsync -az --delete \
     --exclude='.git' --exclude='target' --exclude='node_modules' \
-    -e "sshpass -e ssh $SSH_OPTS -p 22" \
-    /deploy/source/ ubuntu-server@{host}:rust-erp-dev/rust-erp/
+    -e "$SSH" \
+    /deploy/source/ "$REMOTE:rust-erp-dev/rust-erp/"
 
 echo "[2/4] Building on server..."
-sshpass -e ssh $SSH_OPTS -p 22 ubuntu-server@{host} \
-    'source $HOME/.cargo/env && cd $HOME/rust-erp-dev/rust-erp && cargo build --release 2>&1 | tail -5'
+$SSH "$REMOTE" "source \$HOME/.cargo/env && cd $SRC && cargo build --release 2>&1 | tail -5"
 
-echo "[3/4] Deploying binary + static..."
-sshpass -e ssh $SSH_OPTS -p 22 ubuntu-server@{host} \
-    'echo $SSHPASS | sudo -S systemctl stop erp.service && \
-     echo $SSHPASS | sudo -S cp $HOME/rust-erp-dev/rust-erp/target/release/erp-server /opt/rust-erp/erp-server && \
-     echo $SSHPASS | sudo -S cp -r $HOME/rust-erp-dev/rust-erp/erp_web/static/* /opt/rust-erp/erp_web/static/ && \
-     echo $SSHPASS | sudo -S systemctl start erp.service'
+echo "[3/4] Deploying binary + static (previous binary kept as erp-server.bak)..."
+# Once the service is stopped, a failed step puts the previous binary back
+# and starts the service again.
+$SSH "$REMOTE" bash -s <<'INSTALL'
+SRC="$HOME/rust-erp-dev/rust-erp"
+backed_up=0
+restore() {{
+    echo "Install failed; restarting {service} on the previous binary..."
+    if [ "$backed_up" = 1 ]; then
+        sudo -n cp -p {install_dir}/erp-server.bak {install_dir}/erp-server
+    fi
+    sudo -n systemctl start {service}
+    exit 1
+}}
+sudo -n systemctl stop {service} || exit 1
+if [ -f {install_dir}/erp-server ]; then
+    sudo -n cp -p {install_dir}/erp-server {install_dir}/erp-server.bak || restore
+    backed_up=1
+fi
+sudo -n cp "$SRC/target/release/erp-server" {install_dir}/erp-server || restore
+sudo -n cp -r "$SRC"/erp_web/static/* {install_dir}/erp_web/static/ || restore
+sudo -n systemctl start {service} || restore
+INSTALL
 
 echo "[4/4] Health check..."
-sleep 3
-curl -sf http://{host}:9089/health || echo "Warning: health check failed"
+if ! health_check "{health_url}"; then
+    if ! $SSH "$REMOTE" "test -f {install_dir}/erp-server.bak"; then
+        echo "Health check failed; no erp-server.bak to roll back to (first deploy)."
+        exit 1
+    fi
+    echo "Health check failed; rolling back to erp-server.bak..."
+    $SSH "$REMOTE" "sudo -n systemctl stop {service}; \
+        sudo -n cp -p {install_dir}/erp-server.bak {install_dir}/erp-server; \
+        sudo -n systemctl start {service}"
+    health_check "{health_url}" && echo "Rollback healthy." || echo "Warning: rollback health check failed"
+    exit 1
+fi
 echo "Deploy complete."
-"#,
-        host = host
+"#
     );
 
-    let output = client
-        .container()
-        .from("debian:bookworm-slim")
-        .with_exec(vec!["apt-get", "update"])
-        .with_exec(vec![
-            "apt-get", "install", "-y",
-            "sshpass", "rsync", "openssh-client", "curl",
-        ])
-        .with_secret_variable("SSHPASS", ssh_password)
+    let output = ssh_base(client, target)?
         .with_workdir("/deploy")
         .with_directory("/deploy/source", source)
-        .with_exec(vec!["sh", "-c", script.as_str()])
+        .with_exec(vec!["bash", "-c", script.as_str()])
+        .stdout()
+        .await?;
+
+    Ok(format!("[deploy] {host}: {output}"))
+}
+
+/// Restore `erp-server.bak` on one host of a target and restart the service.
+pub async fn rollback(client: &Query, target: &DeployTarget, host: &str) -> eyre::Result<String> {
+    let DeployTarget { user, service, install_dir, .. } = target;
+    let health_url = target.health_url.replace("{host}", host);
+
+    let script = format!(
+        r#"{SSH_SETUP}
+REMOTE="{user}@{host}"
+
+echo "[1/2] Restoring erp-server.bak..."
+$SSH "$REMOTE" "test -f {install_dir}/erp-server.bak && \
+    sudo -n cp -p {install_dir}/erp-server {install_dir}/erp-server.failed && \
+    sudo -n systemctl stop {service} && \
+    {{ sudo -n cp -p {install_dir}/erp-server.bak {install_dir}/erp-server; sudo -n systemctl start {service}; }}"
+
+echo "[2/2] Health check..."
+health_check "{health_url}" || {{ echo "Rollback health check failed"; exit 1; }}
+echo "Rollback complete."
+"#
+    );
+
+    let output = ssh_base(client, target)?
+        .with_exec(vec!["bash", "-c", script.as_str()])
         .stdout()
         .await?;
 
-    Ok(format!("[deploy] {output}"))
+    Ok(format!("[rollback] {host}: {output}"))
 }
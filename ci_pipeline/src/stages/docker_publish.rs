@@ -0,0 +1,95 @@
+use dagger_sdk::{Directory, Query};
+
+use crate::config::PipelineConfig;
+use crate::containers;
+
+/// Build the `erp-server` image and push it tagged with the commit SHA and
+/// the branch. The image goes to `CI_REGISTRY_IMAGE` (e.g.
+/// `ghcr.io/centrixsystems/erp-server`); `CI_REGISTRY_USER` and
+/// `CI_REGISTRY_PASSWORD`, when set, authenticate the push. `sha` and
+/// `branch` default to the `CI_COMMIT`/`CI_BRANCH` of the running build.
+pub async fn run(
+    client: &Query,
+    config: &PipelineConfig,
+    source: Directory,
+    sha: Option<String>,
+    branch: Option<String>,
+) -> eyre::Result<String> {
+    let image = std::env::var("CI_REGISTRY_IMAGE").unwrap_or_default();
+    if image.is_empty() {
+        return Err(eyre::eyre!("CI_REGISTRY_IMAGE environment variable not set"));
+    }
+    let sha = sha
+        .or_else(|| std::env::var("CI_COMMIT").ok())
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| eyre::eyre!("--sha not given and CI_COMMIT not set"))?;
+    let branch = branch
+        .or_else(|| std::env::var("CI_BRANCH").ok())
+        .filter(|b| !b.is_empty())
+        .ok_or_else(|| eyre::eyre!("--branch not given and CI_BRANCH not set"))?;
+
+    let build = containers::rust_base(client, config, source.clone())
+        .with_exec(vec![
+            "cargo", "build", "--release", "--package", "erp_server",
+        ])
+        // The target dir is a cache mount; copy the binary out so it can be
+        // taken into the runtime image.
+        .with_exec(vec!["cp", "/app/target/release/erp-server", "/erp-server"]);
+
+    let mut runtime = client
+        .container()
+        .from("debian:bookworm-slim")
+        .with_exec(vec!["apt-get", "update"])
+        .with_exec(vec![
+            "apt-get", "install", "-y", "--no-install-recommends",
+            "libpq5", "ca-certificates",
+        ])
+        .with_exec(vec!["rm", "-rf", "/var/lib/apt/lists"])
+        .with_file("/opt/rust-erp/erp-server", build.file("/erp-server"))
+        .with_directory("/opt/rust-erp/erp_web/static", source.directory("erp_web/static"))
+        .with_workdir("/opt/rust-erp")
+        .with_env_variable("STATIC_DIR", "/opt/rust-erp/erp_web/static")
+        .with_label("org.opencontainers.image.revision", sha.as_str())
+        .with_entrypoint(vec!["/opt/rust-erp/erp-server"]);
+
+    let user = std::env::var("CI_REGISTRY_USER").unwrap_or_default();
+    let password = std::env::var("CI_REGISTRY_PASSWORD").unwrap_or_default();
+    if !user.is_empty() && !password.is_empty() {
+        let registry = registry_host(&image);
+        let secret = client.set_secret("registry-password", password);
+        runtime = runtime.with_registry_auth(registry, user, secret);
+    }
+
+    let mut published = Vec::new();
+    for tag in [sha.clone(), tag_for_branch(&branch, &sha)] {
+        let reference = runtime.publish(format!("{image}:{tag}")).await?;
+        published.push(reference);
+    }
+
+    Ok(format!("[docker-publish] Pushed:\n{}", published.join("\n")))
+}
+
+/// Docker tags allow `[A-Za-z0-9_.-]`, at most 128 characters, and must not
+/// start with `.` or `-`; `feature/x` becomes `feature-x`. A branch with
+/// nothing left of it is tagged with the short `sha` instead.
+fn tag_for_branch(branch: &str, sha: &str) -> String {
+    let tag: String = branch
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "_.-".contains(c) { c } else { '-' })
+        .take(128)
+        .collect();
+    match tag.trim_start_matches(['.', '-']) {
+        "" => sha.chars().take(7).collect(),
+        tag => tag.to_string(),
+    }
+}
+
+/// Registry `image` is pushed to. As with `docker push`, the first path
+/// component only names one when it looks like a host (`ghcr.io`,
+/// `registry:5000`, `localhost`); `library/foo` goes to Docker Hub.
+fn registry_host(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((host, _)) if host.contains(['.', ':']) || host == "localhost" => host,
+        _ => "docker.io",
+    }
+}
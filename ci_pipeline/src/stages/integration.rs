@@ -1,10 +1,11 @@
 use dagger_sdk::{Directory, Query};
 
+use crate::config::PipelineConfig;
 use crate::containers;
 
 /// Run module lifecycle integration test against a fresh PostgreSQL database.
 /// Flow: migrate -> seed -> install base -> install todo_list -> verify -> uninstall -> verify cleanup
-pub async fn run(client: &Query, source: Directory) -> eyre::Result<String> {
+pub async fn run(client: &Query, config: &PipelineConfig, source: Directory) -> eyre::Result<String> {
     let pg = containers::postgres(client);
     let db_url = "postgres://erp:erp_password@db:5432/erp_test";
 
@@ -50,7 +51,7 @@ echo ""
 echo "=== Integration Test Complete ==="
 "#;
 
-    let output = containers::rust_base(client, source)
+    let output = containers::rust_base(client, config, source)
         .with_service_binding("db", pg)
         .with_env_variable("DATABASE_URL", db_url)
         .with_env_variable("RUST_LOG", "info")
@@ -1,14 +1,16 @@
 use dagger_sdk::{Directory, Query};
 
+use crate::config::PipelineConfig;
 use crate::containers;
 
-/// Run `cargo clippy` with correctness errors and all warnings.
-pub async fn run(client: &Query, source: Directory) -> eyre::Result<String> {
-    let output = containers::rust_base(client, source)
-        .with_exec(vec![
-            "cargo", "clippy", "--workspace", "--lib",
-            "--", "-D", "clippy::correctness", "-W", "clippy::all",
-        ])
+/// Run `cargo clippy` with the `[lint] clippy_args` from `ci.toml`
+/// (correctness errors and all warnings by default).
+pub async fn run(client: &Query, config: &PipelineConfig, source: Directory) -> eyre::Result<String> {
+    let mut args = vec!["cargo", "clippy", "--workspace", "--lib", "--"];
+    args.extend(config.lint.clippy_args.iter().map(String::as_str));
+
+    let output = containers::rust_base(client, config, source)
+        .with_exec(args)
         .stdout()
         .await?;
 
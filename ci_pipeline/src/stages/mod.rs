@@ -1,9 +1,12 @@
 pub mod check;
+pub mod coverage;
 pub mod deploy;
+pub mod docker_publish;
 pub mod fmt;
 pub mod integration;
 pub mod lint;
 pub mod module_lint;
+pub mod release;
 pub mod security;
 pub mod tailwind;
 pub mod test;
@@ -1,9 +1,10 @@
 use dagger_sdk::{Directory, Query};
 
+use crate::config::PipelineConfig;
 use crate::containers;
 
 /// Validate module manifests, XML data files, and code patterns.
-pub async fn run(client: &Query, source: Directory) -> eyre::Result<String> {
+pub async fn run(client: &Query, config: &PipelineConfig, source: Directory) -> eyre::Result<String> {
     let script = r#"
 set -euo pipefail
 
@@ -88,7 +89,7 @@ if [ $ERRORS -gt 0 ]; then
 fi
 "#;
 
-    let output = containers::rust_base(client, source)
+    let output = containers::rust_base(client, config, source)
         .with_exec(vec!["apt-get", "install", "-y", "libxml2-utils"])
         .with_exec(vec!["bash", "-c", script])
         .stdout()
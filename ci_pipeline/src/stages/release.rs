@@ -0,0 +1,155 @@
+use std::process::Command;
+
+use dagger_sdk::{Directory, Query};
+
+use crate::config::PipelineConfig;
+use crate::containers;
+
+/// Build release binaries for `targets`, checksum them, and upload them to
+/// the GitHub release of `tag` along with a changelog of the commits since
+/// the previous tag. Requires `GITHUB_TOKEN` (contents: write) and the repo
+/// as `--repo` or `GITHUB_REPOSITORY`.
+///
+/// `source_path` is the host checkout; the tag and changelog come from its
+/// git history, which is not part of the uploaded source directory.
+/// Targets other than the host's need their C dependencies (libpq) in the
+/// build image.
+pub async fn run(
+    client: &Query,
+    config: &PipelineConfig,
+    source: Directory,
+    source_path: &str,
+    tag: Option<String>,
+    targets: Vec<String>,
+    repo: Option<String>,
+) -> eyre::Result<String> {
+    let token = std::env::var("GITHUB_TOKEN").unwrap_or_default();
+    if token.is_empty() {
+        return Err(eyre::eyre!("GITHUB_TOKEN environment variable not set"));
+    }
+    let repo = repo
+        .or_else(|| std::env::var("GITHUB_REPOSITORY").ok())
+        .filter(|r| !r.is_empty())
+        .ok_or_else(|| eyre::eyre!("--repo not given and GITHUB_REPOSITORY not set"))?;
+    let tag = match tag {
+        Some(tag) => tag,
+        None => git(source_path, &["describe", "--tags", "--exact-match", "HEAD"])
+            .map_err(|e| eyre::eyre!("--tag not given and HEAD is not tagged: {e}"))?,
+    };
+    let targets = if targets.is_empty() {
+        vec!["x86_64-unknown-linux-gnu".to_string()]
+    } else {
+        targets
+    };
+    let notes = changelog(source_path, &tag)?;
+
+    let mut build = containers::rust_base(client, config, source)
+        .with_env_variable("CARGO_PROFILE_RELEASE_LTO", "true")
+        .with_env_variable("CARGO_PROFILE_RELEASE_CODEGEN_UNITS", "1")
+        .with_env_variable("CARGO_PROFILE_RELEASE_STRIP", "true")
+        .with_exec(vec!["mkdir", "-p", "/dist"]);
+    for target in &targets {
+        if let Some((package, linker_var, linker)) = cross_linker(target) {
+            build = build
+                .with_exec(vec!["apt-get", "install", "-y", package])
+                .with_env_variable(linker_var, linker);
+        }
+        let archive = format!("/dist/erp-server-{tag}-{target}.tar.gz");
+        let release_dir = format!("/app/target/{target}/release");
+        build = build
+            .with_exec(vec!["rustup", "target", "add", target.as_str()])
+            .with_exec(vec![
+                "cargo", "build", "--release", "--package", "erp_server",
+                "--target", target.as_str(),
+            ])
+            .with_exec(vec![
+                "tar", "czf", archive.as_str(), "-C", release_dir.as_str(), "erp-server",
+            ]);
+    }
+    let dist = build
+        .with_workdir("/dist")
+        .with_exec(vec!["sh", "-c", "sha256sum *.tar.gz > SHA256SUMS"])
+        .directory("/dist");
+
+    let script = format!(
+        r#"
+set -euo pipefail
+
+API="https://api.github.com/repos/{repo}"
+AUTH="Authorization: Bearer $GITHUB_TOKEN"
+
+echo "[1/2] Creating release {tag}..."
+jq -n --arg tag '{tag}' --rawfile body /release/notes.md \
+    '{{tag_name: $tag, name: $tag, body: $body}}' > /release/request.json
+RELEASE=$(curl -sf -X POST -H "$AUTH" -H "Accept: application/vnd.github+json" \
+    -d @/release/request.json "$API/releases" \
+    || curl -sf -H "$AUTH" "$API/releases/tags/{tag}")
+UPLOAD_URL=$(echo "$RELEASE" | jq -r '.upload_url' | sed 's/{{.*}}//')
+
+echo "[2/2] Uploading assets..."
+for asset in /dist/*; do
+    name=$(basename "$asset")
+    curl -sf -X POST -H "$AUTH" -H "Content-Type: application/octet-stream" \
+        --data-binary @"$asset" "$UPLOAD_URL?name=$name" > /dev/null
+    echo "  $name"
+done
+
+echo "Release complete: $(echo "$RELEASE" | jq -r '.html_url')"
+"#
+    );
+
+    let output = client
+        .container()
+        .from("debian:bookworm-slim")
+        .with_exec(vec!["apt-get", "update"])
+        .with_exec(vec!["apt-get", "install", "-y", "curl", "jq", "ca-certificates"])
+        .with_secret_variable("GITHUB_TOKEN", client.set_secret("github-token", token))
+        .with_directory("/dist", dist)
+        .with_new_file("/release/notes.md", notes.as_str())
+        .with_exec(vec!["bash", "-c", script.as_str()])
+        .stdout()
+        .await?;
+
+    Ok(format!("[release] {tag} ({}):\n{notes}\n{output}", targets.join(", ")))
+}
+
+/// Changelog section for `tag`: one line per commit since the previous tag,
+/// or since the start of history for the first release.
+fn changelog(source_path: &str, tag: &str) -> eyre::Result<String> {
+    let range = match git(source_path, &["describe", "--tags", "--abbrev=0", &format!("{tag}^")]) {
+        Ok(previous) => format!("{previous}..{tag}"),
+        Err(_) => tag.to_string(),
+    };
+    let log = git(source_path, &["log", "--no-merges", "--format=- %s (%h)", &range])?;
+    Ok(format!("## {tag}\n\n{log}\n"))
+}
+
+fn git(source_path: &str, args: &[&str]) -> eyre::Result<String> {
+    let output = Command::new("git").arg("-C").arg(source_path).args(args).output()?;
+    if !output.status.success() {
+        return Err(eyre::eyre!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Cross toolchain package and linker for targets the bookworm image can
+/// reach with Debian's cross compilers.
+fn cross_linker(target: &str) -> Option<(&'static str, &'static str, &'static str)> {
+    match target {
+        "aarch64-unknown-linux-gnu" => Some((
+            "gcc-aarch64-linux-gnu",
+            "CARGO_TARGET_AARCH64_UNKNOWN_LINUX_GNU_LINKER",
+            "aarch64-linux-gnu-gcc",
+        )),
+        "x86_64-unknown-linux-musl" => Some((
+            "musl-tools",
+            "CARGO_TARGET_X86_64_UNKNOWN_LINUX_MUSL_LINKER",
+            "musl-gcc",
+        )),
+        _ => None,
+    }
+}
@@ -1,10 +1,11 @@
 use dagger_sdk::{Directory, Query};
 
+use crate::config::PipelineConfig;
 use crate::containers;
 
 /// Run `cargo audit` to check for known vulnerabilities.
-pub async fn run(client: &Query, source: Directory) -> eyre::Result<String> {
-    let output = containers::rust_base(client, source)
+pub async fn run(client: &Query, config: &PipelineConfig, source: Directory) -> eyre::Result<String> {
+    let output = containers::rust_base(client, config, source)
         .with_exec(vec!["cargo", "install", "cargo-audit"])
         .with_exec(vec!["cargo", "audit"])
         .stdout()
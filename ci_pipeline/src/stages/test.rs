@@ -1,11 +1,19 @@
 use dagger_sdk::{Directory, Query};
 
+use crate::config::PipelineConfig;
 use crate::containers;
 
-/// Run `cargo test --workspace --lib` unit tests.
-pub async fn run(client: &Query, source: Directory) -> eyre::Result<String> {
-    let output = containers::rust_base(client, source)
-        .with_exec(vec!["cargo", "test", "--workspace", "--lib"])
+/// Run `cargo test --workspace --lib` unit tests, with `[test] threads`
+/// from `ci.toml` if set.
+pub async fn run(client: &Query, config: &PipelineConfig, source: Directory) -> eyre::Result<String> {
+    let threads = config.test.threads.map(|n| n.to_string());
+    let mut args = vec!["cargo", "test", "--workspace", "--lib"];
+    if let Some(ref threads) = threads {
+        args.extend(["--", "--test-threads", threads.as_str()]);
+    }
+
+    let output = containers::rust_base(client, config, source)
+        .with_exec(args)
         .stdout()
         .await?;
 
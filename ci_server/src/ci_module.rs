@@ -9,7 +9,7 @@ use erp_core::orm::{Environment, ModelHandlerRegistry, ModelSourcingRegistry};
 
 /// SQL migration for CI platform tables.
 ///
-/// Creates all 8 tables for the generic CI platform with tenant_id for RLS.
+/// Creates the tables for the generic CI platform with tenant_id for RLS.
 pub const MIGRATION_SQL: &str = r#"
 -- ================================================================
 -- CI Platform Tables (generic, pipeline-agnostic)
@@ -32,6 +32,19 @@ CREATE TABLE IF NOT EXISTS ci_projects (
 CREATE INDEX IF NOT EXISTS idx_ci_projects_repo ON ci_projects (github_repo);
 CREATE INDEX IF NOT EXISTS idx_ci_projects_tenant ON ci_projects (tenant_id);
 
+-- private: session/token only; internal: any signed-in user; public: anyone
+ALTER TABLE ci_projects ADD COLUMN IF NOT EXISTS visibility VARCHAR(16) NOT NULL DEFAULT 'private'
+    CHECK (visibility IN ('private', 'internal', 'public'));
+
+-- Where the repo is hosted; Forgejo counts as gitea. A repo name is unique per provider.
+ALTER TABLE ci_projects ADD COLUMN IF NOT EXISTS provider VARCHAR(16) NOT NULL DEFAULT 'github'
+    CHECK (provider IN ('github', 'gitea'));
+ALTER TABLE ci_projects DROP CONSTRAINT IF EXISTS ci_projects_github_repo_key;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_ci_projects_provider_repo ON ci_projects (provider, github_repo);
+
+-- Build every commit of a push instead of only its head.
+ALTER TABLE ci_projects ADD COLUMN IF NOT EXISTS build_all_commits BOOLEAN NOT NULL DEFAULT FALSE;
+
 CREATE TABLE IF NOT EXISTS ci_triggers (
     id              BIGSERIAL PRIMARY KEY,
     tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
@@ -45,6 +58,15 @@ CREATE TABLE IF NOT EXISTS ci_triggers (
     write_date      TIMESTAMPTZ DEFAULT NOW()
 );
 
+ALTER TABLE ci_triggers ADD COLUMN IF NOT EXISTS cron_expr VARCHAR(128);
+ALTER TABLE ci_triggers ADD COLUMN IF NOT EXISTS last_fired_at TIMESTAMPTZ;
+ALTER TABLE ci_triggers ADD COLUMN IF NOT EXISTS env JSONB;
+ALTER TABLE ci_triggers ADD COLUMN IF NOT EXISTS paths TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE ci_triggers ADD COLUMN IF NOT EXISTS paths_ignore TEXT[] NOT NULL DEFAULT '{}';
+-- Pull request filters: skip drafts; require one of the labels (empty: any PR)
+ALTER TABLE ci_triggers ADD COLUMN IF NOT EXISTS skip_drafts BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE ci_triggers ADD COLUMN IF NOT EXISTS labels TEXT[] NOT NULL DEFAULT '{}';
+
 CREATE TABLE IF NOT EXISTS ci_builds (
     id              BIGSERIAL PRIMARY KEY,
     tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
@@ -75,6 +97,20 @@ CREATE INDEX IF NOT EXISTS idx_ci_builds_created ON ci_builds (create_date DESC)
 CREATE INDEX IF NOT EXISTS idx_ci_builds_project ON ci_builds (project_id);
 CREATE INDEX IF NOT EXISTS idx_ci_builds_tenant ON ci_builds (tenant_id);
 
+-- W3C traceparent of the span that created the build (webhook, API call)
+ALTER TABLE ci_builds ADD COLUMN IF NOT EXISTS trace_context VARCHAR(64);
+ALTER TABLE ci_builds ADD COLUMN IF NOT EXISTS cancel_requested_at TIMESTAMPTZ;
+ALTER TABLE ci_builds ADD COLUMN IF NOT EXISTS trigger_id BIGINT REFERENCES ci_triggers(id) ON DELETE SET NULL;
+-- Whether the build's code may see the project's secrets: false for pull
+-- requests from forks or outside contributors until a maintainer approves
+ALTER TABLE ci_builds ADD COLUMN IF NOT EXISTS trusted BOOLEAN NOT NULL DEFAULT TRUE;
+-- Full-text search over commit message, author and branch (not in schema.rs;
+-- queried with raw SQL)
+ALTER TABLE ci_builds ADD COLUMN IF NOT EXISTS search_vector TSVECTOR
+    GENERATED ALWAYS AS (to_tsvector('simple'::regconfig,
+        COALESCE(message, '') || ' ' || COALESCE(author, '') || ' ' || branch)) STORED;
+CREATE INDEX IF NOT EXISTS idx_ci_builds_search ON ci_builds USING GIN (search_vector);
+
 CREATE TABLE IF NOT EXISTS ci_build_steps (
     id              BIGSERIAL PRIMARY KEY,
     tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
@@ -97,6 +133,9 @@ CREATE TABLE IF NOT EXISTS ci_build_steps (
 
 CREATE INDEX IF NOT EXISTS idx_ci_build_steps_build ON ci_build_steps (build_id);
 
+ALTER TABLE ci_build_steps ADD COLUMN IF NOT EXISTS attempt INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE ci_build_steps ADD COLUMN IF NOT EXISTS max_rss_bytes BIGINT;
+
 CREATE TABLE IF NOT EXISTS ci_environments (
     id              BIGSERIAL PRIMARY KEY,
     tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
@@ -116,9 +155,17 @@ CREATE TABLE IF NOT EXISTS ci_environments (
     write_date      TIMESTAMPTZ DEFAULT NOW()
 );
 
+ALTER TABLE ci_environments ADD COLUMN IF NOT EXISTS destroyed_reason VARCHAR(32);
+ALTER TABLE ci_environments ADD COLUMN IF NOT EXISTS port INTEGER;
+ALTER TABLE ci_environments ADD COLUMN IF NOT EXISTS subdomain VARCHAR(63);
+ALTER TABLE ci_environments ADD COLUMN IF NOT EXISTS error TEXT;
+ALTER TABLE ci_environments ADD COLUMN IF NOT EXISTS restart_count INTEGER NOT NULL DEFAULT 0;
+
 CREATE INDEX IF NOT EXISTS idx_ci_environments_status ON ci_environments (status);
 CREATE INDEX IF NOT EXISTS idx_ci_environments_project ON ci_environments (project_id);
 CREATE INDEX IF NOT EXISTS idx_ci_environments_tenant ON ci_environments (tenant_id);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_ci_environments_port ON ci_environments (port) WHERE port IS NOT NULL;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_ci_environments_subdomain ON ci_environments (subdomain) WHERE subdomain IS NOT NULL;
 
 CREATE TABLE IF NOT EXISTS ci_errors (
     id              BIGSERIAL PRIMARY KEY,
@@ -148,6 +195,18 @@ CREATE TABLE IF NOT EXISTS ci_errors (
 CREATE INDEX IF NOT EXISTS idx_ci_errors_fingerprint ON ci_errors (fingerprint);
 CREATE INDEX IF NOT EXISTS idx_ci_errors_status ON ci_errors (status);
 CREATE INDEX IF NOT EXISTS idx_ci_errors_tenant ON ci_errors (tenant_id);
+-- Full-text search over error titles, see ci_builds.search_vector
+ALTER TABLE ci_errors ADD COLUMN IF NOT EXISTS search_vector TSVECTOR
+    GENERATED ALWAYS AS (to_tsvector('simple'::regconfig, title)) STORED;
+CREATE INDEX IF NOT EXISTS idx_ci_errors_search ON ci_errors USING GIN (search_vector);
+-- Near-duplicate clustering: the cluster's root error (NULL for a root) and
+-- the MinHash signature of normalized_text it was matched by
+ALTER TABLE ci_errors ADD COLUMN IF NOT EXISTS cluster_id BIGINT REFERENCES ci_errors(id) ON DELETE SET NULL;
+ALTER TABLE ci_errors ADD COLUMN IF NOT EXISTS minhash BIGINT[];
+CREATE INDEX IF NOT EXISTS idx_ci_errors_cluster ON ci_errors (cluster_id);
+-- CODEOWNERS owners of the file the error was last seen in
+ALTER TABLE ci_errors ADD COLUMN IF NOT EXISTS owners TEXT[];
+CREATE INDEX IF NOT EXISTS idx_ci_errors_owners ON ci_errors USING GIN (owners);
 
 CREATE TABLE IF NOT EXISTS ci_error_occurrences (
     id              BIGSERIAL PRIMARY KEY,
@@ -163,6 +222,35 @@ CREATE TABLE IF NOT EXISTS ci_error_occurrences (
     write_date      TIMESTAMPTZ DEFAULT NOW()
 );
 
+-- Where this occurrence points in the build's sources
+ALTER TABLE ci_error_occurrences ADD COLUMN IF NOT EXISTS file_path VARCHAR(500);
+ALTER TABLE ci_error_occurrences ADD COLUMN IF NOT EXISTS line_number INTEGER;
+CREATE INDEX IF NOT EXISTS idx_ci_error_occurrences_build ON ci_error_occurrences (build_id);
+CREATE INDEX IF NOT EXISTS idx_ci_error_occurrences_error ON ci_error_occurrences (error_id);
+
+-- Mute rules: error output matching a rule is not recorded
+CREATE TABLE IF NOT EXISTS ci_error_rules (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    -- NULL applies to every project of the tenant
+    project_id      BIGINT REFERENCES ci_projects(id) ON DELETE CASCADE,
+    -- Regex over the error text; NULL matches any text
+    pattern         VARCHAR(500),
+    -- NULL matches any category
+    category        VARCHAR(32),
+    reason          TEXT,
+    hit_count       INTEGER NOT NULL DEFAULT 0,
+    last_hit_at     TIMESTAMPTZ,
+    active          BOOLEAN NOT NULL DEFAULT TRUE,
+    create_uid      BIGINT,
+    create_date     TIMESTAMPTZ DEFAULT NOW(),
+    write_uid       BIGINT,
+    write_date      TIMESTAMPTZ DEFAULT NOW(),
+    CHECK (pattern IS NOT NULL OR category IS NOT NULL)
+);
+
+CREATE INDEX IF NOT EXISTS idx_ci_error_rules_tenant ON ci_error_rules (tenant_id);
+
 CREATE TABLE IF NOT EXISTS ci_artifacts (
     id              BIGSERIAL PRIMARY KEY,
     tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
@@ -179,6 +267,363 @@ CREATE TABLE IF NOT EXISTS ci_artifacts (
 );
 
 CREATE INDEX IF NOT EXISTS idx_ci_artifacts_build ON ci_artifacts (build_id);
+
+-- Transactional outbox: external side effects (GitHub statuses, PR comments,
+-- notifications) are written in the same transaction as the state change and
+-- delivered by the outbox worker.
+CREATE TABLE IF NOT EXISTS ci_outbox (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    kind            VARCHAR(64) NOT NULL,
+    payload         JSONB NOT NULL,
+    status          VARCHAR(16) NOT NULL DEFAULT 'pending',
+    attempts        INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_error      TEXT,
+    processed_at    TIMESTAMPTZ,
+    active          BOOLEAN NOT NULL DEFAULT TRUE,
+    create_uid      BIGINT,
+    create_date     TIMESTAMPTZ DEFAULT NOW(),
+    write_uid       BIGINT,
+    write_date      TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_ci_outbox_due ON ci_outbox (next_attempt_at) WHERE status = 'pending';
+
+ALTER TABLE ci_outbox ADD COLUMN IF NOT EXISTS trace_context VARCHAR(64);
+
+-- Event streams for event-sourced aggregates (builds, environments) and
+-- periodic snapshots that bound rehydration cost.
+CREATE TABLE IF NOT EXISTS ci_events (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    aggregate_type  VARCHAR(32) NOT NULL,
+    aggregate_id    BIGINT NOT NULL,
+    sequence        INTEGER NOT NULL,
+    payload         JSONB NOT NULL,
+    create_date     TIMESTAMPTZ DEFAULT NOW(),
+    UNIQUE (aggregate_type, aggregate_id, sequence)
+);
+
+CREATE TABLE IF NOT EXISTS ci_aggregate_snapshots (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    aggregate_type  VARCHAR(32) NOT NULL,
+    aggregate_id    BIGINT NOT NULL,
+    sequence        INTEGER NOT NULL,
+    state           JSONB NOT NULL,
+    create_date     TIMESTAMPTZ DEFAULT NOW(),
+    write_date      TIMESTAMPTZ DEFAULT NOW(),
+    UNIQUE (aggregate_type, aggregate_id)
+);
+
+-- Per-tenant limits; NULL means unlimited.
+CREATE TABLE IF NOT EXISTS ci_tenant_quotas (
+    id                    BIGSERIAL PRIMARY KEY,
+    tenant_id             UUID NOT NULL UNIQUE,
+    max_builds_per_day    INTEGER,
+    max_concurrent_builds INTEGER,
+    max_environments      INTEGER,
+    active                BOOLEAN NOT NULL DEFAULT TRUE,
+    create_uid            BIGINT,
+    create_date           TIMESTAMPTZ DEFAULT NOW(),
+    write_uid             BIGINT,
+    write_date            TIMESTAMPTZ DEFAULT NOW()
+);
+
+-- API tokens for external automation. Only the SHA-256 of the token is
+-- stored; project_id NULL means every project of the tenant.
+CREATE TABLE IF NOT EXISTS ci_api_tokens (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    project_id      BIGINT REFERENCES ci_projects(id) ON DELETE CASCADE,
+    uid             BIGINT NOT NULL,
+    name            VARCHAR(255) NOT NULL,
+    token_prefix    VARCHAR(16) NOT NULL,
+    token_hash      VARCHAR(64) NOT NULL UNIQUE,
+    expires_at      TIMESTAMPTZ,
+    last_used_at    TIMESTAMPTZ,
+    revoked_at      TIMESTAMPTZ,
+    rotated_to_id   BIGINT REFERENCES ci_api_tokens(id),
+    active          BOOLEAN NOT NULL DEFAULT TRUE,
+    create_uid      BIGINT,
+    create_date     TIMESTAMPTZ DEFAULT NOW(),
+    write_uid       BIGINT,
+    write_date      TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_ci_api_tokens_tenant ON ci_api_tokens (tenant_id);
+
+CREATE TABLE IF NOT EXISTS ci_command_policies (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    name            VARCHAR(255) NOT NULL,
+    allow_patterns  TEXT[] NOT NULL DEFAULT '{}',
+    deny_patterns   TEXT[] NOT NULL DEFAULT '{}',
+    banned_env_vars TEXT[] NOT NULL DEFAULT '{}',
+    max_steps       INT,
+    active          BOOLEAN NOT NULL DEFAULT TRUE,
+    create_uid      BIGINT,
+    create_date     TIMESTAMPTZ DEFAULT NOW(),
+    write_uid       BIGINT,
+    write_date      TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_ci_command_policies_tenant ON ci_command_policies (tenant_id);
+
+CREATE TABLE IF NOT EXISTS ci_secrets (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    project_id      BIGINT REFERENCES ci_projects(id) ON DELETE CASCADE,
+    name            VARCHAR(255) NOT NULL,
+    ciphertext      BYTEA NOT NULL,
+    nonce           BYTEA NOT NULL,
+    wrapped_key     BYTEA NOT NULL,
+    key_nonce       BYTEA NOT NULL,
+    master_key_id   VARCHAR(16) NOT NULL,
+    active          BOOLEAN NOT NULL DEFAULT TRUE,
+    create_uid      BIGINT,
+    create_date     TIMESTAMPTZ DEFAULT NOW(),
+    write_uid       BIGINT,
+    write_date      TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_ci_secrets_scope_name
+    ON ci_secrets (tenant_id, COALESCE(project_id, 0), name);
+CREATE INDEX IF NOT EXISTS idx_ci_secrets_master_key ON ci_secrets (master_key_id);
+
+CREATE TABLE IF NOT EXISTS ci_secret_access_log (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    secret_id       BIGINT REFERENCES ci_secrets(id) ON DELETE SET NULL,
+    secret_name     VARCHAR(255) NOT NULL,
+    project_id      BIGINT NOT NULL,
+    build_id        BIGINT NOT NULL REFERENCES ci_builds(id) ON DELETE CASCADE,
+    step_name       VARCHAR(255) NOT NULL,
+    create_date     TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_ci_secret_access_log_name
+    ON ci_secret_access_log (tenant_id, secret_name, create_date DESC);
+CREATE INDEX IF NOT EXISTS idx_ci_secret_access_log_build ON ci_secret_access_log (build_id);
+
+CREATE TABLE IF NOT EXISTS ci_vulnerabilities (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    build_id        BIGINT NOT NULL REFERENCES ci_builds(id) ON DELETE CASCADE,
+    project_id      BIGINT NOT NULL REFERENCES ci_projects(id) ON DELETE CASCADE,
+    source          VARCHAR(32) NOT NULL,
+    advisory_id     VARCHAR(255) NOT NULL,
+    package         VARCHAR(255) NOT NULL,
+    version         VARCHAR(255),
+    severity        VARCHAR(16) NOT NULL DEFAULT 'unknown',
+    title           TEXT,
+    active          BOOLEAN NOT NULL DEFAULT TRUE,
+    create_uid      BIGINT,
+    create_date     TIMESTAMPTZ DEFAULT NOW(),
+    write_uid       BIGINT,
+    write_date      TIMESTAMPTZ DEFAULT NOW(),
+    UNIQUE (build_id, advisory_id, package)
+);
+
+CREATE INDEX IF NOT EXISTS idx_ci_vulnerabilities_project ON ci_vulnerabilities (project_id, build_id);
+
+CREATE TABLE IF NOT EXISTS ci_project_permissions (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    project_id      BIGINT NOT NULL REFERENCES ci_projects(id) ON DELETE CASCADE,
+    action          VARCHAR(16) NOT NULL CHECK (action IN ('trigger', 'cancel', 'retry', 'approve')),
+    user_id         BIGINT,
+    group_id        BIGINT,
+    active          BOOLEAN NOT NULL DEFAULT TRUE,
+    create_uid      BIGINT,
+    create_date     TIMESTAMPTZ DEFAULT NOW(),
+    write_uid       BIGINT,
+    write_date      TIMESTAMPTZ DEFAULT NOW(),
+    CHECK (user_id IS NOT NULL OR group_id IS NOT NULL)
+);
+
+CREATE INDEX IF NOT EXISTS idx_ci_project_permissions_project ON ci_project_permissions (project_id, action);
+
+CREATE TABLE IF NOT EXISTS ci_build_audit (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    project_id      BIGINT NOT NULL REFERENCES ci_projects(id) ON DELETE CASCADE,
+    build_id        BIGINT REFERENCES ci_builds(id) ON DELETE SET NULL,
+    action          VARCHAR(16) NOT NULL,
+    uid             BIGINT NOT NULL,
+    token_id        BIGINT,
+    create_date     TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_ci_build_audit_project ON ci_build_audit (project_id, create_date DESC);
+
+CREATE TABLE IF NOT EXISTS ci_alert_rules (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    project_id      BIGINT REFERENCES ci_projects(id) ON DELETE CASCADE,
+    name            VARCHAR(255) NOT NULL,
+    -- failure_rate: threshold is a percentage; queue_age: seconds
+    kind            VARCHAR(32) NOT NULL CHECK (kind IN ('failure_rate', 'queue_age')),
+    threshold       DOUBLE PRECISION NOT NULL,
+    window_minutes  INTEGER NOT NULL DEFAULT 30,
+    min_builds      INTEGER NOT NULL DEFAULT 5,
+    cooldown_minutes INTEGER NOT NULL DEFAULT 60,
+    webhook_url     TEXT,
+    firing          BOOLEAN NOT NULL DEFAULT FALSE,
+    last_fired_at   TIMESTAMPTZ,
+    active          BOOLEAN NOT NULL DEFAULT TRUE,
+    create_uid      BIGINT,
+    create_date     TIMESTAMPTZ DEFAULT NOW(),
+    write_uid       BIGINT,
+    write_date      TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_ci_alert_rules_tenant ON ci_alert_rules (tenant_id);
+
+CREATE TABLE IF NOT EXISTS ci_notification_endpoints (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    project_id      BIGINT REFERENCES ci_projects(id) ON DELETE CASCADE,
+    url             TEXT NOT NULL,
+    -- HMAC-SHA256 key for the X-Centrix-Signature-256 header
+    secret          VARCHAR(255) NOT NULL,
+    -- Event names to send; empty sends every event
+    events          TEXT[] NOT NULL DEFAULT '{}',
+    active          BOOLEAN NOT NULL DEFAULT TRUE,
+    create_uid      BIGINT,
+    create_date     TIMESTAMPTZ DEFAULT NOW(),
+    write_uid       BIGINT,
+    write_date      TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_ci_notification_endpoints_tenant ON ci_notification_endpoints (tenant_id);
+
+CREATE TABLE IF NOT EXISTS ci_notification_deliveries (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    endpoint_id     BIGINT NOT NULL REFERENCES ci_notification_endpoints(id) ON DELETE CASCADE,
+    outbox_id       BIGINT NOT NULL,
+    event           VARCHAR(64) NOT NULL,
+    attempt         INTEGER NOT NULL,
+    -- NULL when no response was received
+    status_code     INTEGER,
+    error           TEXT,
+    duration_ms     INTEGER NOT NULL,
+    create_date     TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_ci_notification_deliveries_endpoint ON ci_notification_deliveries (endpoint_id, create_date DESC);
+
+CREATE TABLE IF NOT EXISTS ci_notification_channels (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    project_id      BIGINT REFERENCES ci_projects(id) ON DELETE CASCADE,
+    name            VARCHAR(255) NOT NULL,
+    -- Message format: discord, or generic ({"text": ...}) for Mattermost, Teams, ...
+    kind            VARCHAR(32) NOT NULL,
+    -- Incoming webhook of the chat; carries its token
+    webhook_url     TEXT NOT NULL,
+    -- Event names to post; empty posts every event
+    events          TEXT[] NOT NULL DEFAULT '{}',
+    active          BOOLEAN NOT NULL DEFAULT TRUE,
+    create_uid      BIGINT,
+    create_date     TIMESTAMPTZ DEFAULT NOW(),
+    write_uid       BIGINT,
+    write_date      TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_ci_notification_channels_tenant ON ci_notification_channels (tenant_id);
+
+-- Digest of the channel's projects: daily, weekly or none. A digest channel
+-- without events gets no per-event messages.
+ALTER TABLE ci_notification_channels ADD COLUMN IF NOT EXISTS digest VARCHAR(16);
+ALTER TABLE ci_notification_channels ADD COLUMN IF NOT EXISTS last_digest_at TIMESTAMPTZ;
+
+CREATE TABLE IF NOT EXISTS ci_notification_rules (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    project_id      BIGINT REFERENCES ci_projects(id) ON DELETE CASCADE,
+    channel_id      BIGINT NOT NULL REFERENCES ci_notification_channels(id) ON DELETE CASCADE,
+    name            VARCHAR(255) NOT NULL,
+    -- Glob or re: pattern, as for triggers; NULL covers every branch
+    branch_pattern  VARCHAR(255),
+    -- Build event names; empty covers every build event
+    events          TEXT[] NOT NULL DEFAULT '{}',
+    -- Minimum minutes between two messages of the rule; 0 sends each one
+    throttle_minutes INTEGER NOT NULL DEFAULT 0,
+    last_notified_at TIMESTAMPTZ,
+    active          BOOLEAN NOT NULL DEFAULT TRUE,
+    create_uid      BIGINT,
+    create_date     TIMESTAMPTZ DEFAULT NOW(),
+    write_uid       BIGINT,
+    write_date      TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_ci_notification_rules_tenant ON ci_notification_rules (tenant_id);
+CREATE INDEX IF NOT EXISTS idx_ci_notification_rules_channel ON ci_notification_rules (channel_id);
+
+-- Only fire when the branch turns red (first failure) or green (recovery)
+ALTER TABLE ci_notification_rules ADD COLUMN IF NOT EXISTS on_transition BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Per-test outcomes parsed from step output, for slowest-test reporting
+CREATE TABLE IF NOT EXISTS ci_test_results (
+    id              BIGSERIAL PRIMARY KEY,
+    tenant_id       UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000001',
+    project_id      BIGINT NOT NULL REFERENCES ci_projects(id) ON DELETE CASCADE,
+    build_id        BIGINT NOT NULL REFERENCES ci_builds(id) ON DELETE CASCADE,
+    step_id         BIGINT NOT NULL REFERENCES ci_build_steps(id) ON DELETE CASCADE,
+    step_name       VARCHAR(255) NOT NULL,
+    name            TEXT NOT NULL,
+    status          VARCHAR(16) NOT NULL,
+    duration_ms     INT,
+    create_date     TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_ci_test_results_tenant ON ci_test_results (tenant_id);
+CREATE INDEX IF NOT EXISTS idx_ci_test_results_build ON ci_test_results (build_id);
+CREATE INDEX IF NOT EXISTS idx_ci_test_results_project_name
+    ON ci_test_results (project_id, name, create_date);
+
+-- ================================================================
+-- Row-level security (keep last; add new ci_* tables to the array)
+-- ================================================================
+--
+-- Rows are visible when their tenant matches `centrix.tenant_id`. API
+-- handlers set it from the session; background workers (executor, outbox,
+-- webhooks) set it to '*', system context, which sees every tenant. A
+-- connection without it sees nothing.
+
+CREATE OR REPLACE FUNCTION ci_tenant_visible(row_tenant UUID) RETURNS BOOLEAN AS $$
+    SELECT COALESCE(current_setting('centrix.tenant_id', true) IN ('*', row_tenant::text), false)
+$$ LANGUAGE sql STABLE;
+
+DO $$
+DECLARE
+    t TEXT;
+BEGIN
+    FOREACH t IN ARRAY ARRAY[
+        'ci_projects', 'ci_triggers', 'ci_builds', 'ci_build_steps',
+        'ci_environments', 'ci_errors', 'ci_error_occurrences', 'ci_error_rules', 'ci_artifacts',
+        'ci_outbox', 'ci_events', 'ci_aggregate_snapshots', 'ci_tenant_quotas',
+        'ci_api_tokens', 'ci_command_policies', 'ci_secrets', 'ci_secret_access_log',
+        'ci_vulnerabilities', 'ci_project_permissions', 'ci_build_audit',
+        'ci_alert_rules', 'ci_notification_endpoints', 'ci_notification_deliveries',
+        'ci_notification_channels', 'ci_notification_rules', 'ci_test_results'
+    ] LOOP
+        EXECUTE format('ALTER TABLE %I ENABLE ROW LEVEL SECURITY', t);
+        EXECUTE format('ALTER TABLE %I FORCE ROW LEVEL SECURITY', t);
+        IF NOT EXISTS (
+            SELECT 1 FROM pg_policies WHERE tablename = t AND policyname = 'ci_tenant_isolation'
+        ) THEN
+            EXECUTE format(
+                'CREATE POLICY ci_tenant_isolation ON %I '
+                'USING (ci_tenant_visible(tenant_id)) WITH CHECK (ci_tenant_visible(tenant_id))',
+                t
+            );
+        END IF;
+    END LOOP;
+END $$;
 "#;
 
 /// Run CI platform migration.
@@ -227,13 +672,14 @@ impl Module for CiModule {
         _registry: &ModelHandlerRegistry,
         model_sourcing: &ModelSourcingRegistry,
     ) {
-        // CI models are infrastructure (DirectCrud, no event sourcing)
+        // CI models are infrastructure (DirectCrud, no event sourcing).
+        // Builds and environments are not: their state changes are events
+        // in `ci_events` (see `events`), appended by the CI API and workers,
+        // so the ORM must not write them behind the event stream's back.
         let direct_crud_models = [
             "ci.project",
             "ci.trigger",
-            "ci.build",
             "ci.build.step",
-            "ci.environment",
             "ci.error",
             "ci.error.occurrence",
             "ci.artifact",
@@ -1,15 +1,49 @@
 //! CI platform configuration — loaded from environment variables.
 
+use std::sync::Arc;
+
+use crate::services::github_service::GithubClient;
+use crate::services::oidc_service::OidcSigner;
+use crate::services::secret_service::Keyring;
+use crate::telemetry::OtelConfig;
+
 #[derive(Clone, Debug)]
 pub struct CiConfig {
     /// GitHub webhook secret for HMAC validation.
     pub github_webhook_secret: String,
-    /// GitHub personal access token for API calls.
-    pub github_token: String,
+    /// GitHub API client, holding the access token of each repository.
+    pub github: Arc<GithubClient>,
+    /// Report builds as check runs with inline annotations instead of
+    /// commit statuses. The GitHub tokens must then be GitHub App tokens.
+    pub github_checks: bool,
+    /// Base URL of the Gitea/Forgejo instance hosting `gitea` projects.
+    pub gitea_url: Option<String>,
+    /// Gitea webhook secret for HMAC validation.
+    pub gitea_webhook_secret: String,
+    /// Gitea access token for commit statuses and PR files.
+    pub gitea_token: String,
+    /// Tenant that projects are created in for webhooks from unregistered
+    /// repos. `None` ignores such webhooks. Only set when webhooks are
+    /// signed and [`auto_onboard_owners`](Self::auto_onboard_owners) is not
+    /// empty.
+    pub auto_onboard_tenant: Option<uuid::Uuid>,
+    /// Owners (`acme`) or repos (`acme/site`), lowercase, that
+    /// auto-onboarding registers; webhooks of any other repo are ignored.
+    pub auto_onboard_owners: Vec<String>,
     /// Throttle window in seconds between duplicate builds.
     pub throttle_window_secs: u64,
+    /// Commit message markers that record a push as skipped instead of
+    /// building it, matched case-insensitively.
+    pub skip_ci_patterns: Vec<String>,
+    /// Most commits of one push built for `build_all_commits` projects;
+    /// older ones are left out.
+    pub max_push_commits: usize,
+    /// Largest artifact accepted by the upload API, in bytes.
+    pub max_artifact_bytes: usize,
     /// Maximum number of concurrent builds across all projects.
     pub max_concurrent_builds: usize,
+    /// Maximum steps of one build running at once.
+    pub max_parallel_steps: usize,
     /// Dashboard base URL for GitHub status links.
     pub dashboard_url: String,
     /// Maximum running ephemeral environments.
@@ -22,22 +56,130 @@ pub struct CiConfig {
     pub dormant_ttl_days: i64,
     /// Minutes of inactivity before environment goes dormant.
     pub idle_timeout_min: i64,
+    /// Directory holding the Docker Compose project of each review
+    /// environment, on the same filesystem as `workspace_dir`. `None`
+    /// disables provisioning.
+    pub env_dir: Option<String>,
+    /// Host review environments are published on, each at its allocated
+    /// port.
+    pub env_host: String,
+    /// Host ports allocated to review environments.
+    pub env_ports: std::ops::RangeInclusive<u16>,
+    /// Wildcard domain under which each review environment gets a
+    /// subdomain. `None` makes `env_host:port` their URL.
+    pub env_domain: Option<String>,
+    /// Port the app container of a review environment listens on.
+    pub env_app_port: u16,
+    /// Image of the database each review environment gets to itself.
+    pub env_db_image: String,
+    /// Directory of the database snapshots review environments start
+    /// from. `None` disables snapshots.
+    pub env_snapshot_dir: Option<String>,
+    /// Seconds between health checks of a running review environment.
+    pub env_health_interval_secs: u64,
+    /// Restarts of an unhealthy review environment before it is failed.
+    pub env_max_restarts: i32,
+    /// Hour of the day (UTC) digests are sent at; weekly ones on Mondays.
+    pub digest_hour: u32,
+    /// Name this server's executor reports as in runner metrics.
+    pub runner_name: String,
     /// Directory for build workspaces (cloned repos, temp files).
     pub workspace_dir: String,
+    /// Directory for build cache archives kept between builds.
+    pub cache_dir: String,
+    /// Run steps sandboxed unless the project's pipeline opts out.
+    pub sandbox_by_default: bool,
+    /// Path to the bubblewrap binary used for sandboxed steps.
+    pub sandbox_bwrap: String,
+    /// Container runtime (`docker` or `podman`) for sandboxed steps that
+    /// name an image.
+    pub container_runtime: String,
+    /// `ci_pipeline` binary run for `engine: "dagger"` pipelines.
+    pub dagger_bin: String,
+    /// cgroup v2 directory delegated to the server; each host step runs in
+    /// a child cgroup of it. `None` falls back to rlimits.
+    pub cgroup_root: Option<String>,
+    /// Master keys for secrets at rest. `None` disables secrets.
+    pub secrets_keyring: Option<Arc<Keyring>>,
+    /// Signer for build step OIDC tokens. `None` disables `CI_OIDC_TOKEN`.
+    pub oidc_signer: Option<Arc<OidcSigner>>,
+    /// Share of finished builds expected to succeed; SLO burn rates are
+    /// measured against the remaining error budget.
+    pub slo_target: f64,
+    /// Successful default-branch builds without an occurrence after which
+    /// an error is resolved automatically. 0 disables auto-resolution.
+    pub error_auto_resolve_builds: u32,
+    /// Requests a minute allowed per API token (or client IP without one)
+    /// on `/ci/api`. 0 disables the limit.
+    pub api_rate_limit: u32,
+    /// Requests a minute allowed per client IP on `/ci/webhook/*`. 0
+    /// disables the limit.
+    pub webhook_rate_limit: u32,
+    /// Take the client IP for rate limiting from `X-Forwarded-For`, for
+    /// servers behind a reverse proxy.
+    pub rate_limit_trust_forwarded: bool,
+    /// Bearer token that may scrape all of `/ci/metrics`. Without it the
+    /// endpoint is open to anonymous scrapers.
+    pub metrics_token: Option<String>,
+    /// OTLP trace export. The subscriber is installed from the same
+    /// settings before this config is loaded.
+    pub otel: OtelConfig,
 }
 
 impl CiConfig {
     pub fn from_env() -> Self {
         let github_webhook_secret = std::env::var("CI_WEBHOOK_SECRET").unwrap_or_default();
         let github_token = std::env::var("CI_GITHUB_TOKEN").unwrap_or_default();
+        // Per-owner or per-repo tokens, e.g. `acme=ghp_..,acme/site=ghp_..`.
+        let github_tokens = std::env::var("CI_GITHUB_TOKENS").unwrap_or_default();
+        let github = GithubClient::new(github_token, &github_tokens)
+            .map(Arc::new)
+            .expect("invalid CI_GITHUB_TOKENS");
+        let github_checks = std::env::var("CI_GITHUB_CHECKS")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+        let gitea_url = std::env::var("CI_GITEA_URL").ok().map(|u| u.trim_end_matches('/').to_string()).filter(|u| !u.is_empty());
+        let gitea_webhook_secret = std::env::var("CI_GITEA_WEBHOOK_SECRET").unwrap_or_default();
+        let gitea_token = std::env::var("CI_GITEA_TOKEN").unwrap_or_default();
+        let mut auto_onboard_tenant: Option<uuid::Uuid> = std::env::var("CI_AUTO_ONBOARD_TENANT")
+            .ok()
+            .filter(|t| !t.is_empty())
+            .map(|t| t.parse().expect("invalid CI_AUTO_ONBOARD_TENANT"));
+        let auto_onboard_owners: Vec<String> = std::env::var("CI_AUTO_ONBOARD_OWNERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|o| o.trim().trim_end_matches('/').to_lowercase())
+            .filter(|o| !o.is_empty())
+            .collect();
         let throttle_window_secs = std::env::var("CI_THROTTLE_WINDOW")
             .ok()
             .and_then(|s| s.parse().ok())
             .unwrap_or(60);
+        let skip_ci_patterns = std::env::var("CI_SKIP_PATTERNS")
+            .unwrap_or_else(|_| "[skip ci],[ci skip]".to_string())
+            .split(',')
+            .map(|p| p.trim().to_lowercase())
+            .filter(|p| !p.is_empty())
+            .collect();
+        let max_push_commits = std::env::var("CI_MAX_PUSH_COMMITS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(20);
+        let max_artifact_bytes = std::env::var("CI_MAX_ARTIFACT_BYTES")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(10 * 1024 * 1024);
         let max_concurrent_builds = std::env::var("CI_MAX_CONCURRENT")
             .ok()
             .and_then(|s| s.parse().ok())
             .unwrap_or(1);
+        let max_parallel_steps = std::env::var("CI_MAX_PARALLEL_STEPS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(4);
         let dashboard_url =
             std::env::var("CI_DASHBOARD_URL").unwrap_or_else(|_| "http://localhost:9090/ci".to_string());
         let max_running_envs = std::env::var("CI_MAX_RUNNING_ENVS")
@@ -60,28 +202,156 @@ impl CiConfig {
             .ok()
             .and_then(|s| s.parse().ok())
             .unwrap_or(60);
+        let env_dir = std::env::var("CI_ENV_DIR").ok().filter(|d| !d.is_empty());
+        let env_host = std::env::var("CI_ENV_HOST").unwrap_or_else(|_| "localhost".to_string());
+        let env_ports = std::env::var("CI_ENV_PORTS")
+            .ok()
+            .and_then(|s| {
+                let (start, end) = s.split_once('-')?;
+                Some(start.trim().parse::<u16>().ok()?..=end.trim().parse().ok()?)
+            })
+            .filter(|ports| !ports.is_empty())
+            .unwrap_or(20000..=20999);
+        let env_domain = std::env::var("CI_ENV_DOMAIN").ok().map(|d| d.trim_start_matches("*.").trim_end_matches('.').to_string()).filter(|d| !d.is_empty());
+        let env_app_port = std::env::var("CI_ENV_APP_PORT")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(8080);
+        let env_db_image = std::env::var("CI_ENV_DB_IMAGE").unwrap_or_else(|_| "postgres:16-alpine".to_string());
+        let env_snapshot_dir = std::env::var("CI_ENV_SNAPSHOT_DIR").ok().filter(|d| !d.is_empty());
+        let env_health_interval_secs = std::env::var("CI_ENV_HEALTH_INTERVAL_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(60);
+        let env_max_restarts = std::env::var("CI_ENV_MAX_RESTARTS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|&n| n >= 0)
+            .unwrap_or(3);
+        let digest_hour = std::env::var("CI_DIGEST_HOUR")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|&h| h < 24)
+            .unwrap_or(8);
+        let runner_name = std::env::var("CI_RUNNER_NAME")
+            .or_else(|_| std::env::var("HOSTNAME"))
+            .unwrap_or_else(|_| "local".to_string());
         let workspace_dir = std::env::var("CI_WORKSPACE_DIR")
             .unwrap_or_else(|_| "/tmp/ci-workspace".to_string());
+        let cache_dir = std::env::var("CI_CACHE_DIR").unwrap_or_else(|_| "/tmp/ci-cache".to_string());
+        let sandbox_by_default = std::env::var("CI_SANDBOX_DEFAULT")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+        let sandbox_bwrap = std::env::var("CI_SANDBOX_BWRAP").unwrap_or_else(|_| "bwrap".to_string());
+        let container_runtime = std::env::var("CI_CONTAINER_RUNTIME").unwrap_or_else(|_| "docker".to_string());
+        let dagger_bin = std::env::var("CI_DAGGER_BIN").unwrap_or_else(|_| "centrix-ci".to_string());
+        let cgroup_root = std::env::var("CI_CGROUP_ROOT").ok().filter(|r| !r.is_empty());
+        let secrets_keyring = Keyring::from_env()
+            .expect("invalid CI_SECRETS_MASTER_KEY / CI_SECRETS_PREVIOUS_KEYS")
+            .map(Arc::new);
+        let oidc_issuer = std::env::var("CI_OIDC_ISSUER").unwrap_or_else(|_| dashboard_url.clone());
+        let oidc_signer = OidcSigner::from_env(oidc_issuer)
+            .expect("invalid CI_OIDC_SIGNING_KEY / CI_OIDC_SIGNING_KEY_FILE")
+            .map(Arc::new);
+
+        let slo_target = std::env::var("CI_SLO_TARGET")
+            .ok()
+            .and_then(|s| s.parse::<f64>().ok())
+            .filter(|t| *t > 0.0 && *t < 1.0)
+            .unwrap_or(0.95);
+        let metrics_token = std::env::var("CI_METRICS_TOKEN").ok().filter(|t| !t.is_empty());
+        let error_auto_resolve_builds = std::env::var("CI_ERROR_AUTO_RESOLVE_BUILDS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(10);
+        let api_rate_limit = std::env::var("CI_RATE_LIMIT_API")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(600);
+        let webhook_rate_limit = std::env::var("CI_RATE_LIMIT_WEBHOOK")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(120);
+        let rate_limit_trust_forwarded = std::env::var("CI_RATE_LIMIT_TRUST_FORWARDED")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
 
         if github_webhook_secret.is_empty() {
             tracing::warn!("CI_WEBHOOK_SECRET not set -- webhook signature validation disabled");
         }
-        if github_token.is_empty() {
+        // Unsigned webhooks would let anyone register repos.
+        if auto_onboard_tenant.is_some() {
+            let signed = !github_webhook_secret.is_empty() || (gitea_url.is_some() && !gitea_webhook_secret.is_empty());
+            if !signed {
+                tracing::error!("CI_AUTO_ONBOARD_TENANT set without a webhook secret -- auto-onboarding disabled");
+                auto_onboard_tenant = None;
+            } else if auto_onboard_owners.is_empty() {
+                tracing::error!("CI_AUTO_ONBOARD_TENANT set without CI_AUTO_ONBOARD_OWNERS -- auto-onboarding disabled");
+                auto_onboard_tenant = None;
+            }
+        }
+        if !github.has_token() {
             tracing::warn!("CI_GITHUB_TOKEN not set -- GitHub status updates disabled");
         }
+        if gitea_url.is_some() && gitea_webhook_secret.is_empty() {
+            tracing::warn!("CI_GITEA_WEBHOOK_SECRET not set -- Gitea webhook signature validation disabled");
+        }
+        if secrets_keyring.is_none() {
+            tracing::warn!("CI_SECRETS_MASTER_KEY not set -- build secrets disabled");
+        }
+        if metrics_token.is_none() {
+            tracing::warn!("CI_METRICS_TOKEN not set -- /ci/metrics is readable without authentication");
+        }
 
         Self {
             github_webhook_secret,
-            github_token,
+            github,
+            github_checks,
+            gitea_url,
+            gitea_webhook_secret,
+            gitea_token,
+            auto_onboard_tenant,
+            auto_onboard_owners,
             throttle_window_secs,
+            skip_ci_patterns,
+            max_push_commits,
+            max_artifact_bytes,
             max_concurrent_builds,
+            max_parallel_steps,
             dashboard_url,
             max_running_envs,
             max_envs_per_pr,
             max_envs_global,
             dormant_ttl_days,
             idle_timeout_min,
+            env_dir,
+            env_host,
+            env_ports,
+            env_domain,
+            env_app_port,
+            env_db_image,
+            env_snapshot_dir,
+            env_health_interval_secs,
+            env_max_restarts,
+            digest_hour,
+            runner_name,
             workspace_dir,
+            cache_dir,
+            sandbox_by_default,
+            sandbox_bwrap,
+            container_runtime,
+            dagger_bin,
+            cgroup_root,
+            secrets_keyring,
+            oidc_signer,
+            slo_target,
+            error_auto_resolve_builds,
+            api_rate_limit,
+            webhook_rate_limit,
+            rate_limit_trust_forwarded,
+            metrics_token,
+            otel: OtelConfig::from_env(),
         }
     }
 }
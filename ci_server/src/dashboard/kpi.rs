@@ -1,7 +1,18 @@
 //! KPI queries for the CI dashboard.
+//!
+//! Build KPIs take an optional `trigger_event` (`push`, `pull_request`,
+//! `manual`, `schedule`) to separate e.g. scheduled from webhook builds,
+//! and an optional `project_id` and `branch` for a single project's (or
+//! branch's) numbers; all `None` aggregates across every build.
+//!
+//! [`query_error_trends`] charts code quality instead: how many errors
+//! were new and how many recurring, per day or week and per category.
+//!
+//! [`query_slowest_tests`] and [`query_test_trend`] read the per-test
+//! results parsed from step output (`ci_test_results`) of one project.
 
 use diesel::prelude::*;
-use diesel::sql_types::{BigInt, Double, Nullable, Text};
+use diesel::sql_types::{BigInt, Double, Integer, Nullable, Text, Timestamptz};
 use diesel_async::{AsyncPgConnection, RunQueryDsl};
 use serde::Serialize;
 
@@ -19,6 +30,9 @@ pub struct BuildSuccessRate {
 pub async fn query_success_rate(
     conn: &mut AsyncPgConnection,
     days: i32,
+    trigger_event: Option<&str>,
+    project_id: Option<i64>,
+    branch: Option<&str>,
 ) -> anyhow::Result<BuildSuccessRate> {
     let result = diesel::sql_query(format!(
         "SELECT \
@@ -27,8 +41,14 @@ pub async fn query_success_rate(
             COALESCE(COUNT(*) FILTER (WHERE status = 'success')::float / NULLIF(COUNT(*), 0), 0) AS rate \
          FROM ci_builds \
          WHERE create_date >= NOW() - INTERVAL '{days} days' \
+           AND ($1::text IS NULL OR trigger_event = $1) \
+           AND ($2::bigint IS NULL OR project_id = $2) \
+           AND ($3::text IS NULL OR branch = $3) \
            AND status IN ('success', 'failure')"
     ))
+    .bind::<Nullable<Text>, _>(trigger_event)
+    .bind::<Nullable<BigInt>, _>(project_id)
+    .bind::<Nullable<Text>, _>(branch)
     .get_result(conn)
     .await?;
     Ok(result)
@@ -46,6 +66,9 @@ pub struct AvgBuildDuration {
 pub async fn query_avg_duration(
     conn: &mut AsyncPgConnection,
     days: i32,
+    trigger_event: Option<&str>,
+    project_id: Option<i64>,
+    branch: Option<&str>,
 ) -> anyhow::Result<AvgBuildDuration> {
     let result = diesel::sql_query(format!(
         "SELECT \
@@ -53,8 +76,14 @@ pub async fn query_avg_duration(
             COUNT(*) AS count \
          FROM ci_builds \
          WHERE create_date >= NOW() - INTERVAL '{days} days' \
+           AND ($1::text IS NULL OR trigger_event = $1) \
+           AND ($2::bigint IS NULL OR project_id = $2) \
+           AND ($3::text IS NULL OR branch = $3) \
            AND duration_ms IS NOT NULL"
     ))
+    .bind::<Nullable<Text>, _>(trigger_event)
+    .bind::<Nullable<BigInt>, _>(project_id)
+    .bind::<Nullable<Text>, _>(branch)
     .get_result(conn)
     .await?;
     Ok(result)
@@ -101,14 +130,239 @@ pub struct BuildsByStatus {
 pub async fn query_builds_by_status(
     conn: &mut AsyncPgConnection,
     days: i32,
+    trigger_event: Option<&str>,
+    project_id: Option<i64>,
+    branch: Option<&str>,
 ) -> anyhow::Result<Vec<BuildsByStatus>> {
     let results = diesel::sql_query(format!(
         "SELECT status, COUNT(*) AS count \
          FROM ci_builds \
          WHERE create_date >= NOW() - INTERVAL '{days} days' \
+           AND ($1::text IS NULL OR trigger_event = $1) \
+           AND ($2::bigint IS NULL OR project_id = $2) \
+           AND ($3::text IS NULL OR branch = $3) \
          GROUP BY status \
          ORDER BY count DESC"
     ))
+    .bind::<Nullable<Text>, _>(trigger_event)
+    .bind::<Nullable<BigInt>, _>(project_id)
+    .bind::<Nullable<Text>, _>(branch)
+    .load(conn)
+    .await?;
+    Ok(results)
+}
+
+/// Width of the buckets of an error trend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrendBucket {
+    Day,
+    Week,
+}
+
+impl TrendBucket {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Day => "day",
+            Self::Week => "week",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "day" => Some(Self::Day),
+            "week" => Some(Self::Week),
+            _ => None,
+        }
+    }
+}
+
+/// Errors seen in a bucket: `new` ones were first seen in it, `recurring`
+/// ones before it. `occurrences` counts every occurrence of either.
+#[derive(Debug, Default, Serialize)]
+pub struct TrendCounts {
+    pub new: i64,
+    pub recurring: i64,
+    pub occurrences: i64,
+}
+
+/// One bucket of an error trend, in total and by category.
+#[derive(Debug, Serialize)]
+pub struct ErrorTrendPoint {
+    pub bucket_start: chrono::DateTime<chrono::Utc>,
+    #[serde(flatten)]
+    pub total: TrendCounts,
+    pub categories: std::collections::BTreeMap<String, TrendCounts>,
+}
+
+#[derive(QueryableByName)]
+struct ErrorTrendRow {
+    #[diesel(sql_type = Timestamptz)]
+    bucket_start: chrono::DateTime<chrono::Utc>,
+    #[diesel(sql_type = Nullable<Text>)]
+    category: Option<String>,
+    #[diesel(sql_type = BigInt)]
+    new_errors: i64,
+    #[diesel(sql_type = BigInt)]
+    recurring_errors: i64,
+    #[diesel(sql_type = BigInt)]
+    occurrences: i64,
+}
+
+/// New vs recurring errors per bucket over N days, oldest bucket first.
+/// Buckets without errors are included, so the series has no gaps. An
+/// error is counted once per bucket and category however often it occurred.
+pub async fn query_error_trends(
+    conn: &mut AsyncPgConnection,
+    days: i32,
+    bucket: TrendBucket,
+    project_id: Option<i64>,
+) -> anyhow::Result<Vec<ErrorTrendPoint>> {
+    let unit = bucket.as_str();
+    let rows: Vec<ErrorTrendRow> = diesel::sql_query(format!(
+        "WITH buckets AS ( \
+             SELECT generate_series( \
+                 date_trunc('{unit}', NOW() - INTERVAL '{days} days'), \
+                 date_trunc('{unit}', NOW()), \
+                 INTERVAL '1 {unit}') AS bucket_start \
+         ), hits AS ( \
+             SELECT date_trunc('{unit}', o.create_date) AS bucket_start, \
+                    e.id, e.category, e.first_seen_at \
+             FROM ci_error_occurrences o \
+             JOIN ci_errors e ON e.id = o.error_id \
+             WHERE o.create_date >= date_trunc('{unit}', NOW() - INTERVAL '{days} days') \
+               AND o.active AND e.active \
+               AND ($1::bigint IS NULL OR e.project_id = $1) \
+         ) \
+         SELECT b.bucket_start, h.category, \
+             COUNT(DISTINCT h.id) FILTER (WHERE h.first_seen_at >= b.bucket_start) AS new_errors, \
+             COUNT(DISTINCT h.id) FILTER (WHERE h.first_seen_at < b.bucket_start) AS recurring_errors, \
+             COUNT(h.id) AS occurrences \
+         FROM buckets b \
+         LEFT JOIN hits h ON h.bucket_start = b.bucket_start \
+         GROUP BY b.bucket_start, h.category \
+         ORDER BY b.bucket_start, h.category"
+    ))
+    .bind::<Nullable<BigInt>, _>(project_id)
+    .load(conn)
+    .await?;
+
+    let mut points: Vec<ErrorTrendPoint> = Vec::new();
+    for row in rows {
+        if points
+            .last()
+            .is_none_or(|p| p.bucket_start != row.bucket_start)
+        {
+            points.push(ErrorTrendPoint {
+                bucket_start: row.bucket_start,
+                total: TrendCounts::default(),
+                categories: Default::default(),
+            });
+        }
+        let Some(category) = row.category else {
+            continue;
+        };
+        let point = points.last_mut().expect("point pushed above");
+        point.total.new += row.new_errors;
+        point.total.recurring += row.recurring_errors;
+        point.total.occurrences += row.occurrences;
+        point.categories.insert(
+            category,
+            TrendCounts {
+                new: row.new_errors,
+                recurring: row.recurring_errors,
+                occurrences: row.occurrences,
+            },
+        );
+    }
+    Ok(points)
+}
+
+/// A test's timings over N days, from the runs that reported one.
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct SlowTest {
+    #[diesel(sql_type = Text)]
+    pub name: String,
+    #[diesel(sql_type = Double)]
+    pub avg_ms: f64,
+    #[diesel(sql_type = Integer)]
+    pub max_ms: i32,
+    #[diesel(sql_type = BigInt)]
+    pub runs: i64,
+    #[diesel(sql_type = BigInt)]
+    pub failures: i64,
+}
+
+/// A project's tests with the highest average duration over N days,
+/// slowest first. Skipped tests and untimed runs are left out.
+pub async fn query_slowest_tests(
+    conn: &mut AsyncPgConnection,
+    project_id: i64,
+    days: i32,
+    branch: Option<&str>,
+    limit: i64,
+) -> anyhow::Result<Vec<SlowTest>> {
+    let results = diesel::sql_query(format!(
+        "SELECT t.name, \
+            AVG(t.duration_ms)::float AS avg_ms, \
+            MAX(t.duration_ms) AS max_ms, \
+            COUNT(*) AS runs, \
+            COUNT(*) FILTER (WHERE t.status = 'failed') AS failures \
+         FROM ci_test_results t \
+         JOIN ci_builds b ON b.id = t.build_id \
+         WHERE t.project_id = $1 \
+           AND t.create_date >= NOW() - INTERVAL '{days} days' \
+           AND ($2::text IS NULL OR b.branch = $2) \
+           AND t.status != 'skipped' \
+           AND t.duration_ms IS NOT NULL \
+         GROUP BY t.name \
+         ORDER BY avg_ms DESC, t.name \
+         LIMIT $3"
+    ))
+    .bind::<BigInt, _>(project_id)
+    .bind::<Nullable<Text>, _>(branch)
+    .bind::<BigInt, _>(limit)
+    .load(conn)
+    .await?;
+    Ok(results)
+}
+
+/// One run of a test.
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct TestRun {
+    #[diesel(sql_type = BigInt)]
+    pub build_id: i64,
+    #[diesel(sql_type = Text)]
+    pub branch: String,
+    #[diesel(sql_type = Text)]
+    pub status: String,
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub duration_ms: Option<i32>,
+    #[diesel(sql_type = Timestamptz)]
+    pub create_date: chrono::DateTime<chrono::Utc>,
+}
+
+/// Every run of the test `name` in a project over N days, oldest first,
+/// to chart its duration and spot when it slowed down or started failing.
+pub async fn query_test_trend(
+    conn: &mut AsyncPgConnection,
+    project_id: i64,
+    name: &str,
+    days: i32,
+    branch: Option<&str>,
+) -> anyhow::Result<Vec<TestRun>> {
+    let results = diesel::sql_query(format!(
+        "SELECT t.build_id, b.branch, t.status, t.duration_ms, t.create_date \
+         FROM ci_test_results t \
+         JOIN ci_builds b ON b.id = t.build_id \
+         WHERE t.project_id = $1 \
+           AND t.name = $2 \
+           AND t.create_date >= NOW() - INTERVAL '{days} days' \
+           AND ($3::text IS NULL OR b.branch = $3) \
+         ORDER BY t.create_date, t.id"
+    ))
+    .bind::<BigInt, _>(project_id)
+    .bind::<Text, _>(name)
+    .bind::<Nullable<Text>, _>(branch)
     .load(conn)
     .await?;
     Ok(results)
@@ -0,0 +1,111 @@
+//! Connection acquisition with tenant context for row-level security.
+//!
+//! The `ci_*` tables enforce RLS on `centrix.tenant_id` (see
+//! `ci_module::MIGRATION_SQL`), failing closed: a connection without the
+//! setting sees no rows at all. Tenant connections see their tenant's rows;
+//! system connections carry the explicit [`SYSTEM_CONTEXT`] marker and see
+//! every tenant's.
+//!
+//! The setting lives on the pooled connection and the connections handed
+//! out here span many statements outside any transaction, so it cannot be
+//! transaction-local. Instead [`TenantConn`] clears it again before the
+//! connection goes back to the pool, so the next borrower, CI or framework
+//! code, never inherits a tenant or system context.
+
+use std::ops::{Deref, DerefMut};
+
+use diesel::sql_types::Text;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use uuid::Uuid;
+
+use erp_core::db::diesel_pool::DieselPool;
+
+/// `centrix.tenant_id` of system context, which sees every tenant.
+pub const SYSTEM_CONTEXT: &str = "*";
+
+/// A pooled connection with a tenant or system context, cleared when it is
+/// dropped.
+pub struct TenantConn<C>
+where
+    C: DerefMut<Target = AsyncPgConnection> + Send + 'static,
+{
+    conn: Option<C>,
+}
+
+impl<C> Deref for TenantConn<C>
+where
+    C: DerefMut<Target = AsyncPgConnection> + Send + 'static,
+{
+    type Target = AsyncPgConnection;
+
+    fn deref(&self) -> &AsyncPgConnection {
+        self.conn.as_ref().expect("connection taken on drop only")
+    }
+}
+
+impl<C> DerefMut for TenantConn<C>
+where
+    C: DerefMut<Target = AsyncPgConnection> + Send + 'static,
+{
+    fn deref_mut(&mut self) -> &mut AsyncPgConnection {
+        self.conn.as_mut().expect("connection taken on drop only")
+    }
+}
+
+impl<C> Drop for TenantConn<C>
+where
+    C: DerefMut<Target = AsyncPgConnection> + Send + 'static,
+{
+    fn drop(&mut self) {
+        let Some(mut conn) = self.conn.take() else {
+            return;
+        };
+        // The connection returns to the pool when the task drops it, after
+        // the reset.
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    if let Err(e) = set_context(&mut conn, "").await {
+                        tracing::warn!("Tenant context not cleared: {e}");
+                    }
+                });
+            }
+            Err(_) => tracing::warn!("Connection dropped outside the runtime, tenant context kept"),
+        }
+    }
+}
+
+/// Acquire a connection that only sees rows of `tenant_id`.
+pub async fn tenant_conn(
+    pool: &DieselPool,
+    tenant_id: Uuid,
+) -> anyhow::Result<impl DerefMut<Target = AsyncPgConnection> + Send> {
+    acquire(pool, &tenant_id.to_string()).await
+}
+
+/// Acquire a connection in system context (all tenants), for background
+/// workers and unauthenticated entry points such as webhooks.
+pub async fn system_conn(
+    pool: &DieselPool,
+) -> anyhow::Result<impl DerefMut<Target = AsyncPgConnection> + Send> {
+    acquire(pool, SYSTEM_CONTEXT).await
+}
+
+async fn acquire(
+    pool: &DieselPool,
+    context: &str,
+) -> anyhow::Result<impl DerefMut<Target = AsyncPgConnection> + Send> {
+    let mut conn = TenantConn {
+        conn: Some(pool.get().await?),
+    };
+    set_context(&mut conn, context).await?;
+    Ok(conn)
+}
+
+async fn set_context(conn: &mut AsyncPgConnection, context: &str) -> anyhow::Result<()> {
+    diesel::sql_query("SELECT set_config('centrix.tenant_id', $1, false)")
+        .bind::<Text, _>(context)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
@@ -2,6 +2,8 @@
 
 use serde::{Deserialize, Serialize};
 
+use super::Aggregate;
+
 /// Events that can happen to a CI build.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -25,6 +27,12 @@ pub enum CiBuildEvent {
         exit_code: i32,
         duration_ms: i32,
     },
+    /// A build step was killed for running past its timeout.
+    StepTimedOut {
+        step_name: String,
+        timeout_secs: u64,
+        duration_ms: i32,
+    },
     /// Build finished successfully.
     BuildSucceeded { duration_ms: i32 },
     /// Build failed.
@@ -34,18 +42,24 @@ pub enum CiBuildEvent {
     },
     /// Build was cancelled.
     BuildCancelled,
+    /// Build was recorded as skipped instead of being queued.
+    BuildSkipped { reason: String },
 }
 
 /// Aggregate state for a CI build.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct CiBuildAggregate {
     pub status: String,
     pub started: bool,
     pub finished: bool,
 }
 
-impl CiBuildAggregate {
-    pub fn apply(&mut self, event: &CiBuildEvent) {
+impl Aggregate for CiBuildAggregate {
+    const AGGREGATE_TYPE: &'static str = "ci.build";
+
+    type Event = CiBuildEvent;
+
+    fn apply(&mut self, event: &CiBuildEvent) {
         match event {
             CiBuildEvent::BuildCreated { .. } => {
                 self.status = "pending".to_string();
@@ -54,7 +68,7 @@ impl CiBuildAggregate {
                 self.status = "running".to_string();
                 self.started = true;
             }
-            CiBuildEvent::StepCompleted { .. } => {}
+            CiBuildEvent::StepCompleted { .. } | CiBuildEvent::StepTimedOut { .. } => {}
             CiBuildEvent::BuildSucceeded { .. } => {
                 self.status = "success".to_string();
                 self.finished = true;
@@ -67,6 +81,10 @@ impl CiBuildAggregate {
                 self.status = "cancelled".to_string();
                 self.finished = true;
             }
+            CiBuildEvent::BuildSkipped { .. } => {
+                self.status = "skipped".to_string();
+                self.finished = true;
+            }
         }
     }
 }
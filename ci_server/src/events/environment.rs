@@ -2,6 +2,8 @@
 
 use serde::{Deserialize, Serialize};
 
+use super::Aggregate;
+
 /// Events that can happen to a CI environment.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -18,6 +20,15 @@ pub enum CiEnvironmentEvent {
     EnvironmentCreating { url: Option<String> },
     /// Environment is ready and running.
     EnvironmentRunning { url: String },
+    /// Provisioning the environment failed, or it stayed unhealthy
+    /// through its restarts.
+    EnvironmentFailed { error: String },
+    /// A running environment failed its health check.
+    EnvironmentUnhealthy { error: String },
+    /// An unhealthy environment's containers were restarted.
+    EnvironmentRestarted,
+    /// An unhealthy environment passed its health check again.
+    EnvironmentRecovered,
     /// Environment went dormant due to inactivity.
     EnvironmentDormant,
     /// Environment was woken up from dormant state.
@@ -26,23 +37,90 @@ pub enum CiEnvironmentEvent {
     EnvironmentDestroyed { reason: String },
 }
 
-/// Aggregate state for a CI environment.
-#[derive(Debug, Clone, Default)]
+impl CiEnvironmentEvent {
+    /// Whether the event is a valid transition from `status`; the empty
+    /// status is that of a stream without events.
+    pub fn applies_to(&self, status: &str) -> bool {
+        match self {
+            Self::EnvironmentRequested { .. } => status.is_empty(),
+            Self::EnvironmentCreating { .. } => status == "requested",
+            Self::EnvironmentRunning { .. } => matches!(status, "requested" | "creating"),
+            Self::EnvironmentFailed { .. } => {
+                matches!(status, "requested" | "creating" | "unhealthy")
+            }
+            Self::EnvironmentUnhealthy { .. } => status == "running",
+            Self::EnvironmentRestarted | Self::EnvironmentRecovered => status == "unhealthy",
+            Self::EnvironmentDormant => status == "running",
+            Self::EnvironmentWoken => status == "dormant",
+            Self::EnvironmentDestroyed { .. } => !status.is_empty() && status != "destroyed",
+        }
+    }
+
+    /// Whether the event counts as activity, resetting the idle timeout.
+    pub fn is_activity(&self) -> bool {
+        matches!(
+            self,
+            Self::EnvironmentCreating { .. }
+                | Self::EnvironmentRunning { .. }
+                | Self::EnvironmentWoken
+        )
+    }
+}
+
+/// Aggregate state for a CI environment, projected onto its
+/// `ci_environments` row.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct CiEnvironmentAggregate {
     pub status: String,
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Why the environment is `failed` or `unhealthy`.
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub destroyed_reason: Option<String>,
+    /// Restarts since the environment was last healthy.
+    #[serde(default)]
+    pub restart_count: i32,
 }
 
-impl CiEnvironmentAggregate {
-    pub fn apply(&mut self, event: &CiEnvironmentEvent) {
+impl Aggregate for CiEnvironmentAggregate {
+    const AGGREGATE_TYPE: &'static str = "ci.environment";
+
+    type Event = CiEnvironmentEvent;
+
+    fn apply(&mut self, event: &CiEnvironmentEvent) {
         match event {
             CiEnvironmentEvent::EnvironmentRequested { .. } => {
                 self.status = "requested".to_string();
             }
-            CiEnvironmentEvent::EnvironmentCreating { .. } => {
+            CiEnvironmentEvent::EnvironmentCreating { url } => {
                 self.status = "creating".to_string();
+                if url.is_some() {
+                    self.url = url.clone();
+                }
+            }
+            CiEnvironmentEvent::EnvironmentRunning { url } => {
+                self.status = "running".to_string();
+                self.url = Some(url.clone());
+                self.error = None;
+                self.restart_count = 0;
+            }
+            CiEnvironmentEvent::EnvironmentFailed { error } => {
+                self.status = "failed".to_string();
+                self.error = Some(error.clone());
+            }
+            CiEnvironmentEvent::EnvironmentUnhealthy { error } => {
+                self.status = "unhealthy".to_string();
+                self.error = Some(error.clone());
+            }
+            CiEnvironmentEvent::EnvironmentRestarted => {
+                self.restart_count += 1;
             }
-            CiEnvironmentEvent::EnvironmentRunning { .. } => {
+            CiEnvironmentEvent::EnvironmentRecovered => {
                 self.status = "running".to_string();
+                self.error = None;
+                self.restart_count = 0;
             }
             CiEnvironmentEvent::EnvironmentDormant => {
                 self.status = "dormant".to_string();
@@ -50,8 +128,9 @@ impl CiEnvironmentAggregate {
             CiEnvironmentEvent::EnvironmentWoken => {
                 self.status = "running".to_string();
             }
-            CiEnvironmentEvent::EnvironmentDestroyed { .. } => {
+            CiEnvironmentEvent::EnvironmentDestroyed { reason } => {
                 self.status = "destroyed".to_string();
+                self.destroyed_reason = Some(reason.clone());
             }
         }
     }
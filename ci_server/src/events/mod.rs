@@ -5,3 +5,19 @@
 
 pub mod build;
 pub mod environment;
+pub mod store;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// State rebuilt by folding an event stream.
+///
+/// Aggregates are serializable so [`store`] can snapshot them.
+pub trait Aggregate: Default + Serialize + DeserializeOwned {
+    /// Value stored in `ci_events.aggregate_type`.
+    const AGGREGATE_TYPE: &'static str;
+
+    type Event: Serialize + DeserializeOwned;
+
+    fn apply(&mut self, event: &Self::Event);
+}
@@ -0,0 +1,170 @@
+//! Event store for CI aggregates with periodic snapshots.
+//!
+//! Every [`SNAPSHOT_INTERVAL`] events the folded aggregate state is written
+//! to `ci_aggregate_snapshots`, so [`load`] replays at most that many events
+//! no matter how long the stream grows (builds with many step events).
+//!
+//! Appends to one stream are serialized by a transaction-scoped advisory
+//! lock on the aggregate, so concurrent writers (the cancel API and the
+//! executor, or several servers) never compute the same sequence.
+
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Text};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+
+use crate::models::event::{CiAggregateSnapshot, CiEvent, NewCiAggregateSnapshot, NewCiEvent};
+use crate::schema::{ci_aggregate_snapshots, ci_events};
+
+use super::Aggregate;
+
+/// Number of events between snapshots.
+pub const SNAPSHOT_INTERVAL: i32 = 50;
+
+/// Append an event to an aggregate's stream. Returns the event's sequence.
+///
+/// Runs in a transaction (a savepoint inside the caller's), holding the
+/// stream's lock until the outermost one ends.
+pub async fn append<A: Aggregate>(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+    aggregate_id: i64,
+    event: &A::Event,
+) -> anyhow::Result<i32> {
+    let payload = serde_json::to_value(event)?;
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        async move { append_locked::<A>(conn, tenant_id, aggregate_id, payload).await }
+            .scope_boxed()
+    })
+    .await
+}
+
+async fn append_locked<A: Aggregate>(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+    aggregate_id: i64,
+    payload: serde_json::Value,
+) -> anyhow::Result<i32> {
+    diesel::sql_query("SELECT pg_advisory_xact_lock(hashtextextended($1, $2))")
+        .bind::<Text, _>(A::AGGREGATE_TYPE)
+        .bind::<BigInt, _>(aggregate_id)
+        .execute(conn)
+        .await?;
+
+    let last: Option<i32> = ci_events::table
+        .filter(ci_events::aggregate_type.eq(A::AGGREGATE_TYPE))
+        .filter(ci_events::aggregate_id.eq(aggregate_id))
+        .select(diesel::dsl::max(ci_events::sequence))
+        .first(conn)
+        .await?;
+    let sequence = last.unwrap_or(0) + 1;
+
+    let new_event = NewCiEvent {
+        tenant_id,
+        aggregate_type: A::AGGREGATE_TYPE.to_string(),
+        aggregate_id,
+        sequence,
+        payload,
+    };
+    diesel::insert_into(ci_events::table)
+        .values(&new_event)
+        .execute(conn)
+        .await?;
+
+    if sequence % SNAPSHOT_INTERVAL == 0 {
+        let (state, _) = load::<A>(conn, aggregate_id).await?;
+        save_snapshot(conn, tenant_id, aggregate_id, sequence, &state).await?;
+    }
+
+    Ok(sequence)
+}
+
+/// Rehydrate an aggregate from its latest snapshot plus the events after it.
+/// Returns the state and the sequence of the last applied event (0 if none).
+pub async fn load<A: Aggregate>(
+    conn: &mut AsyncPgConnection,
+    aggregate_id: i64,
+) -> anyhow::Result<(A, i32)> {
+    let snapshot: Option<CiAggregateSnapshot> = ci_aggregate_snapshots::table
+        .filter(ci_aggregate_snapshots::aggregate_type.eq(A::AGGREGATE_TYPE))
+        .filter(ci_aggregate_snapshots::aggregate_id.eq(aggregate_id))
+        .first(conn)
+        .await
+        .optional()?;
+
+    let (mut state, mut sequence) = match snapshot {
+        Some(snap) => (serde_json::from_value::<A>(snap.state)?, snap.sequence),
+        None => (A::default(), 0),
+    };
+
+    let events: Vec<CiEvent> = ci_events::table
+        .filter(ci_events::aggregate_type.eq(A::AGGREGATE_TYPE))
+        .filter(ci_events::aggregate_id.eq(aggregate_id))
+        .filter(ci_events::sequence.gt(sequence))
+        .order(ci_events::sequence.asc())
+        .load(conn)
+        .await?;
+
+    for event in events {
+        let parsed: A::Event = serde_json::from_value(event.payload)?;
+        state.apply(&parsed);
+        sequence = event.sequence;
+    }
+
+    Ok((state, sequence))
+}
+
+/// List the raw events of an aggregate's stream in order.
+pub async fn history(
+    conn: &mut AsyncPgConnection,
+    aggregate_type: &str,
+    aggregate_id: i64,
+) -> anyhow::Result<Vec<CiEvent>> {
+    let events = ci_events::table
+        .filter(ci_events::aggregate_type.eq(aggregate_type))
+        .filter(ci_events::aggregate_id.eq(aggregate_id))
+        .order(ci_events::sequence.asc())
+        .load(conn)
+        .await?;
+    Ok(events)
+}
+
+async fn save_snapshot<A: Aggregate>(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+    aggregate_id: i64,
+    sequence: i32,
+    state: &A,
+) -> anyhow::Result<()> {
+    let state = serde_json::to_value(state)?;
+    let snapshot = NewCiAggregateSnapshot {
+        tenant_id,
+        aggregate_type: A::AGGREGATE_TYPE.to_string(),
+        aggregate_id,
+        sequence,
+        state: state.clone(),
+    };
+
+    diesel::insert_into(ci_aggregate_snapshots::table)
+        .values(&snapshot)
+        .on_conflict((
+            ci_aggregate_snapshots::aggregate_type,
+            ci_aggregate_snapshots::aggregate_id,
+        ))
+        .do_update()
+        .set((
+            ci_aggregate_snapshots::sequence.eq(sequence),
+            ci_aggregate_snapshots::state.eq(state),
+            ci_aggregate_snapshots::write_date.eq(chrono::Utc::now()),
+        ))
+        .execute(conn)
+        .await?;
+
+    tracing::debug!(
+        aggregate_type = A::AGGREGATE_TYPE,
+        aggregate_id,
+        sequence,
+        "Aggregate snapshot saved"
+    );
+    Ok(())
+}
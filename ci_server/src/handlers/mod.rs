@@ -1,5 +1,7 @@
 //! ORM model handlers for CI platform.
 //!
-//! CI models use DirectCrud (registered in CiModule::register_handlers).
-//! No custom ModelHandler implementations needed — the framework's
+//! CI models use DirectCrud (registered in CiModule::register_handlers),
+//! except `ci.build` and `ci.environment`, whose state changes go through
+//! the event stream (`crate::events`) and so only through the CI API and
+//! workers. No custom ModelHandler implementations needed — the framework's
 //! GenericModelHandler handles all CRUD operations via the ORM.
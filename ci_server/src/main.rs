@@ -10,6 +10,7 @@
 mod ci_module;
 mod config;
 mod dashboard;
+mod db;
 mod events;
 mod handlers;
 mod metrics;
@@ -18,6 +19,7 @@ mod routes;
 mod schema;
 mod seeder;
 mod services;
+mod telemetry;
 
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -52,24 +54,8 @@ struct Cli {
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Initialize tracing
-    let log_format = std::env::var("LOG_FORMAT").unwrap_or_default();
-    if log_format == "json" {
-        tracing_subscriber::fmt()
-            .json()
-            .with_env_filter(
-                tracing_subscriber::EnvFilter::try_from_default_env()
-                    .unwrap_or_else(|_| "info".into()),
-            )
-            .init();
-    } else {
-        tracing_subscriber::fmt()
-            .with_env_filter(
-                tracing_subscriber::EnvFilter::try_from_default_env()
-                    .unwrap_or_else(|_| "info".into()),
-            )
-            .init();
-    }
+    // Initialize logging and trace export
+    let tracer_provider = telemetry::init_tracing(&telemetry::OtelConfig::from_env())?;
 
     let cli = Cli::parse();
     let dev_mode = DevMode::from_features(&cli.dev);
@@ -193,13 +179,10 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
-    // Seed CI-specific data (models, views, actions, menus)
+    // Seed CI-specific data (models, views, actions, menus). The demo
+    // projects are CI rows, so this needs system context.
     {
-        let mut conn = data_services
-            .diesel
-            .get()
-            .await
-            .map_err(|e| anyhow::anyhow!("diesel pool: {e}"))?;
+        let mut conn = db::system_conn(&data_services.diesel).await?;
         seeder::seed_ci_module(&mut conn).await?;
     }
 
@@ -239,18 +222,80 @@ async fn main() -> anyhow::Result<()> {
     // CI router state
     let ci_config = config::CiConfig::from_env();
 
+    // Finish any pending master key rotation before secrets are used
+    if let Some(keyring) = ci_config
+        .secrets_keyring
+        .as_ref()
+        .filter(|k| k.has_previous())
+    {
+        let mut conn = db::system_conn(&data_arc.diesel).await?;
+        services::secret_service::rewrap_all(&mut conn, keyring).await?;
+    }
+
     // Spawn build executor background task
     {
         let executor_pool = data_arc.diesel.clone();
         let executor_config = ci_config.clone();
+        let executor_db_url = db_url.clone();
         tokio::spawn(async move {
-            services::executor::run_executor(executor_pool, executor_config).await;
+            services::executor::run_executor(executor_pool, executor_config, executor_db_url).await;
         });
     }
 
+    // Spawn build feed listener (fans build events out to WebSocket clients)
+    tokio::spawn(services::build_feed::run_listener(db_url.clone()));
+
+    // Spawn executor watchdog (flips readiness if the executor loop stalls)
+    tokio::spawn(services::executor::run_watchdog());
+
+    // Spawn outbox worker (delivers GitHub statuses and PR comments)
+    {
+        let outbox_pool = data_arc.diesel.clone();
+        let outbox_config = ci_config.clone();
+        tokio::spawn(async move {
+            services::outbox_service::run_outbox_worker(outbox_pool, outbox_config).await;
+        });
+    }
+
+    // Spawn alert evaluator (threshold rules, notifies through the outbox)
+    tokio::spawn(services::alert_service::run_alert_evaluator(
+        data_arc.diesel.clone(),
+    ));
+
+    // Spawn build scheduler (cron triggers)
+    tokio::spawn(services::schedule_service::run_scheduler(
+        data_arc.diesel.clone(),
+    ));
+
+    // Spawn SLO exporter (per-project failure ratios and burn rates)
+    tokio::spawn(services::slo_service::run_slo_exporter(
+        data_arc.diesel.clone(),
+        ci_config.slo_target,
+    ));
+
+    // Spawn error auto-resolver (resolves errors gone from the default branch)
+    tokio::spawn(services::error_service::run_auto_resolver(
+        data_arc.diesel.clone(),
+        ci_config.error_auto_resolve_builds,
+    ));
+
+    // Spawn digest scheduler (daily and weekly summaries to chat channels)
+    tokio::spawn(services::digest_service::run_digest_scheduler(
+        data_arc.diesel.clone(),
+        ci_config.digest_hour,
+    ));
+
+    // Spawn environment provisioner (brings review environments up and down)
+    tokio::spawn(services::compose_provisioner::run_provisioner(
+        data_arc.diesel.clone(),
+        ci_config.clone(),
+    ));
+
     let ci_state = routes::CiRouterState {
         pool: data_arc.diesel.clone(),
+        rate_limits: routes::rate_limit::RateLimits::from_config(&ci_config),
         config: ci_config,
+        session_store: session_store.clone(),
     };
 
     // App state (for framework web client)
@@ -299,6 +344,7 @@ async fn main() -> anyhow::Result<()> {
 
     tracing::info!("Stopping DBOS runtime...");
     dbos_runtime_handle.stop().await;
+    telemetry::shutdown(tracer_provider);
     tracing::info!("Shutdown complete");
 
     Ok(())
@@ -1,33 +1,250 @@
 //! Prometheus metrics for CI platform observability.
+//!
+//! Build and step series carry `project` (the GitHub repo) and `branch`
+//! labels. To keep cardinality bounded, only the first [`MAX_PROJECTS`]
+//! projects and the first [`MAX_BRANCHES_PER_PROJECT`] branches of each get
+//! their own series; later ones are reported as `other`.
+//!
+//! The registry is served by the CI router at `/ci/metrics` rather than a
+//! separate exporter port.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{LazyLock, Mutex, OnceLock};
 
 use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::PrometheusHandle;
+
+/// Distinct `project` label values before new projects become `other`.
+pub const MAX_PROJECTS: usize = 200;
+
+/// Distinct `branch` label values per project before new branches become
+/// `other`.
+pub const MAX_BRANCHES_PER_PROJECT: usize = 20;
+
+const OVERFLOW_LABEL: &str = "other";
+
+/// Label values admitted so far: project -> branches.
+static ADMITTED: LazyLock<Mutex<HashMap<String, HashSet<String>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The `project` and `branch` label values to report, after the
+/// cardinality guard.
+fn build_labels(project: &str, branch: &str) -> (String, String) {
+    let mut admitted = ADMITTED.lock().unwrap_or_else(|e| e.into_inner());
+    if !admitted.contains_key(project) && admitted.len() >= MAX_PROJECTS {
+        return (OVERFLOW_LABEL.to_string(), OVERFLOW_LABEL.to_string());
+    }
+    let branches = admitted.entry(project.to_string()).or_default();
+    if !branches.contains(branch) {
+        if branches.len() >= MAX_BRANCHES_PER_PROJECT {
+            return (project.to_string(), OVERFLOW_LABEL.to_string());
+        }
+        branches.insert(branch.to_string());
+    }
+    (project.to_string(), branch.to_string())
+}
+
+/// The `project` label value to report for series without a branch.
+fn project_label(project: &str) -> String {
+    let mut admitted = ADMITTED.lock().unwrap_or_else(|e| e.into_inner());
+    if !admitted.contains_key(project) {
+        if admitted.len() >= MAX_PROJECTS {
+            return OVERFLOW_LABEL.to_string();
+        }
+        admitted.insert(project.to_string(), HashSet::new());
+    }
+    project.to_string()
+}
 
-/// Initialize metrics exporter (Prometheus).
+/// How often histograms and summaries are compacted.
+const UPKEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Install the Prometheus recorder. Must run inside the tokio runtime.
 pub fn init_metrics() {
-    let builder = metrics_exporter_prometheus::PrometheusBuilder::new();
-    if let Err(e) = builder.install() {
-        tracing::warn!("Failed to install Prometheus exporter: {}", e);
+    match metrics_exporter_prometheus::PrometheusBuilder::new().install_recorder() {
+        Ok(handle) => {
+            let upkeep = handle.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(UPKEEP_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    upkeep.run_upkeep();
+                }
+            });
+            let _ = HANDLE.set(handle);
+        }
+        Err(e) => tracing::warn!("Failed to install Prometheus recorder: {}", e),
     }
 }
 
+/// The registry in Prometheus text format, once the recorder is installed.
+pub fn render() -> Option<String> {
+    HANDLE.get().map(PrometheusHandle::render)
+}
+
+/// Keep only the samples labelled with one of `projects`, plus the
+/// `# HELP`/`# TYPE` lines. Series without a `project` label (global
+/// gauges) and the `other` overflow bucket are dropped.
+pub fn filter_projects(exposition: &str, projects: &HashSet<String>) -> String {
+    exposition
+        .lines()
+        .filter(|line| {
+            line.starts_with('#')
+                || line
+                    .split_once("project=\"")
+                    .and_then(|(_, rest)| rest.split_once('"'))
+                    .is_some_and(|(project, _)| projects.contains(project))
+        })
+        .fold(String::new(), |mut out, line| {
+            out.push_str(line);
+            out.push('\n');
+            out
+        })
+}
+
 /// Record a webhook received event.
 pub fn webhook_received(event_type: &str) {
     counter!("ci_webhooks_received_total", "event" => event_type.to_string()).increment(1);
 }
 
 /// Record a build state transition.
-pub fn build_status_changed(status: &str) {
-    counter!("ci_builds_total", "status" => status.to_string()).increment(1);
+pub fn build_status_changed(status: &str, project: &str, branch: &str) {
+    let (project, branch) = build_labels(project, branch);
+    counter!(
+        "ci_builds_total",
+        "status" => status.to_string(),
+        "project" => project,
+        "branch" => branch
+    )
+    .increment(1);
 }
 
 /// Record build duration.
-pub fn build_duration(duration_ms: u64) {
-    histogram!("ci_build_duration_ms").record(duration_ms as f64);
+pub fn build_duration(project: &str, branch: &str, duration_ms: u64) {
+    let (project, branch) = build_labels(project, branch);
+    histogram!("ci_build_duration_ms", "project" => project, "branch" => branch)
+        .record(duration_ms as f64);
 }
 
 /// Record step duration.
-pub fn step_duration(step_name: &str, duration_ms: u64) {
-    histogram!("ci_step_duration_ms", "step" => step_name.to_string()).record(duration_ms as f64);
+pub fn step_duration(step_name: &str, project: &str, branch: &str, duration_ms: u64) {
+    let (project, branch) = build_labels(project, branch);
+    histogram!(
+        "ci_step_duration_ms",
+        "step" => step_name.to_string(),
+        "project" => project,
+        "branch" => branch
+    )
+    .record(duration_ms as f64);
+}
+
+/// Set a project's failure ratio over one SLO window, and the burn rate it
+/// implies against the configured success target.
+pub fn build_slo_window(project: &str, window: &str, failure_ratio: f64, burn_rate: f64) {
+    let project = project_label(project);
+    gauge!(
+        "ci_build_slo_failure_ratio",
+        "project" => project.clone(),
+        "window" => window.to_string()
+    )
+    .set(failure_ratio);
+    gauge!(
+        "ci_build_slo_burn_rate",
+        "project" => project,
+        "window" => window.to_string()
+    )
+    .set(burn_rate);
+}
+
+/// Set the build success SLO target the burn rates are computed against.
+pub fn build_slo_target(target: f64) {
+    gauge!("ci_build_slo_target").set(target);
+}
+
+/// Set the build queue gauges: pending builds, age of the oldest pending
+/// build, and running builds. Refreshed on every executor poll.
+pub fn build_queue(pending: u64, oldest_pending_age_secs: u64, running: u64) {
+    gauge!("ci_builds_pending").set(pending as f64);
+    gauge!("ci_oldest_pending_build_age_seconds").set(oldest_pending_age_secs as f64);
+    gauge!("ci_builds_running").set(running as f64);
+}
+
+/// Count a newly queued build ahead of the next executor poll.
+pub fn build_queued() {
+    gauge!("ci_builds_pending").increment(1.0);
+}
+
+/// Record one executor poll: how it ended (`idle`, `executed`, `error`),
+/// how long it took, and the resulting health gauges.
+pub fn executor_poll(outcome: &str, duration_ms: u64, last_ok_unix: i64, consecutive_errors: u64) {
+    histogram!("ci_executor_poll_duration_ms", "outcome" => outcome.to_string())
+        .record(duration_ms as f64);
+    gauge!("ci_executor_last_successful_poll_timestamp_seconds").set(last_ok_unix as f64);
+    gauge!("ci_executor_consecutive_errors").set(consecutive_errors as f64);
+}
+
+/// Set whether the executor watchdog considers the loop stalled.
+pub fn executor_stalled(stalled: bool) {
+    gauge!("ci_executor_stalled").set(if stalled { 1.0 } else { 0.0 });
+}
+
+/// Set a runner's capacity (builds it can run at once).
+pub fn runner_capacity(runner: &str, capacity: usize) {
+    gauge!("ci_runner_capacity", "runner" => runner.to_string()).set(capacity as f64);
+}
+
+/// Mark a runner busy with `build_id`, or idle with `None`.
+pub fn runner_current_build(runner: &str, build_id: Option<i64>) {
+    gauge!("ci_runner_busy", "runner" => runner.to_string()).set(if build_id.is_some() {
+        1.0
+    } else {
+        0.0
+    });
+    gauge!("ci_runner_current_build", "runner" => runner.to_string())
+        .set(build_id.unwrap_or(0) as f64);
+}
+
+/// Count a build a runner finished, and whether it failed.
+pub fn runner_build_finished(runner: &str, failed: bool) {
+    counter!("ci_runner_builds_total", "runner" => runner.to_string()).increment(1);
+    if failed {
+        counter!("ci_runner_build_failures_total", "runner" => runner.to_string()).increment(1);
+    }
+}
+
+/// Record one GitHub API call. `status` is `None` when no response came
+/// back; those and non-2xx responses count as errors.
+pub fn github_request(endpoint: &str, status: Option<u16>, duration_ms: u64) {
+    let status_label = status.map_or_else(|| "error".to_string(), |s| s.to_string());
+    counter!(
+        "ci_github_requests_total",
+        "endpoint" => endpoint.to_string(),
+        "status" => status_label
+    )
+    .increment(1);
+    histogram!("ci_github_request_duration_ms", "endpoint" => endpoint.to_string())
+        .record(duration_ms as f64);
+    if !status.is_some_and(|s| (200..300).contains(&s)) {
+        counter!("ci_github_errors_total", "endpoint" => endpoint.to_string()).increment(1);
+    }
+}
+
+/// Count a GitHub API call retried after a server error or rate limit.
+pub fn github_retry(endpoint: &str) {
+    counter!("ci_github_retries_total", "endpoint" => endpoint.to_string()).increment(1);
+}
+
+/// Set the GitHub rate limit reported on the last response of `endpoint`.
+pub fn github_rate_limit(endpoint: &str, remaining: u64, limit: u64, reset_unix: Option<u64>) {
+    gauge!("ci_github_rate_limit_remaining", "endpoint" => endpoint.to_string())
+        .set(remaining as f64);
+    gauge!("ci_github_rate_limit_limit", "endpoint" => endpoint.to_string()).set(limit as f64);
+    if let Some(reset) = reset_unix {
+        gauge!("ci_github_rate_limit_reset_timestamp_seconds", "endpoint" => endpoint.to_string())
+            .set(reset as f64);
+    }
 }
 
 /// Set current active environment count.
@@ -39,3 +256,9 @@ pub fn active_environments(count: usize) {
 pub fn error_recorded(category: &str) {
     counter!("ci_errors_total", "category" => category.to_string()).increment(1);
 }
+
+/// Count a request rejected by the rate limiter of `scope` (`api`,
+/// `webhook`).
+pub fn rate_limited(scope: &str) {
+    counter!("ci_rate_limited_total", "scope" => scope.to_string()).increment(1);
+}
@@ -0,0 +1,49 @@
+//! ci.alert.rule — Threshold alert evaluated by the server itself.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::ci_alert_rules;
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_alert_rules)]
+pub struct CiAlertRule {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    /// Restrict the rule to one project; all of the tenant's when unset.
+    pub project_id: Option<i64>,
+    pub name: String,
+    /// `failure_rate` or `queue_age`.
+    pub kind: String,
+    /// Percent for `failure_rate`, seconds for `queue_age`.
+    pub threshold: f64,
+    pub window_minutes: i32,
+    /// Fewer finished builds in the window never fire `failure_rate`.
+    pub min_builds: i32,
+    pub cooldown_minutes: i32,
+    pub webhook_url: Option<String>,
+    pub firing: bool,
+    pub last_fired_at: Option<DateTime<Utc>>,
+    pub active: bool,
+    pub create_uid: Option<i64>,
+    pub create_date: Option<DateTime<Utc>>,
+    pub write_uid: Option<i64>,
+    pub write_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_alert_rules)]
+pub struct NewCiAlertRule {
+    pub tenant_id: Uuid,
+    pub project_id: Option<i64>,
+    pub name: String,
+    pub kind: String,
+    pub threshold: f64,
+    pub window_minutes: Option<i32>,
+    pub min_builds: Option<i32>,
+    pub cooldown_minutes: Option<i32>,
+    pub webhook_url: Option<String>,
+    pub create_uid: Option<i64>,
+}
@@ -0,0 +1,43 @@
+//! ci.api.token — Hashed API tokens for external automation.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::ci_api_tokens;
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_api_tokens)]
+pub struct CiApiToken {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    pub project_id: Option<i64>,
+    pub uid: i64,
+    pub name: String,
+    pub token_prefix: String,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub rotated_to_id: Option<i64>,
+    pub active: bool,
+    pub create_uid: Option<i64>,
+    pub create_date: Option<DateTime<Utc>>,
+    pub write_uid: Option<i64>,
+    pub write_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_api_tokens)]
+pub struct NewCiApiToken {
+    pub tenant_id: Uuid,
+    pub project_id: Option<i64>,
+    pub uid: i64,
+    pub name: String,
+    pub token_prefix: String,
+    pub token_hash: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub create_uid: Option<i64>,
+}
@@ -30,6 +30,17 @@ pub struct CiBuild {
     pub create_date: Option<DateTime<Utc>>,
     pub write_uid: Option<i64>,
     pub write_date: Option<DateTime<Utc>>,
+    /// W3C `traceparent` the build's trace continues from.
+    pub trace_context: Option<String>,
+    /// Set by the cancel API on a running build; the executor kills the
+    /// current step once it sees it.
+    pub cancel_requested_at: Option<DateTime<Utc>>,
+    /// The trigger that queued the build, for scheduled builds.
+    pub trigger_id: Option<i64>,
+    /// Whether the build gets the project's secrets and OIDC tokens. Pull
+    /// requests from forks or outside contributors run untrusted until a
+    /// maintainer approves them.
+    pub trusted: bool,
 }
 
 #[derive(Debug, Insertable, Deserialize)]
@@ -45,4 +56,96 @@ pub struct NewCiBuild {
     pub fingerprint: String,
     pub trigger_event: String,
     pub status: String,
+    pub trigger_id: Option<i64>,
+    pub trusted: bool,
+}
+
+/// Lifecycle state of a build, stored as a string in `ci_builds.status`.
+///
+/// [`BuildStatus::can_transition_to`] is the single source of truth for
+/// which status changes are legal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildStatus {
+    Pending,
+    Running,
+    Success,
+    Failure,
+    Cancelled,
+    Timeout,
+    /// The in-repo pipeline definition could not be loaded or is invalid.
+    ConfigError,
+    /// Recorded instead of queued, e.g. for a `[skip ci]` commit.
+    Skipped,
+}
+
+impl BuildStatus {
+    pub const ALL: [BuildStatus; 8] = [
+        BuildStatus::Pending,
+        BuildStatus::Running,
+        BuildStatus::Success,
+        BuildStatus::Failure,
+        BuildStatus::Cancelled,
+        BuildStatus::Timeout,
+        BuildStatus::ConfigError,
+        BuildStatus::Skipped,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BuildStatus::Pending => "pending",
+            BuildStatus::Running => "running",
+            BuildStatus::Success => "success",
+            BuildStatus::Failure => "failure",
+            BuildStatus::Cancelled => "cancelled",
+            BuildStatus::Timeout => "timeout",
+            BuildStatus::ConfigError => "config_error",
+            BuildStatus::Skipped => "skipped",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|status| status.as_str() == s)
+    }
+
+    /// Terminal states never transition again.
+    pub fn is_terminal(self) -> bool {
+        matches!(
+            self,
+            BuildStatus::Success
+                | BuildStatus::Failure
+                | BuildStatus::Cancelled
+                | BuildStatus::Timeout
+                | BuildStatus::ConfigError
+                | BuildStatus::Skipped
+        )
+    }
+
+    /// Whether a build in `self` may move to `next`.
+    pub fn can_transition_to(self, next: BuildStatus) -> bool {
+        use BuildStatus::*;
+        matches!(
+            (self, next),
+            (Pending, Running)
+                | (Pending, Cancelled)
+                | (
+                    Running,
+                    Success | Failure | Cancelled | Timeout | ConfigError
+                )
+        )
+    }
+
+    /// All states from which `self` can be reached.
+    pub fn predecessors(self) -> Vec<BuildStatus> {
+        Self::ALL
+            .into_iter()
+            .filter(|from| from.can_transition_to(self))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for BuildStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
@@ -0,0 +1,33 @@
+//! ci.build.audit — Who triggered, cancelled, retried, or approved a build.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::ci_build_audit;
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_build_audit)]
+pub struct CiBuildAudit {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    pub project_id: i64,
+    pub build_id: Option<i64>,
+    pub action: String,
+    pub uid: i64,
+    /// Set when the action was taken with an API token.
+    pub token_id: Option<i64>,
+    pub create_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_build_audit)]
+pub struct NewCiBuildAudit {
+    pub tenant_id: Uuid,
+    pub project_id: i64,
+    pub build_id: Option<i64>,
+    pub action: String,
+    pub uid: i64,
+    pub token_id: Option<i64>,
+}
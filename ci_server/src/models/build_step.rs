@@ -27,6 +27,10 @@ pub struct CiBuildStep {
     pub create_date: Option<DateTime<Utc>>,
     pub write_uid: Option<i64>,
     pub write_date: Option<DateTime<Utc>>,
+    /// 1 for the first run, incremented on each retry.
+    pub attempt: i32,
+    /// Peak memory of the step's processes, when it ran in a cgroup.
+    pub max_rss_bytes: Option<i64>,
 }
 
 #[derive(Debug, Insertable, Deserialize)]
@@ -37,4 +41,5 @@ pub struct NewCiBuildStep {
     pub name: String,
     pub sequence: i32,
     pub status: String,
+    pub attempt: i32,
 }
@@ -0,0 +1,40 @@
+//! ci.command.policy — Admin-managed rules on what pipeline steps may run.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::ci_command_policies;
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_command_policies)]
+pub struct CiCommandPolicy {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    pub name: String,
+    /// When non-empty, every step command must match one of these regexes.
+    pub allow_patterns: Vec<String>,
+    /// No step command may match any of these regexes.
+    pub deny_patterns: Vec<String>,
+    /// Environment variable names pipelines may not set.
+    pub banned_env_vars: Vec<String>,
+    pub max_steps: Option<i32>,
+    pub active: bool,
+    pub create_uid: Option<i64>,
+    pub create_date: Option<DateTime<Utc>>,
+    pub write_uid: Option<i64>,
+    pub write_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_command_policies)]
+pub struct NewCiCommandPolicy {
+    pub tenant_id: Uuid,
+    pub name: String,
+    pub allow_patterns: Vec<String>,
+    pub deny_patterns: Vec<String>,
+    pub banned_env_vars: Vec<String>,
+    pub max_steps: Option<i32>,
+    pub create_uid: Option<i64>,
+}
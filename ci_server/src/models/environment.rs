@@ -26,6 +26,18 @@ pub struct CiEnvironment {
     pub create_date: Option<DateTime<Utc>>,
     pub write_uid: Option<i64>,
     pub write_date: Option<DateTime<Utc>>,
+    /// Why the environment was destroyed, e.g. `pr_closed`.
+    pub destroyed_reason: Option<String>,
+    /// Host port allocated to the environment's app; released on destroy.
+    pub port: Option<i32>,
+    /// Subdomain of the wildcard domain allocated to the environment;
+    /// released on destroy.
+    pub subdomain: Option<String>,
+    /// Why the environment failed, or what its failing health check
+    /// returned while `unhealthy`.
+    pub error: Option<String>,
+    /// Restarts since the environment last passed its health check.
+    pub restart_count: i32,
 }
 
 #[derive(Debug, Insertable, Deserialize)]
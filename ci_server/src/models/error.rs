@@ -32,6 +32,20 @@ pub struct CiError {
     pub create_date: Option<DateTime<Utc>>,
     pub write_uid: Option<i64>,
     pub write_date: Option<DateTime<Utc>>,
+    /// Root error of the cluster of near-duplicates this one belongs to;
+    /// `None` for a root (or unclustered) error.
+    pub cluster_id: Option<i64>,
+    #[serde(skip)]
+    pub minhash: Option<Vec<i64>>,
+    /// CODEOWNERS owners of the file the error was last seen in.
+    pub owners: Option<Vec<String>>,
+}
+
+impl CiError {
+    /// ID of the cluster this error belongs to: its root's ID.
+    pub fn cluster(&self) -> i64 {
+        self.cluster_id.unwrap_or(self.id)
+    }
 }
 
 #[derive(Debug, Insertable, Deserialize)]
@@ -51,6 +65,10 @@ pub struct NewCiError {
     pub status: String,
     pub raw_text: String,
     pub normalized_text: String,
+    pub cluster_id: Option<i64>,
+    pub minhash: Option<Vec<i64>>,
+    pub assigned_to: Option<String>,
+    pub owners: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
@@ -67,6 +85,10 @@ pub struct CiErrorOccurrence {
     pub create_date: Option<DateTime<Utc>>,
     pub write_uid: Option<i64>,
     pub write_date: Option<DateTime<Utc>>,
+    /// Source location in this build, which may differ from the error's
+    /// first-seen location.
+    pub file_path: Option<String>,
+    pub line_number: Option<i32>,
 }
 
 #[derive(Debug, Insertable)]
@@ -77,4 +99,6 @@ pub struct NewCiErrorOccurrence {
     pub build_id: i64,
     pub step_name: String,
     pub raw_output: Option<String>,
+    pub file_path: Option<String>,
+    pub line_number: Option<i32>,
 }
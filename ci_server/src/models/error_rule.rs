@@ -0,0 +1,41 @@
+//! ci.error.rule — Mute rule keeping matching output out of `ci_errors`.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::ci_error_rules;
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_error_rules)]
+pub struct CiErrorRule {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    /// Restrict the rule to one project; all of the tenant's when unset.
+    pub project_id: Option<i64>,
+    /// Regex the error text must match; any text when unset.
+    pub pattern: Option<String>,
+    /// Category the error must have; any when unset.
+    pub category: Option<String>,
+    pub reason: Option<String>,
+    /// Errors suppressed so far.
+    pub hit_count: i32,
+    pub last_hit_at: Option<DateTime<Utc>>,
+    pub active: bool,
+    pub create_uid: Option<i64>,
+    pub create_date: Option<DateTime<Utc>>,
+    pub write_uid: Option<i64>,
+    pub write_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_error_rules)]
+pub struct NewCiErrorRule {
+    pub tenant_id: Uuid,
+    pub project_id: Option<i64>,
+    pub pattern: Option<String>,
+    pub category: Option<String>,
+    pub reason: Option<String>,
+    pub create_uid: Option<i64>,
+}
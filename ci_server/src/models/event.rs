@@ -0,0 +1,53 @@
+//! ci.event + ci.aggregate.snapshot — Event streams and their snapshots.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::{ci_aggregate_snapshots, ci_events};
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_events)]
+pub struct CiEvent {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    pub aggregate_type: String,
+    pub aggregate_id: i64,
+    pub sequence: i32,
+    pub payload: serde_json::Value,
+    pub create_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_events)]
+pub struct NewCiEvent {
+    pub tenant_id: Uuid,
+    pub aggregate_type: String,
+    pub aggregate_id: i64,
+    pub sequence: i32,
+    pub payload: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_aggregate_snapshots)]
+pub struct CiAggregateSnapshot {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    pub aggregate_type: String,
+    pub aggregate_id: i64,
+    pub sequence: i32,
+    pub state: serde_json::Value,
+    pub create_date: Option<DateTime<Utc>>,
+    pub write_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_aggregate_snapshots)]
+pub struct NewCiAggregateSnapshot {
+    pub tenant_id: Uuid,
+    pub aggregate_type: String,
+    pub aggregate_id: i64,
+    pub sequence: i32,
+    pub state: serde_json::Value,
+}
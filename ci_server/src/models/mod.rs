@@ -1,9 +1,24 @@
 //! CI platform data models — generic, pipeline-agnostic.
 
+pub mod alert_rule;
+pub mod api_token;
 pub mod artifact;
 pub mod build;
+pub mod build_audit;
 pub mod build_step;
+pub mod command_policy;
 pub mod environment;
 pub mod error;
+pub mod error_rule;
+pub mod event;
+pub mod notification;
+pub mod notification_rule;
+pub mod outbox;
 pub mod project;
+pub mod project_permission;
+pub mod quota;
+pub mod secret;
+pub mod secret_access;
+pub mod test_result;
 pub mod trigger;
+pub mod vulnerability;
@@ -0,0 +1,114 @@
+//! ci.notification.endpoint — External URL notified of build and
+//! environment events, and its delivery log; ci.notification.channel —
+//! Chat webhook the same events are posted to as messages.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::{
+    ci_notification_channels, ci_notification_deliveries, ci_notification_endpoints,
+};
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_notification_endpoints)]
+pub struct CiNotificationEndpoint {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    /// Restrict the endpoint to one project; all of the tenant's when unset.
+    pub project_id: Option<i64>,
+    pub url: String,
+    /// Signing key; only returned when the endpoint is created.
+    #[serde(skip_serializing, default)]
+    pub secret: String,
+    /// Events sent to the endpoint; every event when empty.
+    pub events: Vec<String>,
+    pub active: bool,
+    pub create_uid: Option<i64>,
+    pub create_date: Option<DateTime<Utc>>,
+    pub write_uid: Option<i64>,
+    pub write_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_notification_endpoints)]
+pub struct NewCiNotificationEndpoint {
+    pub tenant_id: Uuid,
+    pub project_id: Option<i64>,
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<String>,
+    pub create_uid: Option<i64>,
+}
+
+/// One attempt at POSTing an event to an endpoint.
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_notification_deliveries)]
+pub struct CiNotificationDelivery {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    pub endpoint_id: i64,
+    pub outbox_id: i64,
+    pub event: String,
+    pub attempt: i32,
+    /// HTTP status of the response; `None` when none was received.
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub duration_ms: i32,
+    pub create_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_notification_deliveries)]
+pub struct NewCiNotificationDelivery {
+    pub tenant_id: Uuid,
+    pub endpoint_id: i64,
+    pub outbox_id: i64,
+    pub event: String,
+    pub attempt: i32,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub duration_ms: i32,
+}
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_notification_channels)]
+pub struct CiNotificationChannel {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    /// Restrict the channel to one project; all of the tenant's when unset.
+    pub project_id: Option<i64>,
+    pub name: String,
+    /// Message format, one of `chat_notifier::KINDS`.
+    pub kind: String,
+    /// Incoming webhook URL; it embeds the chat's token, so it is never
+    /// returned.
+    #[serde(skip_serializing, default)]
+    pub webhook_url: String,
+    /// Events posted to the channel; every event when empty, none for a
+    /// digest channel.
+    pub events: Vec<String>,
+    pub active: bool,
+    pub create_uid: Option<i64>,
+    pub create_date: Option<DateTime<Utc>>,
+    pub write_uid: Option<i64>,
+    pub write_date: Option<DateTime<Utc>>,
+    /// `daily` or `weekly` digest of the channel's projects, if any.
+    pub digest: Option<String>,
+    /// When the last digest was sent.
+    pub last_digest_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_notification_channels)]
+pub struct NewCiNotificationChannel {
+    pub tenant_id: Uuid,
+    pub project_id: Option<i64>,
+    pub name: String,
+    pub kind: String,
+    pub webhook_url: String,
+    pub events: Vec<String>,
+    pub create_uid: Option<i64>,
+    pub digest: Option<String>,
+}
@@ -0,0 +1,48 @@
+//! ci.notification.rule — Which build events of which branches reach a
+//! notification channel, and how often.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::ci_notification_rules;
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_notification_rules)]
+pub struct CiNotificationRule {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    /// Restrict the rule to one project; all of the channel's when unset.
+    pub project_id: Option<i64>,
+    pub channel_id: i64,
+    pub name: String,
+    /// Branches covered, as a trigger's pattern; all when unset.
+    pub branch_pattern: Option<String>,
+    /// Build events covered; every build event when empty.
+    pub events: Vec<String>,
+    /// Minutes after a message during which the rule stays quiet.
+    pub throttle_minutes: i32,
+    pub last_notified_at: Option<DateTime<Utc>>,
+    pub active: bool,
+    pub create_uid: Option<i64>,
+    pub create_date: Option<DateTime<Utc>>,
+    pub write_uid: Option<i64>,
+    pub write_date: Option<DateTime<Utc>>,
+    /// Only fire for a build that turns its branch red or green again.
+    pub on_transition: bool,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_notification_rules)]
+pub struct NewCiNotificationRule {
+    pub tenant_id: Uuid,
+    pub project_id: Option<i64>,
+    pub channel_id: i64,
+    pub name: String,
+    pub branch_pattern: Option<String>,
+    pub events: Vec<String>,
+    pub throttle_minutes: i32,
+    pub create_uid: Option<i64>,
+    pub on_transition: bool,
+}
@@ -0,0 +1,39 @@
+//! ci.outbox — Pending external side effects, drained by the outbox worker.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::ci_outbox;
+
+#[derive(Debug, Clone, Queryable, QueryableByName, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_outbox)]
+pub struct CiOutboxMessage {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub processed_at: Option<DateTime<Utc>>,
+    pub active: bool,
+    pub create_uid: Option<i64>,
+    pub create_date: Option<DateTime<Utc>>,
+    pub write_uid: Option<i64>,
+    pub write_date: Option<DateTime<Utc>>,
+    /// W3C `traceparent` of the span that enqueued the message.
+    pub trace_context: Option<String>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_outbox)]
+pub struct NewCiOutboxMessage {
+    pub tenant_id: Uuid,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub status: String,
+    pub trace_context: Option<String>,
+}
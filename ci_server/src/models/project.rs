@@ -1,4 +1,4 @@
-//! ci.project — A registered GitHub repo with pipeline config.
+//! ci.project — A registered GitHub or Gitea repo with pipeline config.
 
 use chrono::{DateTime, Utc};
 use diesel::prelude::*;
@@ -13,14 +13,22 @@ pub struct CiProject {
     pub id: i64,
     pub tenant_id: Uuid,
     pub name: String,
+    /// `owner/name` on the project's provider.
     pub github_repo: String,
     pub default_branch: String,
     pub pipeline_config: Option<serde_json::Value>,
+    /// `private`, `internal`, or `public`; see [`Visibility`].
+    pub visibility: String,
     pub active: bool,
     pub create_uid: Option<i64>,
     pub create_date: Option<DateTime<Utc>>,
     pub write_uid: Option<i64>,
     pub write_date: Option<DateTime<Utc>>,
+    /// `github` or `gitea`; see [`Provider`].
+    pub provider: String,
+    /// Build each commit of a push, not just the head, up to
+    /// `CI_MAX_PUSH_COMMITS`.
+    pub build_all_commits: bool,
 }
 
 #[derive(Debug, Insertable, Deserialize)]
@@ -31,5 +39,80 @@ pub struct NewCiProject {
     pub github_repo: String,
     pub default_branch: String,
     pub pipeline_config: Option<serde_json::Value>,
+    /// Defaults to `private` when omitted.
+    pub visibility: Option<String>,
     pub active: bool,
+    /// Defaults to `github` when omitted.
+    pub provider: Option<String>,
+    /// Defaults to `false` when omitted.
+    pub build_all_commits: Option<bool>,
+}
+
+/// Who may read a project's builds without project access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    /// Only sessions and tokens with access to the project.
+    Private,
+    /// Any authenticated caller.
+    Internal,
+    /// Anyone, without authentication.
+    Public,
+}
+
+impl Visibility {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "private" => Some(Self::Private),
+            "internal" => Some(Self::Internal),
+            "public" => Some(Self::Public),
+            _ => None,
+        }
+    }
+}
+
+/// Forge hosting a project's repo: where its webhooks come from, where it
+/// is cloned from, and where commit statuses go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    Github,
+    /// Gitea or Forgejo, at `CI_GITEA_URL`.
+    Gitea,
+}
+
+impl Provider {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "github" => Some(Self::Github),
+            "gitea" => Some(Self::Gitea),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Github => "github",
+            Self::Gitea => "gitea",
+        }
+    }
+
+    /// Web URL of `repo` (`owner/name`); `None` for Gitea without
+    /// `CI_GITEA_URL`.
+    pub fn repo_url(self, repo: &str, gitea_url: Option<&str>) -> Option<String> {
+        match self {
+            Self::Github => Some(format!("https://github.com/{repo}")),
+            Self::Gitea => gitea_url.map(|url| format!("{url}/{repo}")),
+        }
+    }
+}
+
+impl CiProject {
+    /// Parsed visibility; unknown values are treated as private.
+    pub fn visibility(&self) -> Visibility {
+        Visibility::parse(&self.visibility).unwrap_or(Visibility::Private)
+    }
+
+    /// Parsed provider; unknown values are treated as GitHub.
+    pub fn provider(&self) -> Provider {
+        Provider::parse(&self.provider).unwrap_or(Provider::Github)
+    }
 }
@@ -0,0 +1,36 @@
+//! ci.project.permission — Per-project grants for manual build actions.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::ci_project_permissions;
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_project_permissions)]
+pub struct CiProjectPermission {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    pub project_id: i64,
+    /// `trigger`, `cancel`, `retry`, or `approve`.
+    pub action: String,
+    pub user_id: Option<i64>,
+    pub group_id: Option<i64>,
+    pub active: bool,
+    pub create_uid: Option<i64>,
+    pub create_date: Option<DateTime<Utc>>,
+    pub write_uid: Option<i64>,
+    pub write_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_project_permissions)]
+pub struct NewCiProjectPermission {
+    pub tenant_id: Uuid,
+    pub project_id: i64,
+    pub action: String,
+    pub user_id: Option<i64>,
+    pub group_id: Option<i64>,
+    pub create_uid: Option<i64>,
+}
@@ -0,0 +1,23 @@
+//! ci.tenant.quota — Per-tenant limits on builds and environments.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::ci_tenant_quotas;
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_tenant_quotas)]
+pub struct CiTenantQuota {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    pub max_builds_per_day: Option<i32>,
+    pub max_concurrent_builds: Option<i32>,
+    pub max_environments: Option<i32>,
+    pub active: bool,
+    pub create_uid: Option<i64>,
+    pub create_date: Option<DateTime<Utc>>,
+    pub write_uid: Option<i64>,
+    pub write_date: Option<DateTime<Utc>>,
+}
@@ -0,0 +1,48 @@
+//! ci.secret — Envelope-encrypted secret values injected into builds.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::ci_secrets;
+
+/// A stored secret. The encrypted material is never serialized, so this
+/// struct is safe to return from the API.
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_secrets)]
+pub struct CiSecret {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    /// `None` for tenant-wide secrets.
+    pub project_id: Option<i64>,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub ciphertext: Vec<u8>,
+    #[serde(skip_serializing)]
+    pub nonce: Vec<u8>,
+    #[serde(skip_serializing)]
+    pub wrapped_key: Vec<u8>,
+    #[serde(skip_serializing)]
+    pub key_nonce: Vec<u8>,
+    pub master_key_id: String,
+    pub active: bool,
+    pub create_uid: Option<i64>,
+    pub create_date: Option<DateTime<Utc>>,
+    pub write_uid: Option<i64>,
+    pub write_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_secrets)]
+pub struct NewCiSecret {
+    pub tenant_id: Uuid,
+    pub project_id: Option<i64>,
+    pub name: String,
+    pub ciphertext: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub wrapped_key: Vec<u8>,
+    pub key_nonce: Vec<u8>,
+    pub master_key_id: String,
+    pub create_uid: Option<i64>,
+}
@@ -0,0 +1,33 @@
+//! ci.secret.access — Audit record of a secret injected into a build step.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::ci_secret_access_log;
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_secret_access_log)]
+pub struct CiSecretAccess {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    /// `None` once the secret itself has been deleted.
+    pub secret_id: Option<i64>,
+    pub secret_name: String,
+    pub project_id: i64,
+    pub build_id: i64,
+    pub step_name: String,
+    pub create_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_secret_access_log)]
+pub struct NewCiSecretAccess {
+    pub tenant_id: Uuid,
+    pub secret_id: Option<i64>,
+    pub secret_name: String,
+    pub project_id: i64,
+    pub build_id: i64,
+    pub step_name: String,
+}
@@ -0,0 +1,39 @@
+//! ci.test.result — Outcome of one test case, parsed from a step's output.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::ci_test_results;
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_test_results)]
+pub struct CiTestResult {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    pub project_id: i64,
+    pub build_id: i64,
+    pub step_id: i64,
+    pub step_name: String,
+    /// Test identifier as the runner prints it, e.g. `tests::parses_env`.
+    pub name: String,
+    /// `passed`, `failed` or `skipped`.
+    pub status: String,
+    /// `None` when the runner does not report per-test timings.
+    pub duration_ms: Option<i32>,
+    pub create_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_test_results)]
+pub struct NewCiTestResult {
+    pub tenant_id: Uuid,
+    pub project_id: i64,
+    pub build_id: i64,
+    pub step_id: i64,
+    pub step_name: String,
+    pub name: String,
+    pub status: String,
+    pub duration_ms: Option<i32>,
+}
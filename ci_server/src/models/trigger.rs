@@ -20,6 +20,20 @@ pub struct CiTrigger {
     pub create_date: Option<DateTime<Utc>>,
     pub write_uid: Option<i64>,
     pub write_date: Option<DateTime<Utc>>,
+    /// Cron expression (UTC) for `event_type = "schedule"` triggers.
+    pub cron_expr: Option<String>,
+    pub last_fired_at: Option<DateTime<Utc>>,
+    /// `{"NAME": "value"}` passed to the steps of builds this trigger starts.
+    pub env: Option<serde_json::Value>,
+    /// Globs of changed files that should start a build; empty means any.
+    pub paths: Vec<String>,
+    /// Globs of changed files that never start a build on their own.
+    pub paths_ignore: Vec<String>,
+    /// Don't build draft pull requests.
+    pub skip_drafts: bool,
+    /// Only build pull requests carrying one of these labels; empty means
+    /// any.
+    pub labels: Vec<String>,
 }
 
 #[derive(Debug, Insertable, Deserialize)]
@@ -30,4 +44,14 @@ pub struct NewCiTrigger {
     pub event_type: String,
     pub branch_pattern: Option<String>,
     pub active: bool,
+    pub cron_expr: Option<String>,
+    pub env: Option<serde_json::Value>,
+    #[serde(default)]
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub paths_ignore: Vec<String>,
+    #[serde(default)]
+    pub skip_drafts: bool,
+    #[serde(default)]
+    pub labels: Vec<String>,
 }
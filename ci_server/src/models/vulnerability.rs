@@ -0,0 +1,43 @@
+//! ci.vulnerability — A dependency advisory found by a build's audit step.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::ci_vulnerabilities;
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = ci_vulnerabilities)]
+pub struct CiVulnerability {
+    pub id: i64,
+    pub tenant_id: Uuid,
+    pub build_id: i64,
+    pub project_id: i64,
+    /// Report format the finding came from (`cargo-audit`, `npm-audit`).
+    pub source: String,
+    pub advisory_id: String,
+    pub package: String,
+    pub version: Option<String>,
+    pub severity: String,
+    pub title: Option<String>,
+    pub active: bool,
+    pub create_uid: Option<i64>,
+    pub create_date: Option<DateTime<Utc>>,
+    pub write_uid: Option<i64>,
+    pub write_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = ci_vulnerabilities)]
+pub struct NewCiVulnerability {
+    pub tenant_id: Uuid,
+    pub build_id: i64,
+    pub project_id: i64,
+    pub source: String,
+    pub advisory_id: String,
+    pub package: String,
+    pub version: Option<String>,
+    pub severity: String,
+    pub title: Option<String>,
+}
@@ -1,10 +1,12 @@
 //! REST API for builds and projects.
 
+use diesel::pg::Pg;
 use diesel::prelude::*;
 use diesel_async::{AsyncPgConnection, RunQueryDsl};
 use serde::{Deserialize, Serialize};
 
-use crate::models::build::CiBuild;
+use crate::models::artifact::CiArtifact;
+use crate::models::build::{BuildStatus, CiBuild};
 use crate::models::build_step::CiBuildStep;
 use crate::schema::{ci_build_steps, ci_builds};
 
@@ -22,6 +24,9 @@ pub struct BuildJson {
     pub trigger_event: String,
     pub duration_ms: Option<i32>,
     pub create_date: Option<chrono::DateTime<chrono::Utc>>,
+    /// False while a pull request build awaits a maintainer's approval to
+    /// see secrets.
+    pub trusted: bool,
     pub steps: Vec<StepJson>,
 }
 
@@ -30,9 +35,35 @@ pub struct StepJson {
     pub id: i64,
     pub name: String,
     pub sequence: i32,
+    pub attempt: i32,
     pub status: String,
     pub duration_ms: Option<i32>,
     pub exit_code: Option<i32>,
+    pub max_rss_bytes: Option<i64>,
+}
+
+/// JSON response for an artifact, without its content.
+#[derive(Debug, Serialize)]
+pub struct ArtifactJson {
+    pub id: i64,
+    pub build_id: i64,
+    pub name: String,
+    pub artifact_type: String,
+    pub size_bytes: Option<i64>,
+    pub create_date: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl From<CiArtifact> for ArtifactJson {
+    fn from(artifact: CiArtifact) -> Self {
+        Self {
+            id: artifact.id,
+            build_id: artifact.build_id,
+            name: artifact.name,
+            artifact_type: artifact.artifact_type,
+            size_bytes: artifact.size_bytes,
+            create_date: artifact.create_date,
+        }
+    }
 }
 
 /// Get a build by ID with its steps.
@@ -41,7 +72,10 @@ pub async fn get_build(conn: &mut AsyncPgConnection, build_id: i64) -> anyhow::R
 
     let steps: Vec<CiBuildStep> = ci_build_steps::table
         .filter(ci_build_steps::build_id.eq(build_id))
-        .order(ci_build_steps::sequence.asc())
+        .order((
+            ci_build_steps::sequence.asc(),
+            ci_build_steps::attempt.asc(),
+        ))
         .load(conn)
         .await?;
 
@@ -57,15 +91,18 @@ pub async fn get_build(conn: &mut AsyncPgConnection, build_id: i64) -> anyhow::R
         trigger_event: build.trigger_event,
         duration_ms: build.duration_ms,
         create_date: build.create_date,
+        trusted: build.trusted,
         steps: steps
             .into_iter()
             .map(|s| StepJson {
                 id: s.id,
                 name: s.name,
                 sequence: s.sequence,
+                attempt: s.attempt,
                 status: s.status,
                 duration_ms: s.duration_ms,
                 exit_code: s.exit_code,
+                max_rss_bytes: s.max_rss_bytes,
             })
             .collect(),
     })
@@ -86,7 +123,10 @@ pub async fn get_latest_build(
 
     let steps: Vec<CiBuildStep> = ci_build_steps::table
         .filter(ci_build_steps::build_id.eq(build.id))
-        .order(ci_build_steps::sequence.asc())
+        .order((
+            ci_build_steps::sequence.asc(),
+            ci_build_steps::attempt.asc(),
+        ))
         .load(conn)
         .await?;
 
@@ -102,15 +142,18 @@ pub async fn get_latest_build(
         trigger_event: build.trigger_event,
         duration_ms: build.duration_ms,
         create_date: build.create_date,
+        trusted: build.trusted,
         steps: steps
             .into_iter()
             .map(|s| StepJson {
                 id: s.id,
                 name: s.name,
                 sequence: s.sequence,
+                attempt: s.attempt,
                 status: s.status,
                 duration_ms: s.duration_ms,
                 exit_code: s.exit_code,
+                max_rss_bytes: s.max_rss_bytes,
             })
             .collect(),
     })
@@ -118,6 +161,105 @@ pub async fn get_latest_build(
 
 // ── Trigger API ──
 
+/// Chrome trace event format (`chrome://tracing`, Perfetto, speedscope) of
+/// a build: queue wait, checkout/setup, then each step, in microseconds
+/// since the build was queued.
+pub async fn get_build_timeline(
+    conn: &mut AsyncPgConnection,
+    build_id: i64,
+) -> anyhow::Result<serde_json::Value> {
+    use chrono::{DateTime, Utc};
+
+    let build: CiBuild = ci_builds::table.find(build_id).first(conn).await?;
+    let steps: Vec<CiBuildStep> = ci_build_steps::table
+        .filter(ci_build_steps::build_id.eq(build_id))
+        .order((
+            ci_build_steps::sequence.asc(),
+            ci_build_steps::attempt.asc(),
+        ))
+        .load(conn)
+        .await?;
+
+    let now = Utc::now();
+    let origin = build.create_date.or(build.started_at).unwrap_or(now);
+    let micros = |t: DateTime<Utc>| (t - origin).num_microseconds().unwrap_or(0).max(0);
+    let span = |name: &str,
+                cat: &str,
+                tid: u8,
+                start: DateTime<Utc>,
+                end: DateTime<Utc>,
+                args: serde_json::Value| {
+        serde_json::json!({
+            "name": name,
+            "cat": cat,
+            "ph": "X",
+            "pid": build.id,
+            "tid": tid,
+            "ts": micros(start),
+            "dur": (micros(end) - micros(start)).max(0),
+            "args": args,
+        })
+    };
+
+    let mut events = vec![
+        serde_json::json!({"name": "process_name", "ph": "M", "pid": build.id, "args": {"name": format!("Build #{}", build.id)}}),
+        serde_json::json!({"name": "thread_name", "ph": "M", "pid": build.id, "tid": 1, "args": {"name": "queue"}}),
+        serde_json::json!({"name": "thread_name", "ph": "M", "pid": build.id, "tid": 2, "args": {"name": "build"}}),
+    ];
+
+    let started = build.started_at;
+    events.push(span(
+        "queued",
+        "queue",
+        1,
+        origin,
+        started.unwrap_or(now),
+        serde_json::json!({}),
+    ));
+
+    if let Some(started) = started {
+        let first_step = steps.first().and_then(|s| s.started_at.or(s.create_date));
+        let setup_end = first_step.or(build.finished_at).unwrap_or(now);
+        events.push(span(
+            "checkout",
+            "setup",
+            2,
+            started,
+            setup_end,
+            serde_json::json!({}),
+        ));
+    }
+
+    for step in &steps {
+        let Some(start) = step.started_at.or(step.create_date) else {
+            continue;
+        };
+        let end = step.finished_at.unwrap_or_else(|| match step.duration_ms {
+            Some(ms) => start + chrono::Duration::milliseconds(ms as i64),
+            None => now,
+        });
+        events.push(span(
+            &step.name,
+            "step",
+            2,
+            start,
+            end,
+            serde_json::json!({"sequence": step.sequence, "status": step.status, "exit_code": step.exit_code}),
+        ));
+    }
+
+    Ok(serde_json::json!({
+        "traceEvents": events,
+        "displayTimeUnit": "ms",
+        "otherData": {
+            "build_id": build.id,
+            "status": build.status,
+            "branch": build.branch,
+            "commit_sha": build.commit_sha,
+        },
+    }))
+}
+
 /// Request body for manually triggering a build.
 #[derive(Debug, Deserialize)]
 pub struct TriggerRequest {
@@ -162,7 +304,9 @@ pub async fn trigger_build(
         message: Some("Manual trigger via API".to_string()),
         fingerprint,
         trigger_event: "manual".to_string(),
-        status: "pending".to_string(),
+        status: BuildStatus::Pending.as_str().to_string(),
+        trigger_id: None,
+        trusted: true,
     };
 
     let build = crate::services::build_service::create_build(conn, new_build).await?;
@@ -173,22 +317,314 @@ pub async fn trigger_build(
     })
 }
 
-/// List builds with optional limit.
-pub async fn list_builds(
+/// Queue a new build of the same commit and branch as a finished build.
+pub async fn retry_build(
+    conn: &mut AsyncPgConnection,
+    build: &CiBuild,
+) -> anyhow::Result<TriggerResponse> {
+    use crate::models::build::NewCiBuild;
+
+    if !BuildStatus::parse(&build.status).is_some_and(|s| s.is_terminal()) {
+        anyhow::bail!("build {} is still {}", build.id, build.status);
+    }
+
+    let new_build = NewCiBuild {
+        tenant_id: build.tenant_id,
+        project_id: build.project_id,
+        commit_sha: build.commit_sha.clone(),
+        branch: build.branch.clone(),
+        pr_number: build.pr_number,
+        author: build.author.clone(),
+        message: Some(format!("Retry of build #{}", build.id)),
+        fingerprint: retry_fingerprint(build),
+        trigger_event: "retry".to_string(),
+        status: BuildStatus::Pending.as_str().to_string(),
+        trigger_id: None,
+        trusted: build.trusted,
+    };
+
+    let retried = crate::services::build_service::create_build(conn, new_build).await?;
+
+    Ok(TriggerResponse {
+        id: retried.id,
+        status: retried.status,
+    })
+}
+
+/// Let the code of an untrusted pull request build see the project's
+/// secrets and OIDC tokens. A pending build is trusted in place; a finished
+/// one, which ran without them, is re-run trusted.
+pub async fn approve_build(
     conn: &mut AsyncPgConnection,
+    build: &CiBuild,
+) -> anyhow::Result<TriggerResponse> {
+    if build.trusted {
+        anyhow::bail!("build {} is already trusted", build.id);
+    }
+    let status = BuildStatus::parse(&build.status);
+    if status == Some(BuildStatus::Pending) {
+        let updated = diesel::update(ci_builds::table.find(build.id))
+            .filter(ci_builds::status.eq(BuildStatus::Pending.as_str()))
+            .set(ci_builds::trusted.eq(true))
+            .execute(conn)
+            .await?;
+        if updated > 0 {
+            return Ok(TriggerResponse {
+                id: build.id,
+                status: build.status.clone(),
+            });
+        }
+        anyhow::bail!("build {} has started meanwhile", build.id);
+    }
+    if !status.is_some_and(|s| s.is_terminal()) {
+        anyhow::bail!("build {} is still {}", build.id, build.status);
+    }
+
+    let approved = CiBuild {
+        trusted: true,
+        ..build.clone()
+    };
+    retry_build(conn, &approved).await
+}
+
+/// Fingerprint of the retry of `build`.
+fn retry_fingerprint(build: &CiBuild) -> String {
+    format!("{}-{}-retry-{}", build.commit_sha, build.branch, build.id)
+}
+
+/// SQL condition on `ci_builds` true of builds without a retry, a build
+/// with its [`retry_fingerprint`].
+const UNRETRIED_SQL: &str = "NOT EXISTS (SELECT 1 FROM ci_builds retry \
+     WHERE retry.fingerprint = ci_builds.commit_sha || '-' || ci_builds.branch || '-retry-' || ci_builds.id)";
+
+/// Statuses a bulk re-run retries: failures that can be spurious, not
+/// pipeline config errors.
+pub const RERUN_STATUSES: [BuildStatus; 2] = [BuildStatus::Failure, BuildStatus::Timeout];
+
+/// Most builds one bulk re-run retries.
+pub const MAX_BULK_RERUN: i64 = 100;
+
+/// Builds matching `filter` that have not been retried yet, oldest first,
+/// at most `limit`.
+pub async fn unretried_builds(
+    conn: &mut AsyncPgConnection,
+    filter: &BuildFilter,
     limit: i64,
-) -> anyhow::Result<Vec<BuildJson>> {
-    let builds: Vec<CiBuild> = ci_builds::table
-        .order(ci_builds::id.desc())
+) -> anyhow::Result<Vec<CiBuild>> {
+    let builds = filter
+        .query()
+        .filter(diesel::dsl::sql::<diesel::sql_types::Bool>(UNRETRIED_SQL))
+        .order(ci_builds::id.asc())
         .limit(limit)
         .load(conn)
         .await?;
+    Ok(builds)
+}
+
+#[derive(Debug, Serialize)]
+pub struct CancelResponse {
+    pub id: i64,
+    pub status: String,
+    /// The build was running: its executor kills the current step and then
+    /// finishes it as cancelled.
+    pub cancel_requested: bool,
+}
+
+/// Cancel a pending or running build.
+pub async fn cancel_build(
+    conn: &mut AsyncPgConnection,
+    build: &CiBuild,
+    dashboard_url: &str,
+) -> anyhow::Result<CancelResponse> {
+    use crate::services::build_service::{self, TransitionError};
+
+    match BuildStatus::parse(&build.status) {
+        Some(BuildStatus::Pending) => {
+            let target_url = format!("{dashboard_url}/api/builds/{}", build.id);
+            match build_service::cancel_queued(conn, build, target_url).await {
+                Ok(()) => {
+                    return Ok(CancelResponse {
+                        id: build.id,
+                        status: BuildStatus::Cancelled.as_str().to_string(),
+                        cancel_requested: false,
+                    })
+                }
+                // Claimed by the executor in the meantime; cancel it there.
+                Err(e)
+                    if matches!(
+                        e.downcast_ref::<TransitionError>(),
+                        Some(TransitionError::Illegal { from, .. }) if from == BuildStatus::Running.as_str()
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Some(BuildStatus::Running) => {}
+        _ => anyhow::bail!("build {} is already {}", build.id, build.status),
+    }
+
+    if !build_service::request_cancel(conn, build.id).await? {
+        anyhow::bail!("build {} is no longer running", build.id);
+    }
+    crate::services::executor::wake_cancelled();
+
+    Ok(CancelResponse {
+        id: build.id,
+        status: BuildStatus::Running.as_str().to_string(),
+        cancel_requested: true,
+    })
+}
+
+/// A step of a build, any attempt.
+pub async fn get_step(
+    conn: &mut AsyncPgConnection,
+    build_id: i64,
+    step_id: i64,
+) -> anyhow::Result<Option<CiBuildStep>> {
+    let step = ci_build_steps::table
+        .find(step_id)
+        .filter(ci_build_steps::build_id.eq(build_id))
+        .first(conn)
+        .await
+        .optional()?;
+    Ok(step)
+}
+
+/// Which part of a step log [`log_excerpt`] returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRange {
+    All,
+    /// The last lines.
+    Tail(usize),
+    /// Bytes from `offset`, to the end without a `length`.
+    Bytes {
+        offset: usize,
+        length: Option<usize>,
+    },
+}
+
+/// The part of `log` in `range`. Byte offsets are moved back to the
+/// nearest character boundary.
+pub fn log_excerpt(log: &str, range: LogRange) -> &str {
+    let floor = |mut i: usize| {
+        i = i.min(log.len());
+        while !log.is_char_boundary(i) {
+            i -= 1;
+        }
+        i
+    };
+    match range {
+        LogRange::All => log,
+        LogRange::Tail(0) => "",
+        LogRange::Tail(lines) => {
+            let body = log.strip_suffix('\n').unwrap_or(log);
+            let start = body
+                .rmatch_indices('\n')
+                .nth(lines - 1)
+                .map_or(0, |(i, _)| i + 1);
+            &log[start..]
+        }
+        LogRange::Bytes { offset, length } => {
+            let start = floor(offset);
+            let end = length.map_or(log.len(), |len| floor(offset.saturating_add(len)));
+            &log[start..end.max(start)]
+        }
+    }
+}
+
+/// Which builds [`list_builds`] returns. Unset fields don't filter.
+#[derive(Debug, Default)]
+pub struct BuildFilter {
+    pub project_id: Option<i64>,
+    /// Any of these statuses.
+    pub statuses: Vec<String>,
+    pub branch: Option<String>,
+    pub author: Option<String>,
+    pub trigger_event: Option<String>,
+    /// Created at or after.
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+    /// Created before.
+    pub until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl BuildFilter {
+    pub fn query(&self) -> ci_builds::BoxedQuery<'_, Pg> {
+        let mut query = ci_builds::table.into_boxed();
+        if let Some(project_id) = self.project_id {
+            query = query.filter(ci_builds::project_id.eq(project_id));
+        }
+        if !self.statuses.is_empty() {
+            query = query.filter(ci_builds::status.eq_any(&self.statuses));
+        }
+        if let Some(branch) = &self.branch {
+            query = query.filter(ci_builds::branch.eq(branch));
+        }
+        if let Some(author) = &self.author {
+            query = query.filter(ci_builds::author.eq(author));
+        }
+        if let Some(trigger_event) = &self.trigger_event {
+            query = query.filter(ci_builds::trigger_event.eq(trigger_event));
+        }
+        if let Some(since) = self.since {
+            query = query.filter(ci_builds::create_date.ge(since));
+        }
+        if let Some(until) = self.until {
+            query = query.filter(ci_builds::create_date.lt(until));
+        }
+        query
+    }
+}
+
+/// A page of [`list_builds`], newest first.
+#[derive(Debug)]
+pub struct Page {
+    pub limit: i64,
+    /// Builds to skip.
+    pub offset: i64,
+    /// Only builds older than this build ID; stable while new builds arrive,
+    /// unlike `offset`.
+    pub before: Option<i64>,
+}
+
+/// Builds of one page, with the number matching the filter overall.
+#[derive(Debug)]
+pub struct BuildPage {
+    pub builds: Vec<BuildJson>,
+    pub total: i64,
+    /// `before` of the next page; `None` on the last one.
+    pub next_cursor: Option<i64>,
+}
+
+/// List the builds matching `filter`, one page at a time.
+pub async fn list_builds(
+    conn: &mut AsyncPgConnection,
+    filter: &BuildFilter,
+    page: &Page,
+) -> anyhow::Result<BuildPage> {
+    let total: i64 = filter.query().count().get_result(conn).await?;
+
+    let mut query = filter.query();
+    if let Some(before) = page.before {
+        query = query.filter(ci_builds::id.lt(before));
+    }
+    let builds: Vec<CiBuild> = query
+        .order(ci_builds::id.desc())
+        .limit(page.limit)
+        .offset(page.offset)
+        .load(conn)
+        .await?;
+    let next_cursor = builds
+        .last()
+        .filter(|_| builds.len() as i64 == page.limit)
+        .map(|b| b.id);
 
     let mut result = Vec::with_capacity(builds.len());
     for build in builds {
         let steps: Vec<CiBuildStep> = ci_build_steps::table
             .filter(ci_build_steps::build_id.eq(build.id))
-            .order(ci_build_steps::sequence.asc())
+            .order((
+                ci_build_steps::sequence.asc(),
+                ci_build_steps::attempt.asc(),
+            ))
             .load(conn)
             .await?;
 
@@ -204,19 +640,277 @@ pub async fn list_builds(
             trigger_event: build.trigger_event,
             duration_ms: build.duration_ms,
             create_date: build.create_date,
+            trusted: build.trusted,
             steps: steps
                 .into_iter()
                 .map(|s| StepJson {
                     id: s.id,
                     name: s.name,
                     sequence: s.sequence,
+                    attempt: s.attempt,
                     status: s.status,
                     duration_ms: s.duration_ms,
                     exit_code: s.exit_code,
+                    max_rss_bytes: s.max_rss_bytes,
                 })
                 .collect(),
         });
     }
 
-    Ok(result)
+    Ok(BuildPage {
+        builds: result,
+        total,
+        next_cursor,
+    })
+}
+
+/// Days of finished builds the queue's wait estimate averages over.
+const QUEUE_ESTIMATE_DAYS: i32 = 7;
+
+/// A pending build, in claim order.
+#[derive(Debug, Serialize)]
+pub struct QueuedBuildJson {
+    pub id: i64,
+    pub project_id: i64,
+    pub branch: String,
+    pub commit_sha: String,
+    pub trigger_event: String,
+    /// Place in the queue, from 1; counts builds the caller cannot see.
+    pub position: usize,
+    pub queued_secs: i64,
+    /// Rough time until an executor claims it, without recent history `None`.
+    pub estimated_wait_secs: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunningBuildJson {
+    pub id: i64,
+    pub project_id: i64,
+    pub branch: String,
+    pub commit_sha: String,
+    pub trigger_event: String,
+    pub started_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub running_secs: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProjectQueueJson {
+    pub project_id: i64,
+    pub pending: usize,
+    pub running: usize,
+}
+
+/// JSON response for the build queue.
+#[derive(Debug, Serialize)]
+pub struct QueueJson {
+    pub pending: Vec<QueuedBuildJson>,
+    pub running: Vec<RunningBuildJson>,
+    /// Queue depth per project with pending or running builds.
+    pub projects: Vec<ProjectQueueJson>,
+    /// Average build duration the estimates assume.
+    pub avg_duration_ms: Option<f64>,
+}
+
+/// Pending and running builds with wait estimates. `slots` is how many
+/// builds run at once; `visible` picks the projects whose builds are listed,
+/// which doesn't change the positions and estimates of the others.
+pub async fn queue_status(
+    conn: &mut AsyncPgConnection,
+    slots: usize,
+    visible: impl Fn(i64) -> bool,
+) -> anyhow::Result<QueueJson> {
+    let pending: Vec<CiBuild> = ci_builds::table
+        .filter(ci_builds::status.eq(BuildStatus::Pending.as_str()))
+        .order(ci_builds::id.asc())
+        .load(conn)
+        .await?;
+    let running: Vec<CiBuild> = ci_builds::table
+        .filter(ci_builds::status.eq(BuildStatus::Running.as_str()))
+        .order(ci_builds::started_at.asc())
+        .load(conn)
+        .await?;
+    let avg_duration_ms =
+        crate::dashboard::kpi::query_avg_duration(conn, QUEUE_ESTIMATE_DAYS, None, None, None)
+            .await?
+            .avg_ms;
+
+    let now = chrono::Utc::now();
+    let elapsed_ms: Vec<i64> = running
+        .iter()
+        .map(|b| {
+            b.started_at
+                .map_or(0, |t| (now - t).num_milliseconds().max(0))
+        })
+        .collect();
+    let waits = avg_duration_ms
+        .map(|avg| estimate_waits(avg as i64, slots, &elapsed_ms, pending.len()))
+        .unwrap_or_default();
+
+    let mut projects: std::collections::BTreeMap<i64, ProjectQueueJson> = Default::default();
+    for (build, pending) in running
+        .iter()
+        .map(|b| (b, false))
+        .chain(pending.iter().map(|b| (b, true)))
+        .filter(|(b, _)| visible(b.project_id))
+    {
+        let depth = projects
+            .entry(build.project_id)
+            .or_insert(ProjectQueueJson {
+                project_id: build.project_id,
+                pending: 0,
+                running: 0,
+            });
+        if pending {
+            depth.pending += 1;
+        } else {
+            depth.running += 1;
+        }
+    }
+
+    Ok(QueueJson {
+        pending: pending
+            .into_iter()
+            .enumerate()
+            .filter(|(_, b)| visible(b.project_id))
+            .map(|(i, b)| QueuedBuildJson {
+                id: b.id,
+                project_id: b.project_id,
+                position: i + 1,
+                queued_secs: b.create_date.map_or(0, |t| (now - t).num_seconds().max(0)),
+                estimated_wait_secs: waits.get(i).map(|ms| ms / 1000),
+                branch: b.branch,
+                commit_sha: b.commit_sha,
+                trigger_event: b.trigger_event,
+            })
+            .collect(),
+        running: running
+            .into_iter()
+            .zip(elapsed_ms)
+            .filter(|(b, _)| visible(b.project_id))
+            .map(|(b, elapsed)| RunningBuildJson {
+                id: b.id,
+                project_id: b.project_id,
+                branch: b.branch,
+                commit_sha: b.commit_sha,
+                trigger_event: b.trigger_event,
+                started_at: b.started_at,
+                running_secs: elapsed / 1000,
+            })
+            .collect(),
+        projects: projects.into_values().collect(),
+        avg_duration_ms,
+    })
+}
+
+/// Milliseconds until each of `pending` queued builds starts, if every
+/// build takes `avg_ms` and `slots` run at once, the first of them already
+/// `running_ms` in.
+pub fn estimate_waits(avg_ms: i64, slots: usize, running_ms: &[i64], pending: usize) -> Vec<i64> {
+    use std::cmp::Reverse;
+    use std::collections::BinaryHeap;
+
+    // When each slot frees up next.
+    let mut free_at: BinaryHeap<Reverse<i64>> = running_ms
+        .iter()
+        .map(|elapsed| Reverse((avg_ms - elapsed).max(0)))
+        .collect();
+    while free_at.len() < slots.max(1) {
+        free_at.push(Reverse(0));
+    }
+    (0..pending)
+        .map(|_| {
+            let Reverse(start) = free_at.pop().unwrap_or(Reverse(0));
+            free_at.push(Reverse(start + avg_ms));
+            start
+        })
+        .collect()
+}
+
+/// Most results one build search returns.
+pub const MAX_SEARCH_RESULTS: i64 = 100;
+
+/// A build matching a search, best match first.
+#[derive(Debug, Serialize)]
+pub struct BuildSearchHit {
+    pub id: i64,
+    pub project_id: i64,
+    pub commit_sha: String,
+    pub branch: String,
+    pub pr_number: Option<i32>,
+    pub author: Option<String>,
+    pub message: Option<String>,
+    pub status: String,
+    pub create_date: Option<chrono::DateTime<chrono::Utc>>,
+    pub rank: f64,
+}
+
+/// Builds whose commit message, author or branch, or one of whose error
+/// titles, match `query` (web search syntax: words, `"phrases"`, `-not`,
+/// `or`), ranked by relevance.
+pub async fn search_builds(
+    conn: &mut AsyncPgConnection,
+    query: &str,
+    project_id: Option<i64>,
+    limit: i64,
+) -> anyhow::Result<Vec<BuildSearchHit>> {
+    use diesel::sql_types::{BigInt, Double, Nullable, Text};
+
+    #[derive(QueryableByName)]
+    struct Hit {
+        #[diesel(sql_type = BigInt)]
+        id: i64,
+        #[diesel(sql_type = Double)]
+        rank: f64,
+    }
+
+    let hits: Vec<Hit> = diesel::sql_query(
+        "WITH q AS (SELECT websearch_to_tsquery('simple', $1) AS query), \
+         hits AS ( \
+            SELECT b.id AS build_id, ts_rank(b.search_vector, q.query) AS rank \
+            FROM ci_builds b, q WHERE b.search_vector @@ q.query \
+            UNION ALL \
+            SELECT o.build_id, ts_rank(e.search_vector, q.query) \
+            FROM ci_errors e JOIN ci_error_occurrences o ON o.error_id = e.id, q \
+            WHERE e.search_vector @@ q.query \
+         ) \
+         SELECT h.build_id AS id, SUM(h.rank)::float8 AS rank \
+         FROM hits h JOIN ci_builds b ON b.id = h.build_id \
+         WHERE ($2::bigint IS NULL OR b.project_id = $2) \
+         GROUP BY h.build_id \
+         ORDER BY rank DESC, h.build_id DESC \
+         LIMIT $3",
+    )
+    .bind::<Text, _>(query)
+    .bind::<Nullable<BigInt>, _>(project_id)
+    .bind::<BigInt, _>(limit)
+    .load(conn)
+    .await?;
+
+    let ids: Vec<i64> = hits.iter().map(|h| h.id).collect();
+    let mut builds: std::collections::HashMap<i64, CiBuild> = ci_builds::table
+        .filter(ci_builds::id.eq_any(&ids))
+        .load::<CiBuild>(conn)
+        .await?
+        .into_iter()
+        .map(|b| (b.id, b))
+        .collect();
+
+    Ok(hits
+        .into_iter()
+        .filter_map(|hit| {
+            let b = builds.remove(&hit.id)?;
+            Some(BuildSearchHit {
+                id: b.id,
+                project_id: b.project_id,
+                commit_sha: b.commit_sha,
+                branch: b.branch,
+                pr_number: b.pr_number,
+                author: b.author,
+                message: b.message,
+                status: b.status,
+                create_date: b.create_date,
+                rank: hit.rank,
+            })
+        })
+        .collect())
 }
@@ -0,0 +1,128 @@
+//! Request authentication for the CI API.
+//!
+//! [`CiPrincipal`] resolves the caller from a `Bearer` API token or the
+//! framework session cookie and carries the tenant used to scope database
+//! access (row-level security).
+
+use axum::extract::{FromRequestParts, OptionalFromRequestParts};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use uuid::Uuid;
+
+use crate::services::token_service;
+
+use super::CiRouterState;
+
+/// Name of the framework's session cookie.
+const SESSION_COOKIE: &str = "session_id";
+
+/// The authenticated caller of a CI API request.
+#[derive(Debug, Clone)]
+pub struct CiPrincipal {
+    pub uid: i64,
+    pub tenant_id: Uuid,
+    /// Set when authenticated by a project-scoped API token.
+    pub project_id: Option<i64>,
+    /// Set when authenticated by an API token rather than a session.
+    pub token_id: Option<i64>,
+}
+
+impl CiPrincipal {
+    /// Whether the caller may act on `project_id`.
+    pub fn can_access_project(&self, project_id: i64) -> bool {
+        self.project_id.is_none_or(|scope| scope == project_id)
+    }
+}
+
+impl FromRequestParts<CiRouterState> for CiPrincipal {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &CiRouterState,
+    ) -> Result<Self, Self::Rejection> {
+        <Self as OptionalFromRequestParts<CiRouterState>>::from_request_parts(parts, state)
+            .await?
+            .ok_or_else(|| StatusCode::UNAUTHORIZED.into_response())
+    }
+}
+
+/// `Option<CiPrincipal>` for endpoints that also serve anonymous callers
+/// (public projects). A bad API token is still rejected; a stale session
+/// cookie just means anonymous. API token requests are rate limited here,
+/// once the token is verified (see [`super::rate_limit`]).
+impl OptionalFromRequestParts<CiRouterState> for CiPrincipal {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &CiRouterState,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        if let Some(token) = bearer_token(&parts.headers) {
+            state.rate_limits.before_token(parts)?;
+            let principal = token_principal(state, &token).await;
+            let token_id = principal.as_ref().ok().and_then(|p| p.token_id);
+            if matches!(principal, Ok(_) | Err(StatusCode::UNAUTHORIZED)) {
+                state.rate_limits.after_token(parts, token_id)?;
+            }
+            return principal.map(Some).map_err(IntoResponse::into_response);
+        }
+
+        let Some(session_id) = session_cookie(&parts.headers) else {
+            return Ok(None);
+        };
+        Ok(state
+            .session_store
+            .get(&session_id)
+            .await
+            .map(|session| CiPrincipal {
+                uid: session.uid,
+                tenant_id: session.tenant_id,
+                project_id: None,
+                token_id: None,
+            }))
+    }
+}
+
+async fn token_principal(state: &CiRouterState, token: &str) -> Result<CiPrincipal, StatusCode> {
+    let mut conn = crate::db::system_conn(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let token = token_service::verify_token(&mut conn, token)
+        .await
+        .map_err(|e| {
+            tracing::error!("API token lookup failed: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    Ok(CiPrincipal {
+        uid: token.uid,
+        tenant_id: token.tenant_id,
+        project_id: token.project_id,
+        token_id: Some(token.id),
+    })
+}
+
+/// Extract a CI API token from `Authorization: Bearer ...`.
+pub fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .filter(|t| t.starts_with(token_service::TOKEN_PREFIX))
+        .map(|t| t.trim().to_string())
+}
+
+/// Extract the session id from the `Cookie` header.
+fn session_cookie(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(axum::http::header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value.to_string())
+}
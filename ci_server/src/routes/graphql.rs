@@ -0,0 +1,677 @@
+//! GraphQL API over projects, builds and environments.
+//!
+//! `POST /graphql` serves read-only queries, so a dashboard can fetch e.g.
+//! a project's recent failed builds with their steps and errors in one
+//! round trip:
+//!
+//! ```graphql
+//! { builds(filter: {projectId: 3, statuses: ["failure"]}, limit: 10) {
+//!     id branch status steps { name status durationMs } errors { title } } }
+//! ```
+//!
+//! The caller's read permission on each model is checked once per request,
+//! as the REST handlers check it per call, and every resolver of a model
+//! the caller cannot read fails. Project-scoped tokens only see their
+//! project. Step output is not exposed; fetch it from the step log
+//! endpoint, which masks secrets.
+//!
+//! A request runs on one tenant connection. The fields of a build list
+//! (`project`, `steps`, `errors`, `artifacts`) are batched through a
+//! [`DataLoader`], one query per field for all the builds.
+
+use std::collections::{HashMap, HashSet};
+use std::ops::DerefMut;
+use std::sync::{Arc, LazyLock};
+
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::{
+    ComplexObject, Context, EmptyMutation, EmptySubscription, InputObject, Object, Schema,
+    ServerError, SimpleObject,
+};
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use axum::extract::State;
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+
+use super::auth::CiPrincipal;
+use super::{api, CiRouterState};
+use crate::models::artifact::CiArtifact;
+use crate::models::build::CiBuild;
+use crate::models::build_step::CiBuildStep;
+use crate::models::environment::CiEnvironment;
+use crate::models::error::CiError;
+use crate::models::project::CiProject;
+use crate::schema::{
+    ci_artifacts, ci_build_steps, ci_builds, ci_environments, ci_error_occurrences, ci_errors,
+    ci_projects,
+};
+use crate::services::access_service::{self, Perm};
+use crate::services::project_service;
+
+/// Most items one list field returns.
+const MAX_LIMIT: i64 = 200;
+
+/// Deepest selection nesting accepted.
+const MAX_DEPTH: usize = 8;
+
+/// Most fields one query may resolve.
+const MAX_COMPLEXITY: usize = 2_000;
+
+pub type CiSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+static SCHEMA: LazyLock<CiSchema> = LazyLock::new(|| {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+});
+
+/// Models the resolvers read.
+const MODELS: &[&str] = &[
+    "ci.project",
+    "ci.build",
+    "ci.build.step",
+    "ci.error",
+    "ci.artifact",
+    "ci.environment",
+];
+
+/// Execute a GraphQL request on behalf of the caller.
+pub async fn graphql_handler(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    let caller = match Caller::authorize(&state, principal).await {
+        Ok(caller) => Arc::new(caller),
+        Err(status) => {
+            let reason = status.canonical_reason().unwrap_or("request failed");
+            return async_graphql::Response::from_errors(vec![ServerError::new(reason, None)])
+                .into();
+        }
+    };
+    let loader = DataLoader::new(BuildLoader(caller.clone()), tokio::spawn);
+    let request = request.into_inner().data(caller).data(loader);
+    SCHEMA.execute(request).await.into()
+}
+
+type Conn = Box<dyn DerefMut<Target = AsyncPgConnection> + Send>;
+
+/// Per-request context of the resolvers.
+struct Caller {
+    principal: CiPrincipal,
+    /// Of [`MODELS`], those the caller may read.
+    readable: HashSet<&'static str>,
+    conn: Mutex<Conn>,
+}
+
+impl Caller {
+    /// Acquire the request's connection and check which models the caller
+    /// may read.
+    async fn authorize(state: &CiRouterState, principal: CiPrincipal) -> Result<Self, StatusCode> {
+        let mut conn = state.tenant_conn(&principal).await?;
+        let mut readable = HashSet::new();
+        for &model in MODELS {
+            match access_service::check_access(&mut conn, principal.uid, model, Perm::Read).await {
+                Ok(true) => {
+                    readable.insert(model);
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::error!("CI access check error: {e}");
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+            }
+        }
+        Ok(Self {
+            principal,
+            readable,
+            conn: Mutex::new(Box::new(conn)),
+        })
+    }
+
+    /// The request's connection, once the caller may read `model`.
+    async fn conn(
+        &self,
+        model: &str,
+    ) -> async_graphql::Result<MappedMutexGuard<'_, AsyncPgConnection>> {
+        if !self.readable.contains(model) {
+            tracing::warn!(uid = self.principal.uid, model, "CI access denied");
+            return Err(denied(StatusCode::FORBIDDEN));
+        }
+        Ok(MutexGuard::map(self.conn.lock().await, |conn| &mut ***conn))
+    }
+}
+
+fn caller<'a>(ctx: &Context<'a>) -> &'a Caller {
+    ctx.data_unchecked::<Arc<Caller>>()
+}
+
+fn loader<'a>(ctx: &Context<'a>) -> &'a DataLoader<BuildLoader> {
+    ctx.data_unchecked::<DataLoader<BuildLoader>>()
+}
+
+/// Map a rejected connection to a GraphQL error.
+fn denied(status: StatusCode) -> async_graphql::Error {
+    async_graphql::Error::new(status.canonical_reason().unwrap_or("request failed"))
+}
+
+fn internal(e: impl std::fmt::Display) -> async_graphql::Error {
+    tracing::error!("GraphQL resolver error: {e}");
+    async_graphql::Error::new("internal error")
+}
+
+fn clamp_limit(limit: Option<i64>, default: i64) -> i64 {
+    limit.unwrap_or(default).clamp(1, MAX_LIMIT)
+}
+
+#[derive(Clone, SimpleObject)]
+#[graphql(complex)]
+pub struct Project {
+    pub id: i64,
+    pub name: String,
+    pub github_repo: String,
+    pub default_branch: String,
+    pub visibility: String,
+    pub provider: String,
+    pub build_all_commits: bool,
+}
+
+impl From<CiProject> for Project {
+    fn from(p: CiProject) -> Self {
+        Self {
+            id: p.id,
+            name: p.name,
+            github_repo: p.github_repo,
+            default_branch: p.default_branch,
+            visibility: p.visibility,
+            provider: p.provider,
+            build_all_commits: p.build_all_commits,
+        }
+    }
+}
+
+#[ComplexObject]
+impl Project {
+    /// The project's builds, newest first.
+    async fn builds(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<BuildFilterInput>,
+        limit: Option<i64>,
+    ) -> async_graphql::Result<Vec<Build>> {
+        let filter = api::BuildFilter {
+            project_id: Some(self.id),
+            ..filter.unwrap_or_default().into_filter()
+        };
+        load_builds(ctx, &filter, clamp_limit(limit, 20), 0).await
+    }
+
+    /// The project's environments, newest first.
+    async fn environments(
+        &self,
+        ctx: &Context<'_>,
+        status: Option<String>,
+    ) -> async_graphql::Result<Vec<Environment>> {
+        load_environments(ctx, Some(self.id), status).await
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct Build {
+    pub id: i64,
+    pub project_id: i64,
+    pub commit_sha: String,
+    pub branch: String,
+    pub pr_number: Option<i32>,
+    pub author: Option<String>,
+    pub message: Option<String>,
+    pub status: String,
+    pub trigger_event: String,
+    pub trigger_id: Option<i64>,
+    pub duration_ms: Option<i32>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub create_date: Option<DateTime<Utc>>,
+}
+
+impl From<CiBuild> for Build {
+    fn from(b: CiBuild) -> Self {
+        Self {
+            id: b.id,
+            project_id: b.project_id,
+            commit_sha: b.commit_sha,
+            branch: b.branch,
+            pr_number: b.pr_number,
+            author: b.author,
+            message: b.message,
+            status: b.status,
+            trigger_event: b.trigger_event,
+            trigger_id: b.trigger_id,
+            duration_ms: b.duration_ms,
+            started_at: b.started_at,
+            finished_at: b.finished_at,
+            create_date: b.create_date,
+        }
+    }
+}
+
+#[ComplexObject]
+impl Build {
+    async fn project(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Project>> {
+        loader(ctx).load_one(ProjectOf(self.project_id)).await
+    }
+
+    /// Every step attempt, in pipeline order.
+    async fn steps(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Step>> {
+        let steps = loader(ctx).load_one(StepsOf(self.id)).await?;
+        Ok(steps.unwrap_or_default())
+    }
+
+    /// Errors that occurred in this build.
+    async fn errors(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<BuildError>> {
+        let errors = loader(ctx).load_one(ErrorsOf(self.id)).await?;
+        Ok(errors.unwrap_or_default())
+    }
+
+    async fn artifacts(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Artifact>> {
+        let artifacts = loader(ctx).load_one(ArtifactsOf(self.id)).await?;
+        Ok(artifacts.unwrap_or_default())
+    }
+}
+
+#[derive(Clone, SimpleObject)]
+pub struct Step {
+    pub id: i64,
+    pub name: String,
+    pub sequence: i32,
+    pub attempt: i32,
+    pub status: String,
+    pub exit_code: Option<i32>,
+    pub duration_ms: Option<i32>,
+    pub max_rss_bytes: Option<i64>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl From<CiBuildStep> for Step {
+    fn from(s: CiBuildStep) -> Self {
+        Self {
+            id: s.id,
+            name: s.name,
+            sequence: s.sequence,
+            attempt: s.attempt,
+            status: s.status,
+            exit_code: s.exit_code,
+            duration_ms: s.duration_ms,
+            max_rss_bytes: s.max_rss_bytes,
+            started_at: s.started_at,
+            finished_at: s.finished_at,
+        }
+    }
+}
+
+#[derive(Clone, SimpleObject)]
+pub struct BuildError {
+    pub id: i64,
+    pub category: String,
+    pub severity: String,
+    pub title: String,
+    pub file_path: Option<String>,
+    pub line_number: Option<i32>,
+    pub status: String,
+    pub occurrence_count: i32,
+    pub first_seen_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub assigned_to: Option<String>,
+    /// CODEOWNERS owners of the file the error was last seen in.
+    pub owners: Vec<String>,
+}
+
+impl From<CiError> for BuildError {
+    fn from(e: CiError) -> Self {
+        Self {
+            id: e.id,
+            category: e.category,
+            severity: e.severity,
+            title: e.title,
+            file_path: e.file_path,
+            line_number: e.line_number,
+            status: e.status,
+            occurrence_count: e.occurrence_count,
+            first_seen_at: e.first_seen_at,
+            last_seen_at: e.last_seen_at,
+            assigned_to: e.assigned_to,
+            owners: e.owners.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, SimpleObject)]
+pub struct Artifact {
+    pub id: i64,
+    pub name: String,
+    pub artifact_type: String,
+    pub size_bytes: Option<i64>,
+    pub create_date: Option<DateTime<Utc>>,
+}
+
+impl From<CiArtifact> for Artifact {
+    fn from(a: CiArtifact) -> Self {
+        Self {
+            id: a.id,
+            name: a.name,
+            artifact_type: a.artifact_type,
+            size_bytes: a.size_bytes,
+            create_date: a.create_date,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Environment {
+    pub id: i64,
+    pub project_id: i64,
+    pub build_id: Option<i64>,
+    pub pr_number: i32,
+    pub branch: String,
+    pub commit_sha: String,
+    pub status: String,
+    pub url: Option<String>,
+    pub last_activity: Option<DateTime<Utc>>,
+    pub destroyed_reason: Option<String>,
+    pub error: Option<String>,
+    pub restart_count: i32,
+}
+
+impl From<CiEnvironment> for Environment {
+    fn from(e: CiEnvironment) -> Self {
+        Self {
+            id: e.id,
+            project_id: e.project_id,
+            build_id: e.build_id,
+            pr_number: e.pr_number,
+            branch: e.branch,
+            commit_sha: e.commit_sha,
+            status: e.status,
+            url: e.url,
+            last_activity: e.last_activity,
+            destroyed_reason: e.destroyed_reason,
+            error: e.error,
+            restart_count: e.restart_count,
+        }
+    }
+}
+
+/// Build filters; unset fields don't filter.
+#[derive(InputObject, Default)]
+pub struct BuildFilterInput {
+    pub project_id: Option<i64>,
+    /// Any of these statuses.
+    #[graphql(default)]
+    pub statuses: Vec<String>,
+    pub branch: Option<String>,
+    pub author: Option<String>,
+    pub trigger_event: Option<String>,
+    /// Created at or after.
+    pub since: Option<DateTime<Utc>>,
+    /// Created before.
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl BuildFilterInput {
+    fn into_filter(self) -> api::BuildFilter {
+        api::BuildFilter {
+            project_id: self.project_id,
+            statuses: self.statuses,
+            branch: self.branch,
+            author: self.author,
+            trigger_event: self.trigger_event,
+            since: self.since,
+            until: self.until,
+        }
+    }
+}
+
+async fn load_builds(
+    ctx: &Context<'_>,
+    filter: &api::BuildFilter,
+    limit: i64,
+    offset: i64,
+) -> async_graphql::Result<Vec<Build>> {
+    let caller = caller(ctx);
+    if filter
+        .project_id
+        .is_some_and(|id| !caller.principal.can_access_project(id))
+    {
+        return Ok(Vec::new());
+    }
+    let mut conn = caller.conn("ci.build").await?;
+    let mut query = filter.query();
+    if let Some(scope) = caller.principal.project_id {
+        query = query.filter(ci_builds::project_id.eq(scope));
+    }
+    let builds: Vec<CiBuild> = query
+        .order(ci_builds::id.desc())
+        .limit(limit)
+        .offset(offset.max(0))
+        .load(&mut *conn)
+        .await
+        .map_err(internal)?;
+    Ok(builds.into_iter().map(Into::into).collect())
+}
+
+async fn load_environments(
+    ctx: &Context<'_>,
+    project_id: Option<i64>,
+    status: Option<String>,
+) -> async_graphql::Result<Vec<Environment>> {
+    let caller = caller(ctx);
+    let project_id = project_id.or(caller.principal.project_id);
+    if project_id.is_some_and(|id| !caller.principal.can_access_project(id)) {
+        return Ok(Vec::new());
+    }
+    let mut conn = caller.conn("ci.environment").await?;
+    let mut query = ci_environments::table
+        .filter(ci_environments::active.eq(true))
+        .into_boxed();
+    if let Some(project_id) = project_id {
+        query = query.filter(ci_environments::project_id.eq(project_id));
+    }
+    if let Some(status) = status {
+        query = query.filter(ci_environments::status.eq(status));
+    }
+    let environments: Vec<CiEnvironment> = query
+        .order(ci_environments::id.desc())
+        .limit(MAX_LIMIT)
+        .load(&mut *conn)
+        .await
+        .map_err(internal)?;
+    Ok(environments.into_iter().map(Into::into).collect())
+}
+
+/// Batches the per-build fields of a request.
+struct BuildLoader(Arc<Caller>);
+
+/// [`BuildLoader`] keys: the build or project id a field is looked up by.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct ProjectOf(i64);
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct StepsOf(i64);
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct ErrorsOf(i64);
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct ArtifactsOf(i64);
+
+impl Loader<ProjectOf> for BuildLoader {
+    type Value = Project;
+    type Error = async_graphql::Error;
+
+    async fn load(&self, keys: &[ProjectOf]) -> Result<HashMap<ProjectOf, Project>, Self::Error> {
+        let ids: Vec<i64> = keys.iter().map(|k| k.0).collect();
+        let mut conn = self.0.conn("ci.project").await?;
+        let projects: Vec<CiProject> = ci_projects::table
+            .filter(ci_projects::id.eq_any(&ids))
+            .load(&mut *conn)
+            .await
+            .map_err(internal)?;
+        Ok(projects
+            .into_iter()
+            .map(|p| (ProjectOf(p.id), p.into()))
+            .collect())
+    }
+}
+
+impl Loader<StepsOf> for BuildLoader {
+    type Value = Vec<Step>;
+    type Error = async_graphql::Error;
+
+    async fn load(&self, keys: &[StepsOf]) -> Result<HashMap<StepsOf, Vec<Step>>, Self::Error> {
+        let build_ids: Vec<i64> = keys.iter().map(|k| k.0).collect();
+        let mut conn = self.0.conn("ci.build.step").await?;
+        let steps: Vec<CiBuildStep> = ci_build_steps::table
+            .filter(ci_build_steps::build_id.eq_any(&build_ids))
+            .order((
+                ci_build_steps::build_id.asc(),
+                ci_build_steps::sequence.asc(),
+                ci_build_steps::attempt.asc(),
+            ))
+            .load(&mut *conn)
+            .await
+            .map_err(internal)?;
+        let mut by_build: HashMap<StepsOf, Vec<Step>> = HashMap::new();
+        for step in steps {
+            by_build
+                .entry(StepsOf(step.build_id))
+                .or_default()
+                .push(step.into());
+        }
+        Ok(by_build)
+    }
+}
+
+impl Loader<ErrorsOf> for BuildLoader {
+    type Value = Vec<BuildError>;
+    type Error = async_graphql::Error;
+
+    async fn load(
+        &self,
+        keys: &[ErrorsOf],
+    ) -> Result<HashMap<ErrorsOf, Vec<BuildError>>, Self::Error> {
+        let build_ids: Vec<i64> = keys.iter().map(|k| k.0).collect();
+        let mut conn = self.0.conn("ci.error").await?;
+        let errors: Vec<(i64, CiError)> = ci_error_occurrences::table
+            .inner_join(ci_errors::table)
+            .filter(ci_error_occurrences::build_id.eq_any(&build_ids))
+            .select((ci_error_occurrences::build_id, CiError::as_select()))
+            .distinct()
+            .order((ci_error_occurrences::build_id.asc(), ci_errors::id.asc()))
+            .load(&mut *conn)
+            .await
+            .map_err(internal)?;
+        let mut by_build: HashMap<ErrorsOf, Vec<BuildError>> = HashMap::new();
+        for (build_id, error) in errors {
+            by_build
+                .entry(ErrorsOf(build_id))
+                .or_default()
+                .push(error.into());
+        }
+        Ok(by_build)
+    }
+}
+
+impl Loader<ArtifactsOf> for BuildLoader {
+    type Value = Vec<Artifact>;
+    type Error = async_graphql::Error;
+
+    async fn load(
+        &self,
+        keys: &[ArtifactsOf],
+    ) -> Result<HashMap<ArtifactsOf, Vec<Artifact>>, Self::Error> {
+        let build_ids: Vec<i64> = keys.iter().map(|k| k.0).collect();
+        let mut conn = self.0.conn("ci.artifact").await?;
+        let artifacts: Vec<CiArtifact> = ci_artifacts::table
+            .filter(ci_artifacts::build_id.eq_any(&build_ids))
+            .filter(ci_artifacts::active.eq(true))
+            .order((ci_artifacts::build_id.asc(), ci_artifacts::id.asc()))
+            .load(&mut *conn)
+            .await
+            .map_err(internal)?;
+        let mut by_build: HashMap<ArtifactsOf, Vec<Artifact>> = HashMap::new();
+        for artifact in artifacts {
+            by_build
+                .entry(ArtifactsOf(artifact.build_id))
+                .or_default()
+                .push(artifact.into());
+        }
+        Ok(by_build)
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Active projects visible to the caller.
+    async fn projects(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Project>> {
+        let caller = caller(ctx);
+        let mut conn = caller.conn("ci.project").await?;
+        let projects = project_service::list_projects(&mut conn)
+            .await
+            .map_err(internal)?;
+        Ok(projects
+            .into_iter()
+            .filter(|p| caller.principal.can_access_project(p.id))
+            .map(Into::into)
+            .collect())
+    }
+
+    async fn project(&self, ctx: &Context<'_>, id: i64) -> async_graphql::Result<Option<Project>> {
+        let caller = caller(ctx);
+        if !caller.principal.can_access_project(id) {
+            return Ok(None);
+        }
+        let mut conn = caller.conn("ci.project").await?;
+        let project = project_service::get_project(&mut conn, id)
+            .await
+            .map_err(internal)?;
+        Ok(project.map(Into::into))
+    }
+
+    /// Builds matching `filter`, newest first.
+    async fn builds(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<BuildFilterInput>,
+        limit: Option<i64>,
+        offset: Option<i64>,
+    ) -> async_graphql::Result<Vec<Build>> {
+        let filter = filter.unwrap_or_default().into_filter();
+        load_builds(ctx, &filter, clamp_limit(limit, 50), offset.unwrap_or(0)).await
+    }
+
+    async fn build(&self, ctx: &Context<'_>, id: i64) -> async_graphql::Result<Option<Build>> {
+        let caller = caller(ctx);
+        let mut conn = caller.conn("ci.build").await?;
+        let build = crate::services::build_service::get_build(&mut conn, id)
+            .await
+            .map_err(internal)?;
+        Ok(build
+            .filter(|b| caller.principal.can_access_project(b.project_id))
+            .map(Into::into))
+    }
+
+    /// Active environments, newest first.
+    async fn environments(
+        &self,
+        ctx: &Context<'_>,
+        project_id: Option<i64>,
+        status: Option<String>,
+    ) -> async_graphql::Result<Vec<Environment>> {
+        load_environments(ctx, project_id, status).await
+    }
+}
@@ -1,46 +1,343 @@
-//! CI platform HTTP routes — webhook, API, WebSocket.
+//! CI platform HTTP routes — webhook, API, public read-only, WebSocket.
 
 pub mod api;
+pub mod auth;
+pub mod graphql;
+pub mod public;
+pub mod rate_limit;
 pub mod webhook;
 pub mod websocket;
 
+use std::ops::DerefMut;
 use std::sync::Arc;
 
 use axum::body::Bytes;
-use axum::extract::{Path, Query, State};
+use axum::extract::{DefaultBodyLimit, Multipart, Path, Query, State};
 use axum::http::{HeaderMap, StatusCode};
 use axum::response::Json;
-use axum::routing::{get, post};
+use axum::routing::{delete, get, post, put};
 use axum::Router;
+use diesel_async::AsyncPgConnection;
+use tracing::Instrument;
 
 use erp_core::db::diesel_pool::DieselPool;
+use erp_web::session::SessionStore;
 
 use crate::config::CiConfig;
+use crate::services::access_service::{self, BuildAction, Perm};
+use crate::services::{alert_service, notification_rules, notification_service, policy_service};
+use auth::CiPrincipal;
 
 /// Shared state for CI route handlers.
 #[derive(Clone)]
 pub struct CiRouterState {
     pub pool: Arc<DieselPool>,
     pub config: CiConfig,
+    pub session_store: Arc<SessionStore>,
+    pub rate_limits: Arc<rate_limit::RateLimits>,
+}
+
+impl CiRouterState {
+    /// Acquire a connection scoped to the caller's tenant.
+    pub async fn tenant_conn(
+        &self,
+        principal: &CiPrincipal,
+    ) -> Result<impl DerefMut<Target = AsyncPgConnection> + Send, StatusCode> {
+        crate::db::tenant_conn(&self.pool, principal.tenant_id)
+            .await
+            .map_err(|e| {
+                tracing::error!("CI db connection error: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })
+    }
+
+    /// Acquire a tenant connection after checking that the caller holds
+    /// `perm` on `model` in `ir_model_access`. Returns 403 otherwise.
+    pub async fn authorized_conn(
+        &self,
+        principal: &CiPrincipal,
+        model: &str,
+        perm: Perm,
+    ) -> Result<impl DerefMut<Target = AsyncPgConnection> + Send, StatusCode> {
+        let mut conn = self.tenant_conn(principal).await?;
+        match access_service::check_access(&mut conn, principal.uid, model, perm).await {
+            Ok(true) => Ok(conn),
+            Ok(false) => {
+                tracing::warn!(uid = principal.uid, model, %perm, "CI access denied");
+                Err(StatusCode::FORBIDDEN)
+            }
+            Err(e) => {
+                tracing::error!("CI access check error: {e}");
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
+    /// Acquire a tenant connection after checking that the caller may take
+    /// `action` on `project_id`: project scope, the action's model
+    /// permission, and the project's own grants. Returns 403 otherwise.
+    pub async fn authorized_action_conn(
+        &self,
+        principal: &CiPrincipal,
+        project_id: i64,
+        action: BuildAction,
+    ) -> Result<impl DerefMut<Target = AsyncPgConnection> + Send, StatusCode> {
+        if !principal.can_access_project(project_id) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        let (model, perm) = action.model_perm();
+        let mut conn = self.authorized_conn(principal, model, perm).await?;
+        match access_service::check_project_action(&mut conn, principal.uid, project_id, action)
+            .await
+        {
+            Ok(true) => Ok(conn),
+            Ok(false) => {
+                tracing::warn!(uid = principal.uid, project_id, %action, "CI project action denied");
+                Err(StatusCode::FORBIDDEN)
+            }
+            Err(e) => {
+                tracing::error!("CI project access check error: {e}");
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+/// Record a manual build action; failures are logged, not returned, since
+/// the action itself already happened.
+async fn audit_action(
+    conn: &mut AsyncPgConnection,
+    principal: &CiPrincipal,
+    project_id: i64,
+    build_id: Option<i64>,
+    action: BuildAction,
+) {
+    if let Err(e) = crate::services::audit_service::record_build_action(
+        conn,
+        principal.tenant_id,
+        project_id,
+        build_id,
+        action,
+        principal.uid,
+        principal.token_id,
+    )
+    .await
+    {
+        tracing::error!(project_id, build_id, "Build action audit failed: {e}");
+    }
 }
 
 /// Build the CI platform's Axum router (nested at `/ci`).
 pub fn ci_router(state: CiRouterState) -> Router {
+    let max_artifact_bytes = state.config.max_artifact_bytes;
+    let rate_limits = state.rate_limits.clone();
     Router::new()
         // Webhook
         .route("/webhook/github", post(webhook_handler))
+        .route("/webhook/gitea", post(gitea_webhook_handler))
+        .route("/webhook/generic", post(generic_webhook_handler))
+        // Public/internal projects (no authentication required)
+        .route(
+            "/public/projects/{project_id}/builds",
+            get(public::list_project_builds),
+        )
+        .route(
+            "/public/projects/{project_id}/badge.svg",
+            get(public::badge),
+        )
+        .route("/public/builds/{build_id}", get(public::get_build))
+        .route(
+            "/public/builds/{build_id}/logs",
+            get(public::get_build_logs),
+        )
+        // Readiness (fails while the build executor is stalled)
+        .route("/health/ready", get(readiness))
+        // Prometheus scrape endpoint
+        .route("/metrics", get(metrics_handler))
+        // OIDC discovery for build step tokens
+        .route("/.well-known/openid-configuration", get(oidc_discovery))
+        .route("/.well-known/jwks.json", get(oidc_jwks))
+        // GraphQL queries over projects, builds and environments
+        .route("/graphql", post(graphql::graphql_handler))
+        // Live build events for the dashboard
+        .route("/ws/events", get(websocket::events_handler))
         // Build API
         .route("/api/builds", get(list_builds_handler))
         .route("/api/builds/trigger", post(trigger_build_handler))
+        .route("/api/builds/rerun-failed", post(rerun_failed_handler))
+        .route("/api/builds/search", get(search_builds_handler))
         .route("/api/builds/{build_id}", get(get_build))
+        .route("/api/builds/{build_id}/retry", post(retry_build_handler))
+        .route("/api/builds/{build_id}/cancel", post(cancel_build_handler))
+        .route(
+            "/api/builds/{build_id}/approve",
+            post(approve_build_handler),
+        )
+        .route("/api/builds/{build_id}/timeline", get(get_build_timeline))
+        .route(
+            "/api/builds/{build_id}/steps/{step_id}/logs",
+            get(get_step_logs),
+        )
+        .route(
+            "/api/builds/{build_id}/provenance/verify",
+            get(verify_provenance),
+        )
+        .route(
+            "/api/builds/{build_id}/vulnerabilities",
+            get(list_build_vulnerabilities),
+        )
+        .route(
+            "/api/builds/{build_id}/errors.sarif",
+            get(build_errors_sarif),
+        )
+        .route(
+            "/api/builds/{build_id}/artifacts",
+            get(list_build_artifacts).post(upload_artifact).layer(
+                // Room for the multipart framing around the file itself.
+                DefaultBodyLimit::max(max_artifact_bytes.saturating_add(64 * 1024)),
+            ),
+        )
+        .route(
+            "/api/artifacts/{artifact_id}/download",
+            get(download_artifact),
+        )
         .route("/api/builds/latest", get(get_latest_build))
+        // Build queue
+        .route("/api/queue", get(queue_status))
         // KPI API
         .route("/api/kpi/success_rate", get(kpi_success_rate))
         .route("/api/kpi/avg_duration", get(kpi_avg_duration))
         .route("/api/kpi/env_utilization", get(kpi_env_utilization))
         .route("/api/kpi/builds_by_status", get(kpi_builds_by_status))
+        .route("/api/kpi/slowest_tests", get(kpi_slowest_tests))
+        .route("/api/kpi/test_trend", get(kpi_test_trend))
         // Project API
         .route("/api/projects", get(list_projects))
+        .route("/api/projects/{project_id}/pipeline", put(update_pipeline))
+        // Trigger rules
+        .route(
+            "/api/projects/{project_id}/triggers",
+            get(list_triggers).post(create_trigger),
+        )
+        .route(
+            "/api/projects/{project_id}/triggers/dry-run",
+            post(dry_run_trigger),
+        )
+        .route(
+            "/api/projects/{project_id}/triggers/{trigger_id}",
+            get(get_trigger).put(update_trigger).delete(delete_trigger),
+        )
+        // Deduplicated errors
+        .route("/api/errors", get(list_errors))
+        .route("/api/errors/clusters", get(list_error_clusters))
+        .route("/api/errors/trends", get(error_trends))
+        .route(
+            "/api/errors/rules",
+            get(list_error_rules).post(create_error_rule),
+        )
+        .route("/api/errors/rules/{rule_id}", delete(delete_error_rule))
+        .route("/api/errors/clusters/{cluster_id}", get(get_error_cluster))
+        .route("/api/errors/{error_id}", get(get_error))
+        .route(
+            "/api/errors/{error_id}/occurrences",
+            get(list_error_occurrences),
+        )
+        .route("/api/errors/{error_id}/resolve", post(resolve_error))
+        .route("/api/errors/{error_id}/ignore", post(ignore_error))
+        .route("/api/errors/{error_id}/assign", post(assign_error))
+        .route("/api/errors/{error_id}/merge", post(merge_error))
+        .route("/api/errors/{error_id}/split", post(split_error))
+        // Ephemeral review environments
+        .route("/api/environments", get(list_environments))
+        .route("/api/environments/{env_id}", get(get_environment))
+        .route("/api/environments/{env_id}/wake", post(wake_environment))
+        .route(
+            "/api/environments/{env_id}/destroy",
+            post(destroy_environment),
+        )
+        .route(
+            "/api/environments/{env_id}/history",
+            get(environment_history),
+        )
+        .route("/api/environments/{env_id}/logs", get(environment_logs))
+        .route(
+            "/api/environments/{env_id}/replay",
+            post(replay_environment),
+        )
+        .route(
+            "/api/environments/{env_id}/snapshot",
+            post(snapshot_environment),
+        )
+        // Per-project build action grants and their audit trail
+        .route(
+            "/api/projects/{project_id}/permissions",
+            get(list_project_permissions).post(grant_project_permission),
+        )
+        .route(
+            "/api/projects/{project_id}/permissions/{permission_id}",
+            delete(revoke_project_permission),
+        )
+        .route("/api/projects/{project_id}/audit", get(project_audit_log))
+        // Secrets (values are write-only)
+        .route("/api/projects/{project_id}/secrets", get(list_secrets))
+        .route(
+            "/api/projects/{project_id}/secrets/{name}",
+            put(set_secret).delete(delete_secret),
+        )
+        .route("/api/secrets/access", get(secret_access_log))
+        .route(
+            "/api/secrets/{name}",
+            put(set_tenant_secret).delete(delete_tenant_secret),
+        )
+        // Command policies (admin only)
+        .route("/api/policies", get(list_policies).post(create_policy))
+        .route(
+            "/api/policies/{policy_id}",
+            put(update_policy).delete(delete_policy),
+        )
+        // Threshold alert rules
+        .route(
+            "/api/alerts/rules",
+            get(list_alert_rules).post(create_alert_rule),
+        )
+        .route("/api/alerts/rules/{rule_id}", delete(delete_alert_rule))
+        // Outbound notification webhooks
+        .route(
+            "/api/notifications/endpoints",
+            get(list_notification_endpoints).post(create_notification_endpoint),
+        )
+        .route(
+            "/api/notifications/endpoints/{endpoint_id}",
+            delete(delete_notification_endpoint),
+        )
+        .route(
+            "/api/notifications/endpoints/{endpoint_id}/deliveries",
+            get(list_notification_deliveries),
+        )
+        .route(
+            "/api/notifications/channels",
+            get(list_notification_channels).post(create_notification_channel),
+        )
+        .route(
+            "/api/notifications/channels/{channel_id}",
+            delete(delete_notification_channel),
+        )
+        .route(
+            "/api/notifications/rules",
+            get(list_notification_rules).post(create_notification_rule),
+        )
+        .route(
+            "/api/notifications/rules/{rule_id}",
+            delete(delete_notification_rule),
+        )
+        // API token management
+        .route("/api/tokens", get(list_tokens).post(create_token))
+        .route("/api/tokens/{token_id}", delete(revoke_token))
+        .route("/api/tokens/{token_id}/rotate", post(rotate_token))
+        .layer(axum::middleware::from_fn_with_state(
+            rate_limits,
+            rate_limit::enforce,
+        ))
         .with_state(state)
 }
 
@@ -51,72 +348,694 @@ async fn webhook_handler(
     headers: HeaderMap,
     body: Bytes,
 ) -> Result<StatusCode, StatusCode> {
-    crate::metrics::webhook_received(
-        headers
-            .get("x-github-event")
-            .and_then(|v| v.to_str().ok())
-            .unwrap_or("unknown"),
-    );
+    let event = headers
+        .get("x-github-event")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("unknown")
+        .to_string();
+    crate::metrics::webhook_received(&event);
+
+    // Root span of the trace each resulting build continues.
+    webhook::handle_webhook(&state.config, &state.pool, &headers, body)
+        .instrument(tracing::info_span!("webhook", %event))
+        .await
+}
+
+async fn gitea_webhook_handler(
+    State(state): State<CiRouterState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, StatusCode> {
+    let event = headers
+        .get("x-gitea-event")
+        .or_else(|| headers.get("x-forgejo-event"))
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("unknown")
+        .to_string();
+    crate::metrics::webhook_received(&event);
+
+    webhook::handle_gitea_webhook(&state.config, &state.pool, &headers, body)
+        .instrument(tracing::info_span!("webhook", provider = "gitea", %event))
+        .await
+}
+
+/// Start a build from a non-forge system, authenticated by an API token
+/// scoped to the project.
+async fn generic_webhook_handler(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Json(payload): Json<webhook::GenericPayload>,
+) -> Result<(StatusCode, Json<webhook::GenericResponse>), StatusCode> {
+    crate::metrics::webhook_received("generic");
+    let Some(project_id) = principal.project_id else {
+        tracing::warn!(
+            uid = principal.uid,
+            "Generic webhook needs a project-scoped token"
+        );
+        return Err(StatusCode::FORBIDDEN);
+    };
+    let mut conn = state
+        .authorized_action_conn(&principal, project_id, BuildAction::Trigger)
+        .await?;
+
+    let (status, response) =
+        webhook::handle_generic_webhook(&state.config, &mut conn, project_id, payload)
+            .instrument(tracing::info_span!("webhook", provider = "generic"))
+            .await?;
+    if response.build_id.is_some() {
+        audit_action(
+            &mut conn,
+            &principal,
+            project_id,
+            response.build_id,
+            BuildAction::Trigger,
+        )
+        .await;
+    }
+    Ok((status, Json(response)))
+}
+
+// ── Health ──
+
+async fn readiness() -> (StatusCode, Json<serde_json::Value>) {
+    let health = &crate::services::executor::HEALTH;
+    let status = if health.is_stalled() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (
+        status,
+        Json(serde_json::json!({
+            "executor_stalled": health.is_stalled(),
+            "executor_last_poll_ok": health.last_poll_ok(),
+            "executor_consecutive_errors": health.consecutive_errors(),
+        })),
+    )
+}
+
+// ── Metrics ──
+
+/// Prometheus exposition. The configured metrics token (or, without one,
+/// an anonymous request) sees every series; an API token or session sees
+/// only the series of its tenant's projects.
+async fn metrics_handler(
+    State(state): State<CiRouterState>,
+    headers: HeaderMap,
+    principal: Result<CiPrincipal, StatusCode>,
+) -> Result<([(axum::http::header::HeaderName, &'static str); 1], String), StatusCode> {
+    let exposition = crate::metrics::render().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let content_type = [(
+        axum::http::header::CONTENT_TYPE,
+        "text/plain; version=0.0.4",
+    )];
+
+    let bearer = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+    let full_access = match (&state.config.metrics_token, bearer) {
+        // Compare digests so the comparison time says nothing about the token.
+        (Some(token), Some(bearer)) => {
+            use sha2::{Digest, Sha256};
+            Sha256::digest(token.as_bytes()) == Sha256::digest(bearer.as_bytes())
+        }
+        (None, None) => headers.get(axum::http::header::COOKIE).is_none(),
+        _ => false,
+    };
+    if full_access {
+        return Ok((content_type, exposition));
+    }
+
+    let principal = principal?;
+    let mut conn = state
+        .authorized_conn(&principal, "ci.build", Perm::Read)
+        .await?;
+    let projects = crate::services::project_service::list_projects(&mut conn)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .into_iter()
+        .filter(|p| principal.can_access_project(p.id))
+        .map(|p| p.github_repo)
+        .collect();
+
+    Ok((
+        content_type,
+        crate::metrics::filter_projects(&exposition, &projects),
+    ))
+}
+
+// ── OIDC ──
+
+async fn oidc_discovery(
+    State(state): State<CiRouterState>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let signer = state
+        .config
+        .oidc_signer
+        .as_ref()
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(signer.discovery()))
+}
 
-    webhook::handle_webhook(&state.config, &state.pool, &headers, body).await
+async fn oidc_jwks(
+    State(state): State<CiRouterState>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let signer = state
+        .config
+        .oidc_signer
+        .as_ref()
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(signer.jwks()))
 }
 
 // ── Build API ──
 
 async fn trigger_build_handler(
     State(state): State<CiRouterState>,
+    principal: CiPrincipal,
     Json(req): Json<api::TriggerRequest>,
 ) -> Result<(StatusCode, Json<api::TriggerResponse>), StatusCode> {
+    let project_id = req.project_id;
+    let mut conn = state
+        .authorized_action_conn(&principal, project_id, BuildAction::Trigger)
+        .await?;
+
+    let response = api::trigger_build(&mut conn, req).await.map_err(|e| {
+        if e.is::<crate::services::quota_service::QuotaExceeded>() {
+            tracing::warn!("Trigger build rejected: {e}");
+            return StatusCode::TOO_MANY_REQUESTS;
+        }
+        tracing::error!("Trigger build error: {e}");
+        StatusCode::BAD_REQUEST
+    })?;
+    audit_action(
+        &mut conn,
+        &principal,
+        project_id,
+        Some(response.id),
+        BuildAction::Trigger,
+    )
+    .await;
+
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+/// Re-run a finished build as a new build.
+async fn retry_build_handler(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(build_id): Path<i64>,
+) -> Result<(StatusCode, Json<api::TriggerResponse>), StatusCode> {
+    let build = {
+        let mut conn = state.tenant_conn(&principal).await?;
+        crate::services::build_service::get_build(&mut conn, build_id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .filter(|b| principal.can_access_project(b.project_id))
+            .ok_or(StatusCode::NOT_FOUND)?
+    };
+    let mut conn = state
+        .authorized_action_conn(&principal, build.project_id, BuildAction::Retry)
+        .await?;
+
+    let response = api::retry_build(&mut conn, &build).await.map_err(|e| {
+        if e.is::<crate::services::quota_service::QuotaExceeded>() {
+            tracing::warn!("Retry build rejected: {e}");
+            return StatusCode::TOO_MANY_REQUESTS;
+        }
+        tracing::warn!(build_id, "Retry build error: {e}");
+        StatusCode::CONFLICT
+    })?;
+    audit_action(
+        &mut conn,
+        &principal,
+        build.project_id,
+        Some(response.id),
+        BuildAction::Retry,
+    )
+    .await;
+
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+/// Approve an untrusted pull request build (from a fork or an outside
+/// contributor) to run with the project's secrets: in place while pending,
+/// otherwise as a trusted re-run.
+async fn approve_build_handler(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(build_id): Path<i64>,
+) -> Result<(StatusCode, Json<api::TriggerResponse>), StatusCode> {
+    let build = {
+        let mut conn = state.tenant_conn(&principal).await?;
+        crate::services::build_service::get_build(&mut conn, build_id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .filter(|b| principal.can_access_project(b.project_id))
+            .ok_or(StatusCode::NOT_FOUND)?
+    };
+    let mut conn = state
+        .authorized_action_conn(&principal, build.project_id, BuildAction::Approve)
+        .await?;
+
+    let response = api::approve_build(&mut conn, &build).await.map_err(|e| {
+        if e.is::<crate::services::quota_service::QuotaExceeded>() {
+            tracing::warn!("Approve build rejected: {e}");
+            return StatusCode::TOO_MANY_REQUESTS;
+        }
+        tracing::warn!(build_id, "Approve build error: {e}");
+        StatusCode::CONFLICT
+    })?;
+    audit_action(
+        &mut conn,
+        &principal,
+        build.project_id,
+        Some(response.id),
+        BuildAction::Approve,
+    )
+    .await;
+
+    let status = if response.id == build.id {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    };
+    Ok((status, Json(response)))
+}
+
+#[derive(serde::Deserialize)]
+pub struct SearchBuildsQuery {
+    pub q: String,
+    pub project_id: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+/// Full-text search over commit messages, authors, branches and error
+/// titles, best match first.
+async fn search_builds_handler(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Query(query): Query<SearchBuildsQuery>,
+) -> Result<Json<Vec<api::BuildSearchHit>>, StatusCode> {
+    if query.q.trim().is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if query
+        .project_id
+        .is_some_and(|id| !principal.can_access_project(id))
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
     let mut conn = state
-        .pool
-        .get()
+        .authorized_conn(&principal, "ci.build", Perm::Read)
+        .await?;
+
+    let limit = query.limit.unwrap_or(20).clamp(1, api::MAX_SEARCH_RESULTS);
+    api::search_builds(
+        &mut conn,
+        &query.q,
+        query.project_id.or(principal.project_id),
+        limit,
+    )
+    .await
+    .map(Json)
+    .map_err(|e| {
+        tracing::error!("Build search error: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+#[derive(serde::Deserialize)]
+pub struct RerunFailedRequest {
+    pub project_id: Option<i64>,
+    pub branch: Option<String>,
+    /// Failed at or after; the last 24 hours when unset.
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+    pub until: Option<chrono::DateTime<chrono::Utc>>,
+    /// At most this many builds, up to [`api::MAX_BULK_RERUN`].
+    pub limit: Option<i64>,
+}
+
+#[derive(serde::Serialize)]
+pub struct RerunJson {
+    pub build_id: i64,
+    pub retry_id: i64,
+}
+
+#[derive(serde::Serialize)]
+pub struct RerunSkippedJson {
+    pub build_id: i64,
+    pub reason: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct RerunFailedResponse {
+    pub retried: Vec<RerunJson>,
+    pub skipped: Vec<RerunSkippedJson>,
+}
+
+/// Retry every failed or timed-out build matching the filters that has
+/// not been retried yet, e.g. after an outage failed a batch of builds.
+/// Builds of projects the caller may not retry in are skipped, and the
+/// run stops at the tenant's build quota.
+async fn rerun_failed_handler(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Json(req): Json<RerunFailedRequest>,
+) -> Result<Json<RerunFailedResponse>, StatusCode> {
+    if req
+        .project_id
+        .is_some_and(|id| !principal.can_access_project(id))
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let (model, perm) = BuildAction::Retry.model_perm();
+    let mut conn = state.authorized_conn(&principal, model, perm).await?;
+
+    let filter = api::BuildFilter {
+        project_id: req.project_id.or(principal.project_id),
+        statuses: api::RERUN_STATUSES
+            .iter()
+            .map(|s| s.as_str().to_string())
+            .collect(),
+        branch: req.branch,
+        since: Some(
+            req.since
+                .unwrap_or_else(|| chrono::Utc::now() - chrono::Duration::hours(24)),
+        ),
+        until: req.until,
+        ..Default::default()
+    };
+    let limit = req
+        .limit
+        .unwrap_or(api::MAX_BULK_RERUN)
+        .clamp(1, api::MAX_BULK_RERUN);
+    let builds = api::unretried_builds(&mut conn, &filter, limit)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|e| {
+            tracing::error!("Rerun failed builds query error: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut allowed = std::collections::HashMap::new();
+    let mut response = RerunFailedResponse {
+        retried: Vec::new(),
+        skipped: Vec::new(),
+    };
+    let mut builds = builds.into_iter();
+    for build in builds.by_ref() {
+        let may_retry = match allowed.get(&build.project_id) {
+            Some(&may_retry) => may_retry,
+            None => {
+                let may_retry = access_service::check_project_action(
+                    &mut conn,
+                    principal.uid,
+                    build.project_id,
+                    BuildAction::Retry,
+                )
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                allowed.insert(build.project_id, may_retry);
+                may_retry
+            }
+        };
+        if !may_retry {
+            response.skipped.push(RerunSkippedJson {
+                build_id: build.id,
+                reason: "retry not permitted in this project".to_string(),
+            });
+            continue;
+        }
+
+        match api::retry_build(&mut conn, &build).await {
+            Ok(retry) => {
+                audit_action(
+                    &mut conn,
+                    &principal,
+                    build.project_id,
+                    Some(retry.id),
+                    BuildAction::Retry,
+                )
+                .await;
+                response.retried.push(RerunJson {
+                    build_id: build.id,
+                    retry_id: retry.id,
+                });
+            }
+            Err(e) if e.is::<crate::services::quota_service::QuotaExceeded>() => {
+                tracing::warn!("Bulk rerun stopped: {e}");
+                response.skipped.push(RerunSkippedJson {
+                    build_id: build.id,
+                    reason: e.to_string(),
+                });
+                break;
+            }
+            Err(e) => {
+                tracing::warn!(build_id = build.id, "Bulk rerun error: {e}");
+                response.skipped.push(RerunSkippedJson {
+                    build_id: build.id,
+                    reason: e.to_string(),
+                });
+            }
+        }
+    }
+    response
+        .skipped
+        .extend(builds.map(|build| RerunSkippedJson {
+            build_id: build.id,
+            reason: "build quota exceeded".to_string(),
+        }));
+
+    tracing::info!(
+        uid = principal.uid,
+        retried = response.retried.len(),
+        skipped = response.skipped.len(),
+        "Bulk rerun of failed builds"
+    );
+    Ok(Json(response))
+}
+
+/// Cancel a pending build, or kill the running step of a running one.
+/// Running builds answer 202: they finish as cancelled once the executor
+/// has stopped them.
+async fn cancel_build_handler(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(build_id): Path<i64>,
+) -> Result<(StatusCode, Json<api::CancelResponse>), StatusCode> {
+    let build = {
+        let mut conn = state.tenant_conn(&principal).await?;
+        crate::services::build_service::get_build(&mut conn, build_id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .filter(|b| principal.can_access_project(b.project_id))
+            .ok_or(StatusCode::NOT_FOUND)?
+    };
+    let mut conn = state
+        .authorized_action_conn(&principal, build.project_id, BuildAction::Cancel)
+        .await?;
 
-    api::trigger_build(&mut conn, req)
+    let response = api::cancel_build(&mut conn, &build, &state.config.dashboard_url)
         .await
-        .map(|r| (StatusCode::CREATED, Json(r)))
         .map_err(|e| {
-            tracing::error!("Trigger build error: {e}");
-            StatusCode::BAD_REQUEST
-        })
+            tracing::warn!(build_id, "Cancel build error: {e}");
+            StatusCode::CONFLICT
+        })?;
+    audit_action(
+        &mut conn,
+        &principal,
+        build.project_id,
+        Some(build.id),
+        BuildAction::Cancel,
+    )
+    .await;
+
+    let status = if response.cancel_requested {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(response)))
 }
 
 #[derive(serde::Deserialize)]
 pub struct ListBuildsQuery {
     pub limit: Option<i64>,
+    pub offset: Option<i64>,
+    /// `X-Next-Cursor` of the previous page.
+    pub cursor: Option<i64>,
+    pub project_id: Option<i64>,
+    /// One status, or several separated by commas.
+    pub status: Option<String>,
+    pub branch: Option<String>,
+    pub author: Option<String>,
+    pub trigger_event: Option<String>,
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+    pub until: Option<chrono::DateTime<chrono::Utc>>,
 }
 
+/// Builds newest first, filtered and paginated. The total number matching
+/// is returned in `X-Total-Count`, and the cursor of the next page, if
+/// any, in `X-Next-Cursor`.
 async fn list_builds_handler(
     State(state): State<CiRouterState>,
+    principal: CiPrincipal,
     Query(query): Query<ListBuildsQuery>,
-) -> Result<Json<Vec<api::BuildJson>>, StatusCode> {
+) -> Result<(HeaderMap, Json<Vec<api::BuildJson>>), StatusCode> {
+    if query
+        .project_id
+        .is_some_and(|id| !principal.can_access_project(id))
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
     let mut conn = state
-        .pool
-        .get()
+        .authorized_conn(&principal, "ci.build", Perm::Read)
+        .await?;
+
+    let filter = api::BuildFilter {
+        project_id: query.project_id.or(principal.project_id),
+        statuses: query
+            .status
+            .iter()
+            .flat_map(|s| s.split(','))
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect(),
+        branch: query.branch,
+        author: query.author,
+        trigger_event: query.trigger_event,
+        since: query.since,
+        until: query.until,
+    };
+    let page = api::Page {
+        limit: query.limit.unwrap_or(20).max(1),
+        offset: query.offset.unwrap_or(0).max(0),
+        before: query.cursor,
+    };
+    let page = api::list_builds(&mut conn, &filter, &page)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    api::list_builds(&mut conn, query.limit.unwrap_or(20))
-        .await
-        .map(Json)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    let mut headers = HeaderMap::new();
+    headers.insert("x-total-count", page.total.into());
+    if let Some(cursor) = page.next_cursor {
+        headers.insert("x-next-cursor", cursor.into());
+    }
+    Ok((headers, Json(page.builds)))
 }
 
 async fn get_build(
     State(state): State<CiRouterState>,
+    principal: CiPrincipal,
     Path(build_id): Path<i64>,
 ) -> Result<Json<api::BuildJson>, StatusCode> {
     let mut conn = state
-        .pool
-        .get()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .authorized_conn(&principal, "ci.build", Perm::Read)
+        .await?;
 
     api::get_build(&mut conn, build_id)
         .await
+        .ok()
+        .filter(|b| principal.can_access_project(b.project_id))
         .map(Json)
-        .map_err(|_| StatusCode::NOT_FOUND)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(serde::Deserialize)]
+pub struct StepLogsQuery {
+    /// `stdout` (default) or `stderr`.
+    pub stream: Option<String>,
+    /// Only the last lines.
+    pub tail: Option<usize>,
+    /// First byte to return; with `length`, how many.
+    pub offset: Option<usize>,
+    pub length: Option<usize>,
+}
+
+/// One stream of a step's output as plain text, whole, its last `tail`
+/// lines, or a byte range. `X-Log-Size` carries the full length, so a
+/// client can follow a log by asking for `offset` = the size it has.
+async fn get_step_logs(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path((build_id, step_id)): Path<(i64, i64)>,
+    Query(query): Query<StepLogsQuery>,
+) -> Result<(HeaderMap, String), StatusCode> {
+    let range = match (query.tail, query.offset) {
+        (Some(_), Some(_)) => return Err(StatusCode::BAD_REQUEST),
+        (Some(lines), None) => api::LogRange::Tail(lines),
+        (None, Some(offset)) => api::LogRange::Bytes {
+            offset,
+            length: query.length,
+        },
+        (None, None) => api::LogRange::All,
+    };
+    let mut conn = state
+        .authorized_conn(&principal, "ci.build.step", Perm::Read)
+        .await?;
+
+    let build = crate::services::build_service::get_build(&mut conn, build_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|b| principal.can_access_project(b.project_id))
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let step = api::get_step(&mut conn, build.id, step_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let output = match query.stream.as_deref().unwrap_or("stdout") {
+        "stdout" => step.stdout,
+        "stderr" => step.stderr,
+        _ => return Err(StatusCode::BAD_REQUEST),
+    }
+    .unwrap_or_default();
+
+    // Output is masked at execution time; mask again with the current values
+    // in case a secret changed since the build ran.
+    let secrets = match state.config.secrets_keyring {
+        Some(ref keyring) => crate::services::secret_service::resolve_for_project(
+            &mut conn,
+            keyring,
+            build.tenant_id,
+            build.project_id,
+        )
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        None => Vec::new(),
+    };
+    let output = crate::services::secret_service::mask(&output, &secrets);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        axum::http::HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    headers.insert("x-log-size", output.len().into());
+    Ok((headers, api::log_excerpt(&output, range).to_string()))
+}
+
+/// Trace-event JSON of a build's queue wait, checkout, and steps.
+async fn get_build_timeline(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(build_id): Path<i64>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.build", Perm::Read)
+        .await?;
+
+    let build = crate::services::build_service::get_build(&mut conn, build_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|b| principal.can_access_project(b.project_id))
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    api::get_build_timeline(&mut conn, build.id)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
 #[derive(serde::Deserialize)]
@@ -127,13 +1046,15 @@ pub struct LatestBuildQuery {
 
 async fn get_latest_build(
     State(state): State<CiRouterState>,
+    principal: CiPrincipal,
     Query(query): Query<LatestBuildQuery>,
 ) -> Result<Json<api::BuildJson>, StatusCode> {
+    if !principal.can_access_project(query.project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
     let mut conn = state
-        .pool
-        .get()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .authorized_conn(&principal, "ci.build", Perm::Read)
+        .await?;
 
     api::get_latest_build(&mut conn, query.project_id, &query.branch)
         .await
@@ -141,53 +1062,443 @@ async fn get_latest_build(
         .map_err(|_| StatusCode::NOT_FOUND)
 }
 
+/// Pending builds in claim order, running builds, and how long the queue
+/// is expected to take.
+async fn queue_status(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+) -> Result<Json<api::QueueJson>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.build", Perm::Read)
+        .await?;
+
+    api::queue_status(&mut conn, state.config.max_concurrent_builds, |id| {
+        principal.can_access_project(id)
+    })
+    .await
+    .map(Json)
+    .map_err(|e| {
+        tracing::error!("Queue status error: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Check the signature and artifact digests of a build's provenance.
+async fn verify_provenance(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(build_id): Path<i64>,
+) -> Result<Json<crate::services::provenance_service::Verification>, StatusCode> {
+    let signer = state
+        .config
+        .oidc_signer
+        .clone()
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let mut conn = state
+        .authorized_conn(&principal, "ci.artifact", Perm::Read)
+        .await?;
+
+    let build = crate::services::build_service::get_build(&mut conn, build_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|b| principal.can_access_project(b.project_id))
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    crate::services::provenance_service::verify(&mut conn, &signer, build.id)
+        .await
+        .map_err(|e| {
+            tracing::error!(build_id, "Provenance verification error: {e}");
+            StatusCode::UNPROCESSABLE_ENTITY
+        })?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Artifacts of a build, without their content.
+async fn list_build_artifacts(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(build_id): Path<i64>,
+) -> Result<Json<Vec<api::ArtifactJson>>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.artifact", Perm::Read)
+        .await?;
+
+    let build = crate::services::build_service::get_build(&mut conn, build_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|b| principal.can_access_project(b.project_id))
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    crate::services::artifact_service::list_for_build(&mut conn, build.id)
+        .await
+        .map(|artifacts| Json(artifacts.into_iter().map(Into::into).collect()))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Attach a text artifact to a build. The multipart body carries the
+/// content in a `file` part, whose filename names the artifact, and
+/// optionally its artifact type in a `type` part.
+async fn upload_artifact(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(build_id): Path<i64>,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<api::ArtifactJson>), StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.artifact", Perm::Create)
+        .await?;
+
+    let build = crate::services::build_service::get_build(&mut conn, build_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|b| principal.can_access_project(b.project_id))
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut file = None;
+    let mut artifact_type = None;
+    while let Some(field) = multipart.next_field().await.map_err(|e| e.status())? {
+        match field.name() {
+            Some("file") => {
+                let name = field
+                    .file_name()
+                    .map(str::to_string)
+                    .ok_or(StatusCode::BAD_REQUEST)?;
+                let bytes = field.bytes().await.map_err(|e| e.status())?;
+                file = Some((name, bytes));
+            }
+            Some("type") => {
+                artifact_type = Some(field.text().await.map_err(|e| e.status())?);
+            }
+            _ => {}
+        }
+    }
+    let (name, bytes) = file.ok_or(StatusCode::BAD_REQUEST)?;
+    if bytes.len() > state.config.max_artifact_bytes {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    // Artifacts are stored as text (logs, reports, coverage).
+    let content =
+        String::from_utf8(bytes.to_vec()).map_err(|_| StatusCode::UNSUPPORTED_MEDIA_TYPE)?;
+
+    let artifact = crate::services::artifact_service::upload_artifact(
+        &mut conn,
+        crate::models::artifact::NewCiArtifact {
+            tenant_id: build.tenant_id,
+            build_id: build.id,
+            name,
+            artifact_type: artifact_type.unwrap_or_else(|| {
+                crate::services::artifact_service::DEFAULT_UPLOAD_TYPE.to_string()
+            }),
+            size_bytes: Some(content.len() as i64),
+            content: Some(content),
+        },
+    )
+    .await
+    .map_err(|e| {
+        tracing::warn!(build_id, "Artifact upload rejected: {e}");
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    tracing::info!(
+        build_id,
+        artifact_id = artifact.id,
+        uid = principal.uid,
+        "Artifact uploaded"
+    );
+    Ok((StatusCode::CREATED, Json(artifact.into())))
+}
+
+/// An artifact's content, served as an attachment.
+async fn download_artifact(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(artifact_id): Path<i64>,
+) -> Result<(HeaderMap, String), StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.artifact", Perm::Read)
+        .await?;
+
+    let artifact = crate::services::artifact_service::get_artifact(&mut conn, artifact_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    crate::services::build_service::get_build(&mut conn, artifact.build_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|b| principal.can_access_project(b.project_id))
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        axum::http::HeaderValue::from_static(crate::services::artifact_service::content_type(
+            &artifact,
+        )),
+    );
+    let disposition = format!(
+        "attachment; filename=\"{}\"",
+        artifact.name.replace(['"', '\\'], "_")
+    );
+    if let Ok(value) = axum::http::HeaderValue::from_str(&disposition) {
+        headers.insert(axum::http::header::CONTENT_DISPOSITION, value);
+    }
+    headers.insert(
+        axum::http::header::X_CONTENT_TYPE_OPTIONS,
+        axum::http::HeaderValue::from_static("nosniff"),
+    );
+    Ok((headers, artifact.content.unwrap_or_default()))
+}
+
+/// Dependency vulnerabilities reported by a build's audit steps.
+async fn list_build_vulnerabilities(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(build_id): Path<i64>,
+) -> Result<Json<Vec<crate::models::vulnerability::CiVulnerability>>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.vulnerability", Perm::Read)
+        .await?;
+
+    let build = crate::services::build_service::get_build(&mut conn, build_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|b| principal.can_access_project(b.project_id))
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    crate::services::vulnerability_service::list_for_build(&mut conn, build.id)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// The build's located errors as a SARIF document.
+async fn build_errors_sarif(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(build_id): Path<i64>,
+) -> Result<(HeaderMap, Json<serde_json::Value>), StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.error", Perm::Read)
+        .await?;
+
+    let build = crate::services::build_service::get_build(&mut conn, build_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|b| principal.can_access_project(b.project_id))
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let report = crate::services::sarif::build_report(
+        &mut conn,
+        &build,
+        &state.config.workspace_dir,
+        state.config.gitea_url.as_deref(),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!(build_id, "SARIF export failed: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        axum::http::HeaderValue::from_static(crate::services::sarif::SARIF_CONTENT_TYPE),
+    );
+    Ok((headers, Json(report)))
+}
+
+// ── Project build action grants ──
+
+#[derive(serde::Deserialize)]
+pub struct GrantPermissionRequest {
+    pub action: String,
+    pub user_id: Option<i64>,
+    pub group_id: Option<i64>,
+}
+
+async fn list_project_permissions(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(project_id): Path<i64>,
+) -> Result<Json<Vec<crate::models::project_permission::CiProjectPermission>>, StatusCode> {
+    if !principal.can_access_project(project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.project", Perm::Read)
+        .await?;
+
+    access_service::list_project_permissions(&mut conn, project_id)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn grant_project_permission(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(project_id): Path<i64>,
+    Json(req): Json<GrantPermissionRequest>,
+) -> Result<
+    (
+        StatusCode,
+        Json<crate::models::project_permission::CiProjectPermission>,
+    ),
+    StatusCode,
+> {
+    if !principal.can_access_project(project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let action = BuildAction::parse(&req.action).ok_or(StatusCode::BAD_REQUEST)?;
+    let mut conn = state
+        .authorized_conn(&principal, "ci.project", Perm::Write)
+        .await?;
+
+    access_service::grant_project_permission(
+        &mut conn,
+        crate::models::project_permission::NewCiProjectPermission {
+            tenant_id: principal.tenant_id,
+            project_id,
+            action: action.as_str().to_string(),
+            user_id: req.user_id,
+            group_id: req.group_id,
+            create_uid: Some(principal.uid),
+        },
+    )
+    .await
+    .map(|p| (StatusCode::CREATED, Json(p)))
+    .map_err(|e| {
+        tracing::warn!(project_id, "Grant project permission error: {e}");
+        StatusCode::BAD_REQUEST
+    })
+}
+
+async fn revoke_project_permission(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path((project_id, permission_id)): Path<(i64, i64)>,
+) -> Result<StatusCode, StatusCode> {
+    if !principal.can_access_project(project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.project", Perm::Write)
+        .await?;
+
+    match access_service::revoke_project_permission(
+        &mut conn,
+        project_id,
+        permission_id,
+        principal.uid,
+    )
+    .await
+    {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct AuditQuery {
+    pub limit: Option<i64>,
+}
+
+/// Who triggered, cancelled, retried, or approved builds of a project.
+async fn project_audit_log(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(project_id): Path<i64>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<crate::models::build_audit::CiBuildAudit>>, StatusCode> {
+    if !principal.can_access_project(project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.build", Perm::Read)
+        .await?;
+
+    crate::services::audit_service::list_for_project(
+        &mut conn,
+        project_id,
+        query.limit.unwrap_or(100).min(1000),
+    )
+    .await
+    .map(Json)
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
 // ── KPI API ──
 
 #[derive(serde::Deserialize)]
 pub struct KpiQuery {
     pub days: Option<i32>,
+    pub trigger_event: Option<String>,
+    pub project_id: Option<i64>,
+    pub branch: Option<String>,
 }
 
 async fn kpi_success_rate(
     State(state): State<CiRouterState>,
+    principal: CiPrincipal,
     Query(query): Query<KpiQuery>,
 ) -> Result<Json<crate::dashboard::kpi::BuildSuccessRate>, StatusCode> {
+    if query
+        .project_id
+        .is_some_and(|id| !principal.can_access_project(id))
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
     let mut conn = state
-        .pool
-        .get()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .authorized_conn(&principal, "ci.build", Perm::Read)
+        .await?;
 
-    crate::dashboard::kpi::query_success_rate(&mut conn, query.days.unwrap_or(30))
-        .await
-        .map(Json)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    crate::dashboard::kpi::query_success_rate(
+        &mut conn,
+        query.days.unwrap_or(30),
+        query.trigger_event.as_deref(),
+        query.project_id.or(principal.project_id),
+        query.branch.as_deref(),
+    )
+    .await
+    .map(Json)
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
 async fn kpi_avg_duration(
     State(state): State<CiRouterState>,
+    principal: CiPrincipal,
     Query(query): Query<KpiQuery>,
 ) -> Result<Json<crate::dashboard::kpi::AvgBuildDuration>, StatusCode> {
+    if query
+        .project_id
+        .is_some_and(|id| !principal.can_access_project(id))
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
     let mut conn = state
-        .pool
-        .get()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .authorized_conn(&principal, "ci.build", Perm::Read)
+        .await?;
 
-    crate::dashboard::kpi::query_avg_duration(&mut conn, query.days.unwrap_or(30))
-        .await
-        .map(Json)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    crate::dashboard::kpi::query_avg_duration(
+        &mut conn,
+        query.days.unwrap_or(30),
+        query.trigger_event.as_deref(),
+        query.project_id.or(principal.project_id),
+        query.branch.as_deref(),
+    )
+    .await
+    .map(Json)
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
 async fn kpi_env_utilization(
     State(state): State<CiRouterState>,
+    principal: CiPrincipal,
 ) -> Result<Json<crate::dashboard::kpi::EnvironmentUtilization>, StatusCode> {
     let mut conn = state
-        .pool
-        .get()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .authorized_conn(&principal, "ci.environment", Perm::Read)
+        .await?;
 
     crate::dashboard::kpi::query_env_utilization(&mut conn)
         .await
@@ -197,33 +1508,1980 @@ async fn kpi_env_utilization(
 
 async fn kpi_builds_by_status(
     State(state): State<CiRouterState>,
+    principal: CiPrincipal,
     Query(query): Query<KpiQuery>,
 ) -> Result<Json<Vec<crate::dashboard::kpi::BuildsByStatus>>, StatusCode> {
+    if query
+        .project_id
+        .is_some_and(|id| !principal.can_access_project(id))
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
     let mut conn = state
-        .pool
-        .get()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .authorized_conn(&principal, "ci.build", Perm::Read)
+        .await?;
 
-    crate::dashboard::kpi::query_builds_by_status(&mut conn, query.days.unwrap_or(30))
-        .await
-        .map(Json)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    crate::dashboard::kpi::query_builds_by_status(
+        &mut conn,
+        query.days.unwrap_or(30),
+        query.trigger_event.as_deref(),
+        query.project_id.or(principal.project_id),
+        query.branch.as_deref(),
+    )
+    .await
+    .map(Json)
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
-// ── Project API ──
+#[derive(serde::Deserialize)]
+pub struct TestKpiQuery {
+    /// Required unless the token is scoped to a project.
+    pub project_id: Option<i64>,
+    pub days: Option<i32>,
+    pub branch: Option<String>,
+    /// Slowest tests only.
+    pub limit: Option<i64>,
+    /// Test trend only: the test, as its runner names it.
+    pub name: Option<String>,
+}
 
-async fn list_projects(
+/// The project of a test KPI query, which per-test results need one of.
+fn test_kpi_project(principal: &CiPrincipal, query: &TestKpiQuery) -> Result<i64, StatusCode> {
+    let project_id = query
+        .project_id
+        .or(principal.project_id)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    if !principal.can_access_project(project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(project_id)
+}
+
+async fn kpi_slowest_tests(
     State(state): State<CiRouterState>,
-) -> Result<Json<Vec<crate::models::project::CiProject>>, StatusCode> {
+    principal: CiPrincipal,
+    Query(query): Query<TestKpiQuery>,
+) -> Result<Json<Vec<crate::dashboard::kpi::SlowTest>>, StatusCode> {
+    let project_id = test_kpi_project(&principal, &query)?;
     let mut conn = state
-        .pool
-        .get()
+        .authorized_conn(&principal, "ci.build", Perm::Read)
+        .await?;
+
+    crate::dashboard::kpi::query_slowest_tests(
+        &mut conn,
+        project_id,
+        query.days.unwrap_or(30).clamp(1, 365),
+        query.branch.as_deref(),
+        query.limit.unwrap_or(20).clamp(1, 500),
+    )
+    .await
+    .map(Json)
+    .map_err(|e| {
+        tracing::error!(project_id, "Slowest tests query failed: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+async fn kpi_test_trend(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Query(query): Query<TestKpiQuery>,
+) -> Result<Json<Vec<crate::dashboard::kpi::TestRun>>, StatusCode> {
+    let project_id = test_kpi_project(&principal, &query)?;
+    let name = query.name.as_deref().ok_or(StatusCode::BAD_REQUEST)?;
+    let mut conn = state
+        .authorized_conn(&principal, "ci.build", Perm::Read)
+        .await?;
+
+    crate::dashboard::kpi::query_test_trend(
+        &mut conn,
+        project_id,
+        name,
+        query.days.unwrap_or(30).clamp(1, 365),
+        query.branch.as_deref(),
+    )
+    .await
+    .map(Json)
+    .map_err(|e| {
+        tracing::error!(project_id, "Test trend query failed: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+// ── Project API ──
+
+async fn list_projects(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+) -> Result<Json<Vec<crate::models::project::CiProject>>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.project", Perm::Read)
+        .await?;
+
+    crate::services::project_service::list_projects(&mut conn)
+        .await
+        .map(|projects| {
+            Json(
+                projects
+                    .into_iter()
+                    .filter(|p| principal.can_access_project(p.id))
+                    .collect(),
+            )
+        })
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn update_pipeline(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(project_id): Path<i64>,
+    Json(config): Json<serde_json::Value>,
+) -> Result<Json<crate::models::project::CiProject>, StatusCode> {
+    if !principal.can_access_project(project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.project", Perm::Write)
+        .await?;
+
+    match crate::services::project_service::update_pipeline_config(
+        &mut conn,
+        project_id,
+        config,
+        principal.uid,
+    )
+    .await
+    {
+        Ok(Some(project)) => Ok(Json(project)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) if e.is::<crate::services::policy_service::PolicyViolation>() => {
+            tracing::warn!(project_id, "Pipeline update rejected: {e}");
+            Err(StatusCode::UNPROCESSABLE_ENTITY)
+        }
+        Err(e) => {
+            tracing::error!("Update pipeline error: {e}");
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+// ── Trigger rules API ──
+
+/// A trigger rule as created or replaced through the API.
+#[derive(serde::Deserialize)]
+pub struct TriggerRuleRequest {
+    pub event_type: String,
+    pub branch_pattern: Option<String>,
+    pub cron_expr: Option<String>,
+    pub env: Option<serde_json::Value>,
+    #[serde(default)]
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub paths_ignore: Vec<String>,
+    #[serde(default)]
+    pub skip_drafts: bool,
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+impl TriggerRuleRequest {
+    fn into_new(
+        self,
+        principal: &CiPrincipal,
+        project_id: i64,
+    ) -> crate::models::trigger::NewCiTrigger {
+        crate::models::trigger::NewCiTrigger {
+            tenant_id: principal.tenant_id,
+            project_id,
+            event_type: self.event_type,
+            branch_pattern: self.branch_pattern.filter(|p| !p.trim().is_empty()),
+            active: true,
+            cron_expr: self.cron_expr,
+            env: self.env,
+            paths: self.paths,
+            paths_ignore: self.paths_ignore,
+            skip_drafts: self.skip_drafts,
+            labels: self.labels,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct TriggerDryRunRequest {
+    pub branch: String,
+    /// Pattern to test; without one, only the project's triggers are.
+    pub branch_pattern: Option<String>,
+    /// Limits `triggers` to this event type.
+    pub event_type: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct TriggerDryRunResponse {
+    pub branch: String,
+    /// Whether `branch_pattern` covers the branch, if one was given.
+    pub matches: Option<bool>,
+    /// Active triggers of the project whose pattern covers the branch.
+    pub triggers: Vec<i64>,
+}
+
+async fn list_triggers(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(project_id): Path<i64>,
+) -> Result<Json<Vec<crate::models::trigger::CiTrigger>>, StatusCode> {
+    if !principal.can_access_project(project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.trigger", Perm::Read)
+        .await?;
+
+    crate::services::trigger_service::list(&mut conn, project_id)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn get_trigger(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path((project_id, trigger_id)): Path<(i64, i64)>,
+) -> Result<Json<crate::models::trigger::CiTrigger>, StatusCode> {
+    if !principal.can_access_project(project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.trigger", Perm::Read)
+        .await?;
+
+    crate::services::trigger_service::get(&mut conn, project_id, trigger_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn create_trigger(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(project_id): Path<i64>,
+    Json(req): Json<TriggerRuleRequest>,
+) -> Result<(StatusCode, Json<crate::models::trigger::CiTrigger>), StatusCode> {
+    if !principal.can_access_project(project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.trigger", Perm::Create)
+        .await?;
+    crate::services::project_service::get_project(&mut conn, project_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    crate::services::trigger_service::create(
+        &mut conn,
+        req.into_new(&principal, project_id),
+        principal.uid,
+    )
+    .await
+    .map(|t| (StatusCode::CREATED, Json(t)))
+    .map_err(|e| {
+        tracing::warn!(project_id, "Create trigger error: {e}");
+        StatusCode::BAD_REQUEST
+    })
+}
+
+/// Replace a trigger's rule.
+async fn update_trigger(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path((project_id, trigger_id)): Path<(i64, i64)>,
+    Json(req): Json<TriggerRuleRequest>,
+) -> Result<Json<crate::models::trigger::CiTrigger>, StatusCode> {
+    if !principal.can_access_project(project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.trigger", Perm::Write)
+        .await?;
+
+    crate::services::trigger_service::update(
+        &mut conn,
+        trigger_id,
+        req.into_new(&principal, project_id),
+        principal.uid,
+    )
+    .await
+    .map_err(|e| {
+        tracing::warn!(project_id, trigger_id, "Update trigger error: {e}");
+        StatusCode::BAD_REQUEST
+    })?
+    .map(Json)
+    .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn delete_trigger(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path((project_id, trigger_id)): Path<(i64, i64)>,
+) -> Result<StatusCode, StatusCode> {
+    if !principal.can_access_project(project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.trigger", Perm::Unlink)
+        .await?;
+
+    match crate::services::trigger_service::delete(&mut conn, project_id, trigger_id, principal.uid)
+        .await
+    {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Test a branch name against a pattern and the project's triggers,
+/// without building anything.
+async fn dry_run_trigger(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(project_id): Path<i64>,
+    Json(req): Json<TriggerDryRunRequest>,
+) -> Result<Json<TriggerDryRunResponse>, StatusCode> {
+    use crate::services::trigger_service;
+
+    if !principal.can_access_project(project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if let Some(pattern) = &req.branch_pattern {
+        trigger_service::check_pattern(pattern).map_err(|_| StatusCode::BAD_REQUEST)?;
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.trigger", Perm::Read)
+        .await?;
+
+    let triggers = trigger_service::list(&mut conn, project_id)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let triggers = trigger_service::for_branch(&triggers, &req.branch)
+        .filter(|t| req.event_type.as_ref().is_none_or(|e| *e == t.event_type))
+        .map(|t| t.id)
+        .collect();
+    Ok(Json(TriggerDryRunResponse {
+        matches: req
+            .branch_pattern
+            .as_deref()
+            .map(|p| trigger_service::branch_matches(Some(p), &req.branch)),
+        branch: req.branch,
+        triggers,
+    }))
+}
 
-    crate::services::project_service::list_projects(&mut conn)
+// ── Errors API ──
+
+#[derive(serde::Deserialize)]
+pub struct ListErrorsQuery {
+    pub status: Option<String>,
+    pub category: Option<String>,
+    pub project_id: Option<i64>,
+    /// A CODEOWNERS owner, e.g. `@acme/payments`.
+    pub owner: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct OccurrencesQuery {
+    pub limit: Option<i64>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ErrorTrendsQuery {
+    pub days: Option<i32>,
+    /// `day` (the default) or `week`.
+    pub bucket: Option<String>,
+    pub project_id: Option<i64>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ListClustersQuery {
+    pub project_id: Option<i64>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ErrorRulesQuery {
+    pub project_id: Option<i64>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct CreateErrorRuleRequest {
+    /// Mute one project's errors only; every project's when unset.
+    pub project_id: Option<i64>,
+    pub pattern: Option<String>,
+    pub category: Option<String>,
+    pub reason: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct MergeErrorRequest {
+    /// An error of the cluster to merge into.
+    pub into: i64,
+}
+
+#[derive(serde::Deserialize)]
+pub struct AssignErrorRequest {
+    /// Who to assign the error to; unassigns when null.
+    pub assignee: Option<String>,
+}
+
+async fn list_errors(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Query(query): Query<ListErrorsQuery>,
+) -> Result<Json<Vec<crate::models::error::CiError>>, StatusCode> {
+    if query
+        .project_id
+        .is_some_and(|id| !principal.can_access_project(id))
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.error", Perm::Read)
+        .await?;
+
+    let filter = crate::services::error_service::ErrorFilter {
+        status: query.status,
+        category: query.category,
+        project_id: query.project_id.or(principal.project_id),
+        owner: query.owner,
+    };
+    crate::services::error_service::list_errors(
+        &mut conn,
+        &filter,
+        query.limit.unwrap_or(50).clamp(1, 500),
+        query.offset.unwrap_or(0).max(0),
+    )
+    .await
+    .map(Json)
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Load an error the caller can see, or 404.
+async fn visible_error(
+    conn: &mut AsyncPgConnection,
+    principal: &CiPrincipal,
+    error_id: i64,
+) -> Result<crate::models::error::CiError, StatusCode> {
+    crate::services::error_service::get_error(conn, error_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|e| {
+            e.project_id
+                .is_none_or(|id| principal.can_access_project(id))
+        })
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_error(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(error_id): Path<i64>,
+) -> Result<Json<crate::models::error::CiError>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.error", Perm::Read)
+        .await?;
+    visible_error(&mut conn, &principal, error_id)
+        .await
+        .map(Json)
+}
+
+async fn list_error_occurrences(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(error_id): Path<i64>,
+    Query(query): Query<OccurrencesQuery>,
+) -> Result<Json<Vec<crate::models::error::CiErrorOccurrence>>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.error.occurrence", Perm::Read)
+        .await?;
+    let error = visible_error(&mut conn, &principal, error_id).await?;
+
+    crate::services::error_service::occurrences(
+        &mut conn,
+        error.id,
+        query.limit.unwrap_or(100).clamp(1, 1000),
+    )
+    .await
+    .map(Json)
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn set_error_status(
+    state: &CiRouterState,
+    principal: &CiPrincipal,
+    error_id: i64,
+    status: &str,
+) -> Result<Json<crate::models::error::CiError>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(principal, "ci.error", Perm::Write)
+        .await?;
+    let error = visible_error(&mut conn, principal, error_id).await?;
+
+    crate::services::error_service::set_status(&mut conn, error.id, status, principal.uid)
+        .await
+        .map_err(|e| {
+            tracing::error!(error_id, "Error status change failed: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn resolve_error(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(error_id): Path<i64>,
+) -> Result<Json<crate::models::error::CiError>, StatusCode> {
+    set_error_status(&state, &principal, error_id, "resolved").await
+}
+
+async fn ignore_error(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(error_id): Path<i64>,
+) -> Result<Json<crate::models::error::CiError>, StatusCode> {
+    set_error_status(&state, &principal, error_id, "ignored").await
+}
+
+async fn assign_error(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(error_id): Path<i64>,
+    Json(req): Json<AssignErrorRequest>,
+) -> Result<Json<crate::models::error::CiError>, StatusCode> {
+    let assignee = req
+        .assignee
+        .as_deref()
+        .map(str::trim)
+        .filter(|a| !a.is_empty());
+    if assignee.is_some_and(|a| a.len() > 255) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.error", Perm::Write)
+        .await?;
+    let error = visible_error(&mut conn, &principal, error_id).await?;
+
+    crate::services::error_service::assign(&mut conn, error.id, assignee, principal.uid)
+        .await
+        .map_err(|e| {
+            tracing::error!(error_id, "Error assignment failed: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn error_trends(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Query(query): Query<ErrorTrendsQuery>,
+) -> Result<Json<Vec<crate::dashboard::kpi::ErrorTrendPoint>>, StatusCode> {
+    if query
+        .project_id
+        .is_some_and(|id| !principal.can_access_project(id))
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let bucket = match query.bucket.as_deref() {
+        None => crate::dashboard::kpi::TrendBucket::Day,
+        Some(bucket) => {
+            crate::dashboard::kpi::TrendBucket::parse(bucket).ok_or(StatusCode::BAD_REQUEST)?
+        }
+    };
+    let mut conn = state
+        .authorized_conn(&principal, "ci.error", Perm::Read)
+        .await?;
+
+    crate::dashboard::kpi::query_error_trends(
+        &mut conn,
+        query.days.unwrap_or(30).clamp(1, 365),
+        bucket,
+        query.project_id.or(principal.project_id),
+    )
+    .await
+    .map(Json)
+    .map_err(|e| {
+        tracing::error!("Error trends query failed: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+async fn list_error_clusters(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Query(query): Query<ListClustersQuery>,
+) -> Result<Json<Vec<crate::services::error_cluster::ErrorCluster>>, StatusCode> {
+    if query
+        .project_id
+        .is_some_and(|id| !principal.can_access_project(id))
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.error", Perm::Read)
+        .await?;
+
+    crate::services::error_cluster::list_clusters(
+        &mut conn,
+        query.project_id.or(principal.project_id),
+        query.limit.unwrap_or(50).clamp(1, 500),
+        query.offset.unwrap_or(0).max(0),
+    )
+    .await
+    .map(Json)
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn get_error_cluster(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(cluster_id): Path<i64>,
+) -> Result<Json<Vec<crate::models::error::CiError>>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.error", Perm::Read)
+        .await?;
+    let root = visible_error(&mut conn, &principal, cluster_id).await?;
+    if root.cluster_id.is_some() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    crate::services::error_cluster::members(&mut conn, root.id)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn merge_error(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(error_id): Path<i64>,
+    Json(req): Json<MergeErrorRequest>,
+) -> Result<Json<Vec<crate::models::error::CiError>>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.error", Perm::Write)
+        .await?;
+    let error = visible_error(&mut conn, &principal, error_id).await?;
+    let into = visible_error(&mut conn, &principal, req.into).await?;
+    if error.project_id != into.project_id {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let cluster_id = crate::services::error_cluster::merge(&mut conn, &error, &into, principal.uid)
+        .await
+        .map_err(|e| {
+            tracing::error!(error_id, into = req.into, "Error merge failed: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    crate::services::error_cluster::members(&mut conn, cluster_id)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn split_error(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(error_id): Path<i64>,
+) -> Result<Json<crate::models::error::CiError>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.error", Perm::Write)
+        .await?;
+    let error = visible_error(&mut conn, &principal, error_id).await?;
+
+    crate::services::error_cluster::split(&mut conn, &error, principal.uid)
+        .await
+        .map(Json)
+        .map_err(|e| {
+            tracing::error!(error_id, "Error split failed: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+async fn list_error_rules(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Query(query): Query<ErrorRulesQuery>,
+) -> Result<Json<Vec<crate::models::error_rule::CiErrorRule>>, StatusCode> {
+    if query
+        .project_id
+        .is_some_and(|id| !principal.can_access_project(id))
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.error.rule", Perm::Read)
+        .await?;
+
+    crate::services::error_service::list_rules(&mut conn, query.project_id.or(principal.project_id))
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn create_error_rule(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Json(req): Json<CreateErrorRuleRequest>,
+) -> Result<(StatusCode, Json<crate::models::error_rule::CiErrorRule>), StatusCode> {
+    // A project-scoped token may only mute its own project's errors.
+    let project_id = req.project_id.or(principal.project_id);
+    if project_id.is_some_and(|id| !principal.can_access_project(id)) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.error.rule", Perm::Create)
+        .await?;
+
+    crate::services::error_service::create_rule(
+        &mut conn,
+        crate::models::error_rule::NewCiErrorRule {
+            tenant_id: principal.tenant_id,
+            project_id,
+            pattern: req.pattern.filter(|p| !p.is_empty()),
+            category: req.category.filter(|c| !c.is_empty()),
+            reason: req.reason,
+            create_uid: Some(principal.uid),
+        },
+    )
+    .await
+    .map(|r| (StatusCode::CREATED, Json(r)))
+    .map_err(|e| {
+        tracing::warn!("Create error rule error: {e}");
+        StatusCode::BAD_REQUEST
+    })
+}
+
+async fn delete_error_rule(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(rule_id): Path<i64>,
+) -> Result<StatusCode, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.error.rule", Perm::Unlink)
+        .await?;
+    crate::services::error_service::get_rule(&mut conn, rule_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        // Tenant-wide rules are out of reach of project-scoped tokens.
+        .filter(|r| {
+            r.project_id.map_or(principal.project_id.is_none(), |id| {
+                principal.can_access_project(id)
+            })
+        })
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    match crate::services::error_service::delete_rule(&mut conn, rule_id, principal.uid).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+// ── Environments API ──
+
+#[derive(serde::Deserialize)]
+pub struct ListEnvironmentsQuery {
+    pub project_id: Option<i64>,
+    pub pr_number: Option<i32>,
+    #[serde(default)]
+    pub include_destroyed: bool,
+}
+
+async fn list_environments(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Query(query): Query<ListEnvironmentsQuery>,
+) -> Result<Json<Vec<crate::models::environment::CiEnvironment>>, StatusCode> {
+    if query
+        .project_id
+        .is_some_and(|id| !principal.can_access_project(id))
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.environment", Perm::Read)
+        .await?;
+
+    crate::services::environment_service::list(
+        &mut conn,
+        query.project_id.or(principal.project_id),
+        query.pr_number,
+        query.include_destroyed,
+    )
+    .await
+    .map(Json)
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Load an environment the caller can see, or 404.
+async fn visible_environment(
+    conn: &mut AsyncPgConnection,
+    principal: &CiPrincipal,
+    env_id: i64,
+) -> Result<crate::models::environment::CiEnvironment, StatusCode> {
+    crate::services::environment_service::get_environment(conn, env_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|env| principal.can_access_project(env.project_id))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_environment(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(env_id): Path<i64>,
+) -> Result<Json<crate::models::environment::CiEnvironment>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.environment", Perm::Read)
+        .await?;
+    visible_environment(&mut conn, &principal, env_id)
+        .await
+        .map(Json)
+}
+
+async fn wake_environment(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(env_id): Path<i64>,
+) -> Result<Json<crate::models::environment::CiEnvironment>, StatusCode> {
+    use crate::services::environment_service::{self, EnvironmentError};
+
+    let mut conn = state
+        .authorized_conn(&principal, "ci.environment", Perm::Write)
+        .await?;
+    let env = visible_environment(&mut conn, &principal, env_id).await?;
+
+    environment_service::wake(&mut conn, &env, state.config.max_running_envs)
+        .await
+        .map(Json)
+        .map_err(|e| match e.downcast_ref::<EnvironmentError>() {
+            Some(EnvironmentError::NotDormant { .. }) => StatusCode::CONFLICT,
+            Some(EnvironmentError::AtCapacity { .. }) => {
+                tracing::warn!(env_id, "Wake environment rejected: {e}");
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            None => {
+                tracing::error!(env_id, "Wake environment failed: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })
+}
+
+async fn destroy_environment(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(env_id): Path<i64>,
+) -> Result<StatusCode, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.environment", Perm::Write)
+        .await?;
+    let env = visible_environment(&mut conn, &principal, env_id).await?;
+
+    let destroyed = crate::services::environment_service::destroy(&mut conn, &env, "manual")
+        .await
+        .map_err(|e| {
+            tracing::error!(env_id, "Destroy environment failed: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if destroyed {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(StatusCode::CONFLICT)
+    }
+}
+
+async fn environment_history(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(env_id): Path<i64>,
+) -> Result<Json<Vec<crate::models::event::CiEvent>>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.environment", Perm::Read)
+        .await?;
+    let env = visible_environment(&mut conn, &principal, env_id).await?;
+
+    crate::services::environment_service::history(&mut conn, env.id)
         .await
         .map(Json)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
+
+#[derive(serde::Deserialize)]
+pub struct EnvironmentLogsQuery {
+    /// Lines to return, 200 by default.
+    pub tail: Option<usize>,
+}
+
+/// The last `tail` lines of an environment's container logs as plain
+/// text. 404 where environments are not provisioned.
+async fn environment_logs(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(env_id): Path<i64>,
+    Query(query): Query<EnvironmentLogsQuery>,
+) -> Result<(HeaderMap, String), StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.environment", Perm::Read)
+        .await?;
+    let env = visible_environment(&mut conn, &principal, env_id).await?;
+    let env_dir = state
+        .config
+        .env_dir
+        .as_deref()
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let lines = query.tail.unwrap_or(200);
+    let dir = crate::services::compose_provisioner::env_path(env_dir, env.id);
+    let log = crate::services::environment_logs::recent(&dir, lines)
+        .await
+        .map_err(|e| {
+            tracing::error!(env_id, "Read environment logs failed: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .unwrap_or_default();
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        axum::http::HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    Ok((
+        headers,
+        api::log_excerpt(&log, api::LogRange::Tail(lines)).to_string(),
+    ))
+}
+
+#[derive(serde::Deserialize)]
+pub struct SnapshotRequest {
+    pub name: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct SnapshotResponse {
+    pub name: String,
+    pub size_bytes: u64,
+}
+
+/// Dump a running environment's database as a named snapshot of its
+/// project, for the environment templates that start from it.
+async fn snapshot_environment(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(env_id): Path<i64>,
+    Json(req): Json<SnapshotRequest>,
+) -> Result<(StatusCode, Json<SnapshotResponse>), StatusCode> {
+    if !crate::services::cache_service::valid_name(&req.name) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.environment", Perm::Write)
+        .await?;
+    let env = visible_environment(&mut conn, &principal, env_id).await?;
+    if state.config.env_dir.is_none() || state.config.env_snapshot_dir.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if env.status != "running" {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let size_bytes = crate::services::compose_provisioner::snapshot(&state.config, &env, &req.name)
+        .await
+        .map_err(|e| {
+            tracing::error!(env_id, "Environment snapshot failed: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    tracing::info!(env_id, snapshot = %req.name, size_bytes, "Environment snapshot taken");
+    Ok((
+        StatusCode::CREATED,
+        Json(SnapshotResponse {
+            name: req.name,
+            size_bytes,
+        }),
+    ))
+}
+
+/// Rebuild an environment's record from its event stream.
+async fn replay_environment(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(env_id): Path<i64>,
+) -> Result<Json<crate::models::environment::CiEnvironment>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.environment", Perm::Write)
+        .await?;
+    let env = visible_environment(&mut conn, &principal, env_id).await?;
+
+    crate::services::environment_service::replay(&mut conn, env.id)
+        .await
+        .map_err(|e| {
+            tracing::error!(env_id, "Replay environment failed: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+// ── Secrets API ──
+
+#[derive(serde::Deserialize)]
+pub struct SetSecretRequest {
+    pub value: String,
+}
+
+async fn list_secrets(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(project_id): Path<i64>,
+) -> Result<Json<Vec<crate::models::secret::CiSecret>>, StatusCode> {
+    if !principal.can_access_project(project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.secret", Perm::Read)
+        .await?;
+
+    crate::services::secret_service::list_secrets(&mut conn, project_id)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn set_secret(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path((project_id, name)): Path<(i64, String)>,
+    Json(req): Json<SetSecretRequest>,
+) -> Result<Json<crate::models::secret::CiSecret>, StatusCode> {
+    if !principal.can_access_project(project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let keyring = state
+        .config
+        .secrets_keyring
+        .clone()
+        .ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let mut conn = state
+        .authorized_conn(&principal, "ci.secret", Perm::Write)
+        .await?;
+
+    crate::services::secret_service::set_secret(
+        &mut conn,
+        &keyring,
+        principal.tenant_id,
+        Some(project_id),
+        &name,
+        &req.value,
+        principal.uid,
+    )
+    .await
+    .map(Json)
+    .map_err(|e| {
+        tracing::error!("Set secret error: {e}");
+        StatusCode::BAD_REQUEST
+    })
+}
+
+async fn delete_secret(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path((project_id, name)): Path<(i64, String)>,
+) -> Result<StatusCode, StatusCode> {
+    if !principal.can_access_project(project_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.secret", Perm::Unlink)
+        .await?;
+
+    match crate::services::secret_service::delete_secret(&mut conn, Some(project_id), &name).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Tenant-wide secrets reach every project's builds, so only CI admins with
+/// a token not scoped to one project may change them.
+async fn check_tenant_secret_admin(
+    conn: &mut AsyncPgConnection,
+    principal: &CiPrincipal,
+) -> Result<(), StatusCode> {
+    if principal.project_id.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    match access_service::is_ci_admin(conn, principal.uid).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(StatusCode::FORBIDDEN),
+        Err(e) => {
+            tracing::error!("CI admin check error: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn set_tenant_secret(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(name): Path<String>,
+    Json(req): Json<SetSecretRequest>,
+) -> Result<Json<crate::models::secret::CiSecret>, StatusCode> {
+    let keyring = state
+        .config
+        .secrets_keyring
+        .clone()
+        .ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let mut conn = state
+        .authorized_conn(&principal, "ci.secret", Perm::Write)
+        .await?;
+    check_tenant_secret_admin(&mut conn, &principal).await?;
+
+    crate::services::secret_service::set_secret(
+        &mut conn,
+        &keyring,
+        principal.tenant_id,
+        None,
+        &name,
+        &req.value,
+        principal.uid,
+    )
+    .await
+    .map(Json)
+    .map_err(|e| {
+        tracing::error!("Set secret error: {e}");
+        StatusCode::BAD_REQUEST
+    })
+}
+
+async fn delete_tenant_secret(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(name): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.secret", Perm::Unlink)
+        .await?;
+    check_tenant_secret_admin(&mut conn, &principal).await?;
+
+    match crate::services::secret_service::delete_secret(&mut conn, None, &name).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct SecretAccessQuery {
+    pub name: Option<String>,
+    pub build_id: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+/// Which builds saw a secret (or which secrets a build saw).
+async fn secret_access_log(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Query(query): Query<SecretAccessQuery>,
+) -> Result<Json<Vec<crate::models::secret_access::CiSecretAccess>>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.secret", Perm::Read)
+        .await?;
+
+    crate::services::secret_service::access_log(
+        &mut conn,
+        query.name.as_deref(),
+        query.build_id,
+        query.limit.unwrap_or(100),
+    )
+    .await
+    .map(|entries| {
+        Json(
+            entries
+                .into_iter()
+                .filter(|e| principal.can_access_project(e.project_id))
+                .collect(),
+        )
+    })
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+// ── Command policies ──
+
+#[derive(serde::Deserialize)]
+pub struct PolicyRequest {
+    pub name: String,
+    #[serde(default)]
+    pub allow_patterns: Vec<String>,
+    #[serde(default)]
+    pub deny_patterns: Vec<String>,
+    #[serde(default)]
+    pub banned_env_vars: Vec<String>,
+    pub max_steps: Option<i32>,
+}
+
+impl PolicyRequest {
+    fn into_new(
+        self,
+        principal: &CiPrincipal,
+    ) -> crate::models::command_policy::NewCiCommandPolicy {
+        crate::models::command_policy::NewCiCommandPolicy {
+            tenant_id: principal.tenant_id,
+            name: self.name,
+            allow_patterns: self.allow_patterns,
+            deny_patterns: self.deny_patterns,
+            banned_env_vars: self.banned_env_vars,
+            max_steps: self.max_steps,
+            create_uid: Some(principal.uid),
+        }
+    }
+}
+
+async fn list_policies(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+) -> Result<Json<Vec<crate::models::command_policy::CiCommandPolicy>>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.command.policy", Perm::Read)
+        .await?;
+
+    policy_service::list_policies(&mut conn)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Policies bind every project of the tenant, so project-scoped tokens
+/// may not change them.
+async fn create_policy(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Json(req): Json<PolicyRequest>,
+) -> Result<
+    (
+        StatusCode,
+        Json<crate::models::command_policy::CiCommandPolicy>,
+    ),
+    StatusCode,
+> {
+    if principal.project_id.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.command.policy", Perm::Create)
+        .await?;
+
+    policy_service::create_policy(&mut conn, req.into_new(&principal))
+        .await
+        .map(|p| (StatusCode::CREATED, Json(p)))
+        .map_err(|e| {
+            tracing::warn!("Create command policy error: {e}");
+            StatusCode::BAD_REQUEST
+        })
+}
+
+async fn update_policy(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(policy_id): Path<i64>,
+    Json(req): Json<PolicyRequest>,
+) -> Result<Json<crate::models::command_policy::CiCommandPolicy>, StatusCode> {
+    if principal.project_id.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.command.policy", Perm::Write)
+        .await?;
+
+    match policy_service::update_policy(
+        &mut conn,
+        policy_id,
+        req.into_new(&principal),
+        principal.uid,
+    )
+    .await
+    {
+        Ok(Some(policy)) => Ok(Json(policy)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::warn!(policy_id, "Update command policy error: {e}");
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+async fn delete_policy(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(policy_id): Path<i64>,
+) -> Result<StatusCode, StatusCode> {
+    if principal.project_id.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.command.policy", Perm::Unlink)
+        .await?;
+
+    match policy_service::delete_policy(&mut conn, policy_id, principal.uid).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+// ── Alert rules ──
+
+#[derive(serde::Deserialize)]
+pub struct CreateAlertRuleRequest {
+    pub project_id: Option<i64>,
+    pub name: String,
+    pub kind: String,
+    pub threshold: f64,
+    pub window_minutes: Option<i32>,
+    pub min_builds: Option<i32>,
+    pub cooldown_minutes: Option<i32>,
+    pub webhook_url: Option<String>,
+}
+
+async fn list_alert_rules(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+) -> Result<Json<Vec<crate::models::alert_rule::CiAlertRule>>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.alert.rule", Perm::Read)
+        .await?;
+
+    alert_service::list_rules(&mut conn)
+        .await
+        .map(|rules| {
+            Json(
+                rules
+                    .into_iter()
+                    .filter(|r| {
+                        r.project_id
+                            .is_none_or(|id| principal.can_access_project(id))
+                    })
+                    .collect(),
+            )
+        })
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn create_alert_rule(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Json(req): Json<CreateAlertRuleRequest>,
+) -> Result<(StatusCode, Json<crate::models::alert_rule::CiAlertRule>), StatusCode> {
+    // A project-scoped token may only watch its own project.
+    let project_id = req.project_id.or(principal.project_id);
+    if project_id.is_some_and(|id| !principal.can_access_project(id)) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.alert.rule", Perm::Create)
+        .await?;
+
+    alert_service::create_rule(
+        &mut conn,
+        crate::models::alert_rule::NewCiAlertRule {
+            tenant_id: principal.tenant_id,
+            project_id,
+            name: req.name,
+            kind: req.kind,
+            threshold: req.threshold,
+            window_minutes: req.window_minutes,
+            min_builds: req.min_builds,
+            cooldown_minutes: req.cooldown_minutes,
+            webhook_url: req.webhook_url,
+            create_uid: Some(principal.uid),
+        },
+    )
+    .await
+    .map(|r| (StatusCode::CREATED, Json(r)))
+    .map_err(|e| {
+        tracing::warn!("Create alert rule error: {e}");
+        StatusCode::BAD_REQUEST
+    })
+}
+
+async fn delete_alert_rule(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(rule_id): Path<i64>,
+) -> Result<StatusCode, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.alert.rule", Perm::Unlink)
+        .await?;
+    alert_service::get_rule(&mut conn, rule_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        // Tenant-wide rules are out of reach of project-scoped tokens.
+        .filter(|r| {
+            r.project_id.map_or(principal.project_id.is_none(), |id| {
+                principal.can_access_project(id)
+            })
+        })
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    match alert_service::delete_rule(&mut conn, rule_id, principal.uid).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+// ── Notification endpoints ──
+
+#[derive(serde::Deserialize)]
+pub struct CreateNotificationEndpointRequest {
+    pub project_id: Option<i64>,
+    pub url: String,
+    /// Signing secret; generated when omitted.
+    pub secret: Option<String>,
+    /// Events to send; every event when empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+/// An endpoint as returned on creation — the only time its secret is shown.
+#[derive(serde::Serialize)]
+pub struct CreatedNotificationEndpointJson {
+    #[serde(flatten)]
+    pub endpoint: crate::models::notification::CiNotificationEndpoint,
+    pub secret: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct DeliveriesQuery {
+    pub limit: Option<i64>,
+}
+
+async fn list_notification_endpoints(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+) -> Result<Json<Vec<crate::models::notification::CiNotificationEndpoint>>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.notification.endpoint", Perm::Read)
+        .await?;
+
+    notification_service::list_endpoints(&mut conn)
+        .await
+        .map(|endpoints| {
+            Json(
+                endpoints
+                    .into_iter()
+                    .filter(|e| {
+                        e.project_id
+                            .is_none_or(|id| principal.can_access_project(id))
+                    })
+                    .collect(),
+            )
+        })
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn create_notification_endpoint(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Json(req): Json<CreateNotificationEndpointRequest>,
+) -> Result<(StatusCode, Json<CreatedNotificationEndpointJson>), StatusCode> {
+    // A project-scoped token may only hear of its own project's builds.
+    let project_id = req.project_id.or(principal.project_id);
+    if project_id.is_some_and(|id| !principal.can_access_project(id)) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.notification.endpoint", Perm::Create)
+        .await?;
+
+    notification_service::create_endpoint(
+        &mut conn,
+        crate::models::notification::NewCiNotificationEndpoint {
+            tenant_id: principal.tenant_id,
+            project_id,
+            url: req.url,
+            secret: req
+                .secret
+                .filter(|s| !s.is_empty())
+                .unwrap_or_else(notification_service::generate_secret),
+            events: req.events,
+            create_uid: Some(principal.uid),
+        },
+    )
+    .await
+    .map(|endpoint| {
+        let secret = endpoint.secret.clone();
+        (
+            StatusCode::CREATED,
+            Json(CreatedNotificationEndpointJson { endpoint, secret }),
+        )
+    })
+    .map_err(|e| {
+        tracing::warn!("Create notification endpoint error: {e}");
+        StatusCode::BAD_REQUEST
+    })
+}
+
+async fn delete_notification_endpoint(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(endpoint_id): Path<i64>,
+) -> Result<StatusCode, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.notification.endpoint", Perm::Unlink)
+        .await?;
+    notification_service::get_endpoint(&mut conn, endpoint_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        // Tenant-wide endpoints are out of reach of project-scoped tokens.
+        .filter(|e| {
+            e.project_id.map_or(principal.project_id.is_none(), |id| {
+                principal.can_access_project(id)
+            })
+        })
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    match notification_service::delete_endpoint(&mut conn, endpoint_id, principal.uid).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+async fn list_notification_deliveries(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(endpoint_id): Path<i64>,
+    Query(query): Query<DeliveriesQuery>,
+) -> Result<Json<Vec<crate::models::notification::CiNotificationDelivery>>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.notification.endpoint", Perm::Read)
+        .await?;
+
+    let endpoint = notification_service::get_endpoint(&mut conn, endpoint_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|e| {
+            e.project_id.map_or(principal.project_id.is_none(), |id| {
+                principal.can_access_project(id)
+            })
+        })
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    notification_service::deliveries(&mut conn, endpoint.id, query.limit.unwrap_or(100).min(1000))
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+// ── Notification channels ──
+
+#[derive(serde::Deserialize)]
+pub struct CreateNotificationChannelRequest {
+    pub project_id: Option<i64>,
+    pub name: String,
+    /// Message format: `discord` or `generic`.
+    pub kind: String,
+    pub webhook_url: String,
+    /// Events to post; every event when empty, none with a digest.
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// `daily` or `weekly` digest of the channel's projects.
+    pub digest: Option<String>,
+}
+
+async fn list_notification_channels(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+) -> Result<Json<Vec<crate::models::notification::CiNotificationChannel>>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.notification.channel", Perm::Read)
+        .await?;
+
+    notification_service::list_channels(&mut conn)
+        .await
+        .map(|channels| {
+            Json(
+                channels
+                    .into_iter()
+                    .filter(|c| {
+                        c.project_id
+                            .is_none_or(|id| principal.can_access_project(id))
+                    })
+                    .collect(),
+            )
+        })
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn create_notification_channel(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Json(req): Json<CreateNotificationChannelRequest>,
+) -> Result<
+    (
+        StatusCode,
+        Json<crate::models::notification::CiNotificationChannel>,
+    ),
+    StatusCode,
+> {
+    // A project-scoped token may only hear of its own project's builds.
+    let project_id = req.project_id.or(principal.project_id);
+    if project_id.is_some_and(|id| !principal.can_access_project(id)) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.notification.channel", Perm::Create)
+        .await?;
+
+    notification_service::create_channel(
+        &mut conn,
+        crate::models::notification::NewCiNotificationChannel {
+            tenant_id: principal.tenant_id,
+            project_id,
+            name: req.name,
+            kind: req.kind,
+            webhook_url: req.webhook_url,
+            events: req.events,
+            create_uid: Some(principal.uid),
+            digest: req.digest.filter(|d| !d.is_empty()),
+        },
+    )
+    .await
+    .map(|channel| (StatusCode::CREATED, Json(channel)))
+    .map_err(|e| {
+        tracing::warn!("Create notification channel error: {e}");
+        StatusCode::BAD_REQUEST
+    })
+}
+
+async fn delete_notification_channel(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(channel_id): Path<i64>,
+) -> Result<StatusCode, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.notification.channel", Perm::Unlink)
+        .await?;
+    notification_service::get_channel(&mut conn, channel_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        // Tenant-wide channels are out of reach of project-scoped tokens.
+        .filter(|c| {
+            c.project_id.map_or(principal.project_id.is_none(), |id| {
+                principal.can_access_project(id)
+            })
+        })
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    match notification_service::delete_channel(&mut conn, channel_id, principal.uid).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+// ── Notification rules ──
+
+#[derive(serde::Deserialize)]
+pub struct CreateNotificationRuleRequest {
+    pub project_id: Option<i64>,
+    pub channel_id: i64,
+    pub name: String,
+    /// Branches covered, as a trigger's pattern; all when omitted.
+    pub branch_pattern: Option<String>,
+    /// Build events covered; every build event when empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Minutes the rule stays quiet after a message; none by default.
+    pub throttle_minutes: Option<i32>,
+    /// Only notify first failures and recoveries of the branch.
+    #[serde(default)]
+    pub on_transition: bool,
+}
+
+async fn list_notification_rules(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+) -> Result<Json<Vec<crate::models::notification_rule::CiNotificationRule>>, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.notification.rule", Perm::Read)
+        .await?;
+
+    notification_rules::list_rules(&mut conn)
+        .await
+        .map(|rules| {
+            Json(
+                rules
+                    .into_iter()
+                    .filter(|r| {
+                        r.project_id
+                            .is_none_or(|id| principal.can_access_project(id))
+                    })
+                    .collect(),
+            )
+        })
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn create_notification_rule(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Json(req): Json<CreateNotificationRuleRequest>,
+) -> Result<
+    (
+        StatusCode,
+        Json<crate::models::notification_rule::CiNotificationRule>,
+    ),
+    StatusCode,
+> {
+    // A project-scoped token may only route its own project's builds.
+    let project_id = req.project_id.or(principal.project_id);
+    if project_id.is_some_and(|id| !principal.can_access_project(id)) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.notification.rule", Perm::Create)
+        .await?;
+
+    notification_rules::create_rule(
+        &mut conn,
+        crate::models::notification_rule::NewCiNotificationRule {
+            tenant_id: principal.tenant_id,
+            project_id,
+            channel_id: req.channel_id,
+            name: req.name,
+            branch_pattern: req.branch_pattern.filter(|p| !p.is_empty()),
+            events: req.events,
+            throttle_minutes: req.throttle_minutes.unwrap_or(0),
+            create_uid: Some(principal.uid),
+            on_transition: req.on_transition,
+        },
+    )
+    .await
+    .map(|rule| (StatusCode::CREATED, Json(rule)))
+    .map_err(|e| {
+        tracing::warn!("Create notification rule error: {e}");
+        StatusCode::BAD_REQUEST
+    })
+}
+
+async fn delete_notification_rule(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(rule_id): Path<i64>,
+) -> Result<StatusCode, StatusCode> {
+    let mut conn = state
+        .authorized_conn(&principal, "ci.notification.rule", Perm::Unlink)
+        .await?;
+    notification_rules::get_rule(&mut conn, rule_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        // Tenant-wide rules are out of reach of project-scoped tokens.
+        .filter(|r| {
+            r.project_id.map_or(principal.project_id.is_none(), |id| {
+                principal.can_access_project(id)
+            })
+        })
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    match notification_rules::delete_rule(&mut conn, rule_id, principal.uid).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+// ── API token management ──
+
+#[derive(serde::Deserialize)]
+pub struct CreateTokenRequest {
+    pub name: String,
+    pub project_id: Option<i64>,
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct RotateTokenRequest {
+    pub grace_secs: Option<i64>,
+}
+
+/// A token as returned on issue/rotate — the only time the plaintext is shown.
+#[derive(serde::Serialize)]
+pub struct IssuedTokenJson {
+    #[serde(flatten)]
+    pub token: crate::models::api_token::CiApiToken,
+    pub token_value: String,
+}
+
+impl From<crate::services::token_service::IssuedToken> for IssuedTokenJson {
+    fn from(issued: crate::services::token_service::IssuedToken) -> Self {
+        Self {
+            token: issued.token,
+            token_value: issued.plaintext,
+        }
+    }
+}
+
+async fn list_tokens(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+) -> Result<Json<Vec<crate::models::api_token::CiApiToken>>, StatusCode> {
+    if principal.token_id.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.api.token", Perm::Read)
+        .await?;
+
+    crate::services::token_service::list_tokens(&mut conn)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn create_token(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Json(req): Json<CreateTokenRequest>,
+) -> Result<(StatusCode, Json<IssuedTokenJson>), StatusCode> {
+    // Tokens cannot mint further tokens; only a session user can.
+    if principal.token_id.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if req.expires_in_days.is_some_and(|days| {
+        !(1..=crate::services::token_service::MAX_LIFETIME_DAYS).contains(&days)
+    }) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.api.token", Perm::Create)
+        .await?;
+    if let Some(project_id) = req.project_id {
+        crate::services::project_service::get_project(&mut conn, project_id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .filter(|p| p.tenant_id == principal.tenant_id && principal.can_access_project(p.id))
+            .ok_or(StatusCode::NOT_FOUND)?;
+    }
+
+    let expires_at = req
+        .expires_in_days
+        .map(|days| chrono::Utc::now() + chrono::Duration::days(days));
+
+    crate::services::token_service::issue_token(
+        &mut conn,
+        principal.tenant_id,
+        principal.uid,
+        &req.name,
+        req.project_id,
+        expires_at,
+    )
+    .await
+    .map(|issued| (StatusCode::CREATED, Json(issued.into())))
+    .map_err(|e| {
+        tracing::error!("Create token error: {e}");
+        StatusCode::BAD_REQUEST
+    })
+}
+
+/// A token acts as its owner, so only the owner and CI admins may rotate or
+/// revoke it: a new token for someone else's would be theirs.
+async fn check_token_owner(
+    conn: &mut AsyncPgConnection,
+    principal: &CiPrincipal,
+    token: &crate::models::api_token::CiApiToken,
+) -> Result<(), StatusCode> {
+    if token.uid == principal.uid {
+        return Ok(());
+    }
+    match access_service::is_ci_admin(conn, principal.uid).await {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            tracing::warn!(
+                uid = principal.uid,
+                token_id = token.id,
+                "API token of another user denied"
+            );
+            Err(StatusCode::FORBIDDEN)
+        }
+        Err(e) => {
+            tracing::error!("CI admin check error: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn rotate_token(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(token_id): Path<i64>,
+    Json(req): Json<RotateTokenRequest>,
+) -> Result<Json<IssuedTokenJson>, StatusCode> {
+    // A token may rotate itself; anything else needs a session.
+    if principal.token_id.is_some_and(|id| id != token_id) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.api.token", Perm::Write)
+        .await?;
+
+    let old = crate::services::token_service::get_token(&mut conn, token_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|t| t.revoked_at.is_none())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    check_token_owner(&mut conn, &principal, &old).await?;
+
+    let grace = req
+        .grace_secs
+        .unwrap_or(crate::services::token_service::DEFAULT_ROTATION_GRACE_SECS);
+    if !(0..=crate::services::token_service::MAX_ROTATION_GRACE_SECS).contains(&grace) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    crate::services::token_service::rotate_token(&mut conn, &old, grace)
+        .await
+        .map(|issued| Json(issued.into()))
+        .map_err(|e| {
+            tracing::error!("Rotate token error: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+async fn revoke_token(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    Path(token_id): Path<i64>,
+) -> Result<StatusCode, StatusCode> {
+    if principal.token_id.is_some_and(|id| id != token_id) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let mut conn = state
+        .authorized_conn(&principal, "ci.api.token", Perm::Unlink)
+        .await?;
+
+    let token = crate::services::token_service::get_token(&mut conn, token_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    check_token_owner(&mut conn, &principal, &token).await?;
+
+    match crate::services::token_service::revoke_token(&mut conn, token_id).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
@@ -0,0 +1,227 @@
+//! Read-only endpoints for public and internal projects.
+//!
+//! Served under `/ci/public` without requiring authentication. Public
+//! projects are readable by anyone, internal projects by authenticated
+//! callers of the project's tenant whose token (if any) is scoped to it;
+//! private projects are only reachable through `/ci/api`. Queries
+//! run in system context, so every handler checks visibility itself.
+
+use axum::extract::{Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Json, Response};
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+
+use crate::models::build::{BuildStatus, CiBuild};
+use crate::models::build_step::CiBuildStep;
+use crate::models::project::{CiProject, Visibility};
+use crate::schema::{ci_build_steps, ci_builds};
+use crate::services::{project_service, secret_service};
+
+use super::api;
+use super::auth::CiPrincipal;
+use super::CiRouterState;
+
+/// Step output with secrets stripped.
+#[derive(Debug, Serialize)]
+pub struct StepLogJson {
+    pub name: String,
+    pub sequence: i32,
+    pub attempt: i32,
+    pub status: String,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PublicBuildsQuery {
+    pub limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct BadgeQuery {
+    pub branch: Option<String>,
+}
+
+async fn system_conn(
+    state: &CiRouterState,
+) -> Result<impl std::ops::DerefMut<Target = AsyncPgConnection> + Send, StatusCode> {
+    crate::db::system_conn(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Load a project if `principal` may read it here. Not-readable and
+/// missing projects both come back as 404 so private repos don't leak.
+async fn readable_project(
+    conn: &mut AsyncPgConnection,
+    project_id: i64,
+    principal: Option<&CiPrincipal>,
+) -> Result<CiProject, StatusCode> {
+    let project = project_service::get_project(conn, project_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|p| p.active)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    match project.visibility() {
+        Visibility::Public => Ok(project),
+        Visibility::Internal
+            if principal.is_some_and(|p| {
+                p.tenant_id == project.tenant_id && p.can_access_project(project.id)
+            }) =>
+        {
+            Ok(project)
+        }
+        _ => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+async fn readable_build(
+    conn: &mut AsyncPgConnection,
+    build_id: i64,
+    principal: Option<&CiPrincipal>,
+) -> Result<(CiProject, CiBuild), StatusCode> {
+    let build: CiBuild = ci_builds::table
+        .find(build_id)
+        .first(conn)
+        .await
+        .optional()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let project = readable_project(conn, build.project_id, principal).await?;
+    Ok((project, build))
+}
+
+pub async fn list_project_builds(
+    State(state): State<CiRouterState>,
+    principal: Option<CiPrincipal>,
+    Path(project_id): Path<i64>,
+    Query(query): Query<PublicBuildsQuery>,
+) -> Result<Json<Vec<api::BuildJson>>, StatusCode> {
+    let mut conn = system_conn(&state).await?;
+    readable_project(&mut conn, project_id, principal.as_ref()).await?;
+
+    let filter = api::BuildFilter {
+        project_id: Some(project_id),
+        ..Default::default()
+    };
+    let page = api::Page {
+        limit: query.limit.unwrap_or(20).min(100),
+        offset: 0,
+        before: None,
+    };
+    api::list_builds(&mut conn, &filter, &page)
+        .await
+        .map(|page| Json(page.builds))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn get_build(
+    State(state): State<CiRouterState>,
+    principal: Option<CiPrincipal>,
+    Path(build_id): Path<i64>,
+) -> Result<Json<api::BuildJson>, StatusCode> {
+    let mut conn = system_conn(&state).await?;
+    readable_build(&mut conn, build_id, principal.as_ref()).await?;
+
+    api::get_build(&mut conn, build_id)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::NOT_FOUND)
+}
+
+pub async fn get_build_logs(
+    State(state): State<CiRouterState>,
+    principal: Option<CiPrincipal>,
+    Path(build_id): Path<i64>,
+) -> Result<Json<Vec<StepLogJson>>, StatusCode> {
+    let mut conn = system_conn(&state).await?;
+    let (project, build) = readable_build(&mut conn, build_id, principal.as_ref()).await?;
+
+    let steps: Vec<CiBuildStep> = ci_build_steps::table
+        .filter(ci_build_steps::build_id.eq(build.id))
+        .order((
+            ci_build_steps::sequence.asc(),
+            ci_build_steps::attempt.asc(),
+        ))
+        .load(&mut conn)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Output is masked at execution time; mask again with the current values
+    // in case a secret changed since the build ran.
+    let secrets = match state.config.secrets_keyring {
+        Some(ref keyring) => {
+            secret_service::resolve_for_project(&mut conn, keyring, project.tenant_id, project.id)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        }
+        None => Vec::new(),
+    };
+
+    Ok(Json(
+        steps
+            .into_iter()
+            .map(|s| StepLogJson {
+                name: s.name,
+                sequence: s.sequence,
+                attempt: s.attempt,
+                status: s.status,
+                stdout: s.stdout.map(|o| secret_service::mask(&o, &secrets)),
+                stderr: s.stderr.map(|o| secret_service::mask(&o, &secrets)),
+            })
+            .collect(),
+    ))
+}
+
+/// SVG status badge for the latest build of a branch (default branch if
+/// none given). Skipped builds don't count.
+pub async fn badge(
+    State(state): State<CiRouterState>,
+    principal: Option<CiPrincipal>,
+    Path(project_id): Path<i64>,
+    Query(query): Query<BadgeQuery>,
+) -> Result<Response, StatusCode> {
+    let mut conn = system_conn(&state).await?;
+    let project = readable_project(&mut conn, project_id, principal.as_ref()).await?;
+    let branch = query.branch.unwrap_or(project.default_branch);
+
+    let status: Option<String> = ci_builds::table
+        .filter(ci_builds::project_id.eq(project.id))
+        .filter(ci_builds::branch.eq(&branch))
+        .filter(ci_builds::status.ne(BuildStatus::Skipped.as_str()))
+        .order(ci_builds::id.desc())
+        .select(ci_builds::status)
+        .first(&mut conn)
+        .await
+        .optional()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let status = status.unwrap_or_else(|| "unknown".to_string());
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/svg+xml"),
+            (header::CACHE_CONTROL, "no-cache, max-age=0"),
+        ],
+        badge_svg(&status),
+    )
+        .into_response())
+}
+
+fn badge_svg(status: &str) -> String {
+    let color = match status {
+        "success" => "#4c1",
+        "failure" | "timeout" | "config_error" => "#e05d44",
+        "running" | "pending" => "#dfb317",
+        _ => "#9f9f9f",
+    };
+    // ~7px per character at 11px Verdana, plus padding.
+    let width = 10 + status.len() as u32 * 7;
+    let total = 28 + width;
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{total}" height="20" role="img" aria-label="ci: {status}"><rect width="28" height="20" fill="#555"/><rect x="28" width="{width}" height="20" fill="{color}"/><g fill="#fff" text-anchor="middle" font-family="Verdana,sans-serif" font-size="11"><text x="14" y="14">ci</text><text x="{mid}" y="14">{status}</text></g></svg>"##,
+        mid = 28 + width / 2,
+    )
+}
@@ -0,0 +1,267 @@
+//! Per-caller rate limiting for `/ci/api` and `/ci/webhook/*`.
+//!
+//! Each caller gets a token bucket holding a minute's worth of requests
+//! that refills continuously. Webhooks and API requests without a `Bearer`
+//! token are keyed by client IP. An API token is only a key once
+//! [`CiPrincipal`](super::auth::CiPrincipal) has verified it: a valid
+//! token draws from its own bucket, an invalid one from its client's, and
+//! a client out of requests gets no token looked up at all.
+//! A request finding its bucket empty is answered `429 Too Many Requests`
+//! with a `Retry-After` header, and counted in `ci_rate_limited_total`.
+//!
+//! At most `MAX_TRACKED_CALLERS` buckets are kept. When the table is full,
+//! idle ones are dropped, at most once every `PRUNE_INTERVAL`; until then,
+//! or if none were idle, new callers are limited outright.
+//!
+//! The client IP is the connection's peer address, or with
+//! `CI_RATE_LIMIT_TRUST_FORWARDED` the last `X-Forwarded-For` entry, as
+//! appended by a reverse proxy in front of the server.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::request::Parts;
+use axum::http::{header, Extensions, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use super::auth;
+use crate::config::CiConfig;
+
+/// Buckets tracked before idle (full) ones are dropped.
+const MAX_TRACKED_CALLERS: usize = 10_000;
+
+/// Shortest time between two prunings of a full table.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which limit a request counts against, by path below `/ci`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    Api,
+    Webhook,
+}
+
+impl Scope {
+    fn of(path: &str) -> Option<Self> {
+        if path.starts_with("/api/") || path == "/graphql" {
+            Some(Self::Api)
+        } else if path.starts_with("/webhook/") {
+            Some(Self::Webhook)
+        } else {
+            None
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Api => "api",
+            Self::Webhook => "webhook",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    by_caller: HashMap<String, Bucket>,
+    /// A full table is not pruned again before then.
+    next_prune: Instant,
+}
+
+/// A token bucket per caller, allowing `per_minute` requests a minute.
+#[derive(Debug)]
+struct Limiter {
+    per_minute: u32,
+    buckets: Mutex<Buckets>,
+}
+
+impl Limiter {
+    fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: Mutex::new(Buckets {
+                by_caller: HashMap::new(),
+                next_prune: Instant::now(),
+            }),
+        }
+    }
+
+    fn rate(&self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+
+    /// Requests left in `bucket` at `now`.
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate()).min(f64::from(self.per_minute))
+    }
+
+    /// Take a request from `key`'s bucket. `Err` carries how long until
+    /// one is available again.
+    fn check(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let capacity = f64::from(self.per_minute);
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.by_caller.len() >= MAX_TRACKED_CALLERS && !buckets.by_caller.contains_key(key) {
+            if now < buckets.next_prune {
+                return Err(buckets.next_prune - now);
+            }
+            // A full bucket is the same as no bucket.
+            buckets
+                .by_caller
+                .retain(|_, b| self.refilled(b, now) < capacity);
+            buckets.next_prune = now + PRUNE_INTERVAL;
+            if buckets.by_caller.len() >= MAX_TRACKED_CALLERS {
+                return Err(PRUNE_INTERVAL);
+            }
+        }
+
+        let bucket = buckets.by_caller.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate()))
+        }
+    }
+
+    /// Like [`Limiter::check`], without taking the request.
+    fn peek(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        match buckets.by_caller.get(key).map(|b| self.refilled(b, now)) {
+            Some(tokens) if tokens < 1.0 => {
+                Err(Duration::from_secs_f64((1.0 - tokens) / self.rate()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Request limits of the CI router. A limit of 0 disables that scope.
+#[derive(Debug)]
+pub struct RateLimits {
+    api: Option<Limiter>,
+    webhook: Option<Limiter>,
+    trust_forwarded: bool,
+}
+
+impl RateLimits {
+    pub fn from_config(config: &CiConfig) -> Arc<Self> {
+        let limiter = |per_minute: u32| (per_minute > 0).then(|| Limiter::new(per_minute));
+        Arc::new(Self {
+            api: limiter(config.api_rate_limit),
+            webhook: limiter(config.webhook_rate_limit),
+            trust_forwarded: config.rate_limit_trust_forwarded,
+        })
+    }
+
+    fn limiter(&self, scope: Scope) -> Option<&Limiter> {
+        match scope {
+            Scope::Api => self.api.as_ref(),
+            Scope::Webhook => self.webhook.as_ref(),
+        }
+    }
+
+    /// The client address the request is attributed to.
+    fn client_ip(&self, headers: &HeaderMap, peer: Option<SocketAddr>) -> String {
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .filter(|_| self.trust_forwarded)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty());
+        match (forwarded, peer) {
+            (Some(ip), _) => ip.to_string(),
+            (None, Some(peer)) => peer.ip().to_string(),
+            (None, None) => "unknown".to_string(),
+        }
+    }
+
+    /// Bucket key of the request's client.
+    fn client_key(&self, headers: &HeaderMap, extensions: &Extensions) -> String {
+        let peer = extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+        format!("ip:{}", self.client_ip(headers, peer))
+    }
+
+    /// Refuse to look up an API token for a client out of requests.
+    pub fn before_token(&self, parts: &Parts) -> Result<(), Response> {
+        let Some(limiter) = self.limiter(Scope::Api).filter(|_| is_api(parts)) else {
+            return Ok(());
+        };
+        let key = self.client_key(&parts.headers, &parts.extensions);
+        limiter
+            .peek(&key, Instant::now())
+            .map_err(|retry_after| too_many_requests(Scope::Api, parts.uri.path(), retry_after))
+    }
+
+    /// Take the request of a looked-up API token: from the bucket of
+    /// `token_id`, or from its client's if the token was invalid.
+    pub fn after_token(&self, parts: &Parts, token_id: Option<i64>) -> Result<(), Response> {
+        let Some(limiter) = self.limiter(Scope::Api).filter(|_| is_api(parts)) else {
+            return Ok(());
+        };
+        let key = match token_id {
+            Some(id) => format!("token:{id}"),
+            None => self.client_key(&parts.headers, &parts.extensions),
+        };
+        limiter
+            .check(&key, Instant::now())
+            .map_err(|retry_after| too_many_requests(Scope::Api, parts.uri.path(), retry_after))
+    }
+}
+
+fn is_api(parts: &Parts) -> bool {
+    Scope::of(parts.uri.path()) == Some(Scope::Api)
+}
+
+/// `429 Too Many Requests` for a request to `path`, counted and logged.
+fn too_many_requests(scope: Scope, path: &str, retry_after: Duration) -> Response {
+    crate::metrics::rate_limited(scope.as_str());
+    tracing::warn!(scope = scope.as_str(), path, "Request rate limited");
+    let secs = (retry_after.as_secs_f64().ceil() as u64).max(1);
+    let mut response = StatusCode::TOO_MANY_REQUESTS.into_response();
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+    response
+}
+
+/// Middleware enforcing [`RateLimits`] on the API and webhook routes. API
+/// requests with a token are left to [`RateLimits::before_token`] and
+/// [`RateLimits::after_token`].
+pub async fn enforce(
+    State(limits): State<Arc<RateLimits>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(scope) = Scope::of(request.uri().path()) else {
+        return next.run(request).await;
+    };
+    let Some(limiter) = limits.limiter(scope) else {
+        return next.run(request).await;
+    };
+    if scope == Scope::Api && auth::bearer_token(request.headers()).is_some() {
+        return next.run(request).await;
+    }
+
+    let key = limits.client_key(request.headers(), request.extensions());
+    match limiter.check(&key, Instant::now()) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => too_many_requests(scope, request.uri().path(), retry_after),
+    }
+}
@@ -1,15 +1,49 @@
-//! GitHub webhook handler — receives push/PR events, creates builds.
+//! GitHub, Gitea and generic webhook handlers — receive push/PR events,
+//! create builds.
+//!
+//! Both forges send near-identical push and pull request payloads; events
+//! only match projects whose `provider` is the forge they came from.
+//!
+//! A push whose head commit message contains one of `CI_SKIP_PATTERNS`
+//! (`[skip ci]` and `[ci skip]` by default) is recorded as a `skipped`
+//! build instead of being queued, as is an event the project's triggers
+//! filter out by path (see [`trigger_service`]). Events on branches the
+//! triggers don't cover are ignored. Pushes build their head commit, or
+//! every commit they carry for projects with `build_all_commits`.
+//!
+//! Pull requests build when opened, pushed to, reopened or marked ready for
+//! review, and when labeled with a label one of their triggers requires.
+//!
+//! Closing a pull request cancels its pending and running builds and
+//! destroys its environments.
+//!
+//! Events from repos without a project are ignored, unless
+//! `CI_AUTO_ONBOARD_TENANT` is set; then the repo is registered first (see
+//! [`project_service::onboard`]).
+//!
+//! The generic webhook lets other systems (cron jobs, artifact registries,
+//! internal tools) start builds with a project-scoped API token instead of
+//! a forge signature. It builds like a push, with `generic` triggers.
 
 use std::sync::Arc;
 
 use axum::body::Bytes;
 use axum::http::{HeaderMap, StatusCode};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection};
+use serde::{Deserialize, Serialize};
 
 use erp_core::db::diesel_pool::DieselPool;
 
 use crate::config::CiConfig;
-use crate::models::build::NewCiBuild;
-use crate::services::{build_service, github_service, project_service};
+use crate::models::build::{BuildStatus, CiBuild, NewCiBuild};
+use crate::models::project::{CiProject, Provider};
+use crate::services::outbox_service::{self, OutboxMessage};
+use crate::services::quota_service::QuotaExceeded;
+use crate::services::trigger_service::{self, Selection};
+use crate::services::{
+    build_service, environment_service, gitea_service, github_service, project_service,
+};
 
 /// Handle an incoming GitHub webhook payload.
 pub async fn handle_webhook(
@@ -39,8 +73,8 @@ pub async fn handle_webhook(
         serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
 
     match event_type {
-        "push" => handle_push(config, pool, &payload).await,
-        "pull_request" => handle_pull_request(config, pool, &payload).await,
+        "push" => handle_push(config, pool, Provider::Github, &payload).await,
+        "pull_request" => handle_pull_request(config, pool, Provider::Github, &payload).await,
         "ping" => {
             tracing::info!("Received GitHub ping webhook");
             Ok(StatusCode::OK)
@@ -52,9 +86,52 @@ pub async fn handle_webhook(
     }
 }
 
+/// Handle an incoming Gitea or Forgejo webhook payload.
+pub async fn handle_gitea_webhook(
+    config: &CiConfig,
+    pool: &Arc<DieselPool>,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, StatusCode> {
+    if config.gitea_url.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    // Forgejo also sends the X-Gitea-* headers, but may drop them one day.
+    let header = |gitea: &str, forgejo: &str| {
+        headers
+            .get(gitea)
+            .or_else(|| headers.get(forgejo))
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+    };
+
+    let signature = header("x-gitea-signature", "x-forgejo-signature");
+    if !github_service::validate_signature(&config.gitea_webhook_secret, &body, signature) {
+        tracing::warn!("Gitea webhook signature validation failed");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let event_type = header("x-gitea-event", "x-forgejo-event");
+    let payload: serde_json::Value =
+        serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    match event_type {
+        "push" => handle_push(config, pool, Provider::Gitea, &payload).await,
+        "pull_request" | "pull_request_label" => {
+            handle_pull_request(config, pool, Provider::Gitea, &payload).await
+        }
+        _ => {
+            tracing::debug!("Ignoring Gitea webhook event: {}", event_type);
+            Ok(StatusCode::OK)
+        }
+    }
+}
+
 async fn handle_push(
     config: &CiConfig,
     pool: &Arc<DieselPool>,
+    provider: Provider,
     payload: &serde_json::Value,
 ) -> Result<StatusCode, StatusCode> {
     let repo_full_name = payload["repository"]["full_name"]
@@ -66,24 +143,30 @@ async fn handle_push(
         .unwrap_or_default()
         .strip_prefix("refs/heads/")
         .unwrap_or_default();
-    let author = payload["pusher"]["name"].as_str().unwrap_or_default();
-    let message = payload["head_commit"]["message"]
+    // GitHub names the pusher `name`, Gitea `login`.
+    let author = payload["pusher"]["name"]
         .as_str()
-        .map(|s| s.to_string());
+        .or_else(|| payload["pusher"]["login"].as_str())
+        .unwrap_or_default();
 
     if commit_sha.is_empty() || branch.is_empty() {
         return Ok(StatusCode::OK);
     }
 
-    let mut conn = pool
-        .get()
+    let mut conn = crate::db::system_conn(pool)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     // Find project by repo
-    let project = project_service::find_by_repo(&mut conn, repo_full_name)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let project = find_or_onboard(
+        &mut conn,
+        config,
+        provider,
+        payload,
+        repo_full_name,
+        commit_sha,
+    )
+    .await?;
 
     let project = match project {
         Some(p) => p,
@@ -93,68 +176,109 @@ async fn handle_push(
         }
     };
 
-    // Compute fingerprint for dedup
-    let fingerprint = format!("{}-{}-push", commit_sha, branch);
-
-    // Check throttle
-    if build_service::is_duplicate(&mut conn, &fingerprint, config.throttle_window_secs)
+    let triggers = trigger_service::for_event(&mut conn, project.id, "push")
         .await
-        .unwrap_or(false)
-    {
-        tracing::info!("Duplicate build throttled: {}", fingerprint);
-        return Ok(StatusCode::OK);
-    }
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    // Create build
-    let new_build = NewCiBuild {
-        tenant_id: project.tenant_id,
-        project_id: project.id,
-        commit_sha: commit_sha.to_string(),
-        branch: branch.to_string(),
-        pr_number: None,
-        author: Some(author.to_string()),
-        message,
-        fingerprint,
-        trigger_event: "push".to_string(),
-        status: "pending".to_string(),
-    };
+    let commits = push_commits(payload, project.build_all_commits, config.max_push_commits);
+    let mut created = false;
+    for commit in commits {
+        // Compute fingerprint for dedup
+        let fingerprint = format!("{}-{}-push", commit.sha, branch);
 
-    match build_service::create_build(&mut conn, new_build).await {
-        Ok(build) => {
-            tracing::info!(
-                build_id = build.id,
-                branch = branch,
-                "Build created from push webhook"
-            );
-
-            // Post pending status to GitHub
-            let _ = github_service::post_status(
-                &config.github_token,
-                repo_full_name,
-                commit_sha,
-                "pending",
-                "Build queued",
-                &format!("{}/ci/api/builds/{}", config.dashboard_url, build.id),
-                "centrix-ci",
-            )
-            .await;
+        // Check throttle
+        if build_service::is_duplicate(&mut conn, &fingerprint, config.throttle_window_secs)
+            .await
+            .unwrap_or(false)
+        {
+            tracing::info!("Duplicate build throttled: {}", fingerprint);
+            continue;
+        }
 
-            Ok(StatusCode::CREATED)
+        let selection = trigger_service::select(&triggers, branch, commit.changed.as_deref());
+        if let Selection::Unmatched = selection {
+            tracing::debug!("No push trigger covers branch {}", branch);
+            return Ok(StatusCode::OK);
         }
-        Err(e) => {
-            tracing::error!("Failed to create build: {}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+
+        let mut skip = commit
+            .message
+            .as_deref()
+            .and_then(|m| skip_directive(m, &config.skip_ci_patterns))
+            .map(|pattern| format!("commit message contains {pattern}"));
+        let trigger_id = trigger_id(selection, &mut skip);
+
+        // Create build
+        let new_build = NewCiBuild {
+            tenant_id: project.tenant_id,
+            project_id: project.id,
+            commit_sha: commit.sha.to_string(),
+            branch: branch.to_string(),
+            pr_number: None,
+            author: Some(author.to_string()),
+            message: commit.message,
+            fingerprint,
+            trigger_event: "push".to_string(),
+            status: BuildStatus::Pending.as_str().to_string(),
+            trigger_id,
+            trusted: true,
+        };
+
+        if let Some(reason) = skip {
+            record_skipped(&mut conn, new_build, &reason).await?;
+            continue;
+        }
+
+        match enqueue_build(&mut conn, config, provider, repo_full_name, new_build).await {
+            Ok(build) => {
+                tracing::info!(
+                    build_id = build.id,
+                    branch = branch,
+                    "Build created from push webhook"
+                );
+                created = true;
+            }
+            Err(e) if e.is::<QuotaExceeded>() => {
+                tracing::warn!("Build not created: {}", e);
+                break;
+            }
+            Err(e) => {
+                tracing::error!("Failed to create build: {}", e);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
         }
     }
+
+    Ok(if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    })
 }
 
 async fn handle_pull_request(
     config: &CiConfig,
     pool: &Arc<DieselPool>,
+    provider: Provider,
     payload: &serde_json::Value,
 ) -> Result<StatusCode, StatusCode> {
+    // Gitea says `synchronized` where GitHub says `synchronize`, and
+    // `label_updated` for any change to the labels.
     let action = payload["action"].as_str().unwrap_or_default();
-    if action != "opened" && action != "synchronize" && action != "reopened" {
+    if action == "closed" {
+        return handle_pull_request_closed(config, pool, provider, payload).await;
+    }
+    let relabeled = ["labeled", "label_updated"].contains(&action);
+    if !relabeled
+        && ![
+            "opened",
+            "synchronize",
+            "synchronized",
+            "reopened",
+            "ready_for_review",
+        ]
+        .contains(&action)
+    {
         return Ok(StatusCode::OK);
     }
 
@@ -171,19 +295,31 @@ async fn handle_pull_request(
     let author = payload["pull_request"]["user"]["login"]
         .as_str()
         .unwrap_or_default();
+    let pr = trigger_service::PullRequest {
+        draft: payload["pull_request"]["draft"].as_bool().unwrap_or(false),
+        labels: payload["pull_request"]["labels"]
+            .as_array()
+            .map(|labels| labels.iter().filter_map(|l| l["name"].as_str()).collect())
+            .unwrap_or_default(),
+    };
 
     if commit_sha.is_empty() || branch.is_empty() {
         return Ok(StatusCode::OK);
     }
 
-    let mut conn = pool
-        .get()
+    let mut conn = crate::db::system_conn(pool)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let project = project_service::find_by_repo(&mut conn, repo_full_name)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let project = find_or_onboard(
+        &mut conn,
+        config,
+        provider,
+        payload,
+        repo_full_name,
+        commit_sha,
+    )
+    .await?;
 
     let project = match project {
         Some(p) => p,
@@ -199,6 +335,60 @@ async fn handle_pull_request(
         return Ok(StatusCode::OK);
     }
 
+    let triggers = trigger_service::for_event(&mut conn, project.id, "pull_request")
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !triggers.is_empty()
+        && trigger_service::for_branch(&triggers, branch)
+            .next()
+            .is_none()
+    {
+        tracing::debug!("No pull request trigger covers branch {}", branch);
+        return Ok(StatusCode::OK);
+    }
+    // Labels only start a build when a trigger requires them; GitHub names
+    // the label added, Gitea only reports that the labels changed.
+    if relabeled {
+        let added = payload["label"]["name"].as_str();
+        let wanted = trigger_service::for_branch(&triggers, branch).any(|t| {
+            t.labels
+                .iter()
+                .any(|l| added.map_or(pr.labels.contains(&l.as_str()), |a| a == l))
+        });
+        if !wanted {
+            return Ok(StatusCode::OK);
+        }
+    }
+    // Only ask the forge for the PR's files when a trigger filters on them.
+    let changed =
+        if trigger_service::for_branch(&triggers, branch).any(trigger_service::has_path_filters) {
+            let files = match provider {
+                Provider::Github => {
+                    config
+                        .github
+                        .pr_changed_files(repo_full_name, pr_number)
+                        .await
+                }
+                Provider::Gitea => {
+                    gitea_service::pr_changed_files(config, repo_full_name, pr_number).await
+                }
+            };
+            match files {
+                Ok(files) => Some(files),
+                Err(e) => {
+                    tracing::warn!("Changed files unknown, ignoring path filters: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+    let mut skip = None;
+    let selection =
+        trigger_service::select_pull_request(&triggers, branch, changed.as_deref(), &pr);
+    let trigger_id = trigger_id(selection, &mut skip);
+
     let new_build = NewCiBuild {
         tenant_id: project.tenant_id,
         project_id: project.id,
@@ -209,23 +399,20 @@ async fn handle_pull_request(
         message: None,
         fingerprint,
         trigger_event: "pull_request".to_string(),
-        status: "pending".to_string(),
+        status: BuildStatus::Pending.as_str().to_string(),
+        trigger_id,
+        trusted: pull_request_trusted(payload),
     };
 
-    match build_service::create_build(&mut conn, new_build).await {
-        Ok(build) => {
-            let _ = github_service::post_status(
-                &config.github_token,
-                repo_full_name,
-                commit_sha,
-                "pending",
-                "Build queued",
-                &format!("{}/ci/api/builds/{}", config.dashboard_url, build.id),
-                "centrix-ci",
-            )
-            .await;
+    if let Some(reason) = skip {
+        return record_skipped(&mut conn, new_build, &reason).await;
+    }
 
-            Ok(StatusCode::CREATED)
+    match enqueue_build(&mut conn, config, provider, repo_full_name, new_build).await {
+        Ok(_) => Ok(StatusCode::CREATED),
+        Err(e) if e.is::<QuotaExceeded>() => {
+            tracing::warn!("Build not created: {}", e);
+            Ok(StatusCode::OK)
         }
         Err(e) => {
             tracing::error!("Failed to create build from PR: {}", e);
@@ -233,3 +420,485 @@ async fn handle_pull_request(
         }
     }
 }
+
+/// Tear down what a closed (or merged) PR left behind: cancel its pending
+/// and running builds and destroy its environments with reason
+/// `pr_closed`.
+async fn handle_pull_request_closed(
+    config: &CiConfig,
+    pool: &Arc<DieselPool>,
+    provider: Provider,
+    payload: &serde_json::Value,
+) -> Result<StatusCode, StatusCode> {
+    let repo_full_name = payload["repository"]["full_name"]
+        .as_str()
+        .unwrap_or_default();
+    let pr_number = payload["number"].as_i64().unwrap_or(0) as i32;
+
+    let mut conn = crate::db::system_conn(pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let project = project_service::find_by_repo(&mut conn, provider, repo_full_name)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let Some(project) = project else {
+        return Ok(StatusCode::OK);
+    };
+
+    let builds = build_service::active_for_pr(&mut conn, project.id, pr_number)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    for build in &builds {
+        // A build may finish between the listing and the cancel.
+        match super::api::cancel_build(&mut conn, build, &config.dashboard_url).await {
+            Ok(_) => tracing::info!(build_id = build.id, pr_number, "Build cancelled: PR closed"),
+            Err(e) => tracing::debug!(build_id = build.id, "Build not cancelled: {}", e),
+        }
+    }
+
+    let environments = environment_service::list_for_pr(&mut conn, project.id, pr_number)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    for env in &environments {
+        match environment_service::destroy(&mut conn, env, "pr_closed").await {
+            Ok(true) => tracing::info!(
+                env_id = env.id,
+                pr_number,
+                "Environment destroyed: PR closed"
+            ),
+            Ok(false) => {}
+            Err(e) => {
+                tracing::error!("Failed to destroy environment {}: {}", env.id, e);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// Body of `POST /ci/webhook/generic`.
+#[derive(Debug, Deserialize)]
+pub struct GenericPayload {
+    /// Must name the token's project.
+    pub repo: String,
+    /// Defaults to the project's default branch.
+    pub branch: Option<String>,
+    /// Defaults to the branch head; no commit status is posted then.
+    pub sha: Option<String>,
+    pub author: Option<String>,
+    pub message: Option<String>,
+}
+
+/// Outcome of a generic webhook.
+#[derive(Debug, Serialize)]
+pub struct GenericResponse {
+    pub build_id: Option<i64>,
+    /// `pending` or `skipped` for a build; `throttled` or `ignored` without.
+    pub status: String,
+}
+
+impl GenericResponse {
+    fn without_build(status: &str) -> (StatusCode, Self) {
+        let response = GenericResponse {
+            build_id: None,
+            status: status.to_string(),
+        };
+        (StatusCode::OK, response)
+    }
+}
+
+/// Handle a generic webhook for `project_id`, the project the caller's
+/// token is scoped to.
+pub async fn handle_generic_webhook(
+    config: &CiConfig,
+    conn: &mut AsyncPgConnection,
+    project_id: i64,
+    payload: GenericPayload,
+) -> Result<(StatusCode, GenericResponse), StatusCode> {
+    let project = project_service::get_project(conn, project_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|p| p.active)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if payload.repo != project.github_repo {
+        tracing::warn!(project_id, repo = %payload.repo, "Generic webhook for another repo");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let branch = payload
+        .branch
+        .filter(|b| !b.is_empty())
+        .unwrap_or_else(|| project.default_branch.clone());
+    let commit_sha = payload.sha.filter(|s| !s.is_empty());
+    // Both end up in git's argv.
+    if !valid_branch(&branch) || commit_sha.as_deref().is_some_and(|s| !valid_sha(s)) {
+        tracing::warn!(project_id, "Generic webhook with an invalid branch or sha");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let fingerprint = format!(
+        "{}-{}-generic",
+        commit_sha.as_deref().unwrap_or("HEAD"),
+        branch
+    );
+
+    if commit_sha.is_some()
+        && build_service::is_duplicate(conn, &fingerprint, config.throttle_window_secs)
+            .await
+            .unwrap_or(false)
+    {
+        tracing::info!("Duplicate build throttled: {}", fingerprint);
+        return Ok(GenericResponse::without_build("throttled"));
+    }
+
+    let triggers = trigger_service::for_event(conn, project.id, "generic")
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let selection = trigger_service::select(&triggers, &branch, None);
+    if let Selection::Unmatched = selection {
+        tracing::debug!("No generic trigger covers branch {}", branch);
+        return Ok(GenericResponse::without_build("ignored"));
+    }
+
+    let mut skip = payload
+        .message
+        .as_deref()
+        .and_then(|m| skip_directive(m, &config.skip_ci_patterns))
+        .map(|pattern| format!("message contains {pattern}"));
+    let trigger_id = trigger_id(selection, &mut skip);
+
+    let new_build = NewCiBuild {
+        tenant_id: project.tenant_id,
+        project_id: project.id,
+        commit_sha: commit_sha.clone().unwrap_or_else(|| "HEAD".to_string()),
+        branch,
+        pr_number: None,
+        author: payload.author,
+        message: payload.message,
+        fingerprint,
+        trigger_event: "generic".to_string(),
+        status: BuildStatus::Pending.as_str().to_string(),
+        trigger_id,
+        trusted: true,
+    };
+
+    let result = match (skip, commit_sha) {
+        (Some(reason), _) => build_service::record_skipped(conn, new_build, &reason).await,
+        (None, Some(_)) => {
+            enqueue_build(
+                conn,
+                config,
+                project.provider(),
+                &project.github_repo,
+                new_build,
+            )
+            .await
+        }
+        (None, None) => build_service::create_build(conn, new_build).await,
+    };
+    match result {
+        Ok(build) => {
+            tracing::info!(build_id = build.id, "Build created from generic webhook");
+            let code = if build.status == BuildStatus::Skipped.as_str() {
+                StatusCode::OK
+            } else {
+                StatusCode::CREATED
+            };
+            let response = GenericResponse {
+                build_id: Some(build.id),
+                status: build.status,
+            };
+            Ok((code, response))
+        }
+        Err(e) if e.is::<QuotaExceeded>() => {
+            tracing::warn!("Build not created: {}", e);
+            Err(StatusCode::TOO_MANY_REQUESTS)
+        }
+        Err(e) => {
+            tracing::error!("Failed to create build from generic webhook: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Whether `sha` is an abbreviated or full commit id.
+fn valid_sha(sha: &str) -> bool {
+    (7..=40).contains(&sha.len()) && sha.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Whether `branch` is a branch name git accepts, short of looking it up.
+/// See `git check-ref-format`.
+fn valid_branch(branch: &str) -> bool {
+    !branch.starts_with(['-', '/', '.'])
+        && !branch.ends_with(['/', '.'])
+        && !branch.ends_with(".lock")
+        && !branch.contains("..")
+        && !branch.contains("//")
+        && !branch.contains("@{")
+        && !branch.contains("/.")
+        && branch != "@"
+        && !branch.chars().any(|c| {
+            c.is_ascii_control() || matches!(c, ' ' | '~' | '^' | ':' | '?' | '*' | '[' | '\\')
+        })
+}
+
+/// The project registered for `repo`, onboarding it if auto-onboarding is
+/// on. A failed onboarding is logged and the event ignored.
+async fn find_or_onboard(
+    conn: &mut AsyncPgConnection,
+    config: &CiConfig,
+    provider: Provider,
+    payload: &serde_json::Value,
+    repo: &str,
+    commit_sha: &str,
+) -> Result<Option<CiProject>, StatusCode> {
+    let project = project_service::find_by_repo(conn, provider, repo)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if project.is_some() || config.auto_onboard_tenant.is_none() {
+        return Ok(project);
+    }
+
+    let default_branch = payload["repository"]["default_branch"]
+        .as_str()
+        .unwrap_or("main");
+    match project_service::onboard(conn, config, provider, repo, default_branch, commit_sha).await {
+        Ok(project) => Ok(project),
+        Err(e) => {
+            tracing::warn!("Failed to onboard {}: {}", repo, e);
+            Ok(None)
+        }
+    }
+}
+
+/// A commit of a push to build.
+struct PushCommit<'a> {
+    sha: &'a str,
+    message: Option<String>,
+    /// Files the build's path filters are matched against; `None` if
+    /// unknown.
+    changed: Option<Vec<String>>,
+}
+
+/// The commits of a push to build: its head, or with `all` each commit it
+/// lists, oldest first, keeping the newest `cap`. The head is built alone
+/// when the payload lists no commits.
+fn push_commits(payload: &serde_json::Value, all: bool, cap: usize) -> Vec<PushCommit<'_>> {
+    let listed = payload["commits"].as_array().filter(|c| !c.is_empty());
+    let (Some(commits), true) = (listed, all) else {
+        return vec![PushCommit {
+            sha: payload["after"].as_str().unwrap_or_default(),
+            message: payload["head_commit"]["message"]
+                .as_str()
+                .map(|s| s.to_string()),
+            changed: listed.map(Vec::as_slice).map(changed_files),
+        }];
+    };
+
+    let dropped = commits.len().saturating_sub(cap);
+    if dropped > 0 {
+        tracing::info!(
+            dropped,
+            cap,
+            "Push has too many commits, building the newest"
+        );
+    }
+    commits[dropped..]
+        .iter()
+        .filter_map(|c| {
+            Some(PushCommit {
+                sha: c["id"].as_str().filter(|id| !id.is_empty())?,
+                message: c["message"].as_str().map(|s| s.to_string()),
+                changed: Some(changed_files(std::slice::from_ref(c))),
+            })
+        })
+        .collect()
+}
+
+/// Files added, modified or removed by `commits`.
+fn changed_files(commits: &[serde_json::Value]) -> Vec<String> {
+    let mut files: Vec<String> = commits
+        .iter()
+        .flat_map(|c| ["added", "modified", "removed"].map(|key| &c[key]))
+        .filter_map(|list| list.as_array())
+        .flatten()
+        .filter_map(|f| f.as_str())
+        .map(str::to_string)
+        .collect();
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// GitHub author associations of people with write access to the base
+/// repository.
+const TRUSTED_ASSOCIATIONS: [&str; 3] = ["OWNER", "MEMBER", "COLLABORATOR"];
+
+/// Whether a pull request's code may see the project's secrets: its branch
+/// is in the base repository, so its author can push there anyway, or
+/// GitHub reports the author as a member or collaborator. Fork pull
+/// requests of anyone else run untrusted until a maintainer approves them.
+fn pull_request_trusted(payload: &serde_json::Value) -> bool {
+    let pr = &payload["pull_request"];
+    let head_repo = pr["head"]["repo"]["full_name"].as_str();
+    let base_repo = pr["base"]["repo"]["full_name"]
+        .as_str()
+        .or(payload["repository"]["full_name"].as_str());
+    let same_repo = head_repo.is_some() && head_repo == base_repo;
+    same_repo
+        || pr["author_association"]
+            .as_str()
+            .is_some_and(|a| TRUSTED_ASSOCIATIONS.contains(&a))
+}
+
+/// The id of the trigger the event builds through, setting `skip` if the
+/// project's triggers filter these changes out.
+fn trigger_id(selection: Selection<'_>, skip: &mut Option<String>) -> Option<i64> {
+    match selection {
+        Selection::Untriggered | Selection::Unmatched => None,
+        Selection::Trigger(trigger) => Some(trigger.id),
+        Selection::Filtered => {
+            skip.get_or_insert_with(|| "no changed files match the trigger paths".to_string());
+            None
+        }
+        Selection::Excluded(reason) => {
+            skip.get_or_insert_with(|| reason.to_string());
+            None
+        }
+    }
+}
+
+async fn record_skipped(
+    conn: &mut AsyncPgConnection,
+    new_build: NewCiBuild,
+    reason: &str,
+) -> Result<StatusCode, StatusCode> {
+    match build_service::record_skipped(conn, new_build, reason).await {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(e) => {
+            tracing::error!("Failed to record skipped build: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// The first of `patterns` (already lowercase) found in a commit message.
+fn skip_directive<'a>(message: &str, patterns: &'a [String]) -> Option<&'a str> {
+    let message = message.to_lowercase();
+    patterns
+        .iter()
+        .find(|p| message.contains(p.as_str()))
+        .map(String::as_str)
+}
+
+/// Create a build and queue its "Build queued" commit status in one transaction.
+async fn enqueue_build(
+    conn: &mut AsyncPgConnection,
+    config: &CiConfig,
+    provider: Provider,
+    repo_full_name: &str,
+    new_build: NewCiBuild,
+) -> anyhow::Result<CiBuild> {
+    let build = conn
+        .transaction::<_, anyhow::Error, _>(|conn| {
+            async move {
+                let build = build_service::create_build(conn, new_build).await?;
+
+                outbox_service::enqueue(
+                    conn,
+                    build.tenant_id,
+                    &OutboxMessage::GithubStatus {
+                        repo: repo_full_name.to_string(),
+                        sha: build.commit_sha.clone(),
+                        state: "pending".to_string(),
+                        description: "Build queued".to_string(),
+                        target_url: format!("{}/ci/api/builds/{}", config.dashboard_url, build.id),
+                        context: "centrix-ci".to_string(),
+                        annotations: Vec::new(),
+                    }
+                    .for_provider(provider),
+                )
+                .await?;
+
+                Ok(build)
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    Ok(build)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pull_request(head: &str, association: Option<&str>) -> serde_json::Value {
+        serde_json::json!({
+            "repository": {"full_name": "acme/app"},
+            "pull_request": {
+                "head": {"ref": "main", "repo": {"full_name": head}},
+                "base": {"ref": "main", "repo": {"full_name": "acme/app"}},
+                "author_association": association,
+            },
+        })
+    }
+
+    #[test]
+    fn branch_pull_requests_are_trusted() {
+        assert!(pull_request_trusted(&pull_request("acme/app", None)));
+    }
+
+    #[test]
+    fn fork_pull_requests_need_write_access() {
+        for (association, trusted) in [
+            (None, false),
+            (Some("NONE"), false),
+            (Some("CONTRIBUTOR"), false),
+            (Some("MEMBER"), true),
+            (Some("COLLABORATOR"), true),
+        ] {
+            let payload = pull_request("mallory/app", association);
+            assert_eq!(pull_request_trusted(&payload), trusted, "{association:?}");
+        }
+    }
+
+    #[test]
+    fn generic_shas_are_hex_commit_ids() {
+        assert!(valid_sha("1a2b3c4"));
+        assert!(valid_sha(&"f".repeat(40)));
+        assert!(!valid_sha("1a2b3c"));
+        assert!(!valid_sha(&"f".repeat(41)));
+        assert!(!valid_sha("--upload-pack=x"));
+        assert!(!valid_sha("éééééééé"));
+    }
+
+    #[test]
+    fn generic_branches_are_ref_names() {
+        for branch in ["main", "release/1.2", "feature/a-b_c"] {
+            assert!(valid_branch(branch), "{branch}");
+        }
+        for branch in [
+            "-x",
+            "--upload-pack=evil",
+            "a..b",
+            "a b",
+            "a~1",
+            "topic.lock",
+            "/main",
+            "main/",
+            "a/.b",
+            "@",
+        ] {
+            assert!(!valid_branch(branch), "{branch}");
+        }
+    }
+
+    #[test]
+    fn deleted_forks_are_untrusted() {
+        let mut payload = pull_request("acme/app", None);
+        payload["pull_request"]["head"]["repo"] = serde_json::Value::Null;
+        assert!(!pull_request_trusted(&payload));
+    }
+}
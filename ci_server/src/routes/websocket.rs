@@ -1,3 +1,71 @@
-//! WebSocket log streaming for real-time build output.
+//! WebSocket feed of live build events.
 //!
-//! Placeholder — will be implemented when build execution is wired up.
+//! `GET /ws/events` upgrades to a WebSocket that receives every
+//! [`build_feed::FeedEvent`] of the caller's tenant (and project, for
+//! project-scoped tokens) as a JSON text message, e.g.
+//!
+//! ```json
+//! {"type": "step_finished", "build_id": 7, "project_id": 3, "step_id": 21,
+//!  "name": "test", "status": "success", "duration_ms": 5120}
+//! ```
+//!
+//! A client too slow to keep up gets `{"type": "lagged", "skipped": n}`
+//! in place of the events it missed, and should refetch what it shows.
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::Response;
+use tokio::sync::broadcast::error::RecvError;
+
+use super::auth::CiPrincipal;
+use super::CiRouterState;
+use crate::services::access_service::Perm;
+use crate::services::build_feed;
+
+/// Upgrade to the build event feed. Needs read access to builds.
+pub async fn events_handler(
+    State(state): State<CiRouterState>,
+    principal: CiPrincipal,
+    ws: WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    // Checked once, on connect; the connection is not held open.
+    drop(
+        state
+            .authorized_conn(&principal, "ci.build", Perm::Read)
+            .await?,
+    );
+    Ok(ws.on_upgrade(move |socket| stream_events(socket, principal)))
+}
+
+async fn stream_events(mut socket: WebSocket, principal: CiPrincipal) {
+    let mut feed = build_feed::subscribe();
+    loop {
+        tokio::select! {
+            received = feed.recv() => {
+                let text = match received {
+                    Ok(message) if message.tenant_id == principal.tenant_id
+                        && principal.can_access_project(message.event.project_id()) =>
+                    {
+                        serde_json::to_string(&message.event)
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(skipped)) => serde_json::to_string(
+                        &serde_json::json!({"type": "lagged", "skipped": skipped}),
+                    ),
+                    Err(RecvError::Closed) => break,
+                };
+                let Ok(text) = text else { continue };
+                if socket.send(Message::Text(text.into())).await.is_err() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => match incoming {
+                // Pings are answered by axum; clients have nothing to say.
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    tracing::debug!(uid = principal.uid, "Build feed client disconnected");
+}
@@ -1,7 +1,13 @@
 //! Diesel table definitions for generic CI platform.
 //!
 //! Tables: ci_projects, ci_triggers, ci_builds, ci_build_steps,
-//! ci_environments, ci_errors, ci_error_occurrences, ci_artifacts.
+//! ci_environments, ci_errors, ci_error_occurrences, ci_error_rules,
+//! ci_artifacts, ci_outbox,
+//! ci_events, ci_aggregate_snapshots, ci_tenant_quotas, ci_api_tokens,
+//! ci_command_policies, ci_secrets, ci_secret_access_log, ci_vulnerabilities,
+//! ci_project_permissions, ci_build_audit, ci_alert_rules,
+//! ci_notification_endpoints, ci_notification_deliveries,
+//! ci_notification_channels, ci_notification_rules, ci_test_results.
 //! All tables include tenant_id for multi-tenancy via RLS.
 
 diesel::table! {
@@ -12,11 +18,14 @@ diesel::table! {
         github_repo -> Varchar,
         default_branch -> Varchar,
         pipeline_config -> Nullable<Jsonb>,
+        visibility -> Varchar,
         active -> Bool,
         create_uid -> Nullable<Int8>,
         create_date -> Nullable<Timestamptz>,
         write_uid -> Nullable<Int8>,
         write_date -> Nullable<Timestamptz>,
+        provider -> Varchar,
+        build_all_commits -> Bool,
     }
 }
 
@@ -32,6 +41,13 @@ diesel::table! {
         create_date -> Nullable<Timestamptz>,
         write_uid -> Nullable<Int8>,
         write_date -> Nullable<Timestamptz>,
+        cron_expr -> Nullable<Varchar>,
+        last_fired_at -> Nullable<Timestamptz>,
+        env -> Nullable<Jsonb>,
+        paths -> Array<Text>,
+        paths_ignore -> Array<Text>,
+        skip_drafts -> Bool,
+        labels -> Array<Text>,
     }
 }
 
@@ -57,6 +73,10 @@ diesel::table! {
         create_date -> Nullable<Timestamptz>,
         write_uid -> Nullable<Int8>,
         write_date -> Nullable<Timestamptz>,
+        trace_context -> Nullable<Varchar>,
+        cancel_requested_at -> Nullable<Timestamptz>,
+        trigger_id -> Nullable<Int8>,
+        trusted -> Bool,
     }
 }
 
@@ -79,6 +99,8 @@ diesel::table! {
         create_date -> Nullable<Timestamptz>,
         write_uid -> Nullable<Int8>,
         write_date -> Nullable<Timestamptz>,
+        attempt -> Int4,
+        max_rss_bytes -> Nullable<Int8>,
     }
 }
 
@@ -100,6 +122,11 @@ diesel::table! {
         create_date -> Nullable<Timestamptz>,
         write_uid -> Nullable<Int8>,
         write_date -> Nullable<Timestamptz>,
+        destroyed_reason -> Nullable<Varchar>,
+        port -> Nullable<Int4>,
+        subdomain -> Nullable<Varchar>,
+        error -> Nullable<Text>,
+        restart_count -> Int4,
     }
 }
 
@@ -127,6 +154,9 @@ diesel::table! {
         create_date -> Nullable<Timestamptz>,
         write_uid -> Nullable<Int8>,
         write_date -> Nullable<Timestamptz>,
+        cluster_id -> Nullable<Int8>,
+        minhash -> Nullable<Array<Int8>>,
+        owners -> Nullable<Array<Text>>,
     }
 }
 
@@ -143,6 +173,26 @@ diesel::table! {
         create_date -> Nullable<Timestamptz>,
         write_uid -> Nullable<Int8>,
         write_date -> Nullable<Timestamptz>,
+        file_path -> Nullable<Varchar>,
+        line_number -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    ci_error_rules (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        project_id -> Nullable<Int8>,
+        pattern -> Nullable<Varchar>,
+        category -> Nullable<Varchar>,
+        reason -> Nullable<Text>,
+        hit_count -> Int4,
+        last_hit_at -> Nullable<Timestamptz>,
+        active -> Bool,
+        create_uid -> Nullable<Int8>,
+        create_date -> Nullable<Timestamptz>,
+        write_uid -> Nullable<Int8>,
+        write_date -> Nullable<Timestamptz>,
     }
 }
 
@@ -163,6 +213,292 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    ci_outbox (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        kind -> Varchar,
+        payload -> Jsonb,
+        status -> Varchar,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        last_error -> Nullable<Text>,
+        processed_at -> Nullable<Timestamptz>,
+        active -> Bool,
+        create_uid -> Nullable<Int8>,
+        create_date -> Nullable<Timestamptz>,
+        write_uid -> Nullable<Int8>,
+        write_date -> Nullable<Timestamptz>,
+        trace_context -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    ci_events (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        aggregate_type -> Varchar,
+        aggregate_id -> Int8,
+        sequence -> Int4,
+        payload -> Jsonb,
+        create_date -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    ci_aggregate_snapshots (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        aggregate_type -> Varchar,
+        aggregate_id -> Int8,
+        sequence -> Int4,
+        state -> Jsonb,
+        create_date -> Nullable<Timestamptz>,
+        write_date -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    ci_tenant_quotas (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        max_builds_per_day -> Nullable<Int4>,
+        max_concurrent_builds -> Nullable<Int4>,
+        max_environments -> Nullable<Int4>,
+        active -> Bool,
+        create_uid -> Nullable<Int8>,
+        create_date -> Nullable<Timestamptz>,
+        write_uid -> Nullable<Int8>,
+        write_date -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    ci_api_tokens (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        project_id -> Nullable<Int8>,
+        uid -> Int8,
+        name -> Varchar,
+        token_prefix -> Varchar,
+        token_hash -> Varchar,
+        expires_at -> Nullable<Timestamptz>,
+        last_used_at -> Nullable<Timestamptz>,
+        revoked_at -> Nullable<Timestamptz>,
+        rotated_to_id -> Nullable<Int8>,
+        active -> Bool,
+        create_uid -> Nullable<Int8>,
+        create_date -> Nullable<Timestamptz>,
+        write_uid -> Nullable<Int8>,
+        write_date -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    ci_command_policies (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        name -> Varchar,
+        allow_patterns -> Array<Text>,
+        deny_patterns -> Array<Text>,
+        banned_env_vars -> Array<Text>,
+        max_steps -> Nullable<Int4>,
+        active -> Bool,
+        create_uid -> Nullable<Int8>,
+        create_date -> Nullable<Timestamptz>,
+        write_uid -> Nullable<Int8>,
+        write_date -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    ci_secrets (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        project_id -> Nullable<Int8>,
+        name -> Varchar,
+        ciphertext -> Bytea,
+        nonce -> Bytea,
+        wrapped_key -> Bytea,
+        key_nonce -> Bytea,
+        master_key_id -> Varchar,
+        active -> Bool,
+        create_uid -> Nullable<Int8>,
+        create_date -> Nullable<Timestamptz>,
+        write_uid -> Nullable<Int8>,
+        write_date -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    ci_secret_access_log (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        secret_id -> Nullable<Int8>,
+        secret_name -> Varchar,
+        project_id -> Int8,
+        build_id -> Int8,
+        step_name -> Varchar,
+        create_date -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    ci_vulnerabilities (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        build_id -> Int8,
+        project_id -> Int8,
+        source -> Varchar,
+        advisory_id -> Varchar,
+        package -> Varchar,
+        version -> Nullable<Varchar>,
+        severity -> Varchar,
+        title -> Nullable<Text>,
+        active -> Bool,
+        create_uid -> Nullable<Int8>,
+        create_date -> Nullable<Timestamptz>,
+        write_uid -> Nullable<Int8>,
+        write_date -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    ci_project_permissions (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        project_id -> Int8,
+        action -> Varchar,
+        user_id -> Nullable<Int8>,
+        group_id -> Nullable<Int8>,
+        active -> Bool,
+        create_uid -> Nullable<Int8>,
+        create_date -> Nullable<Timestamptz>,
+        write_uid -> Nullable<Int8>,
+        write_date -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    ci_build_audit (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        project_id -> Int8,
+        build_id -> Nullable<Int8>,
+        action -> Varchar,
+        uid -> Int8,
+        token_id -> Nullable<Int8>,
+        create_date -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    ci_alert_rules (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        project_id -> Nullable<Int8>,
+        name -> Varchar,
+        kind -> Varchar,
+        threshold -> Float8,
+        window_minutes -> Int4,
+        min_builds -> Int4,
+        cooldown_minutes -> Int4,
+        webhook_url -> Nullable<Text>,
+        firing -> Bool,
+        last_fired_at -> Nullable<Timestamptz>,
+        active -> Bool,
+        create_uid -> Nullable<Int8>,
+        create_date -> Nullable<Timestamptz>,
+        write_uid -> Nullable<Int8>,
+        write_date -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    ci_notification_endpoints (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        project_id -> Nullable<Int8>,
+        url -> Text,
+        secret -> Varchar,
+        events -> Array<Text>,
+        active -> Bool,
+        create_uid -> Nullable<Int8>,
+        create_date -> Nullable<Timestamptz>,
+        write_uid -> Nullable<Int8>,
+        write_date -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    ci_notification_deliveries (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        endpoint_id -> Int8,
+        outbox_id -> Int8,
+        event -> Varchar,
+        attempt -> Int4,
+        status_code -> Nullable<Int4>,
+        error -> Nullable<Text>,
+        duration_ms -> Int4,
+        create_date -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    ci_notification_channels (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        project_id -> Nullable<Int8>,
+        name -> Varchar,
+        kind -> Varchar,
+        webhook_url -> Text,
+        events -> Array<Text>,
+        active -> Bool,
+        create_uid -> Nullable<Int8>,
+        create_date -> Nullable<Timestamptz>,
+        write_uid -> Nullable<Int8>,
+        write_date -> Nullable<Timestamptz>,
+        digest -> Nullable<Varchar>,
+        last_digest_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    ci_notification_rules (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        project_id -> Nullable<Int8>,
+        channel_id -> Int8,
+        name -> Varchar,
+        branch_pattern -> Nullable<Varchar>,
+        events -> Array<Text>,
+        throttle_minutes -> Int4,
+        last_notified_at -> Nullable<Timestamptz>,
+        active -> Bool,
+        create_uid -> Nullable<Int8>,
+        create_date -> Nullable<Timestamptz>,
+        write_uid -> Nullable<Int8>,
+        write_date -> Nullable<Timestamptz>,
+        on_transition -> Bool,
+    }
+}
+
+diesel::table! {
+    ci_test_results (id) {
+        id -> Int8,
+        tenant_id -> Uuid,
+        project_id -> Int8,
+        build_id -> Int8,
+        step_id -> Int8,
+        step_name -> Varchar,
+        name -> Text,
+        status -> Varchar,
+        duration_ms -> Nullable<Int4>,
+        create_date -> Nullable<Timestamptz>,
+    }
+}
+
 // Foreign key relationships
 diesel::joinable!(ci_triggers -> ci_projects (project_id));
 diesel::joinable!(ci_builds -> ci_projects (project_id));
@@ -173,6 +509,13 @@ diesel::joinable!(ci_errors -> ci_projects (project_id));
 diesel::joinable!(ci_error_occurrences -> ci_errors (error_id));
 diesel::joinable!(ci_error_occurrences -> ci_builds (build_id));
 diesel::joinable!(ci_artifacts -> ci_builds (build_id));
+diesel::joinable!(ci_api_tokens -> ci_projects (project_id));
+diesel::joinable!(ci_secrets -> ci_projects (project_id));
+diesel::joinable!(ci_secret_access_log -> ci_builds (build_id));
+diesel::joinable!(ci_notification_deliveries -> ci_notification_endpoints (endpoint_id));
+diesel::joinable!(ci_notification_rules -> ci_notification_channels (channel_id));
+diesel::joinable!(ci_test_results -> ci_builds (build_id));
+diesel::joinable!(ci_test_results -> ci_build_steps (step_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     ci_projects,
@@ -182,5 +525,23 @@ diesel::allow_tables_to_appear_in_same_query!(
     ci_environments,
     ci_errors,
     ci_error_occurrences,
+    ci_error_rules,
     ci_artifacts,
+    ci_outbox,
+    ci_events,
+    ci_aggregate_snapshots,
+    ci_tenant_quotas,
+    ci_api_tokens,
+    ci_command_policies,
+    ci_secrets,
+    ci_secret_access_log,
+    ci_vulnerabilities,
+    ci_project_permissions,
+    ci_build_audit,
+    ci_alert_rules,
+    ci_notification_endpoints,
+    ci_notification_deliveries,
+    ci_notification_channels,
+    ci_notification_rules,
+    ci_test_results,
 );
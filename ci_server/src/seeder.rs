@@ -8,6 +8,9 @@ use diesel_async::RunQueryDsl;
 ///
 /// Idempotent — uses ON CONFLICT DO NOTHING.
 pub async fn seed_ci_module(conn: &mut AsyncPgConnection) -> anyhow::Result<()> {
+    // Roles are kept in sync on every start, independent of the guard below.
+    seed_ci_roles(conn).await?;
+
     // Guard: skip if CI data already seeded (check for root menu)
     #[derive(diesel::QueryableByName)]
     struct CountRow {
@@ -88,6 +91,7 @@ pub async fn seed_ci_module(conn: &mut AsyncPgConnection) -> anyhow::Result<()>
           <field name=\"name\"/>\
           <field name=\"github_repo\"/>\
           <field name=\"default_branch\"/>\
+          <field name=\"visibility\"/>\
           <field name=\"active\"/>\
           </list>",
          "ci.view_project_list"),
@@ -100,6 +104,7 @@ pub async fn seed_ci_module(conn: &mut AsyncPgConnection) -> anyhow::Result<()>
               <group>\
                 <field name=\"github_repo\"/>\
                 <field name=\"default_branch\"/>\
+                <field name=\"visibility\"/>\
               </group>\
               <group>\
                 <field name=\"active\"/>\
@@ -193,11 +198,14 @@ pub async fn seed_ci_module(conn: &mut AsyncPgConnection) -> anyhow::Result<()>
           <field name=\"status\"/>\
           <field name=\"url\"/>\
           <field name=\"pr_number\"/>\
+          <field name=\"error\"/>\
+          <field name=\"restart_count\"/>\
           <templates>\
             <t t-name=\"kanban-card\">\
               <div class=\"oe_kanban_card\">\
                 <strong>PR #<field name=\"pr_number\"/></strong>\
                 <div><field name=\"branch\"/></div>\
+                <div t-if=\"record.status.raw_value == 'unhealthy'\" class=\"text-danger\"><field name=\"error\"/> (restarts: <field name=\"restart_count\"/>)</div>\
               </div>\
             </t>\
           </templates>\
@@ -205,13 +213,14 @@ pub async fn seed_ci_module(conn: &mut AsyncPgConnection) -> anyhow::Result<()>
          "ci.view_environment_kanban"),
 
         ("ci.environment.list", "ci.environment", "list",
-         "<list string=\"CI Environments\" default_order=\"id desc\" decoration-success=\"status == 'running'\" decoration-warning=\"status == 'dormant'\">\
+         "<list string=\"CI Environments\" default_order=\"id desc\" decoration-success=\"status == 'running'\" decoration-warning=\"status == 'dormant'\" decoration-danger=\"status == 'failed' or status == 'unhealthy'\">\
           <field name=\"id\"/>\
           <field name=\"project_id\"/>\
           <field name=\"pr_number\"/>\
           <field name=\"branch\"/>\
           <field name=\"status\"/>\
           <field name=\"url\" widget=\"url\"/>\
+          <field name=\"restart_count\"/>\
           <field name=\"create_date\"/>\
           </list>",
          "ci.view_environment_list"),
@@ -500,3 +509,211 @@ pub async fn seed_ci_module(conn: &mut AsyncPgConnection) -> anyhow::Result<()>
 
     Ok(())
 }
+
+/// CI roles, lowest first. Each role is a `res_groups` row (xml id
+/// `ci.<name>`), so SSO group mappings can grant it.
+pub const CI_ROLES: &[(&str, &str)] = &[
+    ("group_ci_viewer", "CI / Viewer"),
+    ("group_ci_developer", "CI / Developer"),
+    ("group_ci_maintainer", "CI / Maintainer"),
+    ("group_ci_admin", "CI / Admin"),
+];
+
+/// Model rights granted from a role up: (lowest role index, model, read,
+/// write, create, unlink).
+const CI_GRANTS: &[(usize, &str, bool, bool, bool, bool)] = &[
+    (0, "ci.project", true, false, false, false),
+    (0, "ci.trigger", true, false, false, false),
+    (0, "ci.build", true, false, false, false),
+    (0, "ci.build.step", true, false, false, false),
+    (0, "ci.environment", true, false, false, false),
+    (0, "ci.error", true, false, false, false),
+    (0, "ci.error.occurrence", true, false, false, false),
+    (0, "ci.error.rule", true, false, false, false),
+    (0, "ci.artifact", true, false, false, false),
+    (0, "ci.vulnerability", true, false, false, false),
+    (1, "ci.build", true, true, true, false),
+    (1, "ci.environment", true, true, true, false),
+    (1, "ci.error", true, true, false, false),
+    (1, "ci.artifact", true, false, true, false),
+    (2, "ci.project", true, true, false, false),
+    (2, "ci.trigger", true, true, true, true),
+    (2, "ci.environment", true, true, true, true),
+    (2, "ci.secret", true, true, true, true),
+    (2, "ci.alert.rule", true, true, true, true),
+    (2, "ci.error.rule", true, true, true, true),
+    (2, "ci.notification.endpoint", true, true, true, true),
+    (2, "ci.notification.channel", true, true, true, true),
+    (2, "ci.notification.rule", true, true, true, true),
+    (3, "ci.project", true, true, true, true),
+    (3, "ci.build", true, true, true, true),
+    (3, "ci.build.step", true, true, true, true),
+    (3, "ci.artifact", true, true, true, true),
+    (3, "ci.api.token", true, true, true, true),
+    (3, "ci.vulnerability", true, true, true, true),
+    (3, "ci.command.policy", true, true, true, true),
+];
+
+/// One `ir_model_access` row per model and role: (model, role xml id,
+/// [read, write, create, unlink]), each role holding the union of its own
+/// grants and those of the roles below it.
+pub fn ci_role_rights() -> Vec<(&'static str, &'static str, [bool; 4])> {
+    let mut rights: std::collections::BTreeMap<(&'static str, usize), [bool; 4]> = std::collections::BTreeMap::new();
+    for &(min_role, model, r, w, c, u) in CI_GRANTS {
+        for role in min_role..CI_ROLES.len() {
+            let perms = rights.entry((model, role)).or_default();
+            for (perm, granted) in perms.iter_mut().zip([r, w, c, u]) {
+                *perm |= granted;
+            }
+        }
+    }
+    rights.into_iter().map(|((model, role), perms)| (model, CI_ROLES[role].0, perms)).collect()
+}
+
+/// Seed the CI role groups and their model access rights.
+///
+/// Idempotent and run on every start, so roles added after the initial seed
+/// reach existing installs. Rights are cumulative (a role's row for a model
+/// also holds the rights of the roles below it), so membership in one group
+/// is enough:
+///
+/// - viewer: read builds, steps, logs, environments, projects
+/// - developer: trigger, cancel, and retry builds; manage environments
+/// - maintainer: edit pipelines and triggers, manage secrets, approve gates
+/// - admin: everything, including API tokens and command policies
+pub async fn seed_ci_roles(conn: &mut AsyncPgConnection) -> anyhow::Result<()> {
+    // Models that only exist for access control on the CI API.
+    let models: Vec<(i64, &str, &str, &str, &str)> = vec![
+        (58, "CI Secret", "ci.secret", "ci_secrets", "Encrypted build secrets"),
+        (59, "CI API Token", "ci.api.token", "ci_api_tokens", "Project-scoped API tokens"),
+        (60, "CI Vulnerability", "ci.vulnerability", "ci_vulnerabilities", "Dependency audit findings"),
+        (61, "CI Alert Rule", "ci.alert.rule", "ci_alert_rules", "Threshold alert rules"),
+        (62, "CI Notification Endpoint", "ci.notification.endpoint", "ci_notification_endpoints", "Outbound event webhooks"),
+        (63, "CI Error Rule", "ci.error.rule", "ci_error_rules", "Error mute rules"),
+        (64, "CI Notification Channel", "ci.notification.channel", "ci_notification_channels", "Chat webhook notifiers"),
+        (65, "CI Notification Rule", "ci.notification.rule", "ci_notification_rules", "Build notification routing rules"),
+        (66, "CI Command Policy", "ci.command.policy", "ci_command_policies", "Pipeline command policies"),
+    ];
+    for (id, name, model, table, info_text) in &models {
+        diesel::sql_query(format!(
+            "INSERT INTO ir_model (id, name, model, table_name, info, state, transient, create_date) \
+             VALUES ({id}, '{name}', '{model}', '{table}', '{info_text}', 'base', false, NOW()) \
+             ON CONFLICT (id) DO NOTHING"
+        ))
+        .execute(conn)
+        .await?;
+    }
+    diesel::sql_query("SELECT setval('ir_model_id_seq', GREATEST((SELECT COALESCE(MAX(id), 0) FROM ir_model), 66))")
+        .execute(conn)
+        .await?;
+
+    for (xml_id, label) in CI_ROLES {
+        diesel::sql_query(format!(
+            "WITH ins AS ( \
+                 INSERT INTO res_groups (name, create_date) \
+                 SELECT '{label}', NOW() \
+                 WHERE NOT EXISTS ( \
+                     SELECT 1 FROM ir_model_data WHERE module = 'ci' AND name = '{xml_id}' \
+                 ) \
+                 RETURNING id \
+             ) \
+             INSERT INTO ir_model_data (name, module, model, res_id, noupdate, create_date) \
+             SELECT '{xml_id}', 'ci', 'res.groups', id, true, NOW() FROM ins"
+        ))
+        .execute(conn)
+        .await?;
+    }
+
+    let mut count = 0;
+    for (model, xml_id, [r, w, c, u]) in ci_role_rights() {
+        let name = format!(
+            "access_{}_{}",
+            model.replace('.', "_"),
+            xml_id.trim_start_matches("group_")
+        );
+        // Installs seeded before rights were merged per role carry only the
+        // lowest role's flags under this name: widen them.
+        diesel::sql_query(format!(
+            "UPDATE ir_model_access SET perm_read = perm_read OR {r}, perm_write = perm_write OR {w}, \
+             perm_create = perm_create OR {c}, perm_unlink = perm_unlink OR {u} \
+             WHERE name = '{name}'"
+        ))
+        .execute(conn)
+        .await?;
+        diesel::sql_query(format!(
+            "INSERT INTO ir_model_access (name, model_id, group_id, perm_read, perm_write, perm_create, perm_unlink, active, create_date) \
+             SELECT '{name}', m.id, d.res_id, {r}, {w}, {c}, {u}, true, NOW() \
+             FROM ir_model m, ir_model_data d \
+             WHERE m.model = '{model}' AND d.module = 'ci' AND d.name = '{xml_id}' \
+               AND NOT EXISTS (SELECT 1 FROM ir_model_access WHERE name = '{name}')"
+        ))
+        .execute(conn)
+        .await?;
+        count += 1;
+    }
+
+    tracing::info!("CI roles seeded: {} roles, {} access rights", CI_ROLES.len(), count);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rights(model: &str, role: &str) -> [bool; 4] {
+        ci_role_rights()
+            .into_iter()
+            .find(|(m, r, _)| *m == model && *r == role)
+            .map(|(_, _, perms)| perms)
+            .unwrap_or_else(|| panic!("no access row for {model} / {role}"))
+    }
+
+    #[test]
+    fn one_row_per_model_and_role() {
+        let rows = ci_role_rights();
+        let mut names: Vec<_> = rows.iter().map(|(model, role, _)| (*model, *role)).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), rows.len());
+    }
+
+    #[test]
+    fn higher_roles_keep_their_own_grants() {
+        assert_eq!(rights("ci.build", "group_ci_viewer"), [true, false, false, false]);
+        assert_eq!(rights("ci.build", "group_ci_developer"), [true, true, true, false]);
+        for model in ["ci.project", "ci.trigger", "ci.environment", "ci.error.rule"] {
+            assert!(rights(model, "group_ci_maintainer")[1], "maintainer cannot write {model}");
+        }
+        for model in ["ci.project", "ci.build", "ci.build.step", "ci.artifact", "ci.vulnerability", "ci.command.policy"] {
+            assert_eq!(rights(model, "group_ci_admin"), [true; 4], "admin lacks rights on {model}");
+        }
+    }
+
+    #[test]
+    fn rights_are_cumulative() {
+        let rows = ci_role_rights();
+        for (model, role, perms) in &rows {
+            let index = CI_ROLES.iter().position(|(xml_id, _)| xml_id == role).unwrap();
+            for (above, _) in &CI_ROLES[index + 1..] {
+                let higher = rights(model, above);
+                assert!(perms.iter().zip(higher).all(|(low, high)| !low || high), "{above} has less than {role} on {model}");
+            }
+        }
+    }
+
+    #[test]
+    fn only_admins_manage_command_policies() {
+        let rows = ci_role_rights();
+        let roles: Vec<_> = rows.iter().filter(|(model, _, _)| *model == "ci.command.policy").map(|(_, role, _)| *role).collect();
+        assert_eq!(roles, ["group_ci_admin"]);
+    }
+
+    #[test]
+    fn viewer_only_reads() {
+        for (_, role, [read, write, create, unlink]) in ci_role_rights() {
+            if role == "group_ci_viewer" {
+                assert!(read && !write && !create && !unlink);
+            }
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! Model access checks against the framework's `ir_model_access` table.
+//!
+//! The `/ci/api` handlers query CI tables directly with Diesel, so the ORM's
+//! access rules never run for them. [`check_access`] evaluates the same
+//! rules the seeder installs: a user has a permission on a model if any
+//! active access row for that model grants it, either globally (no group)
+//! or to one of the user's groups.
+//!
+//! Manual build actions are additionally subject to per-project grants in
+//! `ci_project_permissions` ([`check_project_action`]), so deploy-bearing
+//! projects can be limited to named users or groups.
+
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Bool, Text};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use crate::models::project_permission::{CiProjectPermission, NewCiProjectPermission};
+use crate::schema::ci_project_permissions;
+
+/// A CRUD permission as stored in `ir_model_access`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Perm {
+    Read,
+    Write,
+    Create,
+    Unlink,
+}
+
+impl Perm {
+    fn column(self) -> &'static str {
+        match self {
+            Perm::Read => "perm_read",
+            Perm::Write => "perm_write",
+            Perm::Create => "perm_create",
+            Perm::Unlink => "perm_unlink",
+        }
+    }
+}
+
+impl std::fmt::Display for Perm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.column().trim_start_matches("perm_"))
+    }
+}
+
+/// Whether `uid` holds `perm` on `model` (e.g. `"ci.build"`).
+pub async fn check_access(
+    conn: &mut AsyncPgConnection,
+    uid: i64,
+    model: &str,
+    perm: Perm,
+) -> anyhow::Result<bool> {
+    #[derive(diesel::QueryableByName)]
+    struct Row {
+        #[diesel(sql_type = Bool)]
+        allowed: bool,
+    }
+
+    let row: Row = diesel::sql_query(format!(
+        "SELECT EXISTS ( \
+             SELECT 1 FROM ir_model_access a \
+             JOIN ir_model m ON m.id = a.model_id \
+             WHERE m.model = $1 \
+               AND a.active \
+               AND a.{} \
+               AND (a.group_id IS NULL \
+                    OR a.group_id IN (SELECT gid FROM res_groups_users_rel WHERE uid = $2)) \
+         ) AS allowed",
+        perm.column()
+    ))
+    .bind::<Text, _>(model)
+    .bind::<BigInt, _>(uid)
+    .get_result(conn)
+    .await?;
+
+    Ok(row.allowed)
+}
+
+/// Whether `uid` is a member of the CI admin role (`ci.group_ci_admin`),
+/// which may act on other users' records where others may only act on
+/// their own.
+pub async fn is_ci_admin(conn: &mut AsyncPgConnection, uid: i64) -> anyhow::Result<bool> {
+    #[derive(diesel::QueryableByName)]
+    struct Row {
+        #[diesel(sql_type = Bool)]
+        admin: bool,
+    }
+
+    let row: Row = diesel::sql_query(
+        "SELECT EXISTS ( \
+             SELECT 1 FROM res_groups_users_rel r \
+             JOIN ir_model_data d ON d.res_id = r.gid \
+             WHERE r.uid = $1 \
+               AND d.module = 'ci' AND d.model = 'res.groups' AND d.name = 'group_ci_admin' \
+         ) AS admin",
+    )
+    .bind::<BigInt, _>(uid)
+    .get_result(conn)
+    .await?;
+
+    Ok(row.admin)
+}
+
+/// A manual build action that can be restricted per project.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildAction {
+    Trigger,
+    Cancel,
+    Retry,
+    Approve,
+}
+
+impl BuildAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BuildAction::Trigger => "trigger",
+            BuildAction::Cancel => "cancel",
+            BuildAction::Retry => "retry",
+            BuildAction::Approve => "approve",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "trigger" => Some(BuildAction::Trigger),
+            "cancel" => Some(BuildAction::Cancel),
+            "retry" => Some(BuildAction::Retry),
+            "approve" => Some(BuildAction::Approve),
+            _ => None,
+        }
+    }
+
+    /// The model permission the action needs before any project grant is
+    /// considered.
+    pub fn model_perm(self) -> (&'static str, Perm) {
+        match self {
+            BuildAction::Trigger | BuildAction::Retry => ("ci.build", Perm::Create),
+            BuildAction::Cancel => ("ci.build", Perm::Write),
+            BuildAction::Approve => ("ci.project", Perm::Write),
+        }
+    }
+}
+
+impl std::fmt::Display for BuildAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Whether `uid` may take `action` on `project_id` under the project's own
+/// grants. A project with no active grant for the action leaves it to the
+/// model permission; once one exists, only the granted users and members of
+/// the granted groups qualify.
+pub async fn check_project_action(
+    conn: &mut AsyncPgConnection,
+    uid: i64,
+    project_id: i64,
+    action: BuildAction,
+) -> anyhow::Result<bool> {
+    #[derive(diesel::QueryableByName)]
+    struct Row {
+        #[diesel(sql_type = Bool)]
+        allowed: bool,
+    }
+
+    let row: Row = diesel::sql_query(
+        "SELECT NOT EXISTS ( \
+             SELECT 1 FROM ci_project_permissions \
+             WHERE project_id = $1 AND action = $2 AND active \
+         ) OR EXISTS ( \
+             SELECT 1 FROM ci_project_permissions p \
+             WHERE p.project_id = $1 AND p.action = $2 AND p.active \
+               AND (p.user_id = $3 \
+                    OR p.group_id IN (SELECT gid FROM res_groups_users_rel WHERE uid = $3)) \
+         ) AS allowed",
+    )
+    .bind::<BigInt, _>(project_id)
+    .bind::<Text, _>(action.as_str())
+    .bind::<BigInt, _>(uid)
+    .get_result(conn)
+    .await?;
+
+    Ok(row.allowed)
+}
+
+/// Active grants of a project.
+pub async fn list_project_permissions(
+    conn: &mut AsyncPgConnection,
+    project_id: i64,
+) -> anyhow::Result<Vec<CiProjectPermission>> {
+    let rows = ci_project_permissions::table
+        .filter(ci_project_permissions::project_id.eq(project_id))
+        .filter(ci_project_permissions::active.eq(true))
+        .order(ci_project_permissions::id.asc())
+        .load(conn)
+        .await?;
+    Ok(rows)
+}
+
+/// Grant `action` on a project to a user or a group.
+pub async fn grant_project_permission(
+    conn: &mut AsyncPgConnection,
+    grant: NewCiProjectPermission,
+) -> anyhow::Result<CiProjectPermission> {
+    if grant.user_id.is_none() && grant.group_id.is_none() {
+        anyhow::bail!("a grant needs a user_id or a group_id");
+    }
+    let row = diesel::insert_into(ci_project_permissions::table)
+        .values(&grant)
+        .get_result(conn)
+        .await?;
+    Ok(row)
+}
+
+/// Remove a grant. Returns whether it existed on that project.
+pub async fn revoke_project_permission(
+    conn: &mut AsyncPgConnection,
+    project_id: i64,
+    permission_id: i64,
+    uid: i64,
+) -> anyhow::Result<bool> {
+    let updated = diesel::update(
+        ci_project_permissions::table
+            .filter(ci_project_permissions::id.eq(permission_id))
+            .filter(ci_project_permissions::project_id.eq(project_id))
+            .filter(ci_project_permissions::active.eq(true)),
+    )
+    .set((
+        ci_project_permissions::active.eq(false),
+        ci_project_permissions::write_uid.eq(Some(uid)),
+        ci_project_permissions::write_date.eq(Some(chrono::Utc::now())),
+    ))
+    .execute(conn)
+    .await?;
+    Ok(updated > 0)
+}
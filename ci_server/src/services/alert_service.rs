@@ -0,0 +1,244 @@
+//! Threshold alerting without an external Prometheus/Alertmanager.
+//!
+//! [`run_alert_evaluator`] checks the active `ci_alert_rules` every minute:
+//!
+//! - `failure_rate`: share of builds finished in the last `window_minutes`
+//!   that failed or timed out, in percent, once at least `min_builds`
+//!   finished
+//! - `queue_age`: age in seconds of the oldest pending build
+//!
+//! A rule crossing its threshold fires once, then again only after
+//! `cooldown_minutes`; recovering sends a resolved notice. Notifications are
+//! logged and, with a `webhook_url`, POSTed through the outbox.
+
+use std::sync::Arc;
+
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Double, Integer, Nullable, Uuid as SqlUuid};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use erp_core::db::diesel_pool::DieselPool;
+
+use crate::models::alert_rule::{CiAlertRule, NewCiAlertRule};
+use crate::schema::ci_alert_rules;
+use crate::services::outbox_service::{self, OutboxMessage};
+
+/// How often rules are evaluated.
+const EVAL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Rule kinds understood by the evaluator.
+pub const RULE_KINDS: &[&str] = &["failure_rate", "queue_age"];
+
+/// Run the evaluator forever. Spawned as a background tokio task.
+pub async fn run_alert_evaluator(pool: Arc<DieselPool>) {
+    tracing::info!("Alert evaluator started");
+
+    let mut interval = tokio::time::interval(EVAL_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = evaluate_all(&pool).await {
+            tracing::error!("Alert evaluation error: {e}");
+        }
+    }
+}
+
+async fn evaluate_all(pool: &DieselPool) -> anyhow::Result<()> {
+    let mut conn = crate::db::system_conn(pool).await?;
+
+    let rules: Vec<CiAlertRule> = ci_alert_rules::table
+        .filter(ci_alert_rules::active.eq(true))
+        .order(ci_alert_rules::id.asc())
+        .load(&mut conn)
+        .await?;
+
+    for rule in rules {
+        let Some(value) = measure(&mut conn, &rule).await? else {
+            continue;
+        };
+        let breached = value > rule.threshold;
+        let now = chrono::Utc::now();
+
+        if breached {
+            let cooled_down = rule.last_fired_at.is_none_or(|at| {
+                now - at >= chrono::Duration::minutes(rule.cooldown_minutes as i64)
+            });
+            if rule.firing && !cooled_down {
+                continue;
+            }
+            notify(&mut conn, &rule, "firing", value).await?;
+            diesel::update(ci_alert_rules::table.find(rule.id))
+                .set((
+                    ci_alert_rules::firing.eq(true),
+                    ci_alert_rules::last_fired_at.eq(Some(now)),
+                ))
+                .execute(&mut conn)
+                .await?;
+        } else if rule.firing {
+            notify(&mut conn, &rule, "resolved", value).await?;
+            diesel::update(ci_alert_rules::table.find(rule.id))
+                .set(ci_alert_rules::firing.eq(false))
+                .execute(&mut conn)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Current value of a rule's metric, or `None` when there is too little
+/// data to judge.
+async fn measure(conn: &mut AsyncPgConnection, rule: &CiAlertRule) -> anyhow::Result<Option<f64>> {
+    #[derive(diesel::QueryableByName)]
+    struct Row {
+        #[diesel(sql_type = Nullable<Double>)]
+        value: Option<f64>,
+        #[diesel(sql_type = BigInt)]
+        samples: i64,
+    }
+
+    match rule.kind.as_str() {
+        "failure_rate" => {
+            let row: Row = diesel::sql_query(
+                "SELECT 100.0 * COUNT(*) FILTER (WHERE status IN ('failure', 'timeout')) \
+                     / NULLIF(COUNT(*), 0) AS value, \
+                     COUNT(*) AS samples \
+                 FROM ci_builds \
+                 WHERE tenant_id = $1 AND ($2::BIGINT IS NULL OR project_id = $2) \
+                   AND status IN ('success', 'failure', 'timeout') \
+                   AND finished_at >= NOW() - make_interval(mins => $3)",
+            )
+            .bind::<SqlUuid, _>(rule.tenant_id)
+            .bind::<Nullable<BigInt>, _>(rule.project_id)
+            .bind::<Integer, _>(rule.window_minutes)
+            .get_result(conn)
+            .await?;
+            Ok(row
+                .value
+                .filter(|_| row.samples >= rule.min_builds.max(1) as i64))
+        }
+        "queue_age" => {
+            let row: Row = diesel::sql_query(
+                "SELECT EXTRACT(EPOCH FROM NOW() - MIN(create_date))::FLOAT8 AS value, \
+                     COUNT(*) AS samples \
+                 FROM ci_builds \
+                 WHERE tenant_id = $1 AND ($2::BIGINT IS NULL OR project_id = $2) \
+                   AND status = 'pending'",
+            )
+            .bind::<SqlUuid, _>(rule.tenant_id)
+            .bind::<Nullable<BigInt>, _>(rule.project_id)
+            .get_result(conn)
+            .await?;
+            // An empty queue is a healthy queue.
+            Ok(Some(row.value.unwrap_or(0.0)))
+        }
+        other => {
+            tracing::warn!(rule_id = rule.id, kind = other, "Unknown alert rule kind");
+            Ok(None)
+        }
+    }
+}
+
+async fn notify(
+    conn: &mut AsyncPgConnection,
+    rule: &CiAlertRule,
+    state: &str,
+    value: f64,
+) -> anyhow::Result<()> {
+    let summary = match rule.kind.as_str() {
+        "failure_rate" => format!(
+            "{:.1}% of builds failed in the last {} minutes (threshold {}%)",
+            value, rule.window_minutes, rule.threshold
+        ),
+        _ => format!(
+            "oldest pending build is {:.0}s old (threshold {}s)",
+            value, rule.threshold
+        ),
+    };
+    if state == "firing" {
+        tracing::error!(rule_id = rule.id, rule = %rule.name, "CI alert firing: {summary}");
+    } else {
+        tracing::info!(rule_id = rule.id, rule = %rule.name, "CI alert resolved: {summary}");
+    }
+
+    if let Some(ref url) = rule.webhook_url {
+        outbox_service::enqueue(
+            conn,
+            rule.tenant_id,
+            &OutboxMessage::AlertWebhook {
+                url: url.clone(),
+                payload: serde_json::json!({
+                    "rule_id": rule.id,
+                    "rule": rule.name,
+                    "kind": rule.kind,
+                    "state": state,
+                    "value": value,
+                    "threshold": rule.threshold,
+                    "project_id": rule.project_id,
+                    "summary": summary,
+                }),
+            },
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Active rules of the caller's tenant.
+pub async fn list_rules(conn: &mut AsyncPgConnection) -> anyhow::Result<Vec<CiAlertRule>> {
+    let rows = ci_alert_rules::table
+        .filter(ci_alert_rules::active.eq(true))
+        .order(ci_alert_rules::id.asc())
+        .load(conn)
+        .await?;
+    Ok(rows)
+}
+
+/// An active rule of the caller's tenant.
+pub async fn get_rule(
+    conn: &mut AsyncPgConnection,
+    rule_id: i64,
+) -> anyhow::Result<Option<CiAlertRule>> {
+    let row = ci_alert_rules::table
+        .find(rule_id)
+        .filter(ci_alert_rules::active.eq(true))
+        .first(conn)
+        .await
+        .optional()?;
+    Ok(row)
+}
+
+/// Create a rule after checking its kind.
+pub async fn create_rule(
+    conn: &mut AsyncPgConnection,
+    rule: NewCiAlertRule,
+) -> anyhow::Result<CiAlertRule> {
+    if !RULE_KINDS.contains(&rule.kind.as_str()) {
+        anyhow::bail!("unknown alert rule kind {:?}", rule.kind);
+    }
+    let row = diesel::insert_into(ci_alert_rules::table)
+        .values(&rule)
+        .get_result(conn)
+        .await?;
+    Ok(row)
+}
+
+/// Deactivate a rule. Returns whether it existed.
+pub async fn delete_rule(
+    conn: &mut AsyncPgConnection,
+    rule_id: i64,
+    uid: i64,
+) -> anyhow::Result<bool> {
+    let updated = diesel::update(
+        ci_alert_rules::table
+            .filter(ci_alert_rules::id.eq(rule_id))
+            .filter(ci_alert_rules::active.eq(true)),
+    )
+    .set((
+        ci_alert_rules::active.eq(false),
+        ci_alert_rules::write_uid.eq(Some(uid)),
+        ci_alert_rules::write_date.eq(Some(chrono::Utc::now())),
+    ))
+    .execute(conn)
+    .await?;
+    Ok(updated > 0)
+}
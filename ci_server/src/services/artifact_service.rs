@@ -5,6 +5,10 @@ use diesel_async::{AsyncPgConnection, RunQueryDsl};
 
 use crate::models::artifact::{CiArtifact, NewCiArtifact};
 use crate::schema::ci_artifacts;
+use crate::services::provenance_service::PROVENANCE_ARTIFACT_TYPE;
+
+/// Artifact type of uploads that do not name one.
+pub const DEFAULT_UPLOAD_TYPE: &str = "file";
 
 /// Store a build artifact.
 pub async fn store_artifact(
@@ -18,6 +22,57 @@ pub async fn store_artifact(
     Ok(result)
 }
 
+/// Store an artifact uploaded through the API. Uploads cannot pose as the
+/// build's signed provenance.
+pub async fn upload_artifact(
+    conn: &mut AsyncPgConnection,
+    new_artifact: NewCiArtifact,
+) -> anyhow::Result<CiArtifact> {
+    let name = new_artifact.name.trim();
+    if name.is_empty() || name.len() > 255 || name.contains(['/', '\\']) {
+        anyhow::bail!("invalid artifact name {:?}", new_artifact.name);
+    }
+    let artifact_type = new_artifact.artifact_type.as_str();
+    if artifact_type.is_empty() || artifact_type.len() > 32 {
+        anyhow::bail!("invalid artifact type {artifact_type:?}");
+    }
+    if artifact_type == PROVENANCE_ARTIFACT_TYPE {
+        anyhow::bail!("provenance artifacts are generated by the server");
+    }
+    store_artifact(conn, new_artifact).await
+}
+
+/// Get an active artifact by ID.
+pub async fn get_artifact(
+    conn: &mut AsyncPgConnection,
+    artifact_id: i64,
+) -> anyhow::Result<Option<CiArtifact>> {
+    let result = ci_artifacts::table
+        .find(artifact_id)
+        .filter(ci_artifacts::active.eq(true))
+        .first::<CiArtifact>(conn)
+        .await
+        .optional()?;
+    Ok(result)
+}
+
+/// `Content-Type` an artifact is served with, from its name's extension.
+pub fn content_type(artifact: &CiArtifact) -> &'static str {
+    let extension = artifact
+        .name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("md") => "text/markdown; charset=utf-8",
+        _ => "text/plain; charset=utf-8",
+    }
+}
+
 /// List artifacts for a build.
 pub async fn list_for_build(
     conn: &mut AsyncPgConnection,
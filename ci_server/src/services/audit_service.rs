@@ -0,0 +1,52 @@
+//! Audit trail of manual build actions.
+//!
+//! Every trigger, cancel, retry, and approval made through the API is
+//! recorded with the acting user and, for API tokens, the token used.
+
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use crate::models::build_audit::{CiBuildAudit, NewCiBuildAudit};
+use crate::schema::ci_build_audit;
+use crate::services::access_service::BuildAction;
+
+/// Record that `uid` took `action` on a project (and build, once known).
+pub async fn record_build_action(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+    project_id: i64,
+    build_id: Option<i64>,
+    action: BuildAction,
+    uid: i64,
+    token_id: Option<i64>,
+) -> anyhow::Result<()> {
+    diesel::insert_into(ci_build_audit::table)
+        .values(&NewCiBuildAudit {
+            tenant_id,
+            project_id,
+            build_id,
+            action: action.as_str().to_string(),
+            uid,
+            token_id,
+        })
+        .execute(conn)
+        .await?;
+
+    tracing::info!(project_id, build_id, uid, token_id, action = %action, "Build action audited");
+    Ok(())
+}
+
+/// Recent audit records of a project, newest first.
+pub async fn list_for_project(
+    conn: &mut AsyncPgConnection,
+    project_id: i64,
+    limit: i64,
+) -> anyhow::Result<Vec<CiBuildAudit>> {
+    let rows = ci_build_audit::table
+        .filter(ci_build_audit::project_id.eq(project_id))
+        .order(ci_build_audit::id.desc())
+        .limit(limit)
+        .load(conn)
+        .await?;
+    Ok(rows)
+}
@@ -0,0 +1,133 @@
+//! Live build events for the dashboard's WebSocket feed.
+//!
+//! [`publish`] sends a [`FeedEvent`] with `pg_notify` on [`FEED_CHANNEL`],
+//! so it goes out only when the surrounding transaction commits and reaches
+//! every server, whichever one ran the build. [`run_listener`] forwards the
+//! channel into a process-wide tokio broadcast that each WebSocket
+//! connection [`subscribe`]s to.
+
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use diesel::sql_types::Text;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+/// Postgres channel build feed events are notified on.
+pub const FEED_CHANNEL: &str = "ci_build_feed";
+
+/// Events buffered per subscriber; a connection that falls further behind
+/// skips the oldest.
+const FEED_CAPACITY: usize = 256;
+
+/// Delay before the listener reconnects after losing its connection.
+const LISTEN_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A change to a build, as sent to WebSocket clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FeedEvent {
+    BuildCreated {
+        build_id: i64,
+        project_id: i64,
+        branch: String,
+        commit_sha: String,
+        trigger_event: String,
+    },
+    BuildStarted {
+        build_id: i64,
+        project_id: i64,
+    },
+    StepFinished {
+        build_id: i64,
+        project_id: i64,
+        step_id: i64,
+        name: String,
+        status: String,
+        duration_ms: i32,
+    },
+    BuildCompleted {
+        build_id: i64,
+        project_id: i64,
+        status: String,
+        duration_ms: Option<i32>,
+    },
+}
+
+impl FeedEvent {
+    pub fn project_id(&self) -> i64 {
+        match self {
+            Self::BuildCreated { project_id, .. }
+            | Self::BuildStarted { project_id, .. }
+            | Self::StepFinished { project_id, .. }
+            | Self::BuildCompleted { project_id, .. } => *project_id,
+        }
+    }
+}
+
+/// A feed event with the tenant it belongs to, for filtering subscribers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeedMessage {
+    pub tenant_id: uuid::Uuid,
+    pub event: FeedEvent,
+}
+
+static FEED: LazyLock<broadcast::Sender<FeedMessage>> =
+    LazyLock::new(|| broadcast::channel(FEED_CAPACITY).0);
+
+/// Receive every feed event from now on, of all tenants.
+pub fn subscribe() -> broadcast::Receiver<FeedMessage> {
+    FEED.subscribe()
+}
+
+/// Announce `event` on [`FEED_CHANNEL`]. Inside a transaction it is
+/// delivered on commit.
+pub async fn publish(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+    event: FeedEvent,
+) -> anyhow::Result<()> {
+    let payload = serde_json::to_string(&FeedMessage { tenant_id, event })?;
+    diesel::sql_query("SELECT pg_notify($1, $2)")
+        .bind::<Text, _>(FEED_CHANNEL)
+        .bind::<Text, _>(payload)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Forward [`FEED_CHANNEL`] notifications to the broadcast, reconnecting
+/// whenever the listening connection drops. Spawned as a background tokio
+/// task; `database_url` is used for a dedicated connection so the `LISTEN`
+/// does not tie up a pooled one.
+pub async fn run_listener(database_url: String) {
+    loop {
+        if let Err(e) = listen(&database_url).await {
+            tracing::warn!("Build feed listener disconnected: {e}");
+        }
+        tokio::time::sleep(LISTEN_RETRY_DELAY).await;
+    }
+}
+
+async fn listen(database_url: &str) -> anyhow::Result<()> {
+    let mut conn = AsyncPgConnection::establish(database_url).await?;
+    diesel::sql_query(format!("LISTEN {FEED_CHANNEL}"))
+        .execute(&mut conn)
+        .await?;
+    tracing::info!(channel = FEED_CHANNEL, "Listening for build feed events");
+
+    let mut notifications = std::pin::pin!(conn.notifications_stream());
+    while let Some(notification) = notifications.next().await {
+        let notification = notification?;
+        match serde_json::from_str::<FeedMessage>(&notification.payload) {
+            // No receivers is not an error; nobody is watching.
+            Ok(message) => {
+                let _ = FEED.send(message);
+            }
+            Err(e) => tracing::warn!("Malformed build feed event: {e}"),
+        }
+    }
+    anyhow::bail!("notification stream ended")
+}
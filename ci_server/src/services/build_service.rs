@@ -1,22 +1,94 @@
 //! Build scheduling, throttling, and execution orchestration.
 
 use diesel::prelude::*;
-use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use diesel::sql_types::{BigInt, Jsonb, Text};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
 
-use crate::models::build::{CiBuild, NewCiBuild};
-use crate::schema::ci_builds;
+use crate::events::build::{CiBuildAggregate, CiBuildEvent};
+use crate::events::store;
+use crate::models::build::{BuildStatus, CiBuild, NewCiBuild};
+use crate::models::project::CiProject;
+use crate::models::test_result::NewCiTestResult;
+use crate::schema::{ci_builds, ci_projects, ci_test_results};
+use crate::services::build_feed::{self, FeedEvent};
+use crate::services::log_parser::{TestCounts, TestResult};
+use crate::services::notification_service;
+use crate::services::outbox_service::{self, OutboxMessage};
+use crate::services::quota_service;
 
-/// Create a new build record.
+/// Postgres channel notified whenever a build becomes claimable; every
+/// server's executor listens on it instead of sleeping between polls.
+pub const BUILD_QUEUED_CHANNEL: &str = "ci_build_queued";
+
+/// Create a new build record and notify [`BUILD_QUEUED_CHANNEL`], the build
+/// feed and the `build.created` notification endpoints. All are delivered when the
+/// surrounding transaction commits, so executors never look before the row
+/// is visible.
+///
+/// Fails with [`quota_service::QuotaExceeded`] when the tenant is out of
+/// daily builds.
+#[tracing::instrument(name = "db.create_build", skip_all, fields(project_id = new_build.project_id))]
 pub async fn create_build(
     conn: &mut AsyncPgConnection,
     new_build: NewCiBuild,
 ) -> anyhow::Result<CiBuild> {
+    quota_service::check_build_quota(conn, new_build.tenant_id).await?;
+
     let result = diesel::insert_into(ci_builds::table)
-        .values(&new_build)
+        .values((
+            &new_build,
+            ci_builds::trace_context.eq(crate::telemetry::traceparent()),
+        ))
         .get_result::<CiBuild>(conn)
         .await?;
 
-    crate::metrics::build_status_changed("pending");
+    store::append::<CiBuildAggregate>(
+        conn,
+        result.tenant_id,
+        result.id,
+        &CiBuildEvent::BuildCreated {
+            project_id: result.project_id,
+            commit_sha: result.commit_sha.clone(),
+            branch: result.branch.clone(),
+            pr_number: result.pr_number,
+            author: result.author.clone(),
+            message: result.message.clone(),
+            fingerprint: result.fingerprint.clone(),
+            trigger_event: result.trigger_event.clone(),
+        },
+    )
+    .await?;
+
+    diesel::sql_query("SELECT pg_notify($1, $2)")
+        .bind::<Text, _>(BUILD_QUEUED_CHANNEL)
+        .bind::<Text, _>(result.id.to_string())
+        .execute(conn)
+        .await?;
+    build_feed::publish(
+        conn,
+        result.tenant_id,
+        FeedEvent::BuildCreated {
+            build_id: result.id,
+            project_id: result.project_id,
+            branch: result.branch.clone(),
+            commit_sha: result.commit_sha.clone(),
+            trigger_event: result.trigger_event.clone(),
+        },
+    )
+    .await?;
+    notification_service::notify(
+        conn,
+        result.tenant_id,
+        result.project_id,
+        "build.created",
+        &result,
+    )
+    .await?;
+
+    let repo = project_repo(conn, result.project_id).await?;
+    crate::metrics::build_status_changed("pending", &repo, &result.branch);
+    crate::metrics::build_queued();
     tracing::info!(
         build_id = result.id,
         project_id = result.project_id,
@@ -46,21 +118,150 @@ pub async fn is_duplicate(
     Ok(count > 0)
 }
 
-/// Update build status.
+/// Record a build that will not run, e.g. a `[skip ci]` push. The row is
+/// inserted already `skipped` with `reason` in its summary; it neither
+/// counts against the tenant's quota nor wakes the executors.
+pub async fn record_skipped(
+    conn: &mut AsyncPgConnection,
+    new_build: NewCiBuild,
+    reason: &str,
+) -> anyhow::Result<CiBuild> {
+    let now = chrono::Utc::now();
+    let new_build = NewCiBuild {
+        status: BuildStatus::Skipped.as_str().to_string(),
+        ..new_build
+    };
+
+    let result = conn
+        .transaction::<_, anyhow::Error, _>(|conn| {
+            async move {
+                let result = diesel::insert_into(ci_builds::table)
+                    .values((
+                        &new_build,
+                        ci_builds::finished_at.eq(now),
+                        ci_builds::summary.eq(serde_json::json!({ "skipped": reason })),
+                    ))
+                    .get_result::<CiBuild>(conn)
+                    .await?;
+
+                store::append::<CiBuildAggregate>(
+                    conn,
+                    result.tenant_id,
+                    result.id,
+                    &CiBuildEvent::BuildCreated {
+                        project_id: result.project_id,
+                        commit_sha: result.commit_sha.clone(),
+                        branch: result.branch.clone(),
+                        pr_number: result.pr_number,
+                        author: result.author.clone(),
+                        message: result.message.clone(),
+                        fingerprint: result.fingerprint.clone(),
+                        trigger_event: result.trigger_event.clone(),
+                    },
+                )
+                .await?;
+                store::append::<CiBuildAggregate>(
+                    conn,
+                    result.tenant_id,
+                    result.id,
+                    &CiBuildEvent::BuildSkipped {
+                        reason: reason.to_string(),
+                    },
+                )
+                .await?;
+
+                Ok(result)
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    let repo = project_repo(conn, result.project_id).await?;
+    crate::metrics::build_status_changed(BuildStatus::Skipped.as_str(), &repo, &result.branch);
+    tracing::info!(
+        build_id = result.id,
+        project_id = result.project_id,
+        branch = %result.branch,
+        reason,
+        "Build skipped"
+    );
+
+    Ok(result)
+}
+
+/// Rejected build status change.
+#[derive(Debug, thiserror::Error)]
+pub enum TransitionError {
+    #[error("build {0} not found")]
+    NotFound(i64),
+    #[error("illegal transition for build {build_id}: {from} -> {to}")]
+    Illegal {
+        build_id: i64,
+        from: String,
+        to: BuildStatus,
+    },
+    #[error(transparent)]
+    Database(#[from] diesel::result::Error),
+}
+
+/// Move a build to a new status, enforcing [`BuildStatus::can_transition_to`].
+///
+/// The update is conditional on the current status, so two writers racing
+/// on the same build cannot both succeed.
+#[tracing::instrument(name = "db.update_status", skip(conn))]
 pub async fn update_status(
     conn: &mut AsyncPgConnection,
     build_id: i64,
-    status: &str,
-) -> anyhow::Result<()> {
-    diesel::update(ci_builds::table.find(build_id))
-        .set(ci_builds::status.eq(status))
-        .execute(conn)
-        .await?;
+    status: BuildStatus,
+) -> Result<(), TransitionError> {
+    let from: Vec<&str> = status
+        .predecessors()
+        .into_iter()
+        .map(BuildStatus::as_str)
+        .collect();
+
+    let updated: Option<(i64, String)> = diesel::update(ci_builds::table.find(build_id))
+        .filter(ci_builds::status.eq_any(from))
+        .set((
+            ci_builds::status.eq(status.as_str()),
+            ci_builds::write_date.eq(chrono::Utc::now()),
+        ))
+        .returning((ci_builds::project_id, ci_builds::branch))
+        .get_result(conn)
+        .await
+        .optional()?;
+
+    let Some((project_id, branch)) = updated else {
+        let current: Option<String> = ci_builds::table
+            .find(build_id)
+            .select(ci_builds::status)
+            .first(conn)
+            .await
+            .optional()?;
+        return Err(match current {
+            Some(from) => TransitionError::Illegal {
+                build_id,
+                from,
+                to: status,
+            },
+            None => TransitionError::NotFound(build_id),
+        });
+    };
 
-    crate::metrics::build_status_changed(status);
+    let repo = project_repo(conn, project_id).await?;
+    crate::metrics::build_status_changed(status.as_str(), &repo, &branch);
     Ok(())
 }
 
+/// The project's GitHub repo, used as its metrics label.
+async fn project_repo(conn: &mut AsyncPgConnection, project_id: i64) -> QueryResult<String> {
+    ci_projects::table
+        .find(project_id)
+        .select(ci_projects::github_repo)
+        .first(conn)
+        .await
+}
+
 /// Get the latest build for a project + branch.
 pub async fn get_latest(
     conn: &mut AsyncPgConnection,
@@ -77,6 +278,54 @@ pub async fn get_latest(
     Ok(result)
 }
 
+/// Status of the build of the same project + branch that finished last
+/// before `build_id`, among those that passed or failed; cancelled and
+/// skipped builds say nothing about the branch.
+pub async fn previous_outcome(
+    conn: &mut AsyncPgConnection,
+    build_id: i64,
+) -> anyhow::Result<Option<String>> {
+    let Some(build) = get_build(conn, build_id).await? else {
+        return Ok(None);
+    };
+    let result = ci_builds::table
+        .filter(ci_builds::project_id.eq(build.project_id))
+        .filter(ci_builds::branch.eq(&build.branch))
+        .filter(ci_builds::id.ne(build.id))
+        .filter(ci_builds::status.eq_any([
+            BuildStatus::Success.as_str(),
+            BuildStatus::Failure.as_str(),
+            BuildStatus::Timeout.as_str(),
+            BuildStatus::ConfigError.as_str(),
+        ]))
+        .filter(ci_builds::finished_at.le(build.finished_at.unwrap_or_else(chrono::Utc::now)))
+        .order((ci_builds::finished_at.desc(), ci_builds::id.desc()))
+        .select(ci_builds::status)
+        .first::<String>(conn)
+        .await
+        .optional()?;
+    Ok(result)
+}
+
+/// Pending and running builds of a pull request.
+pub async fn active_for_pr(
+    conn: &mut AsyncPgConnection,
+    project_id: i64,
+    pr_number: i32,
+) -> anyhow::Result<Vec<CiBuild>> {
+    let results = ci_builds::table
+        .filter(ci_builds::project_id.eq(project_id))
+        .filter(ci_builds::pr_number.eq(pr_number))
+        .filter(
+            ci_builds::status
+                .eq_any([BuildStatus::Pending.as_str(), BuildStatus::Running.as_str()]),
+        )
+        .order(ci_builds::id.asc())
+        .load::<CiBuild>(conn)
+        .await?;
+    Ok(results)
+}
+
 /// Get a build by ID.
 pub async fn get_build(
     conn: &mut AsyncPgConnection,
@@ -89,3 +338,186 @@ pub async fn get_build(
         .optional()?;
     Ok(result)
 }
+
+/// Merge `fields` (a JSON object) into a build's summary, replacing keys it
+/// already has.
+pub async fn merge_summary(
+    conn: &mut AsyncPgConnection,
+    build_id: i64,
+    fields: serde_json::Value,
+) -> anyhow::Result<()> {
+    diesel::sql_query(
+        "UPDATE ci_builds SET summary = COALESCE(summary, '{}'::jsonb) || $2 WHERE id = $1",
+    )
+    .bind::<BigInt, _>(build_id)
+    .bind::<Jsonb, _>(fields)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Record a step's test counts in its build's summary, under
+/// `tests.steps.<step>`, and update the build's totals in `tests`. A
+/// retried step's last attempt replaces the counts of its earlier ones.
+pub async fn record_test_counts(
+    conn: &mut AsyncPgConnection,
+    build_id: i64,
+    step_name: &str,
+    counts: TestCounts,
+) -> anyhow::Result<()> {
+    let summary: Option<serde_json::Value> = ci_builds::table
+        .find(build_id)
+        .select(ci_builds::summary)
+        .first(conn)
+        .await?;
+    let mut steps = summary
+        .as_ref()
+        .and_then(|s| s.get("tests")?.get("steps")?.as_object().cloned())
+        .unwrap_or_default();
+    steps.insert(step_name.to_string(), serde_json::to_value(counts)?);
+    let total = steps
+        .values()
+        .filter_map(|v| serde_json::from_value::<TestCounts>(v.clone()).ok())
+        .fold(TestCounts::default(), |a, b| a + b);
+
+    let mut tests = serde_json::to_value(total)?;
+    tests["steps"] = serde_json::Value::Object(steps);
+    merge_summary(conn, build_id, serde_json::json!({ "tests": tests })).await
+}
+
+/// Test results inserted per statement: each row binds 8 parameters, and
+/// Postgres takes at most 65535 in one statement.
+const TEST_RESULTS_PER_INSERT: usize = 8000;
+
+/// Record the per-test results of a build step in `ci_test_results`. As
+/// with [`record_test_counts`], a retried step's last attempt replaces the
+/// results of its earlier ones.
+pub async fn record_test_results(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+    project_id: i64,
+    build_id: i64,
+    step_id: i64,
+    step_name: &str,
+    results: &[TestResult],
+) -> anyhow::Result<()> {
+    if results.is_empty() {
+        return Ok(());
+    }
+    let rows: Vec<NewCiTestResult> = results
+        .iter()
+        .map(|result| NewCiTestResult {
+            tenant_id,
+            project_id,
+            build_id,
+            step_id,
+            step_name: step_name.to_string(),
+            name: result.name.clone(),
+            status: result.status.to_string(),
+            duration_ms: result.duration_ms,
+        })
+        .collect();
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        async move {
+            diesel::delete(
+                ci_test_results::table
+                    .filter(ci_test_results::build_id.eq(build_id))
+                    .filter(ci_test_results::step_name.eq(step_name)),
+            )
+            .execute(conn)
+            .await?;
+            for chunk in rows.chunks(TEST_RESULTS_PER_INSERT) {
+                diesel::insert_into(ci_test_results::table)
+                    .values(chunk)
+                    .execute(conn)
+                    .await?;
+            }
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Cancel a build no executor has claimed yet, queueing its GitHub status
+/// and notifications in the same transaction. Fails with [`TransitionError`] if the build has
+/// left `pending` meanwhile.
+pub async fn cancel_queued(
+    conn: &mut AsyncPgConnection,
+    build: &CiBuild,
+    target_url: String,
+) -> anyhow::Result<()> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        async move {
+            update_status(conn, build.id, BuildStatus::Cancelled).await?;
+            diesel::update(ci_builds::table.find(build.id))
+                .set(ci_builds::finished_at.eq(chrono::Utc::now()))
+                .execute(conn)
+                .await?;
+            store::append::<CiBuildAggregate>(
+                conn,
+                build.tenant_id,
+                build.id,
+                &CiBuildEvent::BuildCancelled,
+            )
+            .await?;
+
+            let project: CiProject = ci_projects::table
+                .find(build.project_id)
+                .first(conn)
+                .await?;
+            outbox_service::enqueue(
+                conn,
+                build.tenant_id,
+                &OutboxMessage::GithubStatus {
+                    repo: project.github_repo.clone(),
+                    sha: build.commit_sha.clone(),
+                    state: "error".to_string(),
+                    description: format!("Build #{} cancelled", build.id),
+                    target_url,
+                    context: "centrix-ci".to_string(),
+                    annotations: Vec::new(),
+                }
+                .for_provider(project.provider()),
+            )
+            .await?;
+
+            build_feed::publish(
+                conn,
+                build.tenant_id,
+                FeedEvent::BuildCompleted {
+                    build_id: build.id,
+                    project_id: build.project_id,
+                    status: BuildStatus::Cancelled.as_str().to_string(),
+                    duration_ms: None,
+                },
+            )
+            .await?;
+            if let Some(cancelled) = get_build(conn, build.id).await? {
+                notification_service::notify(
+                    conn,
+                    build.tenant_id,
+                    build.project_id,
+                    "build.cancelled",
+                    &cancelled,
+                )
+                .await?;
+            }
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Flag a running build for cancellation; its executor kills the current
+/// step and finishes the build. Returns `false` if the build is no longer
+/// running.
+pub async fn request_cancel(conn: &mut AsyncPgConnection, build_id: i64) -> anyhow::Result<bool> {
+    let updated = diesel::update(ci_builds::table.find(build_id))
+        .filter(ci_builds::status.eq(BuildStatus::Running.as_str()))
+        .set(ci_builds::cancel_requested_at.eq(chrono::Utc::now()))
+        .execute(conn)
+        .await?;
+    Ok(updated > 0)
+}
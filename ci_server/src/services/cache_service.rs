@@ -0,0 +1,215 @@
+//! Build caches kept between builds of a project.
+//!
+//! A pipeline's `cache` list names directories of the workspace to carry
+//! over, keyed by the content of lockfiles:
+//!
+//! ```json
+//! { "cache": [{ "name": "cargo", "paths": ["target", ".cargo/registry"],
+//!               "key_files": ["Cargo.lock"] }] }
+//! ```
+//!
+//! Before the steps run, the archive whose key matches is unpacked into the
+//! fresh checkout; without one, the project's latest archive of that name
+//! is used instead. After a passing, trusted push build of the default
+//! branch with no exact match, the paths are archived under the new key,
+//! replacing older archives of the name. Untrusted builds restore nothing.
+//! Archives are tarballs under `CI_CACHE_DIR/<project_id>/`. Paths are
+//! relative to the workspace; point tool homes into it (e.g.
+//! `CARGO_HOME: .cargo`) to cache them.
+
+use std::path::{Component, Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use tokio::process::Command;
+
+/// One entry of `pipeline_config.cache`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheSpec {
+    pub name: String,
+    pub paths: Vec<String>,
+    pub key_files: Vec<String>,
+}
+
+/// How a cache was restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restored {
+    /// The archive for the current key.
+    Hit,
+    /// An older archive of the same name.
+    Partial,
+    Miss,
+}
+
+/// Whether `name` is usable in an archive file name.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// Whether `path` stays inside the workspace.
+pub fn valid_path(path: &str) -> bool {
+    let path = Path::new(path);
+    !path.as_os_str().is_empty()
+        && path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        && !path.starts_with("~")
+}
+
+/// Parse the `cache` list of a pipeline config, dropping invalid entries.
+pub fn parse(config: Option<&serde_json::Value>) -> Vec<CacheSpec> {
+    let strings = |entry: &serde_json::Value, key: &str| -> Vec<String> {
+        entry
+            .get(key)
+            .and_then(|v| v.as_array())
+            .map(|items| {
+                items
+                    .iter()
+                    .filter_map(|i| i.as_str())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    config
+        .and_then(|c| c.get("cache"))
+        .and_then(|c| c.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.get("name")?.as_str()?.to_string();
+            let paths: Vec<String> = strings(entry, "paths")
+                .into_iter()
+                .filter(|p| valid_path(p))
+                .collect();
+            (valid_name(&name) && !paths.is_empty()).then(|| CacheSpec {
+                name,
+                paths,
+                key_files: strings(entry, "key_files"),
+            })
+        })
+        .collect()
+}
+
+fn project_dir(cache_dir: &str, project_id: i64) -> PathBuf {
+    Path::new(cache_dir).join(project_id.to_string())
+}
+
+/// Archive file name for the current key: the cache name plus a hash of
+/// its paths and the key files' contents.
+async fn archive_name(work_dir: &str, spec: &CacheSpec) -> String {
+    let mut hasher = Sha256::new();
+    for path in &spec.paths {
+        hasher.update(path.as_bytes());
+        hasher.update([0]);
+    }
+    for file in &spec.key_files {
+        hasher.update(file.as_bytes());
+        hasher.update([0]);
+        if let Ok(content) = tokio::fs::read(Path::new(work_dir).join(file)).await {
+            hasher.update(&content);
+        }
+        hasher.update([0]);
+    }
+    let key = hex::encode(hasher.finalize());
+    format!("{}-{}.tar", spec.name, &key[..16])
+}
+
+/// Archives of `spec`'s name, newest first.
+async fn archives(dir: &Path, spec: &CacheSpec) -> Vec<PathBuf> {
+    let prefix = format!("{}-", spec.name);
+    let mut found = Vec::new();
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return found;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let ours = file_name
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_suffix(".tar"))
+            .is_some_and(|key| key.len() == 16 && key.chars().all(|c| c.is_ascii_hexdigit()));
+        if ours {
+            let modified = entry.metadata().await.and_then(|m| m.modified()).ok();
+            found.push((modified, entry.path()));
+        }
+    }
+    found.sort_by(|a, b| b.0.cmp(&a.0));
+    found.into_iter().map(|(_, path)| path).collect()
+}
+
+async fn tar(args: &[&str]) -> anyhow::Result<()> {
+    let output = Command::new("tar").args(args).output().await?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "tar failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Unpack the best archive for `spec` into `work_dir`.
+pub async fn restore(
+    cache_dir: &str,
+    project_id: i64,
+    work_dir: &str,
+    spec: &CacheSpec,
+) -> anyhow::Result<Restored> {
+    let dir = project_dir(cache_dir, project_id);
+    let exact = dir.join(archive_name(work_dir, spec).await);
+    let (archive, restored) = if tokio::fs::try_exists(&exact).await? {
+        (exact, Restored::Hit)
+    } else {
+        match archives(&dir, spec).await.into_iter().next() {
+            Some(latest) => (latest, Restored::Partial),
+            None => return Ok(Restored::Miss),
+        }
+    };
+    let archive = archive.to_string_lossy().into_owned();
+    tar(&["-xf", &archive, "-C", work_dir]).await?;
+    Ok(restored)
+}
+
+/// Archive `spec`'s paths under the current key and drop older archives of
+/// the same name. Returns `false` if none of the paths exist.
+pub async fn save(
+    cache_dir: &str,
+    project_id: i64,
+    work_dir: &str,
+    spec: &CacheSpec,
+) -> anyhow::Result<bool> {
+    let mut present = Vec::new();
+    for path in &spec.paths {
+        if tokio::fs::try_exists(Path::new(work_dir).join(path)).await? {
+            present.push(path.as_str());
+        }
+    }
+    if present.is_empty() {
+        return Ok(false);
+    }
+
+    let dir = project_dir(cache_dir, project_id);
+    tokio::fs::create_dir_all(&dir).await?;
+    let name = archive_name(work_dir, spec).await;
+    let archive = dir.join(&name);
+    // Write beside the final name so readers never see a partial archive.
+    let partial = dir.join(format!(".{name}.{}", uuid::Uuid::new_v4()));
+    let partial_str = partial.to_string_lossy().into_owned();
+
+    let mut args = vec!["-cf", partial_str.as_str(), "-C", work_dir, "--"];
+    args.extend(present);
+    if let Err(e) = tar(&args).await {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(e);
+    }
+    tokio::fs::rename(&partial, &archive).await?;
+
+    for old in archives(&dir, spec).await {
+        if old != archive {
+            let _ = tokio::fs::remove_file(&old).await;
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,257 @@
+//! Chat messages for notification events.
+//!
+//! A `ci_notification_channels` row posts the events it subscribes to (see
+//! [`notification_service::EVENTS`]) to a chat's incoming webhook as
+//! human-readable messages, in the format of its `kind`:
+//!
+//! - `discord`: an embed with the event's title, link, details and a status
+//!   color
+//! - `generic`: `{"text": "..."}` in Markdown, which Mattermost, Microsoft
+//!   Teams incoming webhooks, Rocket.Chat and most other chats accept
+//!
+//! Like endpoint deliveries, messages go through the outbox, so a chat that
+//! is down or rate limits gets them later. So do digests (see
+//! [`digest_service`]).
+
+use std::fmt::Write;
+
+use diesel_async::AsyncPgConnection;
+use serde_json::Value;
+
+use crate::config::CiConfig;
+use crate::services::digest_service::{self, Digest};
+use crate::services::{notification_service, project_service};
+
+/// Message formats of a channel.
+pub const KINDS: &[&str] = &["discord", "generic"];
+
+/// A chat that does not answer within this long counts as failed.
+const DELIVERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Discord caps embed titles at 256 characters and descriptions at 4096.
+const MAX_TITLE_CHARS: usize = 256;
+const MAX_TEXT_CHARS: usize = 4000;
+
+/// Message colors: success, failure, warning, in progress, inactive.
+pub const GREEN: u32 = 0x2e_a0_43;
+pub const RED: u32 = 0xcf_22_2e;
+pub const YELLOW: u32 = 0xbf_87_00;
+pub const BLUE: u32 = 0x09_69_da;
+pub const GREY: u32 = 0x6e_77_81;
+
+/// An event as a chat message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub title: String,
+    /// Where the title links to.
+    pub url: Option<String>,
+    /// Details, one per line, in Markdown.
+    pub text: String,
+    /// Status color, as `0xRRGGBB`.
+    pub color: u32,
+}
+
+fn field<'a>(data: &'a Value, name: &str) -> Option<&'a str> {
+    data.get(name).and_then(|v| v.as_str())
+}
+
+/// `text` cut to at most `max` characters, ending with an ellipsis if cut.
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let end = text
+        .char_indices()
+        .nth(max.saturating_sub(1))
+        .map_or(text.len(), |(i, _)| i);
+    format!("{}…", &text[..end])
+}
+
+/// Message of `event` of project `project`, `data` being the row it was
+/// raised with.
+pub fn message(dashboard_url: &str, project: &str, event: &str, data: &Value) -> Message {
+    let id = data.get("id").and_then(|v| v.as_i64()).unwrap_or_default();
+    let short_sha = field(data, "commit_sha").map(|sha| {
+        let end = sha.char_indices().nth(7).map_or(sha.len(), |(i, _)| i);
+        &sha[..end]
+    });
+    let mut text = String::new();
+
+    match event.split_once('.') {
+        Some(("build", outcome)) => {
+            let (verb, color) = match outcome {
+                "created" => ("queued", BLUE),
+                "succeeded" => ("passed", GREEN),
+                "failed" => ("failed", RED),
+                "cancelled" => ("was cancelled", GREY),
+                _ => (outcome, GREY),
+            };
+            let branch = field(data, "branch").unwrap_or_default();
+            if let Some(pr) = data.get("pr_number").and_then(|v| v.as_i64()) {
+                let _ = writeln!(text, "Pull request #{pr}");
+            }
+            if let Some(sha) = short_sha {
+                let _ = write!(text, "Commit `{sha}`");
+                if let Some(author) = field(data, "author") {
+                    let _ = write!(text, " by {author}");
+                }
+                let _ = writeln!(text);
+            }
+            if let Some(summary) = field(data, "message").and_then(|m| m.lines().next()) {
+                let _ = writeln!(text, "> {summary}");
+            }
+            if let Some(ms) = data.get("duration_ms").and_then(|v| v.as_i64()) {
+                let _ = writeln!(text, "Took {}m {}s", ms / 60_000, ms / 1000 % 60);
+            }
+            Message {
+                title: format!("{project}: build #{id} on {branch} {verb}"),
+                url: Some(format!("{dashboard_url}/api/builds/{id}")),
+                text,
+                color,
+            }
+        }
+        Some(("environment", _)) => {
+            let status = field(data, "status").unwrap_or_default();
+            let color = match status {
+                "running" => GREEN,
+                "failed" => RED,
+                "unhealthy" => YELLOW,
+                "destroyed" | "dormant" => GREY,
+                _ => BLUE,
+            };
+            let pr = data
+                .get("pr_number")
+                .and_then(|v| v.as_i64())
+                .unwrap_or_default();
+            if let Some(url) = field(data, "url").filter(|_| status == "running") {
+                let _ = writeln!(text, "{url}");
+            }
+            if let Some(sha) = short_sha {
+                let _ = writeln!(text, "Commit `{sha}`");
+            }
+            if let Some(reason) = field(data, "destroyed_reason") {
+                let _ = writeln!(text, "Reason: {reason}");
+            }
+            if let Some(error) = field(data, "error") {
+                let _ = writeln!(text, "```\n{}\n```", truncate(error, 1000));
+            }
+            Message {
+                title: format!("{project}: review environment of PR #{pr} {status}"),
+                url: Some(format!("{dashboard_url}/api/environments/{id}")),
+                text,
+                color,
+            }
+        }
+        Some(("error", _)) => {
+            let severity = field(data, "severity").unwrap_or_default();
+            let category = field(data, "category").unwrap_or_default();
+            let _ = writeln!(text, "{}", field(data, "title").unwrap_or_default());
+            if let Some(file) = field(data, "file_path") {
+                let _ = match data.get("line_number").and_then(|v| v.as_i64()) {
+                    Some(line) => writeln!(text, "`{file}:{line}`"),
+                    None => writeln!(text, "`{file}`"),
+                };
+            }
+            if let Some(owners) = data.get("owners").and_then(|v| v.as_array()) {
+                let owners: Vec<&str> = owners.iter().filter_map(|o| o.as_str()).collect();
+                if !owners.is_empty() {
+                    let _ = writeln!(text, "Owners: {}", owners.join(", "));
+                }
+            }
+            Message {
+                title: format!("{project}: new {severity} {category} error"),
+                url: Some(format!("{dashboard_url}/api/errors/{id}")),
+                text,
+                color: if severity == "error" { RED } else { YELLOW },
+            }
+        }
+        Some(("digest", _)) => match serde_json::from_value::<Digest>(data.clone()) {
+            Ok(digest) => digest_service::message(dashboard_url, project, &digest),
+            Err(_) => Message {
+                title: format!("{project}: {event}"),
+                url: None,
+                text,
+                color: GREY,
+            },
+        },
+        _ => Message {
+            title: format!("{project}: {event}"),
+            url: None,
+            text,
+            color: GREY,
+        },
+    }
+}
+
+/// Webhook body of `message` for a channel of `kind`.
+pub fn payload(kind: &str, message: &Message) -> Value {
+    let title = truncate(&message.title, MAX_TITLE_CHARS);
+    let text = truncate(message.text.trim_end(), MAX_TEXT_CHARS);
+    match kind {
+        "discord" => {
+            let mut embed = serde_json::json!({
+                "title": title,
+                "description": text,
+                "color": message.color,
+            });
+            if let Some(ref url) = message.url {
+                embed["url"] = url.clone().into();
+            }
+            serde_json::json!({ "username": "Centrix CI", "embeds": [embed] })
+        }
+        _ => {
+            let heading = match &message.url {
+                Some(url) => format!("**[{title}]({url})**"),
+                None => format!("**{title}**"),
+            };
+            let text = if text.is_empty() {
+                heading
+            } else {
+                format!("{heading}\n{text}")
+            };
+            serde_json::json!({ "text": text })
+        }
+    }
+}
+
+/// Post a queued event to a channel. Fails, so the outbox retries it,
+/// unless the chat answered 2xx. Channels deleted since the event was
+/// queued are skipped.
+pub async fn deliver(
+    config: &CiConfig,
+    conn: &mut AsyncPgConnection,
+    channel_id: i64,
+    event: &str,
+    project_id: i64,
+    data: &Value,
+) -> anyhow::Result<()> {
+    let Some(channel) = notification_service::get_channel(conn, channel_id).await? else {
+        tracing::debug!(
+            channel_id,
+            event,
+            "Notification channel gone, dropping message"
+        );
+        return Ok(());
+    };
+    let project = project_service::get_project(conn, project_id)
+        .await?
+        .map(|p| p.name)
+        .unwrap_or_else(|| format!("project {project_id}"));
+
+    let message = message(&config.dashboard_url, &project, event, data);
+    let resp = reqwest::Client::new()
+        .post(&channel.webhook_url)
+        .header("User-Agent", "centrix-ci")
+        .timeout(DELIVERY_TIMEOUT)
+        .json(&payload(&channel.kind, &message))
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        anyhow::bail!(
+            "Chat notification to channel {} failed: {}",
+            channel.name,
+            resp.status()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,141 @@
+//! Error ownership from the repository's CODEOWNERS file.
+//!
+//! After checkout the executor reads `.github/CODEOWNERS`, `CODEOWNERS`,
+//! `docs/CODEOWNERS` or `.gitea/CODEOWNERS`, the first that exists. Each
+//! error found at a file path records that file's owners in
+//! `ci_errors.owners`, and an unassigned error is assigned to the first of
+//! them, so notifications and the error list can be routed by team.
+//!
+//! Patterns follow GitHub's rules: gitignore-style globs, matched against
+//! the path from the repository root, where the last matching line wins
+//! and a line without owners leaves its paths unowned.
+
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::services::sandbox::SANDBOX_WORKDIR;
+
+/// Candidate locations, in GitHub's order of preference.
+pub const CODEOWNERS_FILES: &[&str] = &[
+    ".github/CODEOWNERS",
+    "CODEOWNERS",
+    "docs/CODEOWNERS",
+    ".gitea/CODEOWNERS",
+];
+
+/// Larger files are ignored, as GitHub ignores CODEOWNERS over 3 MB.
+const MAX_FILE_BYTES: u64 = 3 * 1024 * 1024;
+
+#[derive(Debug)]
+struct Rule {
+    pattern: Regex,
+    owners: Vec<String>,
+}
+
+/// A parsed CODEOWNERS file.
+#[derive(Debug, Default)]
+pub struct CodeOwners {
+    rules: Vec<Rule>,
+}
+
+impl CodeOwners {
+    /// Parse CODEOWNERS text. Lines with an invalid pattern are skipped.
+    pub fn parse(text: &str) -> Self {
+        let rules = text
+            .lines()
+            .filter_map(|line| {
+                let line = line.split_once(" #").map_or(line, |(rule, _)| rule).trim();
+                if line.is_empty() || line.starts_with('#') {
+                    return None;
+                }
+                let mut fields = line.split_whitespace();
+                let pattern = glob_regex(fields.next()?)?;
+                let owners = fields
+                    .filter(|owner| owner.contains('@'))
+                    .map(|owner| owner.to_string())
+                    .collect();
+                Some(Rule { pattern, owners })
+            })
+            .collect();
+        Self { rules }
+    }
+
+    /// Owners of `path`, relative to the repository root; empty when no
+    /// line covers it or the last one that does names nobody.
+    pub fn owners_of(&self, path: &str) -> &[String] {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.pattern.is_match(path))
+            .map(|rule| rule.owners.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+/// Read the CODEOWNERS file of the checkout in `work_dir`, if it has one.
+pub async fn load(work_dir: &str) -> Option<CodeOwners> {
+    for file in CODEOWNERS_FILES {
+        let path = Path::new(work_dir).join(file);
+        let Ok(meta) = tokio::fs::metadata(&path).await else {
+            continue;
+        };
+        if !meta.is_file() || meta.len() > MAX_FILE_BYTES {
+            tracing::warn!(file, "CODEOWNERS ignored: not a file or too large");
+            return None;
+        }
+        return match tokio::fs::read_to_string(&path).await {
+            Ok(text) => Some(CodeOwners::parse(&text)),
+            Err(e) => {
+                tracing::warn!(file, "CODEOWNERS not read: {e}");
+                None
+            }
+        };
+    }
+    None
+}
+
+/// `path` as reported by a tool, relative to the repository root: tools
+/// print paths relative to the workspace, or absolute in it (on the host
+/// or at [`SANDBOX_WORKDIR`] in the sandbox).
+pub fn repo_path<'a>(path: &'a str, work_dir: &str) -> &'a str {
+    let path = [work_dir, SANDBOX_WORKDIR]
+        .iter()
+        .find_map(|root| {
+            path.strip_prefix(root.trim_end_matches('/'))?
+                .strip_prefix('/')
+        })
+        .unwrap_or(path);
+    path.trim_start_matches("./")
+}
+
+/// Translate a CODEOWNERS pattern to a regex over repository paths.
+fn glob_regex(pattern: &str) -> Option<Regex> {
+    // A pattern with a slash other than a trailing one is relative to the
+    // root; without, it matches at any depth.
+    let anchored = pattern.trim_end_matches('/').contains('/');
+    let directory = pattern.ends_with('/');
+    let glob = pattern.trim_start_matches('/').trim_end_matches('/');
+
+    let mut regex = String::from(if anchored { "^" } else { "^(?:.*/)?" });
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    // A match names a file, or a directory and everything below it.
+    regex.push_str(if directory { "/.*$" } else { "(?:/.*)?$" });
+    Regex::new(&regex).ok()
+}
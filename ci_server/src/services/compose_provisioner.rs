@@ -0,0 +1,1067 @@
+//! Docker Compose backend for review environments.
+//!
+//! When a PR build of a repository with a `Dockerfile` at its root
+//! succeeds, the executor requests an environment ([`request`]): the
+//! checkout moves to `{env_dir}/{id}/src` and a compose project is rendered
+//! next to it with two services, `app`, built from the checkout, and `db`,
+//! a Postgres of its own on a volume of the project. The app gets
+//! `DATABASE_URL` and `PORT`, and only its port is published, on the host
+//! port [`environment_allocator`] assigned the environment.
+//!
+//! A project's [`EnvironmentTemplate`] may name a compose file of the
+//! repository instead. It is rendered with its relative paths resolved
+//! against the checkout, only the template's service publishing a port,
+//! and no fixed container names, so environments of several PRs run side
+//! by side. A template's `database` seeds the environment's database via
+//! [`environment_snapshot`].
+//!
+//! The template is a project setting, but the compose file it names comes
+//! from the checkout, so it is only used as far as it cannot reach the
+//! host: services asking for privileges or host namespaces are refused, as
+//! are paths (bind mounts, build contexts, env files, secrets and configs)
+//! leading out of the checkout, symlinks included. Compose runs with
+//! [`COMPOSE_HOST_ENV`] only, so interpolation cannot read the server's
+//! environment. Untrusted pull request builds get no environment at all.
+//!
+//! [`run_provisioner`] brings requested environments up as compose project
+//! `ci-env-{id}`, records them running at their allocated URL, and tears
+//! down the projects of destroyed ones, volumes included. The logs of
+//! running environments are captured by [`environment_logs`]. Environment
+//! directories are local to the server whose build requested them; each
+//! server provisions its own.
+//!
+//! Requests beyond `CI_MAX_ENVS_GLOBAL` environments holding containers,
+//! or `CI_MAX_ENVS_PER_PR` for their PR, stay `requested` and are brought
+//! up, oldest first, as others are destroyed. At `CI_MAX_RUNNING_ENVS`,
+//! the least recently active running environment of the server goes
+//! dormant, its containers stopped, to make room; waking it starts them
+//! again.
+//!
+//! A running environment whose template has a health URL is probed every
+//! `CI_ENV_HEALTH_INTERVAL_SECS`. One failing it is `unhealthy`: its
+//! containers are restarted until it passes again, and after
+//! `CI_ENV_MAX_RESTARTS` restarts without recovering it fails.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use diesel_async::AsyncPgConnection;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use erp_core::db::diesel_pool::DieselPool;
+
+use crate::config::CiConfig;
+use crate::models::environment::{CiEnvironment, NewCiEnvironment};
+use crate::services::environment_template::{EnvironmentTemplate, DEFAULT_DATABASE_SERVICE};
+use crate::services::{
+    environment_allocator, environment_logs, environment_service, environment_snapshot,
+};
+
+/// How often environment directories are reconciled with their records.
+const PROVISION_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Longest `up`, image build included, before provisioning fails.
+const UP_TIMEOUT: Duration = Duration::from_secs(900);
+
+/// How long a started environment has to pass its health check.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Delay between health check attempts.
+const HEALTH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a periodic probe retries before the environment is unhealthy.
+const PROBE_WINDOW: Duration = Duration::from_secs(6);
+
+/// Longest database dump of a snapshot.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Timeout of the other compose commands.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Compose file in each environment directory.
+const COMPOSE_FILE: &str = "compose.yml";
+
+/// Trailing bytes of a failed command's stderr kept as its error.
+const MAX_ERROR_BYTES: usize = 2000;
+
+/// Top-level extension of a rendered compose file carrying the settings
+/// the provisioner itself needs; compose ignores `x-` keys.
+const EXTENSION_KEY: &str = "x-centrix-ci";
+
+/// Server variables compose keeps: what it needs to reach the container
+/// engine. A compose file's `${VAR}` reads the environment compose runs
+/// in, which must not be the server's.
+const COMPOSE_HOST_ENV: &[&str] = &[
+    "PATH",
+    "HOME",
+    "USER",
+    "TMPDIR",
+    "XDG_CONFIG_HOME",
+    "XDG_RUNTIME_DIR",
+    "DOCKER_HOST",
+    "DOCKER_CONFIG",
+    "DOCKER_CONTEXT",
+    "DOCKER_CERT_PATH",
+    "DOCKER_TLS_VERIFY",
+    "CONTAINER_HOST",
+];
+
+/// Service keys granting a container privileges on the host, and
+/// `extends`, which reads another compose file.
+const PRIVILEGED_KEYS: &[&str] = &[
+    "privileged",
+    "cap_add",
+    "devices",
+    "device_cgroup_rules",
+    "security_opt",
+    "cgroup_parent",
+    "extends",
+];
+
+/// Service keys that can share a namespace of the host or of another
+/// container.
+const NAMESPACE_KEYS: &[&str] = &["network_mode", "pid", "ipc", "uts", "userns_mode", "cgroup"];
+
+/// Compose project name of an environment.
+pub fn project_name(env_id: i64) -> String {
+    format!("ci-env-{env_id}")
+}
+
+/// Directory of an environment in `env_dir`.
+pub fn env_path(env_dir: &str, env_id: i64) -> PathBuf {
+    Path::new(env_dir).join(env_id.to_string())
+}
+
+/// Request an environment of shape `template` for a successful PR build
+/// of `repo` checked out in `work_dir`. An `owned` checkout, the build's
+/// own clone, moves into the environment; a project's `local_path` is
+/// built in place. Returns `None` when provisioning is disabled or the
+/// repository has no `Dockerfile`, or no template compose file.
+pub async fn request(
+    conn: &mut AsyncPgConnection,
+    config: &CiConfig,
+    new_env: NewCiEnvironment,
+    repo: &str,
+    work_dir: &str,
+    owned: bool,
+    template: &EnvironmentTemplate,
+) -> anyhow::Result<Option<CiEnvironment>> {
+    let Some(ref env_dir) = config.env_dir else {
+        return Ok(None);
+    };
+    let source = template.compose_file.as_deref().unwrap_or("Dockerfile");
+    if !tokio::fs::try_exists(Path::new(work_dir).join(source))
+        .await
+        .unwrap_or(false)
+    {
+        return Ok(None);
+    }
+
+    let env = environment_service::create_environment(conn, new_env).await?;
+    let env = match environment_allocator::allocate(conn, &env, repo, config).await {
+        Ok(allocated) => allocated,
+        Err(e) => {
+            let error = format!("cannot allocate environment: {e}");
+            tracing::warn!(env_id = env.id, "{error}");
+            let failed = environment_service::mark_failed(conn, &env, &error).await?;
+            return Ok(Some(failed.unwrap_or(env)));
+        }
+    };
+    let dir = env_path(env_dir, env.id);
+    if let Err(e) = prepare(&dir, &env, work_dir, owned, config, template).await {
+        let error = format!("cannot prepare environment: {e}");
+        tracing::error!(env_id = env.id, "{error}");
+        let failed = environment_service::mark_failed(conn, &env, &error).await?;
+        return Ok(Some(failed.unwrap_or(env)));
+    }
+    Ok(Some(env))
+}
+
+/// Fill an environment directory: the build context and the compose file,
+/// written last as the sign that the directory is ready.
+async fn prepare(
+    dir: &Path,
+    env: &CiEnvironment,
+    work_dir: &str,
+    owned: bool,
+    config: &CiConfig,
+    template: &EnvironmentTemplate,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    let checkout = if owned {
+        tokio::fs::rename(work_dir, dir.join("src")).await?;
+        dir.join("src")
+    } else {
+        PathBuf::from(work_dir)
+    };
+    let checkout = tokio::fs::canonicalize(&checkout).await?;
+    let port = env.port.context("no port allocated")?;
+    let mut compose = match template.compose_file {
+        Some(ref file) => {
+            let path = confined(&checkout, &checkout.join(file))
+                .with_context(|| format!("cannot use {file}"))?;
+            let raw = tokio::fs::read_to_string(&path)
+                .await
+                .with_context(|| format!("cannot read {file}"))?;
+            let project =
+                serde_yaml::from_str(&raw).with_context(|| format!("cannot parse {file}"))?;
+            let base = path.parent().unwrap_or(&checkout);
+            render_project(project, &checkout, base, port, config, template)
+                .with_context(|| format!("cannot use {file}"))?
+        }
+        None => {
+            let db_password = uuid::Uuid::new_v4().simple().to_string();
+            render(
+                &checkout.to_string_lossy(),
+                port,
+                config,
+                &db_password,
+                template,
+            )
+        }
+    };
+    if template.database.is_seeded() {
+        let init = environment_snapshot::prepare_init(
+            dir,
+            &checkout,
+            env.project_id,
+            &template.database,
+            config.env_snapshot_dir.as_deref(),
+        )
+        .await?;
+        let service = template.database.service();
+        let database = compose["services"]
+            .get_mut(service)
+            .and_then(|s| s.as_object_mut())
+            .with_context(|| format!("no database service {service}"))?;
+        let volumes = database
+            .entry("volumes")
+            .or_insert_with(|| serde_json::json!([]));
+        if let Some(volumes) = volumes.as_array_mut() {
+            volumes.push(format!("{}:/docker-entrypoint-initdb.d:ro", init.display()).into());
+        }
+    }
+    compose[EXTENSION_KEY] = serde_json::json!({
+        "health_url": template.health_url,
+        "db_service": template.database.service(),
+    });
+    tokio::fs::write(dir.join(COMPOSE_FILE), serde_yaml::to_string(&compose)?).await?;
+    Ok(())
+}
+
+/// The generated compose project of an environment built from `context`
+/// and published on host `port`.
+fn render(
+    context: &str,
+    port: i32,
+    config: &CiConfig,
+    db_password: &str,
+    template: &EnvironmentTemplate,
+) -> serde_json::Value {
+    let app_port = template.port.unwrap_or(config.env_app_port).to_string();
+    let mut app_env = serde_json::json!({
+        "DATABASE_URL": format!("postgres://app:{db_password}@db:5432/app"),
+        "PORT": app_port,
+    });
+    for (name, value) in &template.env {
+        app_env[name] = value.as_str().into();
+    }
+    serde_json::json!({
+        "services": {
+            "app": {
+                "build": {"context": context},
+                "environment": app_env,
+                "ports": [format!("{port}:{app_port}")],
+                "depends_on": {"db": {"condition": "service_healthy"}},
+                "restart": "unless-stopped",
+            },
+            "db": {
+                "image": config.env_db_image,
+                "environment": {
+                    "POSTGRES_USER": "app",
+                    "POSTGRES_PASSWORD": db_password,
+                    "POSTGRES_DB": "app",
+                },
+                "volumes": ["db-data:/var/lib/postgresql/data"],
+                // Over TCP: while init scripts load a snapshot or seed, the
+                // entrypoint's temporary server only listens on its socket.
+                "healthcheck": {
+                    "test": ["CMD-SHELL", "pg_isready -h 127.0.0.1 -U app -d app"],
+                    "interval": "5s",
+                    "timeout": "5s",
+                    "retries": 12,
+                    "start_period": "300s",
+                },
+                "restart": "unless-stopped",
+            },
+        },
+        "volumes": {"db-data": {}},
+    })
+}
+
+/// A repository's compose `project`, from a file in `base` of `checkout`,
+/// as an environment published on host `port`. Fails for a project that
+/// could reach the host.
+fn render_project(
+    mut project: serde_json::Value,
+    checkout: &Path,
+    base: &Path,
+    port: i32,
+    config: &CiConfig,
+    template: &EnvironmentTemplate,
+) -> anyhow::Result<serde_json::Value> {
+    let published = template.service();
+    if let Some(root) = project.as_object() {
+        anyhow::ensure!(!root.contains_key("include"), "include is not supported");
+    }
+    for section in ["volumes", "networks"] {
+        let entries = project.get(section).and_then(|s| s.as_object());
+        for (name, entry) in entries.into_iter().flatten() {
+            for key in ["external", "driver_opts"] {
+                anyhow::ensure!(
+                    entry.get(key).is_none(),
+                    "{section}.{name}: {key} is not allowed"
+                );
+            }
+            let driver = entry.get("driver").and_then(|d| d.as_str());
+            anyhow::ensure!(
+                driver != Some("host"),
+                "{section}.{name}: host driver is not allowed"
+            );
+        }
+    }
+    let services = project
+        .get_mut("services")
+        .and_then(|s| s.as_object_mut())
+        .context("no services")?;
+    if !template.services.is_empty() {
+        let mut keep: Vec<String> = template.services.clone();
+        keep.push(published.to_string());
+        let mut i = 0;
+        while i < keep.len() {
+            let needs = services.get(&keep[i]).map(dependencies).unwrap_or_default();
+            for need in needs {
+                if !keep.contains(&need) {
+                    keep.push(need);
+                }
+            }
+            i += 1;
+        }
+        services.retain(|name, _| keep.contains(name));
+    }
+    anyhow::ensure!(services.contains_key(published), "no service {published}");
+
+    let app_port = template.port.unwrap_or(config.env_app_port);
+    for (name, service) in services.iter_mut() {
+        let Some(service) = service.as_object_mut() else {
+            continue;
+        };
+        check_isolation(name, service)?;
+        service.remove("container_name");
+        service.remove("ports");
+        if name == published {
+            service.insert(
+                "ports".into(),
+                serde_json::json!([format!("{port}:{app_port}")]),
+            );
+        }
+        if !template.env.is_empty() {
+            let mut env = environment_map(service.get("environment"));
+            for (name, value) in &template.env {
+                env.insert(name.clone(), value.as_str().into());
+            }
+            service.insert("environment".into(), env.into());
+        }
+        resolve_paths(service, checkout, base).with_context(|| format!("services.{name}"))?;
+    }
+    for section in ["configs", "secrets"] {
+        let entries = project.get_mut(section).and_then(|s| s.as_object_mut());
+        for (name, entry) in entries.into_iter().flatten() {
+            // `environment` reads compose's own environment.
+            anyhow::ensure!(
+                entry.get("environment").is_none(),
+                "{section}.{name}: environment is not allowed"
+            );
+            if let Some(file) = entry.get("file").and_then(|f| f.as_str()) {
+                let file =
+                    resolve(checkout, base, file).with_context(|| format!("{section}.{name}"))?;
+                entry["file"] = file.into();
+            }
+        }
+    }
+    Ok(project)
+}
+
+/// Fail for a service with host privileges or sharing a namespace of the
+/// host or of a container outside the project.
+fn check_isolation(
+    name: &str,
+    service: &serde_json::Map<String, serde_json::Value>,
+) -> anyhow::Result<()> {
+    for key in PRIVILEGED_KEYS {
+        anyhow::ensure!(
+            !service.contains_key(*key),
+            "services.{name}: {key} is not allowed"
+        );
+    }
+    for key in NAMESPACE_KEYS {
+        let mode = service
+            .get(*key)
+            .and_then(|m| m.as_str())
+            .unwrap_or_default();
+        anyhow::ensure!(
+            mode != "host" && !mode.starts_with("container:"),
+            "services.{name}: {key}: {mode} is not allowed"
+        );
+    }
+    let volumes_from = service.get("volumes_from").and_then(|v| v.as_array());
+    for source in volumes_from.into_iter().flatten() {
+        let source = source.as_str().unwrap_or_default();
+        anyhow::ensure!(
+            !source.starts_with("container:"),
+            "services.{name}: volumes_from: {source} is not allowed"
+        );
+    }
+    Ok(())
+}
+
+/// `path` with its symlinks resolved, failing unless it is inside
+/// `checkout` (itself canonical). Components that don't exist yet, as a
+/// bind mount's source compose is to create, are kept as they are.
+pub fn confined(checkout: &Path, path: &Path) -> anyhow::Result<PathBuf> {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::ParentDir => {
+                normal.pop();
+            }
+            std::path::Component::CurDir => {}
+            component => normal.push(component),
+        }
+    }
+    let mut existing = normal.as_path();
+    let mut missing = Vec::new();
+    while std::fs::symlink_metadata(existing).is_err() {
+        let Some(parent) = existing.parent() else {
+            break;
+        };
+        missing.extend(existing.file_name());
+        existing = parent;
+    }
+    let mut resolved = std::fs::canonicalize(existing)?;
+    resolved.extend(missing.into_iter().rev());
+    anyhow::ensure!(
+        resolved.starts_with(checkout),
+        "{} is outside the repository",
+        path.display()
+    );
+    Ok(resolved)
+}
+
+/// Services a compose service depends on, in list or mapping form.
+fn dependencies(service: &serde_json::Value) -> Vec<String> {
+    match service.get("depends_on") {
+        Some(serde_json::Value::Array(names)) => names
+            .iter()
+            .filter_map(|n| n.as_str())
+            .map(|n| n.to_string())
+            .collect(),
+        Some(serde_json::Value::Object(names)) => names.keys().cloned().collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// A service's `environment`, in list (`NAME=value`) or mapping form, as a
+/// mapping.
+fn environment_map(env: Option<&serde_json::Value>) -> serde_json::Map<String, serde_json::Value> {
+    match env {
+        Some(serde_json::Value::Object(vars)) => vars.clone(),
+        Some(serde_json::Value::Array(vars)) => vars
+            .iter()
+            .filter_map(|v| v.as_str())
+            .map(|v| match v.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.into()),
+                None => (v.to_string(), serde_json::Value::Null),
+            })
+            .collect(),
+        _ => serde_json::Map::new(),
+    }
+}
+
+/// `path` of the file in `base`, as an absolute path inside `checkout`.
+fn resolve(checkout: &Path, base: &Path, path: &str) -> anyhow::Result<String> {
+    anyhow::ensure!(!path.starts_with('~'), "{path} is outside the repository");
+    let resolved = confined(checkout, &base.join(path))?;
+    Ok(resolved.to_string_lossy().into_owned())
+}
+
+/// A build context of `base`, unless remote (a URL or `git@` address).
+fn resolve_context(checkout: &Path, base: &Path, context: &str) -> anyhow::Result<String> {
+    if context.contains("://") || context.starts_with("git@") {
+        Ok(context.to_string())
+    } else {
+        resolve(checkout, base, context)
+    }
+}
+
+/// Resolve the paths of a service's build context, env files and bind
+/// mounts against `base`: the rendered file lives elsewhere. Fails for a
+/// path outside `checkout`.
+fn resolve_paths(
+    service: &mut serde_json::Map<String, serde_json::Value>,
+    checkout: &Path,
+    base: &Path,
+) -> anyhow::Result<()> {
+    match service.get_mut("build") {
+        Some(serde_json::Value::String(context)) => {
+            *context = resolve_context(checkout, base, context)?
+        }
+        Some(serde_json::Value::Object(build)) => {
+            anyhow::ensure!(
+                !build.contains_key("additional_contexts"),
+                "build.additional_contexts is not allowed"
+            );
+            let context = build.get("context").and_then(|c| c.as_str()).unwrap_or(".");
+            let context = resolve_context(checkout, base, context)?;
+            if let Some(dockerfile) = build.get("dockerfile").and_then(|d| d.as_str()) {
+                anyhow::ensure!(
+                    crate::services::cache_service::valid_path(dockerfile),
+                    "build.dockerfile must be a path inside the build context"
+                );
+            }
+            build.insert("context".into(), context.into());
+        }
+        _ => {}
+    }
+    match service.get_mut("env_file") {
+        Some(serde_json::Value::String(file)) => *file = resolve(checkout, base, file)?,
+        Some(serde_json::Value::Array(files)) => {
+            for file in files {
+                match file {
+                    serde_json::Value::String(path) => *path = resolve(checkout, base, path)?,
+                    serde_json::Value::Object(entry) => {
+                        if let Some(path) = entry.get("path").and_then(|p| p.as_str()) {
+                            let path = resolve(checkout, base, path)?;
+                            entry.insert("path".into(), path.into());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        _ => {}
+    }
+    let volumes = service.get_mut("volumes").and_then(|v| v.as_array_mut());
+    for volume in volumes.into_iter().flatten() {
+        match volume {
+            // Short syntax: sources starting with `.`, `/` or `~` are
+            // paths, others name volumes.
+            serde_json::Value::String(spec) if spec.starts_with(['.', '/', '~']) => {
+                let (source, target) = spec.split_once(':').unwrap_or((spec.as_str(), ""));
+                let source = resolve(checkout, base, source)?;
+                *spec = if target.is_empty() {
+                    source
+                } else {
+                    format!("{source}:{target}")
+                };
+            }
+            serde_json::Value::Object(mount) => match mount.get("type").and_then(|t| t.as_str()) {
+                Some("bind") => {
+                    if let Some(source) = mount.get("source").and_then(|s| s.as_str()) {
+                        let source = resolve(checkout, base, source)?;
+                        mount.insert("source".into(), source.into());
+                    }
+                }
+                Some("volume" | "tmpfs" | "image") | None => {}
+                Some(other) => anyhow::bail!("volumes: {other} mounts are not allowed"),
+            },
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Run the provisioner forever, unless provisioning is disabled. Spawned
+/// as a background tokio task.
+pub async fn run_provisioner(pool: Arc<DieselPool>, config: CiConfig) {
+    let Some(env_dir) = config.env_dir.clone() else {
+        return;
+    };
+    tracing::info!(env_dir = %env_dir, "Environment provisioner started");
+
+    let mut followers = HashMap::new();
+    let mut probed = HashMap::new();
+    let mut interval = tokio::time::interval(PROVISION_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = reconcile(&pool, &config, &env_dir, &mut followers, &mut probed).await {
+            tracing::error!("Environment provisioner error: {e}");
+        }
+    }
+}
+
+/// Provision each requested environment with a directory in `env_dir`
+/// that the quotas admit, keep running ones started with their logs
+/// followed and probed, restart unhealthy ones, stop dormant ones, and
+/// tear down those of destroyed ones. `followers` holds the environments
+/// this server started, `probed` when each was last probed. Environments
+/// are provisioned one at a time, so one still `creating` was interrupted
+/// by a restart and is brought up again, ahead of new requests.
+async fn reconcile(
+    pool: &DieselPool,
+    config: &CiConfig,
+    env_dir: &str,
+    followers: &mut HashMap<i64, JoinHandle<()>>,
+    probed: &mut HashMap<i64, Instant>,
+) -> anyhow::Result<()> {
+    let probe_interval = Duration::from_secs(config.env_health_interval_secs);
+    let mut entries = match tokio::fs::read_dir(env_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut env_ids = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(env_id) = entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.parse::<i64>().ok())
+        {
+            env_ids.push(env_id);
+        }
+    }
+    env_ids.sort_unstable();
+
+    let mut conn = crate::db::system_conn(pool).await?;
+    let mut pending = Vec::new();
+    for &env_id in &env_ids {
+        let dir = env_path(env_dir, env_id);
+        match environment_service::get_environment(&mut conn, env_id).await? {
+            Some(env) if env.status == "requested" || env.status == "creating" => {
+                if tokio::fs::try_exists(dir.join(COMPOSE_FILE))
+                    .await
+                    .unwrap_or(false)
+                {
+                    pending.push(env);
+                }
+            }
+            Some(env) if env.status == "running" => {
+                match followers.get(&env_id) {
+                    Some(follower) if !follower.is_finished() => {}
+                    Some(_) => {
+                        followers.insert(env_id, follow_logs(config, &dir, env_id));
+                    }
+                    None => {
+                        if !resume(config, &dir, env_id).await {
+                            continue;
+                        }
+                        followers.insert(env_id, follow_logs(config, &dir, env_id));
+                    }
+                }
+                if !probed
+                    .get(&env_id)
+                    .is_some_and(|at| at.elapsed() < probe_interval)
+                {
+                    probed.insert(env_id, Instant::now());
+                    if let Err(e) = check_health(config, &dir, &env, PROBE_WINDOW).await {
+                        tracing::warn!(env_id, "Environment unhealthy: {e}");
+                        environment_service::mark_unhealthy(&mut conn, &env, &e).await?;
+                    }
+                }
+                continue;
+            }
+            Some(env) if env.status == "unhealthy" => {
+                heal(&mut conn, config, &env, &dir).await?;
+                continue;
+            }
+            Some(env) if env.status == "dormant" => {
+                if let Some(follower) = followers.remove(&env_id) {
+                    follower.abort();
+                    suspend(config, &dir, env_id).await;
+                }
+            }
+            Some(env) if env.status != "destroyed" => {}
+            _ => teardown(config, &dir, env_id).await,
+        }
+        if let Some(follower) = followers.remove(&env_id) {
+            follower.abort();
+        }
+        probed.remove(&env_id);
+    }
+
+    pending.sort_by_key(|env| (env.status != "creating", env.id));
+    for env in pending {
+        if env.status == "requested"
+            && !admit(&mut conn, config, env_dir, &env, &env_ids, followers).await?
+        {
+            tracing::debug!(env_id = env.id, "Environment waiting for capacity");
+            continue;
+        }
+        provision(&mut conn, config, &env, &env_path(env_dir, env.id)).await?;
+    }
+    Ok(())
+}
+
+/// Whether the quotas leave room to bring up requested `env`. At the
+/// running limit, the least recently active running environment among
+/// `local`, those of this server, goes dormant to make room.
+async fn admit(
+    conn: &mut AsyncPgConnection,
+    config: &CiConfig,
+    env_dir: &str,
+    env: &CiEnvironment,
+    local: &[i64],
+    followers: &mut HashMap<i64, JoinHandle<()>>,
+) -> anyhow::Result<bool> {
+    let provisioned = environment_service::count_provisioned(conn, None).await?;
+    if provisioned >= config.max_envs_global as i64 {
+        return Ok(false);
+    }
+    let of_pr =
+        environment_service::count_provisioned(conn, Some((env.project_id, env.pr_number))).await?;
+    if of_pr >= config.max_envs_per_pr as i64 {
+        return Ok(false);
+    }
+    if environment_service::count_running(conn).await? < config.max_running_envs as i64 {
+        return Ok(true);
+    }
+
+    let Some(idle) = environment_service::least_recently_active(conn, local).await? else {
+        return Ok(false);
+    };
+    if let Some(follower) = followers.remove(&idle.id) {
+        follower.abort();
+    }
+    if !suspend(config, &env_path(env_dir, idle.id), idle.id).await {
+        return Ok(false);
+    }
+    environment_service::mark_dormant(conn, &idle).await?;
+    tracing::info!(
+        env_id = idle.id,
+        queued_env_id = env.id,
+        "Environment made dormant to make room"
+    );
+    Ok(true)
+}
+
+/// Start a running environment's containers, which stay stopped while it
+/// is dormant. A no-op for started ones.
+async fn resume(config: &CiConfig, dir: &Path, env_id: i64) -> bool {
+    let mut start = compose(config, dir, env_id);
+    start.arg("start");
+    match run(start, COMMAND_TIMEOUT).await {
+        Ok(_) => true,
+        Err(e) => {
+            tracing::warn!(env_id, "Environment not started: {e}");
+            false
+        }
+    }
+}
+
+/// Stop a dormant environment's containers, keeping them and their data.
+async fn suspend(config: &CiConfig, dir: &Path, env_id: i64) -> bool {
+    let mut stop = compose(config, dir, env_id);
+    stop.arg("stop");
+    match run(stop, COMMAND_TIMEOUT).await {
+        Ok(_) => true,
+        Err(e) => {
+            tracing::warn!(env_id, "Environment not stopped: {e}");
+            false
+        }
+    }
+}
+
+/// Capture a running environment's logs until its containers stop.
+fn follow_logs(config: &CiConfig, dir: &Path, env_id: i64) -> JoinHandle<()> {
+    let mut logs = compose(config, dir, env_id);
+    logs.args(["logs", "--follow", "--no-color", "--timestamps"]);
+    let dir = dir.to_path_buf();
+    tokio::spawn(async move {
+        if let Err(e) = environment_logs::capture(logs, &dir).await {
+            tracing::warn!(env_id, "Environment logs not captured: {e}");
+        }
+    })
+}
+
+/// Bring an environment up and record it running, or failed. A failed
+/// environment's containers are removed; its directory stays until it is
+/// destroyed.
+async fn provision(
+    conn: &mut AsyncPgConnection,
+    config: &CiConfig,
+    env: &CiEnvironment,
+    dir: &Path,
+) -> anyhow::Result<()> {
+    let Some(url) = environment_allocator::url(env, config) else {
+        environment_service::mark_failed(conn, env, "no port allocated").await?;
+        return Ok(());
+    };
+    let env = if env.status == "requested" {
+        match environment_service::begin_creating(conn, env, &url).await? {
+            Some(env) => env,
+            None => return Ok(()),
+        }
+    } else {
+        env.clone()
+    };
+    tracing::info!(env_id = env.id, url = %url, "Provisioning environment");
+
+    let mut up = compose(config, dir, env.id);
+    up.args(["up", "--detach", "--build", "--wait"]);
+    let started = match run(up, UP_TIMEOUT).await {
+        Ok(_) => check_health(config, dir, &env, HEALTH_TIMEOUT).await,
+        Err(e) => Err(e),
+    };
+    match started {
+        Ok(()) => {
+            environment_service::mark_running(conn, &env, &url, config).await?;
+            tracing::info!(env_id = env.id, url = %url, "Environment running");
+        }
+        Err(e) => {
+            tracing::warn!(env_id = env.id, "Environment provisioning failed: {e}");
+            fail(conn, config, &env, dir, &e).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Restart an unhealthy environment and record whether that made it pass
+/// its health check. One restarted `env_max_restarts` times fails.
+async fn heal(
+    conn: &mut AsyncPgConnection,
+    config: &CiConfig,
+    env: &CiEnvironment,
+    dir: &Path,
+) -> anyhow::Result<()> {
+    if env.restart_count >= config.env_max_restarts {
+        let error = format!(
+            "unhealthy after {} restarts: {}",
+            env.restart_count,
+            env.error.as_deref().unwrap_or("health check failed"),
+        );
+        tracing::warn!(env_id = env.id, "{error}");
+        return fail(conn, config, env, dir, &error).await;
+    }
+
+    let mut restart = compose(config, dir, env.id);
+    restart.arg("restart");
+    if let Err(e) = run(restart, COMMAND_TIMEOUT).await {
+        tracing::warn!(env_id = env.id, "Environment not restarted: {e}");
+    }
+    let Some(env) = environment_service::record_restart(conn, env).await? else {
+        return Ok(());
+    };
+    match check_health(config, dir, &env, HEALTH_TIMEOUT).await {
+        Ok(()) => {
+            environment_service::mark_recovered(conn, &env).await?;
+            tracing::info!(
+                env_id = env.id,
+                restarts = env.restart_count,
+                "Environment recovered"
+            );
+        }
+        Err(e) => {
+            tracing::warn!(
+                env_id = env.id,
+                restarts = env.restart_count,
+                "Environment still unhealthy: {e}"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Record an environment failed with `error` and remove its containers,
+/// capturing their logs first. Its directory stays until it is destroyed.
+async fn fail(
+    conn: &mut AsyncPgConnection,
+    config: &CiConfig,
+    env: &CiEnvironment,
+    dir: &Path,
+    error: &str,
+) -> anyhow::Result<()> {
+    environment_service::mark_failed(conn, env, error).await?;
+    let mut logs = compose(config, dir, env.id);
+    logs.args(["logs", "--no-color", "--timestamps"]);
+    if let Err(e) = tokio::time::timeout(COMMAND_TIMEOUT, environment_logs::capture(logs, dir))
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("compose logs timed out")))
+    {
+        tracing::warn!(env_id = env.id, "Failed environment logs not captured: {e}");
+    }
+    let mut down = compose(config, dir, env.id);
+    down.args(["down", "--remove-orphans"]);
+    if let Err(e) = run(down, COMMAND_TIMEOUT).await {
+        tracing::warn!(env_id = env.id, "Failed environment not stopped: {e}");
+    }
+    Ok(())
+}
+
+/// The provisioner's settings in an environment's compose file.
+async fn extension(dir: &Path) -> Result<serde_json::Value, String> {
+    let compose = tokio::fs::read_to_string(dir.join(COMPOSE_FILE))
+        .await
+        .map_err(|e| format!("cannot read {COMPOSE_FILE}: {e}"))?;
+    let mut compose: serde_json::Value =
+        serde_yaml::from_str(&compose).map_err(|e| format!("cannot parse {COMPOSE_FILE}: {e}"))?;
+    Ok(compose[EXTENSION_KEY].take())
+}
+
+/// Dump the database of running `env` as snapshot `name` of its project,
+/// replacing an older one. Returns the snapshot's size in bytes.
+pub async fn snapshot(config: &CiConfig, env: &CiEnvironment, name: &str) -> anyhow::Result<u64> {
+    let env_dir = config
+        .env_dir
+        .as_deref()
+        .context("provisioning is disabled")?;
+    let snapshot_dir = config
+        .env_snapshot_dir
+        .as_deref()
+        .context("snapshots are not enabled")?;
+    let dir = env_path(env_dir, env.id);
+    let extension = extension(&dir).await.map_err(anyhow::Error::msg)?;
+    let service = extension["db_service"]
+        .as_str()
+        .unwrap_or(DEFAULT_DATABASE_SERVICE);
+
+    let path = environment_snapshot::path(snapshot_dir, env.project_id, name);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let partial = path.with_extension("sql.partial");
+    let file = std::fs::File::create(&partial)?;
+    let mut dump = compose(config, &dir, env.id);
+    dump.args([
+        "exec",
+        "-T",
+        service,
+        "sh",
+        "-c",
+        environment_snapshot::DUMP_COMMAND,
+    ])
+    .stdout(file);
+    if let Err(e) = execute(dump, SNAPSHOT_TIMEOUT).await {
+        let _ = tokio::fs::remove_file(&partial).await;
+        anyhow::bail!("cannot dump database: {e}");
+    }
+    tokio::fs::rename(&partial, &path).await?;
+    Ok(tokio::fs::metadata(&path).await?.len())
+}
+
+/// Wait up to `within` for a started environment to answer its template's
+/// health check, if it has one, at its port on the environment host.
+async fn check_health(
+    config: &CiConfig,
+    dir: &Path,
+    env: &CiEnvironment,
+    within: Duration,
+) -> Result<(), String> {
+    let extension = extension(dir).await?;
+    let Some(path) = extension["health_url"].as_str() else {
+        return Ok(());
+    };
+    let port = env.port.ok_or("no port allocated")?;
+    let url = format!("http://{}:{port}{path}", config.env_host);
+    let client = reqwest::Client::builder()
+        .timeout(HEALTH_INTERVAL * 5)
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let deadline = Instant::now() + within;
+    loop {
+        let outcome = match client.get(&url).send().await {
+            Ok(resp) if resp.status().is_success() => return Ok(()),
+            Ok(resp) => resp.status().to_string(),
+            Err(e) => e.to_string(),
+        };
+        if Instant::now() >= deadline {
+            return Err(format!(
+                "health check {path} failed for {}s: {outcome}",
+                within.as_secs()
+            ));
+        }
+        tokio::time::sleep(HEALTH_INTERVAL).await;
+    }
+}
+
+/// Remove an environment's containers, volumes and built images, then its
+/// directory. A failed `down` is retried on the next pass.
+async fn teardown(config: &CiConfig, dir: &Path, env_id: i64) {
+    if tokio::fs::try_exists(dir.join(COMPOSE_FILE))
+        .await
+        .unwrap_or(false)
+    {
+        let mut down = compose(config, dir, env_id);
+        down.args(["down", "--volumes", "--remove-orphans", "--rmi", "local"]);
+        if let Err(e) = run(down, COMMAND_TIMEOUT).await {
+            tracing::warn!(env_id, "Environment not torn down: {e}");
+            return;
+        }
+    }
+    match tokio::fs::remove_dir_all(dir).await {
+        Ok(()) => tracing::info!(env_id, "Environment torn down"),
+        Err(e) => tracing::warn!(env_id, "Environment directory not removed: {e}"),
+    }
+}
+
+/// `compose` of the configured container runtime, on an environment's
+/// project.
+fn compose(config: &CiConfig, dir: &Path, env_id: i64) -> Command {
+    let mut cmd = Command::new(&config.container_runtime);
+    cmd.env_clear();
+    for name in COMPOSE_HOST_ENV {
+        if let Some(value) = std::env::var_os(name) {
+            cmd.env(name, value);
+        }
+    }
+    cmd.arg("compose")
+        .args([
+            "--project-name",
+            &project_name(env_id),
+            "--file",
+            COMPOSE_FILE,
+        ])
+        .current_dir(dir)
+        .kill_on_drop(true);
+    cmd
+}
+
+/// Run a compose command, returning its stdout, or the tail of its stderr
+/// when it fails.
+async fn run(mut cmd: Command, timeout: Duration) -> Result<String, String> {
+    cmd.stdout(Stdio::piped());
+    execute(cmd, timeout).await
+}
+
+/// Run a compose command whose stdout is already directed, returning the
+/// stdout captured if any, or the tail of its stderr when it fails.
+async fn execute(mut cmd: Command, timeout: Duration) -> Result<String, String> {
+    let child = cmd
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("cannot run compose: {e}"))?;
+    let output = match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => return Err(format!("cannot run compose: {e}")),
+        Err(_) => return Err(format!("compose timed out after {}s", timeout.as_secs())),
+    };
+    if output.status.success() {
+        return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stderr = stderr.trim();
+    if stderr.is_empty() {
+        return Err(format!("compose exited with {}", output.status));
+    }
+    let mut start = stderr.len().saturating_sub(MAX_ERROR_BYTES);
+    while !stderr.is_char_boundary(start) {
+        start += 1;
+    }
+    Err(stderr[start..].to_string())
+}
@@ -0,0 +1,384 @@
+//! Daily and weekly CI digests.
+//!
+//! A notification channel with a `digest` of `daily` or `weekly` gets one
+//! message per project it covers, summing up the day or week before:
+//!
+//! - success rate of the finished builds (failure and timeout count as
+//!   failed, cancelled builds not at all)
+//! - the slowest builds
+//! - errors first seen in the period, most frequent first
+//! - flaky steps: steps that failed, then passed on a retry in the same
+//!   build, by how many builds they did it in
+//!
+//! Daily digests go out at `CI_DIGEST_HOUR` (UTC), weekly ones at that hour
+//! on Mondays; a project with no finished build and no new error in the
+//! period is left out. A digest channel without `events` gets only its
+//! digests, not the per-build messages they stand in for.
+//!
+//! Each period is claimed on the channel (`last_digest_at`) in the
+//! transaction queueing its messages, so of several servers one sends it.
+
+use std::fmt::Write;
+use std::sync::Arc;
+
+use chrono::{DateTime, Datelike, Duration, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Text, Timestamptz};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+
+use erp_core::db::diesel_pool::DieselPool;
+
+use crate::models::notification::CiNotificationChannel;
+use crate::schema::{ci_builds, ci_errors, ci_notification_channels, ci_projects};
+use crate::services::chat_notifier::{self, Message};
+use crate::services::outbox_service::{self, OutboxMessage};
+
+/// How often channels are checked for a due digest.
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// Digest periods of a channel.
+pub const PERIODS: &[&str] = &["daily", "weekly"];
+
+/// Slowest builds, new errors and flaky steps listed in a digest.
+const TOP: i64 = 5;
+
+/// A project's digest of one period.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Digest {
+    /// `daily` or `weekly`.
+    pub period: String,
+    pub since: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+    pub succeeded: i64,
+    pub failed: i64,
+    pub slowest: Vec<SlowBuild>,
+    /// Errors first seen in the period.
+    pub new_errors: i64,
+    pub top_errors: Vec<NewError>,
+    pub flaky_steps: Vec<FlakyStep>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SlowBuild {
+    pub id: i64,
+    pub branch: String,
+    pub status: String,
+    pub duration_ms: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewError {
+    pub id: i64,
+    pub title: String,
+    pub occurrence_count: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, QueryableByName)]
+pub struct FlakyStep {
+    #[diesel(sql_type = Text)]
+    pub name: String,
+    /// Builds the step passed in only after a retry.
+    #[diesel(sql_type = BigInt)]
+    pub builds: i64,
+}
+
+impl Digest {
+    /// Percent of the finished builds that succeeded; `None` without any.
+    pub fn success_rate(&self) -> Option<f64> {
+        let finished = self.succeeded + self.failed;
+        (finished > 0).then(|| 100.0 * self.succeeded as f64 / finished as f64)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.succeeded + self.failed == 0 && self.new_errors == 0
+    }
+}
+
+/// Length of a digest period.
+fn length(period: &str) -> Duration {
+    match period {
+        "weekly" => Duration::days(7),
+        _ => Duration::days(1),
+    }
+}
+
+/// End of the latest `period` that ended by `now`, digests being sent at
+/// `hour`.
+pub fn period_end(period: &str, hour: u32, now: DateTime<Utc>) -> DateTime<Utc> {
+    let today = now
+        .date_naive()
+        .and_hms_opt(hour, 0, 0)
+        .unwrap_or_default()
+        .and_utc();
+    let end = if today <= now {
+        today
+    } else {
+        today - Duration::days(1)
+    };
+    match period {
+        "weekly" => end - Duration::days(end.weekday().num_days_from_monday() as i64),
+        _ => end,
+    }
+}
+
+/// Run the scheduler forever. Spawned as a background tokio task.
+pub async fn run_digest_scheduler(pool: Arc<DieselPool>, hour: u32) {
+    tracing::info!(hour, "Digest scheduler started");
+
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = send_due(&pool, hour).await {
+            tracing::error!("Digest error: {e}");
+        }
+    }
+}
+
+async fn send_due(pool: &DieselPool, hour: u32) -> anyhow::Result<()> {
+    let mut conn = crate::db::system_conn(pool).await?;
+
+    let channels: Vec<CiNotificationChannel> = ci_notification_channels::table
+        .filter(ci_notification_channels::active.eq(true))
+        .filter(ci_notification_channels::digest.is_not_null())
+        .order(ci_notification_channels::id.asc())
+        .load(&mut conn)
+        .await?;
+
+    let now = Utc::now();
+    for channel in channels {
+        let Some(period) = channel.digest.clone() else {
+            continue;
+        };
+        let until = period_end(&period, hour, now);
+        if channel
+            .last_digest_at
+            .or(channel.create_date)
+            .is_some_and(|at| at >= until)
+        {
+            continue;
+        }
+        let sent = conn
+            .transaction::<_, anyhow::Error, _>(|conn| {
+                async move { send(conn, &channel, &period, until).await }.scope_boxed()
+            })
+            .await?;
+        if sent > 0 {
+            tracing::info!(period, sent, "Digests queued");
+        }
+    }
+    Ok(())
+}
+
+/// Claim the period ending `until` on `channel` and queue its digests.
+/// Returns how many were queued.
+async fn send(
+    conn: &mut AsyncPgConnection,
+    channel: &CiNotificationChannel,
+    period: &str,
+    until: DateTime<Utc>,
+) -> anyhow::Result<usize> {
+    let claimed = diesel::sql_query(
+        "UPDATE ci_notification_channels SET last_digest_at = NOW() \
+         WHERE id = $1 AND COALESCE(last_digest_at, create_date, '-infinity') < $2",
+    )
+    .bind::<BigInt, _>(channel.id)
+    .bind::<Timestamptz, _>(until)
+    .execute(conn)
+    .await?;
+    if claimed == 0 {
+        return Ok(0);
+    }
+
+    let projects: Vec<i64> = match channel.project_id {
+        Some(project_id) => vec![project_id],
+        None => {
+            ci_projects::table
+                .filter(ci_projects::tenant_id.eq(channel.tenant_id))
+                .filter(ci_projects::active.eq(true))
+                .order(ci_projects::id.asc())
+                .select(ci_projects::id)
+                .load(conn)
+                .await?
+        }
+    };
+
+    let mut sent = 0;
+    for project_id in projects {
+        let digest = compile(conn, project_id, period, until - length(period), until).await?;
+        if digest.is_empty() {
+            continue;
+        }
+        outbox_service::enqueue(
+            conn,
+            channel.tenant_id,
+            &OutboxMessage::ChatNotification {
+                channel_id: channel.id,
+                event: format!("digest.{period}"),
+                project_id,
+                data: serde_json::to_value(&digest)?,
+            },
+        )
+        .await?;
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+/// Digest of `project_id` over `since..until`.
+pub async fn compile(
+    conn: &mut AsyncPgConnection,
+    project_id: i64,
+    period: &str,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> anyhow::Result<Digest> {
+    let finished = ci_builds::table
+        .filter(ci_builds::project_id.eq(project_id))
+        .filter(ci_builds::finished_at.ge(since))
+        .filter(ci_builds::finished_at.lt(until));
+
+    let outcomes: Vec<String> = finished
+        .clone()
+        .filter(ci_builds::status.eq_any(["success", "failure", "timeout"]))
+        .select(ci_builds::status)
+        .load(conn)
+        .await?;
+    let succeeded = outcomes.iter().filter(|s| *s == "success").count() as i64;
+
+    let slowest: Vec<(i64, String, String, Option<i32>)> = finished
+        .filter(ci_builds::duration_ms.is_not_null())
+        .order(ci_builds::duration_ms.desc())
+        .limit(TOP)
+        .select((
+            ci_builds::id,
+            ci_builds::branch,
+            ci_builds::status,
+            ci_builds::duration_ms,
+        ))
+        .load(conn)
+        .await?;
+
+    let new = ci_errors::table
+        .filter(ci_errors::project_id.eq(project_id))
+        .filter(ci_errors::active.eq(true))
+        .filter(ci_errors::first_seen_at.ge(since))
+        .filter(ci_errors::first_seen_at.lt(until));
+    let new_errors: i64 = new.clone().count().get_result(conn).await?;
+    let top_errors: Vec<(i64, String, i32)> = new
+        .order((ci_errors::occurrence_count.desc(), ci_errors::id.asc()))
+        .limit(TOP)
+        .select((ci_errors::id, ci_errors::title, ci_errors::occurrence_count))
+        .load(conn)
+        .await?;
+
+    // Retries follow failures only, so a passing retry is a flaky pass.
+    let flaky_steps: Vec<FlakyStep> = diesel::sql_query(
+        "SELECT s.name, COUNT(DISTINCT s.build_id) AS builds \
+         FROM ci_build_steps s JOIN ci_builds b ON b.id = s.build_id \
+         WHERE b.project_id = $1 AND b.finished_at >= $2 AND b.finished_at < $3 \
+           AND s.attempt > 1 AND s.status = 'success' \
+         GROUP BY s.name ORDER BY builds DESC, s.name LIMIT $4",
+    )
+    .bind::<BigInt, _>(project_id)
+    .bind::<Timestamptz, _>(since)
+    .bind::<Timestamptz, _>(until)
+    .bind::<BigInt, _>(TOP)
+    .load(conn)
+    .await?;
+
+    Ok(Digest {
+        period: period.to_string(),
+        since,
+        until,
+        succeeded,
+        failed: outcomes.len() as i64 - succeeded,
+        slowest: slowest
+            .into_iter()
+            .map(|(id, branch, status, duration_ms)| SlowBuild {
+                id,
+                branch,
+                status,
+                duration_ms: duration_ms.unwrap_or_default(),
+            })
+            .collect(),
+        new_errors,
+        top_errors: top_errors
+            .into_iter()
+            .map(|(id, title, occurrence_count)| NewError {
+                id,
+                title,
+                occurrence_count,
+            })
+            .collect(),
+        flaky_steps,
+    })
+}
+
+fn minutes_seconds(ms: i32) -> String {
+    format!("{}m {:02}s", ms / 60_000, ms / 1000 % 60)
+}
+
+/// Chat message of a project's digest.
+pub fn message(dashboard_url: &str, project: &str, digest: &Digest) -> Message {
+    let mut text = String::new();
+    let _ = writeln!(
+        text,
+        "{} – {}",
+        digest.since.format("%Y-%m-%d"),
+        (digest.until - Duration::days(1)).format("%Y-%m-%d"),
+    );
+    let _ = match digest.success_rate() {
+        Some(rate) => writeln!(
+            text,
+            "**{rate:.0}%** of {} builds passed, {} failed",
+            digest.succeeded + digest.failed,
+            digest.failed,
+        ),
+        None => writeln!(text, "No builds finished"),
+    };
+
+    if !digest.slowest.is_empty() {
+        let _ = writeln!(text, "\n**Slowest builds**");
+        for build in &digest.slowest {
+            let _ = writeln!(
+                text,
+                "- [#{id}]({dashboard_url}/api/builds/{id}) on {} ({}): {}",
+                build.branch,
+                build.status,
+                minutes_seconds(build.duration_ms),
+                id = build.id,
+            );
+        }
+    }
+    if digest.new_errors > 0 {
+        let _ = writeln!(text, "\n**{} new errors**", digest.new_errors);
+        for error in &digest.top_errors {
+            let _ = writeln!(
+                text,
+                "- [{}]({dashboard_url}/api/errors/{}) ×{}",
+                error.title, error.id, error.occurrence_count,
+            );
+        }
+    }
+    if !digest.flaky_steps.is_empty() {
+        let _ = writeln!(text, "\n**Flaky steps** (passed on a retry)");
+        for step in &digest.flaky_steps {
+            let _ = writeln!(text, "- `{}` in {} builds", step.name, step.builds);
+        }
+    }
+
+    let color = match digest.success_rate() {
+        Some(rate) if rate >= 90.0 => chat_notifier::GREEN,
+        Some(rate) if rate >= 70.0 => chat_notifier::YELLOW,
+        Some(_) => chat_notifier::RED,
+        None => chat_notifier::GREY,
+    };
+    Message {
+        title: format!("{project}: {} CI digest", digest.period),
+        url: None,
+        text,
+        color,
+    }
+}
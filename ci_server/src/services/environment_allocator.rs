@@ -0,0 +1,138 @@
+//! Ports and subdomains of review environments.
+//!
+//! Each environment's app is published on a host port from the
+//! `CI_ENV_PORTS` pool (`20000-20999` by default). With a wildcard domain
+//! configured (`CI_ENV_DOMAIN`), it also gets a subdomain named after the
+//! repository and PR, e.g. `web-pr-12`; a second live environment of the
+//! PR gets `web-pr-12-2`. Assignments are stored in `ci_environments.port`
+//! and `.subdomain`, unique among the environments holding one, and
+//! released when the environment is destroyed, so the next environment of
+//! a PR takes over its predecessor's address.
+//!
+//! An environment's URL is `https://{subdomain}.{domain}` with a domain,
+//! for a reverse proxy behind the wildcard DNS record to route to
+//! `{env_host}:{port}`, and `http://{env_host}:{port}` without.
+
+use std::collections::HashSet;
+
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+
+use crate::config::CiConfig;
+use crate::models::environment::CiEnvironment;
+use crate::schema::ci_environments;
+
+/// Transaction-level advisory lock serializing allocations.
+const ALLOCATION_LOCK: i64 = 0x6369_656e_7661_6c6c;
+
+/// Longest repository part of a subdomain, leaving room for the PR number
+/// and a suffix within a DNS label's 63 characters.
+const MAX_REPO_SLUG: usize = 40;
+
+/// Rejected allocation.
+#[derive(Debug, thiserror::Error)]
+pub enum AllocationError {
+    #[error("no free port in {start}-{end}")]
+    PortsExhausted { start: u16, end: u16 },
+}
+
+/// Subdomain of PR `pr_number` of `repo` (`owner/name`) before any suffix.
+pub fn subdomain_base(repo: &str, pr_number: i32) -> String {
+    let name = repo.rsplit('/').next().unwrap_or(repo).to_lowercase();
+    let slug: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let slug: Vec<&str> = slug.split('-').filter(|part| !part.is_empty()).collect();
+    let mut slug = slug.join("-");
+    slug.truncate(MAX_REPO_SLUG);
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        format!("pr-{pr_number}")
+    } else {
+        format!("{slug}-pr-{pr_number}")
+    }
+}
+
+/// URL of an environment, from its allocation. `None` before it has one.
+pub fn url(env: &CiEnvironment, config: &CiConfig) -> Option<String> {
+    match (&env.subdomain, &config.env_domain) {
+        (Some(subdomain), Some(domain)) => Some(format!("https://{subdomain}.{domain}")),
+        _ => env
+            .port
+            .map(|port| format!("http://{}:{port}", config.env_host)),
+    }
+}
+
+/// Assign `env` the lowest free port of the pool and, with a wildcard
+/// domain, the first free subdomain for its PR of `repo`. Fails with
+/// [`AllocationError`] when the pool is used up.
+pub async fn allocate(
+    conn: &mut AsyncPgConnection,
+    env: &CiEnvironment,
+    repo: &str,
+    config: &CiConfig,
+) -> anyhow::Result<CiEnvironment> {
+    let (start, end) = (*config.env_ports.start(), *config.env_ports.end());
+    let base = config
+        .env_domain
+        .as_ref()
+        .map(|_| subdomain_base(repo, env.pr_number));
+    let env_id = env.id;
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        async move {
+            diesel::sql_query("SELECT pg_advisory_xact_lock($1)")
+                .bind::<BigInt, _>(ALLOCATION_LOCK)
+                .execute(conn)
+                .await?;
+
+            let taken: HashSet<i32> = ci_environments::table
+                .filter(ci_environments::port.between(i32::from(start), i32::from(end)))
+                .select(ci_environments::port)
+                .load::<Option<i32>>(conn)
+                .await?
+                .into_iter()
+                .flatten()
+                .collect();
+            let port = (start..=end)
+                .map(i32::from)
+                .find(|port| !taken.contains(port))
+                .ok_or(AllocationError::PortsExhausted { start, end })?;
+
+            let subdomain = match base {
+                Some(base) => {
+                    let taken: HashSet<String> = ci_environments::table
+                        .filter(
+                            ci_environments::subdomain
+                                .eq(&base)
+                                .or(ci_environments::subdomain.like(format!("{base}-%"))),
+                        )
+                        .select(ci_environments::subdomain)
+                        .load::<Option<String>>(conn)
+                        .await?
+                        .into_iter()
+                        .flatten()
+                        .collect();
+                    std::iter::once(base.clone())
+                        .chain((2..).map(|n| format!("{base}-{n}")))
+                        .find(|candidate| !taken.contains(candidate))
+                }
+                None => None,
+            };
+
+            let allocated = diesel::update(ci_environments::table.find(env_id))
+                .set((
+                    ci_environments::port.eq(port),
+                    ci_environments::subdomain.eq(subdomain),
+                    ci_environments::write_date.eq(chrono::Utc::now()),
+                ))
+                .get_result::<CiEnvironment>(conn)
+                .await?;
+            Ok(allocated)
+        }
+        .scope_boxed()
+    })
+    .await
+}
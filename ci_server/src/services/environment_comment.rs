@@ -0,0 +1,68 @@
+//! Review environment announcement posted on pull requests.
+//!
+//! When an environment of a PR starts running, one comment per PR gives
+//! its URL and when it expires if left idle, and links to the environment
+//! in the dashboard. Later environments of the PR edit the same comment
+//! (keyed [`STICKY_KEY`]). The URL is also reported as a deployment, so
+//! GitHub shows a "View deployment" button on the PR; Gitea gets a commit
+//! status linking to it instead.
+
+use std::fmt::Write;
+
+use chrono::{DateTime, Utc};
+
+/// Sticky comment key of the environment announcement.
+pub const STICKY_KEY: &str = "review-environment";
+
+/// A running environment, as the announcement describes it.
+#[derive(Debug)]
+pub struct Announcement<'a> {
+    pub env_id: i64,
+    pub url: &'a str,
+    pub commit_sha: &'a str,
+    pub idle_timeout_min: i32,
+    pub dormant_ttl_days: i64,
+    /// When the environment is removed if nobody uses it.
+    pub expires_at: DateTime<Utc>,
+    pub target_url: &'a str,
+}
+
+/// Deployment environment name of a PR's environments.
+pub fn deployment_environment(pr_number: i32) -> String {
+    format!("pr-{pr_number}")
+}
+
+/// One-line description for the deployment or commit status.
+pub fn description(announcement: &Announcement<'_>) -> String {
+    format!(
+        "Review environment ready, expires {} if idle",
+        announcement.expires_at.format("%Y-%m-%d %H:%M UTC"),
+    )
+}
+
+/// Markdown body of the announcement comment.
+pub fn render(announcement: &Announcement<'_>) -> String {
+    let end = announcement
+        .commit_sha
+        .char_indices()
+        .nth(7)
+        .map_or(announcement.commit_sha.len(), |(i, _)| i);
+    let short_sha = &announcement.commit_sha[..end];
+
+    let mut body = String::new();
+    let _ = writeln!(
+        body,
+        "### 🚀 Review environment ready\n\n**{url}**\n\nCommit `{short_sha}` · [environment #{id}]({target})\n",
+        url = announcement.url,
+        id = announcement.env_id,
+        target = announcement.target_url,
+    );
+    let _ = writeln!(
+        body,
+        "Expires **{}** if left idle: it goes dormant after {} minutes without activity and is removed {} days later.",
+        announcement.expires_at.format("%Y-%m-%d %H:%M UTC"),
+        announcement.idle_timeout_min,
+        announcement.dormant_ttl_days,
+    );
+    body
+}
@@ -0,0 +1,142 @@
+//! Runtime logs of review environments.
+//!
+//! While an environment runs, the provisioner follows its compose
+//! project's logs, the output of every container with timestamps, into
+//! `logs/containers.log` in the environment directory. At 10 MiB the file
+//! is rotated to `containers.log.1`, and the one before to
+//! `containers.log.2`. A follower that starts again, after the app
+//! restarted or the server did, resumes from the log's last write. The logs of an environment whose provisioning
+//! failed are captured once before its containers are removed.
+//!
+//! `GET /api/environments/{id}/logs?tail=` serves the last lines, from the
+//! server that provisions the environment. The logs go with the
+//! environment directory when it is torn down.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use anyhow::Context;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+
+/// Current log file, under `logs/` in the environment directory.
+const LOG_FILE: &str = "containers.log";
+
+/// Size at which the log file is rotated.
+const MAX_LOG_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Rotated log files kept besides the current one.
+const ROTATED_FILES: usize = 2;
+
+fn log_path(dir: &Path) -> PathBuf {
+    dir.join("logs").join(LOG_FILE)
+}
+
+fn rotated_path(path: &Path, generation: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{generation}"));
+    PathBuf::from(name)
+}
+
+/// An append-only log file rotated by size, at line boundaries.
+struct RotatingLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl RotatingLog {
+    async fn open(path: PathBuf) -> std::io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        let size = file.metadata().await?.len();
+        Ok(Self { path, file, size })
+    }
+
+    async fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > MAX_LOG_BYTES {
+            self.rotate().await?;
+        }
+        self.file.write_all(line).await?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    async fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush().await?;
+        for generation in (1..ROTATED_FILES).rev() {
+            let from = rotated_path(&self.path, generation);
+            match tokio::fs::rename(&from, rotated_path(&self.path, generation + 1)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        tokio::fs::rename(&self.path, rotated_path(&self.path, 1)).await?;
+        *self = Self::open(self.path.clone()).await?;
+        Ok(())
+    }
+}
+
+/// Append the output of `logs`, a `compose logs` command, to the log of
+/// the environment in `dir`, from the log's last write on. Returns when
+/// the command exits.
+pub async fn capture(mut logs: Command, dir: &Path) -> anyhow::Result<()> {
+    let path = log_path(dir);
+    if let Ok(modified) = tokio::fs::metadata(&path).await.and_then(|m| m.modified()) {
+        let since = chrono::DateTime::<chrono::Utc>::from(modified);
+        logs.args([
+            "--since",
+            &since.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        ]);
+    }
+    let mut log = RotatingLog::open(path).await?;
+
+    let mut child = logs
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("cannot run compose logs")?;
+    let stdout = child.stdout.take().context("compose logs has no stdout")?;
+    let mut reader = BufReader::new(stdout);
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line).await? > 0 {
+        if !line.ends_with(b"\n") {
+            line.push(b'\n');
+        }
+        log.write_line(&line).await?;
+        line.clear();
+    }
+    log.file.flush().await?;
+    child.wait().await?;
+    Ok(())
+}
+
+/// Recent log text of the environment in `dir`: the current file, after
+/// the last rotated one when the current file has fewer than `lines`
+/// lines. `None` before anything was captured.
+pub async fn recent(dir: &Path, lines: usize) -> std::io::Result<Option<String>> {
+    let path = log_path(dir);
+    let current = match tokio::fs::read(&path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let current = String::from_utf8_lossy(&current).into_owned();
+    if current.lines().count() >= lines {
+        return Ok(Some(current));
+    }
+    match tokio::fs::read(rotated_path(&path, 1)).await {
+        Ok(previous) => Ok(Some(
+            String::from_utf8_lossy(&previous).into_owned() + &current,
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Some(current)),
+        Err(e) => Err(e),
+    }
+}
@@ -1,10 +1,26 @@
 //! Ephemeral environment management (pluggable backends).
+//!
+//! Environments are provisioned by [`compose_provisioner`](super::compose_provisioner);
+//! this module records their lifecycle. Every transition is a
+//! [`CiEnvironmentEvent`] appended to the environment's stream in the
+//! event store, and the `ci_environments` row is the projection of that
+//! stream: [`apply`] checks an event against the folded state, appends it
+//! and projects the new state, and [`replay`] rebuilds a row from its
+//! events alone. [`history`] is the environment's audit trail.
 
 use diesel::prelude::*;
-use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
 
+use crate::config::CiConfig;
+use crate::events::environment::{CiEnvironmentAggregate, CiEnvironmentEvent};
+use crate::events::{store, Aggregate};
 use crate::models::environment::{CiEnvironment, NewCiEnvironment};
+use crate::models::event::CiEvent;
 use crate::schema::ci_environments;
+use crate::services::environment_comment::{self, Announcement};
+use crate::services::outbox_service::{self, OutboxMessage};
+use crate::services::{notification_service, project_service, quota_service};
 
 /// Count currently active (non-destroyed) environments.
 pub async fn count_active(conn: &mut AsyncPgConnection) -> anyhow::Result<i64> {
@@ -17,31 +33,426 @@ pub async fn count_active(conn: &mut AsyncPgConnection) -> anyhow::Result<i64> {
     Ok(count)
 }
 
-/// Create a new environment record.
+/// Create a new environment record, subject to the tenant's quota, and
+/// start its stream with [`CiEnvironmentEvent::EnvironmentRequested`].
 pub async fn create_environment(
     conn: &mut AsyncPgConnection,
     new_env: NewCiEnvironment,
 ) -> anyhow::Result<CiEnvironment> {
-    let result = diesel::insert_into(ci_environments::table)
-        .values(&new_env)
-        .get_result::<CiEnvironment>(conn)
+    quota_service::check_environment_quota(conn, new_env.tenant_id).await?;
+
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        async move {
+            let inserted = diesel::insert_into(ci_environments::table)
+                .values(&new_env)
+                .get_result::<CiEnvironment>(conn)
+                .await?;
+            let event = CiEnvironmentEvent::EnvironmentRequested {
+                project_id: inserted.project_id,
+                build_id: inserted.build_id,
+                pr_number: inserted.pr_number,
+                branch: inserted.branch.clone(),
+                commit_sha: inserted.commit_sha.clone(),
+            };
+            store::append::<CiEnvironmentAggregate>(conn, inserted.tenant_id, inserted.id, &event)
+                .await?;
+            let mut state = CiEnvironmentAggregate::default();
+            state.apply(&event);
+            let result = project(conn, inserted.id, &state, None).await?;
+            notification_service::notify(
+                conn,
+                result.tenant_id,
+                result.project_id,
+                "environment.created",
+                &result,
+            )
+            .await?;
+            Ok(result)
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Apply `event` to an environment: append it to the environment's stream
+/// and project the resulting state onto its row. Returns `None`, changing
+/// nothing, if the event is not a valid transition from the current state.
+pub async fn apply(
+    conn: &mut AsyncPgConnection,
+    env: &CiEnvironment,
+    event: CiEnvironmentEvent,
+) -> anyhow::Result<Option<CiEnvironment>> {
+    let (env_id, tenant_id) = (env.id, env.tenant_id);
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        async move {
+            // Transitions of one environment are serialized on its row.
+            let row: Option<CiEnvironment> = ci_environments::table
+                .find(env_id)
+                .for_update()
+                .first(conn)
+                .await
+                .optional()?;
+            let Some(row) = row else {
+                return Ok(None);
+            };
+            let (mut state, sequence) = store::load::<CiEnvironmentAggregate>(conn, env_id).await?;
+            if sequence == 0 {
+                // Rows from before environments were event-sourced: their
+                // stream starts from the current state.
+                state = CiEnvironmentAggregate {
+                    status: row.status,
+                    url: row.url,
+                    error: row.error,
+                    destroyed_reason: row.destroyed_reason,
+                    restart_count: row.restart_count,
+                };
+            }
+            if !event.applies_to(&state.status) {
+                return Ok(None);
+            }
+
+            store::append::<CiEnvironmentAggregate>(conn, tenant_id, env_id, &event).await?;
+            state.apply(&event);
+            let active_at = event.is_activity().then(chrono::Utc::now);
+            let updated = project(conn, env_id, &state, active_at).await?;
+            let notification = match event {
+                CiEnvironmentEvent::EnvironmentDestroyed { .. } => "environment.destroyed",
+                _ => "environment.updated",
+            };
+            notification_service::notify(
+                conn,
+                tenant_id,
+                updated.project_id,
+                notification,
+                &updated,
+            )
+            .await?;
+            Ok(Some(updated))
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Write an environment's folded state to its row. A destroyed
+/// environment's port and subdomain are released; `active_at` records
+/// activity.
+async fn project(
+    conn: &mut AsyncPgConnection,
+    env_id: i64,
+    state: &CiEnvironmentAggregate,
+    active_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> anyhow::Result<CiEnvironment> {
+    let release = (state.status == "destroyed").then_some((
+        ci_environments::port.eq(None::<i32>),
+        ci_environments::subdomain.eq(None::<String>),
+    ));
+    let row = diesel::update(ci_environments::table.find(env_id))
+        .set((
+            ci_environments::status.eq(&state.status),
+            ci_environments::url.eq(&state.url),
+            ci_environments::destroyed_reason.eq(&state.destroyed_reason),
+            ci_environments::error.eq(&state.error),
+            ci_environments::restart_count.eq(state.restart_count),
+            active_at.map(|at| ci_environments::last_activity.eq(at)),
+            release,
+            ci_environments::write_date.eq(chrono::Utc::now()),
+        ))
+        .get_result(conn)
         .await?;
-    Ok(result)
+    Ok(row)
 }
 
-/// Update environment status.
-pub async fn update_status(
+/// Rebuild an environment's row from its event stream. Returns `None` for
+/// an unknown environment; one without events is returned as it is.
+pub async fn replay(
     conn: &mut AsyncPgConnection,
     env_id: i64,
-    status: &str,
+) -> anyhow::Result<Option<CiEnvironment>> {
+    let (state, sequence) = store::load::<CiEnvironmentAggregate>(conn, env_id).await?;
+    if sequence == 0 {
+        return get_environment(conn, env_id).await;
+    }
+    let row = project(conn, env_id, &state, None).await?;
+    Ok(Some(row))
+}
+
+/// Claim a requested environment for provisioning at `url`. Returns
+/// `None` if it is no longer requested.
+pub async fn begin_creating(
+    conn: &mut AsyncPgConnection,
+    env: &CiEnvironment,
+    url: &str,
+) -> anyhow::Result<Option<CiEnvironment>> {
+    let event = CiEnvironmentEvent::EnvironmentCreating {
+        url: Some(url.to_string()),
+    };
+    apply(conn, env, event).await
+}
+
+/// Record a provisioned environment as running at `url`, and announce it
+/// on its PR with a comment and a deployment.
+pub async fn mark_running(
+    conn: &mut AsyncPgConnection,
+    env: &CiEnvironment,
+    url: &str,
+    config: &CiConfig,
+) -> anyhow::Result<Option<CiEnvironment>> {
+    let event = CiEnvironmentEvent::EnvironmentRunning {
+        url: url.to_string(),
+    };
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        async move {
+            let running = apply(conn, env, event).await?;
+            if let Some(ref running) = running {
+                announce(conn, running, config).await?;
+            }
+            Ok(running)
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Queue the PR comment and deployment of a running environment.
+async fn announce(
+    conn: &mut AsyncPgConnection,
+    env: &CiEnvironment,
+    config: &CiConfig,
 ) -> anyhow::Result<()> {
-    diesel::update(ci_environments::table.find(env_id))
-        .set(ci_environments::status.eq(status))
-        .execute(conn)
-        .await?;
+    let Some(ref url) = env.url else {
+        return Ok(());
+    };
+    let Some(project) = project_service::get_project(conn, env.project_id).await? else {
+        return Ok(());
+    };
+    let started = env.last_activity.unwrap_or_else(chrono::Utc::now);
+    let expires_at = started
+        + chrono::Duration::minutes(i64::from(env.idle_timeout_min))
+        + chrono::Duration::days(config.dormant_ttl_days);
+    let target_url = format!("{}/api/environments/{}", config.dashboard_url, env.id);
+    let announcement = Announcement {
+        env_id: env.id,
+        url,
+        commit_sha: &env.commit_sha,
+        idle_timeout_min: env.idle_timeout_min,
+        dormant_ttl_days: config.dormant_ttl_days,
+        expires_at,
+        target_url: &target_url,
+    };
+
+    outbox_service::enqueue(
+        conn,
+        env.tenant_id,
+        &OutboxMessage::GithubDeployment {
+            repo: project.github_repo.clone(),
+            sha: env.commit_sha.clone(),
+            environment: environment_comment::deployment_environment(env.pr_number),
+            environment_url: url.clone(),
+            description: environment_comment::description(&announcement),
+        }
+        .for_provider(project.provider()),
+    )
+    .await?;
+    outbox_service::enqueue(
+        conn,
+        env.tenant_id,
+        &OutboxMessage::GithubPrComment {
+            repo: project.github_repo.clone(),
+            pr_number: env.pr_number,
+            body: environment_comment::render(&announcement),
+            sticky: Some(environment_comment::STICKY_KEY.to_string()),
+        }
+        .for_provider(project.provider()),
+    )
+    .await?;
     Ok(())
 }
 
+/// Record that provisioning an environment failed with `error`.
+pub async fn mark_failed(
+    conn: &mut AsyncPgConnection,
+    env: &CiEnvironment,
+    error: &str,
+) -> anyhow::Result<Option<CiEnvironment>> {
+    let event = CiEnvironmentEvent::EnvironmentFailed {
+        error: error.to_string(),
+    };
+    apply(conn, env, event).await
+}
+
+/// Record that a running environment failed its health check with
+/// `error`.
+pub async fn mark_unhealthy(
+    conn: &mut AsyncPgConnection,
+    env: &CiEnvironment,
+    error: &str,
+) -> anyhow::Result<Option<CiEnvironment>> {
+    let event = CiEnvironmentEvent::EnvironmentUnhealthy {
+        error: error.to_string(),
+    };
+    apply(conn, env, event).await
+}
+
+/// Record a restart of an unhealthy environment.
+pub async fn record_restart(
+    conn: &mut AsyncPgConnection,
+    env: &CiEnvironment,
+) -> anyhow::Result<Option<CiEnvironment>> {
+    apply(conn, env, CiEnvironmentEvent::EnvironmentRestarted).await
+}
+
+/// Record that an unhealthy environment passed its health check again.
+pub async fn mark_recovered(
+    conn: &mut AsyncPgConnection,
+    env: &CiEnvironment,
+) -> anyhow::Result<Option<CiEnvironment>> {
+    apply(conn, env, CiEnvironmentEvent::EnvironmentRecovered).await
+}
+
+/// Record a running environment as dormant, its containers stopped.
+pub async fn mark_dormant(
+    conn: &mut AsyncPgConnection,
+    env: &CiEnvironment,
+) -> anyhow::Result<Option<CiEnvironment>> {
+    apply(conn, env, CiEnvironmentEvent::EnvironmentDormant).await
+}
+
+/// Count environments holding containers, those `creating`, `running`,
+/// `unhealthy` or `dormant`, optionally only those of PR `pr_number` of a
+/// project.
+pub async fn count_provisioned(
+    conn: &mut AsyncPgConnection,
+    pr: Option<(i64, i32)>,
+) -> anyhow::Result<i64> {
+    let mut query = ci_environments::table
+        .filter(ci_environments::status.eq_any(["creating", "running", "unhealthy", "dormant"]))
+        .filter(ci_environments::active.eq(true))
+        .into_boxed();
+    if let Some((project_id, pr_number)) = pr {
+        query = query
+            .filter(ci_environments::project_id.eq(project_id))
+            .filter(ci_environments::pr_number.eq(pr_number));
+    }
+    let count = query.count().get_result(conn).await?;
+    Ok(count)
+}
+
+/// Count environments running, healthy or not, or being brought up.
+pub async fn count_running(conn: &mut AsyncPgConnection) -> anyhow::Result<i64> {
+    let count = ci_environments::table
+        .filter(ci_environments::status.eq_any(["creating", "running", "unhealthy"]))
+        .filter(ci_environments::active.eq(true))
+        .count()
+        .get_result(conn)
+        .await?;
+    Ok(count)
+}
+
+/// The running environment among `env_ids` with the oldest activity.
+pub async fn least_recently_active(
+    conn: &mut AsyncPgConnection,
+    env_ids: &[i64],
+) -> anyhow::Result<Option<CiEnvironment>> {
+    let result = ci_environments::table
+        .filter(ci_environments::id.eq_any(env_ids))
+        .filter(ci_environments::status.eq("running"))
+        .filter(ci_environments::active.eq(true))
+        .order((
+            ci_environments::last_activity.asc().nulls_first(),
+            ci_environments::id.asc(),
+        ))
+        .first::<CiEnvironment>(conn)
+        .await
+        .optional()?;
+    Ok(result)
+}
+
+/// Rejected environment lifecycle action.
+#[derive(Debug, thiserror::Error)]
+pub enum EnvironmentError {
+    #[error("environment {id} is {status}, not dormant")]
+    NotDormant { id: i64, status: String },
+    #[error("{limit} environments are already running")]
+    AtCapacity { limit: usize },
+}
+
+/// Get an active environment by ID.
+pub async fn get_environment(
+    conn: &mut AsyncPgConnection,
+    env_id: i64,
+) -> anyhow::Result<Option<CiEnvironment>> {
+    let result = ci_environments::table
+        .find(env_id)
+        .filter(ci_environments::active.eq(true))
+        .first::<CiEnvironment>(conn)
+        .await
+        .optional()?;
+    Ok(result)
+}
+
+/// List environments, newest first, optionally of one project or PR.
+/// Destroyed ones are left out unless `include_destroyed`.
+pub async fn list(
+    conn: &mut AsyncPgConnection,
+    project_id: Option<i64>,
+    pr_number: Option<i32>,
+    include_destroyed: bool,
+) -> anyhow::Result<Vec<CiEnvironment>> {
+    let mut query = ci_environments::table
+        .filter(ci_environments::active.eq(true))
+        .into_boxed();
+    if let Some(project_id) = project_id {
+        query = query.filter(ci_environments::project_id.eq(project_id));
+    }
+    if let Some(pr_number) = pr_number {
+        query = query.filter(ci_environments::pr_number.eq(pr_number));
+    }
+    if !include_destroyed {
+        query = query.filter(ci_environments::status.ne("destroyed"));
+    }
+    let results = query
+        .order(ci_environments::id.desc())
+        .load::<CiEnvironment>(conn)
+        .await?;
+    Ok(results)
+}
+
+/// Wake a dormant environment, unless `max_running` environments are
+/// running or being brought up already. Fails with [`EnvironmentError`]
+/// otherwise. The provisioner starts its containers again.
+pub async fn wake(
+    conn: &mut AsyncPgConnection,
+    env: &CiEnvironment,
+    max_running: usize,
+) -> anyhow::Result<CiEnvironment> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        async move {
+            let running = count_running(conn).await?;
+            if running >= max_running as i64 {
+                return Err(EnvironmentError::AtCapacity { limit: max_running }.into());
+            }
+
+            apply(conn, env, CiEnvironmentEvent::EnvironmentWoken)
+                .await?
+                .ok_or_else(|| {
+                    EnvironmentError::NotDormant {
+                        id: env.id,
+                        status: env.status.clone(),
+                    }
+                    .into()
+                })
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// The lifecycle events of an environment, oldest first.
+pub async fn history(conn: &mut AsyncPgConnection, env_id: i64) -> anyhow::Result<Vec<CiEvent>> {
+    store::history(conn, CiEnvironmentAggregate::AGGREGATE_TYPE, env_id).await
+}
+
 /// List environments for a specific PR.
 pub async fn list_for_pr(
     conn: &mut AsyncPgConnection,
@@ -57,3 +468,17 @@ pub async fn list_for_pr(
         .await?;
     Ok(results)
 }
+
+/// Destroy an environment, recording `reason` (e.g. `pr_closed`) and
+/// releasing its port and subdomain. Returns `false` if it was already
+/// destroyed.
+pub async fn destroy(
+    conn: &mut AsyncPgConnection,
+    env: &CiEnvironment,
+    reason: &str,
+) -> anyhow::Result<bool> {
+    let event = CiEnvironmentEvent::EnvironmentDestroyed {
+        reason: reason.to_string(),
+    };
+    Ok(apply(conn, env, event).await?.is_some())
+}
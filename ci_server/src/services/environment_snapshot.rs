@@ -0,0 +1,76 @@
+//! Database snapshots and seeds of review environments.
+//!
+//! A snapshot is a plain `pg_dump` of an environment's database, kept per
+//! project as `{CI_ENV_SNAPSHOT_DIR}/{project_id}/{name}.sql`.
+//! `POST /api/environments/{id}/snapshot` takes one from a running
+//! environment, replacing an older snapshot of that name, so a curated
+//! preview's data can start the next ones. Any dump in that place works
+//! too, e.g. a nightly, scrubbed one of staging.
+//!
+//! An environment whose template names a snapshot or a seed script gets
+//! them copied into an init directory of its own, `db-init/`, mounted as
+//! its database service's `/docker-entrypoint-initdb.d`: the snapshot as
+//! `01-snapshot.sql`, the seed after it as `02-seed.*`. They run once,
+//! when the empty database is first initialized.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::services::compose_provisioner;
+use crate::services::environment_template::DatabaseTemplate;
+
+/// Shell command dumping a Postgres container's database to stdout, with
+/// the user and database the official image was configured with.
+pub const DUMP_COMMAND: &str = "pg_dump --no-owner --no-privileges --username \"$POSTGRES_USER\" \"${POSTGRES_DB:-$POSTGRES_USER}\"";
+
+/// Init directory in an environment directory.
+const INIT_DIR: &str = "db-init";
+
+/// Init script extensions the Postgres entrypoint runs.
+const SEED_EXTENSIONS: &[&str] = &[".sql.gz", ".sql", ".sh"];
+
+/// The extension of a seed script, if the Postgres entrypoint runs it.
+pub fn seed_extension(path: &str) -> Option<&'static str> {
+    SEED_EXTENSIONS
+        .iter()
+        .find(|ext| path.ends_with(**ext))
+        .copied()
+}
+
+/// File of snapshot `name` of a project.
+pub fn path(snapshot_dir: &str, project_id: i64, name: &str) -> PathBuf {
+    Path::new(snapshot_dir)
+        .join(project_id.to_string())
+        .join(format!("{name}.sql"))
+}
+
+/// Fill the init directory of the environment in `dir` from `database`,
+/// with the project's snapshot and the seed script of `checkout`, which
+/// must not lead out of it. Returns the directory's absolute path, to mount.
+pub async fn prepare_init(
+    dir: &Path,
+    checkout: &Path,
+    project_id: i64,
+    database: &DatabaseTemplate,
+    snapshot_dir: Option<&str>,
+) -> anyhow::Result<PathBuf> {
+    let init = dir.join(INIT_DIR);
+    tokio::fs::create_dir_all(&init).await?;
+    if let Some(ref name) = database.snapshot {
+        let snapshot_dir = snapshot_dir.context("snapshots are not enabled")?;
+        let snapshot = path(snapshot_dir, project_id, name);
+        tokio::fs::copy(&snapshot, init.join("01-snapshot.sql"))
+            .await
+            .with_context(|| format!("cannot load snapshot {name}"))?;
+    }
+    if let Some(ref seed) = database.seed {
+        let ext = seed_extension(seed).context("seed must be a .sql, .sql.gz or .sh file")?;
+        let source = compose_provisioner::confined(checkout, &checkout.join(seed))
+            .with_context(|| format!("cannot use seed {seed}"))?;
+        tokio::fs::copy(source, init.join(format!("02-seed{ext}")))
+            .await
+            .with_context(|| format!("cannot copy seed {seed}"))?;
+    }
+    Ok(tokio::fs::canonicalize(&init).await?)
+}
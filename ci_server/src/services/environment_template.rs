@@ -0,0 +1,149 @@
+//! Per-project shape of review environments.
+//!
+//! Without configuration a review environment is the repository's
+//! `Dockerfile` next to a Postgres of its own (see
+//! [`compose_provisioner`](super::compose_provisioner)). A pipeline's
+//! `environment` section, in the project's pipeline config, describes
+//! another shape:
+//!
+//! ```yaml
+//! environment:
+//!   compose_file: deploy/compose.review.yml
+//!   service: web
+//!   port: 3000
+//!   services: [web, worker]
+//!   health_url: /healthz
+//!   env: { RAILS_ENV: review }
+//!   database: { service: postgres, snapshot: staging, seed: db/seed.sql }
+//! ```
+//!
+//! `compose_file` is a compose file of the repository to run instead of the
+//! generated project. `service` (`app` by default) is the one reached at
+//! the environment's URL, listening on `port` (`CI_ENV_APP_PORT` by
+//! default); no other service publishes ports. `services` limits the
+//! project to those services and what they depend on. The environment
+//! only counts as running once `health_url`, a path on its URL, answers
+//! with a success status. `env` is set on every service.
+//!
+//! `database` fills the environment's fresh database before the app
+//! starts: with `snapshot`, a snapshot of the project (see
+//! [`environment_snapshot`](super::environment_snapshot)), then with
+//! `seed`, a `.sql`, `.sql.gz` or `.sh` script of the repository. Both run
+//! as init scripts of the database `service` (`db`, the generated one, by
+//! default), which must use the official Postgres image or one following
+//! its `/docker-entrypoint-initdb.d` convention.
+//!
+//! The section is a project setting: an in-repo pipeline file cannot set
+//! it, and only trusted builds get an environment.
+
+/// Service reached at an environment's URL without `service`.
+pub const DEFAULT_SERVICE: &str = "app";
+
+/// Database service without `database.service`, the generated one's.
+pub const DEFAULT_DATABASE_SERVICE: &str = "db";
+
+/// The `environment` section of a pipeline config.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvironmentTemplate {
+    /// Compose file relative to the repository root; `None` for the
+    /// generated project.
+    pub compose_file: Option<String>,
+    /// Service reached at the environment's URL.
+    pub service: Option<String>,
+    /// Port `service` listens on.
+    pub port: Option<u16>,
+    /// Services to run, with their dependencies; all when empty.
+    pub services: Vec<String>,
+    /// Path checked before the environment counts as running.
+    pub health_url: Option<String>,
+    /// Variables set on every service.
+    pub env: Vec<(String, String)>,
+    /// How the environment's database is filled.
+    pub database: DatabaseTemplate,
+}
+
+/// The `database` part of an environment template.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DatabaseTemplate {
+    /// Service running the database.
+    pub service: Option<String>,
+    /// Snapshot of the project loaded first.
+    pub snapshot: Option<String>,
+    /// Script of the repository run after the snapshot.
+    pub seed: Option<String>,
+}
+
+impl DatabaseTemplate {
+    /// The service running the database.
+    pub fn service(&self) -> &str {
+        self.service.as_deref().unwrap_or(DEFAULT_DATABASE_SERVICE)
+    }
+
+    /// Whether the database starts with anything but an empty schema.
+    pub fn is_seeded(&self) -> bool {
+        self.snapshot.is_some() || self.seed.is_some()
+    }
+}
+
+impl EnvironmentTemplate {
+    /// Parse the `environment` section of a pipeline config, ignoring
+    /// malformed fields.
+    pub fn from_pipeline(config: Option<&serde_json::Value>) -> Self {
+        let section = config.and_then(|c| c.get("environment"));
+        let string = |name: &str| {
+            section
+                .and_then(|s| s.get(name))
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string())
+        };
+        Self {
+            compose_file: string("compose_file"),
+            service: string("service"),
+            port: section
+                .and_then(|s| s.get("port"))
+                .and_then(|p| p.as_u64())
+                .and_then(|p| u16::try_from(p).ok())
+                .filter(|p| *p > 0),
+            services: section
+                .and_then(|s| s.get("services"))
+                .and_then(|s| s.as_array())
+                .map(|services| {
+                    services
+                        .iter()
+                        .filter_map(|s| s.as_str())
+                        .map(|s| s.to_string())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            health_url: string("health_url").filter(|u| u.starts_with('/')),
+            env: section
+                .and_then(|s| s.get("env"))
+                .and_then(|e| e.as_object())
+                .map(|vars| {
+                    vars.iter()
+                        .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            database: {
+                let database = section.and_then(|s| s.get("database"));
+                let string = |name: &str| {
+                    database
+                        .and_then(|d| d.get(name))
+                        .and_then(|v| v.as_str())
+                        .map(|s| s.to_string())
+                };
+                DatabaseTemplate {
+                    service: string("service"),
+                    snapshot: string("snapshot"),
+                    seed: string("seed"),
+                }
+            },
+        }
+    }
+
+    /// The service reached at the environment's URL.
+    pub fn service(&self) -> &str {
+        self.service.as_deref().unwrap_or(DEFAULT_SERVICE)
+    }
+}
@@ -0,0 +1,269 @@
+//! Clustering of near-duplicate errors.
+//!
+//! Fingerprints only match errors whose normalized text is identical, so
+//! "expected Foo, found Bar" and "expected Foo, found Baz" become two
+//! errors. Each new error gets a MinHash signature over character
+//! shingles of its normalized text, and joins the cluster of the most
+//! similar recent error of the same project and category when their
+//! estimated Jaccard similarity reaches [`SIMILARITY_THRESHOLD`].
+//!
+//! A cluster is identified by its root error: members point at it through
+//! `cluster_id`, the root itself has none. Clusters can be merged and
+//! errors split off by hand; the automatic pass only places new errors,
+//! so manual changes stick.
+
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Nullable, Text, Timestamptz};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use serde::Serialize;
+
+use crate::models::error::CiError;
+use crate::schema::ci_errors;
+
+/// Hash functions in a signature.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Estimated Jaccard similarity at which two errors are clustered.
+pub const SIMILARITY_THRESHOLD: f64 = 0.6;
+
+/// Characters per shingle.
+const SHINGLE_LEN: usize = 5;
+
+/// Leading characters of the normalized text that are shingled; the head
+/// of a message is what distinguishes it.
+const MAX_SHINGLED_CHARS: usize = 2000;
+
+/// Recent errors a new one is compared against.
+const MAX_CANDIDATES: i64 = 500;
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// splitmix64 finalizer, used to derive the independent hash functions.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// MinHash signature of `normalized` text. Signatures are stored, so the
+/// hashing must stay stable across releases.
+pub fn signature(normalized: &str) -> Vec<i64> {
+    let chars: Vec<char> = normalized.chars().take(MAX_SHINGLED_CHARS).collect();
+    let shingles: Vec<u64> = if chars.len() <= SHINGLE_LEN {
+        vec![fnv1a(normalized.as_bytes())]
+    } else {
+        chars
+            .windows(SHINGLE_LEN)
+            .map(|w| fnv1a(w.iter().collect::<String>().as_bytes()))
+            .collect()
+    };
+    (0..SIGNATURE_LEN as u64)
+        .map(|i| {
+            let seed = mix(i.wrapping_add(1));
+            let min = shingles
+                .iter()
+                .map(|s| mix(s ^ seed))
+                .min()
+                .unwrap_or(u64::MAX);
+            min as i64
+        })
+        .collect()
+}
+
+/// Estimated Jaccard similarity of the texts behind two signatures.
+pub fn similarity(a: &[i64], b: &[i64]) -> f64 {
+    if a.is_empty() || a.len() != b.len() {
+        return 0.0;
+    }
+    let equal = a.iter().zip(b).filter(|(x, y)| x == y).count();
+    equal as f64 / a.len() as f64
+}
+
+/// The cluster a new error with `minhash` joins: that of the most similar
+/// recent error of the same tenant, project and category, if any is
+/// similar enough.
+pub async fn find_cluster(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+    project_id: Option<i64>,
+    category: &str,
+    minhash: &[i64],
+) -> anyhow::Result<Option<i64>> {
+    let mut query = ci_errors::table
+        .filter(ci_errors::tenant_id.eq(tenant_id))
+        .filter(ci_errors::category.eq(category))
+        .filter(ci_errors::active.eq(true))
+        .filter(ci_errors::minhash.is_not_null())
+        .into_boxed();
+    query = match project_id {
+        Some(project_id) => query.filter(ci_errors::project_id.eq(project_id)),
+        None => query.filter(ci_errors::project_id.is_null()),
+    };
+    let candidates: Vec<(i64, Option<i64>, Option<Vec<i64>>)> = query
+        .order(ci_errors::last_seen_at.desc())
+        .limit(MAX_CANDIDATES)
+        .select((ci_errors::id, ci_errors::cluster_id, ci_errors::minhash))
+        .load(conn)
+        .await?;
+
+    let best = candidates
+        .into_iter()
+        .filter_map(|(id, cluster_id, other)| {
+            let score = similarity(minhash, other.as_deref()?);
+            Some((cluster_id.unwrap_or(id), score))
+        })
+        .filter(|(_, score)| *score >= SIMILARITY_THRESHOLD)
+        .max_by(|a, b| a.1.total_cmp(&b.1));
+    Ok(best.map(|(cluster, _)| cluster))
+}
+
+/// A cluster of errors, as listed by [`list_clusters`].
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct ErrorCluster {
+    /// The root error's ID.
+    #[diesel(sql_type = BigInt)]
+    pub cluster_id: i64,
+    /// The root error's title.
+    #[diesel(sql_type = Text)]
+    pub title: String,
+    #[diesel(sql_type = Text)]
+    pub category: String,
+    #[diesel(sql_type = Nullable<BigInt>)]
+    pub project_id: Option<i64>,
+    #[diesel(sql_type = BigInt)]
+    pub error_count: i64,
+    #[diesel(sql_type = BigInt)]
+    pub occurrence_count: i64,
+    /// Members not resolved or ignored.
+    #[diesel(sql_type = BigInt)]
+    pub unresolved_count: i64,
+    #[diesel(sql_type = Timestamptz)]
+    pub last_seen_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Clusters with at least one active error, most recently seen first,
+/// optionally of one project.
+pub async fn list_clusters(
+    conn: &mut AsyncPgConnection,
+    project_id: Option<i64>,
+    limit: i64,
+    offset: i64,
+) -> anyhow::Result<Vec<ErrorCluster>> {
+    let clusters = diesel::sql_query(
+        "SELECT r.id AS cluster_id, r.title, r.category, r.project_id, \
+             COUNT(e.id) AS error_count, \
+             COALESCE(SUM(e.occurrence_count), 0)::BIGINT AS occurrence_count, \
+             COUNT(e.id) FILTER (WHERE e.status IN ('open', 'regressed')) AS unresolved_count, \
+             MAX(e.last_seen_at) AS last_seen_at \
+         FROM ci_errors e \
+         JOIN ci_errors r ON r.id = COALESCE(e.cluster_id, e.id) \
+         WHERE e.active \
+           AND ($1::bigint IS NULL OR r.project_id = $1) \
+         GROUP BY r.id, r.title, r.category, r.project_id \
+         ORDER BY last_seen_at DESC, r.id DESC \
+         LIMIT $2 OFFSET $3",
+    )
+    .bind::<Nullable<BigInt>, _>(project_id)
+    .bind::<BigInt, _>(limit)
+    .bind::<BigInt, _>(offset)
+    .load(conn)
+    .await?;
+    Ok(clusters)
+}
+
+/// Active errors of a cluster, root first.
+pub async fn members(
+    conn: &mut AsyncPgConnection,
+    cluster_id: i64,
+) -> anyhow::Result<Vec<CiError>> {
+    let rows = ci_errors::table
+        .filter(
+            ci_errors::id
+                .eq(cluster_id)
+                .or(ci_errors::cluster_id.eq(cluster_id)),
+        )
+        .filter(ci_errors::active.eq(true))
+        .order((ci_errors::cluster_id.is_not_null(), ci_errors::id.asc()))
+        .load(conn)
+        .await?;
+    Ok(rows)
+}
+
+/// Move every error of `error`'s cluster into `into`'s cluster. Returns the
+/// resulting cluster ID.
+pub async fn merge(
+    conn: &mut AsyncPgConnection,
+    error: &CiError,
+    into: &CiError,
+    uid: i64,
+) -> anyhow::Result<i64> {
+    let (source, target) = (error.cluster(), into.cluster());
+    if source != target {
+        diesel::update(
+            ci_errors::table.filter(
+                ci_errors::id
+                    .eq(source)
+                    .or(ci_errors::cluster_id.eq(source)),
+            ),
+        )
+        .set((
+            ci_errors::cluster_id.eq(Some(target)),
+            ci_errors::write_uid.eq(Some(uid)),
+            ci_errors::write_date.eq(Some(chrono::Utc::now())),
+        ))
+        .execute(conn)
+        .await?;
+    }
+    Ok(target)
+}
+
+/// Take `error` out of its cluster into one of its own. Splitting a root
+/// leaves its other members clustered under the oldest of them.
+pub async fn split(
+    conn: &mut AsyncPgConnection,
+    error: &CiError,
+    uid: i64,
+) -> anyhow::Result<CiError> {
+    let error_id = error.id;
+    let is_root = error.cluster_id.is_none();
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        async move {
+            let now = chrono::Utc::now();
+            if is_root {
+                let new_root: Option<i64> = ci_errors::table
+                    .filter(ci_errors::cluster_id.eq(error_id))
+                    .order(ci_errors::id.asc())
+                    .select(ci_errors::id)
+                    .first(conn)
+                    .await
+                    .optional()?;
+                if let Some(new_root) = new_root {
+                    diesel::update(ci_errors::table.find(new_root))
+                        .set(ci_errors::cluster_id.eq(None::<i64>))
+                        .execute(conn)
+                        .await?;
+                    diesel::update(ci_errors::table.filter(ci_errors::cluster_id.eq(error_id)))
+                        .set(ci_errors::cluster_id.eq(Some(new_root)))
+                        .execute(conn)
+                        .await?;
+                }
+            }
+            let row: CiError = diesel::update(ci_errors::table.find(error_id))
+                .set((
+                    ci_errors::cluster_id.eq(None::<i64>),
+                    ci_errors::write_uid.eq(Some(uid)),
+                    ci_errors::write_date.eq(Some(now)),
+                ))
+                .get_result(conn)
+                .await?;
+            Ok(row)
+        }
+        .scope_boxed()
+    })
+    .await
+}
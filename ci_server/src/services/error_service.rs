@@ -1,16 +1,278 @@
 //! Error fingerprinting and deduplication across builds.
+//!
+//! Failed steps' output is stripped of ANSI colours and scanned for compiler
+//! and linter diagnostics (rustc/clippy `--> file:line:col` blocks,
+//! `file:line:col: error:` lines, and cargo's `--message-format=json`
+//! messages) and test panics; each becomes an occurrence of a deduplicated
+//! `ci_errors` row, with the location and severity it had in that build.
+//! Output without any of those still yields its `error:` lines, or failing
+//! that its last lines, as errors without a location (see
+//! [`extract_failures`]). Steps running cargo with
+//! `--message-format=json` get exact locations instead of scraped ones, and
+//! their stored output is rendered back to the usual text with
+//! [`render_cargo_messages`]. Test runners and linters with a parser of
+//! their own in [`log_parser`](crate::services::log_parser) are read by it
+//! first.
+//!
+//! Errors are `open` until triaged as `resolved` or `ignored`. A new
+//! occurrence reopens a resolved error as `regressed`, since it has come
+//! back; ignored errors stay ignored. Output matching one of the project's
+//! `ci_error_rules` (e.g. a known noisy deprecation warning) is not recorded
+//! at all. Errors at a file path are owned, and assigned by default, by
+//! that file's owners in the repository's CODEOWNERS (see
+//! [`codeowners`](crate::services::codeowners)). [`run_auto_resolver`] resolves errors
+//! that have not occurred in the last `CI_ERROR_AUTO_RESOLVE_BUILDS`
+//! successful builds of their project's default branch, so the list
+//! doesn't accumulate errors that were fixed without anyone triaging them.
 
 use diesel::prelude::*;
+use diesel::sql_types::BigInt;
 use diesel_async::{AsyncPgConnection, RunQueryDsl};
 use regex::Regex;
-use std::sync::LazyLock;
+use serde::Deserialize;
+use std::sync::{Arc, LazyLock};
 
-use crate::models::error::{CiError, NewCiError, NewCiErrorOccurrence};
-use crate::schema::{ci_error_occurrences, ci_errors};
+use erp_core::db::diesel_pool::DieselPool;
+
+use crate::models::error::{CiError, CiErrorOccurrence, NewCiError, NewCiErrorOccurrence};
+use crate::models::error_rule::{CiErrorRule, NewCiErrorRule};
+use crate::schema::{ci_error_occurrences, ci_error_rules, ci_errors};
+use crate::services::{error_cluster, notification_service};
 
 static NUMERIC_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b\d+\b").unwrap());
 static PATH_REGEX: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r"/[a-zA-Z0-9_./-]+").unwrap());
+/// rustc/clippy header: `error[E0425]: message` / `warning: message`.
+static RUSTC_HEADER_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(error|warning)(?:\[[A-Za-z0-9_:]+\])?: (.+)$").unwrap());
+/// rustc/clippy location line under a header: `  --> src/main.rs:4:5`.
+static RUSTC_LOCATION_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s*--> ([^:\s]+):(\d+):\d+").unwrap());
+/// Colour and cursor escape sequences.
+static ANSI_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\x1b\[[0-9;?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)").unwrap());
+/// Rust panic, since 1.73 with the message on the next line:
+/// `thread 'tests::parse' panicked at src/lib.rs:10:5:`.
+static PANIC_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^thread '([^']+)' panicked at ([^:\s]+):(\d+):\d+:$").unwrap());
+/// Rust panic before 1.73: `thread 'main' panicked at 'message', src/main.rs:2:5`.
+static LEGACY_PANIC_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^thread '([^']+)' panicked at '(.*)', ([^:\s]+):(\d+):\d+$").unwrap());
+/// Unlocated error line: `error: ...`, `Error: ...`, `FATAL: ...`.
+static ERROR_LINE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(?:error|Error|ERROR|fatal|FATAL)(?:\[[A-Za-z0-9_:]+\])?: (.+)$").unwrap());
+/// One-line `file:line[:col]: error: message` (gcc, eslint -f unix, ...).
+static LINE_DIAGNOSTIC_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^([^:\s]+):(\d+)(?::\d+)?: (?:fatal )?(error|warning): (.+)$").unwrap());
+
+/// Triage states of a deduplicated error.
+pub const STATUSES: &[&str] = &["open", "resolved", "ignored", "regressed"];
+
+/// How often [`run_auto_resolver`] looks for stale errors.
+const AUTO_RESOLVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// Categories [`classify_category`] assigns.
+pub const CATEGORIES: &[&str] = &["compile", "test", "lint", "timeout", "runtime"];
+
+/// Diagnostics kept per step; more are usually cascades of the first.
+pub const MAX_DIAGNOSTICS: usize = 50;
+
+/// Unlocated errors kept per step.
+pub const MAX_UNLOCATED_ERRORS: usize = 10;
+
+/// Lines of output recorded for a failure nothing more specific was found in.
+const FALLBACK_TAIL_LINES: usize = 20;
+
+/// Cargo and rustc summaries of errors reported on their own already.
+const SUMMARY_MARKERS: &[&str] = &["could not compile", "aborting due to", "test failed, to rerun pass", "could not document"];
+
+/// A compiler or linter message pointing at a source line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// `error` or `warning`.
+    pub severity: &'static str,
+    pub message: String,
+    pub file_path: String,
+    pub line: i32,
+}
+
+fn severity(level: &str) -> &'static str {
+    if level == "warning" { "warning" } else { "error" }
+}
+
+/// One line of cargo's `--message-format=json` output.
+#[derive(Debug, Deserialize)]
+struct CargoMessage {
+    reason: String,
+    /// Set for `compiler-message`.
+    message: Option<CargoDiagnostic>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CargoDiagnostic {
+    message: String,
+    /// `error`, `warning`, `note`, `help`, `error: internal compiler error`, ...
+    level: String,
+    spans: Vec<CargoSpan>,
+    /// The diagnostic as cargo would have printed it.
+    rendered: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CargoSpan {
+    file_name: String,
+    line_start: i32,
+    is_primary: bool,
+}
+
+fn cargo_message(line: &str) -> Option<CargoMessage> {
+    if !line.starts_with("{\"reason\":") {
+        return None;
+    }
+    serde_json::from_str(line).ok()
+}
+
+/// A `compiler-message` as a diagnostic at its primary span. Notes, help
+/// and span-less messages (`aborting due to ...`) are skipped.
+fn cargo_diagnostic(message: &CargoMessage) -> Option<Diagnostic> {
+    let diagnostic = message.message.as_ref().filter(|_| message.reason == "compiler-message")?;
+    if diagnostic.level != "warning" && !diagnostic.level.starts_with("error") {
+        return None;
+    }
+    let span = diagnostic.spans.iter().find(|s| s.is_primary)?;
+    Some(Diagnostic { severity: severity(&diagnostic.level), message: diagnostic.message.clone(), file_path: span.file_name.clone(), line: span.line_start })
+}
+
+/// Replace cargo's JSON messages in `output` with the text cargo would have
+/// printed: compiler messages become their rendered form, and artifact and
+/// build-script messages are dropped. Returns `None` when there are none,
+/// so output without them is kept as it is.
+pub fn render_cargo_messages(output: &str) -> Option<String> {
+    let mut rendered = String::with_capacity(output.len());
+    let mut any = false;
+    for line in output.lines() {
+        match cargo_message(line) {
+            Some(message) => {
+                any = true;
+                if let Some(text) = message.message.and_then(|m| m.rendered) {
+                    rendered.push_str(&text);
+                    if !text.ends_with('\n') {
+                        rendered.push('\n');
+                    }
+                }
+            }
+            None => {
+                rendered.push_str(line);
+                rendered.push('\n');
+            }
+        }
+    }
+    any.then_some(rendered)
+}
+
+/// Extract located diagnostics from step output, at most [`MAX_DIAGNOSTICS`].
+/// Summary lines without a location (`error: could not compile`) are skipped.
+pub fn parse_diagnostics(output: &str) -> Vec<Diagnostic> {
+    let mut found = Vec::new();
+    let mut header: Option<(&'static str, &str)> = None;
+    // A panic whose message is on the following line.
+    let mut panic: Option<(&str, &str, i32)> = None;
+    for line in output.lines() {
+        if found.len() >= MAX_DIAGNOSTICS {
+            break;
+        }
+        if let Some(message) = cargo_message(line) {
+            found.extend(cargo_diagnostic(&message));
+            continue;
+        }
+        if let Some((thread, file_path, line_number)) = panic.take() {
+            found.push(Diagnostic { severity: "error", message: format!("'{thread}' panicked: {}", line.trim()), file_path: file_path.to_string(), line: line_number });
+            continue;
+        }
+        if let Some(caps) = PANIC_REGEX.captures(line) {
+            if let Ok(line_number) = caps[3].parse() {
+                panic = Some((caps.get(1).map_or("", |m| m.as_str()), caps.get(2).map_or("", |m| m.as_str()), line_number));
+            }
+            continue;
+        }
+        if let Some(caps) = LEGACY_PANIC_REGEX.captures(line) {
+            if let Ok(line_number) = caps[4].parse() {
+                found.push(Diagnostic { severity: "error", message: format!("'{}' panicked: {}", &caps[1], &caps[2]), file_path: caps[3].to_string(), line: line_number });
+            }
+            continue;
+        }
+        if let Some(caps) = RUSTC_HEADER_REGEX.captures(line) {
+            header = Some((severity(&caps[1]), caps.get(2).map_or("", |m| m.as_str())));
+            continue;
+        }
+        if let Some(caps) = RUSTC_LOCATION_REGEX.captures(line) {
+            if let (Some((severity, message)), Ok(line)) = (header.take(), caps[2].parse()) {
+                found.push(Diagnostic { severity, message: message.to_string(), file_path: caps[1].to_string(), line });
+            }
+            continue;
+        }
+        if let Some(caps) = LINE_DIAGNOSTIC_REGEX.captures(line) {
+            if let Ok(line) = caps[2].parse() {
+                found.push(Diagnostic { severity: severity(&caps[3]), message: caps[4].to_string(), file_path: caps[1].to_string(), line });
+            }
+        }
+    }
+    found
+}
+
+/// Remove ANSI colour and cursor sequences, as left by tools that colour
+/// their output even when it's not a terminal.
+pub fn strip_ansi(text: &str) -> std::borrow::Cow<'_, str> {
+    ANSI_REGEX.replace_all(text, "")
+}
+
+/// What a failed step's output yields for [`record_diagnostic`] and
+/// [`record_error`].
+#[derive(Debug, Default)]
+pub struct StepFailures {
+    pub diagnostics: Vec<Diagnostic>,
+    /// Error texts without a location. [`extract_failures`] only looks for
+    /// them when no diagnostics were found; test parsers report failing
+    /// tests they can't place alongside those they can.
+    pub errors: Vec<String>,
+}
+
+/// Extract the failures of a step from its output: located diagnostics if
+/// there are any, else its `error:` lines, else the tail of stderr (or of
+/// stdout, with nothing on stderr) as a single error.
+pub fn extract_failures(stdout: &str, stderr: &str) -> StepFailures {
+    let stdout = strip_ansi(stdout);
+    let stderr = strip_ansi(stderr);
+
+    let mut diagnostics = parse_diagnostics(&stderr);
+    diagnostics.extend(parse_diagnostics(&stdout));
+    diagnostics.truncate(MAX_DIAGNOSTICS);
+    if !diagnostics.is_empty() {
+        return StepFailures { diagnostics, errors: Vec::new() };
+    }
+
+    let mut errors: Vec<String> = Vec::new();
+    for line in stderr.lines().chain(stdout.lines()) {
+        let Some(caps) = ERROR_LINE_REGEX.captures(line.trim_end()) else { continue };
+        let message = caps[1].trim();
+        if SUMMARY_MARKERS.iter().any(|m| message.contains(m)) || errors.iter().any(|e| e == message) {
+            continue;
+        }
+        errors.push(message.to_string());
+        if errors.len() >= MAX_UNLOCATED_ERRORS {
+            break;
+        }
+    }
+    if errors.is_empty() {
+        let output = if stderr.trim().is_empty() { &stdout } else { &stderr };
+        let lines: Vec<&str> = output.lines().filter(|l| !l.trim().is_empty()).collect();
+        let tail = lines[lines.len().saturating_sub(FALLBACK_TAIL_LINES)..].join("\n");
+        if !tail.is_empty() {
+            errors.push(tail);
+        }
+    }
+    StepFailures { diagnostics: Vec::new(), errors }
+}
 
 /// Normalize error text for fingerprinting: remove numbers, paths, whitespace.
 pub fn normalize(text: &str) -> String {
@@ -42,7 +304,8 @@ pub fn classify_category(text: &str) -> &'static str {
     }
 }
 
-/// Record an error occurrence, creating or updating the deduplicated error record.
+/// Record an error occurrence, creating or updating the deduplicated error
+/// record. Returns the error's ID, or `None` if a rule muted it.
 pub async fn record_error(
     conn: &mut AsyncPgConnection,
     build_id: i64,
@@ -50,12 +313,84 @@ pub async fn record_error(
     raw_text: &str,
     tenant_id: uuid::Uuid,
     project_id: Option<i64>,
-) -> anyhow::Result<i64> {
-    let normalized = normalize(raw_text);
-    let fp = fingerprint(&normalized);
+) -> anyhow::Result<Option<i64>> {
+    let occurrence = Occurrence { text: raw_text, severity: "error", location: None, owners: &[] };
+    record(conn, build_id, step_name, occurrence, tenant_id, project_id).await
+}
+
+/// Record a parsed diagnostic as an error occurrence at its location. The
+/// location is left out of the fingerprint, so the same message on another
+/// line is the same error. `owners` are the CODEOWNERS owners of its file.
+pub async fn record_diagnostic(
+    conn: &mut AsyncPgConnection,
+    build_id: i64,
+    step_name: &str,
+    diagnostic: &Diagnostic,
+    owners: &[String],
+    tenant_id: uuid::Uuid,
+    project_id: Option<i64>,
+) -> anyhow::Result<Option<i64>> {
+    let occurrence = Occurrence {
+        text: &diagnostic.message,
+        severity: diagnostic.severity,
+        location: Some((diagnostic.file_path.as_str(), diagnostic.line)),
+        owners,
+    };
+    record(conn, build_id, step_name, occurrence, tenant_id, project_id).await
+}
+
+/// Errors of a build that point at a source line, with the occurrence
+/// carrying this build's location.
+pub async fn located_for_build(
+    conn: &mut AsyncPgConnection,
+    build_id: i64,
+    limit: i64,
+) -> anyhow::Result<Vec<(CiError, CiErrorOccurrence)>> {
+    let rows = ci_errors::table
+        .inner_join(ci_error_occurrences::table)
+        .filter(ci_error_occurrences::build_id.eq(build_id))
+        .filter(ci_error_occurrences::file_path.is_not_null())
+        .filter(ci_error_occurrences::line_number.is_not_null())
+        .order(ci_error_occurrences::id.asc())
+        .limit(limit)
+        .select((CiError::as_select(), CiErrorOccurrence::as_select()))
+        .load(conn)
+        .await?;
+    Ok(rows)
+}
+
+/// What [`record`] stores: the error text, its severity, where it points
+/// and who owns that file.
+struct Occurrence<'a> {
+    text: &'a str,
+    severity: &'a str,
+    location: Option<(&'a str, i32)>,
+    owners: &'a [String],
+}
+
+async fn record(
+    conn: &mut AsyncPgConnection,
+    build_id: i64,
+    step_name: &str,
+    occurrence: Occurrence<'_>,
+    tenant_id: uuid::Uuid,
+    project_id: Option<i64>,
+) -> anyhow::Result<Option<i64>> {
+    let Occurrence { text: raw_text, severity, location, owners } = occurrence;
     let category = classify_category(raw_text);
     let now = chrono::Utc::now();
 
+    if let Some(rule_id) = muting_rule(conn, tenant_id, project_id, raw_text, category).await? {
+        diesel::update(ci_error_rules::table.find(rule_id))
+            .set((ci_error_rules::hit_count.eq(ci_error_rules::hit_count + 1), ci_error_rules::last_hit_at.eq(Some(now))))
+            .execute(conn)
+            .await?;
+        return Ok(None);
+    }
+
+    let normalized = normalize(raw_text);
+    let fp = fingerprint(&normalized);
+
     // Find existing error by fingerprint
     let existing: Option<CiError> = ci_errors::table
         .filter(ci_errors::fingerprint.eq(&fp))
@@ -65,11 +400,19 @@ pub async fn record_error(
         .optional()?;
 
     let error_id = if let Some(err) = existing {
-        // Update occurrence count and last_seen
+        // Update occurrence count and last_seen; a resolved error is back
+        let status = if err.status == "resolved" { "regressed" } else { err.status.as_str() };
+        // Ownership follows the file the error was last seen in; an
+        // occurrence without one leaves it as it was.
+        let error_owners = if owners.is_empty() { err.owners.clone() } else { Some(owners.to_vec()) };
+        let assignee = err.assigned_to.clone().or_else(|| owners.first().cloned());
         diesel::update(ci_errors::table.find(err.id))
             .set((
                 ci_errors::occurrence_count.eq(err.occurrence_count + 1),
                 ci_errors::last_seen_at.eq(now),
+                ci_errors::status.eq(status),
+                ci_errors::owners.eq(error_owners),
+                ci_errors::assigned_to.eq(assignee),
             ))
             .execute(conn)
             .await?;
@@ -77,33 +420,43 @@ pub async fn record_error(
     } else {
         // Create new error
         let title = raw_text.lines().next().unwrap_or("Unknown error");
-        let title = if title.len() > 200 {
-            &title[..200]
-        } else {
-            title
-        };
+        // Output is arbitrary text; cut on a character boundary.
+        let mut end = title.len().min(200);
+        while !title.is_char_boundary(end) {
+            end -= 1;
+        }
+        let title = &title[..end];
 
+        let minhash = error_cluster::signature(&normalized);
+        let cluster_id = error_cluster::find_cluster(conn, tenant_id, project_id, category, &minhash).await?;
         let new_error = NewCiError {
             tenant_id,
             project_id,
             fingerprint: fp,
             category: category.to_string(),
-            severity: "error".to_string(),
+            severity: severity.to_string(),
             title: title.to_string(),
-            file_path: None,
-            line_number: None,
+            file_path: location.map(|(path, _)| path.to_string()),
+            line_number: location.map(|(_, line)| line),
             first_seen_at: now,
             last_seen_at: now,
             occurrence_count: 1,
             status: "open".to_string(),
             raw_text: raw_text.to_string(),
             normalized_text: normalized,
+            cluster_id,
+            minhash: Some(minhash),
+            assigned_to: owners.first().cloned(),
+            owners: (!owners.is_empty()).then(|| owners.to_vec()),
         };
 
         let result: CiError = diesel::insert_into(ci_errors::table)
             .values(&new_error)
             .get_result(conn)
             .await?;
+        if let Some(project_id) = project_id {
+            notification_service::notify(conn, tenant_id, project_id, "error.created", &result).await?;
+        }
         result.id
     };
 
@@ -114,6 +467,8 @@ pub async fn record_error(
         build_id,
         step_name: step_name.to_string(),
         raw_output: Some(raw_text.to_string()),
+        file_path: location.map(|(path, _)| path.to_string()),
+        line_number: location.map(|(_, line)| line),
     };
 
     diesel::insert_into(ci_error_occurrences::table)
@@ -122,5 +477,219 @@ pub async fn record_error(
         .await?;
 
     crate::metrics::error_recorded(category);
-    Ok(error_id)
+    Ok(Some(error_id))
+}
+
+/// Whether `rule` mutes `text` of `category`. An unparsable pattern (only
+/// possible for rules written around [`create_rule`]) matches nothing.
+fn rule_matches(rule: &CiErrorRule, text: &str, category: &str) -> bool {
+    rule.category.as_deref().is_none_or(|c| c == category)
+        && rule.pattern.as_deref().is_none_or(|p| Regex::new(p).is_ok_and(|re| re.is_match(text)))
+}
+
+/// The first active rule of the tenant or project that mutes `text`.
+async fn muting_rule(conn: &mut AsyncPgConnection, tenant_id: uuid::Uuid, project_id: Option<i64>, text: &str, category: &str) -> anyhow::Result<Option<i64>> {
+    let mut query = ci_error_rules::table
+        .filter(ci_error_rules::tenant_id.eq(tenant_id))
+        .filter(ci_error_rules::active.eq(true))
+        .into_boxed();
+    query = match project_id {
+        Some(project_id) => query.filter(ci_error_rules::project_id.eq(project_id).or(ci_error_rules::project_id.is_null())),
+        None => query.filter(ci_error_rules::project_id.is_null()),
+    };
+    let rules: Vec<CiErrorRule> = query.order(ci_error_rules::id.asc()).load(conn).await?;
+    Ok(rules.iter().find(|r| rule_matches(r, text, category)).map(|r| r.id))
+}
+
+/// Active mute rules, oldest first; with `project_id`, the project's own and
+/// the tenant-wide ones.
+pub async fn list_rules(conn: &mut AsyncPgConnection, project_id: Option<i64>) -> anyhow::Result<Vec<CiErrorRule>> {
+    let mut query = ci_error_rules::table.filter(ci_error_rules::active.eq(true)).into_boxed();
+    if let Some(project_id) = project_id {
+        query = query.filter(ci_error_rules::project_id.eq(project_id).or(ci_error_rules::project_id.is_null()));
+    }
+    let rows = query.order(ci_error_rules::id.asc()).load(conn).await?;
+    Ok(rows)
+}
+
+/// Get an active mute rule by ID.
+pub async fn get_rule(conn: &mut AsyncPgConnection, rule_id: i64) -> anyhow::Result<Option<CiErrorRule>> {
+    let row = ci_error_rules::table
+        .find(rule_id)
+        .filter(ci_error_rules::active.eq(true))
+        .first(conn)
+        .await
+        .optional()?;
+    Ok(row)
+}
+
+/// Create a mute rule after checking its pattern and category.
+pub async fn create_rule(conn: &mut AsyncPgConnection, rule: NewCiErrorRule) -> anyhow::Result<CiErrorRule> {
+    if rule.pattern.is_none() && rule.category.is_none() {
+        anyhow::bail!("an error rule needs a pattern or a category");
+    }
+    if let Some(pattern) = &rule.pattern {
+        if pattern.len() > 500 {
+            anyhow::bail!("error rule pattern longer than 500 characters");
+        }
+        Regex::new(pattern).map_err(|e| anyhow::anyhow!("invalid error rule pattern: {e}"))?;
+    }
+    if let Some(category) = &rule.category {
+        if !CATEGORIES.contains(&category.as_str()) {
+            anyhow::bail!("unknown error category {category:?}");
+        }
+    }
+    let row = diesel::insert_into(ci_error_rules::table)
+        .values(&rule)
+        .get_result(conn)
+        .await?;
+    Ok(row)
+}
+
+/// Deactivate a mute rule. Returns whether it existed.
+pub async fn delete_rule(conn: &mut AsyncPgConnection, rule_id: i64, uid: i64) -> anyhow::Result<bool> {
+    let updated = diesel::update(ci_error_rules::table.filter(ci_error_rules::id.eq(rule_id)).filter(ci_error_rules::active.eq(true)))
+        .set((
+            ci_error_rules::active.eq(false),
+            ci_error_rules::write_uid.eq(Some(uid)),
+            ci_error_rules::write_date.eq(Some(chrono::Utc::now())),
+        ))
+        .execute(conn)
+        .await?;
+    Ok(updated > 0)
+}
+
+/// Which errors [`list_errors`] returns. Unset fields don't filter.
+#[derive(Debug, Default)]
+pub struct ErrorFilter {
+    pub status: Option<String>,
+    pub category: Option<String>,
+    pub project_id: Option<i64>,
+    /// One of the error's CODEOWNERS owners, e.g. `@acme/payments`.
+    pub owner: Option<String>,
+}
+
+/// Errors matching `filter`, most recently seen first.
+pub async fn list_errors(conn: &mut AsyncPgConnection, filter: &ErrorFilter, limit: i64, offset: i64) -> anyhow::Result<Vec<CiError>> {
+    let mut query = ci_errors::table.filter(ci_errors::active.eq(true)).into_boxed();
+    if let Some(status) = &filter.status {
+        query = query.filter(ci_errors::status.eq(status));
+    }
+    if let Some(category) = &filter.category {
+        query = query.filter(ci_errors::category.eq(category));
+    }
+    if let Some(project_id) = filter.project_id {
+        query = query.filter(ci_errors::project_id.eq(project_id));
+    }
+    if let Some(owner) = &filter.owner {
+        query = query.filter(ci_errors::owners.contains(vec![owner.clone()]));
+    }
+    let rows = query
+        .order((ci_errors::last_seen_at.desc(), ci_errors::id.desc()))
+        .limit(limit)
+        .offset(offset)
+        .load(conn)
+        .await?;
+    Ok(rows)
+}
+
+/// Get an error by ID.
+pub async fn get_error(conn: &mut AsyncPgConnection, error_id: i64) -> anyhow::Result<Option<CiError>> {
+    let row = ci_errors::table
+        .find(error_id)
+        .filter(ci_errors::active.eq(true))
+        .first(conn)
+        .await
+        .optional()?;
+    Ok(row)
+}
+
+/// Move an error to one of [`STATUSES`]. Returns `None` if it does not exist.
+pub async fn set_status(conn: &mut AsyncPgConnection, error_id: i64, status: &str, uid: i64) -> anyhow::Result<Option<CiError>> {
+    if !STATUSES.contains(&status) {
+        anyhow::bail!("unknown error status {status:?}");
+    }
+    let row = diesel::update(ci_errors::table.find(error_id).filter(ci_errors::active.eq(true)))
+        .set((
+            ci_errors::status.eq(status),
+            ci_errors::write_uid.eq(Some(uid)),
+            ci_errors::write_date.eq(Some(chrono::Utc::now())),
+        ))
+        .get_result(conn)
+        .await
+        .optional()?;
+    Ok(row)
+}
+
+/// Assign an error to someone, or unassign it with `None`.
+pub async fn assign(conn: &mut AsyncPgConnection, error_id: i64, assignee: Option<&str>, uid: i64) -> anyhow::Result<Option<CiError>> {
+    let row = diesel::update(ci_errors::table.find(error_id).filter(ci_errors::active.eq(true)))
+        .set((
+            ci_errors::assigned_to.eq(assignee),
+            ci_errors::write_uid.eq(Some(uid)),
+            ci_errors::write_date.eq(Some(chrono::Utc::now())),
+        ))
+        .get_result(conn)
+        .await
+        .optional()?;
+    Ok(row)
+}
+
+/// Most recent occurrences of an error, newest first.
+pub async fn occurrences(conn: &mut AsyncPgConnection, error_id: i64, limit: i64) -> anyhow::Result<Vec<CiErrorOccurrence>> {
+    let rows = ci_error_occurrences::table
+        .filter(ci_error_occurrences::error_id.eq(error_id))
+        .order(ci_error_occurrences::id.desc())
+        .limit(limit)
+        .load(conn)
+        .await?;
+    Ok(rows)
+}
+
+/// Resolve stale errors forever, every [`AUTO_RESOLVE_INTERVAL`]. Spawned as
+/// a background tokio task; `after_builds` of 0 disables it.
+pub async fn run_auto_resolver(pool: Arc<DieselPool>, after_builds: u32) {
+    if after_builds == 0 {
+        return;
+    }
+    tracing::info!(after_builds, "Error auto-resolver started");
+
+    let mut interval = tokio::time::interval(AUTO_RESOLVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let resolved = match crate::db::system_conn(&pool).await {
+            Ok(mut conn) => resolve_stale(&mut conn, after_builds).await,
+            Err(e) => Err(e),
+        };
+        match resolved {
+            Ok(0) => {}
+            Ok(count) => tracing::info!(count, "Auto-resolved stale errors"),
+            Err(e) => tracing::error!("Error auto-resolve failed: {e}"),
+        }
+    }
+}
+
+/// Resolve the `open` and `regressed` errors of every tenant that were last
+/// seen before `after_builds` successful builds of their project's default
+/// branch. Errors without a project are left alone. Returns how many were
+/// resolved.
+pub async fn resolve_stale(conn: &mut AsyncPgConnection, after_builds: u32) -> anyhow::Result<usize> {
+    let resolved = diesel::sql_query(
+        "UPDATE ci_errors e SET status = 'resolved', write_date = NOW() \
+         FROM ci_projects p \
+         WHERE p.id = e.project_id \
+           AND e.active \
+           AND e.status IN ('open', 'regressed') \
+           AND ( \
+               SELECT COUNT(*) FROM ci_builds b \
+               WHERE b.project_id = e.project_id \
+                 AND b.branch = p.default_branch \
+                 AND b.status = 'success' \
+                 AND b.finished_at > e.last_seen_at \
+           ) >= $1",
+    )
+    .bind::<BigInt, _>(i64::from(after_builds))
+    .execute(conn)
+    .await?;
+    Ok(resolved)
 }
@@ -1,72 +1,292 @@
-//! Build executor — background task that polls for pending builds and runs them.
+//! Build executor — background task that picks up pending builds and runs them.
 //!
-//! Picks up `status = 'pending'` builds, checks out the repo, runs each
+//! Claims `status = 'pending'` builds, checks out the repo, runs each
 //! pipeline step as a shell command, and records stdout/stderr/exit_code.
+//! Claiming locks the row with `FOR UPDATE SKIP LOCKED`, so several servers
+//! can share one queue; claims are serialized by [`CLAIM_LOCK`] so the
+//! concurrency limits they check hold across servers. The loop is woken by a Postgres notification on
+//! [`build_service::BUILD_QUEUED_CHANNEL`] when builds are created, with a
+//! slow fallback poll for anything the listener missed.
+//!
+//! The loop reports its progress to [`HEALTH`]; [`run_watchdog`] flags it as
+//! stalled (failing readiness) when it misses the deadline it last set.
+//!
+//! Steps run once the steps they `depends_on` have passed (in list order
+//! when no step declares any), up to `max_parallel_steps` at a time. Each
+//! runs in its own process group; a build flagged by the cancel API has
+//! its groups killed, either when [`wake_cancelled`] is signalled or on
+//! the next [`CANCEL_POLL_INTERVAL`] check. A failed or timed-out step with
+//! `retries` runs again after `retry_delay_secs`, up to that many more
+//! times; every attempt gets its own `ci_build_steps` row. A step with
+//! `allow_failure` that still fails is recorded as failed, but its
+//! dependents run and the build can pass. Steps with `when: always` run
+//! once their dependencies have finished even after a failure, and
+//! `when: on_failure` steps only run after one.
+//!
+//! Step env is layered: the project's `pipeline_config.env`, an in-repo
+//! file's `env`, the build's trigger's `ci_triggers.env`, the step's own
+//! `env`, then secrets and the `CI_*` variables. Builds of pull requests
+//! from forks or outside contributors are untrusted (`ci_builds.trusted`)
+//! and get no secrets until a maintainer approves them. Their steps always
+//! run sandboxed, and they cannot run `engine: dagger`, which drives the
+//! host's container engine.
+//!
+//! Cloned workspaces get the pipeline's `cache` directories restored before
+//! the steps and saved after (see [`cache_service`]).
+//!
+//! A pipeline with `engine: "dagger"` instead runs the `ci_pipeline` binary
+//! and records each stage it reports as a step. The binary drives the host's
+//! container engine, so it cannot run in a sandboxed project, and it gets
+//! only the build's variables and [`DAGGER_HOST_ENV`], not the server's
+//! environment. An in-repo pipeline file can only pick another engine than
+//! the project's when the project sets `allow_repo_engine`.
 
-use std::sync::Arc;
-use std::time::Instant;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
 
 use diesel::prelude::*;
-use diesel_async::RunQueryDsl;
-use tokio::process::Command;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::{Notify, Semaphore};
+use tracing::Instrument;
 
 use erp_core::db::diesel_pool::DieselPool;
 
 use crate::config::CiConfig;
-use crate::schema::{ci_builds, ci_projects};
-use crate::services::{github_service, step_executor};
+use crate::events::build::{CiBuildAggregate, CiBuildEvent};
+use crate::events::store;
+use crate::models::build::BuildStatus;
+use crate::models::environment::NewCiEnvironment;
+use crate::models::project::Provider;
+use crate::schema::{ci_builds, ci_projects, ci_triggers};
+use crate::services::github_service::{self, CheckAnnotation};
+use crate::services::outbox_service::{self, OutboxMessage};
+use crate::services::cache_service::{self, CacheSpec, Restored};
+use crate::services::codeowners::{self, CodeOwners};
+use crate::services::limits::{self, ResourceLimits, StepCgroup};
+use crate::services::log_parser::{self, LogParser};
+use crate::services::environment_template::EnvironmentTemplate;
+use crate::services::sandbox::{SandboxConfig, StepBackend};
+use crate::services::{
+    build_feed, build_service, compose_provisioner, error_service, notification_service, oidc_service, pipeline_file, policy_service, pr_comment, provenance_service, quota_service,
+    secret_service, step_executor, trigger_service, vulnerability_service,
+};
+use crate::services::vulnerability_service::{ReportFormat, Severity};
+
+/// Transaction-level advisory lock serializing build claims.
+const CLAIM_LOCK: i64 = 0x6369_636c_6169_6d62;
+
+/// How long the executor waits for a wakeup before polling anyway.
+const FALLBACK_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Delay before the queue listener reconnects after losing its connection.
+const LISTEN_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Slack on top of every progress deadline before the loop counts as stalled.
+const STALL_GRACE: Duration = Duration::from_secs(60);
+
+/// How often the watchdog checks the executor.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often a running step checks whether its build was cancelled, for
+/// cancellations made on another server.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Wait before re-running a failed step that sets `retries` but no
+/// `retry_delay_secs`.
+const DEFAULT_RETRY_DELAY_SECS: u64 = 10;
+
+/// Signalled by the queue listener when a build is queued on any server.
+static BUILD_QUEUED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Signalled when a running build is flagged for cancellation.
+static CANCEL_REQUESTED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Make running steps check for cancellation now. Call after the flag has
+/// been committed.
+pub fn wake_cancelled() {
+    CANCEL_REQUESTED.notify_waiters();
+}
+
+/// Liveness of the executor loop, shared with the watchdog and readiness
+/// endpoint.
+#[derive(Debug, Default)]
+pub struct ExecutorHealth {
+    /// Unix time of the last poll that completed without error.
+    last_poll_ok: AtomicI64,
+    /// Unix time by which the loop must report progress again.
+    deadline: AtomicI64,
+    consecutive_errors: AtomicU64,
+    stalled: AtomicBool,
+}
+
+impl ExecutorHealth {
+    /// Record progress and allow `within` (plus grace) until the next report.
+    fn beat(&self, within: Duration) {
+        let within = i64::try_from(within.saturating_add(STALL_GRACE).as_secs()).unwrap_or(i64::MAX);
+        let deadline = chrono::Utc::now().timestamp().saturating_add(within);
+        self.deadline.store(deadline, Ordering::Relaxed);
+    }
+
+    fn poll_finished(&self, ok: bool, elapsed: Duration, executed: bool) {
+        let errors = if ok {
+            self.last_poll_ok.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+            self.consecutive_errors.store(0, Ordering::Relaxed);
+            0
+        } else {
+            self.consecutive_errors.fetch_add(1, Ordering::Relaxed) + 1
+        };
+        let outcome = match (ok, executed) {
+            (false, _) => "error",
+            (true, true) => "executed",
+            (true, false) => "idle",
+        };
+        crate::metrics::executor_poll(
+            outcome,
+            elapsed.as_millis() as u64,
+            self.last_poll_ok.load(Ordering::Relaxed),
+            errors,
+        );
+    }
+
+    /// Whether the watchdog has seen the loop miss its deadline.
+    pub fn is_stalled(&self) -> bool {
+        self.stalled.load(Ordering::Relaxed)
+    }
+
+    pub fn last_poll_ok(&self) -> i64 {
+        self.last_poll_ok.load(Ordering::Relaxed)
+    }
+
+    pub fn consecutive_errors(&self) -> u64 {
+        self.consecutive_errors.load(Ordering::Relaxed)
+    }
+}
+
+pub static HEALTH: LazyLock<ExecutorHealth> = LazyLock::new(ExecutorHealth::default);
 
 /// Run the executor loop forever. Spawned as a background tokio task.
-pub async fn run_executor(pool: Arc<DieselPool>, config: CiConfig) {
+///
+/// `database_url` is used for a dedicated connection that listens for
+/// queued builds, so the `LISTEN` does not tie up a pooled one.
+pub async fn run_executor(pool: Arc<DieselPool>, config: CiConfig, database_url: String) {
     tracing::info!(
         workspace = %config.workspace_dir,
         max_concurrent = config.max_concurrent_builds,
         "Build executor started"
     );
+    tokio::spawn(listen_for_builds(database_url));
+    // The executor runs one build at a time.
+    crate::metrics::runner_capacity(&config.runner_name, 1);
+    crate::metrics::runner_current_build(&config.runner_name, None);
+
+    loop {
+        HEALTH.beat(FALLBACK_POLL_INTERVAL);
+        let poll_start = Instant::now();
+        let executed = match poll_and_execute(&pool, &config).await {
+            Ok(executed) => {
+                HEALTH.poll_finished(true, poll_start.elapsed(), executed);
+                executed
+            }
+            Err(e) => {
+                tracing::error!("Executor poll error: {e}");
+                HEALTH.poll_finished(false, poll_start.elapsed(), false);
+                false
+            }
+        };
 
+        // A finished build may have freed capacity for the next one.
+        if executed {
+            continue;
+        }
+
+        HEALTH.beat(FALLBACK_POLL_INTERVAL);
+        tokio::select! {
+            _ = BUILD_QUEUED.notified() => {}
+            _ = tokio::time::sleep(FALLBACK_POLL_INTERVAL) => {}
+        }
+    }
+}
+
+/// Forward notifications on [`build_service::BUILD_QUEUED_CHANNEL`] to the
+/// executor loop, reconnecting whenever the listening connection drops.
+async fn listen_for_builds(database_url: String) {
     loop {
-        if let Err(e) = poll_and_execute(&pool, &config).await {
-            tracing::error!("Executor poll error: {e}");
+        if let Err(e) = listen(&database_url).await {
+            tracing::warn!("Build queue listener disconnected: {e}");
         }
-        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        // Anything queued while we were disconnected was not announced.
+        BUILD_QUEUED.notify_one();
+        tokio::time::sleep(LISTEN_RETRY_DELAY).await;
     }
 }
 
-/// Poll for one pending build and execute it.
-async fn poll_and_execute(pool: &DieselPool, config: &CiConfig) -> anyhow::Result<()> {
-    let mut conn = pool.get().await?;
+async fn listen(database_url: &str) -> anyhow::Result<()> {
+    let mut conn = AsyncPgConnection::establish(database_url).await?;
+    diesel::sql_query(format!("LISTEN {}", build_service::BUILD_QUEUED_CHANNEL))
+        .execute(&mut conn)
+        .await?;
+    tracing::info!(channel = build_service::BUILD_QUEUED_CHANNEL, "Listening for queued builds");
+
+    let mut notifications = std::pin::pin!(conn.notifications_stream());
+    while let Some(notification) = notifications.next().await {
+        notification?;
+        BUILD_QUEUED.notify_one();
+    }
+    anyhow::bail!("notification stream ended")
+}
+
+/// Watch the executor loop and flag it stalled when it misses its progress
+/// deadline. Spawned as a background tokio task next to [`run_executor`].
+pub async fn run_watchdog() {
+    let mut interval = tokio::time::interval(WATCHDOG_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let deadline = HEALTH.deadline.load(Ordering::Relaxed);
+        let overdue = deadline > 0 && chrono::Utc::now().timestamp() > deadline;
+        let was_stalled = HEALTH.stalled.swap(overdue, Ordering::Relaxed);
+        crate::metrics::executor_stalled(overdue);
+
+        match (was_stalled, overdue) {
+            (false, true) => tracing::error!(
+                last_poll_ok = HEALTH.last_poll_ok(),
+                consecutive_errors = HEALTH.consecutive_errors(),
+                "Build executor stalled: no progress past its deadline"
+            ),
+            (true, false) => tracing::info!("Build executor recovered"),
+            _ => {}
+        }
+    }
+}
+
+/// Claim one pending build and execute it. Returns whether a build ran.
+async fn poll_and_execute(pool: &DieselPool, config: &CiConfig) -> anyhow::Result<bool> {
+    let mut conn = crate::db::system_conn(pool).await?;
 
-    // Check how many builds are currently running
     let running_count: i64 = ci_builds::table
-        .filter(ci_builds::status.eq("running"))
+        .filter(ci_builds::status.eq(BuildStatus::Running.as_str()))
         .count()
         .get_result(&mut conn)
         .await?;
 
-    if running_count >= config.max_concurrent_builds as i64 {
-        return Ok(());
-    }
-
-    // Pick the oldest pending build
-    let pending: Option<PendingBuild> = ci_builds::table
-        .inner_join(ci_projects::table)
-        .filter(ci_builds::status.eq("pending"))
-        .order(ci_builds::id.asc())
-        .select((
-            ci_builds::id,
-            ci_builds::tenant_id,
-            ci_builds::commit_sha,
-            ci_builds::branch,
-            ci_projects::github_repo,
-            ci_projects::pipeline_config,
-        ))
+    let (pending_count, oldest_pending): (i64, Option<chrono::DateTime<chrono::Utc>>) = ci_builds::table
+        .filter(ci_builds::status.eq(BuildStatus::Pending.as_str()))
+        .select((diesel::dsl::count_star(), diesel::dsl::min(ci_builds::create_date)))
         .first(&mut conn)
-        .await
-        .optional()?;
+        .await?;
+    let oldest_age = oldest_pending.map_or(0, |t| (chrono::Utc::now() - t).num_seconds().max(0));
+    crate::metrics::build_queue(pending_count as u64, oldest_age as u64, running_count as u64);
 
-    let build = match pending {
+    let build = match claim_build(&mut conn, config).await? {
         Some(b) => b,
-        None => return Ok(()),
+        None => return Ok(false),
     };
 
     tracing::info!(
@@ -76,300 +296,1653 @@ async fn poll_and_execute(pool: &DieselPool, config: &CiConfig) -> anyhow::Resul
         "Executing build"
     );
 
-    // Mark as running
-    diesel::update(ci_builds::table.find(build.id))
-        .set((
-            ci_builds::status.eq("running"),
-            ci_builds::started_at.eq(chrono::Utc::now()),
-        ))
-        .execute(&mut conn)
-        .await?;
+    // One trace per build, continuing the webhook or API request that
+    // created it.
+    let span = tracing::info_span!(
+        "build",
+        build_id = build.id,
+        repo = %build.github_repo,
+        branch = %build.branch
+    );
+    crate::telemetry::set_parent(&span, build.trace_context.as_deref());
+    crate::metrics::runner_current_build(&config.runner_name, Some(build.id));
+    let result = execute_build(&mut conn, &build, config).instrument(span).await;
+    crate::metrics::runner_current_build(&config.runner_name, None);
+    result?;
 
-    crate::metrics::build_status_changed("running");
-
-    // Post "pending" status to GitHub
-    let target_url = format!("{}/api/builds/{}", config.dashboard_url, build.id);
-    let _ = github_service::post_status(
-        &config.github_token,
-        &build.github_repo,
-        &build.commit_sha,
-        "pending",
-        "Build running",
-        &target_url,
-        "centrix-ci",
-    )
-    .await;
+    Ok(true)
+}
+
+/// Run a claimed build to completion.
+async fn execute_build(
+    conn: &mut diesel_async::AsyncPgConnection,
+    build: &PendingBuild,
+    config: &CiConfig,
+) -> anyhow::Result<()> {
+    // Checkout has no timeout of its own; give it a step's worth.
+    HEALTH.beat(Duration::from_secs(600));
 
     // Parse pipeline config
-    let pipeline = parse_pipeline(&build.pipeline_config);
+    let mut pipeline = parse_pipeline(&build.pipeline_config, config.sandbox_by_default);
     let build_start = Instant::now();
 
-    // Determine working directory
-    let work_dir = if let Some(ref local_path) = pipeline.local_path {
-        local_path.clone()
-    } else {
-        // Clone from GitHub
-        let workspace = format!("{}/{}", config.workspace_dir, build.id);
-        tokio::fs::create_dir_all(&workspace).await?;
-        let clone_url = format!("https://github.com/{}.git", build.github_repo);
+    // Re-check command policies: configs edited through the UI skip the
+    // API's save-time validation.
+    if let Some(ref pipeline_config) = build.pipeline_config {
+        if let Err(e) =
+            policy_service::validate_pipeline(conn, build.tenant_id, pipeline_config).await
+        {
+            tracing::warn!(build_id = build.id, "Pipeline rejected by policy: {e}");
+            finish_build(conn, build, BuildStatus::Failure, build_start, Some(&e.to_string()), config).await?;
+            return Ok(());
+        }
+    }
 
-        let clone_result = Command::new("git")
-            .args(["clone", "--depth", "1", "--branch", &build.branch, &clone_url, &workspace])
-            .output()
-            .await;
+    // Decrypt the project's secrets once; each step's injection is audited.
+    // Untrusted pull request builds run code from their author, so they get
+    // none until a maintainer approves them.
+    let secrets = match config.secrets_keyring {
+        Some(_) if !build.trusted => {
+            tracing::info!(build_id = build.id, "Untrusted pull request build, withholding secrets");
+            Vec::new()
+        }
+        Some(ref keyring) => {
+            match secret_service::resolve_for_project(conn, keyring, build.tenant_id, build.project_id).await {
+                Ok(secrets) => secrets,
+                Err(e) => {
+                    tracing::error!(build_id = build.id, "Secret resolution failed: {e}");
+                    finish_build(conn, build, BuildStatus::Failure, build_start, Some("failed to decrypt build secrets"), config).await?;
+                    return Ok(());
+                }
+            }
+        }
+        None => Vec::new(),
+    };
 
-        match clone_result {
-            Ok(output) if output.status.success() => {}
-            Ok(output) => {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                tracing::error!(build_id = build.id, "git clone failed: {stderr}");
-                finish_build(&mut conn, build.id, "failure", build_start, Some(&format!("git clone failed: {stderr}")), &build.github_repo, &build.commit_sha, config).await?;
+    // Determine working directory
+    let work_dir = match checkout(build, &pipeline, config)
+        .instrument(tracing::info_span!("checkout"))
+        .await
+    {
+        Ok(work_dir) => work_dir,
+        Err(msg) => {
+            tracing::error!(build_id = build.id, "{msg}");
+            finish_build(conn, build, BuildStatus::Failure, build_start, Some(&msg), config).await?;
+            return Ok(());
+        }
+    };
+
+    // A pipeline definition in the repo replaces the project's, keeping the
+    // project's checkout (`local_path`, `git`), sandbox, limits, vulnerability
+    // gate, and review environment settings.
+    let pipeline_config = match pipeline_file::load(&work_dir).await {
+        Ok(Some(file)) => {
+            tracing::info!(build_id = build.id, file = file.file, "Using in-repo pipeline definition");
+            if let Err(e) = policy_service::validate_pipeline(conn, build.tenant_id, &file.config).await {
+                tracing::warn!(build_id = build.id, "Pipeline rejected by policy: {e}");
+                finish_build(conn, build, BuildStatus::Failure, build_start, Some(&e.to_string()), config).await?;
+                remove_workspace(build, &pipeline, config).await;
                 return Ok(());
             }
-            Err(e) => {
-                tracing::error!(build_id = build.id, "git clone error: {e}");
-                finish_build(&mut conn, build.id, "failure", build_start, Some(&format!("git clone error: {e}")), &build.github_repo, &build.commit_sha, config).await?;
+            let mut repo_pipeline = parse_pipeline(&Some(file.config.clone()), config.sandbox_by_default);
+            if let Err(e) = check_repo_engine(build.pipeline_config.as_ref(), pipeline.dagger.is_some(), repo_pipeline.dagger.is_some()) {
+                tracing::warn!(build_id = build.id, "{e}");
+                finish_build(conn, build, BuildStatus::ConfigError, build_start, Some(&e), config).await?;
+                remove_workspace(build, &pipeline, config).await;
                 return Ok(());
             }
+            repo_pipeline.local_path = pipeline.local_path;
+            repo_pipeline.sandbox = pipeline.sandbox;
+            repo_pipeline.limits = pipeline.limits;
+            repo_pipeline.git = pipeline.git;
+            repo_pipeline.vulnerability_gate = pipeline.vulnerability_gate;
+            repo_pipeline.environment = pipeline.environment;
+            for step in &mut repo_pipeline.steps {
+                step.limits = step.limits.within(pipeline.limits);
+            }
+            // The project's env stays as defaults under the file's.
+            repo_pipeline.env = pipeline.env.into_iter().chain(repo_pipeline.env).collect();
+            pipeline = repo_pipeline;
+            Some(file.config)
+        }
+        Ok(None) => build.pipeline_config.clone(),
+        Err(e) => {
+            tracing::warn!(build_id = build.id, "{e}");
+            finish_build(conn, build, BuildStatus::ConfigError, build_start, Some(&e.to_string()), config).await?;
+            remove_workspace(build, &pipeline, config).await;
+            return Ok(());
         }
+    };
+
+    if pipeline.dagger.is_some() && pipeline.sandbox.enabled {
+        let msg = "engine: dagger cannot run in a sandboxed project";
+        tracing::warn!(build_id = build.id, "{msg}");
+        finish_build(conn, build, BuildStatus::ConfigError, build_start, Some(msg), config).await?;
+        remove_workspace(build, &pipeline, config).await;
+        return Ok(());
+    }
 
-        // Checkout specific commit if not HEAD
-        if build.commit_sha != "HEAD" && build.commit_sha.len() >= 7 {
-            let _ = Command::new("git")
-                .args(["checkout", &build.commit_sha])
-                .current_dir(&workspace)
-                .output()
-                .await;
+    // An untrusted build runs its author's code, never on the bare host.
+    if !build.trusted && !pipeline.sandbox.enabled {
+        if pipeline.dagger.is_some() {
+            let msg = "engine: dagger cannot run for an untrusted pull request until a maintainer approves it";
+            tracing::warn!(build_id = build.id, "{msg}");
+            finish_build(conn, build, BuildStatus::Failure, build_start, Some(msg), config).await?;
+            remove_workspace(build, &pipeline, config).await;
+            return Ok(());
         }
+        pipeline.sandbox.enabled = true;
+    }
+
+    // Trigger env overrides the pipeline's; steps' own env overrides both.
+    pipeline.env.extend(trigger_env(conn, build).await?);
 
-        workspace
+    let cache_hits = restore_caches(build, &pipeline, &work_dir, config).await;
+    let codeowners = codeowners::load(&work_dir).await;
+
+    // Execute the steps, independent ones concurrently
+    let backend = pipeline.sandbox.backend(config);
+    let ctx = StepContext { build, pipeline: &pipeline, backend: backend.as_ref(), work_dir: &work_dir, secrets: &secrets, config, codeowners: codeowners.as_ref() };
+    let outcome = match pipeline.dagger {
+        Some(ref dagger) => run_dagger(conn, &ctx, dagger).await?,
+        None => run_steps(conn, &ctx).await?,
     };
+    if outcome == StepsOutcome::Cancelled {
+        finish_build(conn, build, BuildStatus::Cancelled, build_start, None, config).await?;
+        remove_workspace(build, &pipeline, config).await;
+        return Ok(());
+    }
+    let all_passed = outcome == StepsOutcome::Passed;
+    if all_passed {
+        save_caches(build, &pipeline, &work_dir, config, &cache_hits).await;
+    }
 
-    // If local_path, do a git pull to get latest
-    if pipeline.local_path.is_some() {
-        let pull_result = Command::new("git")
-            .args(["pull", "--ff-only"])
-            .current_dir(&work_dir)
-            .output()
-            .await;
+    let mut gate_error = None;
+    if let Some(threshold) = pipeline.vulnerability_gate.filter(|_| all_passed) {
+        let new = vulnerability_service::new_findings(conn, build.id, build.project_id, threshold).await?;
+        if !new.is_empty() {
+            let ids: Vec<String> = new.iter().take(5).map(|v| format!("{} ({})", v.advisory_id, v.package)).collect();
+            gate_error = Some(format!("{} new vulnerabilities at or above {threshold}: {}", new.len(), ids.join(", ")));
+            tracing::warn!(build_id = build.id, count = new.len(), "Vulnerability gate failed");
+        }
+    }
 
-        if let Ok(output) = pull_result {
-            if !output.status.success() {
-                tracing::warn!(
-                    build_id = build.id,
-                    "git pull failed (continuing with current state): {}",
-                    String::from_utf8_lossy(&output.stderr)
-                );
+    let final_status = if all_passed && gate_error.is_none() {
+        BuildStatus::Success
+    } else {
+        BuildStatus::Failure
+    };
+    finish_build(conn, build, final_status, build_start, gate_error.as_deref(), config).await?;
+
+    if final_status == BuildStatus::Success {
+        if let Some(ref signer) = config.oidc_signer {
+            if let Err(e) =
+                provenance_service::generate(conn, signer, build.id, pipeline_config.as_ref(), config.gitea_url.as_deref()).await
+            {
+                tracing::error!(build_id = build.id, "Provenance generation failed: {e}");
+            }
+        }
+
+        // PR builds get a review environment, which takes over the checkout.
+        // It runs the PR's compose file on the host, so untrusted builds get
+        // none until a maintainer approves them.
+        if let Some(pr_number) = build.pr_number.filter(|_| build.trusted) {
+            let new_env = NewCiEnvironment {
+                tenant_id: build.tenant_id,
+                project_id: build.project_id,
+                build_id: Some(build.id),
+                pr_number,
+                branch: build.branch.clone(),
+                commit_sha: build.commit_sha.clone(),
+                status: "requested".to_string(),
+                idle_timeout_min: config.idle_timeout_min as i32,
+            };
+            match compose_provisioner::request(conn, config, new_env, &build.github_repo, &work_dir, pipeline.local_path.is_none(), &pipeline.environment).await {
+                Ok(Some(env)) => tracing::info!(build_id = build.id, env_id = env.id, "Environment requested"),
+                Ok(None) => {}
+                Err(e) => tracing::warn!(build_id = build.id, "Environment not requested: {e}"),
             }
         }
     }
 
-    // Execute each step
-    let mut all_passed = true;
-    let timeout = std::time::Duration::from_secs(pipeline.timeout_secs);
+    remove_workspace(build, &pipeline, config).await;
 
-    for (seq, step_def) in pipeline.steps.iter().enumerate() {
-        let step_start = Instant::now();
-
-        let step_id = step_executor::start_step(
-            &mut conn,
-            build.id,
-            &step_def.name,
-            (seq + 1) as i32,
-            build.tenant_id,
-        )
-        .await?;
+    Ok(())
+}
 
-        tracing::info!(
-            build_id = build.id,
-            step = %step_def.name,
-            command = %step_def.command,
-            "Running step"
-        );
+/// Unpack the pipeline's caches into a fresh checkout. Returns the names
+/// restored from an archive of the current key. Untrusted builds start
+/// without: the archives are the trusted builds' own.
+async fn restore_caches(build: &PendingBuild, pipeline: &PipelineConfig, work_dir: &str, config: &CiConfig) -> Vec<String> {
+    let mut hits = Vec::new();
+    if pipeline.local_path.is_some() || !build.trusted {
+        return hits;
+    }
+    for spec in &pipeline.cache {
+        match cache_service::restore(&config.cache_dir, build.project_id, work_dir, spec).await {
+            Ok(restored) => {
+                tracing::info!(build_id = build.id, cache = %spec.name, ?restored, "Cache restored");
+                if restored == Restored::Hit {
+                    hits.push(spec.name.clone());
+                }
+            }
+            Err(e) => tracing::warn!(build_id = build.id, cache = %spec.name, "Cache restore failed: {e}"),
+        }
+    }
+    hits
+}
 
-        // Run the command with timeout
-        let cmd_result = tokio::time::timeout(timeout, async {
-            Command::new("bash")
-                .args(["-c", &step_def.command])
-                .current_dir(&work_dir)
-                .env("CI", "true")
-                .env("CI_BUILD_ID", build.id.to_string())
-                .env("CI_BRANCH", &build.branch)
-                .env("CI_COMMIT", &build.commit_sha)
-                .output()
-                .await
-        })
-        .await;
-
-        let (exit_code, stdout_str, stderr_str) = match cmd_result {
-            Ok(Ok(output)) => {
-                let code = output.status.code().unwrap_or(-1);
-                let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-                let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-                // Truncate to 64KB per field
-                let stdout = if stdout.len() > 65536 {
-                    format!("...truncated...\n{}", &stdout[stdout.len() - 65536..])
-                } else {
-                    stdout
+/// Archive the caches that had no exact match. Only trusted pushes to the
+/// default branch save, so other branches cannot poison what it restores:
+/// a pull request's branch can be named like the default one, a fork's
+/// `main` included.
+async fn save_caches(build: &PendingBuild, pipeline: &PipelineConfig, work_dir: &str, config: &CiConfig, hits: &[String]) {
+    if pipeline.local_path.is_some() || build.pr_number.is_some() || !build.trusted || build.branch != build.default_branch {
+        return;
+    }
+    for spec in pipeline.cache.iter().filter(|spec| !hits.contains(&spec.name)) {
+        match cache_service::save(&config.cache_dir, build.project_id, work_dir, spec).await {
+            Ok(true) => tracing::info!(build_id = build.id, cache = %spec.name, "Cache saved"),
+            Ok(false) => tracing::debug!(build_id = build.id, cache = %spec.name, "Nothing to cache"),
+            Err(e) => tracing::warn!(build_id = build.id, cache = %spec.name, "Cache save failed: {e}"),
+        }
+    }
+}
+
+/// A step's report file at `path` of the workspace. The server reads it,
+/// not the step, so it must stay inside the workspace once its symlinks
+/// are resolved.
+async fn read_report(work_dir: &str, path: &str) -> anyhow::Result<String> {
+    let workspace = tokio::fs::canonicalize(work_dir).await?;
+    let file = tokio::fs::canonicalize(std::path::Path::new(work_dir).join(path)).await.map_err(|e| anyhow::anyhow!("cannot read {path}: {e}"))?;
+    anyhow::ensure!(cache_service::valid_path(path) && file.starts_with(&workspace), "{path} is outside the workspace");
+    tokio::fs::read_to_string(&file).await.map_err(|e| anyhow::anyhow!("cannot read {path}: {e}"))
+}
+
+/// How a build's steps ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StepsOutcome {
+    Passed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StepState {
+    Waiting,
+    Running,
+    Passed,
+    Failed,
+    /// Failed with `allow_failure`; counts as passed for dependents.
+    AllowedFailure,
+    /// Will not run; recorded with the reason.
+    Skipped,
+}
+
+impl StepState {
+    /// Whether the step is done, however it ended.
+    fn settled(self) -> bool {
+        !matches!(self, StepState::Waiting | StepState::Running)
+    }
+}
+
+/// What every step of a build shares.
+struct StepContext<'a> {
+    build: &'a PendingBuild,
+    pipeline: &'a PipelineConfig,
+    backend: &'a dyn StepBackend,
+    work_dir: &'a str,
+    secrets: &'a [secret_service::ResolvedSecret],
+    config: &'a CiConfig,
+    /// The checkout's CODEOWNERS, for routing the errors steps report.
+    codeowners: Option<&'a CodeOwners>,
+}
+
+/// A step whose command is running.
+struct RunningStep {
+    step_id: i64,
+    started: Instant,
+    timeout: Duration,
+    span: tracing::Span,
+    /// Process group of the command, killed on cancellation.
+    pgid: Option<u32>,
+    cgroup: Option<StepCgroup>,
+}
+
+/// How a step's command ended.
+enum StepRun {
+    Finished(std::io::Result<std::process::Output>),
+    TimedOut,
+}
+
+/// Run the pipeline's steps, each once everything it depends on has passed,
+/// at most `max_parallel_steps` at a time. After a failure no new
+/// `on_success` steps start; the ones already running finish. `always` and
+/// `on_failure` steps start once their dependencies have settled, however
+/// they ended. A cancelled build has its running steps killed.
+async fn run_steps(
+    conn: &mut diesel_async::AsyncPgConnection,
+    ctx: &StepContext<'_>,
+) -> anyhow::Result<StepsOutcome> {
+    let StepContext { build, pipeline, config, .. } = *ctx;
+    let permits = Arc::new(Semaphore::new(config.max_parallel_steps));
+    let mut state = vec![StepState::Waiting; pipeline.steps.len()];
+    // Attempts started per step, and when a failed step may run again.
+    let mut attempts = vec![0u32; pipeline.steps.len()];
+    let mut retry_at: Vec<Option<Instant>> = vec![None; pipeline.steps.len()];
+    let mut running: HashMap<usize, RunningStep> = HashMap::new();
+    let mut commands = FuturesUnordered::new();
+    let mut failed = false;
+    let mut cancelled = false;
+    let mut cancel_poll = tokio::time::interval(CANCEL_POLL_INTERVAL);
+
+    loop {
+        if !cancelled && cancel_requested(conn, build.id).await? {
+            tracing::info!(build_id = build.id, running = running.len(), "Build cancelled, killing running steps");
+            for step in running.values() {
+                if let Some(pgid) = step.pgid {
+                    kill_process_group(pgid);
+                }
+            }
+            cancelled = true;
+        }
+
+        // Settle the steps a failure rules out, so `always` and
+        // `on_failure` steps waiting on them can start.
+        if failed && !cancelled {
+            for (seq, step_def) in pipeline.steps.iter().enumerate() {
+                if state[seq] == StepState::Waiting && step_def.when == StepWhen::OnSuccess {
+                    step_executor::skip_step(conn, build.id, &step_def.name, (seq + 1) as i32, build.tenant_id, "Skipped (previous step failed)").await?;
+                    state[seq] = StepState::Skipped;
+                    retry_at[seq] = None;
+                }
+            }
+        }
+
+        if !cancelled {
+            for (seq, step_def) in pipeline.steps.iter().enumerate() {
+                let deps_ready = match step_def.when {
+                    StepWhen::OnSuccess => !failed && step_def.depends_on.iter().all(|&dep| matches!(state[dep], StepState::Passed | StepState::AllowedFailure)),
+                    StepWhen::Always => step_def.depends_on.iter().all(|&dep| state[dep].settled()),
+                    StepWhen::OnFailure => failed && step_def.depends_on.iter().all(|&dep| state[dep].settled()),
                 };
-                let stderr = if stderr.len() > 65536 {
-                    format!("...truncated...\n{}", &stderr[stderr.len() - 65536..])
-                } else {
-                    stderr
+                let ready = state[seq] == StepState::Waiting
+                    && retry_at[seq].is_none_or(|at| at <= Instant::now())
+                    && deps_ready;
+                if !ready {
+                    continue;
+                }
+                let Ok(permit) = permits.clone().try_acquire_owned() else {
+                    break;
                 };
-                (code, stdout, stderr)
+                attempts[seq] += 1;
+                retry_at[seq] = None;
+                let (step, child, stop) = launch_step(conn, ctx, seq, attempts[seq]).await?;
+                let span = step.span.clone();
+                let timeout = step.timeout;
+                state[seq] = StepState::Running;
+                running.insert(seq, step);
+                commands.push(
+                    async move {
+                        let _permit = permit;
+                        (seq, wait_step(child, timeout, stop).await)
+                    }
+                    .instrument(span),
+                );
             }
-            Ok(Err(e)) => {
-                (-1, String::new(), format!("Failed to execute command: {e}"))
+        }
+
+        let next_retry = retry_at.iter().flatten().min().copied().filter(|_| !cancelled);
+        if running.is_empty() && next_retry.is_none() {
+            // Nothing left can fail the build, so `on_failure` steps won't
+            // run; settle them and give `always` steps after them a chance.
+            let mut settled_any = false;
+            if !failed && !cancelled {
+                for (seq, step_def) in pipeline.steps.iter().enumerate() {
+                    if state[seq] == StepState::Waiting && step_def.when == StepWhen::OnFailure {
+                        step_executor::skip_step(conn, build.id, &step_def.name, (seq + 1) as i32, build.tenant_id, "Skipped (no step failed)").await?;
+                        state[seq] = StepState::Skipped;
+                        settled_any = true;
+                    }
+                }
             }
-            Err(_) => {
-                (-1, String::new(), format!("Step timed out after {}s", timeout.as_secs()))
+            if settled_any {
+                continue;
             }
+            break;
+        }
+        let retry_wait = next_retry.map(|at| at.saturating_duration_since(Instant::now()));
+        if let Some(longest) = running.values().map(|s| s.timeout).chain(retry_wait).max() {
+            HEALTH.beat(longest);
+        }
+
+        tokio::select! {
+            Some((seq, result)) = commands.next() => {
+                let step = running.remove(&seq).expect("finished step is running");
+                let passed = finish_step(conn, ctx, seq, step, result, cancelled).await?;
+                let step_def = &pipeline.steps[seq];
+                if passed {
+                    state[seq] = StepState::Passed;
+                } else if !cancelled && attempts[seq] <= step_def.retries {
+                    tracing::info!(
+                        build_id = build.id,
+                        step = %step_def.name,
+                        attempt = attempts[seq],
+                        retries = step_def.retries,
+                        delay_secs = step_def.retry_delay_secs,
+                        "Retrying step"
+                    );
+                    state[seq] = StepState::Waiting;
+                    let delay = Duration::from_secs(step_def.retry_delay_secs);
+                    retry_at[seq] = Some(Instant::now().checked_add(delay).unwrap_or_else(Instant::now));
+                } else if !cancelled && step_def.allow_failure {
+                    tracing::info!(build_id = build.id, step = %step_def.name, "Step failure allowed");
+                    state[seq] = StepState::AllowedFailure;
+                } else {
+                    state[seq] = StepState::Failed;
+                    failed = true;
+                }
+            }
+            _ = CANCEL_REQUESTED.notified(), if !cancelled => {}
+            _ = cancel_poll.tick(), if !cancelled => {}
+            _ = tokio::time::sleep(retry_wait.unwrap_or_default()), if retry_wait.is_some() => {}
+        }
+    }
+
+    // Steps still waiting never got to run: the build was cancelled, a
+    // step they need was skipped, or their dependencies form a cycle. Skips
+    // are passed down the graph first, whatever the list order, so only
+    // steps a cycle holds up are left.
+    let mut propagated = !cancelled;
+    while propagated {
+        propagated = false;
+        for (seq, step_def) in pipeline.steps.iter().enumerate() {
+            if state[seq] == StepState::Waiting && step_def.depends_on.iter().any(|&dep| state[dep] == StepState::Skipped) {
+                step_executor::skip_step(conn, build.id, &step_def.name, (seq + 1) as i32, build.tenant_id, "Skipped (dependency skipped)").await?;
+                state[seq] = StepState::Skipped;
+                propagated = true;
+            }
+        }
+    }
+    let mut cycle = false;
+    for (seq, step_def) in pipeline.steps.iter().enumerate() {
+        if state[seq] != StepState::Waiting {
+            continue;
+        }
+        let reason = if cancelled {
+            "Skipped (build cancelled)"
+        } else {
+            cycle = true;
+            "Skipped (dependency cycle)"
         };
+        step_executor::skip_step(conn, build.id, &step_def.name, (seq + 1) as i32, build.tenant_id, reason).await?;
+    }
+
+    Ok(if cancelled {
+        StepsOutcome::Cancelled
+    } else if failed || cycle {
+        StepsOutcome::Failed
+    } else {
+        StepsOutcome::Passed
+    })
+}
 
-        let step_duration = step_start.elapsed().as_millis() as i32;
+/// Record a step starting and spawn its command in its own process group.
+/// Also returns the backend's teardown for the step if it gets killed.
+async fn launch_step(
+    conn: &mut diesel_async::AsyncPgConnection,
+    ctx: &StepContext<'_>,
+    seq: usize,
+    attempt: u32,
+) -> anyhow::Result<(RunningStep, std::io::Result<Child>, Option<Command>)> {
+    let StepContext { build, pipeline, backend, work_dir, secrets, config, .. } = *ctx;
+    let step_def = &pipeline.steps[seq];
+    let started = Instant::now();
+    let span = tracing::info_span!("step", step = %step_def.name, sequence = seq + 1, attempt);
 
-        step_executor::complete_step(
-            &mut conn,
+    let step_id = step_executor::start_attempt(
+        conn,
+        build.id,
+        &step_def.name,
+        (seq + 1) as i32,
+        attempt as i32,
+        build.tenant_id,
+    )
+    .instrument(span.clone())
+    .await?;
+
+    tracing::info!(
+        build_id = build.id,
+        step = %step_def.name,
+        command = %step_def.command,
+        attempt,
+        "Running step"
+    );
+
+    let mut envs: Vec<(&str, String)> = pipeline
+        .env
+        .iter()
+        .chain(&step_def.env)
+        .map(|(k, v)| (k.as_str(), v.clone()))
+        .chain(secrets.iter().map(|s| (s.name.as_str(), s.value.clone())))
+        .collect();
+    envs.extend([
+        ("CI", "true".to_string()),
+        ("CI_BUILD_ID", build.id.to_string()),
+        ("CI_BRANCH", build.branch.clone()),
+        ("CI_COMMIT", build.commit_sha.clone()),
+    ]);
+    // Untrusted pull request builds get no cloud identity, like no secrets.
+    if let Some(signer) = config.oidc_signer.as_ref().filter(|_| build.trusted) {
+        let claims = oidc_service::BuildClaims {
+            project_id: build.project_id,
+            repository: build.github_repo.clone(),
+            build_id: build.id,
+            branch: build.branch.clone(),
+            commit_sha: build.commit_sha.clone(),
+            step: step_def.name.clone(),
+            event: build.trigger_event.clone(),
+            pr_number: build.pr_number,
+        };
+        let audience = pipeline.oidc_audience.as_deref().unwrap_or(signer.issuer());
+        envs.push(("CI_OIDC_TOKEN", signer.issue(audience, &claims, step_def.timeout_secs)?));
+    }
+    // Let in-build tooling export spans into the step's trace.
+    if let Some(traceparent) = span.in_scope(crate::telemetry::traceparent) {
+        if let Some(trace_id) = crate::telemetry::trace_id(&traceparent) {
+            envs.push(("CI_TRACE_ID", trace_id.to_string()));
+        }
+        envs.push(("TRACEPARENT", traceparent));
+        if let Some(ref endpoint) = config.otel.endpoint {
+            envs.push(("OTEL_EXPORTER_OTLP_ENDPOINT", endpoint.clone()));
+        }
+    }
+
+    secret_service::record_access(
+        conn,
+        build.tenant_id,
+        build.project_id,
+        build.id,
+        &step_def.name,
+        secrets,
+    )
+    .await?;
+
+    let run_name = format!("ci-{}-{}", build.id, seq + 1);
+    let mut cmd = backend.command(&run_name, work_dir, &step_def.command, &envs, &step_def.limits);
+    let mut cgroup = None;
+    if !backend.enforces_limits() {
+        if let Some(ref root) = config.cgroup_root {
+            match StepCgroup::create(root, &format!("{run_name}-{attempt}"), &step_def.limits) {
+                Ok(created) => cgroup = Some(created),
+                Err(e) => tracing::warn!(build_id = build.id, step = %step_def.name, "Step cgroup not created, using rlimits: {e}"),
+            }
+        }
+        limits::confine(&mut cmd, &step_def.limits, cgroup.as_ref());
+    }
+    let child = cmd
+        .process_group(0)
+        .kill_on_drop(true)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn();
+    let pgid = child.as_ref().ok().and_then(|c| c.id());
+
+    Ok((
+        RunningStep {
             step_id,
+            started,
+            timeout: Duration::from_secs(step_def.timeout_secs),
+            span,
+            pgid,
+            cgroup,
+        },
+        child,
+        backend.stop_command(&run_name),
+    ))
+}
+
+/// Wait for a step's command, killing its process group on timeout. Runs
+/// `stop` once the command has been killed, whether here or on cancellation.
+async fn wait_step(child: std::io::Result<Child>, timeout: Duration, stop: Option<Command>) -> StepRun {
+    let child = match child {
+        Ok(child) => child,
+        Err(e) => return StepRun::Finished(Err(e)),
+    };
+    let pgid = child.id();
+    let run = match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(result) => StepRun::Finished(result),
+        Err(_) => {
+            if let Some(pgid) = pgid {
+                kill_process_group(pgid);
+            }
+            StepRun::TimedOut
+        }
+    };
+
+    let killed = match run {
+        StepRun::Finished(Ok(ref output)) => output.status.code().is_none(),
+        StepRun::Finished(Err(_)) => false,
+        StepRun::TimedOut => true,
+    };
+    if let Some(mut stop) = stop.filter(|_| killed) {
+        if let Err(e) = stop.kill_on_drop(true).output().await {
+            tracing::warn!("Step teardown failed: {e}");
+        }
+    }
+    run
+}
+
+/// Record a finished step. Returns whether it passed.
+async fn finish_step(
+    conn: &mut diesel_async::AsyncPgConnection,
+    ctx: &StepContext<'_>,
+    seq: usize,
+    step: RunningStep,
+    result: StepRun,
+    cancelled: bool,
+) -> anyhow::Result<bool> {
+    let StepContext { build, pipeline, work_dir, secrets, codeowners, .. } = *ctx;
+    let step_def = &pipeline.steps[seq];
+    let mut timed_out = false;
+    let (mut exit_code, stdout_str, mut stderr_str) = match result {
+        StepRun::Finished(Ok(output)) => {
+            let code = output.status.code().unwrap_or(-1);
+            let stdout = secret_service::mask(&String::from_utf8_lossy(&output.stdout), secrets);
+            let stderr = secret_service::mask(&String::from_utf8_lossy(&output.stderr), secrets);
+            (code, stdout, stderr)
+        }
+        StepRun::Finished(Err(e)) => {
+            (-1, String::new(), format!("Failed to execute command: {e}"))
+        }
+        StepRun::TimedOut => {
+            timed_out = true;
+            (-1, String::new(), format!("Step timed out after {}s", step_def.timeout_secs))
+        }
+    };
+    let step_duration = step.started.elapsed().as_millis() as i32;
+
+    if let Some(cgroup) = step.cgroup {
+        let peak = cgroup.peak_memory();
+        cgroup.remove().await;
+        if let Some(bytes) = peak {
+            step_executor::record_peak_memory(conn, step.step_id, bytes as i64).await?;
+        }
+    }
+
+    if cancelled && exit_code != 0 {
+        tracing::info!(build_id = build.id, step = %step_def.name, "Step killed: build cancelled");
+        stderr_str.push_str("\nKilled (build cancelled)");
+        step_executor::end_step(conn, step.step_id, "cancelled", step_duration, Some(truncate_output(stdout_str)), Some(truncate_output(stderr_str)))
+            .instrument(step.span)
+            .await?;
+        publish_step_finished(conn, build, step.step_id, &step_def.name, "cancelled", step_duration).await;
+        return Ok(false);
+    }
+
+    if let Some(ref report) = step_def.vulnerability_report {
+        let raw = match report.path {
+            Some(ref path) => read_report(work_dir, path).await,
+            None => Ok(stdout_str.clone()),
+        };
+        match raw.and_then(|raw| vulnerability_service::parse_report(report.format, &raw)) {
+            Ok(findings) => {
+                vulnerability_service::record(conn, build.tenant_id, build.id, build.project_id, report.format, &findings).await?;
+                // Audit tools exit non-zero when they find anything;
+                // whether that fails the build is the gate's call.
+                if exit_code > 0 {
+                    exit_code = 0;
+                }
+            }
+            Err(e) => {
+                tracing::warn!(build_id = build.id, step = %step_def.name, "Vulnerability report not parsed: {e}");
+                stderr_str.push_str(&format!("\nVulnerability report not parsed: {e}"));
+            }
+        }
+    }
+
+    // Parse before truncation drops the middle of the output. A timed-out
+    // step's output is cut short, so neither its failures nor its test
+    // counts are complete.
+    let parsed = if timed_out {
+        log_parser::ParsedOutput::default()
+    } else {
+        log_parser::extract(step_def.parser, &stdout_str, &stderr_str, exit_code != 0)
+    };
+
+    // Rendered text can reveal a secret the JSON escaping hid from masking.
+    let stdout_str = match error_service::render_cargo_messages(&stdout_str) {
+        Some(rendered) => secret_service::mask(&rendered, secrets),
+        None => stdout_str,
+    };
+    let stdout_str = truncate_output(stdout_str);
+    let stderr_str = truncate_output(stderr_str);
+
+    let event = if timed_out {
+        step_executor::end_step(conn, step.step_id, "timed_out", step_duration, Some(stdout_str), Some(stderr_str))
+            .instrument(step.span)
+            .await?;
+        CiBuildEvent::StepTimedOut {
+            step_name: step_def.name.clone(),
+            timeout_secs: step_def.timeout_secs,
+            duration_ms: step_duration,
+        }
+    } else {
+        step_executor::complete_step(
+            conn,
+            step.step_id,
             exit_code,
             step_duration,
             Some(stdout_str),
             Some(stderr_str),
         )
+        .instrument(step.span)
         .await?;
+        CiBuildEvent::StepCompleted {
+            step_name: step_def.name.clone(),
+            exit_code,
+            duration_ms: step_duration,
+        }
+    };
+    store::append::<CiBuildAggregate>(conn, build.tenant_id, build.id, &event).await?;
+    let status = match (timed_out, exit_code) {
+        (true, _) => "timed_out",
+        (false, 0) => "success",
+        (false, _) => "failure",
+    };
+    publish_step_finished(conn, build, step.step_id, &step_def.name, status, step_duration).await;
 
-        crate::metrics::step_duration(&step_def.name, step_duration as u64);
-
-        if exit_code != 0 {
-            tracing::warn!(
-                build_id = build.id,
-                step = %step_def.name,
-                exit_code,
-                "Step failed"
-            );
-            all_passed = false;
-            // Mark remaining steps as skipped
-            for (skip_seq, skip_step) in pipeline.steps.iter().enumerate().skip(seq + 1) {
-                let skip_id = step_executor::start_step(
-                    &mut conn,
-                    build.id,
-                    &skip_step.name,
-                    (skip_seq + 1) as i32,
-                    build.tenant_id,
-                )
-                .await?;
-                step_executor::complete_step(&mut conn, skip_id, -1, 0, None, Some("Skipped (previous step failed)".to_string()))
-                    .await?;
-            }
-            break;
+    if let Some(tests) = parsed.tests {
+        if let Err(e) = build_service::record_test_counts(conn, build.id, &step_def.name, tests).await {
+            tracing::warn!(build_id = build.id, step = %step_def.name, "Test counts not recorded: {e}");
         }
+    }
+    if let Err(e) = build_service::record_test_results(conn, build.tenant_id, build.project_id, build.id, step.step_id, &step_def.name, &parsed.results).await {
+        tracing::warn!(build_id = build.id, step = %step_def.name, "Test results not recorded: {e}");
+    }
+    for diagnostic in &parsed.failures.diagnostics {
+        let owners = codeowners.map_or(&[][..], |c| c.owners_of(codeowners::repo_path(&diagnostic.file_path, work_dir)));
+        if let Err(e) = error_service::record_diagnostic(conn, build.id, &step_def.name, diagnostic, owners, build.tenant_id, Some(build.project_id)).await {
+            tracing::warn!(build_id = build.id, step = %step_def.name, "Diagnostic not recorded: {e}");
+        }
+    }
+    for error in &parsed.failures.errors {
+        if let Err(e) = error_service::record_error(conn, build.id, &step_def.name, error, build.tenant_id, Some(build.project_id)).await {
+            tracing::warn!(build_id = build.id, step = %step_def.name, "Error not recorded: {e}");
+        }
+    }
 
-        tracing::info!(
+    crate::metrics::step_duration(&step_def.name, &build.github_repo, &build.branch, step_duration as u64);
+
+    if exit_code != 0 {
+        tracing::warn!(
             build_id = build.id,
             step = %step_def.name,
-            duration_ms = step_duration,
-            "Step passed"
+            exit_code,
+            "Step failed"
         );
+        return Ok(false);
+    }
+
+    tracing::info!(
+        build_id = build.id,
+        step = %step_def.name,
+        duration_ms = step_duration,
+        "Step passed"
+    );
+    Ok(true)
+}
+
+/// Announce a finished step on the build feed. Failures are logged; the
+/// feed is best effort and must not fail the step.
+async fn publish_step_finished(conn: &mut AsyncPgConnection, build: &PendingBuild, step_id: i64, name: &str, status: &str, duration_ms: i32) {
+    let event = build_feed::FeedEvent::StepFinished {
+        build_id: build.id,
+        project_id: build.project_id,
+        step_id,
+        name: name.to_string(),
+        status: status.to_string(),
+        duration_ms,
+    };
+    if let Err(e) = build_feed::publish(conn, build.tenant_id, event).await {
+        tracing::warn!(build_id = build.id, step = name, "Build feed event not sent: {e}");
+    }
+}
+
+/// SIGKILL a step's process group; `kill_on_drop` only reaches the shell.
+fn kill_process_group(pgid: u32) {
+    // SAFETY: killpg has no memory-safety preconditions.
+    unsafe {
+        libc::killpg(pgid as libc::pid_t, libc::SIGKILL);
     }
+}
+
+/// Whether the cancel API has flagged the build.
+async fn cancel_requested(conn: &mut diesel_async::AsyncPgConnection, build_id: i64) -> anyhow::Result<bool> {
+    let requested: Option<chrono::DateTime<chrono::Utc>> = ci_builds::table
+        .find(build_id)
+        .select(ci_builds::cancel_requested_at)
+        .first(conn)
+        .await?;
+    Ok(requested.is_some())
+}
+
+// ── Dagger engine ──
+
+/// One line of `centrix-ci --output json`.
+#[derive(Debug, serde::Deserialize)]
+struct StageEvent {
+    event: String,
+    stage: String,
+    duration_ms: Option<u64>,
+    status: Option<String>,
+    exit_code: Option<i32>,
+    log_path: Option<String>,
+    error: Option<String>,
+    reason: Option<String>,
+}
+
+/// Server variables the Dagger CLI keeps: what it needs to find and reach
+/// the container engine. The rest of the server's environment (database
+/// URL, secrets master key, OIDC signing key) is cleared.
+const DAGGER_HOST_ENV: &[&str] = &[
+    "PATH",
+    "HOME",
+    "USER",
+    "TMPDIR",
+    "XDG_CACHE_HOME",
+    "XDG_CONFIG_HOME",
+    "XDG_RUNTIME_DIR",
+    "DOCKER_HOST",
+    "DOCKER_CONFIG",
+    "_EXPERIMENTAL_DAGGER_RUNNER_HOST",
+    "_EXPERIMENTAL_DAGGER_CLI_BIN",
+];
+
+/// Complete environment of a Dagger run: [`DAGGER_HOST_ENV`] as `host` has
+/// it, then the pipeline's env, secrets and the `CI_*` variables.
+fn dagger_env(build: &PendingBuild, pipeline: &PipelineConfig, secrets: &[secret_service::ResolvedSecret], host: impl Fn(&str) -> Option<String>) -> Vec<(String, String)> {
+    let mut envs: Vec<(String, String)> = DAGGER_HOST_ENV.iter().filter_map(|name| Some((name.to_string(), host(name)?))).collect();
+    envs.extend(pipeline.env.iter().cloned());
+    envs.extend(secrets.iter().map(|s| (s.name.clone(), s.value.clone())));
+    envs.extend([
+        ("CI".to_string(), "true".to_string()),
+        ("CI_BUILD_ID".to_string(), build.id.to_string()),
+        ("CI_BRANCH".to_string(), build.branch.clone()),
+        ("CI_COMMIT".to_string(), build.commit_sha.clone()),
+    ]);
+    envs
+}
 
-    let final_status = if all_passed { "success" } else { "failure" };
-    finish_build(&mut conn, build.id, final_status, build_start, None, &build.github_repo, &build.commit_sha, config).await?;
+/// Fail unless an in-repo pipeline file keeps the project's engine, or the
+/// project's `pipeline_config` lets the file choose (`allow_repo_engine`).
+/// Otherwise anyone who can push could switch a sandboxed shell pipeline to
+/// Dagger, which runs on the host.
+fn check_repo_engine(project_config: Option<&serde_json::Value>, project_dagger: bool, file_dagger: bool) -> Result<(), String> {
+    let allowed = project_config.and_then(|c| c.get("allow_repo_engine")).and_then(|a| a.as_bool()).unwrap_or(false);
+    if project_dagger == file_dagger || allowed {
+        return Ok(());
+    }
+    let engine = |dagger: bool| if dagger { "dagger" } else { "shell" };
+    Err(format!(
+        "in-repo pipeline selects engine {} but the project uses {}; set allow_repo_engine to let the file choose",
+        engine(file_dagger),
+        engine(project_dagger)
+    ))
+}
 
-    // Cleanup cloned workspace (only if we cloned, not local_path)
+/// Run the pipeline through the `ci_pipeline` Dagger binary. Every stage it
+/// reports becomes a step, with the stage's log file as its stdout.
+async fn run_dagger(
+    conn: &mut diesel_async::AsyncPgConnection,
+    ctx: &StepContext<'_>,
+    dagger: &DaggerConfig,
+) -> anyhow::Result<StepsOutcome> {
+    let StepContext { build, pipeline, work_dir, secrets, config, .. } = *ctx;
+    let log_dir = format!("{}/{}-dagger-logs", config.workspace_dir, build.id);
+    let timeout = Duration::from_secs(dagger.timeout_secs);
+    HEALTH.beat(timeout);
+
+    tracing::info!(build_id = build.id, command = %dagger.command, "Running Dagger pipeline");
+    secret_service::record_access(conn, build.tenant_id, build.project_id, build.id, "dagger", secrets).await?;
+
+    let mut command = Command::new(&config.dagger_bin);
+    command
+        .args(["--output", "json", "--log-dir", log_dir.as_str(), dagger.command.as_str(), "--source", work_dir])
+        .args(&dagger.args)
+        .current_dir(work_dir)
+        .env_clear()
+        .envs(dagger_env(build, pipeline, secrets, |name| std::env::var(name).ok()));
+    // The stages run in the engine's containers; this confines the CLI.
+    let cgroup = match config.cgroup_root {
+        Some(ref root) => StepCgroup::create(root, &format!("ci-{}-dagger", build.id), &pipeline.limits)
+            .inspect_err(|e| tracing::warn!(build_id = build.id, "Dagger cgroup not created, using rlimits: {e}"))
+            .ok(),
+        None => None,
+    };
+    limits::confine(&mut command, &pipeline.limits, cgroup.as_ref());
+    command
+        .process_group(0)
+        .kill_on_drop(true)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            if let Some(cgroup) = cgroup {
+                cgroup.remove().await;
+            }
+            let step_id = step_executor::start_step(conn, build.id, "dagger", 1, build.tenant_id).await?;
+            step_executor::complete_step(conn, step_id, -1, 0, None, Some(format!("Failed to run {}: {e}", config.dagger_bin))).await?;
+            return Ok(StepsOutcome::Failed);
+        }
+    };
+    let pgid = child.id();
+    let mut lines = BufReader::new(child.stdout.take().expect("stdout is piped")).lines();
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let stderr = tokio::spawn(async move {
+        let mut out = String::new();
+        let _ = stderr.read_to_string(&mut out).await;
+        out
+    });
+
+    // Stages that have started but not finished, by name.
+    let mut open: HashMap<String, (i64, Instant)> = HashMap::new();
+    let mut seq = 0;
+    let mut failed = false;
+    let mut cancelled = false;
+    let mut timed_out = false;
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    let mut cancel_poll = tokio::time::interval(CANCEL_POLL_INTERVAL);
+
+    loop {
+        let check_cancel = tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else { break };
+                // Anything that isn't an event is Dagger's own chatter.
+                if let Ok(event) = serde_json::from_str::<StageEvent>(&line) {
+                    failed |= record_stage_event(conn, ctx, event, &mut open, &mut seq).await?;
+                }
+                false
+            }
+            _ = &mut deadline => {
+                timed_out = true;
+                break;
+            }
+            _ = CANCEL_REQUESTED.notified() => true,
+            _ = cancel_poll.tick() => true,
+        };
+        if check_cancel && cancel_requested(conn, build.id).await? {
+            cancelled = true;
+            break;
+        }
+    }
+
+    if cancelled || timed_out {
+        if let Some(pgid) = pgid {
+            kill_process_group(pgid);
+        }
+    }
+    let status = child.wait().await?;
+    if let Some(cgroup) = cgroup {
+        cgroup.remove().await;
+    }
+    let stderr = secret_service::mask(&stderr.await.unwrap_or_default(), secrets);
+    let _ = tokio::fs::remove_dir_all(&log_dir).await;
+
+    // Stages still open were cut short by the kill or a crash.
+    let (open_status, note) = if cancelled {
+        ("cancelled", "Killed (build cancelled)".to_string())
+    } else if timed_out {
+        ("timed_out", format!("Pipeline timed out after {}s", dagger.timeout_secs))
+    } else {
+        ("failure", "Pipeline exited before the stage finished".to_string())
+    };
+    for (_, (step_id, started)) in open.drain() {
+        step_executor::end_step(conn, step_id, open_status, started.elapsed().as_millis() as i32, None, Some(note.clone())).await?;
+    }
+    // No stage got going (bad arguments, no engine): keep the binary's own
+    // error output.
+    if seq == 0 && !cancelled {
+        let step_id = step_executor::start_step(conn, build.id, "dagger", 1, build.tenant_id).await?;
+        step_executor::complete_step(conn, step_id, status.code().unwrap_or(-1), 0, None, Some(truncate_output(stderr))).await?;
+    }
+
+    Ok(if cancelled {
+        StepsOutcome::Cancelled
+    } else if failed || timed_out || !status.success() {
+        StepsOutcome::Failed
+    } else {
+        StepsOutcome::Passed
+    })
+}
+
+/// Mirror a Dagger stage event into the build's steps. Returns whether the
+/// stage failed.
+async fn record_stage_event(
+    conn: &mut diesel_async::AsyncPgConnection,
+    ctx: &StepContext<'_>,
+    event: StageEvent,
+    open: &mut HashMap<String, (i64, Instant)>,
+    seq: &mut i32,
+) -> anyhow::Result<bool> {
+    let StepContext { build, secrets, .. } = *ctx;
+    match event.event.as_str() {
+        "started" => {
+            *seq += 1;
+            let step_id = step_executor::start_step(conn, build.id, &event.stage, *seq, build.tenant_id).await?;
+            open.insert(event.stage, (step_id, Instant::now()));
+            Ok(false)
+        }
+        "finished" => {
+            let step_id = match open.remove(&event.stage) {
+                Some((step_id, _)) => step_id,
+                None => {
+                    *seq += 1;
+                    step_executor::start_step(conn, build.id, &event.stage, *seq, build.tenant_id).await?
+                }
+            };
+            let log = match event.log_path {
+                Some(ref path) => tokio::fs::read_to_string(path).await.unwrap_or_default(),
+                None => String::new(),
+            };
+            let passed = event.status.as_deref() == Some("success");
+            let exit_code = event.exit_code.unwrap_or(if passed { 0 } else { 1 });
+            let duration_ms = event.duration_ms.unwrap_or(0) as i32;
+
+            step_executor::complete_step(
+                conn,
+                step_id,
+                exit_code,
+                duration_ms,
+                Some(truncate_output(secret_service::mask(&log, secrets))),
+                event.error.map(|e| truncate_output(secret_service::mask(&e, secrets))),
+            )
+            .await?;
+            store::append::<CiBuildAggregate>(
+                conn,
+                build.tenant_id,
+                build.id,
+                &CiBuildEvent::StepCompleted {
+                    step_name: event.stage.clone(),
+                    exit_code,
+                    duration_ms,
+                },
+            )
+            .await?;
+            crate::metrics::step_duration(&event.stage, &build.github_repo, &build.branch, duration_ms as u64);
+            Ok(exit_code != 0)
+        }
+        "skipped" => {
+            *seq += 1;
+            let reason = format!("Skipped ({})", event.reason.as_deref().unwrap_or("unchanged"));
+            step_executor::skip_step(conn, build.id, &event.stage, *seq, build.tenant_id, &reason).await?;
+            Ok(false)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Cleanup cloned workspace (only if we cloned, not local_path)
+async fn remove_workspace(build: &PendingBuild, pipeline: &PipelineConfig, config: &CiConfig) {
     if pipeline.local_path.is_none() {
         let workspace = format!("{}/{}", config.workspace_dir, build.id);
         let _ = tokio::fs::remove_dir_all(&workspace).await;
     }
+}
 
-    Ok(())
+/// Clone the repo (or update `local_path`) and return the working directory.
+/// Errors are the message the build fails with.
+async fn checkout(build: &PendingBuild, pipeline: &PipelineConfig, config: &CiConfig) -> Result<String, String> {
+    // If local_path, do a git pull to get latest
+    if let Some(ref local_path) = pipeline.local_path {
+        let pull_result = Command::new("git")
+            .args(["pull", "--ff-only"])
+            .current_dir(local_path)
+            .output()
+            .await;
+
+        if let Ok(output) = pull_result {
+            if !output.status.success() {
+                tracing::warn!(
+                    build_id = build.id,
+                    "git pull failed (continuing with current state): {}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+            }
+        }
+        return Ok(local_path.clone());
+    }
+
+    // Clone from the project's provider
+    let clone_url = match build.provider().repo_url(&build.github_repo, config.gitea_url.as_deref()) {
+        Some(url) => format!("{url}.git"),
+        None => return Err("CI_GITEA_URL not set".to_string()),
+    };
+    let workspace = format!("{}/{}", config.workspace_dir, build.id);
+    tokio::fs::create_dir_all(&workspace)
+        .await
+        .map_err(|e| format!("cannot create workspace: {e}"))?;
+    let git_opts = &pipeline.git;
+    let depth = git_opts.depth.map(|d| d.to_string());
+
+    let mut clone = vec!["clone", "--branch", build.branch.as_str()];
+    if let Some(ref depth) = depth {
+        clone.extend(["--depth", depth.as_str()]);
+    }
+    clone.extend(["--end-of-options", clone_url.as_str(), workspace.as_str()]);
+    // LFS objects are pulled once the right commit is checked out.
+    git(&clone, ".", git_opts.lfs).await?;
+
+    // Checkout specific commit if not HEAD. `--end-of-options` keeps a sha
+    // from ever being read as an option.
+    if build.commit_sha != "HEAD" && build.commit_sha.len() >= 7 {
+        let checkout = ["checkout", "--end-of-options", build.commit_sha.as_str()];
+        if git(&checkout, &workspace, git_opts.lfs).await.is_err() {
+            // A shallow clone may not reach the commit; fetch it directly.
+            let mut fetch = vec!["fetch"];
+            if let Some(ref depth) = depth {
+                fetch.extend(["--depth", depth.as_str()]);
+            }
+            fetch.extend(["--end-of-options", "origin", build.commit_sha.as_str()]);
+            git(&fetch, &workspace, git_opts.lfs).await?;
+            git(&checkout, &workspace, git_opts.lfs).await?;
+        }
+    }
+
+    if git_opts.submodules != Submodules::None {
+        let mut update = vec!["submodule", "update", "--init"];
+        if git_opts.submodules == Submodules::Recursive {
+            update.push("--recursive");
+        }
+        if let Some(ref depth) = depth {
+            update.extend(["--depth", depth.as_str()]);
+        }
+        git(&update, &workspace, git_opts.lfs).await?;
+    }
+
+    if git_opts.lfs {
+        git(&["lfs", "pull"], &workspace, false).await?;
+        if git_opts.submodules != Submodules::None {
+            let mut foreach = vec!["submodule", "foreach"];
+            if git_opts.submodules == Submodules::Recursive {
+                foreach.push("--recursive");
+            }
+            foreach.extend(["git", "lfs", "pull"]);
+            git(&foreach, &workspace, false).await?;
+        }
+    }
+
+    Ok(workspace)
+}
+
+/// Run a git command in `dir`, turning a failure into the message recorded
+/// in the build summary. `skip_lfs` defers LFS downloads.
+async fn git(args: &[&str], dir: &str, skip_lfs: bool) -> Result<(), String> {
+    let mut cmd = Command::new("git");
+    cmd.args(args).current_dir(dir);
+    if skip_lfs {
+        cmd.env("GIT_LFS_SKIP_SMUDGE", "1");
+    }
+    match cmd.output().await {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(format!("git {} failed: {}", args[0], String::from_utf8_lossy(&output.stderr).trim())),
+        Err(e) => Err(format!("git {} error: {e}", args[0])),
+    }
 }
 
-/// Update build to terminal status with timing, then post GitHub commit status.
+/// Claim the oldest pending build whose tenant has concurrency to spare,
+/// mark it running, and queue the "pending" GitHub commit status, all in
+/// one transaction. Nothing is claimed while `max_concurrent_builds` are
+/// running. The running builds are counted under [`CLAIM_LOCK`], so two
+/// servers cannot both take the last slot.
+async fn claim_build(
+    conn: &mut diesel_async::AsyncPgConnection,
+    config: &CiConfig,
+) -> anyhow::Result<Option<PendingBuild>> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        async move {
+            diesel::sql_query("SELECT pg_advisory_xact_lock($1)")
+                .bind::<diesel::sql_types::BigInt, _>(CLAIM_LOCK)
+                .execute(conn)
+                .await?;
+            let running: i64 = ci_builds::table
+                .filter(ci_builds::status.eq(BuildStatus::Running.as_str()))
+                .count()
+                .get_result(conn)
+                .await?;
+            if running >= config.max_concurrent_builds as i64 {
+                return Ok(None);
+            }
+
+            let build_id: Option<i64> = ci_builds::table
+                .filter(ci_builds::status.eq(BuildStatus::Pending.as_str()))
+                .filter(diesel::dsl::sql::<diesel::sql_types::Bool>(
+                    quota_service::CLAIMABLE_TENANT_SQL,
+                ))
+                .order(ci_builds::id.asc())
+                .select(ci_builds::id)
+                .for_update()
+                .skip_locked()
+                .first(conn)
+                .await
+                .optional()?;
+            let Some(build_id) = build_id else {
+                return Ok(None);
+            };
+
+            let build: PendingBuild = ci_builds::table
+                .inner_join(ci_projects::table)
+                .filter(ci_builds::id.eq(build_id))
+                .select((
+                    ci_builds::id,
+                    ci_builds::tenant_id,
+                    ci_builds::project_id,
+                    ci_builds::commit_sha,
+                    ci_builds::branch,
+                    ci_projects::github_repo,
+                    ci_projects::pipeline_config,
+                    ci_builds::trace_context,
+                    ci_builds::trigger_event,
+                    ci_builds::trigger_id,
+                    ci_projects::default_branch,
+                    ci_projects::provider,
+                    ci_builds::pr_number,
+                    ci_builds::trusted,
+                ))
+                .first(conn)
+                .await?;
+            let target_url = format!("{}/api/builds/{}", config.dashboard_url, build.id);
+
+            build_service::update_status(conn, build.id, BuildStatus::Running).await?;
+            diesel::update(ci_builds::table.find(build.id))
+                .set(ci_builds::started_at.eq(chrono::Utc::now()))
+                .execute(conn)
+                .await?;
+            store::append::<CiBuildAggregate>(
+                conn,
+                build.tenant_id,
+                build.id,
+                &CiBuildEvent::BuildStarted,
+            )
+            .await?;
+            build_feed::publish(conn, build.tenant_id, build_feed::FeedEvent::BuildStarted { build_id: build.id, project_id: build.project_id }).await?;
+
+            outbox_service::enqueue(
+                conn,
+                build.tenant_id,
+                &OutboxMessage::GithubStatus {
+                    repo: build.github_repo.clone(),
+                    sha: build.commit_sha.clone(),
+                    state: "pending".to_string(),
+                    description: "Build running".to_string(),
+                    target_url,
+                    context: "centrix-ci".to_string(),
+                    annotations: Vec::new(),
+                }
+                .for_provider(build.provider()),
+            )
+            .await?;
+            Ok(Some(build))
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Update build to terminal status with timing and queue the final GitHub
+/// commit status, the notifications, and for PR builds the summary comment,
+/// in the same transaction.
 async fn finish_build(
     conn: &mut diesel_async::AsyncPgConnection,
-    build_id: i64,
-    status: &str,
+    build: &PendingBuild,
+    status: BuildStatus,
     start: Instant,
     error_msg: Option<&str>,
-    github_repo: &str,
-    commit_sha: &str,
     config: &CiConfig,
 ) -> anyhow::Result<()> {
+    let build_id = build.id;
     let duration = start.elapsed().as_millis() as i32;
 
-    let summary = error_msg.map(|msg| serde_json::json!({"error": msg}));
+    // Maps CI status to GitHub status API values
+    let gh_state = match status {
+        BuildStatus::Success => "success",
+        BuildStatus::Failure => "failure",
+        _ => "error",
+    };
+    let description = match (status, error_msg) {
+        // The message can carry text of the repository; cut on a character.
+        (_, Some(msg)) => {
+            let end = msg.char_indices().nth(140).map_or(msg.len(), |(i, _)| i);
+            format!("Build #{build_id} failed: {}", &msg[..end])
+        }
+        (BuildStatus::Success, _) => format!("Build #{build_id} passed ({duration}ms)"),
+        _ => format!("Build #{build_id} {status}"),
+    };
+    let target_url = format!("{}/api/builds/{}", config.dashboard_url, build_id);
+    let event = match status {
+        BuildStatus::Success => CiBuildEvent::BuildSucceeded {
+            duration_ms: duration,
+        },
+        BuildStatus::Cancelled => CiBuildEvent::BuildCancelled,
+        _ => CiBuildEvent::BuildFailed {
+            duration_ms: duration,
+            error_summary: error_msg.map(|msg| msg.to_string()),
+        },
+    };
+    let notification = match status {
+        BuildStatus::Success => "build.succeeded",
+        BuildStatus::Cancelled => "build.cancelled",
+        _ => "build.failed",
+    };
 
-    diesel::update(ci_builds::table.find(build_id))
-        .set((
-            ci_builds::status.eq(status),
-            ci_builds::finished_at.eq(chrono::Utc::now()),
-            ci_builds::duration_ms.eq(duration),
-            ci_builds::summary.eq(summary),
-        ))
-        .execute(conn)
-        .await?;
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        async move {
+            build_service::update_status(conn, build_id, status).await?;
+            diesel::update(ci_builds::table.find(build_id))
+                .set((
+                    ci_builds::finished_at.eq(chrono::Utc::now()),
+                    ci_builds::duration_ms.eq(duration),
+                ))
+                .execute(conn)
+                .await?;
+            if let Some(msg) = error_msg {
+                build_service::merge_summary(conn, build_id, serde_json::json!({"error": msg})).await?;
+            }
+            store::append::<CiBuildAggregate>(conn, build.tenant_id, build_id, &event).await?;
+
+            let annotations = if config.github_checks && status != BuildStatus::Success && build.provider() == Provider::Github {
+                check_annotations(conn, build_id).await?
+            } else {
+                Vec::new()
+            };
+            outbox_service::enqueue(
+                conn,
+                build.tenant_id,
+                &OutboxMessage::GithubStatus {
+                    repo: build.github_repo.clone(),
+                    sha: build.commit_sha.clone(),
+                    state: gh_state.to_string(),
+                    description,
+                    target_url: target_url.clone(),
+                    context: "centrix-ci".to_string(),
+                    annotations,
+                }
+                .for_provider(build.provider()),
+            )
+            .await?;
+
+            if let Some(pr_number) = build.pr_number {
+                let steps = step_executor::latest_attempts(conn, build_id).await?;
+                let body = pr_comment::render(&pr_comment::Summary {
+                    build_id,
+                    commit_sha: &build.commit_sha,
+                    status,
+                    duration_ms: duration,
+                    error: error_msg,
+                    steps: &steps,
+                    target_url: &target_url,
+                });
+                outbox_service::enqueue(
+                    conn,
+                    build.tenant_id,
+                    &OutboxMessage::GithubPrComment {
+                        repo: build.github_repo.clone(),
+                        pr_number,
+                        body,
+                        sticky: Some(pr_comment::STICKY_KEY.to_string()),
+                    }
+                    .for_provider(build.provider()),
+                )
+                .await?;
+            }
 
-    crate::metrics::build_status_changed(status);
-    crate::metrics::build_duration(duration as u64);
+            build_feed::publish(
+                conn,
+                build.tenant_id,
+                build_feed::FeedEvent::BuildCompleted { build_id, project_id: build.project_id, status: status.as_str().to_string(), duration_ms: Some(duration) },
+            )
+            .await?;
+            if let Some(finished) = build_service::get_build(conn, build_id).await? {
+                notification_service::notify(conn, build.tenant_id, build.project_id, notification, &finished).await?;
+            }
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    crate::metrics::build_duration(&build.github_repo, &build.branch, duration as u64);
+    crate::metrics::runner_build_finished(&config.runner_name, status != BuildStatus::Success);
 
     tracing::info!(
         build_id,
-        status,
+        status = %status,
         duration_ms = duration,
         "Build finished"
     );
 
-    // Post final status to GitHub (maps CI status to GitHub status API values)
-    let gh_state = match status {
-        "success" => "success",
-        "failure" => "failure",
-        _ => "error",
-    };
-    let description = match (status, error_msg) {
-        (_, Some(msg)) => format!("Build #{build_id} failed: {}", &msg[..msg.len().min(140)]),
-        ("success", _) => format!("Build #{build_id} passed ({duration}ms)"),
-        _ => format!("Build #{build_id} {status}"),
-    };
-    let target_url = format!("{}/api/builds/{}", config.dashboard_url, build_id);
-    let _ = github_service::post_status(
-        &config.github_token,
-        github_repo,
-        commit_sha,
-        gh_state,
-        &description,
-        &target_url,
-        "centrix-ci",
-    )
-    .await;
-
     Ok(())
 }
 
+/// Check run annotations for the located errors a build recorded.
+async fn check_annotations(conn: &mut AsyncPgConnection, build_id: i64) -> anyhow::Result<Vec<CheckAnnotation>> {
+    let located = error_service::located_for_build(conn, build_id, github_service::MAX_ANNOTATIONS as i64).await?;
+    Ok(located
+        .into_iter()
+        .filter_map(|(error, occurrence)| {
+            Some(CheckAnnotation {
+                path: occurrence.file_path?,
+                line: occurrence.line_number?,
+                level: if error.severity == "warning" { "warning" } else { "failure" }.to_string(),
+                title: occurrence.step_name,
+                message: occurrence.raw_output.unwrap_or(error.title),
+            })
+        })
+        .collect())
+}
+
 // ── Pipeline config parsing ──
 
 #[derive(Debug, Clone, Queryable)]
 struct PendingBuild {
     pub id: i64,
     pub tenant_id: uuid::Uuid,
+    pub project_id: i64,
     pub commit_sha: String,
     pub branch: String,
     pub github_repo: String,
     pub pipeline_config: Option<serde_json::Value>,
+    pub trace_context: Option<String>,
+    pub trigger_event: String,
+    pub trigger_id: Option<i64>,
+    pub default_branch: String,
+    pub provider: String,
+    pub pr_number: Option<i32>,
+    pub trusted: bool,
+}
+
+impl PendingBuild {
+    fn provider(&self) -> Provider {
+        Provider::parse(&self.provider).unwrap_or(Provider::Github)
+    }
 }
 
 struct PipelineConfig {
     steps: Vec<StepDef>,
-    timeout_secs: u64,
     local_path: Option<String>,
+    sandbox: SandboxConfig,
+    /// Ceiling on every step's resource limits.
+    limits: ResourceLimits,
+    /// Variables set for every step.
+    env: Vec<(String, String)>,
+    /// `aud` claim of step OIDC tokens; defaults to the issuer.
+    oidc_audience: Option<String>,
+    /// Fail on new vulnerabilities at or above this severity.
+    vulnerability_gate: Option<Severity>,
+    /// Set for `engine: "dagger"`: the pipeline runs through `ci_pipeline`
+    /// instead of `steps`.
+    dagger: Option<DaggerConfig>,
+    /// Workspace directories kept between builds.
+    cache: Vec<CacheSpec>,
+    /// How the repository is cloned.
+    git: GitOptions,
+    /// Shape of the PR's review environment.
+    environment: EnvironmentTemplate,
+}
+
+/// `git: { depth, submodules, lfs }` of a project's pipeline config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct GitOptions {
+    /// Shallow clone depth; `None` (`depth: 0`) clones the full history.
+    depth: Option<u32>,
+    submodules: Submodules,
+    lfs: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Submodules {
+    None,
+    /// Only the repository's own submodules.
+    Top,
+    Recursive,
+}
+
+impl Default for GitOptions {
+    fn default() -> Self {
+        Self { depth: Some(1), submodules: Submodules::None, lfs: false }
+    }
+}
+
+impl GitOptions {
+    fn from_value(value: Option<&serde_json::Value>) -> Self {
+        let Some(value) = value else {
+            return Self::default();
+        };
+        let submodules = match value.get("submodules") {
+            Some(serde_json::Value::Bool(true)) => Submodules::Top,
+            Some(serde_json::Value::String(s)) if s == "recursive" => Submodules::Recursive,
+            _ => Submodules::None,
+        };
+        Self {
+            depth: match value.get("depth").and_then(|d| d.as_u64()) {
+                Some(0) => None,
+                Some(depth) => Some(depth.min(u32::MAX as u64) as u32),
+                None => Some(1),
+            },
+            submodules,
+            lfs: value.get("lfs").and_then(|l| l.as_bool()).unwrap_or(false),
+        }
+    }
+}
+
+struct DaggerConfig {
+    /// `centrix-ci` subcommand to run.
+    command: String,
+    /// Extra arguments after `--source`.
+    args: Vec<String>,
+    timeout_secs: u64,
 }
 
 struct StepDef {
     name: String,
     command: String,
+    env: Vec<(String, String)>,
+    /// The step's own `timeout_secs`, else the pipeline's.
+    timeout_secs: u64,
+    /// Indices of the steps that must pass before this one starts.
+    depends_on: Vec<usize>,
+    /// Extra attempts after a failure or timeout.
+    retries: u32,
+    retry_delay_secs: u64,
+    /// A failure after the last attempt doesn't fail the build.
+    allow_failure: bool,
+    when: StepWhen,
+    /// The step's own `limits` within the pipeline's.
+    limits: ResourceLimits,
+    vulnerability_report: Option<VulnerabilityReport>,
+    /// The step's `parser`, else the one its name and command suggest.
+    parser: LogParser,
+}
+
+/// A step's `when`: what its dependencies and the rest of the build must
+/// have done for it to run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StepWhen {
+    /// Once its dependencies passed, unless a step has failed. The default.
+    OnSuccess,
+    /// Once its dependencies finished, e.g. for cleanup.
+    Always,
+    /// Once its dependencies finished, only if a step has failed.
+    OnFailure,
+}
+
+impl StepWhen {
+    fn parse(when: Option<&str>) -> Self {
+        match when {
+            Some("always") => StepWhen::Always,
+            Some("on_failure") => StepWhen::OnFailure,
+            _ => StepWhen::OnSuccess,
+        }
+    }
+}
+
+struct VulnerabilityReport {
+    format: ReportFormat,
+    /// Report file relative to the workspace; stdout when unset.
+    path: Option<String>,
+}
+
+/// Keep the last 64KB of step output.
+fn truncate_output(output: String) -> String {
+    if output.len() > 65536 {
+        let mut start = output.len() - 65536;
+        while !output.is_char_boundary(start) {
+            start += 1;
+        }
+        format!("...truncated...\n{}", &output[start..])
+    } else {
+        output
+    }
+}
+
+/// Read a `{"NAME": "value"}` object into env pairs, ignoring non-strings.
+fn parse_env(value: Option<&serde_json::Value>) -> Vec<(String, String)> {
+    value
+        .and_then(|v| v.as_object())
+        .map(|obj| {
+            obj.iter()
+                .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
-fn parse_pipeline(config: &Option<serde_json::Value>) -> PipelineConfig {
+/// Env of the trigger behind a build: the one that queued it, else the
+/// project's first active trigger for its event that covers the branch
+/// (see [`trigger_service`]).
+async fn trigger_env(conn: &mut diesel_async::AsyncPgConnection, build: &PendingBuild) -> anyhow::Result<Vec<(String, String)>> {
+    let env: Option<Option<serde_json::Value>> = match build.trigger_id {
+        Some(trigger_id) => ci_triggers::table
+            .filter(ci_triggers::id.eq(trigger_id))
+            .filter(ci_triggers::active.eq(true))
+            .select(ci_triggers::env)
+            .first(conn)
+            .await
+            .optional()?,
+        None => {
+            let triggers = trigger_service::for_event(conn, build.project_id, &build.trigger_event).await?;
+            trigger_service::for_branch(&triggers, &build.branch).next().map(|t| t.env.clone())
+        }
+    };
+    Ok(parse_env(env.flatten().as_ref()))
+}
+
+fn parse_pipeline(config: &Option<serde_json::Value>, sandbox_default: bool) -> PipelineConfig {
+    let sandbox = SandboxConfig::from_pipeline(config.as_ref(), sandbox_default);
     let config = match config {
         Some(v) => v,
         None => {
@@ -377,14 +1950,39 @@ fn parse_pipeline(config: &Option<serde_json::Value>) -> PipelineConfig {
                 steps: vec![StepDef {
                     name: "check".to_string(),
                     command: "echo 'No pipeline configured'".to_string(),
+                    env: Vec::new(),
+                    timeout_secs: 600,
+                    depends_on: Vec::new(),
+                    retries: 0,
+                    retry_delay_secs: DEFAULT_RETRY_DELAY_SECS,
+                    allow_failure: false,
+                    when: StepWhen::OnSuccess,
+                    limits: ResourceLimits::default(),
+                    vulnerability_report: None,
+                    parser: LogParser::Generic,
                 }],
-                timeout_secs: 600,
                 local_path: None,
+                sandbox,
+                limits: ResourceLimits::default(),
+                env: Vec::new(),
+                oidc_audience: None,
+                vulnerability_gate: None,
+                dagger: None,
+                cache: Vec::new(),
+                git: GitOptions::default(),
+                environment: EnvironmentTemplate::default(),
             };
         }
     };
 
-    let steps = config
+    let timeout_secs = config
+        .get("timeout_secs")
+        .and_then(|t| t.as_u64())
+        .unwrap_or(600)
+        .min(pipeline_file::MAX_TIMEOUT_SECS);
+    let limits = ResourceLimits::from_value(config.get("limits"));
+
+    let parsed: Vec<(StepDef, Option<Vec<String>>)> = config
         .get("steps")
         .and_then(|s| s.as_array())
         .map(|arr| {
@@ -392,16 +1990,49 @@ fn parse_pipeline(config: &Option<serde_json::Value>) -> PipelineConfig {
                 .filter_map(|step| {
                     let name = step.get("name")?.as_str()?.to_string();
                     let command = step.get("command")?.as_str()?.to_string();
-                    Some(StepDef { name, command })
+                    let env = parse_env(step.get("env"));
+                    let step_timeout = step.get("timeout_secs").and_then(|t| t.as_u64()).map_or(timeout_secs, |t| t.min(pipeline_file::MAX_TIMEOUT_SECS));
+                    let vulnerability_report = step.get("vulnerability_report").and_then(|r| {
+                        Some(VulnerabilityReport {
+                            format: ReportFormat::parse(r.get("format")?.as_str()?)?,
+                            path: r.get("path").and_then(|p| p.as_str()).map(|s| s.to_string()),
+                        })
+                    });
+                    let depends_on = step.get("depends_on").and_then(|d| d.as_array()).map(|deps| {
+                        deps.iter().filter_map(|d| d.as_str()).map(|d| d.to_string()).collect()
+                    });
+                    let retries = step.get("retries").and_then(|r| r.as_u64()).unwrap_or(0).min(pipeline_file::MAX_STEP_RETRIES) as u32;
+                    let retry_delay_secs = step.get("retry_delay_secs").and_then(|d| d.as_u64()).unwrap_or(DEFAULT_RETRY_DELAY_SECS).min(pipeline_file::MAX_RETRY_DELAY_SECS);
+                    let allow_failure = step.get("allow_failure").and_then(|a| a.as_bool()).unwrap_or(false);
+                    let when = StepWhen::parse(step.get("when").and_then(|w| w.as_str()));
+                    let step_limits = ResourceLimits::from_value(step.get("limits")).within(limits);
+                    let parser = step.get("parser").and_then(|p| p.as_str()).and_then(LogParser::parse).unwrap_or_else(|| LogParser::detect(&name, &command));
+                    let step = StepDef { name, command, env, timeout_secs: step_timeout, depends_on: Vec::new(), retries, retry_delay_secs, allow_failure, when, limits: step_limits, vulnerability_report, parser };
+                    Some((step, depends_on))
                 })
                 .collect()
         })
         .unwrap_or_default();
 
-    let timeout_secs = config
-        .get("timeout_secs")
-        .and_then(|t| t.as_u64())
-        .unwrap_or(600);
+    // Without any `depends_on` the steps run in order, each after the one
+    // before it. Unknown step names are ignored.
+    let dag = parsed.iter().any(|(_, deps)| deps.is_some());
+    let names: Vec<String> = parsed.iter().map(|(step, _)| step.name.clone()).collect();
+    let steps = parsed
+        .into_iter()
+        .enumerate()
+        .map(|(seq, (mut step, deps))| {
+            step.depends_on = if dag {
+                deps.unwrap_or_default()
+                    .iter()
+                    .filter_map(|dep| names.iter().position(|name| name == dep))
+                    .collect()
+            } else {
+                seq.checked_sub(1).into_iter().collect()
+            };
+            step
+        })
+        .collect();
 
     let local_path = config
         .get("local_path")
@@ -410,7 +2041,109 @@ fn parse_pipeline(config: &Option<serde_json::Value>) -> PipelineConfig {
 
     PipelineConfig {
         steps,
-        timeout_secs,
         local_path,
+        sandbox,
+        limits,
+        env: parse_env(config.get("env")),
+        oidc_audience: config
+            .get("oidc_audience")
+            .and_then(|a| a.as_str())
+            .map(|s| s.to_string()),
+        vulnerability_gate: config.get("vulnerability_gate").map(|g| {
+            g.get("threshold")
+                .and_then(|t| t.as_str())
+                .map(Severity::parse)
+                .unwrap_or(Severity::High)
+        }),
+        dagger: (config.get("engine").and_then(|e| e.as_str()) == Some("dagger")).then(|| {
+            let dagger = config.get("dagger");
+            DaggerConfig {
+                command: dagger
+                    .and_then(|d| d.get("command"))
+                    .and_then(|c| c.as_str())
+                    .unwrap_or("all")
+                    .to_string(),
+                args: dagger
+                    .and_then(|d| d.get("args"))
+                    .and_then(|a| a.as_array())
+                    .map(|args| args.iter().filter_map(|a| a.as_str()).map(|a| a.to_string()).collect())
+                    .unwrap_or_default(),
+                timeout_secs,
+            }
+        }),
+        cache: cache_service::parse(Some(config)),
+        git: GitOptions::from_value(config.get("git")),
+        environment: EnvironmentTemplate::from_pipeline(Some(config)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build() -> PendingBuild {
+        PendingBuild {
+            id: 42,
+            tenant_id: uuid::Uuid::nil(),
+            project_id: 1,
+            commit_sha: "abc123".to_string(),
+            branch: "main".to_string(),
+            github_repo: "acme/app".to_string(),
+            pipeline_config: None,
+            trace_context: None,
+            trigger_event: "push".to_string(),
+            trigger_id: None,
+            default_branch: "main".to_string(),
+            provider: "github".to_string(),
+            pr_number: None,
+            trusted: true,
+        }
+    }
+
+    #[test]
+    fn dagger_env_drops_the_server_environment() {
+        let pipeline = parse_pipeline(&Some(serde_json::json!({"engine": "dagger", "env": {"RUST_LOG": "info"}})), false);
+        let secrets = [secret_service::ResolvedSecret { id: 1, name: "DEPLOY_KEY".to_string(), value: "s3cret".to_string() }];
+        let host = |name: &str| match name {
+            "PATH" => Some("/usr/bin".to_string()),
+            "DATABASE_URL" | "CI_SECRETS_MASTER_KEY" | "CI_OIDC_SIGNING_KEY" => Some("server only".to_string()),
+            _ => None,
+        };
+
+        let envs = dagger_env(&build(), &pipeline, &secrets, host);
+        let get = |name: &str| envs.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+        assert_eq!(get("PATH"), Some("/usr/bin"));
+        assert_eq!(get("RUST_LOG"), Some("info"));
+        assert_eq!(get("DEPLOY_KEY"), Some("s3cret"));
+        assert_eq!(get("CI_BUILD_ID"), Some("42"));
+        for name in ["DATABASE_URL", "CI_SECRETS_MASTER_KEY", "CI_OIDC_SIGNING_KEY", "HOME"] {
+            assert_eq!(get(name), None, "{name} leaked into the Dagger run");
+        }
+    }
+
+    #[test]
+    fn repo_files_keep_the_project_engine() {
+        assert!(check_repo_engine(None, false, false).is_ok());
+        assert!(check_repo_engine(None, true, true).is_ok());
+        assert!(check_repo_engine(None, false, true).is_err());
+        assert!(check_repo_engine(Some(&serde_json::json!({"steps": []})), true, false).is_err());
+        assert!(check_repo_engine(Some(&serde_json::json!({"allow_repo_engine": true})), false, true).is_ok());
+    }
+
+    #[test]
+    fn dagger_pipelines_parse_their_invocation() {
+        let config = serde_json::json!({
+            "engine": "dagger",
+            "timeout_secs": 900,
+            "dagger": {"command": "test", "args": ["--base", "origin/main"]},
+        });
+        let dagger = parse_pipeline(&Some(config), false).dagger.expect("dagger engine");
+        assert_eq!(dagger.command, "test");
+        assert_eq!(dagger.args, ["--base", "origin/main"]);
+        assert_eq!(dagger.timeout_secs, 900);
+
+        let dagger = parse_pipeline(&Some(serde_json::json!({"engine": "dagger"})), false).dagger.expect("dagger engine");
+        assert_eq!(dagger.command, "all");
+        assert!(parse_pipeline(&Some(serde_json::json!({"steps": []})), false).dagger.is_none());
     }
 }
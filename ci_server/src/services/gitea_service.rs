@@ -0,0 +1,227 @@
+//! Gitea / Forgejo integration — commit statuses, PR comments, PR changed
+//! files and repository listings.
+//!
+//! Webhooks are signed like GitHub's (hex HMAC-SHA256 of the body, without
+//! the `sha256=` prefix) and checked with
+//! [`github_service::validate_signature`](super::github_service::validate_signature).
+
+use crate::config::CiConfig;
+
+/// The instance URL and token, or why Gitea can't be called.
+fn instance(config: &CiConfig) -> anyhow::Result<(&str, &str)> {
+    let url = config
+        .gitea_url
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("CI_GITEA_URL not set"))?;
+    Ok((url, &config.gitea_token))
+}
+
+/// Post a commit status to Gitea.
+#[tracing::instrument(name = "gitea.post_status", skip_all, fields(%repo, %sha, %state))]
+pub async fn post_status(
+    config: &CiConfig,
+    repo: &str,
+    sha: &str,
+    state: &str,
+    description: &str,
+    target_url: &str,
+    context: &str,
+) -> anyhow::Result<()> {
+    let (base_url, token) = instance(config)?;
+    if token.is_empty() {
+        tracing::debug!("Gitea token not set, skipping status update");
+        return Ok(());
+    }
+
+    let url = format!("{base_url}/api/v1/repos/{repo}/statuses/{sha}");
+    let body = serde_json::json!({
+        "state": state,
+        "description": description,
+        "target_url": target_url,
+        "context": context,
+    });
+
+    let resp = reqwest::Client::new()
+        .post(&url)
+        .header("Authorization", format!("token {token}"))
+        .header("User-Agent", "centrix-ci")
+        .json(&body)
+        .send()
+        .await?;
+
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let text = resp.text().await.unwrap_or_default();
+        anyhow::bail!("Gitea status update failed: {} {}", status, text);
+    }
+
+    Ok(())
+}
+
+/// Paths changed by a PR.
+#[tracing::instrument(name = "gitea.pr_changed_files", skip_all, fields(%repo, pr_number))]
+pub async fn pr_changed_files(
+    config: &CiConfig,
+    repo: &str,
+    pr_number: i32,
+) -> anyhow::Result<Vec<String>> {
+    let (base_url, token) = instance(config)?;
+    if token.is_empty() {
+        anyhow::bail!("Gitea token not set");
+    }
+
+    let client = reqwest::Client::new();
+    let mut files = Vec::new();
+    for page in 1..=60 {
+        let url =
+            format!("{base_url}/api/v1/repos/{repo}/pulls/{pr_number}/files?limit=50&page={page}");
+        let resp = client
+            .get(&url)
+            .header("Authorization", format!("token {token}"))
+            .header("User-Agent", "centrix-ci")
+            .send()
+            .await?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().await.unwrap_or_default();
+            anyhow::bail!("Gitea PR files request failed: {} {}", status, text);
+        }
+
+        let entries: Vec<serde_json::Value> = resp.json().await?;
+        let last = entries.len() < 50;
+        files.extend(
+            entries
+                .iter()
+                .filter_map(|f| f["filename"].as_str())
+                .map(str::to_string),
+        );
+        if last {
+            break;
+        }
+    }
+    Ok(files)
+}
+
+/// Names of the files and directories at the root of `repo` at `git_ref`.
+#[tracing::instrument(name = "gitea.root_files", skip_all, fields(%repo, %git_ref))]
+pub async fn root_files(
+    config: &CiConfig,
+    repo: &str,
+    git_ref: &str,
+) -> anyhow::Result<Vec<String>> {
+    let (base_url, token) = instance(config)?;
+
+    let url = format!("{base_url}/api/v1/repos/{repo}/contents?ref={git_ref}");
+    let mut request = reqwest::Client::new()
+        .get(&url)
+        .header("User-Agent", "centrix-ci");
+    if !token.is_empty() {
+        request = request.header("Authorization", format!("token {token}"));
+    }
+    let resp = request.send().await?;
+
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let text = resp.text().await.unwrap_or_default();
+        anyhow::bail!("Gitea contents request failed: {} {}", status, text);
+    }
+
+    let entries: Vec<serde_json::Value> = resp.json().await?;
+    Ok(entries
+        .iter()
+        .filter_map(|e| e["name"].as_str())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Post a comment on a PR.
+#[tracing::instrument(name = "gitea.post_pr_comment", skip_all, fields(%repo, pr_number))]
+pub async fn post_pr_comment(
+    config: &CiConfig,
+    repo: &str,
+    pr_number: i32,
+    body: &str,
+) -> anyhow::Result<()> {
+    let (base_url, token) = instance(config)?;
+    if token.is_empty() {
+        return Ok(());
+    }
+
+    let url = format!("{base_url}/api/v1/repos/{repo}/issues/{pr_number}/comments");
+    let resp = reqwest::Client::new()
+        .post(&url)
+        .header("Authorization", format!("token {token}"))
+        .header("User-Agent", "centrix-ci")
+        .json(&serde_json::json!({ "body": body }))
+        .send()
+        .await?;
+
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let text = resp.text().await.unwrap_or_default();
+        anyhow::bail!("Gitea PR comment failed: {} {}", status, text);
+    }
+
+    Ok(())
+}
+
+/// Post the PR comment identified by `key`, editing an earlier one in
+/// place; see [`GithubClient::upsert_pr_comment`](super::github_service::GithubClient::upsert_pr_comment).
+#[tracing::instrument(name = "gitea.upsert_pr_comment", skip_all, fields(%repo, pr_number, %key))]
+pub async fn upsert_pr_comment(
+    config: &CiConfig,
+    repo: &str,
+    pr_number: i32,
+    key: &str,
+    body: &str,
+) -> anyhow::Result<()> {
+    let (base_url, token) = instance(config)?;
+    if token.is_empty() {
+        return Ok(());
+    }
+
+    let marker = super::github_service::sticky_marker(key);
+    let body = format!("{marker}\n{body}");
+    let client = reqwest::Client::new();
+
+    let url = format!("{base_url}/api/v1/repos/{repo}/issues/{pr_number}/comments");
+    let resp = client
+        .get(&url)
+        .header("Authorization", format!("token {token}"))
+        .header("User-Agent", "centrix-ci")
+        .send()
+        .await?;
+
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let text = resp.text().await.unwrap_or_default();
+        anyhow::bail!("Gitea PR comments request failed: {} {}", status, text);
+    }
+
+    let comments: Vec<serde_json::Value> = resp.json().await?;
+    let existing = comments
+        .iter()
+        .find(|c| c["body"].as_str().is_some_and(|b| b.starts_with(&marker)))
+        .and_then(|c| c["id"].as_i64());
+    let Some(comment_id) = existing else {
+        return post_pr_comment(config, repo, pr_number, &body).await;
+    };
+
+    let url = format!("{base_url}/api/v1/repos/{repo}/issues/comments/{comment_id}");
+    let resp = client
+        .patch(&url)
+        .header("Authorization", format!("token {token}"))
+        .header("User-Agent", "centrix-ci")
+        .json(&serde_json::json!({ "body": body }))
+        .send()
+        .await?;
+
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let text = resp.text().await.unwrap_or_default();
+        anyhow::bail!("Gitea PR comment update failed: {} {}", status, text);
+    }
+
+    Ok(())
+}
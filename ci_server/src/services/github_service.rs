@@ -1,10 +1,586 @@
-//! GitHub integration — webhook validation, status updates, PR comments.
+//! GitHub integration — webhook validation, status updates, check runs,
+//! deployments, PR comments, changed files and repository listings.
+//!
+//! API calls go through one shared [`GithubClient`], which reuses
+//! connections, picks the token for each repository, retries server errors
+//! and secondary rate limits with backoff, and reports the rate limit left
+//! as metrics.
+
+use std::time::Duration;
 
 use hmac::{Hmac, Mac};
+use reqwest::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 
 type HmacSha256 = Hmac<Sha256>;
 
+/// Attempts per request, including the first.
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Backoff before the first retry; doubled for every further one.
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Longest wait before a retry. A rate limit that resets later than this
+/// fails the request instead of holding up the caller.
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(60);
+
+/// Per-request timeout, including reading the body.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// GitHub API client shared by every caller.
+#[derive(Clone)]
+pub struct GithubClient {
+    http: reqwest::Client,
+    default_token: String,
+    /// `(owner, token)` and `(owner/repo, token)` overrides, lowercased.
+    tokens: Vec<(String, String)>,
+}
+
+impl std::fmt::Debug for GithubClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GithubClient")
+            .field("default_token", &!self.default_token.is_empty())
+            .field(
+                "tokens",
+                &self
+                    .tokens
+                    .iter()
+                    .map(|(scope, _)| scope)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl GithubClient {
+    /// Client using `default_token` for every repository not matched by an
+    /// entry of `tokens`: `owner=token` or `owner/repo=token` pairs,
+    /// comma-separated, as in `CI_GITHUB_TOKENS`.
+    pub fn new(default_token: String, tokens: &str) -> anyhow::Result<Self> {
+        let tokens = tokens
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (scope, token) = entry
+                    .split_once('=')
+                    .ok_or_else(|| anyhow::anyhow!("token entries must be owner[/repo]=token"))?;
+                Ok((scope.trim().to_lowercase(), token.trim().to_string()))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let http = reqwest::Client::builder()
+            .user_agent("centrix-ci")
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(Self {
+            http,
+            default_token,
+            tokens,
+        })
+    }
+
+    /// Token for `repo` (`owner/name`): its own, else its owner's, else the
+    /// default. Empty when none is configured.
+    pub fn token_for(&self, repo: &str) -> &str {
+        let repo = repo.to_lowercase();
+        let owner = repo.split('/').next().unwrap_or_default();
+        self.tokens
+            .iter()
+            .find(|(scope, _)| *scope == repo)
+            .or_else(|| self.tokens.iter().find(|(scope, _)| scope == owner))
+            .map_or(&self.default_token, |(_, token)| token)
+    }
+
+    /// Whether any token is configured.
+    pub fn has_token(&self) -> bool {
+        !self.default_token.is_empty() || !self.tokens.is_empty()
+    }
+
+    /// A request to `https://api.github.com{path}` on behalf of `repo`,
+    /// authenticated when it has a token.
+    fn request(&self, method: Method, repo: &str, path: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .http
+            .request(method, format!("https://api.github.com{path}"))
+            .header("Accept", "application/vnd.github+json");
+        match self.token_for(repo) {
+            "" => request,
+            token => request.header("Authorization", format!("Bearer {token}")),
+        }
+    }
+
+    /// Send a request, retrying server errors, rate limits and failed
+    /// connections with exponential backoff, or after the delay GitHub
+    /// asks for. Latency, outcome and the rate limit reported in the
+    /// response headers are recorded under `endpoint` for every attempt.
+    async fn send(
+        &self,
+        endpoint: &str,
+        mut request: reqwest::RequestBuilder,
+    ) -> anyhow::Result<reqwest::Response> {
+        let mut attempt = 1;
+        loop {
+            // Bodies are buffered JSON, so every request can be cloned.
+            let next = (attempt < MAX_ATTEMPTS)
+                .then(|| request.try_clone())
+                .flatten();
+
+            let started = std::time::Instant::now();
+            let result = request.send().await;
+            let elapsed_ms = started.elapsed().as_millis() as u64;
+
+            let wait = match &result {
+                Ok(resp) => {
+                    crate::metrics::github_request(
+                        endpoint,
+                        Some(resp.status().as_u16()),
+                        elapsed_ms,
+                    );
+                    record_rate_limit(endpoint, resp);
+                    retry_wait(resp, attempt)
+                }
+                Err(e) => {
+                    crate::metrics::github_request(endpoint, None, elapsed_ms);
+                    (e.is_connect() || e.is_timeout()).then(|| backoff(attempt))
+                }
+            };
+
+            match (wait, next) {
+                (Some(wait), Some(next)) if wait <= MAX_RETRY_WAIT => {
+                    tracing::debug!(
+                        endpoint,
+                        attempt,
+                        wait_ms = wait.as_millis() as u64,
+                        "Retrying GitHub API request"
+                    );
+                    crate::metrics::github_retry(endpoint);
+                    tokio::time::sleep(wait).await;
+                    request = next;
+                    attempt += 1;
+                }
+                _ => return result.map_err(Into::into),
+            }
+        }
+    }
+
+    /// Post a commit status.
+    #[tracing::instrument(name = "github.post_status", skip_all, fields(%repo, %sha, %state))]
+    pub async fn post_status(
+        &self,
+        repo: &str,
+        sha: &str,
+        state: &str,
+        description: &str,
+        target_url: &str,
+        context: &str,
+    ) -> anyhow::Result<()> {
+        if self.token_for(repo).is_empty() {
+            tracing::debug!("GitHub token not set, skipping status update");
+            return Ok(());
+        }
+
+        let body = serde_json::json!({
+            "state": state,
+            "description": description,
+            "target_url": target_url,
+            "context": context,
+        });
+        let resp = self
+            .send(
+                "statuses",
+                self.request(Method::POST, repo, &format!("/repos/{repo}/statuses/{sha}"))
+                    .json(&body),
+            )
+            .await?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().await.unwrap_or_default();
+            anyhow::bail!("GitHub status update failed: {} {}", status, text);
+        }
+
+        Ok(())
+    }
+
+    /// Post a check run for a commit. Needs a GitHub App installation
+    /// token; personal access tokens cannot create check runs.
+    #[tracing::instrument(name = "github.post_check_run", skip_all, fields(%repo, %sha, state = run.state))]
+    pub async fn post_check_run(
+        &self,
+        repo: &str,
+        sha: &str,
+        run: &CheckRun<'_>,
+    ) -> anyhow::Result<()> {
+        if self.token_for(repo).is_empty() {
+            tracing::debug!("GitHub token not set, skipping check run");
+            return Ok(());
+        }
+
+        let annotations: Vec<serde_json::Value> = run
+            .annotations
+            .iter()
+            .take(MAX_ANNOTATIONS)
+            .map(|a| {
+                serde_json::json!({
+                    "path": a.path,
+                    "start_line": a.line,
+                    "end_line": a.line,
+                    "annotation_level": a.level,
+                    "title": a.title,
+                    "message": a.message,
+                })
+            })
+            .collect();
+        let mut body = serde_json::json!({
+            "name": run.name,
+            "head_sha": sha,
+            "details_url": run.details_url,
+            "output": {
+                "title": run.description,
+                "summary": run.description,
+                "annotations": annotations,
+            },
+        });
+        match run.state {
+            "pending" => body["status"] = "in_progress".into(),
+            state => {
+                body["status"] = "completed".into();
+                body["conclusion"] = match state {
+                    "success" => "success",
+                    _ => "failure",
+                }
+                .into();
+            }
+        }
+
+        let resp = self
+            .send(
+                "check_runs",
+                self.request(Method::POST, repo, &format!("/repos/{repo}/check-runs"))
+                    .json(&body),
+            )
+            .await?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().await.unwrap_or_default();
+            anyhow::bail!("GitHub check run failed: {} {}", status, text);
+        }
+
+        Ok(())
+    }
+
+    /// Record a deployment of `sha` to `environment`, live at
+    /// `environment_url`. Earlier deployments to the environment are marked
+    /// inactive by GitHub.
+    #[tracing::instrument(name = "github.post_deployment", skip_all, fields(%repo, %sha, %environment))]
+    pub async fn post_deployment(
+        &self,
+        repo: &str,
+        sha: &str,
+        environment: &str,
+        environment_url: &str,
+        description: &str,
+    ) -> anyhow::Result<()> {
+        if self.token_for(repo).is_empty() {
+            tracing::debug!("GitHub token not set, skipping deployment");
+            return Ok(());
+        }
+
+        let body = serde_json::json!({
+            "ref": sha,
+            "environment": environment,
+            "description": description,
+            "auto_merge": false,
+            "required_contexts": [],
+            "transient_environment": true,
+            "production_environment": false,
+        });
+        let resp = self
+            .send(
+                "deployments",
+                self.request(Method::POST, repo, &format!("/repos/{repo}/deployments"))
+                    .json(&body),
+            )
+            .await?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().await.unwrap_or_default();
+            anyhow::bail!("GitHub deployment failed: {} {}", status, text);
+        }
+        let deployment: serde_json::Value = resp.json().await?;
+        let Some(deployment_id) = deployment["id"].as_i64() else {
+            anyhow::bail!("GitHub deployment failed: no deployment ID in response");
+        };
+
+        let body = serde_json::json!({
+            "state": "success",
+            "environment_url": environment_url,
+            "description": description,
+            "auto_inactive": true,
+        });
+        let resp = self
+            .send(
+                "deployment_statuses",
+                self.request(
+                    Method::POST,
+                    repo,
+                    &format!("/repos/{repo}/deployments/{deployment_id}/statuses"),
+                )
+                .json(&body),
+            )
+            .await?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().await.unwrap_or_default();
+            anyhow::bail!("GitHub deployment status failed: {} {}", status, text);
+        }
+
+        Ok(())
+    }
+
+    /// Post a comment on a PR.
+    #[tracing::instrument(name = "github.post_pr_comment", skip_all, fields(%repo, pr_number))]
+    pub async fn post_pr_comment(
+        &self,
+        repo: &str,
+        pr_number: i32,
+        body: &str,
+    ) -> anyhow::Result<()> {
+        if self.token_for(repo).is_empty() {
+            return Ok(());
+        }
+
+        let resp = self
+            .send(
+                "issue_comments",
+                self.request(
+                    Method::POST,
+                    repo,
+                    &format!("/repos/{repo}/issues/{pr_number}/comments"),
+                )
+                .json(&serde_json::json!({ "body": body })),
+            )
+            .await?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().await.unwrap_or_default();
+            anyhow::bail!("GitHub PR comment failed: {} {}", status, text);
+        }
+
+        Ok(())
+    }
+
+    /// Post the PR comment identified by `key`, editing it in place if an
+    /// earlier one exists, so the PR carries a single copy.
+    #[tracing::instrument(name = "github.upsert_pr_comment", skip_all, fields(%repo, pr_number, %key))]
+    pub async fn upsert_pr_comment(
+        &self,
+        repo: &str,
+        pr_number: i32,
+        key: &str,
+        body: &str,
+    ) -> anyhow::Result<()> {
+        if self.token_for(repo).is_empty() {
+            return Ok(());
+        }
+
+        let marker = sticky_marker(key);
+        let body = format!("{marker}\n{body}");
+
+        let mut existing = None;
+        for page in 1..=10 {
+            let resp = self
+                .send(
+                    "issue_comments",
+                    self.request(
+                        Method::GET,
+                        repo,
+                        &format!(
+                            "/repos/{repo}/issues/{pr_number}/comments?per_page=100&page={page}"
+                        ),
+                    ),
+                )
+                .await?;
+
+            if !resp.status().is_success() {
+                let status = resp.status();
+                let text = resp.text().await.unwrap_or_default();
+                anyhow::bail!("GitHub PR comments request failed: {} {}", status, text);
+            }
+
+            let comments: Vec<serde_json::Value> = resp.json().await?;
+            existing = comments
+                .iter()
+                .find(|c| c["body"].as_str().is_some_and(|b| b.starts_with(&marker)))
+                .and_then(|c| c["id"].as_i64());
+            if existing.is_some() || comments.len() < 100 {
+                break;
+            }
+        }
+
+        let Some(comment_id) = existing else {
+            return self.post_pr_comment(repo, pr_number, &body).await;
+        };
+
+        let resp = self
+            .send(
+                "issue_comments",
+                self.request(
+                    Method::PATCH,
+                    repo,
+                    &format!("/repos/{repo}/issues/comments/{comment_id}"),
+                )
+                .json(&serde_json::json!({ "body": body })),
+            )
+            .await?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().await.unwrap_or_default();
+            anyhow::bail!("GitHub PR comment update failed: {} {}", status, text);
+        }
+
+        Ok(())
+    }
+
+    /// Paths changed by a PR. GitHub lists at most 3000 files.
+    #[tracing::instrument(name = "github.pr_changed_files", skip_all, fields(%repo, pr_number))]
+    pub async fn pr_changed_files(
+        &self,
+        repo: &str,
+        pr_number: i32,
+    ) -> anyhow::Result<Vec<String>> {
+        if self.token_for(repo).is_empty() {
+            anyhow::bail!("GitHub token not set");
+        }
+
+        let mut files = Vec::new();
+        for page in 1..=30 {
+            let resp = self
+                .send(
+                    "pull_files",
+                    self.request(
+                        Method::GET,
+                        repo,
+                        &format!("/repos/{repo}/pulls/{pr_number}/files?per_page=100&page={page}"),
+                    ),
+                )
+                .await?;
+
+            if !resp.status().is_success() {
+                let status = resp.status();
+                let text = resp.text().await.unwrap_or_default();
+                anyhow::bail!("GitHub PR files request failed: {} {}", status, text);
+            }
+
+            let entries: Vec<serde_json::Value> = resp.json().await?;
+            let last = entries.len() < 100;
+            files.extend(
+                entries
+                    .iter()
+                    .filter_map(|f| f["filename"].as_str())
+                    .map(str::to_string),
+            );
+            if last {
+                break;
+            }
+        }
+        Ok(files)
+    }
+
+    /// Names of the files and directories at the root of `repo` at
+    /// `git_ref`. Public repos can be listed without a token.
+    #[tracing::instrument(name = "github.root_files", skip_all, fields(%repo, %git_ref))]
+    pub async fn root_files(&self, repo: &str, git_ref: &str) -> anyhow::Result<Vec<String>> {
+        let resp = self
+            .send(
+                "contents",
+                self.request(
+                    Method::GET,
+                    repo,
+                    &format!("/repos/{repo}/contents?ref={git_ref}"),
+                ),
+            )
+            .await?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().await.unwrap_or_default();
+            anyhow::bail!("GitHub contents request failed: {} {}", status, text);
+        }
+
+        let entries: Vec<serde_json::Value> = resp.json().await?;
+        Ok(entries
+            .iter()
+            .filter_map(|e| e["name"].as_str())
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+/// Exponential backoff before retry number `attempt`.
+fn backoff(attempt: u32) -> Duration {
+    BASE_BACKOFF * 2u32.pow(attempt - 1)
+}
+
+/// How long to wait before retrying `resp`, or `None` if it should not be
+/// retried. Server errors back off exponentially; rate limits (429, or 403
+/// with `retry-after` or no requests remaining) wait as long as GitHub
+/// says.
+fn retry_wait(resp: &reqwest::Response, attempt: u32) -> Option<Duration> {
+    let header = |name: &str| {
+        resp.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+    };
+    let status = resp.status();
+
+    if status.is_server_error() {
+        return Some(backoff(attempt));
+    }
+    if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::FORBIDDEN {
+        return None;
+    }
+    if let Some(secs) = header("retry-after") {
+        return Some(Duration::from_secs(secs));
+    }
+    if header("x-ratelimit-remaining") == Some(0) {
+        let now = chrono::Utc::now().timestamp() as u64;
+        return header("x-ratelimit-reset")
+            .map(|reset| Duration::from_secs(reset.saturating_sub(now) + 1));
+    }
+    // A 403 without rate limit headers is a permission problem.
+    (status == StatusCode::TOO_MANY_REQUESTS).then(|| backoff(attempt))
+}
+
+/// Record the rate limit reported in `resp`'s headers under `endpoint`,
+/// warning when less than a tenth is left.
+fn record_rate_limit(endpoint: &str, resp: &reqwest::Response) {
+    let header = |name: &str| {
+        resp.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+    };
+    if let (Some(remaining), Some(limit)) =
+        (header("x-ratelimit-remaining"), header("x-ratelimit-limit"))
+    {
+        crate::metrics::github_rate_limit(endpoint, remaining, limit, header("x-ratelimit-reset"));
+        if remaining * 10 < limit {
+            tracing::warn!(
+                endpoint,
+                remaining,
+                limit,
+                "GitHub API rate limit nearly exhausted"
+            );
+        }
+    }
+}
+
 /// Validate a GitHub webhook signature (X-Hub-Signature-256).
 pub fn validate_signature(secret: &str, payload: &[u8], signature: &str) -> bool {
     if secret.is_empty() {
@@ -27,71 +603,34 @@ pub fn validate_signature(secret: &str, payload: &[u8], signature: &str) -> bool
     mac.verify_slice(&sig_bytes).is_ok()
 }
 
-/// Post a commit status to GitHub.
-pub async fn post_status(
-    token: &str,
-    repo: &str,
-    sha: &str,
-    state: &str,
-    description: &str,
-    target_url: &str,
-    context: &str,
-) -> anyhow::Result<()> {
-    if token.is_empty() {
-        tracing::debug!("GitHub token not set, skipping status update");
-        return Ok(());
-    }
-
-    let url = format!("https://api.github.com/repos/{repo}/statuses/{sha}");
-    let body = serde_json::json!({
-        "state": state,
-        "description": description,
-        "target_url": target_url,
-        "context": context,
-    });
-
-    let client = reqwest::Client::new();
-    let resp = client
-        .post(&url)
-        .header("Authorization", format!("Bearer {token}"))
-        .header("Accept", "application/vnd.github+json")
-        .header("User-Agent", "centrix-ci")
-        .json(&body)
-        .send()
-        .await?;
-
-    if !resp.status().is_success() {
-        let status = resp.status();
-        let text = resp.text().await.unwrap_or_default();
-        tracing::warn!("GitHub status update failed: {} {}", status, text);
-    }
-
-    Ok(())
+/// GitHub accepts at most this many annotations per check run request.
+pub const MAX_ANNOTATIONS: usize = 50;
+
+/// A check run annotation, shown inline in the PR diff.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckAnnotation {
+    /// Path relative to the repository root.
+    pub path: String,
+    pub line: i32,
+    /// `failure`, `warning` or `notice`.
+    pub level: String,
+    pub title: String,
+    pub message: String,
+}
+
+/// A build result to report as a check run.
+#[derive(Debug)]
+pub struct CheckRun<'a> {
+    pub name: &'a str,
+    /// Commit status state: `pending` leaves the run `in_progress`,
+    /// anything else completes it.
+    pub state: &'a str,
+    pub description: &'a str,
+    pub details_url: &'a str,
+    pub annotations: &'a [CheckAnnotation],
 }
 
-/// Post a comment on a PR.
-pub async fn post_pr_comment(
-    token: &str,
-    repo: &str,
-    pr_number: i32,
-    body: &str,
-) -> anyhow::Result<()> {
-    if token.is_empty() {
-        return Ok(());
-    }
-
-    let url = format!("https://api.github.com/repos/{repo}/issues/{pr_number}/comments");
-    let payload = serde_json::json!({ "body": body });
-
-    let client = reqwest::Client::new();
-    client
-        .post(&url)
-        .header("Authorization", format!("Bearer {token}"))
-        .header("Accept", "application/vnd.github+json")
-        .header("User-Agent", "centrix-ci")
-        .json(&payload)
-        .send()
-        .await?;
-
-    Ok(())
+/// Hidden first line identifying the sticky comment `key` on a PR.
+pub fn sticky_marker(key: &str) -> String {
+    format!("<!-- centrix-ci:{key} -->")
 }
@@ -0,0 +1,172 @@
+//! Resource limits for step processes.
+//!
+//! A pipeline's `limits` block caps every step, and a step's own `limits`
+//! can tighten but not loosen them:
+//!
+//! ```json
+//! { "limits": { "memory_mb": 4096, "cpus": 2.0, "nice": 10 } }
+//! ```
+//!
+//! With `CI_CGROUP_ROOT` pointing at a cgroup v2 directory delegated to the
+//! server (with the `memory` and `cpu` controllers enabled in its
+//! `cgroup.subtree_control`), each step runs in a child cgroup of its own:
+//! `memory_mb` becomes `memory.max`, `cpus` becomes `cpu.max`, and the
+//! cgroup's `memory.peak` is recorded as the step's `max_rss_bytes`. Without
+//! it, `memory_mb` falls back to an address-space rlimit per process and
+//! `cpus` is not enforced. `nice` applies either way.
+//!
+//! Container steps pass `memory_mb` and `cpus` to the runtime instead.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tokio::process::Command;
+
+/// Highest (least favourable) `nice` value.
+pub const MAX_NICE: i64 = 19;
+
+/// `cpu.max` period in microseconds.
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// Per-step resource limits. Unset fields are unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ResourceLimits {
+    pub memory_mb: Option<u64>,
+    pub cpus: Option<f64>,
+    pub nice: Option<i32>,
+}
+
+impl ResourceLimits {
+    /// Parse a `limits` block, ignoring malformed fields.
+    pub fn from_value(value: Option<&serde_json::Value>) -> Self {
+        let field = |name: &str| value.and_then(|v| v.get(name));
+        Self {
+            memory_mb: field("memory_mb")
+                .and_then(|m| m.as_u64())
+                .filter(|m| *m > 0),
+            cpus: field("cpus").and_then(|c| c.as_f64()).filter(|c| *c > 0.0),
+            nice: field("nice")
+                .and_then(|n| n.as_i64())
+                .filter(|n| (0..=MAX_NICE).contains(n))
+                .map(|n| n as i32),
+        }
+    }
+
+    /// These limits, tightened to stay within `ceiling`.
+    pub fn within(self, ceiling: Self) -> Self {
+        Self {
+            memory_mb: tighter(self.memory_mb, ceiling.memory_mb, u64::min),
+            cpus: tighter(self.cpus, ceiling.cpus, f64::min),
+            nice: tighter(self.nice, ceiling.nice, i32::max),
+        }
+    }
+}
+
+fn tighter<T>(a: Option<T>, b: Option<T>, pick: fn(T, T) -> T) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(pick(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// A step's cgroup under `CI_CGROUP_ROOT`.
+pub struct StepCgroup {
+    path: PathBuf,
+}
+
+impl StepCgroup {
+    /// Create the cgroup `name` under `root` and write its limits.
+    pub fn create(root: &str, name: &str, limits: &ResourceLimits) -> io::Result<Self> {
+        let path = Path::new(root).join(name);
+        std::fs::create_dir_all(&path)?;
+        let cgroup = Self { path };
+        if let Some(mb) = limits.memory_mb {
+            cgroup.write("memory.max", &(mb * 1024 * 1024).to_string())?;
+        }
+        if let Some(cpus) = limits.cpus {
+            let quota = ((cpus * CPU_PERIOD_US as f64) as u64).max(1000);
+            cgroup.write("cpu.max", &format!("{quota} {CPU_PERIOD_US}"))?;
+        }
+        Ok(cgroup)
+    }
+
+    fn write(&self, file: &str, value: &str) -> io::Result<()> {
+        std::fs::write(self.path.join(file), value)
+    }
+
+    /// Peak memory use of everything that ran in the cgroup.
+    pub fn peak_memory(&self) -> Option<u64> {
+        std::fs::read_to_string(self.path.join("memory.peak"))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// Kill whatever is left in the cgroup and remove it.
+    pub async fn remove(self) {
+        let _ = self.write("cgroup.kill", "1");
+        // rmdir fails while the killed processes are still exiting.
+        for _ in 0..50 {
+            match tokio::fs::remove_dir(&self.path).await {
+                Ok(()) => return,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+        tracing::warn!(cgroup = %self.path.display(), "Step cgroup not removed");
+    }
+}
+
+/// Apply `limits` to a step command run directly on the host: join
+/// `cgroup` if there is one, else cap memory with `RLIMIT_AS`, and renice.
+pub fn confine(cmd: &mut Command, limits: &ResourceLimits, cgroup: Option<&StepCgroup>) {
+    let procs =
+        cgroup.and_then(|c| CString::new(c.path.join("cgroup.procs").as_os_str().as_bytes()).ok());
+    let address_space = limits
+        .memory_mb
+        .filter(|_| cgroup.is_none())
+        .map(|mb| (mb * 1024 * 1024) as libc::rlim_t);
+    let nice = limits.nice;
+    if procs.is_none() && address_space.is_none() && nice.is_none() {
+        return;
+    }
+
+    // SAFETY: the hook runs in the forked child before exec and only makes
+    // async-signal-safe calls (open, write, close, setrlimit, setpriority)
+    // on values prepared beforehand.
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(ref procs) = procs {
+                let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // Writing 0 moves the writing process.
+                let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+                libc::close(fd);
+                if written < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(bytes) = address_space {
+                let limit = libc::rlimit {
+                    rlim_cur: bytes,
+                    rlim_max: bytes,
+                };
+                if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(nice) = nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,712 @@
+//! Per-ecosystem parsing of step output.
+//!
+//! Each step's output is read by a parser for the tool it runs, named by
+//! the step's `parser` key or else guessed from its name and command (see
+//! [`LogParser::detect`]). A parser extracts the failures of a failed step
+//! for `ci_errors` (failing tests with the line they failed at, compiler
+//! and linter messages) and the test counts of any step for the build
+//! summary:
+//!
+//! ```json
+//! {"tests": {"passed": 412, "failed": 1, "skipped": 3,
+//!            "steps": {"test": {"passed": 412, "failed": 1, "skipped": 3}}}}
+//! ```
+//!
+//! Test runners that name each test as it finishes (libtest and nextest,
+//! `pytest -v` and `--durations`, `go test -v`, Jest's verbose reporter)
+//! also yield per-test results, with their duration where printed, for
+//! `ci_test_results`.
+//!
+//! Output a parser finds no failures in falls back to
+//! [`error_service::extract_failures`], which also covers cargo and tools
+//! printing `file:line: error:` messages. `go test` only reports passing
+//! tests with `-v`.
+
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::services::error_service::{self, Diagnostic, StepFailures};
+
+/// libtest: `test result: FAILED. 3 passed; 1 failed; 2 ignored; ...`.
+static CARGO_RESULT_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^test result: \w+\. (\d+) passed; (\d+) failed; (\d+) ignored").unwrap()
+});
+/// libtest: `test parser::tests::empty ... ok`.
+static CARGO_TEST_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^test (\S+) \.\.\. (ok|FAILED|ignored)").unwrap());
+/// nextest: `        PASS [   0.012s] ci_server parser::tests::empty`.
+static NEXTEST_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^\s*(PASS|FAIL|TIMEOUT|SIGSEGV|SIGABRT|SKIP) \[\s*(\d+(?:\.\d+)?)s\] (.+?)\s*$")
+        .unwrap()
+});
+/// pytest summary: `=== 1 failed, 12 passed, 2 skipped in 0.52s ===`.
+static PYTEST_SUMMARY_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^=*\s*(\d+ \w+(?:, \d+ \w+)*) in \d+(?:\.\d+)?s\b").unwrap());
+/// pytest short summary: `FAILED tests/test_api.py::test_get - assert 404 == 200`.
+static PYTEST_FAILED_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(?:FAILED|ERROR) (\S+?)(?: - (.+))?$").unwrap());
+/// pytest -v: `tests/test_api.py::test_get PASSED   [ 50%]`.
+static PYTEST_VERBOSE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(\S+::\S+) (PASSED|FAILED|ERROR|SKIPPED|XFAIL|XPASS)\b").unwrap()
+});
+/// pytest --durations: `0.52s call     tests/test_api.py::test_get`.
+static PYTEST_DURATION_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(\d+(?:\.\d+)?)s call\s+(\S+)$").unwrap());
+/// pytest traceback section header: `____ TestApi.test_get ____`.
+static PYTEST_SECTION_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^_{3,} (.+?) _{3,}$").unwrap());
+/// pytest traceback location: `tests/test_api.py:14: AssertionError`.
+static PYTEST_LOCATION_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(\S+\.py):(\d+): ").unwrap());
+/// go test: `=== RUN   TestParse/empty`.
+static GO_RUN_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^=== (?:RUN|CONT)\s+(\S+)").unwrap());
+/// go test: `--- FAIL: TestParse (0.00s)`; subtests are indented.
+static GO_RESULT_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(\s*)--- (PASS|FAIL|SKIP): (\S+)(?: \((\d+(?:\.\d+)?)s\))?").unwrap()
+});
+/// go test log line: `    parse_test.go:12: got 1, want 2`.
+static GO_LOG_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s+(\S+\.go):(\d+): (.+)$").unwrap());
+/// go build error: `./parse.go:12:5: undefined: token`.
+static GO_BUILD_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(\S+\.go):(\d+):\d+: (.+)$").unwrap());
+/// Jest summary: `Tests:       1 failed, 2 skipped, 40 passed, 43 total`.
+static JEST_SUMMARY_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^Tests:\s+(.+), \d+ total$").unwrap());
+/// Jest verbose: `    ✓ parses input (3 ms)`; `√` and `×` on Windows.
+static JEST_TEST_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^\s+(✓|√|✕|×|○|✎) (?:skipped |todo )?(.+?)(?: \((\d+) ms\))?$").unwrap()
+});
+/// Jest failure header: `  ● Parser › rejects empty input`.
+static JEST_FAILURE_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\s*● (.+)$").unwrap());
+/// Stack frame: `at Object.<anonymous> (src/parser.test.js:12:5)`.
+static JS_FRAME_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s*at .*?\(?([^\s():]+):(\d+):\d+\)?$").unwrap());
+/// `N word` pairs of test summaries.
+static COUNT_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(\d+) (\w+)").unwrap());
+/// ESLint stylish message, under its file: `  12:5  error  'x' is unused  no-unused-vars`.
+static ESLINT_STYLISH_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^\s+(\d+):\d+\s+(error|warning)\s+(.+?)(?:\s{2,}(\S+))?$").unwrap()
+});
+/// ESLint unix format: `src/app.js:12:5: 'x' is unused [Error/no-unused-vars]`.
+static ESLINT_UNIX_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^([^:\s]+):(\d+):\d+: (.+) \[(Error|Warning)(?:/(\S+))?\]$").unwrap()
+});
+/// Maven compiler message: `[ERROR] /src/App.java:[12,5] cannot find symbol`.
+static MAVEN_COMPILER_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\[(ERROR|WARNING)\] (\S+?):\[(\d+),\d+\] (.+)$").unwrap());
+/// Surefire failure in the results: `[ERROR]   AppTest.testAdd:15 expected:<1> but was:<2>`.
+static SUREFIRE_FAILURE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\[ERROR\]\s{2,}(\S+:\d+)\s+(.+)$").unwrap());
+/// Surefire counts: `Tests run: 12, Failures: 1, Errors: 0, Skipped: 2`.
+static SUREFIRE_COUNTS_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"Tests run: (\d+), Failures: (\d+), Errors: (\d+), Skipped: (\d+)(, Time elapsed)?")
+        .unwrap()
+});
+
+/// How a step's output is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogParser {
+    Cargo,
+    Pytest,
+    GoTest,
+    Jest,
+    Eslint,
+    Maven,
+    /// Compiler-style messages and `error:` lines only.
+    Generic,
+}
+
+impl LogParser {
+    /// Every parser, as named in a step's `parser` key.
+    pub const NAMES: &'static [&'static str] = &[
+        "cargo", "pytest", "go-test", "jest", "eslint", "maven", "generic",
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Cargo => "cargo",
+            Self::Pytest => "pytest",
+            Self::GoTest => "go-test",
+            Self::Jest => "jest",
+            Self::Eslint => "eslint",
+            Self::Maven => "maven",
+            Self::Generic => "generic",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "cargo" => Some(Self::Cargo),
+            "pytest" => Some(Self::Pytest),
+            "go-test" => Some(Self::GoTest),
+            "jest" => Some(Self::Jest),
+            "eslint" => Some(Self::Eslint),
+            "maven" => Some(Self::Maven),
+            "generic" => Some(Self::Generic),
+            _ => None,
+        }
+    }
+
+    /// The parser for a step without a `parser` key: the first tool its
+    /// command names, else the first its name does. `npm test` and other
+    /// wrappers don't say what they run, and get [`LogParser::Generic`].
+    pub fn detect(name: &str, command: &str) -> Self {
+        [command, name]
+            .into_iter()
+            .find_map(Self::named_in)
+            .unwrap_or(Self::Generic)
+    }
+
+    fn named_in(text: &str) -> Option<Self> {
+        let words: Vec<&str> = text
+            .split(|c: char| !(c.is_ascii_alphanumeric() || c == '.'))
+            .filter(|w| !w.is_empty())
+            .collect();
+        words.iter().enumerate().find_map(|(i, word)| match *word {
+            "cargo" => Some(Self::Cargo),
+            "pytest" | "py.test" => Some(Self::Pytest),
+            "go" if words.get(i + 1) == Some(&"test") => Some(Self::GoTest),
+            "gotest" => Some(Self::GoTest),
+            "jest" => Some(Self::Jest),
+            "eslint" => Some(Self::Eslint),
+            "mvn" | "mvnw" | "maven" => Some(Self::Maven),
+            _ => None,
+        })
+    }
+}
+
+/// Tests a step ran, by outcome. Errors in test setup count as failures;
+/// expected failures and todos as skipped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TestCounts {
+    pub passed: u32,
+    pub failed: u32,
+    pub skipped: u32,
+}
+
+impl std::ops::Add for TestCounts {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            passed: self.passed + other.passed,
+            failed: self.failed + other.failed,
+            skipped: self.skipped + other.skipped,
+        }
+    }
+}
+
+/// Per-test results kept of one step; past this a step's output is
+/// generated rather than a test suite worth tracking.
+pub const MAX_TEST_RESULTS: usize = 5000;
+
+/// One test of a step, as its runner reported it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    pub name: String,
+    /// `passed`, `failed` or `skipped`, as in [`TestCounts`].
+    pub status: &'static str,
+    /// `None` when the runner printed no timing for the test.
+    pub duration_ms: Option<i32>,
+}
+
+/// What a parser found in a step's output.
+#[derive(Debug, Default)]
+pub struct ParsedOutput {
+    /// Empty unless the step failed.
+    pub failures: StepFailures,
+    /// `None` when the output has no test summary.
+    pub tests: Option<TestCounts>,
+    /// Each test the output names, at most [`MAX_TEST_RESULTS`].
+    pub results: Vec<TestResult>,
+}
+
+/// Read a step's output with `parser`. Failures are only looked for when
+/// the step `failed`.
+pub fn extract(parser: LogParser, stdout: &str, stderr: &str, failed: bool) -> ParsedOutput {
+    let stdout = error_service::strip_ansi(stdout);
+    let stderr = error_service::strip_ansi(stderr);
+    let output = format!("{stdout}\n{stderr}");
+
+    let tests = match parser {
+        LogParser::Cargo => cargo_tests(&output),
+        LogParser::Pytest => pytest_tests(&output),
+        LogParser::GoTest => go_tests(&output),
+        LogParser::Jest => jest_tests(&output),
+        LogParser::Maven => maven_tests(&output),
+        LogParser::Eslint | LogParser::Generic => None,
+    };
+    let mut results = match parser {
+        LogParser::Cargo => cargo_results(&output),
+        LogParser::Pytest => pytest_results(&output),
+        LogParser::GoTest => go_results(&output),
+        LogParser::Jest => jest_results(&output),
+        LogParser::Eslint | LogParser::Maven | LogParser::Generic => Vec::new(),
+    };
+    results.truncate(MAX_TEST_RESULTS);
+    if !failed {
+        return ParsedOutput {
+            failures: StepFailures::default(),
+            tests,
+            results,
+        };
+    }
+
+    let mut failures = match parser {
+        LogParser::Pytest => pytest_failures(&output),
+        LogParser::GoTest => go_failures(&output),
+        LogParser::Jest => jest_failures(&output),
+        LogParser::Eslint => eslint_failures(&output),
+        LogParser::Maven => maven_failures(&output),
+        LogParser::Cargo | LogParser::Generic => StepFailures::default(),
+    };
+    if failures.diagnostics.is_empty() && failures.errors.is_empty() {
+        failures = error_service::extract_failures(&stdout, &stderr);
+    }
+    failures
+        .diagnostics
+        .truncate(error_service::MAX_DIAGNOSTICS);
+    failures
+        .errors
+        .truncate(error_service::MAX_UNLOCATED_ERRORS);
+    ParsedOutput {
+        failures,
+        tests,
+        results,
+    }
+}
+
+fn number(text: &str) -> u32 {
+    text.parse().unwrap_or(0)
+}
+
+/// Milliseconds of a runner's `1.234` seconds.
+fn seconds_ms(text: &str) -> Option<i32> {
+    let seconds: f64 = text.parse().ok()?;
+    Some((seconds * 1000.0).round() as i32)
+}
+
+/// Results in order of first report; runners that repeat failures in a
+/// closing summary keep the first report of each test.
+#[derive(Default)]
+struct Results {
+    list: Vec<TestResult>,
+    index: HashMap<String, usize>,
+}
+
+impl Results {
+    fn push(&mut self, name: &str, status: &'static str, duration_ms: Option<i32>) {
+        if self.index.contains_key(name) {
+            return;
+        }
+        self.index.insert(name.to_string(), self.list.len());
+        self.list.push(TestResult {
+            name: name.to_string(),
+            status,
+            duration_ms,
+        });
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut TestResult> {
+        self.index.get(name).map(|&i| &mut self.list[i])
+    }
+}
+
+/// Counts from `N word` pairs of a summary, e.g. `1 failed, 12 passed`.
+fn summary_counts(summary: &str) -> TestCounts {
+    let mut counts = TestCounts::default();
+    for caps in COUNT_REGEX.captures_iter(summary) {
+        let n = number(&caps[1]);
+        match &caps[2] {
+            "passed" | "xpassed" => counts.passed += n,
+            "failed" | "error" | "errors" => counts.failed += n,
+            "skipped" | "xfailed" | "todo" => counts.skipped += n,
+            _ => {}
+        }
+    }
+    counts
+}
+
+/// Sum of every test binary's `test result:` line.
+fn cargo_tests(output: &str) -> Option<TestCounts> {
+    output
+        .lines()
+        .filter_map(|line| CARGO_RESULT_REGEX.captures(line))
+        .map(|caps| TestCounts {
+            passed: number(&caps[1]),
+            failed: number(&caps[2]),
+            skipped: number(&caps[3]),
+        })
+        .reduce(|a, b| a + b)
+}
+
+/// libtest's `test ... ok` lines, without timings, and nextest's, with.
+fn cargo_results(output: &str) -> Vec<TestResult> {
+    let mut results = Results::default();
+    for line in output.lines() {
+        if let Some(caps) = CARGO_TEST_REGEX.captures(line) {
+            let status = match &caps[2] {
+                "ok" => "passed",
+                "FAILED" => "failed",
+                _ => "skipped",
+            };
+            results.push(&caps[1], status, None);
+        } else if let Some(caps) = NEXTEST_REGEX.captures(line) {
+            let status = match &caps[1] {
+                "PASS" => "passed",
+                "SKIP" => "skipped",
+                _ => "failed",
+            };
+            results.push(&caps[3], status, seconds_ms(&caps[2]));
+        }
+    }
+    results.list
+}
+
+fn pytest_tests(output: &str) -> Option<TestCounts> {
+    output
+        .lines()
+        .filter_map(|line| PYTEST_SUMMARY_REGEX.captures(line))
+        .last()
+        .map(|caps| summary_counts(&caps[1]))
+}
+
+/// Tests named by `-v`, the `--durations` report (call phase) and the short
+/// test summary; only the durations report carries timings.
+fn pytest_results(output: &str) -> Vec<TestResult> {
+    let mut results = Results::default();
+    let mut durations = Vec::new();
+    for line in output.lines() {
+        if let Some(caps) = PYTEST_VERBOSE_REGEX.captures(line) {
+            let status = match &caps[2] {
+                "PASSED" | "XPASS" => "passed",
+                "FAILED" | "ERROR" => "failed",
+                _ => "skipped",
+            };
+            results.push(&caps[1], status, None);
+        } else if let Some(caps) = PYTEST_FAILED_REGEX.captures(line) {
+            results.push(&caps[1], "failed", None);
+        } else if let Some(caps) = PYTEST_DURATION_REGEX.captures(line) {
+            durations.push((caps[2].to_string(), seconds_ms(&caps[1])));
+        }
+    }
+    // The report comes before the short summary, so a test it names is
+    // only known to pass once the summary doesn't list it as failed.
+    for (name, duration_ms) in durations {
+        match results.get_mut(&name) {
+            Some(result) => result.duration_ms = duration_ms,
+            None => results.push(&name, "passed", duration_ms),
+        }
+    }
+    results.list
+}
+
+/// Failing tests of the short test summary (`-rf`, on by default), at the
+/// line of their test file where the traceback of their section ends.
+fn pytest_failures(output: &str) -> StepFailures {
+    let mut locations: HashMap<String, Vec<(String, i32)>> = HashMap::new();
+    let mut section: Option<String> = None;
+    let mut failed = Vec::new();
+    for line in output.lines() {
+        if let Some(caps) = PYTEST_SECTION_REGEX.captures(line) {
+            section = Some(caps[1].to_string());
+        } else if let Some(caps) = PYTEST_FAILED_REGEX.captures(line) {
+            let message = caps.get(2).map_or("", |m| m.as_str());
+            failed.push((caps[1].to_string(), message.to_string()));
+        } else if let (Some(section), Some(caps)) = (&section, PYTEST_LOCATION_REGEX.captures(line))
+        {
+            if let Ok(line_number) = caps[2].parse() {
+                locations
+                    .entry(section.clone())
+                    .or_default()
+                    .push((caps[1].to_string(), line_number));
+            }
+        }
+    }
+
+    let mut failures = StepFailures::default();
+    for (node_id, message) in failed {
+        let mut parts = node_id.split("::");
+        let file = parts.next().unwrap_or_default();
+        // Sections are titled `Class.test`, node IDs `file::Class::test`.
+        let test = parts.collect::<Vec<_>>().join(".");
+        let text = match message.as_str() {
+            "" => format!("test {node_id} failed"),
+            message => format!("test {node_id} failed: {message}"),
+        };
+        let location = locations
+            .get(&test)
+            .and_then(|found| found.iter().rev().find(|(path, _)| path.ends_with(file)));
+        match location {
+            Some((_, line)) => failures.diagnostics.push(Diagnostic {
+                severity: "error",
+                message: text,
+                file_path: file.to_string(),
+                line: *line,
+            }),
+            None => failures.errors.push(text),
+        }
+    }
+    failures
+}
+
+/// Top-level tests by outcome; subtests are counted by their parent.
+fn go_tests(output: &str) -> Option<TestCounts> {
+    let mut counts = TestCounts::default();
+    let mut any = false;
+    for caps in output.lines().filter_map(|l| GO_RESULT_REGEX.captures(l)) {
+        if !caps[1].is_empty() {
+            continue;
+        }
+        any = true;
+        match &caps[2] {
+            "PASS" => counts.passed += 1,
+            "FAIL" => counts.failed += 1,
+            _ => counts.skipped += 1,
+        }
+    }
+    any.then_some(counts)
+}
+
+/// Each test and subtest by its full name, e.g. `TestParse/empty`.
+fn go_results(output: &str) -> Vec<TestResult> {
+    let mut results = Results::default();
+    for caps in output.lines().filter_map(|l| GO_RESULT_REGEX.captures(l)) {
+        let status = match &caps[2] {
+            "PASS" => "passed",
+            "FAIL" => "failed",
+            _ => "skipped",
+        };
+        let duration_ms = caps.get(4).and_then(|m| seconds_ms(m.as_str()));
+        results.push(&caps[3], status, duration_ms);
+    }
+    results.list
+}
+
+/// Build errors, and the log lines of failed tests. `t.Errorf` output comes
+/// before a test's `--- FAIL` line with `-v` and after it without.
+fn go_failures(output: &str) -> StepFailures {
+    let mut failures = StepFailures::default();
+    let mut current: Option<String> = None;
+    let mut logs: HashMap<String, Vec<(String, i32, String)>> = HashMap::new();
+    let mut failed: Vec<String> = Vec::new();
+    for line in output.lines() {
+        if let Some(caps) = GO_RUN_REGEX.captures(line) {
+            current = Some(caps[1].to_string());
+        } else if let Some(caps) = GO_RESULT_REGEX.captures(line) {
+            if &caps[2] == "FAIL" {
+                failed.push(caps[3].to_string());
+            }
+            current = Some(caps[3].to_string());
+        } else if let Some(caps) = GO_BUILD_REGEX.captures(line) {
+            if let Ok(line_number) = caps[2].parse() {
+                failures.diagnostics.push(Diagnostic {
+                    severity: "error",
+                    message: caps[3].to_string(),
+                    file_path: caps[1].to_string(),
+                    line: line_number,
+                });
+            }
+        } else if let (Some(test), Some(caps)) = (&current, GO_LOG_REGEX.captures(line)) {
+            if let Ok(line_number) = caps[2].parse() {
+                logs.entry(test.clone()).or_default().push((
+                    caps[1].to_string(),
+                    line_number,
+                    caps[3].to_string(),
+                ));
+            }
+        }
+    }
+
+    for test in &failed {
+        // A parent fails with its subtests; they say why.
+        let subtest_failed = failed
+            .iter()
+            .any(|other| other.starts_with(&format!("{test}/")));
+        let lines = logs.remove(test).unwrap_or_default();
+        if lines.is_empty() && !subtest_failed {
+            failures.errors.push(format!("test {test} failed"));
+        }
+        for (file_path, line, message) in lines {
+            failures.diagnostics.push(Diagnostic {
+                severity: "error",
+                message: format!("{test}: {message}"),
+                file_path,
+                line,
+            });
+        }
+    }
+    failures
+}
+
+fn jest_tests(output: &str) -> Option<TestCounts> {
+    output
+        .lines()
+        .filter_map(|line| JEST_SUMMARY_REGEX.captures(line))
+        .last()
+        .map(|caps| summary_counts(&caps[1]))
+}
+
+/// Tests of the verbose reporter (`--verbose`, the default for a single
+/// test file), by title; Jest omits the time of fast tests.
+fn jest_results(output: &str) -> Vec<TestResult> {
+    let mut results = Results::default();
+    for caps in output.lines().filter_map(|l| JEST_TEST_REGEX.captures(l)) {
+        let status = match &caps[1] {
+            "✓" | "√" => "passed",
+            "✕" | "×" => "failed",
+            _ => "skipped",
+        };
+        let duration_ms = caps.get(3).and_then(|m| m.as_str().parse().ok());
+        results.push(&caps[2], status, duration_ms);
+    }
+    results.list
+}
+
+/// `●` failure blocks, at the first stack frame outside `node_modules`.
+/// Jest repeats them in its closing summary; each is kept once.
+fn jest_failures(output: &str) -> StepFailures {
+    let mut failures = StepFailures::default();
+    let mut seen = std::collections::HashSet::new();
+    let lines: Vec<&str> = output.lines().collect();
+    for (i, line) in lines.iter().enumerate() {
+        let Some(caps) = JEST_FAILURE_REGEX.captures(line) else {
+            continue;
+        };
+        let title = caps[1].trim();
+        if title.starts_with("Console") || !seen.insert(title.to_string()) {
+            continue;
+        }
+        let block: Vec<&str> = lines[i + 1..]
+            .iter()
+            .take_while(|l| !JEST_FAILURE_REGEX.is_match(l))
+            .copied()
+            .collect();
+        let message = block
+            .iter()
+            .map(|l| l.trim())
+            .find(|l| !l.is_empty())
+            .unwrap_or_default();
+        let text = format!("test {title} failed: {message}");
+        let frame = block
+            .iter()
+            .filter_map(|l| JS_FRAME_REGEX.captures(l))
+            .find(|caps| !caps[1].contains("node_modules"));
+        match frame.and_then(|caps| Some((caps[1].to_string(), caps[2].parse().ok()?))) {
+            Some((file_path, line)) => failures.diagnostics.push(Diagnostic {
+                severity: "error",
+                message: text,
+                file_path,
+                line,
+            }),
+            None => failures.errors.push(text),
+        }
+    }
+    failures
+}
+
+/// Messages of the default `stylish` format, where each file's path heads
+/// its messages, and of `-f unix`.
+fn eslint_failures(output: &str) -> StepFailures {
+    let mut failures = StepFailures::default();
+    let mut file: Option<&str> = None;
+    for line in output.lines() {
+        if let Some(caps) = ESLINT_UNIX_REGEX.captures(line) {
+            let Ok(line_number) = caps[2].parse() else {
+                continue;
+            };
+            let message = match caps.get(5) {
+                Some(rule) => format!("{} ({})", &caps[3], rule.as_str()),
+                None => caps[3].to_string(),
+            };
+            failures.diagnostics.push(Diagnostic {
+                severity: if &caps[4] == "Warning" {
+                    "warning"
+                } else {
+                    "error"
+                },
+                message,
+                file_path: caps[1].to_string(),
+                line: line_number,
+            });
+        } else if let (Some(file), Some(caps)) = (file, ESLINT_STYLISH_REGEX.captures(line)) {
+            let Ok(line_number) = caps[1].parse() else {
+                continue;
+            };
+            let message = match caps.get(4) {
+                Some(rule) => format!("{} ({})", &caps[3], rule.as_str()),
+                None => caps[3].to_string(),
+            };
+            failures.diagnostics.push(Diagnostic {
+                severity: if &caps[2] == "warning" {
+                    "warning"
+                } else {
+                    "error"
+                },
+                message,
+                file_path: file.to_string(),
+                line: line_number,
+            });
+        } else if !line.is_empty() && !line.starts_with(char::is_whitespace) && !line.contains(' ')
+        {
+            file = Some(line);
+        }
+    }
+    failures
+}
+
+/// Totals of every module's surefire results; per-class lines carry a
+/// `Time elapsed` and are left out.
+fn maven_tests(output: &str) -> Option<TestCounts> {
+    output
+        .lines()
+        .filter_map(|line| SUREFIRE_COUNTS_REGEX.captures(line))
+        .filter(|caps| caps.get(5).is_none())
+        .map(|caps| {
+            let (run, failures, errors, skipped) = (
+                number(&caps[1]),
+                number(&caps[2]),
+                number(&caps[3]),
+                number(&caps[4]),
+            );
+            let failed = failures + errors;
+            TestCounts {
+                passed: run.saturating_sub(failed + skipped),
+                failed,
+                skipped,
+            }
+        })
+        .reduce(|a, b| a + b)
+}
+
+/// Compiler messages, and the failed tests of surefire's results.
+fn maven_failures(output: &str) -> StepFailures {
+    let mut failures = StepFailures::default();
+    for line in output.lines() {
+        if let Some(caps) = MAVEN_COMPILER_REGEX.captures(line) {
+            if let Ok(line_number) = caps[3].parse() {
+                failures.diagnostics.push(Diagnostic {
+                    severity: if &caps[1] == "WARNING" {
+                        "warning"
+                    } else {
+                        "error"
+                    },
+                    message: caps[4].to_string(),
+                    file_path: caps[2].to_string(),
+                    line: line_number,
+                });
+            }
+        } else if let Some(caps) = SUREFIRE_FAILURE_REGEX.captures(line) {
+            let text = format!("test {} failed: {}", &caps[1], &caps[2]);
+            if !failures.errors.contains(&text) {
+                failures.errors.push(text);
+            }
+        }
+    }
+    failures
+}
@@ -1,10 +1,45 @@
 //! CI platform services — generic, pipeline-agnostic business logic.
 
+pub mod access_service;
+pub mod alert_service;
 pub mod artifact_service;
+pub mod audit_service;
+pub mod build_feed;
 pub mod build_service;
+pub mod cache_service;
+pub mod chat_notifier;
+pub mod codeowners;
+pub mod compose_provisioner;
+pub mod digest_service;
+pub mod environment_allocator;
+pub mod environment_comment;
+pub mod environment_logs;
 pub mod environment_service;
+pub mod environment_snapshot;
+pub mod environment_template;
+pub mod error_cluster;
 pub mod error_service;
 pub mod executor;
+pub mod gitea_service;
 pub mod github_service;
+pub mod limits;
+pub mod log_parser;
+pub mod notification_rules;
+pub mod notification_service;
+pub mod oidc_service;
+pub mod pipeline_file;
+pub mod outbox_service;
+pub mod policy_service;
+pub mod pr_comment;
 pub mod project_service;
+pub mod provenance_service;
+pub mod quota_service;
+pub mod sandbox;
+pub mod sarif;
+pub mod schedule_service;
+pub mod secret_service;
+pub mod slo_service;
 pub mod step_executor;
+pub mod token_service;
+pub mod trigger_service;
+pub mod vulnerability_service;
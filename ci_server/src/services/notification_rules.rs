@@ -0,0 +1,211 @@
+//! Routing of build events to notification channels.
+//!
+//! A `ci_notification_rules` row sends a channel the build events of the
+//! branches matching its pattern, at most once per `throttle_minutes`.
+//! "Notify #backend when development goes red, at most once an hour":
+//!
+//! ```json
+//! {"channel_id": 4, "name": "development red", "branch_pattern": "development",
+//!  "events": ["build.failed"], "throttle_minutes": 60}
+//! ```
+//!
+//! With `on_transition`, a rule only fires when a build changes the state
+//! of its branch: a `build.failed` after a passing build or as the branch's
+//! first (first failure), a `build.succeeded` after a failed one
+//! (recovery). The build compared with is the branch's last one to finish
+//! before (see [`build_service::previous_outcome`]). A branch that stays
+//! broken pings once, not on every build. Other build events never fire
+//! such a rule.
+//!
+//! A channel with active rules for a project gets that project's build
+//! events only through them; its own `events` still pick its environment
+//! and error events. One event matching several rules of a channel is one
+//! message. A throttled rule drops its matches until the window is over.
+//!
+//! [`notification_service::notify`](super::notification_service::notify)
+//! evaluates the rules on every build transition, inside the transaction
+//! making it, so throttling holds across servers.
+
+use std::collections::HashSet;
+
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use crate::models::build::BuildStatus;
+use crate::models::notification_rule::{CiNotificationRule, NewCiNotificationRule};
+use crate::schema::ci_notification_rules;
+use crate::services::{build_service, notification_service, trigger_service};
+
+/// Where a build event goes, per the rules of its project.
+#[derive(Debug, Default)]
+pub struct Routing {
+    /// Channels with rules for the project, which get build events only
+    /// through them.
+    pub ruled: HashSet<i64>,
+    /// Channels a matching, unthrottled rule sends the event to.
+    pub send: HashSet<i64>,
+}
+
+/// Whether `rule` covers `event` of a build on `branch`.
+pub fn matches(rule: &CiNotificationRule, event: &str, branch: &str) -> bool {
+    (rule.events.is_empty() || rule.events.iter().any(|name| name == event))
+        && trigger_service::branch_matches(rule.branch_pattern.as_deref(), branch)
+}
+
+/// Whether `event` of a build turns its branch red or green, given the
+/// status of the branch's previous build.
+pub fn is_transition(event: &str, previous: Option<&str>) -> bool {
+    let passed = |status: &str| status == BuildStatus::Success.as_str();
+    match event {
+        "build.failed" => previous.is_none_or(passed),
+        "build.succeeded" => previous.is_some_and(|status| !passed(status)),
+        _ => false,
+    }
+}
+
+/// Evaluate the active rules of `channels` for `event` of build `build_id`
+/// of `project_id` on `branch`, starting the throttle window of those that
+/// send it.
+pub async fn route(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+    project_id: i64,
+    channels: &[i64],
+    event: &str,
+    build_id: i64,
+    branch: &str,
+) -> anyhow::Result<Routing> {
+    let mut routing = Routing::default();
+    if channels.is_empty() {
+        return Ok(routing);
+    }
+    let rules: Vec<CiNotificationRule> = ci_notification_rules::table
+        .filter(ci_notification_rules::tenant_id.eq(tenant_id))
+        .filter(ci_notification_rules::active.eq(true))
+        .filter(ci_notification_rules::channel_id.eq_any(channels))
+        .filter(
+            ci_notification_rules::project_id
+                .is_null()
+                .or(ci_notification_rules::project_id.eq(project_id)),
+        )
+        .order(ci_notification_rules::id.asc())
+        .load(conn)
+        .await?;
+
+    let now = chrono::Utc::now();
+    let mut transition = None;
+    for rule in rules {
+        routing.ruled.insert(rule.channel_id);
+        if !matches(&rule, event, branch) {
+            continue;
+        }
+        if rule.on_transition {
+            if transition.is_none() {
+                let previous = build_service::previous_outcome(conn, build_id).await?;
+                transition = Some(is_transition(event, previous.as_deref()));
+            }
+            if transition == Some(false) {
+                continue;
+            }
+        }
+        // Claimed in the database: of two transitions at once, one sends.
+        let cutoff = now - chrono::Duration::minutes(rule.throttle_minutes.max(0) as i64);
+        let claimed = diesel::update(
+            ci_notification_rules::table
+                .filter(ci_notification_rules::id.eq(rule.id))
+                .filter(
+                    ci_notification_rules::last_notified_at
+                        .is_null()
+                        .or(ci_notification_rules::last_notified_at.le(cutoff)),
+                ),
+        )
+        .set(ci_notification_rules::last_notified_at.eq(Some(now)))
+        .execute(conn)
+        .await?;
+        if claimed > 0 {
+            routing.send.insert(rule.channel_id);
+        } else {
+            tracing::debug!(rule_id = rule.id, event, "Notification rule throttled");
+        }
+    }
+    Ok(routing)
+}
+
+/// List active rules.
+pub async fn list_rules(conn: &mut AsyncPgConnection) -> anyhow::Result<Vec<CiNotificationRule>> {
+    let rows = ci_notification_rules::table
+        .filter(ci_notification_rules::active.eq(true))
+        .order(ci_notification_rules::id.asc())
+        .load(conn)
+        .await?;
+    Ok(rows)
+}
+
+/// Get an active rule by id.
+pub async fn get_rule(
+    conn: &mut AsyncPgConnection,
+    rule_id: i64,
+) -> anyhow::Result<Option<CiNotificationRule>> {
+    let row = ci_notification_rules::table
+        .find(rule_id)
+        .filter(ci_notification_rules::active.eq(true))
+        .first(conn)
+        .await
+        .optional()?;
+    Ok(row)
+}
+
+/// Create a rule after checking its channel, branch pattern, events and
+/// throttle.
+pub async fn create_rule(
+    conn: &mut AsyncPgConnection,
+    rule: NewCiNotificationRule,
+) -> anyhow::Result<CiNotificationRule> {
+    let Some(channel) = notification_service::get_channel(conn, rule.channel_id).await? else {
+        anyhow::bail!("unknown notification channel {}", rule.channel_id);
+    };
+    if let (Some(channel_project), Some(rule_project)) = (channel.project_id, rule.project_id) {
+        if channel_project != rule_project {
+            anyhow::bail!("channel {} belongs to another project", channel.id);
+        }
+    }
+    if let Some(pattern) = &rule.branch_pattern {
+        trigger_service::check_pattern(pattern)?;
+    }
+    if let Some(unknown) = rule
+        .events
+        .iter()
+        .find(|e| !e.starts_with("build.") || !notification_service::EVENTS.contains(&e.as_str()))
+    {
+        anyhow::bail!("unknown build event {unknown:?}");
+    }
+    if rule.throttle_minutes < 0 {
+        anyhow::bail!("throttle must not be negative");
+    }
+    let row = diesel::insert_into(ci_notification_rules::table)
+        .values(&rule)
+        .get_result(conn)
+        .await?;
+    Ok(row)
+}
+
+/// Deactivate a rule. Returns whether it existed.
+pub async fn delete_rule(
+    conn: &mut AsyncPgConnection,
+    rule_id: i64,
+    uid: i64,
+) -> anyhow::Result<bool> {
+    let updated = diesel::update(
+        ci_notification_rules::table
+            .filter(ci_notification_rules::id.eq(rule_id))
+            .filter(ci_notification_rules::active.eq(true)),
+    )
+    .set((
+        ci_notification_rules::active.eq(false),
+        ci_notification_rules::write_uid.eq(Some(uid)),
+        ci_notification_rules::write_date.eq(Some(chrono::Utc::now())),
+    ))
+    .execute(conn)
+    .await?;
+    Ok(updated > 0)
+}
@@ -0,0 +1,411 @@
+//! Outbound notification webhooks.
+//!
+//! External systems register a `ci_notification_endpoints` row and receive
+//! build, environment and error events ([`EVENTS`]) as JSON POSTs:
+//!
+//! ```json
+//! {"event": "build.failed", "project_id": 3, "timestamp": "...", "data": {...}}
+//! ```
+//!
+//! `data` is the build, environment or error row after the change; a new
+//! error's `owners` say which team it belongs to. Each request carries
+//! `X-Centrix-Event`, `X-Centrix-Delivery` (stable across retries) and
+//! `X-Centrix-Signature-256`: `sha256=` and the hex HMAC-SHA256 of the
+//! body, keyed with the endpoint's secret, as GitHub signs its webhooks.
+//!
+//! [`notify`] enqueues one outbox message per matching endpoint, inside the
+//! caller's transaction; the outbox worker delivers it with its usual retry
+//! and backoff, and every attempt is logged in `ci_notification_deliveries`.
+//!
+//! Chats are notified through `ci_notification_channels` instead, which
+//! subscribe to the same events and get them as messages formatted for
+//! Discord or any chat taking `{"text": ...}` webhooks (see
+//! [`chat_notifier`]). The build events of a channel can be narrowed down
+//! by branch and throttled with rules (see [`notification_rules`]).
+
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::models::notification::{
+    CiNotificationChannel, CiNotificationDelivery, CiNotificationEndpoint,
+    NewCiNotificationChannel, NewCiNotificationDelivery, NewCiNotificationEndpoint,
+};
+use crate::models::outbox::CiOutboxMessage;
+use crate::schema::{
+    ci_notification_channels, ci_notification_deliveries, ci_notification_endpoints,
+};
+use crate::services::notification_rules::{self, Routing};
+use crate::services::outbox_service::{self, OutboxMessage};
+use crate::services::{chat_notifier, digest_service};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Events an endpoint can subscribe to.
+pub const EVENTS: &[&str] = &[
+    "build.created",
+    "build.succeeded",
+    "build.failed",
+    "build.cancelled",
+    "environment.created",
+    "environment.updated",
+    "environment.destroyed",
+    "error.created",
+];
+
+/// An endpoint that does not answer within this long counts as failed.
+const DELIVERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Whether a subscription to `events` covers `event`.
+fn subscribes(events: &[String], event: &str) -> bool {
+    events.is_empty() || events.iter().any(|name| name == event)
+}
+
+/// Queue `event` for every active endpoint and channel of the tenant that
+/// covers `project_id` and subscribes to it. Call inside the transaction
+/// making the change.
+pub async fn notify(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+    project_id: i64,
+    event: &str,
+    data: &impl Serialize,
+) -> anyhow::Result<()> {
+    let endpoints: Vec<CiNotificationEndpoint> = ci_notification_endpoints::table
+        .filter(ci_notification_endpoints::tenant_id.eq(tenant_id))
+        .filter(ci_notification_endpoints::active.eq(true))
+        .filter(
+            ci_notification_endpoints::project_id
+                .is_null()
+                .or(ci_notification_endpoints::project_id.eq(project_id)),
+        )
+        .load(conn)
+        .await?;
+
+    let channels: Vec<CiNotificationChannel> = ci_notification_channels::table
+        .filter(ci_notification_channels::tenant_id.eq(tenant_id))
+        .filter(ci_notification_channels::active.eq(true))
+        .filter(
+            ci_notification_channels::project_id
+                .is_null()
+                .or(ci_notification_channels::project_id.eq(project_id)),
+        )
+        .load(conn)
+        .await?;
+
+    let subscribed: Vec<&CiNotificationEndpoint> = endpoints
+        .iter()
+        .filter(|e| subscribes(&e.events, event))
+        .collect();
+    if !subscribed.is_empty() {
+        // Rendered once, so every retry sends (and signs) the same bytes.
+        let body = serde_json::to_string(&serde_json::json!({
+            "event": event,
+            "project_id": project_id,
+            "timestamp": chrono::Utc::now(),
+            "data": data,
+        }))?;
+        for endpoint in subscribed {
+            outbox_service::enqueue(
+                conn,
+                tenant_id,
+                &OutboxMessage::NotificationWebhook {
+                    endpoint_id: endpoint.id,
+                    event: event.to_string(),
+                    body: body.clone(),
+                },
+            )
+            .await?;
+        }
+    }
+
+    if channels.is_empty() {
+        return Ok(());
+    }
+    let data = serde_json::to_value(data)?;
+    let build = data
+        .get("id")
+        .and_then(|id| id.as_i64())
+        .zip(data.get("branch").and_then(|b| b.as_str()));
+    let routing = match build {
+        Some((build_id, branch)) if event.starts_with("build.") => {
+            let ids: Vec<i64> = channels.iter().map(|c| c.id).collect();
+            notification_rules::route(conn, tenant_id, project_id, &ids, event, build_id, branch)
+                .await?
+        }
+        _ => Routing::default(),
+    };
+    let subscribed = channels.iter().filter(|c| {
+        if routing.ruled.contains(&c.id) {
+            routing.send.contains(&c.id)
+        } else {
+            // A digest stands in for the per-event messages not asked for.
+            (c.digest.is_none() || !c.events.is_empty()) && subscribes(&c.events, event)
+        }
+    });
+    for channel in subscribed {
+        outbox_service::enqueue(
+            conn,
+            tenant_id,
+            &OutboxMessage::ChatNotification {
+                channel_id: channel.id,
+                event: event.to_string(),
+                project_id,
+                data: data.clone(),
+            },
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// `X-Centrix-Signature-256` value of `body`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// POST a queued notification and log the attempt. Fails, so the outbox
+/// retries it, unless the endpoint answered 2xx. Endpoints deleted since
+/// the event was queued are skipped.
+pub async fn deliver(
+    conn: &mut AsyncPgConnection,
+    outbox: &CiOutboxMessage,
+    endpoint_id: i64,
+    event: &str,
+    body: &str,
+) -> anyhow::Result<()> {
+    let Some(endpoint) = get_endpoint(conn, endpoint_id).await? else {
+        tracing::debug!(
+            endpoint_id,
+            event,
+            "Notification endpoint gone, dropping delivery"
+        );
+        return Ok(());
+    };
+
+    let started = std::time::Instant::now();
+    let result = reqwest::Client::new()
+        .post(&endpoint.url)
+        .header("Content-Type", "application/json")
+        .header("User-Agent", "centrix-ci")
+        .header("X-Centrix-Event", event)
+        .header("X-Centrix-Delivery", outbox.id.to_string())
+        .header(
+            "X-Centrix-Signature-256",
+            sign(&endpoint.secret, body.as_bytes()),
+        )
+        .timeout(DELIVERY_TIMEOUT)
+        .body(body.to_string())
+        .send()
+        .await;
+    let duration_ms = started.elapsed().as_millis() as i32;
+
+    let (status_code, error) = match &result {
+        Ok(resp) if resp.status().is_success() => (Some(resp.status().as_u16() as i32), None),
+        Ok(resp) => (
+            Some(resp.status().as_u16() as i32),
+            Some(format!("Notification webhook failed: {}", resp.status())),
+        ),
+        Err(e) => (None, Some(format!("Notification webhook failed: {e}"))),
+    };
+
+    diesel::insert_into(ci_notification_deliveries::table)
+        .values(&NewCiNotificationDelivery {
+            tenant_id: outbox.tenant_id,
+            endpoint_id,
+            outbox_id: outbox.id,
+            event: event.to_string(),
+            attempt: outbox.attempts,
+            status_code,
+            error: error.clone(),
+            duration_ms,
+        })
+        .execute(conn)
+        .await?;
+
+    match error {
+        Some(error) => Err(anyhow::anyhow!(error)),
+        None => Ok(()),
+    }
+}
+
+/// Fresh signing secret for an endpoint registered without one.
+pub fn generate_secret() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// List active endpoints.
+pub async fn list_endpoints(
+    conn: &mut AsyncPgConnection,
+) -> anyhow::Result<Vec<CiNotificationEndpoint>> {
+    let rows = ci_notification_endpoints::table
+        .filter(ci_notification_endpoints::active.eq(true))
+        .order(ci_notification_endpoints::id.asc())
+        .load(conn)
+        .await?;
+    Ok(rows)
+}
+
+/// Get an active endpoint by ID.
+pub async fn get_endpoint(
+    conn: &mut AsyncPgConnection,
+    endpoint_id: i64,
+) -> anyhow::Result<Option<CiNotificationEndpoint>> {
+    let row = ci_notification_endpoints::table
+        .find(endpoint_id)
+        .filter(ci_notification_endpoints::active.eq(true))
+        .first(conn)
+        .await
+        .optional()?;
+    Ok(row)
+}
+
+/// Create an endpoint after checking its URL and events.
+pub async fn create_endpoint(
+    conn: &mut AsyncPgConnection,
+    endpoint: NewCiNotificationEndpoint,
+) -> anyhow::Result<CiNotificationEndpoint> {
+    if !(endpoint.url.starts_with("https://") || endpoint.url.starts_with("http://")) {
+        anyhow::bail!("notification URL must be http(s): {:?}", endpoint.url);
+    }
+    if let Some(unknown) = endpoint
+        .events
+        .iter()
+        .find(|e| !EVENTS.contains(&e.as_str()))
+    {
+        anyhow::bail!("unknown notification event {unknown:?}");
+    }
+    let row = diesel::insert_into(ci_notification_endpoints::table)
+        .values(&endpoint)
+        .get_result(conn)
+        .await?;
+    Ok(row)
+}
+
+/// Deactivate an endpoint. Returns whether it existed.
+pub async fn delete_endpoint(
+    conn: &mut AsyncPgConnection,
+    endpoint_id: i64,
+    uid: i64,
+) -> anyhow::Result<bool> {
+    let updated = diesel::update(
+        ci_notification_endpoints::table
+            .filter(ci_notification_endpoints::id.eq(endpoint_id))
+            .filter(ci_notification_endpoints::active.eq(true)),
+    )
+    .set((
+        ci_notification_endpoints::active.eq(false),
+        ci_notification_endpoints::write_uid.eq(Some(uid)),
+        ci_notification_endpoints::write_date.eq(Some(chrono::Utc::now())),
+    ))
+    .execute(conn)
+    .await?;
+    Ok(updated > 0)
+}
+
+/// Most recent delivery attempts to an endpoint, newest first.
+pub async fn deliveries(
+    conn: &mut AsyncPgConnection,
+    endpoint_id: i64,
+    limit: i64,
+) -> anyhow::Result<Vec<CiNotificationDelivery>> {
+    let rows = ci_notification_deliveries::table
+        .filter(ci_notification_deliveries::endpoint_id.eq(endpoint_id))
+        .order(ci_notification_deliveries::id.desc())
+        .limit(limit)
+        .load(conn)
+        .await?;
+    Ok(rows)
+}
+
+/// List active channels.
+pub async fn list_channels(
+    conn: &mut AsyncPgConnection,
+) -> anyhow::Result<Vec<CiNotificationChannel>> {
+    let rows = ci_notification_channels::table
+        .filter(ci_notification_channels::active.eq(true))
+        .order(ci_notification_channels::id.asc())
+        .load(conn)
+        .await?;
+    Ok(rows)
+}
+
+/// Get an active channel by ID.
+pub async fn get_channel(
+    conn: &mut AsyncPgConnection,
+    channel_id: i64,
+) -> anyhow::Result<Option<CiNotificationChannel>> {
+    let row = ci_notification_channels::table
+        .find(channel_id)
+        .filter(ci_notification_channels::active.eq(true))
+        .first(conn)
+        .await
+        .optional()?;
+    Ok(row)
+}
+
+/// Create a channel after checking its kind, webhook URL, events and
+/// digest period.
+pub async fn create_channel(
+    conn: &mut AsyncPgConnection,
+    channel: NewCiNotificationChannel,
+) -> anyhow::Result<CiNotificationChannel> {
+    if channel.name.trim().is_empty() {
+        anyhow::bail!("notification channel needs a name");
+    }
+    if !chat_notifier::KINDS.contains(&channel.kind.as_str()) {
+        anyhow::bail!("unknown notification channel kind {:?}", channel.kind);
+    }
+    if !channel.webhook_url.starts_with("https://") && !channel.webhook_url.starts_with("http://") {
+        anyhow::bail!("webhook URL must be http(s)");
+    }
+    if let Some(unknown) = channel
+        .events
+        .iter()
+        .find(|e| !EVENTS.contains(&e.as_str()))
+    {
+        anyhow::bail!("unknown notification event {unknown:?}");
+    }
+    if let Some(period) = channel
+        .digest
+        .as_deref()
+        .filter(|p| !digest_service::PERIODS.contains(p))
+    {
+        anyhow::bail!("unknown digest period {period:?}");
+    }
+    let row = diesel::insert_into(ci_notification_channels::table)
+        .values(&channel)
+        .get_result(conn)
+        .await?;
+    Ok(row)
+}
+
+/// Deactivate a channel. Returns whether it existed.
+pub async fn delete_channel(
+    conn: &mut AsyncPgConnection,
+    channel_id: i64,
+    uid: i64,
+) -> anyhow::Result<bool> {
+    let updated = diesel::update(
+        ci_notification_channels::table
+            .filter(ci_notification_channels::id.eq(channel_id))
+            .filter(ci_notification_channels::active.eq(true)),
+    )
+    .set((
+        ci_notification_channels::active.eq(false),
+        ci_notification_channels::write_uid.eq(Some(uid)),
+        ci_notification_channels::write_date.eq(Some(chrono::Utc::now())),
+    ))
+    .execute(conn)
+    .await?;
+    Ok(updated > 0)
+}
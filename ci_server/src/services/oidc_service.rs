@@ -0,0 +1,248 @@
+//! OIDC ID tokens for build steps (workload identity).
+//!
+//! Each step receives a short-lived RS256 JWT in `CI_OIDC_TOKEN` describing
+//! the build it belongs to. Cloud providers verify it against the server's
+//! JWKS (`{issuer}/.well-known/jwks.json`) and exchange it for temporary
+//! credentials, so pipelines need no static cloud keys.
+//!
+//! The subject names where the build's code came from: `repo:<repo>:ref:
+//! refs/heads/<branch>` for branch builds, `repo:<repo>:pull_request` for
+//! pull request builds, whose head branch is named by the pull request's
+//! author and so proves nothing. Untrusted pull request builds (forks,
+//! outside contributors) get no token at all.
+//!
+//! The signing key is an RSA private key in PEM (PKCS#8 or PKCS#1) from
+//! `CI_OIDC_SIGNING_KEY` or the file named by `CI_OIDC_SIGNING_KEY_FILE`.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs1v15::SigningKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::signature::{Keypair, SignatureEncoding, Signer, Verifier};
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPrivateKey;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Upper bound on token lifetime, however long the step may run.
+const MAX_TOKEN_LIFETIME_SECS: u64 = 3600;
+
+/// Build-specific claims carried by a step token.
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildClaims {
+    pub project_id: i64,
+    pub repository: String,
+    pub build_id: i64,
+    pub branch: String,
+    pub commit_sha: String,
+    pub step: String,
+    /// What started the build: `push`, `pull_request`, `schedule`, ...
+    pub event: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pr_number: Option<i32>,
+}
+
+impl BuildClaims {
+    /// The token's `sub`: trust policies scoped to a branch must not match
+    /// a pull request whose head branch merely has that name.
+    pub fn subject(&self) -> String {
+        match self.pr_number {
+            Some(_) => format!("repo:{}:pull_request", self.repository),
+            None => format!("repo:{}:ref:refs/heads/{}", self.repository, self.branch),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    sub: String,
+    aud: &'a str,
+    iat: i64,
+    nbf: i64,
+    exp: i64,
+    jti: String,
+    #[serde(flatten)]
+    build: &'a BuildClaims,
+}
+
+/// Signs step tokens and publishes the matching public key.
+pub struct OidcSigner {
+    issuer: String,
+    kid: String,
+    signing_key: SigningKey<Sha256>,
+    n: Vec<u8>,
+    e: Vec<u8>,
+}
+
+impl std::fmt::Debug for OidcSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OidcSigner")
+            .field("issuer", &self.issuer)
+            .field("kid", &self.kid)
+            .finish()
+    }
+}
+
+impl OidcSigner {
+    /// Load the signer from the environment. Returns `None` when no signing
+    /// key is configured, which disables OIDC tokens.
+    pub fn from_env(issuer: String) -> anyhow::Result<Option<Self>> {
+        let pem = match (
+            std::env::var("CI_OIDC_SIGNING_KEY"),
+            std::env::var("CI_OIDC_SIGNING_KEY_FILE"),
+        ) {
+            (Ok(pem), _) => pem,
+            (_, Ok(path)) => std::fs::read_to_string(path)?,
+            _ => return Ok(None),
+        };
+
+        let private = RsaPrivateKey::from_pkcs8_pem(&pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+            .map_err(|e| anyhow::anyhow!("invalid OIDC signing key: {e}"))?;
+        let n = private.n().to_bytes_be();
+        let e = private.e().to_bytes_be();
+        let kid = hex::encode(&Sha256::digest(&n)[..8]);
+
+        Ok(Some(Self {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            kid,
+            signing_key: SigningKey::<Sha256>::new(private),
+            n,
+            e,
+        }))
+    }
+
+    /// Issue a token for one step. It expires after `lifetime_secs`, capped
+    /// at one hour.
+    pub fn issue(
+        &self,
+        audience: &str,
+        build: &BuildClaims,
+        lifetime_secs: u64,
+    ) -> anyhow::Result<String> {
+        let now = chrono::Utc::now().timestamp();
+        let lifetime = lifetime_secs.min(MAX_TOKEN_LIFETIME_SECS) as i64;
+        let claims = Claims {
+            iss: &self.issuer,
+            sub: build.subject(),
+            aud: audience,
+            iat: now,
+            nbf: now,
+            exp: now + lifetime,
+            jti: uuid::Uuid::new_v4().to_string(),
+            build,
+        };
+
+        let header = serde_json::json!({"alg": "RS256", "typ": "JWT", "kid": self.kid});
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+        );
+        let signature = self.sign(signing_input.as_bytes());
+        Ok(format!(
+            "{signing_input}.{}",
+            URL_SAFE_NO_PAD.encode(signature)
+        ))
+    }
+
+    /// Sign arbitrary bytes with the server key (RSASSA-PKCS1-v1_5, SHA-256).
+    /// Also used for build provenance, so the JWKS verifies both.
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.signing_key.sign(message).to_vec()
+    }
+
+    /// Verify a signature made by [`OidcSigner::sign`].
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        let Ok(signature) = rsa::pkcs1v15::Signature::try_from(signature) else {
+            return false;
+        };
+        self.signing_key
+            .verifying_key()
+            .verify(message, &signature)
+            .is_ok()
+    }
+
+    /// Key id of the signing key, as published in the JWKS.
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    /// The issuer URL, also the default audience when a pipeline does not
+    /// set `oidc_audience`.
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// The JSON Web Key Set verifiers fetch.
+    pub fn jwks(&self) -> serde_json::Value {
+        serde_json::json!({
+            "keys": [{
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": self.kid,
+                "n": URL_SAFE_NO_PAD.encode(&self.n),
+                "e": URL_SAFE_NO_PAD.encode(&self.e),
+            }]
+        })
+    }
+
+    /// Minimal OpenID provider metadata, enough for workload identity
+    /// federation to locate the JWKS.
+    pub fn discovery(&self) -> serde_json::Value {
+        serde_json::json!({
+            "issuer": self.issuer,
+            "jwks_uri": format!("{}/.well-known/jwks.json", self.issuer),
+            "response_types_supported": ["id_token"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"],
+            "claims_supported": [
+                "iss", "sub", "aud", "iat", "nbf", "exp", "jti",
+                "project_id", "repository", "build_id", "branch", "commit_sha", "step",
+                "event", "pr_number"
+            ],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(event: &str, pr_number: Option<i32>) -> BuildClaims {
+        BuildClaims {
+            project_id: 1,
+            repository: "acme/app".to_string(),
+            build_id: 7,
+            branch: "main".to_string(),
+            commit_sha: "abc123".to_string(),
+            step: "deploy".to_string(),
+            event: event.to_string(),
+            pr_number,
+        }
+    }
+
+    #[test]
+    fn branch_builds_are_scoped_to_their_ref() {
+        assert_eq!(
+            claims("push", None).subject(),
+            "repo:acme/app:ref:refs/heads/main"
+        );
+    }
+
+    #[test]
+    fn pull_requests_never_claim_a_branch() {
+        // A fork's branch named `main` must not pass policies for main.
+        assert_eq!(
+            claims("pull_request", Some(12)).subject(),
+            "repo:acme/app:pull_request"
+        );
+        assert_eq!(
+            claims("retry", Some(12)).subject(),
+            "repo:acme/app:pull_request"
+        );
+    }
+}
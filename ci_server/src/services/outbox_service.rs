@@ -0,0 +1,423 @@
+//! Transactional outbox for external side effects.
+//!
+//! State changes enqueue their side effects (commit statuses, deployments, PR comments,
+//! alert and notification webhooks, chat messages) with [`enqueue`] on the same
+//! connection, inside the same transaction.
+//! The outbox worker delivers them afterwards with retry and backoff, so a
+//! crash between the DB update and the HTTP call no longer loses the effect.
+//!
+//! Commit statuses of one context on one commit are delivered in the order
+//! they were enqueued: a status waits while an older one for the same
+//! commit and context is pending, so a retried `pending` never lands after
+//! the `success` that followed it.
+
+use std::sync::Arc;
+
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+
+use erp_core::db::diesel_pool::DieselPool;
+
+use crate::config::CiConfig;
+use crate::models::outbox::{CiOutboxMessage, NewCiOutboxMessage};
+use crate::models::project::Provider;
+use crate::schema::ci_outbox;
+use crate::services::github_service::{CheckAnnotation, CheckRun};
+use crate::services::{chat_notifier, gitea_service, notification_service};
+
+/// Messages are given up on (status `dead`) after this many attempts.
+const MAX_ATTEMPTS: i32 = 8;
+
+/// Messages claimed per drain pass.
+const BATCH_SIZE: i64 = 20;
+
+/// A side effect waiting to be delivered.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OutboxMessage {
+    /// Commit status on GitHub, or a check run with `annotations` when
+    /// `CI_GITHUB_CHECKS` is set.
+    GithubStatus {
+        repo: String,
+        sha: String,
+        state: String,
+        description: String,
+        target_url: String,
+        context: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        annotations: Vec<CheckAnnotation>,
+    },
+    /// Commit status on Gitea / Forgejo.
+    GiteaStatus {
+        repo: String,
+        sha: String,
+        state: String,
+        description: String,
+        target_url: String,
+        context: String,
+    },
+    /// Successful deployment on GitHub of `sha` to `environment`, live at
+    /// `environment_url`.
+    GithubDeployment {
+        repo: String,
+        sha: String,
+        environment: String,
+        environment_url: String,
+        description: String,
+    },
+    /// Comment on a GitHub pull request. With a `sticky` key, an earlier
+    /// comment of the same key is edited instead of adding another.
+    GithubPrComment {
+        repo: String,
+        pr_number: i32,
+        body: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sticky: Option<String>,
+    },
+    /// Comment on a Gitea / Forgejo pull request.
+    GiteaPrComment {
+        repo: String,
+        pr_number: i32,
+        body: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sticky: Option<String>,
+    },
+    /// Alert notification POSTed as JSON to a rule's webhook.
+    AlertWebhook {
+        url: String,
+        payload: serde_json::Value,
+    },
+    /// Build or environment event POSTed to a notification endpoint; see
+    /// [`notification_service`].
+    NotificationWebhook {
+        endpoint_id: i64,
+        event: String,
+        /// The exact JSON body, signed when it is sent.
+        body: String,
+    },
+    /// Build or environment event posted to a chat channel as a message;
+    /// see [`chat_notifier`].
+    ChatNotification {
+        channel_id: i64,
+        event: String,
+        project_id: i64,
+        /// The row the event was raised with.
+        data: serde_json::Value,
+    },
+}
+
+impl OutboxMessage {
+    /// Value stored in the `kind` column.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OutboxMessage::GithubStatus { .. } => "github_status",
+            OutboxMessage::GiteaStatus { .. } => "gitea_status",
+            OutboxMessage::GithubDeployment { .. } => "github_deployment",
+            OutboxMessage::GithubPrComment { .. } => "github_pr_comment",
+            OutboxMessage::GiteaPrComment { .. } => "gitea_pr_comment",
+            OutboxMessage::AlertWebhook { .. } => "alert_webhook",
+            OutboxMessage::NotificationWebhook { .. } => "notification_webhook",
+            OutboxMessage::ChatNotification { .. } => "chat_notification",
+        }
+    }
+
+    /// Send a commit status, deployment or PR comment to the project's own
+    /// provider: for Gitea projects a `GithubStatus` becomes a
+    /// `GiteaStatus`, which has no annotations, a `GithubDeployment` a
+    /// `GiteaStatus` linking to the environment, since Gitea has no
+    /// deployments, and a `GithubPrComment` a `GiteaPrComment`.
+    pub fn for_provider(self, provider: Provider) -> Self {
+        match (self, provider) {
+            (
+                OutboxMessage::GithubStatus {
+                    repo,
+                    sha,
+                    state,
+                    description,
+                    target_url,
+                    context,
+                    ..
+                },
+                Provider::Gitea,
+            ) => OutboxMessage::GiteaStatus {
+                repo,
+                sha,
+                state,
+                description,
+                target_url,
+                context,
+            },
+            (
+                OutboxMessage::GithubDeployment {
+                    repo,
+                    sha,
+                    environment,
+                    environment_url,
+                    description,
+                },
+                Provider::Gitea,
+            ) => OutboxMessage::GiteaStatus {
+                repo,
+                sha,
+                state: "success".to_string(),
+                description,
+                target_url: environment_url,
+                context: format!("centrix-ci/{environment}"),
+            },
+            (
+                OutboxMessage::GithubPrComment {
+                    repo,
+                    pr_number,
+                    body,
+                    sticky,
+                },
+                Provider::Gitea,
+            ) => OutboxMessage::GiteaPrComment {
+                repo,
+                pr_number,
+                body,
+                sticky,
+            },
+            (message, _) => message,
+        }
+    }
+}
+
+/// Enqueue a side effect. Call on the connection that performs the state
+/// change, inside its transaction.
+pub async fn enqueue(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+    message: &OutboxMessage,
+) -> anyhow::Result<i64> {
+    let new_message = NewCiOutboxMessage {
+        tenant_id,
+        kind: message.kind().to_string(),
+        payload: serde_json::to_value(message)?,
+        status: "pending".to_string(),
+        trace_context: crate::telemetry::traceparent(),
+    };
+
+    let result: CiOutboxMessage = diesel::insert_into(ci_outbox::table)
+        .values(&new_message)
+        .get_result(conn)
+        .await?;
+    Ok(result.id)
+}
+
+/// Run the outbox worker forever. Spawned as a background tokio task.
+pub async fn run_outbox_worker(pool: Arc<DieselPool>, config: CiConfig) {
+    tracing::info!("Outbox worker started");
+
+    loop {
+        match drain_once(&pool, &config).await {
+            // A full batch means more may be waiting; go again immediately.
+            Ok(n) if n as i64 >= BATCH_SIZE => continue,
+            Ok(_) => {}
+            Err(e) => tracing::error!("Outbox drain error: {e}"),
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    }
+}
+
+/// Claim and deliver one batch of due messages. Returns the number claimed.
+async fn drain_once(pool: &DieselPool, config: &CiConfig) -> anyhow::Result<usize> {
+    let mut conn = crate::db::system_conn(pool).await?;
+
+    // Claiming leases the row for five minutes by pushing next_attempt_at
+    // forward; a worker that dies mid-delivery lets the lease expire and the
+    // message is picked up again. A message being delivered stays pending,
+    // so it holds back newer statuses of its commit and context too.
+    let claimed: Vec<CiOutboxMessage> = diesel::sql_query(format!(
+        "UPDATE ci_outbox \
+         SET attempts = attempts + 1, \
+             next_attempt_at = NOW() + INTERVAL '5 minutes', \
+             write_date = NOW() \
+         WHERE id IN ( \
+             SELECT id FROM ci_outbox m \
+             WHERE status = 'pending' AND next_attempt_at <= NOW() \
+               AND NOT EXISTS ( \
+                   SELECT 1 FROM ci_outbox older \
+                   WHERE m.kind IN ('github_status', 'gitea_status') \
+                     AND older.kind = m.kind \
+                     AND older.status = 'pending' \
+                     AND older.id < m.id \
+                     AND older.payload->>'repo' = m.payload->>'repo' \
+                     AND older.payload->>'sha' = m.payload->>'sha' \
+                     AND older.payload->>'context' = m.payload->>'context' \
+               ) \
+             ORDER BY id \
+             LIMIT {BATCH_SIZE} \
+             FOR UPDATE SKIP LOCKED \
+         ) \
+         RETURNING *"
+    ))
+    .load(&mut conn)
+    .await?;
+
+    let count = claimed.len();
+    for message in claimed {
+        let span =
+            tracing::info_span!("outbox.deliver", outbox_id = message.id, kind = %message.kind);
+        crate::telemetry::set_parent(&span, message.trace_context.as_deref());
+        let result = match serde_json::from_value::<OutboxMessage>(message.payload.clone()) {
+            Ok(parsed) => {
+                deliver(config, &mut conn, &message, &parsed)
+                    .instrument(span)
+                    .await
+            }
+            Err(e) => Err(anyhow::anyhow!("invalid outbox payload: {e}")),
+        };
+
+        match result {
+            Ok(()) => {
+                diesel::update(ci_outbox::table.find(message.id))
+                    .set((
+                        ci_outbox::status.eq("done"),
+                        ci_outbox::processed_at.eq(chrono::Utc::now()),
+                        ci_outbox::last_error.eq(None::<String>),
+                    ))
+                    .execute(&mut conn)
+                    .await?;
+            }
+            Err(e) => {
+                let dead = message.attempts >= MAX_ATTEMPTS;
+                let backoff = chrono::Duration::seconds(2_i64.pow(message.attempts.min(12) as u32));
+                tracing::warn!(
+                    outbox_id = message.id,
+                    kind = %message.kind,
+                    attempts = message.attempts,
+                    dead,
+                    "Outbox delivery failed: {e}"
+                );
+                diesel::update(ci_outbox::table.find(message.id))
+                    .set((
+                        ci_outbox::status.eq(if dead { "dead" } else { "pending" }),
+                        ci_outbox::next_attempt_at.eq(chrono::Utc::now() + backoff),
+                        ci_outbox::last_error.eq(Some(e.to_string())),
+                    ))
+                    .execute(&mut conn)
+                    .await?;
+            }
+        }
+    }
+
+    Ok(count)
+}
+
+/// Perform the side effect described by `message`, the payload of
+/// `outbox`.
+async fn deliver(
+    config: &CiConfig,
+    conn: &mut AsyncPgConnection,
+    outbox: &CiOutboxMessage,
+    message: &OutboxMessage,
+) -> anyhow::Result<()> {
+    match message {
+        OutboxMessage::GithubStatus {
+            repo,
+            sha,
+            state,
+            description,
+            target_url,
+            context,
+            annotations,
+        } if config.github_checks => {
+            let run = CheckRun {
+                name: context,
+                state,
+                description,
+                details_url: target_url,
+                annotations,
+            };
+            config.github.post_check_run(repo, sha, &run).await
+        }
+        OutboxMessage::GithubStatus {
+            repo,
+            sha,
+            state,
+            description,
+            target_url,
+            context,
+            ..
+        } => {
+            config
+                .github
+                .post_status(repo, sha, state, description, target_url, context)
+                .await
+        }
+        OutboxMessage::GiteaStatus {
+            repo,
+            sha,
+            state,
+            description,
+            target_url,
+            context,
+        } => {
+            gitea_service::post_status(config, repo, sha, state, description, target_url, context)
+                .await
+        }
+        OutboxMessage::GithubDeployment {
+            repo,
+            sha,
+            environment,
+            environment_url,
+            description,
+        } => {
+            config
+                .github
+                .post_deployment(repo, sha, environment, environment_url, description)
+                .await
+        }
+        OutboxMessage::GithubPrComment {
+            repo,
+            pr_number,
+            body,
+            sticky,
+        } => match sticky {
+            Some(key) => {
+                config
+                    .github
+                    .upsert_pr_comment(repo, *pr_number, key, body)
+                    .await
+            }
+            None => config.github.post_pr_comment(repo, *pr_number, body).await,
+        },
+        OutboxMessage::GiteaPrComment {
+            repo,
+            pr_number,
+            body,
+            sticky,
+        } => match sticky {
+            Some(key) => {
+                gitea_service::upsert_pr_comment(config, repo, *pr_number, key, body).await
+            }
+            None => gitea_service::post_pr_comment(config, repo, *pr_number, body).await,
+        },
+        OutboxMessage::AlertWebhook { url, payload } => {
+            let resp = reqwest::Client::new()
+                .post(url)
+                .header("User-Agent", "centrix-ci")
+                .json(payload)
+                .send()
+                .await?;
+            if !resp.status().is_success() {
+                anyhow::bail!("Alert webhook failed: {}", resp.status());
+            }
+            Ok(())
+        }
+        OutboxMessage::NotificationWebhook {
+            endpoint_id,
+            event,
+            body,
+        } => notification_service::deliver(conn, outbox, *endpoint_id, event, body).await,
+        OutboxMessage::ChatNotification {
+            channel_id,
+            event,
+            project_id,
+            data,
+        } => chat_notifier::deliver(config, conn, *channel_id, event, *project_id, data).await,
+    }
+}
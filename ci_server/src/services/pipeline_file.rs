@@ -0,0 +1,626 @@
+//! In-repo pipeline definitions.
+//!
+//! A project may check in `.centrix-ci.yml` (or `.yaml`, or
+//! `.centrix-ci.toml`) at the repository root. The executor reads it after
+//! checkout and prefers it over `ci_projects.pipeline_config`, so pipeline
+//! changes ride along with the code they build and are reviewed in the same
+//! PR. The file uses the same shape as the stored JSON config:
+//!
+//! ```yaml
+//! timeout_secs: 900
+//! env:
+//!   RUST_LOG: info
+//! steps:
+//!   - name: test
+//!     command: cargo test --workspace
+//!     timeout_secs: 1800
+//!     retries: 2
+//!     retry_delay_secs: 30
+//!   - name: clippy-nightly
+//!     command: cargo +nightly clippy --workspace --message-format=json
+//!     allow_failure: true
+//!   - name: audit
+//!     command: cargo audit --json
+//!     vulnerability_report: { format: cargo-audit }
+//!     depends_on: []
+//!   - name: e2e
+//!     command: npm run e2e
+//!     parser: jest
+//!   - name: upload-reports
+//!     command: ./ci/upload-reports.sh
+//!     depends_on: [test, audit]
+//!     when: always
+//! cache:
+//!   - { name: cargo, paths: [target], key_files: [Cargo.lock] }
+//! ```
+//!
+//! Steps run in order unless any step declares `depends_on`; then each
+//! step waits only for the steps it names, and independent ones run in
+//! parallel. A step runs once everything it waits for passed, unless it
+//! sets `when: always` (run however they ended) or `when: on_failure`
+//! (run only if some step failed). A step's `parser` says how its output
+//! is read for errors and test counts (see [`LogParser`]); without one it
+//! is guessed from the step's name and command. `engine: dagger` replaces `steps` with a `ci_pipeline` run:
+//!
+//! ```yaml
+//! engine: dagger
+//! dagger: { command: all, args: [--base, origin/development] }
+//! ```
+//!
+//! A file's engine must be the project's unless the project's config sets
+//! `allow_repo_engine: true`.
+//!
+//! `local_path`, `git`, `sandbox`, `limits`, `vulnerability_gate`, and
+//! `environment` stay project settings: the first two are needed before
+//! there is a checkout, a branch must not be able to opt out of the sandbox
+//! or the vulnerability gate or raise its resource limits, and a review
+//! environment runs on the host, so a branch must not pick what it brings
+//! up. Steps may still set tighter `limits` of their own. A
+//! project's `environment` is checked with [`validate_environment`] when the
+//! project's config is saved.
+
+use std::path::Path;
+
+use crate::services::limits::MAX_NICE;
+use crate::services::log_parser::LogParser;
+use crate::services::vulnerability_service::ReportFormat;
+use crate::services::{cache_service, environment_snapshot};
+
+/// Candidate file names, in order of preference.
+pub const PIPELINE_FILES: &[&str] = &[".centrix-ci.yml", ".centrix-ci.yaml", ".centrix-ci.toml"];
+
+const TOP_LEVEL_KEYS: &[&str] = &[
+    "steps",
+    "engine",
+    "dagger",
+    "timeout_secs",
+    "env",
+    "oidc_audience",
+    "cache",
+];
+const STEP_KEYS: &[&str] = &[
+    "name",
+    "command",
+    "env",
+    "timeout_secs",
+    "depends_on",
+    "retries",
+    "retry_delay_secs",
+    "allow_failure",
+    "when",
+    "limits",
+    "vulnerability_report",
+    "parser",
+];
+
+/// Upper bound on a step's `retries`.
+pub const MAX_STEP_RETRIES: u64 = 10;
+
+/// Upper bound on a step's `retry_delay_secs`: an hour.
+pub const MAX_RETRY_DELAY_SECS: u64 = 60 * 60;
+
+/// Upper bound on `timeout_secs`, of the pipeline or a step: 7 days.
+pub const MAX_TIMEOUT_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Why an in-repo pipeline definition was rejected.
+#[derive(Debug, thiserror::Error)]
+pub enum PipelineFileError {
+    #[error("cannot read {file}: {message}")]
+    Read { file: String, message: String },
+    #[error("cannot parse {file}: {message}")]
+    Parse { file: String, message: String },
+    #[error("invalid {file}: {}", .errors.join("; "))]
+    Invalid { file: String, errors: Vec<String> },
+}
+
+/// A pipeline definition found in a checkout.
+#[derive(Debug)]
+pub struct PipelineFile {
+    pub file: &'static str,
+    /// The definition as a pipeline config value.
+    pub config: serde_json::Value,
+}
+
+/// Load and validate the pipeline definition in `work_dir`, if it has one.
+///
+/// Errors end up in commit statuses and PR comments, so they never quote
+/// the file: it must be a regular file, not a symlink to one outside the
+/// checkout, and syntax errors only say where they are.
+pub async fn load(work_dir: &str) -> Result<Option<PipelineFile>, PipelineFileError> {
+    for file in PIPELINE_FILES {
+        let path = Path::new(work_dir).join(file);
+        let read_error = |message: String| PipelineFileError::Read {
+            file: file.to_string(),
+            message,
+        };
+        match tokio::fs::symlink_metadata(&path).await {
+            Ok(meta) if meta.is_file() => {}
+            Ok(_) => return Err(read_error("not a regular file".to_string())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(read_error(e.to_string())),
+        }
+        let raw = tokio::fs::read_to_string(&path)
+            .await
+            .map_err(|e| read_error(e.to_string()))?;
+
+        let parsed = if file.ends_with(".toml") {
+            toml::from_str::<serde_json::Value>(&raw).map_err(|e| {
+                let (line, column) = e
+                    .span()
+                    .map(|span| line_column(&raw, span.start))
+                    .unwrap_or_default();
+                syntax_error(line, column)
+            })
+        } else {
+            serde_yaml::from_str::<serde_json::Value>(&raw).map_err(|e| {
+                let (line, column) = e
+                    .location()
+                    .map(|l| (l.line(), l.column()))
+                    .unwrap_or_default();
+                syntax_error(line, column)
+            })
+        };
+        let config = parsed.map_err(|message| PipelineFileError::Parse {
+            file: file.to_string(),
+            message,
+        })?;
+
+        validate(&config).map_err(|errors| PipelineFileError::Invalid {
+            file: file.to_string(),
+            errors,
+        })?;
+        return Ok(Some(PipelineFile { file, config }));
+    }
+    Ok(None)
+}
+
+/// 1-based line and column of byte `offset` of `text`.
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let mut end = offset.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let before = &text[..end];
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .count()
+        + 1;
+    (line, column)
+}
+
+fn syntax_error(line: usize, column: usize) -> String {
+    if line == 0 {
+        "syntax error".to_string()
+    } else {
+        format!("syntax error at line {line}, column {column}")
+    }
+}
+
+/// Check a pipeline definition against the schema the executor understands.
+/// Returns every problem found, each prefixed with its location.
+pub fn validate(config: &serde_json::Value) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+    let Some(root) = config.as_object() else {
+        return Err(vec!["top level must be a mapping".to_string()]);
+    };
+
+    for key in root.keys() {
+        match key.as_str() {
+            "local_path" | "git" | "sandbox" | "limits" | "vulnerability_gate" | "environment" => {
+                errors.push(format!("{key}: set on the project, not in the repository"))
+            }
+            k if !TOP_LEVEL_KEYS.contains(&k) => errors.push(format!("{key}: unknown key")),
+            _ => {}
+        }
+    }
+
+    let dagger = match root.get("engine") {
+        None => false,
+        Some(engine) => match engine.as_str() {
+            Some("shell") => false,
+            Some("dagger") => true,
+            _ => {
+                errors.push("engine: expected shell or dagger".to_string());
+                false
+            }
+        },
+    };
+    if let Some(config) = root.get("dagger") {
+        validate_dagger(config, &mut errors);
+    }
+
+    match root.get("steps").and_then(|s| s.as_array()) {
+        None if dagger => {}
+        None => errors.push("steps: required list of steps".to_string()),
+        Some(steps) if steps.is_empty() => errors.push("steps: must not be empty".to_string()),
+        Some(steps) => {
+            let mut names = std::collections::HashSet::new();
+            for (i, step) in steps.iter().enumerate() {
+                validate_step(&format!("steps[{i}]"), step, &mut names, &mut errors);
+            }
+            validate_dependencies(steps, &mut errors);
+        }
+    }
+
+    if let Some(timeout) = root.get("timeout_secs") {
+        if !timeout.as_u64().is_some_and(valid_timeout) {
+            errors.push(format!(
+                "timeout_secs: must be an integer from 1 to {MAX_TIMEOUT_SECS}"
+            ));
+        }
+    }
+    if let Some(env) = root.get("env") {
+        validate_env("env", env, &mut errors);
+    }
+    if let Some(cache) = root.get("cache") {
+        validate_cache(cache, &mut errors);
+    }
+    if root.get("oidc_audience").is_some_and(|a| !a.is_string()) {
+        errors.push("oidc_audience: must be a string".to_string());
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn valid_timeout(secs: u64) -> bool {
+    (1..=MAX_TIMEOUT_SECS).contains(&secs)
+}
+
+fn validate_step(
+    at: &str,
+    step: &serde_json::Value,
+    names: &mut std::collections::HashSet<String>,
+    errors: &mut Vec<String>,
+) {
+    let Some(step) = step.as_object() else {
+        errors.push(format!("{at}: must be a mapping"));
+        return;
+    };
+    for key in step.keys() {
+        if !STEP_KEYS.contains(&key.as_str()) {
+            errors.push(format!("{at}.{key}: unknown key"));
+        }
+    }
+
+    match step.get("name").and_then(|n| n.as_str()) {
+        Some(name) if !name.trim().is_empty() => {
+            if !names.insert(name.to_string()) {
+                errors.push(format!("{at}.name: duplicate step name {name:?}"));
+            }
+        }
+        _ => errors.push(format!("{at}.name: required non-empty string")),
+    }
+    if !step
+        .get("command")
+        .and_then(|c| c.as_str())
+        .is_some_and(|c| !c.trim().is_empty())
+    {
+        errors.push(format!("{at}.command: required non-empty string"));
+    }
+    if let Some(env) = step.get("env") {
+        validate_env(&format!("{at}.env"), env, errors);
+    }
+    if let Some(timeout) = step.get("timeout_secs") {
+        if !timeout.as_u64().is_some_and(valid_timeout) {
+            errors.push(format!(
+                "{at}.timeout_secs: must be an integer from 1 to {MAX_TIMEOUT_SECS}"
+            ));
+        }
+    }
+    if let Some(retries) = step.get("retries") {
+        if !retries.as_u64().is_some_and(|r| r <= MAX_STEP_RETRIES) {
+            errors.push(format!(
+                "{at}.retries: must be an integer from 0 to {MAX_STEP_RETRIES}"
+            ));
+        }
+    }
+    if let Some(delay) = step.get("retry_delay_secs") {
+        if !delay.as_u64().is_some_and(|d| d <= MAX_RETRY_DELAY_SECS) {
+            errors.push(format!(
+                "{at}.retry_delay_secs: must be an integer from 0 to {MAX_RETRY_DELAY_SECS}"
+            ));
+        }
+    }
+    if step.get("allow_failure").is_some_and(|a| !a.is_boolean()) {
+        errors.push(format!("{at}.allow_failure: must be a boolean"));
+    }
+    if let Some(when) = step.get("when") {
+        if !when
+            .as_str()
+            .is_some_and(|w| ["on_success", "always", "on_failure"].contains(&w))
+        {
+            errors.push(format!(
+                "{at}.when: expected on_success, always or on_failure"
+            ));
+        }
+    }
+    if let Some(limits) = step.get("limits") {
+        validate_limits(&format!("{at}.limits"), limits, errors);
+    }
+    if let Some(report) = step.get("vulnerability_report") {
+        let format = report.get("format").and_then(|f| f.as_str());
+        if format.and_then(ReportFormat::parse).is_none() {
+            errors.push(format!(
+                "{at}.vulnerability_report.format: expected cargo-audit or npm-audit"
+            ));
+        }
+        if report
+            .get("path")
+            .is_some_and(|p| !p.as_str().is_some_and(cache_service::valid_path))
+        {
+            errors.push(format!(
+                "{at}.vulnerability_report.path: must be a path inside the workspace"
+            ));
+        }
+    }
+    if let Some(parser) = step.get("parser") {
+        if parser.as_str().and_then(LogParser::parse).is_none() {
+            errors.push(format!(
+                "{at}.parser: expected one of {}",
+                LogParser::NAMES.join(", ")
+            ));
+        }
+    }
+    if let Some(deps) = step.get("depends_on") {
+        if !deps
+            .as_array()
+            .is_some_and(|deps| deps.iter().all(|d| d.is_string()))
+        {
+            errors.push(format!("{at}.depends_on: must be a list of step names"));
+        }
+    }
+}
+
+/// `dagger: { command, args }` of an `engine: dagger` pipeline.
+fn validate_dagger(config: &serde_json::Value, errors: &mut Vec<String>) {
+    let Some(config) = config.as_object() else {
+        errors.push("dagger: must be a mapping".to_string());
+        return;
+    };
+    for key in config.keys() {
+        if !["command", "args"].contains(&key.as_str()) {
+            errors.push(format!("dagger.{key}: unknown key"));
+        }
+    }
+    if config.get("command").is_some_and(|c| !c.is_string()) {
+        errors.push("dagger.command: must be a string".to_string());
+    }
+    if let Some(args) = config.get("args") {
+        if !args
+            .as_array()
+            .is_some_and(|args| args.iter().all(|a| a.is_string()))
+        {
+            errors.push("dagger.args: must be a list of strings".to_string());
+        }
+    }
+}
+
+/// `depends_on` must name other steps of the pipeline, without cycles.
+fn validate_dependencies(steps: &[serde_json::Value], errors: &mut Vec<String>) {
+    let name = |step: &serde_json::Value| step.get("name").and_then(|n| n.as_str());
+    let names: std::collections::HashSet<&str> = steps.iter().filter_map(name).collect();
+    let mut pending: Vec<(&str, Vec<&str>)> = Vec::new();
+    for (i, step) in steps.iter().enumerate() {
+        let deps: Vec<&str> = step
+            .get("depends_on")
+            .and_then(|d| d.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|d| d.as_str())
+            .collect();
+        for dep in &deps {
+            if !names.contains(dep) || name(step) == Some(*dep) {
+                errors.push(format!(
+                    "steps[{i}].depends_on: no other step named {dep:?}"
+                ));
+            }
+        }
+        if let Some(step_name) = name(step) {
+            pending.push((step_name, deps));
+        }
+    }
+
+    // Peel off steps whose dependencies are all resolved; whatever is left
+    // sits on a cycle.
+    let mut resolved = std::collections::HashSet::new();
+    loop {
+        let before = pending.len();
+        pending.retain(|(step, deps)| {
+            let ready = deps
+                .iter()
+                .all(|d| resolved.contains(d) || !names.contains(d) || *d == *step);
+            if ready {
+                resolved.insert(*step);
+            }
+            !ready
+        });
+        if pending.len() == before {
+            break;
+        }
+    }
+    if !pending.is_empty() {
+        let cycle: Vec<&str> = pending.iter().map(|(step, _)| *step).collect();
+        errors.push(format!("depends_on: cycle between {}", cycle.join(", ")));
+    }
+}
+
+/// `cache: [{ name, paths, key_files }]`.
+fn validate_cache(cache: &serde_json::Value, errors: &mut Vec<String>) {
+    let Some(entries) = cache.as_array() else {
+        errors.push("cache: must be a list".to_string());
+        return;
+    };
+    let mut names = std::collections::HashSet::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let at = format!("cache[{i}]");
+        let Some(entry) = entry.as_object() else {
+            errors.push(format!("{at}: must be a mapping"));
+            continue;
+        };
+        for key in entry.keys() {
+            if !["name", "paths", "key_files"].contains(&key.as_str()) {
+                errors.push(format!("{at}.{key}: unknown key"));
+            }
+        }
+        match entry.get("name").and_then(|n| n.as_str()) {
+            Some(name) if cache_service::valid_name(name) => {
+                if !names.insert(name) {
+                    errors.push(format!("{at}.name: duplicate cache name {name:?}"));
+                }
+            }
+            _ => errors.push(format!(
+                "{at}.name: required, letters, digits, '_', '-' and '.' only"
+            )),
+        }
+        match entry.get("paths").and_then(|p| p.as_array()) {
+            Some(paths) if !paths.is_empty() => {
+                for (j, path) in paths.iter().enumerate() {
+                    if !path.as_str().is_some_and(cache_service::valid_path) {
+                        errors.push(format!(
+                            "{at}.paths[{j}]: must be a path inside the workspace"
+                        ));
+                    }
+                }
+            }
+            _ => errors.push(format!("{at}.paths: required non-empty list")),
+        }
+        if let Some(files) = entry.get("key_files") {
+            if !files
+                .as_array()
+                .is_some_and(|files| files.iter().all(|f| f.is_string()))
+            {
+                errors.push(format!("{at}.key_files: must be a list of file names"));
+            }
+        }
+    }
+}
+
+/// `environment: { compose_file, service, port, services, health_url, env,
+/// database }` of a project's pipeline config.
+pub fn validate_environment(environment: &serde_json::Value, errors: &mut Vec<String>) {
+    let Some(environment) = environment.as_object() else {
+        errors.push("environment: must be a mapping".to_string());
+        return;
+    };
+    for (key, value) in environment {
+        let (valid, expected) = match key.as_str() {
+            "compose_file" => (
+                value.as_str().is_some_and(cache_service::valid_path),
+                "a path inside the repository",
+            ),
+            "service" => (
+                value.as_str().is_some_and(|s| !s.trim().is_empty()),
+                "a non-empty string",
+            ),
+            "port" => (
+                value.as_u64().is_some_and(|p| (1..=65535).contains(&p)),
+                "a port number",
+            ),
+            "services" => (
+                value
+                    .as_array()
+                    .is_some_and(|s| s.iter().all(|s| s.is_string())),
+                "a list of service names",
+            ),
+            "health_url" => (
+                value.as_str().is_some_and(|u| u.starts_with('/')),
+                "a path starting with '/'",
+            ),
+            "env" => {
+                validate_env("environment.env", value, errors);
+                continue;
+            }
+            "database" => {
+                validate_database(value, errors);
+                continue;
+            }
+            _ => {
+                errors.push(format!("environment.{key}: unknown key"));
+                continue;
+            }
+        };
+        if !valid {
+            errors.push(format!("environment.{key}: must be {expected}"));
+        }
+    }
+}
+
+/// `environment.database: { service, snapshot, seed }`.
+fn validate_database(database: &serde_json::Value, errors: &mut Vec<String>) {
+    let Some(database) = database.as_object() else {
+        errors.push("environment.database: must be a mapping".to_string());
+        return;
+    };
+    for (key, value) in database {
+        let (valid, expected) = match key.as_str() {
+            "service" => (
+                value.as_str().is_some_and(|s| !s.trim().is_empty()),
+                "a non-empty string",
+            ),
+            "snapshot" => (
+                value.as_str().is_some_and(cache_service::valid_name),
+                "a snapshot name: letters, digits, '_', '-' and '.' only",
+            ),
+            "seed" => (
+                value.as_str().is_some_and(|s| {
+                    cache_service::valid_path(s)
+                        && environment_snapshot::seed_extension(s).is_some()
+                }),
+                "a .sql, .sql.gz or .sh file inside the repository",
+            ),
+            _ => {
+                errors.push(format!("environment.database.{key}: unknown key"));
+                continue;
+            }
+        };
+        if !valid {
+            errors.push(format!("environment.database.{key}: must be {expected}"));
+        }
+    }
+}
+
+/// `limits: { memory_mb, cpus, nice }`.
+fn validate_limits(at: &str, limits: &serde_json::Value, errors: &mut Vec<String>) {
+    let Some(limits) = limits.as_object() else {
+        errors.push(format!("{at}: must be a mapping"));
+        return;
+    };
+    for (key, value) in limits {
+        let (valid, expected) = match key.as_str() {
+            "memory_mb" => (value.as_u64().is_some_and(|m| m > 0), "a positive integer"),
+            "cpus" => (value.as_f64().is_some_and(|c| c > 0.0), "a positive number"),
+            "nice" => (
+                value.as_i64().is_some_and(|n| (0..=MAX_NICE).contains(&n)),
+                "an integer from 0 to 19",
+            ),
+            _ => {
+                errors.push(format!("{at}.{key}: unknown key"));
+                continue;
+            }
+        };
+        if !valid {
+            errors.push(format!("{at}.{key}: must be {expected}"));
+        }
+    }
+}
+
+fn validate_env(at: &str, env: &serde_json::Value, errors: &mut Vec<String>) {
+    match env.as_object() {
+        None => errors.push(format!("{at}: must be a mapping of names to strings")),
+        Some(vars) => {
+            for (name, value) in vars {
+                if !value.is_string() {
+                    errors.push(format!("{at}.{name}: must be a string"));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! Command policy engine for pipeline configs.
+//!
+//! Admins define policies in `ci_command_policies` (through
+//! `/ci/api/policies`, gated on the admin-only `ci.command.policy` model);
+//! all active policies of a tenant apply together. A pipeline config is checked when it is saved
+//! through the API and again by the executor before any step runs, because
+//! configs edited through the UI never pass through the API.
+
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use regex::RegexSet;
+
+use crate::models::command_policy::{CiCommandPolicy, NewCiCommandPolicy};
+use crate::schema::ci_command_policies;
+
+/// A pipeline config broke one or more command policies.
+#[derive(Debug, thiserror::Error)]
+#[error("pipeline rejected by command policy: {}", .violations.join("; "))]
+pub struct PolicyViolation {
+    pub violations: Vec<String>,
+}
+
+/// The combined, compiled policies of one tenant.
+pub struct CommandPolicy {
+    allow: Option<RegexSet>,
+    deny: RegexSet,
+    banned_env_vars: Vec<String>,
+    max_steps: Option<i32>,
+}
+
+impl CommandPolicy {
+    /// Merge policy rows into one policy. The tightest `max_steps` wins.
+    pub fn compile(policies: &[CiCommandPolicy]) -> anyhow::Result<Self> {
+        let allow: Vec<&String> = policies.iter().flat_map(|p| &p.allow_patterns).collect();
+        let deny: Vec<&String> = policies.iter().flat_map(|p| &p.deny_patterns).collect();
+
+        Ok(Self {
+            allow: if allow.is_empty() {
+                None
+            } else {
+                Some(RegexSet::new(allow)?)
+            },
+            deny: RegexSet::new(deny)?,
+            banned_env_vars: policies
+                .iter()
+                .flat_map(|p| &p.banned_env_vars)
+                .cloned()
+                .collect(),
+            max_steps: policies.iter().filter_map(|p| p.max_steps).min(),
+        })
+    }
+
+    /// Check a pipeline config. Collects every violation rather than
+    /// stopping at the first, so the author can fix them in one go.
+    pub fn check(&self, config: &serde_json::Value) -> Result<(), PolicyViolation> {
+        let mut violations = Vec::new();
+        let steps = config
+            .get("steps")
+            .and_then(|s| s.as_array())
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+
+        if let Some(max) = self.max_steps {
+            if steps.len() > max as usize {
+                violations.push(format!("{} steps exceeds the limit of {max}", steps.len()));
+            }
+        }
+
+        for (i, step) in steps.iter().enumerate() {
+            let name = step
+                .get("name")
+                .and_then(|n| n.as_str())
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("#{}", i + 1));
+            let command = step
+                .get("command")
+                .and_then(|c| c.as_str())
+                .unwrap_or_default();
+
+            self.check_command(&format!("step '{name}'"), command, &mut violations);
+            for var in self.banned_env(step.get("env")) {
+                violations.push(format!("step '{name}' sets banned env var {var}"));
+            }
+        }
+        // A Dagger pipeline runs no `steps`; its invocation, as
+        // `dagger <command> <args>`, stands in for their commands.
+        if config.get("engine").and_then(|e| e.as_str()) == Some("dagger") {
+            let dagger = config.get("dagger");
+            let mut invocation = vec![dagger
+                .and_then(|d| d.get("command"))
+                .and_then(|c| c.as_str())
+                .unwrap_or("all")];
+            invocation.extend(
+                dagger
+                    .and_then(|d| d.get("args"))
+                    .and_then(|a| a.as_array())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|a| a.as_str()),
+            );
+            let command = format!("dagger {}", invocation.join(" "));
+            self.check_command("dagger pipeline", &command, &mut violations);
+        }
+        for var in self.banned_env(config.get("env")) {
+            violations.push(format!("pipeline sets banned env var {var}"));
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(PolicyViolation { violations })
+        }
+    }
+
+    fn check_command(&self, what: &str, command: &str, violations: &mut Vec<String>) {
+        if self.deny.is_match(command) {
+            violations.push(format!("{what} matches a denied command pattern"));
+        }
+        if self
+            .allow
+            .as_ref()
+            .is_some_and(|allow| !allow.is_match(command))
+        {
+            violations.push(format!("{what} matches no allowed command pattern"));
+        }
+    }
+
+    fn banned_env<'a>(
+        &'a self,
+        env: Option<&'a serde_json::Value>,
+    ) -> impl Iterator<Item = &'a String> {
+        env.and_then(|e| e.as_object())
+            .into_iter()
+            .flat_map(|e| e.keys())
+            .filter(|key| {
+                self.banned_env_vars
+                    .iter()
+                    .any(|b| b.eq_ignore_ascii_case(key))
+            })
+    }
+}
+
+/// Load and compile the active policies of a tenant.
+///
+/// Filters on `tenant_id` explicitly so it also works on the executor's
+/// system-context connection.
+pub async fn load_policy(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+) -> anyhow::Result<CommandPolicy> {
+    let policies: Vec<CiCommandPolicy> = ci_command_policies::table
+        .filter(ci_command_policies::tenant_id.eq(tenant_id))
+        .filter(ci_command_policies::active.eq(true))
+        .load(conn)
+        .await?;
+    CommandPolicy::compile(&policies)
+}
+
+/// Check a pipeline config against the tenant's policies. Fails with a
+/// [`PolicyViolation`] when it breaks any of them.
+pub async fn validate_pipeline(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+    config: &serde_json::Value,
+) -> anyhow::Result<()> {
+    load_policy(conn, tenant_id).await?.check(config)?;
+    Ok(())
+}
+
+/// Reject a policy whose patterns do not compile or whose step limit is not
+/// positive, before it can break every pipeline of the tenant.
+fn check_policy(policy: &NewCiCommandPolicy) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !policy.name.trim().is_empty(),
+        "policy name must not be empty"
+    );
+    for pattern in policy.allow_patterns.iter().chain(&policy.deny_patterns) {
+        if let Err(e) = regex::Regex::new(pattern) {
+            anyhow::bail!("invalid pattern {pattern:?}: {e}");
+        }
+    }
+    if policy.max_steps.is_some_and(|max| max < 1) {
+        anyhow::bail!("max_steps must be at least 1");
+    }
+    Ok(())
+}
+
+/// List active policies.
+pub async fn list_policies(conn: &mut AsyncPgConnection) -> anyhow::Result<Vec<CiCommandPolicy>> {
+    let rows = ci_command_policies::table
+        .filter(ci_command_policies::active.eq(true))
+        .order(ci_command_policies::id.asc())
+        .load(conn)
+        .await?;
+    Ok(rows)
+}
+
+/// Create a policy after checking its patterns.
+pub async fn create_policy(
+    conn: &mut AsyncPgConnection,
+    policy: NewCiCommandPolicy,
+) -> anyhow::Result<CiCommandPolicy> {
+    check_policy(&policy)?;
+    let row = diesel::insert_into(ci_command_policies::table)
+        .values(&policy)
+        .get_result(conn)
+        .await?;
+    Ok(row)
+}
+
+/// Replace the rules of an active policy after checking its patterns.
+/// Returns `None` when there is no such policy.
+pub async fn update_policy(
+    conn: &mut AsyncPgConnection,
+    policy_id: i64,
+    policy: NewCiCommandPolicy,
+    uid: i64,
+) -> anyhow::Result<Option<CiCommandPolicy>> {
+    check_policy(&policy)?;
+    let row = diesel::update(
+        ci_command_policies::table
+            .filter(ci_command_policies::id.eq(policy_id))
+            .filter(ci_command_policies::active.eq(true)),
+    )
+    .set((
+        ci_command_policies::name.eq(policy.name),
+        ci_command_policies::allow_patterns.eq(policy.allow_patterns),
+        ci_command_policies::deny_patterns.eq(policy.deny_patterns),
+        ci_command_policies::banned_env_vars.eq(policy.banned_env_vars),
+        ci_command_policies::max_steps.eq(policy.max_steps),
+        ci_command_policies::write_uid.eq(Some(uid)),
+        ci_command_policies::write_date.eq(Some(chrono::Utc::now())),
+    ))
+    .get_result(conn)
+    .await
+    .optional()?;
+    Ok(row)
+}
+
+/// Deactivate a policy. Returns whether it existed.
+pub async fn delete_policy(
+    conn: &mut AsyncPgConnection,
+    policy_id: i64,
+    uid: i64,
+) -> anyhow::Result<bool> {
+    let updated = diesel::update(
+        ci_command_policies::table
+            .filter(ci_command_policies::id.eq(policy_id))
+            .filter(ci_command_policies::active.eq(true)),
+    )
+    .set((
+        ci_command_policies::active.eq(false),
+        ci_command_policies::write_uid.eq(Some(uid)),
+        ci_command_policies::write_date.eq(Some(chrono::Utc::now())),
+    ))
+    .execute(conn)
+    .await?;
+    Ok(updated > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: &[&str], deny: &[&str]) -> CommandPolicy {
+        CommandPolicy::compile(&[CiCommandPolicy {
+            id: 1,
+            tenant_id: uuid::Uuid::nil(),
+            name: "test".to_string(),
+            allow_patterns: allow.iter().map(|p| p.to_string()).collect(),
+            deny_patterns: deny.iter().map(|p| p.to_string()).collect(),
+            banned_env_vars: Vec::new(),
+            max_steps: None,
+            active: true,
+            create_uid: None,
+            create_date: None,
+            write_uid: None,
+            write_date: None,
+        }])
+        .unwrap()
+    }
+
+    #[test]
+    fn dagger_pipelines_are_checked() {
+        let dagger = serde_json::json!({
+            "engine": "dagger",
+            "dagger": {"command": "deploy", "args": ["--env", "prod"]},
+        });
+        assert!(policy(&[], &["deploy"]).check(&dagger).is_err());
+        assert!(policy(&["^cargo "], &[]).check(&dagger).is_err());
+        assert!(policy(&["^dagger (all|deploy)"], &[])
+            .check(&dagger)
+            .is_ok());
+    }
+
+    #[test]
+    fn broken_policies_are_rejected() {
+        let new = |deny: &str, max_steps| NewCiCommandPolicy {
+            tenant_id: uuid::Uuid::nil(),
+            name: "no curl".to_string(),
+            allow_patterns: Vec::new(),
+            deny_patterns: vec![deny.to_string()],
+            banned_env_vars: Vec::new(),
+            max_steps,
+            create_uid: None,
+        };
+        assert!(check_policy(&new("curl .*\\| *sh", Some(20))).is_ok());
+        assert!(check_policy(&new("curl (", None)).is_err());
+        assert!(check_policy(&new("curl", Some(0))).is_err());
+    }
+}
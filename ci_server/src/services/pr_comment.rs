@@ -0,0 +1,111 @@
+//! Build summary posted on pull requests.
+//!
+//! When a PR build finishes, one comment per PR lists each step's result
+//! and duration, with the end of the first failed step's stderr, and links
+//! to the build in the dashboard. Later builds of the PR edit the same
+//! comment (keyed [`STICKY_KEY`]) rather than adding new ones.
+
+use std::fmt::Write;
+
+use crate::models::build::BuildStatus;
+use crate::models::build_step::CiBuildStep;
+
+/// Sticky comment key of the build summary.
+pub const STICKY_KEY: &str = "build-summary";
+
+/// Lines of the failed step's stderr quoted in the comment.
+const STDERR_TAIL_LINES: usize = 30;
+
+/// A finished build, as the summary describes it.
+#[derive(Debug)]
+pub struct Summary<'a> {
+    pub build_id: i64,
+    pub commit_sha: &'a str,
+    pub status: BuildStatus,
+    pub duration_ms: i32,
+    /// Why the build failed before or around its steps, if it did.
+    pub error: Option<&'a str>,
+    pub steps: &'a [CiBuildStep],
+    pub target_url: &'a str,
+}
+
+/// Markdown body of the summary comment.
+pub fn render(summary: &Summary<'_>) -> String {
+    let headline = match summary.status {
+        BuildStatus::Success => "✅ passed",
+        BuildStatus::Cancelled => "⏹️ cancelled",
+        _ => "❌ failed",
+    };
+    let end = summary
+        .commit_sha
+        .char_indices()
+        .nth(7)
+        .map_or(summary.commit_sha.len(), |(i, _)| i);
+    let short_sha = &summary.commit_sha[..end];
+
+    let mut body = String::new();
+    let _ = writeln!(
+        body,
+        "### Build #{} {headline}\n\nCommit `{short_sha}` · {} · [details]({})\n",
+        summary.build_id,
+        format_duration(summary.duration_ms),
+        summary.target_url,
+    );
+    if let Some(error) = summary.error {
+        let _ = writeln!(body, "> {}\n", error.lines().next().unwrap_or_default());
+    }
+
+    if !summary.steps.is_empty() {
+        body.push_str("| Step | Result | Duration |\n|---|---|---|\n");
+        for step in summary.steps {
+            let _ = writeln!(
+                body,
+                "| `{}` | {} | {} |",
+                step.name,
+                step_result(&step.status),
+                step.duration_ms.map(format_duration).unwrap_or_default(),
+            );
+        }
+    }
+
+    let failed = summary
+        .steps
+        .iter()
+        .find(|s| matches!(s.status.as_str(), "failure" | "timed_out"));
+    if let Some(step) = failed {
+        let stderr = step.stderr.as_deref().unwrap_or_default().trim_end();
+        if !stderr.is_empty() {
+            let lines: Vec<&str> = stderr.lines().collect();
+            let tail = lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n");
+            let _ = write!(
+                body,
+                "\n<details><summary><code>{}</code> stderr</summary>\n\n````text\n{tail}\n````\n</details>\n",
+                step.name,
+            );
+        }
+    }
+    body
+}
+
+fn step_result(status: &str) -> &str {
+    match status {
+        "success" => "✅ passed",
+        "failure" => "❌ failed",
+        "timed_out" => "⏱️ timed out",
+        "cancelled" => "⏹️ cancelled",
+        "skipped" => "⏭️ skipped",
+        "running" => "⏳ running",
+        other => other,
+    }
+}
+
+/// `850ms`, `12.4s` or `3m 07s`.
+fn format_duration(ms: i32) -> String {
+    if ms < 1_000 {
+        format!("{ms}ms")
+    } else if ms < 60_000 {
+        format!("{:.1}s", ms as f64 / 1000.0)
+    } else {
+        format!("{}m {:02}s", ms / 60_000, ms % 60_000 / 1000)
+    }
+}
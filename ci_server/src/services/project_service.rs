@@ -1,10 +1,21 @@
 //! Project CRUD and pipeline discovery.
+//!
+//! With `CI_AUTO_ONBOARD_TENANT` set, a signed webhook from a repo no
+//! project is registered for creates one in that tenant ([`onboard`]), if
+//! the repo or its owner is listed in `CI_AUTO_ONBOARD_OWNERS`. Without a
+//! webhook secret or that list, auto-onboarding stays off. Its pipeline is
+//! inferred from the files at the repo root: `Cargo.toml` gets cargo build,
+//! clippy and test steps, `package.json` npm install, build and test. A repo
+//! with an in-repo pipeline file gets none, the file takes over; a repo with
+//! neither is not onboarded.
 
 use diesel::prelude::*;
 use diesel_async::{AsyncPgConnection, RunQueryDsl};
 
-use crate::models::project::{CiProject, NewCiProject};
+use crate::config::CiConfig;
+use crate::models::project::{CiProject, NewCiProject, Provider};
 use crate::schema::ci_projects;
+use crate::services::{gitea_service, pipeline_file, policy_service};
 
 /// List all active projects.
 pub async fn list_projects(conn: &mut AsyncPgConnection) -> anyhow::Result<Vec<CiProject>> {
@@ -16,12 +27,28 @@ pub async fn list_projects(conn: &mut AsyncPgConnection) -> anyhow::Result<Vec<C
     Ok(results)
 }
 
-/// Find a project by its GitHub repo identifier (e.g., "centrixsystems/centrix").
+/// Get a project by ID.
+pub async fn get_project(
+    conn: &mut AsyncPgConnection,
+    project_id: i64,
+) -> anyhow::Result<Option<CiProject>> {
+    let result = ci_projects::table
+        .find(project_id)
+        .first::<CiProject>(conn)
+        .await
+        .optional()?;
+    Ok(result)
+}
+
+/// Find a project by its repo identifier on `provider` (e.g.,
+/// "centrixsystems/centrix").
 pub async fn find_by_repo(
     conn: &mut AsyncPgConnection,
+    provider: Provider,
     github_repo: &str,
 ) -> anyhow::Result<Option<CiProject>> {
     let result = ci_projects::table
+        .filter(ci_projects::provider.eq(provider.as_str()))
         .filter(ci_projects::github_repo.eq(github_repo))
         .filter(ci_projects::active.eq(true))
         .first::<CiProject>(conn)
@@ -30,14 +57,218 @@ pub async fn find_by_repo(
     Ok(result)
 }
 
-/// Create a new project.
+/// Create a new project. The pipeline config must pass the tenant's
+/// command policies.
 pub async fn create_project(
     conn: &mut AsyncPgConnection,
     new_project: NewCiProject,
 ) -> anyhow::Result<CiProject> {
+    if let Some(ref config) = new_project.pipeline_config {
+        policy_service::validate_pipeline(conn, new_project.tenant_id, config).await?;
+    }
+
     let result = diesel::insert_into(ci_projects::table)
         .values(&new_project)
         .get_result::<CiProject>(conn)
         .await?;
     Ok(result)
 }
+
+/// Replace a project's pipeline config after checking it against the
+/// tenant's command policies, and its review `environment`.
+pub async fn update_pipeline_config(
+    conn: &mut AsyncPgConnection,
+    project_id: i64,
+    config: serde_json::Value,
+    uid: i64,
+) -> anyhow::Result<Option<CiProject>> {
+    let Some(project) = ci_projects::table
+        .find(project_id)
+        .filter(ci_projects::active.eq(true))
+        .first::<CiProject>(conn)
+        .await
+        .optional()?
+    else {
+        return Ok(None);
+    };
+
+    policy_service::validate_pipeline(conn, project.tenant_id, &config).await?;
+    if let Some(environment) = config.get("environment") {
+        let mut errors = Vec::new();
+        pipeline_file::validate_environment(environment, &mut errors);
+        anyhow::ensure!(errors.is_empty(), "{}", errors.join("; "));
+    }
+
+    let result = diesel::update(ci_projects::table.find(project.id))
+        .set((
+            ci_projects::pipeline_config.eq(Some(config)),
+            ci_projects::write_uid.eq(Some(uid)),
+            ci_projects::write_date.eq(chrono::Utc::now()),
+        ))
+        .get_result::<CiProject>(conn)
+        .await?;
+    Ok(Some(result))
+}
+
+/// Default pipeline for a repo whose root holds `root_files`, or `None` if
+/// nothing there is recognised.
+pub fn infer_pipeline(root_files: &[String]) -> Option<serde_json::Value> {
+    let has = |name: &str| root_files.iter().any(|f| f == name);
+    let mut steps = Vec::new();
+    let mut cache = Vec::new();
+
+    if has("Cargo.toml") {
+        for (name, command) in [
+            ("cargo-build", "cargo build --workspace --all-targets"),
+            (
+                "cargo-clippy",
+                "cargo clippy --workspace --all-targets -- -D warnings",
+            ),
+            ("cargo-test", "cargo test --workspace"),
+        ] {
+            steps.push(serde_json::json!({
+                "name": name,
+                "command": command,
+                "timeout_secs": 1800,
+            }));
+        }
+        cache.push(serde_json::json!({
+            "name": "cargo",
+            "paths": ["target"],
+            "key_files": ["Cargo.lock"],
+        }));
+    }
+    if has("package.json") {
+        let install = if has("package-lock.json") {
+            "npm ci"
+        } else {
+            "npm install"
+        };
+        for (name, command) in [
+            ("npm-install", install),
+            ("npm-build", "npm run build --if-present"),
+            ("npm-test", "npm run test --if-present"),
+        ] {
+            steps.push(serde_json::json!({ "name": name, "command": command }));
+        }
+        cache.push(serde_json::json!({
+            "name": "npm",
+            "paths": ["node_modules"],
+            "key_files": ["package-lock.json"],
+        }));
+    }
+
+    (!steps.is_empty()).then(|| serde_json::json!({ "steps": steps, "cache": cache }))
+}
+
+/// Whether auto-onboarding may register `repo`: its owner or the repo
+/// itself is in `owners` (lowercase).
+pub fn onboarding_allowed(owners: &[String], repo: &str) -> bool {
+    let repo = repo.to_lowercase();
+    let owner = repo
+        .split_once('/')
+        .map_or(repo.as_str(), |(owner, _)| owner);
+    owners.iter().any(|o| *o == owner || *o == repo)
+}
+
+/// Register `repo` on `provider` in the auto-onboarding tenant, inferring
+/// its pipeline from the tree at `git_ref`. Returns `None` when
+/// auto-onboarding is off, the provider's webhooks are unsigned, the repo's
+/// owner is not allowlisted or the repo has nothing to build.
+pub async fn onboard(
+    conn: &mut AsyncPgConnection,
+    config: &CiConfig,
+    provider: Provider,
+    repo: &str,
+    default_branch: &str,
+    git_ref: &str,
+) -> anyhow::Result<Option<CiProject>> {
+    let Some(tenant_id) = config.auto_onboard_tenant else {
+        return Ok(None);
+    };
+    let secret = match provider {
+        Provider::Github => &config.github_webhook_secret,
+        Provider::Gitea => &config.gitea_webhook_secret,
+    };
+    if secret.is_empty() {
+        tracing::warn!(
+            repo,
+            provider = provider.as_str(),
+            "Not onboarding repo: webhooks are unsigned"
+        );
+        return Ok(None);
+    }
+    if !onboarding_allowed(&config.auto_onboard_owners, repo) {
+        tracing::info!(
+            repo,
+            "Not onboarding repo: owner not in CI_AUTO_ONBOARD_OWNERS"
+        );
+        return Ok(None);
+    }
+
+    let root_files = match provider {
+        Provider::Github => config.github.root_files(repo, git_ref).await?,
+        Provider::Gitea => gitea_service::root_files(config, repo, git_ref).await?,
+    };
+    let pipeline_config = if root_files
+        .iter()
+        .any(|f| pipeline_file::PIPELINE_FILES.contains(&f.as_str()))
+    {
+        None
+    } else {
+        match infer_pipeline(&root_files) {
+            Some(pipeline) => Some(pipeline),
+            None => {
+                tracing::info!(
+                    repo,
+                    "Not onboarding repo: no pipeline file, Cargo.toml or package.json"
+                );
+                return Ok(None);
+            }
+        }
+    };
+
+    let new_project = NewCiProject {
+        tenant_id,
+        name: repo.rsplit('/').next().unwrap_or(repo).to_string(),
+        github_repo: repo.to_string(),
+        default_branch: default_branch.to_string(),
+        pipeline_config,
+        visibility: None,
+        active: true,
+        provider: Some(provider.as_str().to_string()),
+        build_all_commits: None,
+    };
+    match create_project(conn, new_project).await {
+        Ok(project) => {
+            tracing::info!(
+                project_id = project.id,
+                repo,
+                provider = provider.as_str(),
+                "Project onboarded"
+            );
+            Ok(Some(project))
+        }
+        // A concurrent webhook for the same repo may have won the insert.
+        Err(e) => match find_by_repo(conn, provider, repo).await? {
+            Some(project) => Ok(Some(project)),
+            None => Err(e),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn onboarding_is_limited_to_allowlisted_owners() {
+        let owners = vec!["acme".to_string(), "other/site".to_string()];
+        assert!(onboarding_allowed(&owners, "acme/app"));
+        assert!(onboarding_allowed(&owners, "Acme/App"));
+        assert!(onboarding_allowed(&owners, "other/site"));
+        assert!(!onboarding_allowed(&owners, "other/app"));
+        assert!(!onboarding_allowed(&owners, "acme-evil/app"));
+        assert!(!onboarding_allowed(&[], "acme/app"));
+    }
+}
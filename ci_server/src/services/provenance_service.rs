@@ -0,0 +1,204 @@
+//! SLSA build provenance attestations.
+//!
+//! After a successful build an in-toto statement with a SLSA v1 provenance
+//! predicate is generated (source repo and commit, pipeline definition hash,
+//! step commands, artifact digests), wrapped in a DSSE envelope signed with
+//! the server key, and stored as the build's `provenance` artifact.
+//! [`verify`] checks the signature and recomputes the artifact digests.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use diesel_async::AsyncPgConnection;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::models::artifact::{CiArtifact, NewCiArtifact};
+use crate::services::oidc_service::OidcSigner;
+use crate::services::{artifact_service, build_service, project_service};
+
+/// Artifact type of stored provenance envelopes.
+pub const PROVENANCE_ARTIFACT_TYPE: &str = "provenance";
+
+const PAYLOAD_TYPE: &str = "application/vnd.in-toto+json";
+const BUILD_TYPE: &str = "https://centrix.systems/ci/build/v1";
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// DSSE pre-authentication encoding of a payload.
+fn pae(payload: &[u8]) -> Vec<u8> {
+    let mut out = format!(
+        "DSSEv1 {} {PAYLOAD_TYPE} {} ",
+        PAYLOAD_TYPE.len(),
+        payload.len()
+    )
+    .into_bytes();
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Subjects of the statement: every non-provenance artifact of the build.
+fn subjects(artifacts: &[CiArtifact]) -> Vec<serde_json::Value> {
+    artifacts
+        .iter()
+        .filter(|a| a.artifact_type != PROVENANCE_ARTIFACT_TYPE)
+        .map(|a| {
+            let digest = sha256_hex(a.content.as_deref().unwrap_or_default().as_bytes());
+            serde_json::json!({"name": a.name, "digest": {"sha256": digest}})
+        })
+        .collect()
+}
+
+/// Generate, sign, and store the provenance of a finished build.
+/// `gitea_url` locates the sources of Gitea projects.
+pub async fn generate(
+    conn: &mut AsyncPgConnection,
+    signer: &OidcSigner,
+    build_id: i64,
+    pipeline_config: Option<&serde_json::Value>,
+    gitea_url: Option<&str>,
+) -> anyhow::Result<CiArtifact> {
+    let build = build_service::get_build(conn, build_id)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("build {build_id} not found"))?;
+    let project = project_service::get_project(conn, build.project_id)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("project {} not found", build.project_id))?;
+    let artifacts = artifact_service::list_for_build(conn, build_id).await?;
+
+    let pipeline = pipeline_config.cloned().unwrap_or(serde_json::Value::Null);
+    let steps: Vec<serde_json::Value> = pipeline
+        .get("steps")
+        .and_then(|s| s.as_array())
+        .map(|steps| {
+            steps
+                .iter()
+                .map(|s| serde_json::json!({"name": s.get("name"), "command": s.get("command")}))
+                .collect()
+        })
+        .unwrap_or_default();
+    let repo_url = project
+        .provider()
+        .repo_url(&project.github_repo, gitea_url)
+        .unwrap_or_else(|| project.github_repo.clone());
+    let source_uri = format!("git+{repo_url}@refs/heads/{}", build.branch);
+
+    let statement = serde_json::json!({
+        "_type": "https://in-toto.io/Statement/v1",
+        "subject": subjects(&artifacts),
+        "predicateType": "https://slsa.dev/provenance/v1",
+        "predicate": {
+            "buildDefinition": {
+                "buildType": BUILD_TYPE,
+                "externalParameters": {
+                    "repository": project.github_repo,
+                    "ref": format!("refs/heads/{}", build.branch),
+                    "trigger": build.trigger_event,
+                },
+                "internalParameters": {
+                    "pipelineSha256": sha256_hex(&serde_json::to_vec(&pipeline)?),
+                    "steps": steps,
+                },
+                "resolvedDependencies": [{
+                    "uri": source_uri,
+                    "digest": {"gitCommit": build.commit_sha},
+                }],
+            },
+            "runDetails": {
+                "builder": {"id": signer.issuer()},
+                "metadata": {
+                    "invocationId": build.id.to_string(),
+                    "startedOn": build.started_at,
+                    "finishedOn": build.finished_at,
+                },
+            },
+        },
+    });
+
+    let payload = serde_json::to_vec(&statement)?;
+    let envelope = serde_json::json!({
+        "payloadType": PAYLOAD_TYPE,
+        "payload": STANDARD.encode(&payload),
+        "signatures": [{
+            "keyid": signer.kid(),
+            "sig": STANDARD.encode(signer.sign(&pae(&payload))),
+        }],
+    });
+    let content = serde_json::to_string(&envelope)?;
+
+    let artifact = artifact_service::store_artifact(
+        conn,
+        NewCiArtifact {
+            tenant_id: build.tenant_id,
+            build_id,
+            name: "provenance.intoto.json".to_string(),
+            artifact_type: PROVENANCE_ARTIFACT_TYPE.to_string(),
+            size_bytes: Some(content.len() as i64),
+            content: Some(content),
+        },
+    )
+    .await?;
+
+    tracing::info!(
+        build_id,
+        artifact_id = artifact.id,
+        "Build provenance stored"
+    );
+    Ok(artifact)
+}
+
+/// Outcome of verifying a build's provenance.
+#[derive(Debug, Serialize)]
+pub struct Verification {
+    pub build_id: i64,
+    pub signature_valid: bool,
+    /// Every current artifact digest matches its subject in the statement.
+    pub subjects_match: bool,
+    /// Artifacts whose digest is missing from or differs in the statement.
+    pub mismatched: Vec<String>,
+    pub statement: serde_json::Value,
+}
+
+/// Verify a build's stored provenance. Returns `None` if it has none.
+pub async fn verify(
+    conn: &mut AsyncPgConnection,
+    signer: &OidcSigner,
+    build_id: i64,
+) -> anyhow::Result<Option<Verification>> {
+    let artifacts = artifact_service::list_for_build(conn, build_id).await?;
+    let Some(stored) = artifacts
+        .iter()
+        .rev()
+        .find(|a| a.artifact_type == PROVENANCE_ARTIFACT_TYPE)
+    else {
+        return Ok(None);
+    };
+
+    let envelope: serde_json::Value =
+        serde_json::from_str(stored.content.as_deref().unwrap_or_default())?;
+    let payload = STANDARD.decode(envelope["payload"].as_str().unwrap_or_default())?;
+    let signature_valid = envelope["signatures"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|s| s["keyid"].as_str() == Some(signer.kid()))
+        .filter_map(|s| STANDARD.decode(s["sig"].as_str()?).ok())
+        .any(|sig| signer.verify(&pae(&payload), &sig));
+
+    let statement: serde_json::Value = serde_json::from_slice(&payload)?;
+    let recorded = statement["subject"].as_array().cloned().unwrap_or_default();
+    let mismatched: Vec<String> = subjects(&artifacts)
+        .into_iter()
+        .filter(|s| !recorded.contains(s))
+        .filter_map(|s| s["name"].as_str().map(str::to_string))
+        .collect();
+
+    Ok(Some(Verification {
+        build_id,
+        signature_valid,
+        subjects_match: mismatched.is_empty(),
+        mismatched,
+        statement,
+    }))
+}
@@ -0,0 +1,102 @@
+//! Per-tenant quota enforcement for builds and environments.
+//!
+//! Limits live in `ci_tenant_quotas`; a missing row or NULL column means
+//! unlimited. Concurrency is enforced by the executor's claim query, see
+//! [`CLAIMABLE_TENANT_SQL`].
+
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use crate::models::quota::CiTenantQuota;
+use crate::schema::{ci_builds, ci_environments, ci_tenant_quotas};
+
+/// SQL predicate over `ci_builds` that is true while the build's tenant is
+/// below its concurrent-build limit.
+pub const CLAIMABLE_TENANT_SQL: &str = "COALESCE(\
+        (SELECT q.max_concurrent_builds FROM ci_tenant_quotas q \
+         WHERE q.tenant_id = ci_builds.tenant_id AND q.active), \
+        2147483647) > \
+    (SELECT COUNT(*) FROM ci_builds r \
+     WHERE r.tenant_id = ci_builds.tenant_id AND r.status = 'running')";
+
+/// A tenant limit was hit.
+#[derive(Debug, thiserror::Error)]
+#[error("quota exceeded: {resource} (limit {limit})")]
+pub struct QuotaExceeded {
+    pub resource: &'static str,
+    pub limit: i32,
+}
+
+/// Load the quota row for a tenant, if one is configured.
+pub async fn get_quota(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+) -> anyhow::Result<Option<CiTenantQuota>> {
+    let result = ci_tenant_quotas::table
+        .filter(ci_tenant_quotas::tenant_id.eq(tenant_id))
+        .filter(ci_tenant_quotas::active.eq(true))
+        .first::<CiTenantQuota>(conn)
+        .await
+        .optional()?;
+    Ok(result)
+}
+
+/// Reject a new build when the tenant has used up its daily allowance.
+pub async fn check_build_quota(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+) -> anyhow::Result<()> {
+    let Some(limit) = get_quota(conn, tenant_id)
+        .await?
+        .and_then(|q| q.max_builds_per_day)
+    else {
+        return Ok(());
+    };
+
+    let cutoff = chrono::Utc::now() - chrono::Duration::days(1);
+    let count: i64 = ci_builds::table
+        .filter(ci_builds::tenant_id.eq(tenant_id))
+        .filter(ci_builds::create_date.gt(cutoff))
+        .count()
+        .get_result(conn)
+        .await?;
+
+    if count >= limit as i64 {
+        return Err(QuotaExceeded {
+            resource: "builds per day",
+            limit,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Reject a new environment when the tenant is at its environment limit.
+pub async fn check_environment_quota(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+) -> anyhow::Result<()> {
+    let Some(limit) = get_quota(conn, tenant_id)
+        .await?
+        .and_then(|q| q.max_environments)
+    else {
+        return Ok(());
+    };
+
+    let count: i64 = ci_environments::table
+        .filter(ci_environments::tenant_id.eq(tenant_id))
+        .filter(ci_environments::status.ne("destroyed"))
+        .filter(ci_environments::active.eq(true))
+        .count()
+        .get_result(conn)
+        .await?;
+
+    if count >= limit as i64 {
+        return Err(QuotaExceeded {
+            resource: "environments",
+            limit,
+        }
+        .into());
+    }
+    Ok(())
+}
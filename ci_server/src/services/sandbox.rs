@@ -0,0 +1,284 @@
+//! Sandboxed step execution.
+//!
+//! By default steps run as `bash -c` directly on the CI host, with the
+//! build's variables and only [`HOST_ENV`] of the server's environment. A
+//! project can opt into the hardened mode with a `sandbox` block in its
+//! pipeline config:
+//!
+//! ```json
+//! { "sandbox": { "enabled": true, "network": false } }
+//! ```
+//!
+//! Sandboxed steps run under bubblewrap in fresh user, PID, IPC, UTS and
+//! network namespaces with every capability dropped. The host's system
+//! directories are mounted read-only, only the build workspace is writable,
+//! and the environment is cleared down to the CI variables. Network access
+//! stays off unless the project whitelists it with `"network": true`.
+//!
+//! With an `"image"` in the block, each step instead runs in a throwaway
+//! Docker or Podman container of that image (which must provide `bash`),
+//! with the same isolation: read-only root filesystem, no capabilities, no
+//! network unless whitelisted, and only the workspace mounted writable.
+//!
+//! The three modes are [`StepBackend`]s; [`SandboxConfig::backend`] picks
+//! the one a pipeline runs on. Untrusted pull request builds are always
+//! sandboxed, whatever their project says.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use tokio::process::Command;
+
+use crate::config::CiConfig;
+use crate::services::limits::ResourceLimits;
+
+/// Path inside the sandbox where the workspace is mounted.
+pub const SANDBOX_WORKDIR: &str = "/workspace";
+
+/// Host directories exposed read-only inside the sandbox (when present).
+const READ_ONLY_MOUNTS: &[&str] = &["/usr", "/bin", "/sbin", "/lib", "/lib64", "/etc"];
+
+/// Server variables steps on the host keep: where the toolchains are. The
+/// rest of the server's environment (database URL, secrets master key,
+/// OIDC signing key, forge tokens) is cleared.
+pub const HOST_ENV: &[&str] = &[
+    "PATH",
+    "HOME",
+    "USER",
+    "LANG",
+    "LC_ALL",
+    "TZ",
+    "TMPDIR",
+    "CARGO_HOME",
+    "RUSTUP_HOME",
+    "GOPATH",
+    "GOROOT",
+    "JAVA_HOME",
+    "NVM_DIR",
+];
+
+/// Per-project sandbox settings, parsed from `pipeline_config.sandbox`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SandboxConfig {
+    pub enabled: bool,
+    /// Share the host network namespace with the step.
+    pub network: bool,
+    /// Run steps in containers of this image rather than under bubblewrap.
+    pub image: Option<String>,
+}
+
+impl SandboxConfig {
+    /// Parse the `sandbox` block of a pipeline config. `default_enabled`
+    /// applies when the project does not say either way.
+    pub fn from_pipeline(config: Option<&serde_json::Value>, default_enabled: bool) -> Self {
+        let block = config.and_then(|c| c.get("sandbox"));
+        Self {
+            enabled: block
+                .and_then(|b| b.get("enabled"))
+                .and_then(|v| v.as_bool())
+                .unwrap_or(default_enabled),
+            network: block
+                .and_then(|b| b.get("network"))
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+            image: block
+                .and_then(|b| b.get("image"))
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string()),
+        }
+    }
+
+    /// The backend this pipeline's steps run on.
+    pub fn backend(&self, config: &CiConfig) -> Box<dyn StepBackend> {
+        match (self.enabled, &self.image) {
+            (false, _) => Box::new(HostBackend),
+            (true, Some(image)) => Box::new(ContainerExecutor {
+                runtime: config.container_runtime.clone(),
+                image: image.clone(),
+                network: self.network,
+            }),
+            (true, None) => Box::new(BubblewrapBackend {
+                bwrap: config.sandbox_bwrap.clone(),
+                network: self.network,
+            }),
+        }
+    }
+}
+
+/// Where and how a step's script runs.
+pub trait StepBackend: Send + Sync {
+    /// Build the command that runs `script` in `work_dir` with `envs` set.
+    /// `run_name` is unique per build and step.
+    fn command(
+        &self,
+        run_name: &str,
+        work_dir: &str,
+        script: &str,
+        envs: &[(&str, String)],
+        limits: &ResourceLimits,
+    ) -> Command;
+
+    /// Whether [`command`](Self::command) enforces `limits` itself. The
+    /// executor confines the process of backends that don't.
+    fn enforces_limits(&self) -> bool {
+        false
+    }
+
+    /// Command that tears down a step whose process was killed, for
+    /// backends whose work outlives that process.
+    fn stop_command(&self, _run_name: &str) -> Option<Command> {
+        None
+    }
+}
+
+/// `bash -c` on the CI host.
+pub struct HostBackend;
+
+impl StepBackend for HostBackend {
+    fn command(
+        &self,
+        _run_name: &str,
+        work_dir: &str,
+        script: &str,
+        envs: &[(&str, String)],
+        _limits: &ResourceLimits,
+    ) -> Command {
+        let mut cmd = Command::new("bash");
+        cmd.args(["-c", script]).current_dir(work_dir).env_clear();
+        for name in HOST_ENV {
+            if let Some(value) = std::env::var_os(name) {
+                cmd.env(name, value);
+            }
+        }
+        for (key, value) in envs {
+            cmd.env(key, value);
+        }
+        cmd
+    }
+}
+
+/// bubblewrap namespaces on the CI host.
+pub struct BubblewrapBackend {
+    pub bwrap: String,
+    pub network: bool,
+}
+
+impl StepBackend for BubblewrapBackend {
+    fn command(
+        &self,
+        _run_name: &str,
+        work_dir: &str,
+        script: &str,
+        envs: &[(&str, String)],
+        _limits: &ResourceLimits,
+    ) -> Command {
+        let mut cmd = Command::new(&self.bwrap);
+        cmd.args(["--die-with-parent", "--new-session", "--unshare-all"]);
+        if self.network {
+            cmd.arg("--share-net");
+        }
+        cmd.args(["--cap-drop", "ALL"]);
+
+        for mount in READ_ONLY_MOUNTS {
+            if Path::new(mount).exists() {
+                cmd.args(["--ro-bind", mount, mount]);
+            }
+        }
+        cmd.args(["--proc", "/proc", "--dev", "/dev", "--tmpfs", "/tmp"]);
+        cmd.args([
+            "--bind",
+            work_dir,
+            SANDBOX_WORKDIR,
+            "--chdir",
+            SANDBOX_WORKDIR,
+        ]);
+
+        cmd.args([
+            "--clearenv",
+            "--setenv",
+            "PATH",
+            "/usr/local/bin:/usr/bin:/bin",
+        ]);
+        cmd.args(["--setenv", "HOME", "/tmp"]);
+        for (key, value) in envs {
+            cmd.args(["--setenv", key, value]);
+        }
+
+        cmd.args(["--", "bash", "-c", script]).current_dir(work_dir);
+        cmd
+    }
+}
+
+/// A throwaway Docker or Podman container per step.
+pub struct ContainerExecutor {
+    /// `docker` or `podman` (or a path to either).
+    pub runtime: String,
+    pub image: String,
+    pub network: bool,
+}
+
+impl StepBackend for ContainerExecutor {
+    fn command(
+        &self,
+        run_name: &str,
+        work_dir: &str,
+        script: &str,
+        envs: &[(&str, String)],
+        limits: &ResourceLimits,
+    ) -> Command {
+        let mut cmd = Command::new(&self.runtime);
+        cmd.args(["run", "--rm", "--init", "--name", run_name]);
+        if !self.network {
+            cmd.args(["--network", "none"]);
+        }
+        cmd.args([
+            "--cap-drop",
+            "ALL",
+            "--security-opt",
+            "no-new-privileges",
+            "--read-only",
+            "--tmpfs",
+            "/tmp",
+        ]);
+        if let Some(mb) = limits.memory_mb {
+            cmd.args(["--memory", &format!("{mb}m")]);
+        }
+        if let Some(cpus) = limits.cpus {
+            cmd.args(["--cpus", &cpus.to_string()]);
+        }
+        // Run as the workspace owner so the files a step writes can be
+        // cleaned up afterwards.
+        if let Ok(meta) = std::fs::metadata(work_dir) {
+            cmd.args(["--user", &format!("{}:{}", meta.uid(), meta.gid())]);
+        }
+        cmd.args([
+            "--volume",
+            &format!("{work_dir}:{SANDBOX_WORKDIR}"),
+            "--workdir",
+            SANDBOX_WORKDIR,
+            "--env",
+            "HOME=/tmp",
+        ]);
+        // Values are passed through the runtime's environment so secrets
+        // stay out of its argv.
+        for (key, value) in envs {
+            cmd.args(["--env", key]).env(key, value);
+        }
+
+        cmd.arg(&self.image)
+            .args(["bash", "-c", script])
+            .current_dir(work_dir);
+        cmd
+    }
+
+    fn enforces_limits(&self) -> bool {
+        true
+    }
+
+    /// Killing `docker run` leaves the container running.
+    fn stop_command(&self, run_name: &str) -> Option<Command> {
+        let mut cmd = Command::new(&self.runtime);
+        cmd.args(["rm", "--force", run_name]);
+        Some(cmd)
+    }
+}
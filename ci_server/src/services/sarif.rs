@@ -0,0 +1,134 @@
+//! SARIF 2.1.0 export of a build's errors.
+//!
+//! `GET /api/builds/{id}/errors.sarif` describes each located error
+//! occurrence of the build as a SARIF result, for upload to GitHub code
+//! scanning (`github/codeql-action/upload-sarif`) or for IDE tooling. Each
+//! error category is a rule, and results carry the error's fingerprint, so
+//! the same error keeps its alert across builds. Errors without a location
+//! are left out: code scanning rejects results that have none.
+
+use diesel_async::AsyncPgConnection;
+
+use crate::models::build::CiBuild;
+use crate::models::error::{CiError, CiErrorOccurrence};
+use crate::services::{codeowners, error_service, project_service};
+
+pub const SARIF_CONTENT_TYPE: &str = "application/sarif+json";
+
+const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+/// Results per document, well under code scanning's 25,000 per run.
+const MAX_RESULTS: i64 = 5000;
+
+fn rule_description(category: &str) -> &str {
+    match category {
+        "compile" => "Compilation error",
+        "test" => "Test failure",
+        "lint" => "Lint finding",
+        "timeout" => "Timeout",
+        "runtime" => "Runtime error",
+        other => other,
+    }
+}
+
+/// A result's location: paths in the checkout relative to `%SRCROOT%`,
+/// anything else as an absolute file URI.
+fn location(path: &str, line: Option<i32>, work_dir: &str) -> serde_json::Value {
+    let path = codeowners::repo_path(path, work_dir);
+    let artifact = if path.starts_with('/') {
+        serde_json::json!({"uri": format!("file://{path}")})
+    } else {
+        serde_json::json!({"uri": path, "uriBaseId": "%SRCROOT%"})
+    };
+    let mut physical = serde_json::json!({"artifactLocation": artifact});
+    if let Some(line) = line.filter(|l| *l > 0) {
+        physical["region"] = serde_json::json!({"startLine": line});
+    }
+    serde_json::json!({"physicalLocation": physical})
+}
+
+fn result(
+    error: &CiError,
+    occurrence: &CiErrorOccurrence,
+    rule_index: usize,
+    work_dir: &str,
+) -> serde_json::Value {
+    let path = occurrence.file_path.as_deref().unwrap_or_default();
+    let message = occurrence.raw_output.as_deref().unwrap_or(&error.title);
+    serde_json::json!({
+        "ruleId": error.category,
+        "ruleIndex": rule_index,
+        "level": if error.severity == "warning" { "warning" } else { "error" },
+        "message": {"text": message},
+        "locations": [location(path, occurrence.line_number, work_dir)],
+        "partialFingerprints": {"centrixErrorFingerprint/v1": error.fingerprint},
+        "properties": {
+            "errorId": error.id,
+            "step": occurrence.step_name,
+            "status": error.status,
+        },
+    })
+}
+
+/// The SARIF document of `build`'s located errors. `workspace_dir` is
+/// the executor's, to make paths in the checkout relative; `gitea_url`
+/// locates the repository of Gitea projects.
+pub async fn build_report(
+    conn: &mut AsyncPgConnection,
+    build: &CiBuild,
+    workspace_dir: &str,
+    gitea_url: Option<&str>,
+) -> anyhow::Result<serde_json::Value> {
+    let located = error_service::located_for_build(conn, build.id, MAX_RESULTS).await?;
+    let project = project_service::get_project(conn, build.project_id).await?;
+    let work_dir = format!("{workspace_dir}/{}", build.id);
+
+    let mut categories: Vec<&str> = located.iter().map(|(e, _)| e.category.as_str()).collect();
+    categories.sort_unstable();
+    categories.dedup();
+    let rules: Vec<serde_json::Value> = categories
+        .iter()
+        .map(|category| {
+            serde_json::json!({
+                "id": category,
+                "name": category,
+                "shortDescription": {"text": rule_description(category)},
+            })
+        })
+        .collect();
+    let results: Vec<serde_json::Value> = located
+        .iter()
+        .map(|(error, occurrence)| {
+            let rule_index = categories
+                .iter()
+                .position(|c| *c == error.category)
+                .unwrap_or_default();
+            result(error, occurrence, rule_index, &work_dir)
+        })
+        .collect();
+
+    let mut run = serde_json::json!({
+        "tool": {"driver": {
+            "name": "centrix-ci",
+            "version": env!("CARGO_PKG_VERSION"),
+            "rules": rules,
+        }},
+        "automationDetails": {"id": format!("centrix-ci/build/{}", build.id)},
+        "originalUriBaseIds": {"%SRCROOT%": {"description": {"text": "The repository root"}}},
+        "results": results,
+    });
+    let repo_url = project.and_then(|p| p.provider().repo_url(&p.github_repo, gitea_url));
+    if let Some(repo_url) = repo_url {
+        run["versionControlProvenance"] = serde_json::json!([{
+            "repositoryUri": repo_url,
+            "revisionId": build.commit_sha,
+            "branch": build.branch,
+        }]);
+    }
+
+    Ok(serde_json::json!({
+        "$schema": SARIF_SCHEMA,
+        "version": "2.1.0",
+        "runs": [run],
+    }))
+}
@@ -0,0 +1,157 @@
+//! Scheduled (cron) build triggers.
+//!
+//! An active `ci_triggers` row with `event_type = "schedule"` and a
+//! `cron_expr` queues a build of its `branch_pattern` (the project's default
+//! branch when unset) each time the expression fires, e.g. `0 2 * * *` for a
+//! nightly build. Expressions are evaluated in UTC; the usual five fields
+//! are accepted, as are six or seven with leading seconds. Fires missed
+//! while the server was down are collapsed into one build. Scheduled builds
+//! carry `trigger_event = "schedule"`.
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use diesel::prelude::*;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+
+use erp_core::db::diesel_pool::DieselPool;
+
+use crate::models::build::{BuildStatus, CiBuild, NewCiBuild};
+use crate::models::trigger::CiTrigger;
+use crate::schema::ci_triggers;
+use crate::services::{build_service, project_service};
+
+/// How often triggers are checked. Cron resolution is one minute.
+const SCHEDULE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// `ci_triggers.event_type` and `ci_builds.trigger_event` of scheduled builds.
+pub const SCHEDULE_EVENT: &str = "schedule";
+
+/// Parse a cron expression, defaulting seconds to 0 for five-field input.
+pub fn parse_cron(expr: &str) -> anyhow::Result<Schedule> {
+    let expr = expr.trim();
+    let full = if expr.split_whitespace().count() == 5 {
+        format!("0 {expr}")
+    } else {
+        expr.to_string()
+    };
+    Schedule::from_str(&full).map_err(|e| anyhow::anyhow!("invalid cron expression {expr:?}: {e}"))
+}
+
+/// Run the scheduler forever. Spawned as a background tokio task.
+pub async fn run_scheduler(pool: Arc<DieselPool>) {
+    tracing::info!("Build scheduler started");
+
+    let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = fire_due(&pool).await {
+            tracing::error!("Build scheduler error: {e}");
+        }
+    }
+}
+
+async fn fire_due(pool: &DieselPool) -> anyhow::Result<()> {
+    let mut conn = crate::db::system_conn(pool).await?;
+
+    let triggers: Vec<CiTrigger> = ci_triggers::table
+        .filter(ci_triggers::active.eq(true))
+        .filter(ci_triggers::event_type.eq(SCHEDULE_EVENT))
+        .filter(ci_triggers::cron_expr.is_not_null())
+        .order(ci_triggers::id.asc())
+        .load(&mut conn)
+        .await?;
+
+    let now = Utc::now();
+    for trigger in triggers {
+        let Some(ref expr) = trigger.cron_expr else {
+            continue;
+        };
+        let schedule = match parse_cron(expr) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                tracing::warn!(trigger_id = trigger.id, "{e}");
+                continue;
+            }
+        };
+        let since = trigger.last_fired_at.or(trigger.create_date).unwrap_or(now);
+        let Some(due) = schedule.after(&since).next().filter(|at| *at <= now) else {
+            continue;
+        };
+
+        match fire(&mut conn, &trigger, due, now).await {
+            Ok(Some(build)) => tracing::info!(
+                trigger_id = trigger.id,
+                build_id = build.id,
+                branch = %build.branch,
+                "Scheduled build queued"
+            ),
+            Ok(None) => {}
+            Err(e) => tracing::error!(trigger_id = trigger.id, "Scheduled build failed: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/// Queue the build for one fire of `trigger`. Returns `None` if another
+/// server already claimed this fire or the project is gone or inactive.
+async fn fire(
+    conn: &mut AsyncPgConnection,
+    trigger: &CiTrigger,
+    due: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Option<CiBuild>> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        async move {
+            // Claiming the fire only succeeds if `last_fired_at` is still
+            // what we read, so servers sharing the database fire it once.
+            let claimed = diesel::update(ci_triggers::table.find(trigger.id))
+                .filter(ci_triggers::last_fired_at.is_not_distinct_from(trigger.last_fired_at))
+                .set(ci_triggers::last_fired_at.eq(now))
+                .execute(conn)
+                .await?;
+            if claimed == 0 {
+                return Ok(None);
+            }
+
+            let Some(project) = project_service::get_project(conn, trigger.project_id)
+                .await?
+                .filter(|p| p.active)
+            else {
+                return Ok(None);
+            };
+            let branch = trigger
+                .branch_pattern
+                .clone()
+                .unwrap_or(project.default_branch);
+
+            let build = build_service::create_build(
+                conn,
+                NewCiBuild {
+                    tenant_id: trigger.tenant_id,
+                    project_id: trigger.project_id,
+                    commit_sha: "HEAD".to_string(),
+                    fingerprint: format!("schedule-{}-{}", trigger.id, due.timestamp()),
+                    branch,
+                    pr_number: None,
+                    author: Some(SCHEDULE_EVENT.to_string()),
+                    message: Some(format!(
+                        "Scheduled build ({})",
+                        trigger.cron_expr.as_deref().unwrap_or_default()
+                    )),
+                    trigger_event: SCHEDULE_EVENT.to_string(),
+                    status: BuildStatus::Pending.as_str().to_string(),
+                    trigger_id: Some(trigger.id),
+                    trusted: true,
+                },
+            )
+            .await?;
+            Ok(Some(build))
+        }
+        .scope_boxed()
+    })
+    .await
+}
@@ -0,0 +1,515 @@
+//! Secret storage with envelope encryption at rest.
+//!
+//! Each secret value is encrypted with its own random AES-256-GCM data key,
+//! and the data key is encrypted ("wrapped") with the master key. Only the
+//! wrapped key is stored, tagged with the master key's fingerprint. Rotating
+//! the master key therefore only rewraps data keys; see [`rewrap_all`].
+//!
+//! Both encryptions authenticate the secret's scope, `tenant|project|name`
+//! (see [`scope`]), so a row copied to another tenant, project or name no
+//! longer decrypts.
+//!
+//! Master keys come from the environment as 64 hex chars:
+//! `CI_SECRETS_MASTER_KEY` is the current key, and `CI_SECRETS_PREVIOUS_KEYS`
+//! (comma-separated) lists retired keys that can still unwrap old rows.
+//!
+//! Every injection of a secret into a build step is recorded in
+//! `ci_secret_access_log` (see [`record_access`]).
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use sha2::{Digest, Sha256};
+
+use crate::models::secret::{CiSecret, NewCiSecret};
+use crate::models::secret_access::{CiSecretAccess, NewCiSecretAccess};
+use crate::schema::{ci_secret_access_log, ci_secrets};
+
+/// A master key and its fingerprint.
+struct MasterKey {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+impl MasterKey {
+    fn from_hex(hex_key: &str) -> anyhow::Result<Self> {
+        let bytes = hex::decode(hex_key.trim())?;
+        anyhow::ensure!(
+            bytes.len() == 32,
+            "master key must be 32 bytes (64 hex chars)"
+        );
+        Ok(Self {
+            id: hex::encode(&Sha256::digest(&bytes)[..8]),
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes)),
+        })
+    }
+}
+
+/// The current master key plus any retired keys still needed for decryption.
+pub struct Keyring {
+    current: MasterKey,
+    previous: Vec<MasterKey>,
+}
+
+impl std::fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keyring")
+            .field("current", &self.current.id)
+            .field(
+                "previous",
+                &self.previous.iter().map(|k| &k.id).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+/// Associated data binding a secret's ciphertext to its scope.
+fn scope(tenant_id: uuid::Uuid, project_id: Option<i64>, name: &str) -> String {
+    match project_id {
+        Some(id) => format!("{tenant_id}|{id}|{name}"),
+        None => format!("{tenant_id}||{name}"),
+    }
+}
+
+/// Encrypted material for one secret value.
+struct Sealed {
+    ciphertext: Vec<u8>,
+    nonce: Vec<u8>,
+    wrapped_key: Vec<u8>,
+    key_nonce: Vec<u8>,
+    master_key_id: String,
+}
+
+impl Keyring {
+    /// Load the keyring from the environment. Returns `None` when no master
+    /// key is configured, which disables secrets.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(current) = std::env::var("CI_SECRETS_MASTER_KEY") else {
+            return Ok(None);
+        };
+        let previous = std::env::var("CI_SECRETS_PREVIOUS_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|k| !k.trim().is_empty())
+            .map(MasterKey::from_hex)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Some(Self {
+            current: MasterKey::from_hex(&current)?,
+            previous,
+        }))
+    }
+
+    /// Whether any retired keys are configured (i.e. a rotation is pending).
+    pub fn has_previous(&self) -> bool {
+        !self.previous.is_empty()
+    }
+
+    fn key(&self, id: &str) -> anyhow::Result<&MasterKey> {
+        std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|k| k.id == id)
+            .ok_or_else(|| anyhow::anyhow!("unknown master key {id}"))
+    }
+
+    /// Encrypt `plaintext` for the secret of scope `aad`.
+    fn seal(&self, plaintext: &str, aad: &str) -> anyhow::Result<Sealed> {
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Aes256Gcm::new(&data_key)
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("secret encryption failed"))?;
+
+        let (wrapped_key, key_nonce) = self.wrap_data_key(data_key.as_slice(), aad)?;
+        Ok(Sealed {
+            ciphertext,
+            nonce: nonce.to_vec(),
+            wrapped_key,
+            key_nonce,
+            master_key_id: self.current.id.clone(),
+        })
+    }
+
+    fn wrap_data_key(&self, data_key: &[u8], aad: &str) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        let key_nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let wrapped_key = self
+            .current
+            .cipher
+            .encrypt(
+                &key_nonce,
+                Payload {
+                    msg: data_key,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("data key wrapping failed"))?;
+        Ok((wrapped_key, key_nonce.to_vec()))
+    }
+
+    fn unwrap_data_key(&self, secret: &CiSecret) -> anyhow::Result<Vec<u8>> {
+        let aad = scope(secret.tenant_id, secret.project_id, &secret.name);
+        self.key(&secret.master_key_id)?
+            .cipher
+            .decrypt(
+                Nonce::from_slice(&secret.key_nonce),
+                Payload {
+                    msg: &secret.wrapped_key,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("data key unwrap failed for secret {}", secret.id))
+    }
+
+    /// Decrypt a stored secret value.
+    pub fn open(&self, secret: &CiSecret) -> anyhow::Result<String> {
+        let data_key = self.unwrap_data_key(secret)?;
+        let aad = scope(secret.tenant_id, secret.project_id, &secret.name);
+        let plaintext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key))
+            .decrypt(
+                Nonce::from_slice(&secret.nonce),
+                Payload {
+                    msg: &secret.ciphertext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("decryption failed for secret {}", secret.id))?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
+
+/// Create or replace a secret. `project_id = None` makes it tenant-wide.
+pub async fn set_secret(
+    conn: &mut AsyncPgConnection,
+    keyring: &Keyring,
+    tenant_id: uuid::Uuid,
+    project_id: Option<i64>,
+    name: &str,
+    value: &str,
+    uid: i64,
+) -> anyhow::Result<CiSecret> {
+    anyhow::ensure!(
+        !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        "secret name must be a valid environment variable name"
+    );
+    let sealed = keyring.seal(value, &scope(tenant_id, project_id, name))?;
+
+    if let Some(existing) = find_secret(conn, project_id, name).await? {
+        let result = diesel::update(ci_secrets::table.find(existing.id))
+            .set((
+                ci_secrets::ciphertext.eq(sealed.ciphertext),
+                ci_secrets::nonce.eq(sealed.nonce),
+                ci_secrets::wrapped_key.eq(sealed.wrapped_key),
+                ci_secrets::key_nonce.eq(sealed.key_nonce),
+                ci_secrets::master_key_id.eq(sealed.master_key_id),
+                ci_secrets::write_uid.eq(Some(uid)),
+                ci_secrets::write_date.eq(chrono::Utc::now()),
+            ))
+            .get_result::<CiSecret>(conn)
+            .await?;
+        return Ok(result);
+    }
+
+    let new_secret = NewCiSecret {
+        tenant_id,
+        project_id,
+        name: name.to_string(),
+        ciphertext: sealed.ciphertext,
+        nonce: sealed.nonce,
+        wrapped_key: sealed.wrapped_key,
+        key_nonce: sealed.key_nonce,
+        master_key_id: sealed.master_key_id,
+        create_uid: Some(uid),
+    };
+    let result = diesel::insert_into(ci_secrets::table)
+        .values(&new_secret)
+        .get_result::<CiSecret>(conn)
+        .await?;
+    Ok(result)
+}
+
+async fn find_secret(
+    conn: &mut AsyncPgConnection,
+    project_id: Option<i64>,
+    name: &str,
+) -> anyhow::Result<Option<CiSecret>> {
+    let mut query = ci_secrets::table
+        .filter(ci_secrets::name.eq(name))
+        .filter(ci_secrets::active.eq(true))
+        .into_boxed();
+    query = match project_id {
+        Some(id) => query.filter(ci_secrets::project_id.eq(id)),
+        None => query.filter(ci_secrets::project_id.is_null()),
+    };
+    Ok(query.first::<CiSecret>(conn).await.optional()?)
+}
+
+/// List the secrets visible to a project: its own plus tenant-wide ones.
+/// Values are not decrypted.
+pub async fn list_secrets(
+    conn: &mut AsyncPgConnection,
+    project_id: i64,
+) -> anyhow::Result<Vec<CiSecret>> {
+    let results = ci_secrets::table
+        .filter(
+            ci_secrets::project_id
+                .eq(project_id)
+                .or(ci_secrets::project_id.is_null()),
+        )
+        .filter(ci_secrets::active.eq(true))
+        .order(ci_secrets::name.asc())
+        .load::<CiSecret>(conn)
+        .await?;
+    Ok(results)
+}
+
+/// A decrypted secret ready for injection.
+pub struct ResolvedSecret {
+    pub id: i64,
+    pub name: String,
+    pub value: String,
+}
+
+/// Decrypt the secrets a project's builds receive. A project secret wins
+/// over a tenant-wide one of the same name.
+///
+/// Filters on `tenant_id` explicitly so it also works on the executor's
+/// system-context connection.
+pub async fn resolve_for_project(
+    conn: &mut AsyncPgConnection,
+    keyring: &Keyring,
+    tenant_id: uuid::Uuid,
+    project_id: i64,
+) -> anyhow::Result<Vec<ResolvedSecret>> {
+    let mut secrets: Vec<CiSecret> = ci_secrets::table
+        .filter(ci_secrets::tenant_id.eq(tenant_id))
+        .filter(
+            ci_secrets::project_id
+                .eq(project_id)
+                .or(ci_secrets::project_id.is_null()),
+        )
+        .filter(ci_secrets::active.eq(true))
+        .load(conn)
+        .await?;
+
+    // Project-scoped rows sort after tenant-wide ones, so they overwrite.
+    secrets.sort_by_key(|s| s.project_id.is_some());
+    let mut by_name = std::collections::BTreeMap::new();
+    for secret in secrets {
+        by_name.insert(secret.name.clone(), secret);
+    }
+
+    by_name
+        .into_values()
+        .map(|secret| {
+            Ok(ResolvedSecret {
+                value: keyring.open(&secret)?,
+                id: secret.id,
+                name: secret.name,
+            })
+        })
+        .collect()
+}
+
+/// Record that `secrets` were injected into a build step.
+pub async fn record_access(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+    project_id: i64,
+    build_id: i64,
+    step_name: &str,
+    secrets: &[ResolvedSecret],
+) -> anyhow::Result<()> {
+    if secrets.is_empty() {
+        return Ok(());
+    }
+    let rows: Vec<NewCiSecretAccess> = secrets
+        .iter()
+        .map(|secret| NewCiSecretAccess {
+            tenant_id,
+            secret_id: Some(secret.id),
+            secret_name: secret.name.clone(),
+            project_id,
+            build_id,
+            step_name: step_name.to_string(),
+        })
+        .collect();
+    diesel::insert_into(ci_secret_access_log::table)
+        .values(&rows)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Query the access log, newest first, by secret name and/or build.
+pub async fn access_log(
+    conn: &mut AsyncPgConnection,
+    secret_name: Option<&str>,
+    build_id: Option<i64>,
+    limit: i64,
+) -> anyhow::Result<Vec<CiSecretAccess>> {
+    let mut query = ci_secret_access_log::table.into_boxed();
+    if let Some(name) = secret_name {
+        query = query.filter(ci_secret_access_log::secret_name.eq(name));
+    }
+    if let Some(build_id) = build_id {
+        query = query.filter(ci_secret_access_log::build_id.eq(build_id));
+    }
+    let results = query
+        .order(ci_secret_access_log::id.desc())
+        .limit(limit)
+        .load::<CiSecretAccess>(conn)
+        .await?;
+    Ok(results)
+}
+
+/// Replace every occurrence of a secret value in build output.
+pub fn mask(output: &str, secrets: &[ResolvedSecret]) -> String {
+    secrets
+        .iter()
+        .filter(|s| s.value.len() >= 4)
+        .fold(output.to_string(), |out, s| out.replace(&s.value, "***"))
+}
+
+/// Delete a secret. `project_id = None` deletes a tenant-wide one. Returns
+/// whether one existed.
+pub async fn delete_secret(
+    conn: &mut AsyncPgConnection,
+    project_id: Option<i64>,
+    name: &str,
+) -> anyhow::Result<bool> {
+    let mut query = diesel::delete(ci_secrets::table)
+        .filter(ci_secrets::name.eq(name))
+        .into_boxed();
+    query = match project_id {
+        Some(id) => query.filter(ci_secrets::project_id.eq(id)),
+        None => query.filter(ci_secrets::project_id.is_null()),
+    };
+    Ok(query.execute(conn).await? > 0)
+}
+
+/// Rewrap every data key that is not under the current master key. Run in
+/// system context after rotating `CI_SECRETS_MASTER_KEY`; once it reports
+/// no failures the retired keys can be dropped from the environment.
+pub async fn rewrap_all(conn: &mut AsyncPgConnection, keyring: &Keyring) -> anyhow::Result<usize> {
+    let stale: Vec<CiSecret> = ci_secrets::table
+        .filter(ci_secrets::master_key_id.ne(&keyring.current.id))
+        .load(conn)
+        .await?;
+
+    let mut rewrapped = 0;
+    for secret in &stale {
+        let data_key = match keyring.unwrap_data_key(secret) {
+            Ok(k) => k,
+            Err(e) => {
+                tracing::error!(secret_id = secret.id, "Cannot rewrap secret: {e}");
+                continue;
+            }
+        };
+        let aad = scope(secret.tenant_id, secret.project_id, &secret.name);
+        let (wrapped_key, key_nonce) = keyring.wrap_data_key(&data_key, &aad)?;
+
+        diesel::update(ci_secrets::table.find(secret.id))
+            .set((
+                ci_secrets::wrapped_key.eq(wrapped_key),
+                ci_secrets::key_nonce.eq(key_nonce),
+                ci_secrets::master_key_id.eq(&keyring.current.id),
+                ci_secrets::write_date.eq(chrono::Utc::now()),
+            ))
+            .execute(conn)
+            .await?;
+        rewrapped += 1;
+    }
+
+    tracing::info!(
+        rewrapped,
+        failed = stale.len() - rewrapped,
+        master_key = %keyring.current.id,
+        "Secret data keys rewrapped"
+    );
+    Ok(rewrapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyring() -> Keyring {
+        Keyring {
+            current: MasterKey::from_hex(&"11".repeat(32)).unwrap(),
+            previous: Vec::new(),
+        }
+    }
+
+    fn stored(keyring: &Keyring, project_id: Option<i64>, name: &str) -> CiSecret {
+        let tenant_id = uuid::Uuid::nil();
+        let sealed = keyring
+            .seal("s3cret", &scope(tenant_id, project_id, name))
+            .unwrap();
+        CiSecret {
+            id: 1,
+            tenant_id,
+            project_id,
+            name: name.to_string(),
+            ciphertext: sealed.ciphertext,
+            nonce: sealed.nonce,
+            wrapped_key: sealed.wrapped_key,
+            key_nonce: sealed.key_nonce,
+            master_key_id: sealed.master_key_id,
+            active: true,
+            create_uid: None,
+            create_date: None,
+            write_uid: None,
+            write_date: None,
+        }
+    }
+
+    #[test]
+    fn opens_in_its_own_scope() {
+        let keyring = keyring();
+        assert_eq!(
+            keyring
+                .open(&stored(&keyring, Some(7), "DEPLOY_KEY"))
+                .unwrap(),
+            "s3cret"
+        );
+        assert_eq!(
+            keyring.open(&stored(&keyring, None, "DEPLOY_KEY")).unwrap(),
+            "s3cret"
+        );
+    }
+
+    #[test]
+    fn does_not_open_in_another_scope() {
+        let keyring = keyring();
+        let secret = stored(&keyring, Some(7), "DEPLOY_KEY");
+
+        let moved = CiSecret {
+            project_id: Some(8),
+            ..secret.clone()
+        };
+        assert!(keyring.open(&moved).is_err());
+        let tenant_wide = CiSecret {
+            project_id: None,
+            ..secret.clone()
+        };
+        assert!(keyring.open(&tenant_wide).is_err());
+        let renamed = CiSecret {
+            name: "OTHER_KEY".to_string(),
+            ..secret.clone()
+        };
+        assert!(keyring.open(&renamed).is_err());
+        let other_tenant = CiSecret {
+            tenant_id: uuid::Uuid::from_u128(2),
+            ..secret
+        };
+        assert!(keyring.open(&other_tenant).is_err());
+    }
+}
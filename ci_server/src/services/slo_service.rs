@@ -0,0 +1,97 @@
+//! SLO burn-rate metrics for build outcomes.
+//!
+//! Every minute the failure ratio of finished builds (failure or timeout
+//! over success, failure, and timeout) is computed per project over the
+//! windows of the usual multi-window, multi-burn-rate alerts, and exported
+//! with the burn rate `failure_ratio / (1 - slo_target)`. A page on
+//! `burn_rate{window="1h"} > 14.4 and burn_rate{window="5m"} > 14.4` then
+//! works like it would for any other service.
+
+use std::sync::Arc;
+
+use diesel::sql_types::{Double, Text};
+use diesel_async::RunQueryDsl;
+
+use erp_core::db::diesel_pool::DieselPool;
+
+/// How often the ratios are recomputed.
+const REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Window label and length in minutes, shortest first. The longest one
+/// bounds the query.
+const WINDOWS: &[(&str, i64)] = &[
+    ("5m", 5),
+    ("30m", 30),
+    ("1h", 60),
+    ("2h", 120),
+    ("6h", 360),
+    ("1d", 1440),
+    ("3d", 4320),
+];
+
+/// Run the exporter forever. Spawned as a background tokio task.
+pub async fn run_slo_exporter(pool: Arc<DieselPool>, slo_target: f64) {
+    tracing::info!(slo_target, "SLO exporter started");
+    crate::metrics::build_slo_target(slo_target);
+
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = refresh(&pool, slo_target).await {
+            tracing::error!("SLO refresh error: {e}");
+        }
+    }
+}
+
+async fn refresh(pool: &DieselPool, slo_target: f64) -> anyhow::Result<()> {
+    #[derive(diesel::QueryableByName)]
+    struct Row {
+        #[diesel(sql_type = Text)]
+        project: String,
+        #[diesel(sql_type = Text)]
+        window: String,
+        #[diesel(sql_type = Double)]
+        failure_ratio: f64,
+    }
+
+    let mut conn = crate::db::system_conn(pool).await?;
+
+    let windows = WINDOWS
+        .iter()
+        .map(|(label, minutes)| format!("('{label}', {minutes})"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let longest = WINDOWS.last().map_or(0, |(_, minutes)| *minutes);
+
+    // Every window gets a row for every project with builds in the longest
+    // one, so a quiet window reads 0 rather than keeping a stale value.
+    let rows: Vec<Row> = diesel::sql_query(format!(
+        "SELECT p.github_repo AS project, w.label AS window, \
+             COALESCE(COUNT(b.id) FILTER (WHERE b.status IN ('failure', 'timeout'))::FLOAT8 \
+                 / NULLIF(COUNT(b.id), 0), 0) AS failure_ratio \
+         FROM (VALUES {windows}) AS w(label, minutes) \
+         CROSS JOIN ci_projects p \
+         LEFT JOIN ci_builds b ON b.project_id = p.id \
+             AND b.status IN ('success', 'failure', 'timeout') \
+             AND b.finished_at >= NOW() - make_interval(mins => w.minutes) \
+         WHERE EXISTS ( \
+             SELECT 1 FROM ci_builds r \
+             WHERE r.project_id = p.id \
+               AND r.finished_at >= NOW() - INTERVAL '{longest} minutes' \
+         ) \
+         GROUP BY p.github_repo, w.label"
+    ))
+    .load(&mut conn)
+    .await?;
+
+    let budget = 1.0 - slo_target;
+    for row in rows {
+        crate::metrics::build_slo_window(
+            &row.project,
+            &row.window,
+            row.failure_ratio,
+            row.failure_ratio / budget,
+        );
+    }
+    Ok(())
+}
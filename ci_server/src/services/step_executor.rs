@@ -6,7 +6,7 @@
 use diesel::prelude::*;
 use diesel_async::{AsyncPgConnection, RunQueryDsl};
 
-use crate::models::build_step::NewCiBuildStep;
+use crate::models::build_step::{CiBuildStep, NewCiBuildStep};
 use crate::schema::ci_build_steps;
 
 /// Record a step starting.
@@ -16,6 +16,19 @@ pub async fn start_step(
     step_name: &str,
     sequence: i32,
     tenant_id: uuid::Uuid,
+) -> anyhow::Result<i64> {
+    start_attempt(conn, build_id, step_name, sequence, 1, tenant_id).await
+}
+
+/// Record an attempt of a step starting; retries get a row each.
+#[tracing::instrument(name = "db.start_step", skip(conn, tenant_id))]
+pub async fn start_attempt(
+    conn: &mut AsyncPgConnection,
+    build_id: i64,
+    step_name: &str,
+    sequence: i32,
+    attempt: i32,
+    tenant_id: uuid::Uuid,
 ) -> anyhow::Result<i64> {
     let new_step = NewCiBuildStep {
         tenant_id,
@@ -23,6 +36,7 @@ pub async fn start_step(
         name: step_name.to_string(),
         sequence,
         status: "running".to_string(),
+        attempt,
     };
 
     let result: crate::models::build_step::CiBuildStep =
@@ -35,6 +49,7 @@ pub async fn start_step(
 }
 
 /// Record a step completing.
+#[tracing::instrument(name = "db.complete_step", skip(conn, stdout, stderr))]
 pub async fn complete_step(
     conn: &mut AsyncPgConnection,
     step_id: i64,
@@ -59,3 +74,72 @@ pub async fn complete_step(
 
     Ok(())
 }
+
+/// Record a step that never ran, with the reason in its stderr.
+#[tracing::instrument(name = "db.skip_step", skip(conn, tenant_id))]
+pub async fn skip_step(
+    conn: &mut AsyncPgConnection,
+    build_id: i64,
+    step_name: &str,
+    sequence: i32,
+    tenant_id: uuid::Uuid,
+    reason: &str,
+) -> anyhow::Result<()> {
+    let step_id = start_step(conn, build_id, step_name, sequence, tenant_id).await?;
+    end_step(conn, step_id, "skipped", 0, None, Some(reason.to_string())).await
+}
+
+/// Record a step that ended without an exit status of its own (timed out or
+/// killed on cancellation).
+#[tracing::instrument(name = "db.end_step", skip(conn, stdout, stderr))]
+pub async fn end_step(
+    conn: &mut AsyncPgConnection,
+    step_id: i64,
+    status: &str,
+    duration_ms: i32,
+    stdout: Option<String>,
+    stderr: Option<String>,
+) -> anyhow::Result<()> {
+    diesel::update(ci_build_steps::table.find(step_id))
+        .set((
+            ci_build_steps::status.eq(status),
+            ci_build_steps::duration_ms.eq(duration_ms),
+            ci_build_steps::stdout.eq(stdout),
+            ci_build_steps::stderr.eq(stderr),
+            ci_build_steps::finished_at.eq(chrono::Utc::now()),
+        ))
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Record the peak memory of a step's processes.
+pub async fn record_peak_memory(
+    conn: &mut AsyncPgConnection,
+    step_id: i64,
+    max_rss_bytes: i64,
+) -> anyhow::Result<()> {
+    diesel::update(ci_build_steps::table.find(step_id))
+        .set(ci_build_steps::max_rss_bytes.eq(max_rss_bytes))
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// A build's steps in pipeline order, each at its latest attempt.
+pub async fn latest_attempts(
+    conn: &mut AsyncPgConnection,
+    build_id: i64,
+) -> anyhow::Result<Vec<CiBuildStep>> {
+    let mut steps: Vec<CiBuildStep> = ci_build_steps::table
+        .filter(ci_build_steps::build_id.eq(build_id))
+        .order((
+            ci_build_steps::sequence.asc(),
+            ci_build_steps::attempt.desc(),
+        ))
+        .load(conn)
+        .await?;
+    steps.dedup_by_key(|s| s.sequence);
+    Ok(steps)
+}
@@ -0,0 +1,200 @@
+//! API token issuance, verification, and rotation.
+//!
+//! Tokens look like `cit_<64 hex chars>`. Only their SHA-256 is stored, so a
+//! token is shown exactly once, when it is issued or rotated.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use sha2::{Digest, Sha256};
+
+use crate::models::api_token::{CiApiToken, NewCiApiToken};
+use crate::schema::ci_api_tokens;
+
+/// Prefix identifying CI API tokens in `Authorization` headers.
+pub const TOKEN_PREFIX: &str = "cit_";
+
+/// Default time a rotated-out token keeps working.
+pub const DEFAULT_ROTATION_GRACE_SECS: i64 = 24 * 3600;
+
+/// Longest lifetime a token may be issued with, in days.
+pub const MAX_LIFETIME_DAYS: i64 = 3650;
+
+/// Longest time a rotated-out token may keep working.
+pub const MAX_ROTATION_GRACE_SECS: i64 = 30 * 24 * 3600;
+
+/// A freshly issued token together with its plaintext value.
+pub struct IssuedToken {
+    pub token: CiApiToken,
+    pub plaintext: String,
+}
+
+fn hash_token(plaintext: &str) -> String {
+    hex::encode(Sha256::digest(plaintext.as_bytes()))
+}
+
+fn generate_token() -> String {
+    format!(
+        "{TOKEN_PREFIX}{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// Issue a new token for `uid`, optionally scoped to one project.
+pub async fn issue_token(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+    uid: i64,
+    name: &str,
+    project_id: Option<i64>,
+    expires_at: Option<DateTime<Utc>>,
+) -> anyhow::Result<IssuedToken> {
+    let plaintext = generate_token();
+    let new_token = NewCiApiToken {
+        tenant_id,
+        project_id,
+        uid,
+        name: name.to_string(),
+        token_prefix: plaintext[..TOKEN_PREFIX.len() + 8].to_string(),
+        token_hash: hash_token(&plaintext),
+        expires_at,
+        create_uid: Some(uid),
+    };
+
+    let token = diesel::insert_into(ci_api_tokens::table)
+        .values(&new_token)
+        .get_result::<CiApiToken>(conn)
+        .await?;
+
+    tracing::info!(token_id = token.id, project_id, "API token issued");
+    Ok(IssuedToken { token, plaintext })
+}
+
+/// Resolve a presented token. Returns `None` for unknown, revoked, or
+/// expired tokens, and records the use on success.
+///
+/// Runs before the tenant is known, so call it on a system-context connection.
+pub async fn verify_token(
+    conn: &mut AsyncPgConnection,
+    plaintext: &str,
+) -> anyhow::Result<Option<CiApiToken>> {
+    let now = Utc::now();
+    let token: Option<CiApiToken> = ci_api_tokens::table
+        .filter(ci_api_tokens::token_hash.eq(hash_token(plaintext)))
+        .filter(ci_api_tokens::active.eq(true))
+        .filter(ci_api_tokens::revoked_at.is_null())
+        .filter(
+            ci_api_tokens::expires_at
+                .is_null()
+                .or(ci_api_tokens::expires_at.gt(now)),
+        )
+        .first(conn)
+        .await
+        .optional()?;
+
+    if let Some(ref t) = token {
+        diesel::update(ci_api_tokens::table.find(t.id))
+            .set(ci_api_tokens::last_used_at.eq(now))
+            .execute(conn)
+            .await?;
+    }
+
+    Ok(token)
+}
+
+/// List the tokens of the current tenant (hashes are never serialized).
+pub async fn list_tokens(conn: &mut AsyncPgConnection) -> anyhow::Result<Vec<CiApiToken>> {
+    let results = ci_api_tokens::table
+        .filter(ci_api_tokens::active.eq(true))
+        .order(ci_api_tokens::id.desc())
+        .load::<CiApiToken>(conn)
+        .await?;
+    Ok(results)
+}
+
+/// Get a token by ID.
+pub async fn get_token(
+    conn: &mut AsyncPgConnection,
+    token_id: i64,
+) -> anyhow::Result<Option<CiApiToken>> {
+    let result = ci_api_tokens::table
+        .find(token_id)
+        .filter(ci_api_tokens::active.eq(true))
+        .first::<CiApiToken>(conn)
+        .await
+        .optional()?;
+    Ok(result)
+}
+
+/// Replace a token with a new one of the same owner, scope and lifetime.
+/// The old token stays valid for `grace_secs` (at most
+/// [`MAX_ROTATION_GRACE_SECS`]) so automation can roll over.
+/// Issuing the new token and expiring the old one is one transaction, so a
+/// failed rotation leaves no second live token.
+pub async fn rotate_token(
+    conn: &mut AsyncPgConnection,
+    old: &CiApiToken,
+    grace_secs: i64,
+) -> anyhow::Result<IssuedToken> {
+    let lifetime = match (old.expires_at, old.create_date) {
+        (Some(expires), Some(created)) => Some(expires - created),
+        _ => None,
+    };
+    let issued = conn
+        .transaction::<_, anyhow::Error, _>(|conn| {
+            async move {
+                let issued = issue_token(
+                    conn,
+                    old.tenant_id,
+                    old.uid,
+                    &old.name,
+                    old.project_id,
+                    lifetime.map(|l| Utc::now() + l),
+                )
+                .await?;
+
+                let grace_end = Utc::now()
+                    + chrono::Duration::seconds(grace_secs.clamp(0, MAX_ROTATION_GRACE_SECS));
+                let old_expiry = match old.expires_at {
+                    Some(expires) if expires < grace_end => expires,
+                    _ => grace_end,
+                };
+                // Only a token not rotated or revoked meanwhile: of two
+                // concurrent rotations, one wins.
+                let updated = diesel::update(ci_api_tokens::table.find(old.id))
+                    .filter(ci_api_tokens::rotated_to_id.is_null())
+                    .filter(ci_api_tokens::revoked_at.is_null())
+                    .set((
+                        ci_api_tokens::expires_at.eq(old_expiry),
+                        ci_api_tokens::rotated_to_id.eq(issued.token.id),
+                    ))
+                    .execute(conn)
+                    .await?;
+                if updated == 0 {
+                    anyhow::bail!("token {} was already rotated or revoked", old.id);
+                }
+                Ok(issued)
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    tracing::info!(
+        old_token_id = old.id,
+        new_token_id = issued.token.id,
+        "API token rotated"
+    );
+    Ok(issued)
+}
+
+/// Revoke a token immediately.
+pub async fn revoke_token(conn: &mut AsyncPgConnection, token_id: i64) -> anyhow::Result<bool> {
+    let updated = diesel::update(ci_api_tokens::table.find(token_id))
+        .filter(ci_api_tokens::revoked_at.is_null())
+        .set(ci_api_tokens::revoked_at.eq(Utc::now()))
+        .execute(conn)
+        .await?;
+    Ok(updated > 0)
+}
@@ -0,0 +1,334 @@
+//! Trigger matching for webhook events.
+//!
+//! A push or pull request builds unconditionally unless the project has
+//! active triggers for the event. With such triggers, only branches some
+//! trigger's `branch_pattern` covers build; others are ignored. The event
+//! builds through the first covering trigger whose path filters accept the
+//! changed files, preferring triggers with a pattern over those without;
+//! if none does, it is recorded as skipped.
+//!
+//! `branch_pattern` is a glob over branch names (`main`, `release/*`,
+//! `feature/**`), or with a `re:` prefix a regex that must match the whole
+//! name (`re:v\d+\.\d+`). An unset pattern covers every branch.
+//!
+//! `paths` and `paths_ignore` are glob lists over repository paths, e.g.
+//! `["services/api/**", "Cargo.lock"]`. `*` and `?` stay within one
+//! directory, `**` spans any number of them. A changed file counts if it
+//! matches `paths` (or `paths` is empty) and no `paths_ignore` entry; the
+//! trigger accepts the event if any file counts. When the changed files
+//! are unknown, every trigger accepts.
+//!
+//! Pull request triggers can also leave out drafts (`skip_drafts`) and
+//! require one of their `labels` on the PR. A PR no covering trigger
+//! admits is recorded as skipped, before path filters are looked at.
+
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use regex::Regex;
+
+use crate::models::trigger::{CiTrigger, NewCiTrigger};
+use crate::schema::ci_triggers;
+use crate::services::schedule_service::{self, SCHEDULE_EVENT};
+
+/// Event types a trigger can be defined for.
+pub const EVENT_TYPES: &[&str] = &["push", "pull_request", "generic", SCHEDULE_EVENT];
+
+/// Outcome of matching an event against a project's triggers.
+#[derive(Debug)]
+pub enum Selection<'a> {
+    /// The project has no triggers for the event; build as usual.
+    Untriggered,
+    /// Triggers exist but none covers the branch; ignore the event.
+    Unmatched,
+    /// Build through this trigger.
+    Trigger(&'a CiTrigger),
+    /// Triggers exist but their path filters rule the changes out.
+    Filtered,
+    /// Pull request triggers exist but none admits the PR, for this reason.
+    Excluded(&'static str),
+}
+
+/// What pull request triggers filter on besides branch and paths.
+#[derive(Debug)]
+pub struct PullRequest<'a> {
+    pub draft: bool,
+    pub labels: Vec<&'a str>,
+}
+
+/// Active triggers of `project_id` for `event_type`, those with a branch
+/// pattern first.
+pub async fn for_event(
+    conn: &mut AsyncPgConnection,
+    project_id: i64,
+    event_type: &str,
+) -> anyhow::Result<Vec<CiTrigger>> {
+    let triggers = ci_triggers::table
+        .filter(ci_triggers::project_id.eq(project_id))
+        .filter(ci_triggers::active.eq(true))
+        .filter(ci_triggers::event_type.eq(event_type))
+        .order((
+            ci_triggers::branch_pattern.is_null().asc(),
+            ci_triggers::id.asc(),
+        ))
+        .load(conn)
+        .await?;
+    Ok(triggers)
+}
+
+/// Check that a `re:` branch pattern compiles; globs always do.
+pub fn check_pattern(pattern: &str) -> anyhow::Result<()> {
+    if let Some(regex) = pattern.strip_prefix("re:") {
+        Regex::new(&format!("^(?:{regex})$"))
+            .map_err(|e| anyhow::anyhow!("invalid branch pattern {pattern:?}: {e}"))?;
+    }
+    Ok(())
+}
+
+/// Check a trigger's event type, branch pattern and cron expression
+/// before storing it.
+fn validate(trigger: &NewCiTrigger) -> anyhow::Result<()> {
+    if !EVENT_TYPES.contains(&trigger.event_type.as_str()) {
+        anyhow::bail!("unknown trigger event type {:?}", trigger.event_type);
+    }
+    if let Some(pattern) = &trigger.branch_pattern {
+        check_pattern(pattern)?;
+    }
+    match (&trigger.cron_expr, trigger.event_type == SCHEDULE_EVENT) {
+        (Some(expr), true) => {
+            schedule_service::parse_cron(expr)?;
+        }
+        (None, true) => anyhow::bail!("schedule triggers need a cron expression"),
+        (Some(_), false) => anyhow::bail!("only schedule triggers take a cron expression"),
+        (None, false) => {}
+    }
+    if trigger.env.as_ref().is_some_and(|env| !env.is_object()) {
+        anyhow::bail!("trigger env must be an object");
+    }
+    Ok(())
+}
+
+/// Active triggers of a project, oldest first.
+pub async fn list(conn: &mut AsyncPgConnection, project_id: i64) -> anyhow::Result<Vec<CiTrigger>> {
+    let triggers = ci_triggers::table
+        .filter(ci_triggers::project_id.eq(project_id))
+        .filter(ci_triggers::active.eq(true))
+        .order(ci_triggers::id.asc())
+        .load(conn)
+        .await?;
+    Ok(triggers)
+}
+
+/// Get an active trigger of a project by ID.
+pub async fn get(
+    conn: &mut AsyncPgConnection,
+    project_id: i64,
+    trigger_id: i64,
+) -> anyhow::Result<Option<CiTrigger>> {
+    let trigger = ci_triggers::table
+        .find(trigger_id)
+        .filter(ci_triggers::project_id.eq(project_id))
+        .filter(ci_triggers::active.eq(true))
+        .first(conn)
+        .await
+        .optional()?;
+    Ok(trigger)
+}
+
+/// Create a trigger after validating it.
+pub async fn create(
+    conn: &mut AsyncPgConnection,
+    trigger: NewCiTrigger,
+    uid: i64,
+) -> anyhow::Result<CiTrigger> {
+    validate(&trigger)?;
+    let row = diesel::insert_into(ci_triggers::table)
+        .values((&trigger, ci_triggers::create_uid.eq(Some(uid))))
+        .get_result(conn)
+        .await?;
+    Ok(row)
+}
+
+/// Replace a trigger's rule with `trigger`'s. Its tenant, project and
+/// schedule state are kept. Returns `None` if it does not exist.
+pub async fn update(
+    conn: &mut AsyncPgConnection,
+    trigger_id: i64,
+    trigger: NewCiTrigger,
+    uid: i64,
+) -> anyhow::Result<Option<CiTrigger>> {
+    validate(&trigger)?;
+    let row = diesel::update(
+        ci_triggers::table
+            .filter(ci_triggers::id.eq(trigger_id))
+            .filter(ci_triggers::project_id.eq(trigger.project_id))
+            .filter(ci_triggers::active.eq(true)),
+    )
+    .set((
+        ci_triggers::event_type.eq(trigger.event_type),
+        ci_triggers::branch_pattern.eq(trigger.branch_pattern),
+        ci_triggers::cron_expr.eq(trigger.cron_expr),
+        ci_triggers::env.eq(trigger.env),
+        ci_triggers::paths.eq(trigger.paths),
+        ci_triggers::paths_ignore.eq(trigger.paths_ignore),
+        ci_triggers::skip_drafts.eq(trigger.skip_drafts),
+        ci_triggers::labels.eq(trigger.labels),
+        ci_triggers::write_uid.eq(Some(uid)),
+        ci_triggers::write_date.eq(Some(chrono::Utc::now())),
+    ))
+    .get_result(conn)
+    .await
+    .optional()?;
+    Ok(row)
+}
+
+/// Deactivate a trigger. Returns whether it existed.
+pub async fn delete(
+    conn: &mut AsyncPgConnection,
+    project_id: i64,
+    trigger_id: i64,
+    uid: i64,
+) -> anyhow::Result<bool> {
+    let updated = diesel::update(
+        ci_triggers::table
+            .filter(ci_triggers::id.eq(trigger_id))
+            .filter(ci_triggers::project_id.eq(project_id))
+            .filter(ci_triggers::active.eq(true)),
+    )
+    .set((
+        ci_triggers::active.eq(false),
+        ci_triggers::write_uid.eq(Some(uid)),
+        ci_triggers::write_date.eq(Some(chrono::Utc::now())),
+    ))
+    .execute(conn)
+    .await?;
+    Ok(updated > 0)
+}
+
+/// Whether `pattern` covers `branch`. An invalid `re:` pattern covers
+/// nothing.
+pub fn branch_matches(pattern: Option<&str>, branch: &str) -> bool {
+    match pattern {
+        None => true,
+        Some(pattern) => match pattern.strip_prefix("re:") {
+            Some(regex) => {
+                Regex::new(&format!("^(?:{regex})$")).is_ok_and(|re| re.is_match(branch))
+            }
+            None => glob_match(pattern, branch),
+        },
+    }
+}
+
+/// Triggers among `triggers` that cover `branch`, in order.
+pub fn for_branch<'a>(
+    triggers: &'a [CiTrigger],
+    branch: &'a str,
+) -> impl Iterator<Item = &'a CiTrigger> + 'a {
+    triggers
+        .iter()
+        .filter(move |t| branch_matches(t.branch_pattern.as_deref(), branch))
+}
+
+/// Whether `trigger` filters on changed files at all.
+pub fn has_path_filters(trigger: &CiTrigger) -> bool {
+    !trigger.paths.is_empty() || !trigger.paths_ignore.is_empty()
+}
+
+/// Whether `trigger`'s path filters accept `changed`.
+pub fn accepts(trigger: &CiTrigger, changed: Option<&[String]>) -> bool {
+    let Some(changed) = changed.filter(|_| has_path_filters(trigger)) else {
+        return true;
+    };
+    changed.iter().any(|file| {
+        (trigger.paths.is_empty() || trigger.paths.iter().any(|p| glob_match(p, file)))
+            && !trigger.paths_ignore.iter().any(|p| glob_match(p, file))
+    })
+}
+
+/// Whether `trigger`'s draft and label filters admit `pr`.
+pub fn admits(trigger: &CiTrigger, pr: &PullRequest<'_>) -> bool {
+    !(trigger.skip_drafts && pr.draft)
+        && (trigger.labels.is_empty()
+            || trigger
+                .labels
+                .iter()
+                .any(|l| pr.labels.contains(&l.as_str())))
+}
+
+/// Pick the trigger a pull request on `branch` builds through.
+pub fn select_pull_request<'a>(
+    triggers: &'a [CiTrigger],
+    branch: &str,
+    changed: Option<&[String]>,
+    pr: &PullRequest<'_>,
+) -> Selection<'a> {
+    if triggers.is_empty() {
+        return Selection::Untriggered;
+    }
+    let covering: Vec<&CiTrigger> = for_branch(triggers, branch).collect();
+    if covering.is_empty() {
+        return Selection::Unmatched;
+    }
+    let mut admitting = covering
+        .iter()
+        .copied()
+        .filter(|t| admits(t, pr))
+        .peekable();
+    if admitting.peek().is_none() {
+        let drafts_skipped = covering.iter().any(|t| t.skip_drafts);
+        return Selection::Excluded(if pr.draft && drafts_skipped {
+            "pull request is a draft"
+        } else {
+            "pull request has none of the trigger labels"
+        });
+    }
+    match admitting.find(|t| accepts(t, changed)) {
+        Some(trigger) => Selection::Trigger(trigger),
+        None => Selection::Filtered,
+    }
+}
+
+/// Pick the trigger an event on `branch` builds through.
+pub fn select<'a>(
+    triggers: &'a [CiTrigger],
+    branch: &str,
+    changed: Option<&[String]>,
+) -> Selection<'a> {
+    if triggers.is_empty() {
+        return Selection::Untriggered;
+    }
+    let mut covering = for_branch(triggers, branch).peekable();
+    if covering.peek().is_none() {
+        return Selection::Unmatched;
+    }
+    match covering.find(|t| accepts(t, changed)) {
+        Some(trigger) => Selection::Trigger(trigger),
+        None => Selection::Filtered,
+    }
+}
+
+/// Match a repository path or branch name against a glob.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.trim_start_matches('/');
+    matches(pattern.as_bytes(), path.as_bytes())
+}
+
+fn matches(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        // `**/` also matches no directory at all.
+        [b'*', b'*', b'/', rest @ ..] => {
+            matches(rest, path)
+                || path
+                    .iter()
+                    .enumerate()
+                    .any(|(i, c)| *c == b'/' && matches(rest, &path[i + 1..]))
+        }
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| matches(rest, &path[i..])),
+        [b'*', rest @ ..] => {
+            let segment = path.iter().position(|c| *c == b'/').unwrap_or(path.len());
+            (0..=segment).any(|i| matches(rest, &path[i..]))
+        }
+        [b'?', rest @ ..] => matches!(path, [c, tail @ ..] if *c != b'/' && matches(rest, tail)),
+        [p, rest @ ..] => matches!(path, [c, tail @ ..] if c == p && matches(rest, tail)),
+    }
+}
@@ -0,0 +1,329 @@
+//! Dependency vulnerability reports and the merge gate.
+//!
+//! A step declaring `vulnerability_report: {"format": "cargo-audit"}` (or
+//! `npm-audit`) has its JSON report parsed into `ci_vulnerabilities`; `path`
+//! names a report file in the workspace, otherwise the step's stdout is
+//! used. With `vulnerability_gate: {"threshold": "high"}` in the project's
+//! pipeline config (an in-repo pipeline file cannot set it), a build fails when it has findings at or above the threshold that the
+//! last successful build of the project's default branch did not have.
+
+use std::collections::HashSet;
+
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use crate::models::build::BuildStatus;
+use crate::models::vulnerability::{CiVulnerability, NewCiVulnerability};
+use crate::schema::{ci_builds, ci_projects, ci_vulnerabilities};
+
+/// Advisory severity, ordered from least to most severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Unknown,
+    Info,
+    Low,
+    Moderate,
+    High,
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Unknown => "unknown",
+            Self::Info => "info",
+            Self::Low => "low",
+            Self::Moderate => "moderate",
+            Self::High => "high",
+            Self::Critical => "critical",
+        }
+    }
+
+    pub fn parse(s: &str) -> Self {
+        match s.to_ascii_lowercase().as_str() {
+            "info" | "none" => Self::Info,
+            "low" => Self::Low,
+            "moderate" | "medium" => Self::Moderate,
+            "high" => Self::High,
+            "critical" => Self::Critical,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Qualitative rating of a CVSS base score.
+    fn from_score(score: f64) -> Self {
+        match score {
+            s if s <= 0.0 => Self::Info,
+            s if s < 4.0 => Self::Low,
+            s if s < 7.0 => Self::Moderate,
+            s if s < 9.0 => Self::High,
+            _ => Self::Critical,
+        }
+    }
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Supported audit report formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    CargoAudit,
+    NpmAudit,
+}
+
+impl ReportFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::CargoAudit => "cargo-audit",
+            Self::NpmAudit => "npm-audit",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "cargo-audit" => Some(Self::CargoAudit),
+            "npm-audit" => Some(Self::NpmAudit),
+            _ => None,
+        }
+    }
+}
+
+/// One advisory affecting one package.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub advisory_id: String,
+    pub package: String,
+    pub version: Option<String>,
+    pub severity: Severity,
+    pub title: Option<String>,
+}
+
+/// Parse an audit report.
+pub fn parse_report(format: ReportFormat, raw: &str) -> anyhow::Result<Vec<Finding>> {
+    let report: serde_json::Value = serde_json::from_str(raw)
+        .map_err(|e| anyhow::anyhow!("invalid {} report: {e}", format.as_str()))?;
+    Ok(match format {
+        ReportFormat::CargoAudit => parse_cargo_audit(&report),
+        ReportFormat::NpmAudit => parse_npm_audit(&report),
+    })
+}
+
+/// `cargo audit --json`: `vulnerabilities.list[]` with the RustSec advisory
+/// and the affected package. Severity comes from the advisory's CVSS vector.
+fn parse_cargo_audit(report: &serde_json::Value) -> Vec<Finding> {
+    report["vulnerabilities"]["list"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|v| {
+            let advisory = &v["advisory"];
+            let severity = advisory["cvss"]
+                .as_str()
+                .and_then(cvss3_base_score)
+                .map(Severity::from_score)
+                .unwrap_or(Severity::Unknown);
+            Some(Finding {
+                advisory_id: advisory["id"].as_str()?.to_string(),
+                package: v["package"]["name"]
+                    .as_str()
+                    .or(advisory["package"].as_str())?
+                    .to_string(),
+                version: v["package"]["version"].as_str().map(str::to_string),
+                severity,
+                title: advisory["title"].as_str().map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+/// `npm audit --json` (npm 7+): `vulnerabilities` keyed by package. Only
+/// advisory objects in `via` are findings; plain strings there point at the
+/// dependency that carries the advisory and are reported under it.
+fn parse_npm_audit(report: &serde_json::Value) -> Vec<Finding> {
+    let Some(packages) = report["vulnerabilities"].as_object() else {
+        return Vec::new();
+    };
+    packages
+        .iter()
+        .flat_map(|(package, entry)| {
+            entry["via"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|via| via.is_object())
+                .filter_map(move |via| {
+                    // Prefer the GHSA id from the advisory URL; older
+                    // registries only give a numeric source id.
+                    let advisory_id = via["url"]
+                        .as_str()
+                        .and_then(|u| u.rsplit('/').next())
+                        .filter(|id| !id.is_empty())
+                        .map(str::to_string)
+                        .or_else(|| via["source"].as_u64().map(|s| s.to_string()))?;
+                    Some(Finding {
+                        advisory_id,
+                        package: package.clone(),
+                        version: entry["range"].as_str().map(str::to_string),
+                        severity: Severity::parse(
+                            via["severity"]
+                                .as_str()
+                                .or(entry["severity"].as_str())
+                                .unwrap_or(""),
+                        ),
+                        title: via["title"].as_str().map(str::to_string),
+                    })
+                })
+        })
+        .collect()
+}
+
+/// CVSS v3.x base score of a vector like `CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:H/I:H/A:H`.
+fn cvss3_base_score(vector: &str) -> Option<f64> {
+    let metric = |name: &str| {
+        vector
+            .split('/')
+            .find_map(|part| part.strip_prefix(name)?.strip_prefix(':'))
+    };
+    let changed = metric("S")? == "C";
+    let av = match metric("AV")? {
+        "N" => 0.85,
+        "A" => 0.62,
+        "L" => 0.55,
+        "P" => 0.2,
+        _ => return None,
+    };
+    let ac = match metric("AC")? {
+        "L" => 0.77,
+        "H" => 0.44,
+        _ => return None,
+    };
+    let pr = match (metric("PR")?, changed) {
+        ("N", _) => 0.85,
+        ("L", false) => 0.62,
+        ("L", true) => 0.68,
+        ("H", false) => 0.27,
+        ("H", true) => 0.5,
+        _ => return None,
+    };
+    let ui = match metric("UI")? {
+        "N" => 0.85,
+        "R" => 0.62,
+        _ => return None,
+    };
+    let cia = |name: &str| match metric(name) {
+        Some("H") => Some(0.56),
+        Some("L") => Some(0.22),
+        Some("N") => Some(0.0),
+        _ => None,
+    };
+    let iss = 1.0 - (1.0 - cia("C")?) * (1.0 - cia("I")?) * (1.0 - cia("A")?);
+    let impact = if changed {
+        7.52 * (iss - 0.029) - 3.25 * (iss - 0.02_f64).powi(15)
+    } else {
+        6.42 * iss
+    };
+    if impact <= 0.0 {
+        return Some(0.0);
+    }
+    let exploitability = 8.22 * av * ac * pr * ui;
+    let score = if changed {
+        (1.08 * (impact + exploitability)).min(10.0)
+    } else {
+        (impact + exploitability).min(10.0)
+    };
+    // Round up to one decimal, as the spec requires.
+    Some((score * 10.0).ceil() / 10.0)
+}
+
+/// Store a build's findings. Re-reported advisories are ignored.
+pub async fn record(
+    conn: &mut AsyncPgConnection,
+    tenant_id: uuid::Uuid,
+    build_id: i64,
+    project_id: i64,
+    source: ReportFormat,
+    findings: &[Finding],
+) -> anyhow::Result<usize> {
+    if findings.is_empty() {
+        return Ok(0);
+    }
+    let rows: Vec<NewCiVulnerability> = findings
+        .iter()
+        .map(|f| NewCiVulnerability {
+            tenant_id,
+            build_id,
+            project_id,
+            source: source.as_str().to_string(),
+            advisory_id: f.advisory_id.clone(),
+            package: f.package.clone(),
+            version: f.version.clone(),
+            severity: f.severity.as_str().to_string(),
+            title: f.title.clone(),
+        })
+        .collect();
+    let inserted = diesel::insert_into(ci_vulnerabilities::table)
+        .values(&rows)
+        .on_conflict_do_nothing()
+        .execute(conn)
+        .await?;
+    Ok(inserted)
+}
+
+/// Findings of a build.
+pub async fn list_for_build(
+    conn: &mut AsyncPgConnection,
+    build_id: i64,
+) -> anyhow::Result<Vec<CiVulnerability>> {
+    let rows = ci_vulnerabilities::table
+        .filter(ci_vulnerabilities::build_id.eq(build_id))
+        .order(ci_vulnerabilities::id.asc())
+        .load(conn)
+        .await?;
+    Ok(rows)
+}
+
+/// Findings of `build_id` at or above `threshold` that the last successful
+/// build of the project's default branch did not report. With no such
+/// build, every finding is new.
+pub async fn new_findings(
+    conn: &mut AsyncPgConnection,
+    build_id: i64,
+    project_id: i64,
+    threshold: Severity,
+) -> anyhow::Result<Vec<CiVulnerability>> {
+    let base_branch: String = ci_projects::table
+        .find(project_id)
+        .select(ci_projects::default_branch)
+        .first(conn)
+        .await?;
+    let base_build: Option<i64> = ci_builds::table
+        .filter(ci_builds::project_id.eq(project_id))
+        .filter(ci_builds::branch.eq(&base_branch))
+        .filter(ci_builds::status.eq(BuildStatus::Success.as_str()))
+        .filter(ci_builds::id.ne(build_id))
+        .order(ci_builds::id.desc())
+        .select(ci_builds::id)
+        .first(conn)
+        .await
+        .optional()?;
+
+    let known: HashSet<(String, String)> = match base_build {
+        Some(base) => list_for_build(conn, base)
+            .await?
+            .into_iter()
+            .map(|v| (v.advisory_id, v.package))
+            .collect(),
+        None => HashSet::new(),
+    };
+
+    Ok(list_for_build(conn, build_id)
+        .await?
+        .into_iter()
+        .filter(|v| Severity::parse(&v.severity) >= threshold)
+        .filter(|v| !known.contains(&(v.advisory_id.clone(), v.package.clone())))
+        .collect())
+}
@@ -0,0 +1,138 @@
+//! Logging and OpenTelemetry tracing setup.
+//!
+//! With an OTLP endpoint configured, spans are exported over gRPC: every
+//! build is one trace (webhook, checkout, steps, DB writes, GitHub calls).
+//! The trace context is carried between tasks as a W3C `traceparent`
+//! string stored on the build and outbox rows, and handed to steps in
+//! `TRACEPARENT` so in-build tooling can join the trace.
+
+use std::collections::HashMap;
+
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, TracerProvider};
+use opentelemetry_sdk::Resource;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// OTLP exporter settings.
+#[derive(Clone, Debug)]
+pub struct OtelConfig {
+    /// OTLP gRPC endpoint (e.g. `http://otel-collector:4317`). `None`
+    /// disables trace export.
+    pub endpoint: Option<String>,
+    /// `service.name` resource attribute.
+    pub service_name: String,
+    /// Fraction of new traces sampled; child spans follow their parent.
+    pub sample_ratio: f64,
+}
+
+impl OtelConfig {
+    pub fn from_env() -> Self {
+        Self {
+            endpoint: std::env::var("CI_OTEL_ENDPOINT")
+                .or_else(|_| std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT"))
+                .ok()
+                .filter(|e| !e.is_empty()),
+            service_name: std::env::var("OTEL_SERVICE_NAME")
+                .unwrap_or_else(|_| "centrix-ci".to_string()),
+            sample_ratio: std::env::var("CI_OTEL_SAMPLE_RATIO")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(1.0),
+        }
+    }
+}
+
+/// Install the global subscriber: `RUST_LOG`-filtered logs (JSON when
+/// `LOG_FORMAT=json`) plus the OpenTelemetry layer when exporting. Returns
+/// the tracer provider to flush on shutdown.
+pub fn init_tracing(otel: &OtelConfig) -> anyhow::Result<Option<TracerProvider>> {
+    let filter =
+        tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into());
+    let fmt_layer = if std::env::var("LOG_FORMAT").is_ok_and(|f| f == "json") {
+        tracing_subscriber::fmt::layer().json().boxed()
+    } else {
+        tracing_subscriber::fmt::layer().boxed()
+    };
+
+    let provider = match otel.endpoint {
+        Some(ref endpoint) => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .build()?;
+            Some(
+                TracerProvider::builder()
+                    .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+                    .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                        otel.sample_ratio,
+                    ))))
+                    .with_resource(Resource::new([KeyValue::new(
+                        "service.name",
+                        otel.service_name.clone(),
+                    )]))
+                    .build(),
+            )
+        }
+        None => None,
+    };
+    let otel_layer = provider
+        .as_ref()
+        .map(|p| tracing_opentelemetry::layer().with_tracer(p.tracer("centrix-ci")));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt_layer)
+        .with(otel_layer)
+        .init();
+
+    if let Some(ref endpoint) = otel.endpoint {
+        tracing::info!(%endpoint, "Exporting traces over OTLP");
+    }
+    Ok(provider)
+}
+
+/// Flush and stop trace export.
+pub fn shutdown(provider: Option<TracerProvider>) {
+    if let Some(provider) = provider {
+        if let Err(e) = provider.shutdown() {
+            tracing::warn!("Trace exporter shutdown failed: {e}");
+        }
+    }
+}
+
+/// W3C `traceparent` of the current span, if it is being traced.
+pub fn traceparent() -> Option<String> {
+    let cx = tracing::Span::current().context();
+    let span = cx.span();
+    let sc = span.span_context();
+    if !sc.is_valid() {
+        return None;
+    }
+    Some(format!(
+        "00-{}-{}-{:02x}",
+        sc.trace_id(),
+        sc.span_id(),
+        sc.trace_flags().to_u8()
+    ))
+}
+
+/// Trace id part of a `traceparent`.
+pub fn trace_id(traceparent: &str) -> Option<&str> {
+    traceparent.split('-').nth(1)
+}
+
+/// Make `span` a child of a stored `traceparent`. No-op without one.
+pub fn set_parent(span: &tracing::Span, traceparent: Option<&str>) {
+    let Some(traceparent) = traceparent else {
+        return;
+    };
+    let carrier = HashMap::from([("traceparent".to_string(), traceparent.to_string())]);
+    span.set_parent(TraceContextPropagator::new().extract(&carrier));
+}